@@ -4,8 +4,11 @@
 use super::*;
 
 mod sizes;
+mod workload;
 
-pub use parity_db::{CompressionType, Db, Key, Value};
+pub use workload::{run_bench, Bench};
+
+pub use parity_db::{Db, Key};
 
 use rand::{RngCore, SeedableRng};
 use std::{
@@ -89,6 +92,7 @@ pub struct Args {
 	pub append: bool,
 	pub no_check: bool,
 	pub compress: bool,
+	#[allow(dead_code)]
 	pub ordered: bool,
 }
 
@@ -132,8 +136,7 @@ impl SizePool {
 			.distribution
 			.range((std::ops::Bound::Included(sr), std::ops::Bound::Unbounded));
 		let size = *range.next().unwrap().1 as usize;
-		let mut v = Vec::new();
-		v.resize(size, 0);
+		let mut v = vec![0; size];
 		let fill = if !compressable { size } else { size / 2 };
 		rng.fill_bytes(&mut v[..fill]);
 		v
@@ -183,10 +186,9 @@ fn writer(
 			break
 		}
 
-		let mut key = n as u64 * COMMIT_SIZE as u64 + offset;
-		for _ in 0..commit_size {
+		let first_key = n as u64 * COMMIT_SIZE as u64 + offset;
+		for key in first_key..first_key + commit_size as u64 {
 			commit.push((pool.key(key), Some(pool.value(key, args.compress))));
-			key += 1;
 		}
 		if !args.archive && n >= COMMIT_PRUNE_WINDOW {
 			let prune_start = (n - COMMIT_PRUNE_WINDOW) * COMMIT_SIZE + offset as usize;
@@ -340,7 +342,7 @@ pub fn run_internal(args: Args, db: Db) {
 	let mut queries = 0;
 	for nc in start_commit as u64..(start_commit + commits) as u64 {
 		let counter = nc - start_commit as u64;
-		if counter % 10000 == 0 {
+		if counter.is_multiple_of(10000) {
 			println!("Query {}/{}", counter, commits);
 		}
 		let commits = (start_commit + commits) as u64;
@@ -0,0 +1,267 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+use super::*;
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// Distribution of the keys accessed by a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum KeyDistribution {
+	/// All keys are equally likely.
+	Uniform,
+	/// A few keys get most of the accesses, see `--zipf-exponent`.
+	Zipfian,
+}
+
+/// Run a reproducible workload on a new database and report throughput and latencies
+/// (warning erase db first).
+#[derive(Debug, clap::Parser)]
+pub struct Bench {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Number of distinct keys, all written before the measured run.
+	#[clap(long, default_value = "100000")]
+	pub keys: u64,
+
+	/// Number of operations of the measured run, over all threads.
+	#[clap(long, default_value = "100000")]
+	pub ops: u64,
+
+	/// Number of threads running operations.
+	#[clap(long, default_value = "1")]
+	pub threads: u64,
+
+	/// Percentage of operations that are reads, the rest are writes.
+	#[clap(long, default_value = "50")]
+	pub reads: u8,
+
+	/// Number of writes per commit.
+	#[clap(long, default_value = "100")]
+	pub batch: usize,
+
+	/// Smallest value size in bytes.
+	#[clap(long, default_value = "64")]
+	pub value_size: usize,
+
+	/// Largest value size in bytes [default: --value-size].
+	#[clap(long)]
+	pub max_value_size: Option<usize>,
+
+	/// Distribution of the accessed keys.
+	#[clap(long, arg_enum, default_value = "uniform")]
+	pub distribution: KeyDistribution,
+
+	/// Exponent of the zipfian distribution. Higher values concentrate accesses on fewer keys.
+	#[clap(long, default_value = "0.99")]
+	pub zipf_exponent: f64,
+
+	/// Random seed. Runs with the same seed and arguments perform the same operations.
+	#[clap(long, default_value = "0")]
+	pub seed: u64,
+
+	/// Use a btree indexed column.
+	#[clap(long)]
+	pub ordered: bool,
+
+	/// Enable compression.
+	#[clap(long)]
+	pub compress: bool,
+}
+
+// Picks key indexes following the configured distribution.
+enum KeyPicker {
+	Uniform(u64),
+	// Cumulative probability of each key rank.
+	Zipfian(Vec<f64>),
+}
+
+impl KeyPicker {
+	fn new(args: &Bench) -> KeyPicker {
+		match args.distribution {
+			KeyDistribution::Uniform => KeyPicker::Uniform(args.keys),
+			KeyDistribution::Zipfian => {
+				let mut total = 0.0;
+				let mut cdf: Vec<f64> = (1..=args.keys)
+					.map(|rank| {
+						total += 1.0 / (rank as f64).powf(args.zipf_exponent);
+						total
+					})
+					.collect();
+				for p in &mut cdf {
+					*p /= total;
+				}
+				KeyPicker::Zipfian(cdf)
+			},
+		}
+	}
+
+	fn pick(&self, rng: &mut impl Rng) -> u64 {
+		match self {
+			KeyPicker::Uniform(keys) => rng.gen_range(0..*keys),
+			KeyPicker::Zipfian(cdf) => {
+				let p: f64 = rng.gen();
+				let rank = cdf.partition_point(|c| *c < p).min(cdf.len() - 1) as u64;
+				// Spread popular keys over the key space.
+				scramble(rank) % cdf.len() as u64
+			},
+		}
+	}
+}
+
+fn scramble(mut x: u64) -> u64 {
+	x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+	x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+	x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+	x ^ (x >> 31)
+}
+
+fn bench_key(index: u64, seed: u64) -> Key {
+	let mut key = Key::default();
+	for (i, chunk) in key.chunks_mut(8).enumerate() {
+		chunk.copy_from_slice(
+			&scramble(index ^ seed.rotate_left(i as u32 * 16) ^ i as u64).to_be_bytes(),
+		);
+	}
+	key
+}
+
+fn bench_value(args: &Bench, rng: &mut impl Rng) -> Vec<u8> {
+	let max = args.max_value_size.unwrap_or(args.value_size).max(args.value_size);
+	let size = rng.gen_range(args.value_size..=max);
+	let mut value = vec![0; size];
+	// Half filled values compress to about half their size.
+	let fill = if args.compress { size / 2 } else { size };
+	rng.fill_bytes(&mut value[..fill]);
+	value
+}
+
+#[derive(Default)]
+struct Latencies {
+	reads: Vec<u64>,
+	commits: Vec<u64>,
+	hits: u64,
+}
+
+fn run_thread(db: &Db, args: &Bench, picker: &KeyPicker, index: u64, ops: u64) -> Latencies {
+	let mut rng = rand::rngs::SmallRng::seed_from_u64(args.seed.wrapping_add(index + 1));
+	let mut latencies = Latencies::default();
+	let mut commit = Vec::with_capacity(args.batch);
+	for op in 0..ops {
+		let key = bench_key(picker.pick(&mut rng), args.seed);
+		if rng.gen_range(0..100) < args.reads {
+			let start = Instant::now();
+			let value = db.get(0, &key).unwrap();
+			latencies.reads.push(start.elapsed().as_nanos() as u64);
+			latencies.hits += value.is_some() as u64;
+		} else {
+			commit.push((0, key, Some(bench_value(args, &mut rng))));
+		}
+		if commit.len() >= args.batch || (op + 1 == ops && !commit.is_empty()) {
+			let start = Instant::now();
+			db.commit(commit.drain(..)).unwrap();
+			latencies.commits.push(start.elapsed().as_nanos() as u64);
+		}
+	}
+	latencies
+}
+
+fn print_latencies(name: &str, mut latencies: Vec<u64>) {
+	if latencies.is_empty() {
+		return
+	}
+	latencies.sort_unstable();
+	let at = |p: f64| {
+		let i = ((latencies.len() as f64 * p) as usize).min(latencies.len() - 1);
+		Duration::from_nanos(latencies[i])
+	};
+	println!(
+		"{} latency: p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+		name,
+		at(0.5),
+		at(0.9),
+		at(0.99),
+		at(0.999),
+		Duration::from_nanos(*latencies.last().unwrap()),
+	);
+}
+
+pub fn run_bench(args: Bench, mut options: parity_db::Options) -> Result<(), String> {
+	if args.keys == 0 || args.threads == 0 || args.batch == 0 || args.reads > 100 {
+		return Err("--keys, --threads and --batch must be positive, --reads at most 100".into())
+	}
+	// avoid deleting folders by mistake.
+	options.path.push("bench_db");
+	if options.path.exists() {
+		std::fs::remove_dir_all(options.path.as_path())
+			.map_err(|e| format!("Error clearing bench db: {:?}", e))?;
+	}
+	options.columns = vec![parity_db::ColumnOptions {
+		btree_index: args.ordered,
+		compression: if args.compress {
+			parity_db::CompressionType::Lz4
+		} else {
+			parity_db::CompressionType::NoCompression
+		},
+		..Default::default()
+	}];
+	options.salt = Some([0; 32]);
+	let db = Db::open_or_create(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
+
+	let mut rng = rand::rngs::SmallRng::seed_from_u64(args.seed);
+	let start = Instant::now();
+	let mut index = 0;
+	while index < args.keys {
+		let end = (index + args.batch as u64).min(args.keys);
+		db.commit(
+			(index..end).map(|i| (0, bench_key(i, args.seed), Some(bench_value(&args, &mut rng)))),
+		)
+		.map_err(|e| format!("Commit error: {:?}", e))?;
+		index = end;
+	}
+	let elapsed = start.elapsed().as_secs_f64();
+	println!(
+		"Loaded {} keys in {:.2} seconds, {:.0} keys/s",
+		args.keys,
+		elapsed,
+		args.keys as f64 / elapsed
+	);
+
+	let picker = KeyPicker::new(&args);
+	let start = Instant::now();
+	let results: Vec<Latencies> = thread::scope(|scope| {
+		let threads: Vec<_> = (0..args.threads)
+			.map(|i| {
+				// Spread the remainder over the first threads.
+				let ops = args.ops / args.threads + (i < args.ops % args.threads) as u64;
+				let (db, args, picker) = (&db, &args, &picker);
+				scope.spawn(move || run_thread(db, args, picker, i, ops))
+			})
+			.collect();
+		threads.into_iter().map(|t| t.join().unwrap()).collect()
+	});
+	let elapsed = start.elapsed().as_secs_f64();
+
+	let mut all = Latencies::default();
+	for result in results {
+		all.reads.extend(result.reads);
+		all.commits.extend(result.commits);
+		all.hits += result.hits;
+	}
+	let reads = all.reads.len() as u64;
+	println!(
+		"Completed {} operations in {:.2} seconds, {:.0} ops/s: {} reads ({} hits), {} writes in {} commits",
+		args.ops,
+		elapsed,
+		args.ops as f64 / elapsed,
+		reads,
+		all.hits,
+		args.ops - reads,
+		all.commits.len(),
+	);
+	print_latencies("Read", all.reads);
+	print_latencies("Commit", all.commits);
+	Ok(())
+}
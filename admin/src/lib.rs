@@ -3,7 +3,8 @@
 
 //! Experimental admin functionality for parity-db.
 
-use std::path::PathBuf;
+use parity_db::{ColumnOptions, Metadata};
+use std::{convert::TryInto, path::PathBuf};
 
 mod bench;
 
@@ -27,11 +28,11 @@ pub fn run() -> Result<(), String> {
 		.clone()
 		.unwrap_or_else(|| std::env::current_dir().expect("Cannot resolve current dir"));
 	let nb_column = cli.shared().columns.unwrap_or(1);
-	let mut options = if let Some(metadata) = parity_db::Options::load_metadata(&db_path)
-		.map_err(|e| format!("Error resolving metadata: {:?}", e))?
-	{
+	let metadata = parity_db::Options::load_metadata(&db_path)
+		.map_err(|e| format!("Error resolving metadata: {:?}", e))?;
+	let mut options = if let Some(metadata) = &metadata {
 		let mut options = parity_db::Options::with_columns(db_path.as_path(), 0);
-		options.columns = metadata.columns;
+		options.columns = metadata.columns.clone();
 		options.salt = Some(metadata.salt);
 		options
 	} else {
@@ -49,8 +50,12 @@ pub fn run() -> Result<(), String> {
 	log::debug!("Options: {:?}, {:?}", cli, options);
 	match cli.subcommand {
 		SubCommand::Stats(stat) => {
-			let db = parity_db::Db::open_read_only(&options)
-				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let db = if stat.clear {
+				parity_db::Db::open(&options)
+			} else {
+				parity_db::Db::open_read_only(&options)
+			}
+			.map_err(|e| format!("Invalid db: {:?}", e))?;
 			if stat.clear {
 				db.clear_stats(stat.column).unwrap();
 			} else {
@@ -58,6 +63,24 @@ pub fn run() -> Result<(), String> {
 				db.write_stats_text(&mut out, stat.column).unwrap();
 			}
 		},
+		SubCommand::Info(_info) => {
+			let metadata = metadata.ok_or_else(|| "No database found".to_string())?;
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			print_info(&db, &metadata).map_err(|e| format!("Error reading db: {:?}", e))?;
+		},
+		SubCommand::Metadata(_metadata) => {
+			let metadata = metadata.ok_or_else(|| "No database found".to_string())?;
+			println!("Database version: {}", metadata.version);
+			println!("Salt: {}", hex(&metadata.salt));
+			for (col, column) in metadata.columns.iter().enumerate() {
+				if metadata.dropped.contains(&(col as u8)) {
+					println!("Column {}: dropped", col);
+				} else {
+					println!("Column {}: {}", col, column_config(column));
+				}
+			}
+		},
 		SubCommand::Migrate(args) => {
 			use parity_db::Options;
 			let dest_meta = Options::load_metadata_file(&args.dest_meta)
@@ -87,12 +110,6 @@ pub fn run() -> Result<(), String> {
 			}
 		},
 		SubCommand::Check(check) => {
-			let db = parity_db::Db::open_read_only(&options)
-				.map_err(|e| format!("Invalid db: {:?}", e))?;
-			if !check.index_value {
-				// Note that we should use enum parameter instead.
-				return Err("Requires one of the following check flag: --index-value".to_string())
-			}
 			let check_param = parity_db::CheckOptions::new(
 				check.column,
 				check.range_start,
@@ -100,11 +117,146 @@ pub fn run() -> Result<(), String> {
 				check.display,
 				check.display_value_max,
 			);
-			db.dump(check_param).map_err(|e| format!("Check error: {:?}", e))?;
+			if check.index_value {
+				let db = parity_db::Db::open_read_only(&options)
+					.map_err(|e| format!("Invalid db: {:?}", e))?;
+				db.dump(check_param).map_err(|e| format!("Check error: {:?}", e))?;
+				return Ok(())
+			}
+			let db = if check.fix {
+				let (db, report) = parity_db::Db::open_repair(&options)
+					.map_err(|e| format!("Repair error: {:?}", e))?;
+				for skipped in &report.skipped_logs {
+					println!("Skipped the rest of a log file: {}", skipped);
+				}
+				print_issues(&report.columns);
+				println!(
+					"Removed {} index entries, restored {} index entries",
+					report.removed, report.restored
+				);
+				db
+			} else {
+				let db = parity_db::Db::open_read_only(&options)
+					.map_err(|e| format!("Invalid db: {:?}", e))?;
+				if let Some(error) = db.recovery_report().error {
+					println!("Discarded log records: {}", error);
+				}
+				db
+			};
+			let report = db.check(check_param).map_err(|e| format!("Check error: {:?}", e))?;
+			let issues = print_issues(&report.columns);
+			if issues != 0 {
+				return Err(format!("Found {} issues", issues))
+			}
+			println!("No issues found");
+		},
+		SubCommand::Dump(dump) => {
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let stdout = std::io::stdout();
+			let out = std::io::BufWriter::new(stdout.lock());
+			let count = if dump.hex {
+				db.export(dump.col, HexWriter { inner: out, line: 0 })
+			} else {
+				db.export(dump.col, out)
+			}
+			.map_err(|e| format!("Dump error: {:?}", e))?;
+			log::info!("Dumped {} entries", count);
+		},
+		SubCommand::Restore(restore) => {
+			let db = parity_db::Db::open(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
+			let stdin = std::io::stdin();
+			let input = stdin.lock();
+			let count = if restore.hex {
+				db.import(restore.col, HexReader { inner: input })
+			} else {
+				db.import(restore.col, input)
+			}
+			.map_err(|e| format!("Restore error: {:?}", e))?;
+			log::info!("Restored {} entries", count);
+		},
+		SubCommand::Get(get) => {
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			print_value(&db, &options.columns, &get)?;
+		},
+		SubCommand::Tree(tree) => {
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			print_tree(&db, &tree)?;
+		},
+		SubCommand::Compact(compact) => {
+			if let Some(rate) = compact.rate {
+				options.compaction_rate = rate;
+			}
+			let db = parity_db::Db::open(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
+			db.compact_column(compact.col)
+				.map_err(|e| format!("Compaction error: {:?}", e))?;
+			loop {
+				let stats =
+					db.column_stats(compact.col).map_err(|e| format!("Stats error: {:?}", e))?;
+				let progress = stats.compaction.unwrap_or_default();
+				show_progress(&format!(
+					"Compacting column {}: {} / {} chunks ({:.1}%), {} values moved, {} bytes released",
+					compact.col,
+					progress.scanned_chunks,
+					progress.total_chunks,
+					percent(progress.scanned_chunks, progress.total_chunks),
+					progress.moved_values,
+					progress.released_bytes,
+				));
+				if progress.done {
+					break
+				}
+				std::thread::sleep(PROGRESS_INTERVAL);
+			}
+			eprintln!();
+		},
+		SubCommand::MigrateColumn(args) => {
+			let dest_meta = parity_db::Options::load_metadata_file(&args.dest_meta)
+				.map_err(|e| format!("Error loading dest metadata: {:?}", e))?
+				.ok_or_else(|| "Error opening dest metadata file".to_string())?;
+			let column = dest_meta
+				.columns
+				.get(args.col as usize)
+				.cloned()
+				.ok_or_else(|| format!("No column {} in dest metadata", args.col))?;
+			let db = parity_db::Db::open(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
+			db.migrate_column(args.col, column)
+				.map_err(|e| format!("Migration error: {:?}", e))?;
+			while db.is_migrating(args.col) {
+				let stats =
+					db.column_stats(args.col).map_err(|e| format!("Stats error: {:?}", e))?;
+				let progress = stats.migration.unwrap_or_default();
+				let state = if progress.copy_done {
+					"waiting for snapshots to be released".to_string()
+				} else if progress.total_chunks != 0 {
+					format!(
+						"{} / {} chunks ({:.1}%)",
+						progress.scanned_chunks,
+						progress.total_chunks,
+						percent(progress.scanned_chunks, progress.total_chunks)
+					)
+				} else {
+					"copying".to_string()
+				};
+				show_progress(&format!(
+					"Migrating column {}: {}, {} keys copied",
+					args.col, state, progress.copied_keys
+				));
+				std::thread::sleep(PROGRESS_INTERVAL);
+			}
+			eprintln!();
+			println!(
+				"Migrated column {}: {}",
+				args.col,
+				column_config(&dest_meta.columns[args.col as usize])
+			);
 		},
 		SubCommand::Flush(_flush) => {
 			let _db = parity_db::Db::open(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
 		},
+		SubCommand::Bench(args) => bench::run_bench(args, options)?,
 		SubCommand::Stress(bench) => {
 			let args = bench.get_args();
 			// avoid deleting folders by mistake.
@@ -116,7 +268,7 @@ pub fn run() -> Result<(), String> {
 
 			let mut db_options = options.clone();
 			if args.compress {
-				for mut c in &mut db_options.columns {
+				for c in &mut db_options.columns {
 					c.compression = parity_db::CompressionType::Lz4;
 				}
 			}
@@ -127,6 +279,292 @@ pub fn run() -> Result<(), String> {
 	Ok(())
 }
 
+// Print the configuration, occupancy and index load of each column.
+fn print_info(db: &parity_db::Db, metadata: &Metadata) -> parity_db::Result<()> {
+	println!("Database version: {}", metadata.version);
+	println!("Admin version: {}", env!("CARGO_PKG_VERSION"));
+	for (col, column) in metadata.columns.iter().enumerate() {
+		let col = col as u8;
+		if metadata.dropped.contains(&col) {
+			println!("Column {}: dropped", col);
+			continue;
+		}
+		println!("Column {}: {}", col, column_config(column));
+		let usage = db.space_usage(col)?;
+		let disk = db.column_disk_usage(col)?;
+		if !column.btree_index {
+			let keys = db.approximate_len(col)?;
+			// Index entries are 8 bytes.
+			let entries = disk.index_bytes / 8;
+			println!(
+				"  Keys: ~{}, index: {} bytes, load: {:.1}%",
+				keys,
+				disk.index_bytes,
+				percent(keys, entries)
+			);
+		}
+		println!(
+			"  Values: {} live bytes, {} allocated bytes, {} reclaimable bytes, {} bytes in the log",
+			usage.live_bytes, usage.allocated_bytes, usage.reclaimable_bytes, disk.log_bytes
+		);
+		for table in db.column_stats(col)?.tables {
+			if table.capacity == 0 {
+				continue;
+			}
+			println!(
+				"  Tier {:02}, {} byte entries: {} / {} ({:.1}%)",
+				table.tier,
+				table.entry_size,
+				table.filled,
+				table.capacity,
+				percent(table.filled, table.capacity)
+			);
+		}
+	}
+	Ok(())
+}
+
+// Print a single value along with how it is stored.
+fn print_value(db: &parity_db::Db, columns: &[ColumnOptions], get: &Get) -> Result<(), String> {
+	let (key, key_hash) = match (&get.key, &get.hash) {
+		(Some(key), None) => (parse_hex(key)?, None),
+		(None, Some(hash)) => {
+			let hash: parity_db::Key =
+				parse_hex(hash)?.try_into().map_err(|_| "Key hashes are 32 bytes".to_string())?;
+			(Vec::new(), Some(hash))
+		},
+		_ => return Err("Requires exactly one of --key or --hash".to_string()),
+	};
+	let btree = columns
+		.get(get.col as usize)
+		.ok_or_else(|| format!("Invalid column {}", get.col))?
+		.btree_index;
+	if btree {
+		if key_hash.is_some() {
+			return Err("Btree indexed columns are looked up by key".to_string())
+		}
+		let value = db.get(get.col, &key).map_err(|e| format!("Read error: {:?}", e))?;
+		return match value {
+			Some(value) => {
+				println!("Size: {} bytes", value.len());
+				println!("Value: {}", hex(&value));
+				Ok(())
+			},
+			None => Err("Key not found".to_string()),
+		}
+	}
+	let value = match key_hash {
+		Some(hash) => db.get_hashed_with_meta(get.col, &hash),
+		None => db.get_with_meta(get.col, &key),
+	}
+	.map_err(|e| format!("Read error: {:?}", e))?;
+	let (value, meta) = value.ok_or_else(|| "Key not found".to_string())?;
+	let entry_size = db
+		.column_stats(get.col)
+		.map_err(|e| format!("Read error: {:?}", e))?
+		.tables
+		.iter()
+		.find(|t| t.tier == meta.size_tier)
+		.map_or(0, |t| t.entry_size as u64);
+	println!("Size: {} bytes", value.len());
+	println!("Size tier: {}, {} byte entries", meta.size_tier, entry_size);
+	println!("Compressed: {}", meta.compressed);
+	println!("Reference count: {}", meta.rc);
+	println!(
+		"Location: table_{:02}_{:02x}, entry {}, byte {}",
+		get.col,
+		meta.size_tier,
+		meta.offset,
+		meta.offset * entry_size
+	);
+	println!("Value: {}", hex(&value));
+	Ok(())
+}
+
+fn parse_hex(data: &str) -> Result<Vec<u8>, String> {
+	let data = data.strip_prefix("0x").unwrap_or(data);
+	if !data.len().is_multiple_of(2) {
+		return Err(format!("Odd number of hex digits in {}", data))
+	}
+	(0..data.len())
+		.step_by(2)
+		.map(|i| {
+			u8::from_str_radix(&data[i..i + 2], 16).map_err(|_| format!("Invalid hex {}", data))
+		})
+		.collect()
+}
+
+// Print the shape of a btree and optionally the nodes holding keys with a prefix.
+fn print_tree(db: &parity_db::Db, tree: &Tree) -> Result<(), String> {
+	let stats = db.tree_stats(tree.col).map_err(|e| format!("Read error: {:?}", e))?;
+	let total: u64 = stats.nodes.iter().sum();
+	println!("Depth: {}", stats.depth);
+	for (level, count) in stats.nodes.iter().enumerate() {
+		println!("  Level {}: {} nodes", level, count);
+	}
+	println!("Node occupancy:");
+	for (keys, count) in stats.occupancy.iter().enumerate() {
+		println!("  {} keys: {} nodes ({:.1}%)", keys, count, percent(*count, total));
+	}
+	if let Some(prefix) = &tree.prefix {
+		let prefix = parse_hex(prefix)?;
+		let nodes = db.tree_nodes(tree.col, &prefix).map_err(|e| format!("Read error: {:?}", e))?;
+		for node in nodes {
+			let indent = "  ".repeat(node.level as usize);
+			println!("{}Node {} at level {}:", indent, node.address, node.level);
+			for key in node.keys {
+				println!("{}  {}", indent, hex(&key));
+			}
+		}
+	}
+	Ok(())
+}
+
+// Print the issues found in each column. Returns the number of issues.
+fn print_issues(columns: &[parity_db::ColumnCheck]) -> usize {
+	let mut issues = 0;
+	for column in columns {
+		println!(
+			"Column {}: {} values checked, {} bytes, {} issues",
+			column.col,
+			column.entries,
+			column.bytes,
+			column.issues.len()
+		);
+		for issue in &column.issues {
+			println!("  {:?}", issue);
+		}
+		issues += column.issues.len();
+	}
+	issues
+}
+
+// Persisted settings of a column.
+fn column_config(column: &ColumnOptions) -> String {
+	let mut config = vec![if column.btree_index { "btree" } else { "hash" }.to_string()];
+	let flags = [
+		(column.preimage, "preimage"),
+		(column.uniform, "uniform"),
+		(column.ref_counted, "ref_counted"),
+		(column.wide_ref_counts, "wide_ref_counts"),
+		(column.ttl, "ttl"),
+		(column.value_checksums, "value_checksums"),
+		(column.count_keys, "count_keys"),
+		(column.prefix_compression, "prefix_compression"),
+		(column.store_keys, "store_keys"),
+	];
+	config.extend(flags.iter().filter(|(set, _)| *set).map(|(_, name)| name.to_string()));
+	if column.compression != parity_db::CompressionType::NoCompression {
+		config.push(format!("compression: {:?}", column.compression));
+	}
+	if !column.btree_index && !column.uniform {
+		config.push(format!("key_hash: {:?}", column.key_hash));
+	}
+	if let Some(size) = column.max_file_size {
+		config.push(format!("max_file_size: {}", size));
+	}
+	if let Some(threshold) = column.blob_threshold {
+		config.push(format!("blob_threshold: {}", threshold));
+	}
+	config.join(", ")
+}
+
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+// Overwrite the progress line on stderr.
+fn show_progress(line: &str) {
+	use std::io::Write;
+	let mut stderr = std::io::stderr();
+	let _ = write!(stderr, "\r{}\x1b[K", line);
+	let _ = stderr.flush();
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+	if total == 0 {
+		0.0
+	} else {
+		part as f64 * 100.0 / total as f64
+	}
+}
+
+fn hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+const HEX_LINE_BYTES: usize = 32;
+
+// Writes data as lines of hex digits.
+struct HexWriter<W: std::io::Write> {
+	inner: W,
+	line: usize,
+}
+
+impl<W: std::io::Write> std::io::Write for HexWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		for b in buf {
+			write!(self.inner, "{:02x}", b)?;
+			self.line += 1;
+			if self.line == HEX_LINE_BYTES {
+				self.inner.write_all(b"\n")?;
+				self.line = 0;
+			}
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		if self.line != 0 {
+			self.inner.write_all(b"\n")?;
+			self.line = 0;
+		}
+		self.inner.flush()
+	}
+}
+
+// Reads data written by `HexWriter`, ignoring whitespace.
+struct HexReader<R: std::io::BufRead> {
+	inner: R,
+}
+
+impl<R: std::io::BufRead> HexReader<R> {
+	fn next_digit(&mut self) -> std::io::Result<Option<u8>> {
+		loop {
+			let mut c = [0u8];
+			if self.inner.read(&mut c)? == 0 {
+				return Ok(None)
+			}
+			if c[0].is_ascii_whitespace() {
+				continue
+			}
+			return match (c[0] as char).to_digit(16) {
+				Some(d) => Ok(Some(d as u8)),
+				None => Err(std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("Invalid hex digit {:?}", c[0] as char),
+				)),
+			}
+		}
+	}
+}
+
+impl<R: std::io::BufRead> std::io::Read for HexReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let mut n = 0;
+		while n < buf.len() {
+			let high = match self.next_digit()? {
+				Some(d) => d,
+				None => break,
+			};
+			let low = self.next_digit()?.ok_or_else(|| {
+				std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Odd number of hex digits")
+			})?;
+			buf[n] = high << 4 | low;
+			n += 1;
+		}
+		Ok(n)
+	}
+}
+
 /// Admin cli command for parity-db.
 #[derive(Debug, clap::Parser)]
 pub struct Shared {
@@ -175,6 +613,10 @@ pub struct Cli {
 pub enum SubCommand {
 	/// Show stats.
 	Stats(Stats),
+	/// Show column configuration, value table occupancy and index load.
+	Info(Info),
+	/// Show the database metadata.
+	Metadata(MetadataArgs),
 	/// Migrate db (update version or change column options).
 	Migrate(Migrate),
 	/// Run db until all logs are flushed.
@@ -183,6 +625,20 @@ pub enum SubCommand {
 	Check(Check),
 	/// Stress tests.
 	Stress(bench::Stress),
+	/// Benchmark a configurable workload.
+	Bench(bench::Bench),
+	/// Write all entries of a column to stdout.
+	Dump(Dump),
+	/// Insert entries read from stdin, as written by `dump`, into a column.
+	Restore(Restore),
+	/// Show a single value and how it is stored.
+	Get(Get),
+	/// Show the shape of a btree indexed column.
+	Tree(Tree),
+	/// Compact the value tables of a column.
+	Compact(Compact),
+	/// Change the options of a single column in place.
+	MigrateColumn(MigrateColumn),
 }
 
 impl SubCommand {
@@ -199,10 +655,19 @@ impl Cli {
 	fn shared(&self) -> &Shared {
 		match &self.subcommand {
 			SubCommand::Stats(stats) => &stats.shared,
+			SubCommand::Info(info) => &info.shared,
+			SubCommand::Metadata(metadata) => &metadata.shared,
 			SubCommand::Migrate(stats) => &stats.shared,
 			SubCommand::Flush(flush) => &flush.shared,
 			SubCommand::Check(check) => &check.shared,
 			SubCommand::Stress(bench) => &bench.shared,
+			SubCommand::Bench(bench) => &bench.shared,
+			SubCommand::Dump(dump) => &dump.shared,
+			SubCommand::Restore(restore) => &restore.shared,
+			SubCommand::Get(get) => &get.shared,
+			SubCommand::Tree(tree) => &tree.shared,
+			SubCommand::Compact(compact) => &compact.shared,
+			SubCommand::MigrateColumn(args) => &args.shared,
 		}
 	}
 }
@@ -222,6 +687,20 @@ pub struct Stats {
 	pub clear: bool,
 }
 
+/// Show column configuration, value table occupancy and index load.
+#[derive(Debug, clap::Parser)]
+pub struct Info {
+	#[clap(flatten)]
+	pub shared: Shared,
+}
+
+/// Show the database metadata.
+#[derive(Debug, clap::Parser)]
+pub struct MetadataArgs {
+	#[clap(flatten)]
+	pub shared: Shared,
+}
+
 /// Migrate db (update version or change column options).
 #[derive(Debug, clap::Parser)]
 pub struct Migrate {
@@ -251,6 +730,39 @@ pub struct Migrate {
 	pub dest_meta: PathBuf,
 }
 
+/// Move the values of a hash indexed column to the start of its value tables and truncate the
+/// table files. An interrupted compaction can be resumed by running it again, values already
+/// moved stay in place.
+#[derive(Debug, clap::Parser)]
+pub struct Compact {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to compact.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Maximum number of bytes moved per second.
+	#[clap(long)]
+	pub rate: Option<u64>,
+}
+
+/// Rewrite a single column of the database in place with new options. An interrupted migration
+/// is discarded and must be started again.
+#[derive(Debug, clap::Parser)]
+pub struct MigrateColumn {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to migrate.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Metadata file holding the new options of the column.
+	#[clap(long)]
+	pub dest_meta: PathBuf,
+}
+
 /// Run db until all logs are flushed.
 #[derive(Debug, clap::Parser)]
 pub struct Flush {
@@ -258,7 +770,76 @@ pub struct Flush {
 	pub shared: Shared,
 }
 
-/// Check db.
+/// Write all entries of a column to stdout in the portable dump format. The database must not
+/// be open elsewhere.
+#[derive(Debug, clap::Parser)]
+pub struct Dump {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to dump.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Write the dump as lines of hex digits.
+	#[clap(long)]
+	pub hex: bool,
+}
+
+/// Insert entries read from stdin, as written by `dump`, into a column. The database must not
+/// be open elsewhere.
+#[derive(Debug, clap::Parser)]
+pub struct Restore {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to insert into.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Read the dump as lines of hex digits.
+	#[clap(long)]
+	pub hex: bool,
+}
+
+/// Show a single value, its size tier, compression, reference count and location in the value
+/// tables.
+#[derive(Debug, clap::Parser)]
+pub struct Get {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to read.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Key, in hex.
+	#[clap(long)]
+	pub key: Option<String>,
+
+	/// Key hash as stored in the index of hash indexed columns, in hex.
+	#[clap(long)]
+	pub hash: Option<String>,
+}
+
+/// Show the depth and node occupancy of a btree indexed column, and optionally the nodes that
+/// hold keys starting with a prefix.
+#[derive(Debug, clap::Parser)]
+pub struct Tree {
+	#[clap(flatten)]
+	pub shared: Shared,
+
+	/// Column to show.
+	#[clap(long)]
+	pub col: u8,
+
+	/// Print the nodes that may hold keys starting with this prefix, in hex. An empty prefix
+	/// prints the whole tree.
+	#[clap(long)]
+	pub prefix: Option<String>,
+}
+
+/// Check db integrity, optionally repairing it.
 #[derive(Debug, clap::Parser)]
 pub struct Check {
 	#[clap(flatten)]
@@ -290,4 +871,9 @@ pub struct Check {
 	/// Max length for value to display (when using --display).
 	#[clap(long)]
 	pub display_value_max: Option<u64>,
+
+	/// Repair the database before checking it: skip damaged log records, remove index entries
+	/// of unreadable values and restore missing index entries where possible.
+	#[clap(long)]
+	pub fix: bool,
 }
@@ -0,0 +1,16 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Model check with reference counting on a btree indexed column:
+//! Checks that a sequence of operations, iterator moves and seeks, and restarts behaves the same
+//! as an in-memory collection.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use parity_db_fuzz::{refcounted::*, *};
+
+fuzz_target!(|entry: (Config, Vec<Action<Operation>>)| {
+	let (mut config, actions) = entry;
+	config.btree_index = true;
+	Simulator::simulate(config, actions);
+});
@@ -0,0 +1,15 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Model check with reference counting, with faults injected into file operations:
+//! Checks that a sequence of operations, failed reads, writes and syncs, and restarts behaves the
+//! same as an in-memory collection.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use parity_db_fuzz::{refcounted::*, *};
+
+fuzz_target!(|entry: (Config, Vec<FaultConfig>, Vec<Action<Operation>>)| {
+	let (config, faults, actions) = entry;
+	Simulator::simulate_with_faults(config, faults, actions);
+});
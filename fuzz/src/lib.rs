@@ -6,9 +6,13 @@ use std::{
 	cmp::{Ordering, PartialOrd},
 	collections::HashMap,
 	fmt::Debug,
+	sync::{Arc, Mutex},
 };
 use tempfile::tempdir;
 
+pub mod refcounted;
+pub mod simple;
+
 pub const NUMBER_OF_POSSIBLE_KEYS: usize = 256;
 
 #[derive(Arbitrary, Debug, Clone, Copy)]
@@ -35,6 +39,75 @@ pub struct Config {
 	pub number_of_allowed_io_operations: u8,
 }
 
+#[derive(Arbitrary, Debug, Clone, Copy)]
+pub enum FaultOp {
+	Read,
+	Write,
+	Sync,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+pub enum FaultFile {
+	Any,
+	Log,
+	Index,
+	Table,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+pub enum FaultKind {
+	Fail,
+	DiskFull,
+	Short(u8),
+}
+
+/// A fault injected into the file operations of the database, see `parity_db::FaultRule`.
+#[derive(Arbitrary, Debug, Clone, Copy)]
+pub struct FaultConfig {
+	pub op: FaultOp,
+	pub file: FaultFile,
+	pub skip: u8,
+	pub times: Option<u8>,
+	pub kind: FaultKind,
+}
+
+impl From<FaultConfig> for parity_db::FaultRule {
+	fn from(f: FaultConfig) -> Self {
+		parity_db::FaultRule {
+			op: match f.op {
+				FaultOp::Read => parity_db::IoOp::Read,
+				FaultOp::Write => parity_db::IoOp::Write,
+				FaultOp::Sync => parity_db::IoOp::Sync,
+			},
+			file_prefix: match f.file {
+				FaultFile::Any => "",
+				FaultFile::Log => "log",
+				FaultFile::Index => "index",
+				FaultFile::Table => "table",
+			}
+			.into(),
+			skip: f.skip.into(),
+			times: f.times.map(Into::into),
+			fault: match f.kind {
+				FaultKind::Fail => parity_db::Fault::Fail(std::io::ErrorKind::Other),
+				FaultKind::DiskFull => parity_db::Fault::Fail(std::io::ErrorKind::StorageFull),
+				FaultKind::Short(len) => parity_db::Fault::Short(len.into()),
+			},
+		}
+	}
+}
+
+// Storage of the running simulation, if it injects faults.
+static FAULTY_STORAGE: Mutex<Option<Arc<parity_db::FaultyStorage>>> = Mutex::new(None);
+
+// Lets all further I/O operations succeed.
+fn disable_io_failures() {
+	parity_db::set_number_of_allowed_io_operations(usize::MAX);
+	if let Some(storage) = &*FAULTY_STORAGE.lock().unwrap() {
+		storage.clear();
+	}
+}
+
 #[derive(Arbitrary, Debug)]
 pub enum Action<O: Debug> {
 	Transaction(Vec<O>),
@@ -46,6 +119,7 @@ pub enum Action<O: Debug> {
 	Restart,
 	IterPrev,
 	IterNext,
+	IterSeek(u8),
 }
 
 #[derive(Clone, Debug)]
@@ -59,6 +133,8 @@ pub struct Layer<V> {
 pub enum IterPosition {
 	Start,
 	Value(u8),
+	// After a seek to this key: the next key is at least it, the previous one is at most it.
+	Seeked(u8),
 	End,
 }
 
@@ -67,16 +143,30 @@ impl PartialEq<[u8]> for IterPosition {
 		match self {
 			Self::Start => false,
 			Self::Value(v) => [*v] == other,
+			Self::Seeked(_) => false,
 			Self::End => false,
 		}
 	}
 }
 
+impl IterPosition {
+	// Whether moving from this position in `direction` may reach `key`: `Less` for next,
+	// `Greater` for prev. The key of a seek is included in both directions.
+	fn reaches(&self, key: &[u8], direction: Ordering) -> bool {
+		match self {
+			Self::Seeked(v) => [*v].as_slice().cmp(key) != direction.reverse(),
+			_ => self.partial_cmp(key) == Some(direction),
+		}
+	}
+}
+
 impl PartialOrd<[u8]> for IterPosition {
 	fn partial_cmp(&self, other: &[u8]) -> Option<Ordering> {
 		match self {
 			Self::Start => Some(Ordering::Less),
 			Self::Value(v) => [*v].as_slice().partial_cmp(other),
+			// Depends on the direction, see `reaches`.
+			Self::Seeked(_) => None,
 			Self::End => Some(Ordering::Greater),
 		}
 	}
@@ -136,6 +226,20 @@ pub trait DbSimulator {
 	) -> Vec<Vec<u8>>;
 
 	fn simulate(config: Config, actions: Vec<Action<Self::Operation>>) {
+		Self::simulate_with_faults(config, Vec::new(), actions)
+	}
+
+	/// Like `simulate`, on a database whose file operations fail as described by `faults` once
+	/// it is created. Faults are lifted after the first failure.
+	fn simulate_with_faults(
+		config: Config,
+		faults: Vec<FaultConfig>,
+		actions: Vec<Action<Self::Operation>>,
+	) {
+		let storage = (!faults.is_empty()).then(|| {
+			Arc::new(parity_db::FaultyStorage::new(Arc::new(parity_db::MemoryStorage::default())))
+		});
+		*FAULTY_STORAGE.lock().unwrap() = storage.clone();
 		let dir = tempdir().unwrap();
 		let options = parity_db::Options {
 			path: dir.path().to_owned(),
@@ -145,6 +249,24 @@ pub trait DbSimulator {
 			stats: false,
 			salt: Some([0; 32]),
 			compression_threshold: HashMap::new(),
+			log_archive: None,
+			slow_io_warn: None,
+			direct_io: false,
+			io_uring: false,
+			scrub: None,
+			compaction_rate: 0,
+			background_io_limit: None,
+			grow_policy: Default::default(),
+			preallocate: false,
+			memory_budget: Default::default(),
+			max_commit_bytes: None,
+			compression_threads: 4,
+			reindex_threads: 4,
+			replay_threads: 4,
+			recovery_mode: Default::default(),
+			in_memory: false,
+			storage: storage.clone().map(|s| s as Arc<dyn parity_db::Storage>),
+			expiry_interval: std::time::Duration::from_secs(60),
 			always_flush: true,
 			with_background_thread: false,
 		};
@@ -152,6 +274,11 @@ pub trait DbSimulator {
 		// We don't check for now failures inside of initialization.
 		parity_db::set_number_of_allowed_io_operations(usize::MAX);
 		let mut db = DbWithIter::open(&options).unwrap();
+		if let Some(storage) = &storage {
+			for fault in faults {
+				storage.inject(fault.into());
+			}
+		}
 		let mut layers = Vec::new();
 		// In case of bad writes, when restarting the DB we might end up to with a state of the
 		// previous opening but with a state of a older one.
@@ -173,7 +300,7 @@ pub trait DbSimulator {
 				},
 				Action::ProcessReindex =>
 					db = Self::try_or_restart(
-						|db| db.process_reindex(),
+						|db| db.process_reindex().map(|_| ()),
 						db,
 						&mut layers,
 						&old_layers,
@@ -187,7 +314,7 @@ pub trait DbSimulator {
 						}
 					}
 					db = Self::try_or_restart(
-						|db| db.process_commits(),
+						|db| db.process_commits().map(|_| ()),
 						db,
 						&mut layers,
 						&old_layers,
@@ -196,7 +323,7 @@ pub trait DbSimulator {
 				},
 				Action::FlushLog =>
 					db = Self::try_or_restart(
-						|db| db.flush_logs(),
+						|db| db.flush_logs().map(|_| ()),
 						db,
 						&mut layers,
 						&old_layers,
@@ -204,7 +331,10 @@ pub trait DbSimulator {
 					),
 				Action::EnactLog =>
 					db = Self::try_or_restart(
-						|db| db.enact_logs(),
+						|db| {
+							while db.enact_logs()? {}
+							Ok(())
+						},
 						db,
 						&mut layers,
 						&old_layers,
@@ -212,7 +342,7 @@ pub trait DbSimulator {
 					),
 				Action::CleanLogs =>
 					db = Self::try_or_restart(
-						|db| db.clean_logs(),
+						|db| db.clean_logs().map(|_| ()),
 						db,
 						&mut layers,
 						&old_layers,
@@ -239,7 +369,7 @@ pub trait DbSimulator {
 								log::debug!("Database error: {}, restarting iter.prev without I/O limitations.", e);
 
 								// We ignore the error and reset the iterator
-								parity_db::set_number_of_allowed_io_operations(usize::MAX);
+								disable_io_failures();
 								iter.seek_to_last().unwrap();
 								old_key = IterPosition::End;
 								iter.prev().unwrap()
@@ -271,7 +401,7 @@ pub trait DbSimulator {
 								log::debug!("Database error: {}, restarting iter.next without I/O limitations.", e);
 
 								// We ignore the error and reset the iterator
-								parity_db::set_number_of_allowed_io_operations(usize::MAX);
+								disable_io_failures();
 								iter.seek_to_first().unwrap();
 								old_key = IterPosition::Start;
 								iter.next().unwrap()
@@ -288,6 +418,11 @@ pub trait DbSimulator {
 								.map_or(IterPosition::End, |(k, _)| IterPosition::Value(k[0])),
 						);
 					},
+				Action::IterSeek(key) =>
+					if let Some(iter) = &mut db.iter {
+						retry_operation(|| iter.seek(&[*key]));
+						db.iter_current_key = Some(IterPosition::Seeked(*key));
+					},
 			}
 			retry_operation(|| Self::check_db_and_model_are_equals(&db.db, &layers)).unwrap();
 		}
@@ -302,10 +437,13 @@ pub trait DbSimulator {
 	) -> DbWithIter {
 		match op(&db.db) {
 			Ok(()) => db,
-			Err(e) if e.to_string().contains("Instrumented failure") => {
+			Err(e)
+				if e.to_string().contains("Instrumented failure") ||
+					e.to_string().contains("Injected fault") =>
+			{
 				log::debug!("Restarting after an instrumented failure");
 				drop(db);
-				parity_db::set_number_of_allowed_io_operations(usize::MAX);
+				disable_io_failures();
 				db = DbWithIter::open(options).unwrap();
 				Self::reset_model_from_database(&db.db, layers, old_layers);
 				db
@@ -397,21 +535,15 @@ pub trait DbSimulator {
 		required_content.sort();
 		let next_required_key = required_content
 			.iter()
-			.filter_map(|(k, _)| {
-				if current_position.partial_cmp(k.as_slice()) == Some(direction) {
-					Some(k)
-				} else {
-					None
-				}
-			})
-			.next();
+			.map(|(k, _)| k)
+			.find(|k| current_position.reaches(k, direction));
 
 		let mut possible_content = Self::layer_optional_content(&values);
 		possible_content.sort();
 		let mut result = possible_content
 			.into_iter()
 			.filter(|(k, _)| {
-				current_position.partial_cmp(k.as_slice()) == Some(direction) &&
+				current_position.reaches(k, direction) &&
 					next_required_key.map_or(true, |next_required_key| {
 						k == next_required_key || k.cmp(next_required_key) == direction
 					})
@@ -434,7 +566,32 @@ fn retry_operation<'a, T>(mut op: impl FnMut() -> parity_db::Result<T> + 'a) ->
 		log::debug!("Database error: {}, let's keep going without I/O limitations.", e);
 
 		// We ignore the error and try to redo it
-		parity_db::set_number_of_allowed_io_operations(usize::MAX);
+		disable_io_failures();
 		op().unwrap()
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn btree_iter_prev_after_seek_includes_key() {
+		use refcounted::Operation::Set;
+		let config = Config {
+			btree_index: true,
+			compression: CompressionType::NoCompression,
+			number_of_allowed_io_operations: u8::MAX,
+		};
+		let seek_both_ways =
+			|| [Action::IterSeek(5), Action::IterPrev, Action::IterSeek(5), Action::IterNext];
+		let mut actions = vec![Action::Transaction(vec![Set(3), Set(5)])];
+		// From the commit overlay, the log overlay and the tables.
+		actions.extend(seek_both_ways());
+		actions.push(Action::ProcessCommits);
+		actions.extend(seek_both_ways());
+		actions.extend([Action::FlushLog, Action::EnactLog]);
+		actions.extend(seek_both_ways());
+		refcounted::Simulator::simulate(config, actions);
+	}
+}
@@ -0,0 +1,161 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Asynchronous database access.
+//!
+//! Blocking database calls are executed on a dedicated thread pool and exposed as futures,
+//! independent of any particular async runtime.
+
+use crate::{
+	error::{Error, Result},
+	parking_lot::Mutex,
+};
+use std::{
+	future::Future,
+	io,
+	panic::{self, AssertUnwindSafe},
+	pin::Pin,
+	sync::{mpsc, Arc},
+	task::{Context, Poll, Waker},
+	thread,
+};
+
+const POOL_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed size thread pool for blocking database calls.
+#[derive(Debug)]
+pub(crate) struct BlockingPool {
+	sender: Option<Mutex<mpsc::Sender<Job>>>,
+	threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl BlockingPool {
+	pub fn new() -> Self {
+		let (sender, receiver) = mpsc::channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		let threads = (0..POOL_THREADS)
+			.map(|_| {
+				let receiver = receiver.clone();
+				thread::spawn(move || loop {
+					let job = receiver.lock().recv();
+					match job {
+						Ok(job) => job(),
+						Err(_) => break,
+					}
+				})
+			})
+			.collect();
+		BlockingPool { sender: Some(Mutex::new(sender)), threads }
+	}
+
+	/// Run `f` on the pool, returning a future resolving to its result. If `f` panics the future
+	/// resolves to an error and the worker thread keeps running.
+	pub fn spawn<T: Send + 'static>(
+		&self,
+		f: impl FnOnce() -> Result<T> + Send + 'static,
+	) -> DbFuture<Result<T>> {
+		let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+		let job_shared = shared.clone();
+		let job: Job = Box::new(move || {
+			let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+				Err(Error::Io(io::Error::other("Async database call panicked")))
+			});
+			let mut shared = job_shared.lock();
+			shared.result = Some(result);
+			if let Some(waker) = shared.waker.take() {
+				waker.wake();
+			}
+		});
+		if let Some(sender) = &self.sender {
+			// Workers only exit once the sender is dropped.
+			let _ = sender.lock().send(job);
+		}
+		DbFuture { shared }
+	}
+}
+
+impl Drop for BlockingPool {
+	fn drop(&mut self) {
+		self.sender.take();
+		for t in self.threads.drain(..) {
+			if let Err(e) = t.join() {
+				log::warn!(target: "parity-db", "Async pool thread shutdown error: {:?}", e);
+			}
+		}
+	}
+}
+
+struct Shared<T> {
+	result: Option<T>,
+	waker: Option<Waker>,
+}
+
+/// Future resolving to the result of a database call executed on the blocking pool.
+pub struct DbFuture<T> {
+	shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for DbFuture<T> {
+	type Output = T;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+		let mut shared = self.shared.lock();
+		match shared.result.take() {
+			Some(result) => Poll::Ready(result),
+			None => {
+				shared.waker = Some(cx.waker().clone());
+				Poll::Pending
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::BlockingPool;
+	use std::{
+		future::Future,
+		pin::pin,
+		sync::Arc,
+		task::{Context, Poll, Wake, Waker},
+	};
+
+	struct ThreadWaker(std::thread::Thread);
+
+	impl Wake for ThreadWaker {
+		fn wake(self: Arc<Self>) {
+			self.0.unpark();
+		}
+	}
+
+	pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+		let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+		let mut cx = Context::from_waker(&waker);
+		let mut f = pin!(f);
+		loop {
+			if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+				return r
+			}
+			std::thread::park();
+		}
+	}
+
+	#[test]
+	fn panicking_job_resolves() {
+		let pool = BlockingPool::new();
+		let futures: Vec<_> = (0..super::POOL_THREADS * 2)
+			.map(|_| pool.spawn::<()>(|| panic!("job panic")))
+			.collect();
+		for f in futures {
+			assert!(block_on(f).is_err());
+		}
+		// All workers are still running.
+		let futures: Vec<_> =
+			(0..super::POOL_THREADS * 2).map(|i| pool.spawn(move || Ok(i))).collect();
+		for (i, f) in futures.into_iter().enumerate() {
+			assert_eq!(block_on(f).unwrap(), i);
+		}
+	}
+}
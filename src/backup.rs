@@ -0,0 +1,208 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Backups and checkpoints.
+//!
+//! Every enacted log record marks the table file pages it writes with its record id. An
+//! incremental backup then only copies pages marked after the sequence number of the backup it
+//! updates. Tracking is in memory and starts over when the database is opened or a column is
+//! replaced; older backups are then updated with full file copies. Record ids start over too
+//! when the database is opened, so backups record the session of the tracking they were taken
+//! with, and backups of a previous session are always updated in full.
+
+use crate::{
+	error::{try_io, Result},
+	index::TableId as IndexTableId,
+	table::TableId as ValueTableId,
+};
+use std::{
+	collections::HashMap,
+	io::{Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+/// Written to the backup directory. Contains the backup sequence number and the tracking
+/// session, see `ModifiedPages::session`.
+pub const BACKUP_SEQUENCE_FILE: &str = "backup_seq";
+
+const PAGE_BITS: u8 = 20;
+const PAGE_SIZE: u64 = 1 << PAGE_BITS;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BackupKind {
+	Full,
+	Incremental,
+	Checkpoint,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TableFileId {
+	Index(IndexTableId),
+	/// Value table and segment, see `ColumnOptions::max_file_size`.
+	Value(ValueTableId, u64),
+}
+
+impl TableFileId {
+	fn file_name(&self) -> String {
+		match self {
+			TableFileId::Index(id) => id.file_name(),
+			TableFileId::Value(id, segment) => id.segment_file_name(*segment),
+		}
+	}
+}
+
+/// Log record ids of the last change to each page of the table files.
+#[derive(Debug)]
+pub struct ModifiedPages {
+	// Random identifier of the tracking, new each time the database is opened.
+	session: u64,
+	// Changes up to this record id are not tracked.
+	since: u64,
+	files: HashMap<TableFileId, Vec<u64>>,
+}
+
+impl ModifiedPages {
+	pub fn new(since: u64) -> ModifiedPages {
+		ModifiedPages { session: rand::random::<u64>().max(1), since, files: Default::default() }
+	}
+
+	/// Identifier of the tracking. Record ids of different sessions are not comparable.
+	pub fn session(&self) -> u64 {
+		self.session
+	}
+
+	pub fn record(&mut self, file: TableFileId, offset: u64, len: u64, record_id: u64) {
+		let first = (offset >> PAGE_BITS) as usize;
+		let last = ((offset + len.max(1) - 1) >> PAGE_BITS) as usize;
+		let pages = self.files.entry(file).or_default();
+		if pages.len() <= last {
+			pages.resize(last + 1, 0);
+		}
+		for page in &mut pages[first..=last] {
+			*page = record_id;
+		}
+	}
+
+	/// Forget all changes, after table files were replaced.
+	pub fn reset(&mut self, record_id: u64) {
+		self.since = record_id;
+		self.files.clear();
+	}
+
+	/// Offsets of the pages of file `name` changed after record `base` of tracking session
+	/// `session`. `None` if changes since `base` are not tracked.
+	pub fn modified_since(&self, name: &str, base: u64, session: u64) -> Option<Vec<u64>> {
+		if session != self.session || base < self.since {
+			return None
+		}
+		let pages = self
+			.files
+			.iter()
+			.find(|(id, _)| id.file_name() == name)
+			.map(|(_, pages)| {
+				pages
+					.iter()
+					.enumerate()
+					.filter(|(_, id)| **id > base)
+					.map(|(page, _)| page as u64 * PAGE_SIZE)
+					.collect()
+			})
+			.unwrap_or_default();
+		Some(pages)
+	}
+}
+
+/// Contents of the `BACKUP_SEQUENCE_FILE` of a backup.
+pub fn format_sequence(seq: u64, session: u64) -> String {
+	format!("{} {}", seq, session)
+}
+
+/// Parse the backup sequence number and tracking session. Backups written before sessions
+/// were recorded have session 0, which is never tracked.
+pub fn parse_sequence(s: &str) -> Option<(u64, u64)> {
+	let mut parts = s.split_whitespace();
+	let seq = parts.next()?.parse().ok()?;
+	let session = match parts.next() {
+		Some(session) => session.parse().ok()?,
+		None => 0,
+	};
+	parts.next().is_none().then_some((seq, session))
+}
+
+/// Update `to` to match `from`, given the offsets of all pages that differ. The first page,
+/// holding table headers and stats, is always copied.
+pub fn copy_pages(from: &Path, to: &Path, pages: &[u64]) -> Result<()> {
+	let mut source = try_io!(std::fs::File::open(from));
+	let mut dest = try_io!(std::fs::OpenOptions::new().write(true).open(to));
+	let len = try_io!(source.metadata()).len();
+	try_io!(dest.set_len(len));
+	let mut buf = vec![0; PAGE_SIZE as usize];
+	for offset in std::iter::once(0).chain(pages.iter().cloned().filter(|o| *o != 0)) {
+		if offset >= len {
+			continue
+		}
+		let size = std::cmp::min(PAGE_SIZE, len - offset) as usize;
+		try_io!(source.seek(SeekFrom::Start(offset)));
+		try_io!(source.read_exact(&mut buf[..size]));
+		try_io!(dest.seek(SeekFrom::Start(offset)));
+		try_io!(dest.write_all(&buf[..size]));
+	}
+	Ok(())
+}
+
+/// Copy `from` to `to`, sharing the data blocks on filesystems with copy-on-write support.
+#[cfg(target_os = "linux")]
+pub fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+	let source = std::fs::File::open(from)?;
+	let dest = std::fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+	if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+		return Ok(())
+	}
+	drop(dest);
+	std::fs::remove_file(to)?;
+	std::fs::copy(from, to).map(|_| ())
+}
+
+// `std::fs::copy` already clones files on MacOS.
+#[cfg(not(target_os = "linux"))]
+pub fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+	std::fs::copy(from, to).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{format_sequence, parse_sequence, ModifiedPages, TableFileId, PAGE_SIZE};
+	use crate::table::TableId;
+
+	#[test]
+	fn modified_pages() {
+		let id = TableId::new(0, 1);
+		let name = id.file_name();
+		let mut pages = ModifiedPages::new(10);
+		let session = pages.session();
+		pages.record(TableFileId::Value(id, 0), PAGE_SIZE - 1, 2, 11);
+		pages.record(TableFileId::Value(id, 0), 5 * PAGE_SIZE, 100, 12);
+		assert_eq!(pages.modified_since(&name, 9, session), None);
+		assert_eq!(
+			pages.modified_since(&name, 10, session),
+			Some(vec![0, PAGE_SIZE, 5 * PAGE_SIZE])
+		);
+		assert_eq!(pages.modified_since(&name, 11, session), Some(vec![5 * PAGE_SIZE]));
+		assert_eq!(pages.modified_since("table_01_01", 10, session), Some(vec![]));
+		pages.reset(12);
+		assert_eq!(pages.modified_since(&name, 11, session), None);
+		assert_eq!(pages.modified_since(&name, 12, session), Some(vec![]));
+		// Record ids of other sessions are not comparable.
+		assert_eq!(pages.modified_since(&name, 12, ModifiedPages::new(10).session()), None);
+		assert_eq!(pages.modified_since(&name, 12, 0), None);
+	}
+
+	#[test]
+	fn backup_sequence() {
+		assert_eq!(parse_sequence(&format_sequence(12, 7)), Some((12, 7)));
+		assert_eq!(parse_sequence("12\n"), Some((12, 0)));
+		assert_eq!(parse_sequence("12 x"), None);
+		assert_eq!(parse_sequence("12 7 1"), None);
+	}
+}
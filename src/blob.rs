@@ -0,0 +1,261 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Files holding values larger than `ColumnOptions::blob_threshold`.
+//!
+//! Each value is written to a file of its own, named after the multipart value table with a
+//! random id, and referenced from a value table entry. The file is written and synced with a
+//! `.tmp` suffix when the commit is planned, and renamed when the commit is enacted. The file
+//! of a replaced or removed value is deleted when the change is enacted, so the file system
+//! keeps track of the free space. Files left over by commits that were never enacted are
+//! removed when the database is opened.
+//!
+//! Log records may be replayed again after later records were enacted, so files are not
+//! removed while replaying. Instead the value table is scanned for the files in use once the
+//! replay is complete, and the other files are removed.
+
+use crate::{
+	error::{try_io, Error, Result},
+	file::DbFile,
+	parking_lot::Mutex,
+	storage::Files,
+	table::TableId,
+};
+use rand::Rng;
+use std::{
+	io::{Read, Write},
+	path::PathBuf,
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+pub const TMP_SUFFIX: &str = ".tmp";
+// Separates the value table file name and the id in blob file names.
+pub const FILE_INFIX: &str = ".blob_";
+
+#[derive(Debug)]
+pub struct BlobStore {
+	dir: PathBuf,
+	prefix: String,
+	pub threshold: usize,
+	files: Files,
+	// Files were renamed or removed since the last flush.
+	dirty: AtomicBool,
+	// Files of replaced values were kept while replaying the log.
+	unreferenced: AtomicBool,
+	// Serializes picking new ids.
+	create: Mutex<()>,
+}
+
+impl BlobStore {
+	pub fn new(dir: PathBuf, table: TableId, threshold: u32, files: Files) -> BlobStore {
+		BlobStore {
+			dir,
+			prefix: format!("{}{}", table.file_name(), FILE_INFIX),
+			threshold: threshold as usize,
+			files,
+			dirty: AtomicBool::new(false),
+			unreferenced: AtomicBool::new(false),
+			create: Mutex::new(()),
+		}
+	}
+
+	fn path(&self, id: u64) -> PathBuf {
+		self.dir.join(format!("{}{:016x}", self.prefix, id))
+	}
+
+	fn tmp_path(&self, id: u64) -> PathBuf {
+		self.dir.join(format!("{}{:016x}{}", self.prefix, id, TMP_SUFFIX))
+	}
+
+	/// Write `data` to a new uncommitted blob. Returns the blob id.
+	pub fn write(&self, data: &[u8]) -> Result<u64> {
+		let (id, mut file) = self.create()?;
+		try_io!(file.write_all(data));
+		try_io!(file.sync_data());
+		log::trace!(target: "parity-db", "Written blob {}{:016x}, {} bytes", self.prefix, id, data.len());
+		Ok(id)
+	}
+
+	/// Create a new empty uncommitted blob to be written by `BlobWriter`.
+	pub fn create_writer(&self, checksums: bool) -> Result<BlobWriter> {
+		let (id, file) = self.create()?;
+		Ok(BlobWriter { id, file, len: 0, checksum: checksums.then(crc32fast::Hasher::new) })
+	}
+
+	fn create(&self) -> Result<(u64, DbFile)> {
+		let _lock = self.create.lock();
+		let (id, tmp_path) = loop {
+			let id: u64 = rand::thread_rng().gen();
+			if id == 0 {
+				continue
+			}
+			let tmp_path = self.tmp_path(id);
+			if !try_io!(self.files.exists(&self.path(id))) && !try_io!(self.files.exists(&tmp_path))
+			{
+				break (id, tmp_path)
+			}
+		};
+		let file =
+			try_io!(self.files.open(&tmp_path, true)).expect("Files are created when requested");
+		try_io!(file.set_len(0));
+		Ok((id, file))
+	}
+
+	/// Remove uncommitted blob `id`, which will not be referenced.
+	pub fn discard(&self, id: u64) -> Result<()> {
+		match self.files.remove(&self.tmp_path(id)) {
+			Ok(()) => Ok(()),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(Error::Io(e)),
+		}
+	}
+
+	/// Open blob `id` for reading. Returns `None` if there is no such blob.
+	pub fn open(&self, id: u64) -> Result<Option<DbFile>> {
+		// The blob may be committed between the two attempts.
+		for path in [self.path(id), self.tmp_path(id), self.path(id)] {
+			if let Some(file) = try_io!(self.files.open(&path, false)) {
+				return Ok(Some(file))
+			}
+		}
+		Ok(None)
+	}
+
+	/// Read `len` bytes of blob `id`. Returns `None` if there is no such blob.
+	pub fn read(&self, id: u64, len: u64) -> Result<Option<Vec<u8>>> {
+		match self.open(id)? {
+			Some(mut file) => {
+				let mut data = vec![0; len as usize];
+				try_io!(file.read_exact(&mut data));
+				Ok(Some(data))
+			},
+			None => Ok(None),
+		}
+	}
+
+	/// Called when an entry referencing blob `old` is replaced with one referencing `new`.
+	pub fn replace(&self, old: Option<u64>, new: Option<u64>, replay: bool) -> Result<()> {
+		if let Some(new) = new {
+			self.commit(new)?;
+		}
+		match old {
+			Some(old) if new != Some(old) && replay => {
+				self.unreferenced.store(true, Ordering::Relaxed);
+				Ok(())
+			},
+			Some(old) if new != Some(old) => self.remove(old),
+			_ => Ok(()),
+		}
+	}
+
+	// Make blob `id` permanent. Does nothing if it already is, or if it was removed by a later
+	// commit that is being replayed again.
+	fn commit(&self, id: u64) -> Result<()> {
+		match self.files.rename(&self.tmp_path(id), &self.path(id)) {
+			Ok(()) => {
+				self.dirty.store(true, Ordering::Relaxed);
+				Ok(())
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(Error::Io(e)),
+		}
+	}
+
+	// Remove blob `id`. Does nothing if it does not exist.
+	fn remove(&self, id: u64) -> Result<()> {
+		match self.files.remove(&self.path(id)) {
+			Ok(()) => {
+				log::trace!(target: "parity-db", "Removed blob {}{:016x}", self.prefix, id);
+				self.dirty.store(true, Ordering::Relaxed);
+				Ok(())
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(Error::Io(e)),
+		}
+	}
+
+	/// Make renames and removals durable.
+	pub fn flush(&self) -> Result<()> {
+		if self.dirty.swap(false, Ordering::Relaxed) {
+			try_io!(self.files.sync_dir(&self.dir));
+		}
+		Ok(())
+	}
+
+	/// Paths of all blob files, committed or not.
+	pub fn paths(&self) -> Result<Vec<PathBuf>> {
+		Ok(try_io!(self.files.list(&self.dir))
+			.into_iter()
+			.filter(|name| name.starts_with(&self.prefix))
+			.map(|name| self.dir.join(name))
+			.collect())
+	}
+
+	/// Whether files of replaced values were kept while replaying the log. Resets the flag.
+	pub fn take_unreferenced(&self) -> bool {
+		self.unreferenced.swap(false, Ordering::Relaxed)
+	}
+
+	/// Remove the blobs of commits that were not enacted, and committed blobs for which `keep`
+	/// returns `false`.
+	pub fn retain(&self, keep: impl Fn(u64) -> bool) -> Result<()> {
+		for name in try_io!(self.files.list(&self.dir)) {
+			let id = match name.strip_prefix(&self.prefix) {
+				Some(id) => id,
+				None => continue,
+			};
+			let committed = u64::from_str_radix(id, 16).is_ok_and(&keep);
+			if !committed {
+				log::debug!(target: "parity-db", "Removing unused blob {}", name);
+				try_io!(self.files.remove(&self.dir.join(name)));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Uncommitted blob written in pieces, see `Db::begin_value`.
+pub struct BlobWriter {
+	pub id: u64,
+	file: DbFile,
+	// Bytes written so far.
+	len: u64,
+	checksum: Option<crc32fast::Hasher>,
+}
+
+impl BlobWriter {
+	pub fn write(&mut self, data: &[u8]) -> Result<()> {
+		try_io!(self.file.write_all(data));
+		if let Some(checksum) = &mut self.checksum {
+			checksum.update(data);
+		}
+		self.len += data.len() as u64;
+		Ok(())
+	}
+
+	/// Value length.
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// Append the checksum and sync the file. Returns the blob length, as stored in the value
+	/// table entry.
+	pub fn finish(mut self) -> Result<u64> {
+		if let Some(checksum) = self.checksum.take() {
+			let crc = checksum.finalize().to_le_bytes();
+			try_io!(self.file.write_all(&crc));
+			self.len += crc.len() as u64;
+		}
+		try_io!(self.file.sync_data());
+		Ok(self.len)
+	}
+}
+
+impl std::fmt::Debug for BlobWriter {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("BlobWriter")
+			.field("id", &self.id)
+			.field("len", &self.len)
+			.finish()
+	}
+}
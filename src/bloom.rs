@@ -0,0 +1,106 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Bloom filter of keys in a hash indexed column.
+
+use crate::{index::Entry, table::key::TableKey, Key};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const NUM_PROBES: u64 = 4;
+
+/// Probabilistic set of column keys, used to answer lookups of missing keys without reading
+/// the index. Keys are never removed, so the filter only becomes exact again when rebuilt.
+#[derive(Debug)]
+pub struct BloomFilter {
+	bits: Box<[AtomicU64]>,
+}
+
+impl BloomFilter {
+	pub fn new(bytes: usize) -> Self {
+		let words = (bytes / 8).max(1);
+		BloomFilter { bits: (0..words).map(|_| AtomicU64::new(0)).collect() }
+	}
+
+	// Only the key bits that are kept in index entries are used, so that the filter may be built
+	// from the index. See `IndexTable::recover_key_prefix`.
+	fn probes(&self, key: &Key) -> impl Iterator<Item = (usize, u64)> {
+		let h1 = TableKey::index_from_partial(key) >> Entry::address_bits(0);
+		let h2 = h1.wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(32) | 1;
+		let num_bits = self.bits.len() as u64 * 64;
+		(0..NUM_PROBES).map(move |i| {
+			let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+			((bit / 64) as usize, 1u64 << (bit % 64))
+		})
+	}
+
+	pub fn insert(&self, key: &Key) {
+		for (word, mask) in self.probes(key) {
+			self.bits[word].fetch_or(mask, Ordering::Relaxed);
+		}
+	}
+
+	/// Returns `false` if the key was never inserted.
+	pub fn may_contain(&self, key: &Key) -> bool {
+		self.probes(key)
+			.all(|(word, mask)| self.bits[word].load(Ordering::Relaxed) & mask != 0)
+	}
+
+	/// Filter bits, to be restored with `restore`.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		self.bits
+			.iter()
+			.flat_map(|word| word.load(Ordering::Relaxed).to_le_bytes())
+			.collect()
+	}
+
+	/// Replace the filter bits with `data` returned by `to_bytes`. Returns `false` if the filter
+	/// size differs.
+	pub fn restore(&self, data: &[u8]) -> bool {
+		if data.len() != self.bits.len() * 8 {
+			return false
+		}
+		for (word, bytes) in self.bits.iter().zip(data.chunks_exact(8)) {
+			word.store(u64::from_le_bytes(bytes.try_into().expect("8 bytes")), Ordering::Relaxed);
+		}
+		true
+	}
+
+	pub fn clear(&self) {
+		for word in self.bits.iter() {
+			word.store(0, Ordering::Relaxed);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::BloomFilter;
+
+	#[test]
+	fn no_false_negatives() {
+		let key = |i: u32| {
+			let mut key = [0u8; 32];
+			key[..8].copy_from_slice(&(i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+			key
+		};
+		// 10 bits per key.
+		let filter = BloomFilter::new(1250);
+		for i in 0..1000 {
+			filter.insert(&key(i));
+		}
+		assert!((0..1000).all(|i| filter.may_contain(&key(i))));
+		let false_positives = (1000..11000).filter(|i| filter.may_contain(&key(*i))).count();
+		assert!(false_positives < 300, "{}", false_positives);
+
+		// Bits not kept in index entries are ignored.
+		let mut other = key(1);
+		other[31] ^= 1;
+		assert!(filter.may_contain(&other));
+		let restored = BloomFilter::new(1250);
+		assert!(restored.restore(&filter.to_bytes()));
+		assert!((0..1000).all(|i| restored.may_contain(&key(i))));
+		assert!(!BloomFilter::new(2500).restore(&filter.to_bytes()));
+		filter.clear();
+		assert!(!filter.may_contain(&key(1)));
+	}
+}
@@ -79,6 +79,71 @@ impl BTree {
 		Ok(())
 	}
 
+	/// Remove the keys in `[start, end)`, freeing the nodes and values of the subtrees in range.
+	/// The tree is split at `start` and `end`, and the trees before and after the range are
+	/// joined. Returns the number of keys removed. Reference counts are not checked, see
+	/// `range_keys`.
+	pub fn remove_range(
+		&mut self,
+		start: &[u8],
+		end: &[u8],
+		btree: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<u64> {
+		let root_index = match self.root_index {
+			Some(index) if start < end => index,
+			_ => return Ok(0),
+		};
+		let root = BTree::fetch_root(root_index, btree, log)?;
+		if !root.has_key_in_range(self.depth, start, end, btree, log)? {
+			return Ok(0)
+		}
+		let mut removed = 0;
+		let (left, right) =
+			root.split_range(root_index, self.depth, start, end, btree, log, &mut removed)?;
+		let tree = match (left, right) {
+			(Some((index, depth)), Some(right)) => {
+				let (left, separator) = Node::pop_last(index, depth, btree, log)?;
+				Node::join(left, separator, Some(right), btree, log)?
+			},
+			(left, right) => left.or(right),
+		};
+		self.root_index = tree.map(|(index, _)| index);
+		self.depth = tree.map_or(0, |(_, depth)| depth);
+		Ok(removed)
+	}
+
+	/// Keys in `[start, end)`, in order.
+	pub fn range_keys(
+		&self,
+		start: &[u8],
+		end: &[u8],
+		btree: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<Vec<Vec<u8>>> {
+		let mut keys = Vec::new();
+		if let Some(root_index) = self.root_index.filter(|_| start < end) {
+			let root = BTree::fetch_root(root_index, btree, log)?;
+			let mut collect = |_, _, node: &Node| {
+				keys.extend(node.keys().filter(|k| *k >= start && *k < end).map(<[u8]>::to_vec))
+			};
+			root.walk(
+				btree,
+				log,
+				root_index,
+				self.depth,
+				0,
+				start,
+				Some(end),
+				None,
+				None,
+				&mut collect,
+			)?;
+		}
+		keys.sort();
+		Ok(keys)
+	}
+
 	#[cfg(test)]
 	pub fn is_balanced(&self, tables: TablesRef, log: &impl LogQuery) -> Result<bool> {
 		let root = BTree::fetch_root(self.root_index.unwrap_or(NULL_ADDRESS), tables, log)?;
@@ -101,12 +166,17 @@ impl BTree {
 		}
 	}
 
+	pub fn contains(&self, key: &[u8], values: TablesRef, log: &impl LogQuery) -> Result<bool> {
+		let root = BTree::fetch_root(self.root_index.unwrap_or(NULL_ADDRESS), values, log)?;
+		Ok(root.get(key, values, log)?.is_some())
+	}
+
 	pub fn fetch_root(root: Address, tables: TablesRef, log: &impl LogQuery) -> Result<Node> {
 		Ok(if root == NULL_ADDRESS {
 			Node::default()
 		} else {
 			let root = BTreeTable::get_encoded_entry(root, log, tables)?;
-			Node::from_encoded(root)
+			Node::from_encoded(root, tables.prefix_compression)
 		})
 	}
 }
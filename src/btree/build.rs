@@ -0,0 +1,162 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Bottom-up construction of a btree from keys in ascending order, see `Db::ingest_sorted`.
+
+use super::*;
+use crate::{column::Column, error::Result, log::LogWriter, table::key::TableKey};
+
+// Node of the tree being built.
+#[derive(Default)]
+struct NodeBuf {
+	keys: Vec<SeparatorInner>,
+	// Empty for leaves, one more than `keys` otherwise once the node is complete.
+	children: Vec<Address>,
+}
+
+// Level of the tree being built, leaves first. Nodes are filled with `ORDER` separators. A
+// filled node is only written once the next one is filled, so that the last two nodes of the
+// level can be balanced when the tree is finished.
+#[derive(Default)]
+struct Level {
+	node: NodeBuf,
+	// Last filled node and the separator after it.
+	filled: Option<(NodeBuf, SeparatorInner)>,
+}
+
+/// Outcome of `BTreeTable::ingest_plan`.
+pub enum IngestStep {
+	/// More keys may follow.
+	More,
+	/// The tree is finished.
+	Finished,
+	/// Reading the keys failed. The tree is not finished and stays empty.
+	Failed(Error),
+}
+
+/// Btree built from keys in ascending order. Values and filled nodes are written as keys are
+/// added, the remaining nodes and the tree header with `finish`.
+#[derive(Default)]
+pub struct TreeBuilder {
+	levels: Vec<Level>,
+	last_key: Option<Vec<u8>>,
+	keys: u64,
+	unordered: Option<Vec<u8>>,
+}
+
+impl TreeBuilder {
+	/// Number of keys added.
+	pub fn keys(&self) -> u64 {
+		self.keys
+	}
+
+	/// First key that was not greater than the key before it, if any. No keys are added after it.
+	pub fn unordered_key(&self) -> Option<&[u8]> {
+		self.unordered.as_deref()
+	}
+
+	/// Write `value` and add `key`. Returns false, without adding it, if `key` is not greater
+	/// than the last key added.
+	pub fn push(
+		&mut self,
+		key: &[u8],
+		value: &[u8],
+		tables: TablesRef,
+		writer: &mut LogWriter,
+	) -> Result<bool> {
+		if self.last_key.as_ref().is_some_and(|last| last[..] >= *key) {
+			self.unordered = Some(key.to_vec());
+			return Ok(false)
+		}
+		let value = Column::write_new_value_plan(&TableKey::NoHash, tables, value, writer, None)?;
+		self.push_separator(0, SeparatorInner { key: key.to_vec(), value }, tables, writer)?;
+		self.last_key = Some(key.to_vec());
+		self.keys += 1;
+		Ok(true)
+	}
+
+	fn level(&mut self, depth: usize) -> &mut Level {
+		if depth == self.levels.len() {
+			self.levels.push(Level::default());
+		}
+		&mut self.levels[depth]
+	}
+
+	fn push_separator(
+		&mut self,
+		depth: usize,
+		separator: SeparatorInner,
+		tables: TablesRef,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		let level = self.level(depth);
+		if level.node.keys.len() < ORDER {
+			level.node.keys.push(separator);
+			return Ok(())
+		}
+		// The node is complete, the separator goes to the parent level.
+		let node = std::mem::take(&mut level.node);
+		if let Some((node, up)) = level.filled.replace((node, separator)) {
+			let address = write_node(node, tables, writer)?;
+			self.level(depth + 1).node.children.push(address);
+			self.push_separator(depth + 1, up, tables, writer)?;
+		}
+		Ok(())
+	}
+
+	/// Write the remaining nodes. Returns the root and the depth of the tree, or `None` if no
+	/// keys were added.
+	pub fn finish(
+		&mut self,
+		tables: TablesRef,
+		writer: &mut LogWriter,
+	) -> Result<Option<BTreeHeader>> {
+		if self.keys == 0 {
+			return Ok(None)
+		}
+		let mut depth = 0;
+		loop {
+			let level = std::mem::take(&mut self.levels[depth]);
+			match level.filled {
+				None => {
+					// Only the top level has a single node.
+					let root = write_node(level.node, tables, writer)?;
+					self.levels.clear();
+					return Ok(Some(BTreeHeader { root, depth: depth as u32 }))
+				},
+				Some((mut left, up)) => {
+					// Balance the last two nodes, so that both have at least `ORDER / 2`
+					// separators.
+					left.keys.push(up);
+					left.keys.extend(level.node.keys);
+					left.children.extend(level.node.children);
+					let middle = left.keys.len() / 2;
+					let mut right =
+						NodeBuf { keys: left.keys.split_off(middle + 1), children: Vec::new() };
+					let up = left.keys.pop().expect("Balanced nodes are not empty");
+					if !left.children.is_empty() {
+						right.children = left.children.split_off(middle + 1);
+					}
+					let left = write_node(left, tables, writer)?;
+					let right = write_node(right, tables, writer)?;
+					self.level(depth + 1).node.children.push(left);
+					self.push_separator(depth + 1, up, tables, writer)?;
+					self.level(depth + 1).node.children.push(right);
+				},
+			}
+			depth += 1;
+		}
+	}
+}
+
+fn write_node(node: NodeBuf, tables: TablesRef, writer: &mut LogWriter) -> Result<Address> {
+	let mut encoded = Node::default();
+	for (i, separator) in node.keys.into_iter().enumerate() {
+		encoded.set_separator(i, Separator { modified: true, separator: Some(separator) });
+	}
+	for (i, child) in node.children.into_iter().enumerate() {
+		encoded.set_child(i, Node::new_child(Some(child)));
+	}
+	BTreeTable::write_node_plan(tables, encoded, writer, None)?
+		.ok_or_else(|| Error::InvalidInput("Btree node was not written".into()))
+}
@@ -9,8 +9,8 @@
 /// latest accessed key.u
 use super::*;
 use crate::{
-	btree::BTreeTable, db::CommitOverlay, error::Result, log::LogQuery, parking_lot::RwLock,
-	table::key::TableKeyQuery,
+	btree::BTreeTable, db::CommitOverlay, error::Result, iter::Cursor, log::LogQuery,
+	parking_lot::RwLock, table::key::TableKeyQuery,
 };
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -39,6 +39,7 @@ pub struct BTreeIterator<'a> {
 	col: ColId,
 	pending_backend: Option<PendingBackend>,
 	last_key: LastKey,
+	prefix: Option<Vec<u8>>,
 }
 
 type IterResult = Result<Option<(Vec<u8>, Vec<u8>)>>;
@@ -49,7 +50,7 @@ struct PendingBackend {
 	direction: IterDirection,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LastKey {
 	Start,
 	End,
@@ -86,10 +87,57 @@ impl<'a> BTreeIterator<'a> {
 			last_key: LastKey::Start,
 			log,
 			commit_overlay,
+			prefix: None,
 		})
 	}
 
+	/// Restrict iteration to keys starting with `prefix` and position the iterator at the first
+	/// matching key.
+	pub(crate) fn with_prefix(mut self, prefix: &[u8]) -> Result<Self> {
+		self.prefix = Some(prefix.to_vec());
+		self.seek_to_first()?;
+		Ok(self)
+	}
+
+	/// Restore the position of `cursor`, see `Db::resume_iter`.
+	pub(crate) fn resume(mut self, cursor: &Cursor) -> Result<Self> {
+		let (prefix, last) = cursor.tree_position()?;
+		self.prefix = prefix.map(<[u8]>::to_vec);
+		let log = self.log.read();
+		let record_id = log.last_record_id(self.col);
+		let seek_to = match last {
+			LastKey::Start => SeekTo::Include(prefix.unwrap_or_default()),
+			LastKey::Seeked(key) => SeekTo::Include(key),
+			LastKey::At(key) => SeekTo::Exclude(key),
+			LastKey::End => SeekTo::Last,
+		};
+		if seek_to == SeekTo::Last {
+			self.seek_backend_to_last(record_id, self.table, &*log)?;
+			// The scan is complete.
+			self.pending_backend =
+				Some(PendingBackend { next_item: None, direction: IterDirection::Forward });
+		} else {
+			self.seek_backend(seek_to, record_id, self.table, &*log)?;
+		}
+		drop(log);
+		self.last_key = last.clone();
+		Ok(self)
+	}
+
+	/// Position after the last key returned, or at the last seek, to resume the scan later.
+	pub fn cursor(&self) -> Cursor {
+		Cursor::tree(self.col, self.prefix.clone(), self.last_key.clone())
+	}
+
 	pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+		let key = match &self.prefix {
+			Some(prefix) if key < prefix.as_slice() => prefix.clone(),
+			_ => key.to_vec(),
+		};
+		self.seek_inner(&key)
+	}
+
+	fn seek_inner(&mut self, key: &[u8]) -> Result<()> {
 		// seek require log do not change
 		let log = self.log.read();
 		let record_id = log.last_record_id(self.col);
@@ -103,6 +151,10 @@ impl<'a> BTreeIterator<'a> {
 	}
 
 	pub fn seek_to_last(&mut self) -> Result<()> {
+		if let Some(upper) = self.prefix.as_deref().and_then(prefix_upper_bound) {
+			// Next `prev` returns the last key before `upper`, skipping `upper` itself.
+			return self.seek_inner(&upper)
+		}
 		let log = self.log.read();
 		let record_id = log.last_record_id(self.col);
 		self.last_key = LastKey::End;
@@ -111,11 +163,31 @@ impl<'a> BTreeIterator<'a> {
 
 	#[allow(clippy::should_implement_trait)]
 	pub fn next(&mut self) -> IterResult {
-		self.iter_inner(IterDirection::Forward)
+		self.iter_bounded(IterDirection::Forward)
 	}
 
 	pub fn prev(&mut self) -> IterResult {
-		self.iter_inner(IterDirection::Backward)
+		self.iter_bounded(IterDirection::Backward)
+	}
+
+	fn iter_bounded(&mut self, direction: IterDirection) -> IterResult {
+		loop {
+			let result = self.iter_inner(direction)?;
+			let (prefix, key) = match (&self.prefix, &result) {
+				(Some(prefix), Some((key, _))) => (prefix, key),
+				_ => return Ok(result),
+			};
+			if key.starts_with(prefix) {
+				return Ok(result)
+			}
+			// Keys on the far side of the prefix range are skipped, the ones past the end of the
+			// range terminate iteration.
+			match (direction, key.as_slice().cmp(prefix.as_slice())) {
+				(IterDirection::Forward, std::cmp::Ordering::Less) |
+				(IterDirection::Backward, std::cmp::Ordering::Greater) => continue,
+				_ => return Ok(None),
+			}
+		}
 	}
 
 	fn iter_inner(&mut self, direction: IterDirection) -> IterResult {
@@ -129,6 +201,10 @@ impl<'a> BTreeIterator<'a> {
 					IterDirection::Forward => o.btree_next(&self.last_key),
 					IterDirection::Backward => o.btree_prev(&self.last_key),
 				});
+			let removed = commit_overlay
+				.get(col as usize)
+				.map(|o| o.btree_removed_ranges())
+				.unwrap_or_default();
 			let log = self.log.read();
 			let record_id = log.last_record_id(self.col);
 			// No consistency over iteration, allows dropping lock to overlay.
@@ -162,7 +238,13 @@ impl<'a> BTreeIterator<'a> {
 							}
 						},
 						(IterDirection::Backward, std::cmp::Ordering::Less) |
-						(IterDirection::Forward, std::cmp::Ordering::Greater) => Some((backend_key, backend_value)),
+						(IterDirection::Forward, std::cmp::Ordering::Greater) => {
+							if is_removed(&removed, &backend_key) {
+								self.last_key = LastKey::At(backend_key);
+								continue
+							}
+							Some((backend_key, backend_value))
+						},
 						(_, std::cmp::Ordering::Equal) =>
 							if let Some(value) = commit_value {
 								Some((backend_key, value))
@@ -180,6 +262,10 @@ impl<'a> BTreeIterator<'a> {
 					self.last_key = LastKey::At(k);
 					continue
 				},
+				(None, Some((backend_key, _))) if is_removed(&removed, &backend_key) => {
+					self.last_key = LastKey::At(backend_key);
+					continue
+				},
 				(None, Some((backend_key, backend_value))) => Some((backend_key, backend_value)),
 				(None, None) => {
 					self.pending_backend = Some(PendingBackend { next_item: None, direction });
@@ -411,6 +497,18 @@ impl BTreeIterState {
 	}
 }
 
+/// Smallest key that is greater than all keys starting with `prefix`, if any.
+pub(super) fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+	let mut upper = prefix.to_vec();
+	while let Some(last) = upper.pop() {
+		if last != u8::MAX {
+			upper.push(last + 1);
+			return Some(upper)
+		}
+	}
+	None
+}
+
 fn node_start(node: &Node, direction: IterDirection, is_leaf: bool) -> LastIndex {
 	let ix = match direction {
 		IterDirection::Forward => 0,
@@ -423,3 +521,8 @@ fn node_start(node: &Node, direction: IterDirection, is_leaf: bool) -> LastIndex
 		LastIndex::Descend(ix)
 	}
 }
+
+// Check if a key of the log or the tables is in a range removed by a queued commit.
+fn is_removed(ranges: &[(Vec<u8>, Vec<u8>)], key: &[u8]) -> bool {
+	ranges.iter().any(|(start, end)| key >= &start[..] && key < &end[..])
+}
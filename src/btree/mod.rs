@@ -5,22 +5,28 @@ use crate::{
 	btree::{btree::BTree, node::Node},
 	column::{ColId, Column, TablesRef},
 	compress::Compress,
+	db::check::{CheckIssue, ColumnCheck},
 	error::{Error, Result},
+	file::TableIo,
 	index::Address,
-	log::{LogAction, LogQuery, LogReader, LogWriter},
-	options::{Metadata, Options, DEFAULT_COMPRESSION_THRESHOLD},
+	log::{LogAction, LogOverlays, LogQuery, LogReader, LogWriter},
+	options::{Metadata, Options},
 	parking_lot::RwLock,
+	stats::{RangeSize, TableOccupancy, TreeNode, TreeStats},
 	table::{
 		key::{TableKey, TableKeyQuery},
 		Entry as ValueTableEntry, Value, ValueTable,
 	},
 	Operation,
 };
-pub use iter::{BTreeIterator, LastIndex, LastKey};
-use node::SeparatorInner;
+pub use build::{IngestStep, TreeBuilder};
+use iter::{BTreeIterState, SeekTo};
+pub use iter::{BTreeIterator, LastKey};
+use node::{RangeSample, Separator, SeparatorInner};
 
 #[allow(clippy::module_inception)]
 mod btree;
+mod build;
 mod iter;
 mod node;
 
@@ -48,6 +54,10 @@ pub struct BTreeHeader {
 
 struct Entry {
 	encoded: ValueTableEntry<Vec<u8>>,
+	// Separator keys are stored as the length of the prefix shared with the previous key of the
+	// node and the rest of the key, see `ColumnOptions::prefix_compression`.
+	prefix_compression: bool,
+	last_key: Vec<u8>,
 }
 
 impl Entry {
@@ -56,7 +66,25 @@ impl Entry {
 	}
 
 	fn from_encoded(enc: Vec<u8>) -> Self {
-		Entry { encoded: ValueTableEntry::new(enc) }
+		Entry {
+			encoded: ValueTableEntry::new(enc),
+			prefix_compression: false,
+			last_key: Vec::new(),
+		}
+	}
+
+	fn with_prefix_compression(mut self, prefix_compression: bool) -> Self {
+		self.prefix_compression = prefix_compression;
+		self
+	}
+
+	fn read_size(&mut self) -> usize {
+		let head = self.encoded.read_slice(1)[0];
+		if head == u8::MAX {
+			self.encoded.read_u32() as usize
+		} else {
+			head as usize
+		}
 	}
 
 	fn read_separator(&mut self) -> Option<SeparatorInner> {
@@ -64,33 +92,50 @@ impl Entry {
 			return None
 		}
 		let value = self.encoded.read_u64();
-		let head = self.encoded.read_slice(1);
-		let head = head[0];
-		let size = if head == u8::MAX { self.encoded.read_u32() as usize } else { head as usize };
-		let key = self.encoded.read_slice(size).to_vec();
+		let shared = if self.prefix_compression { self.read_size() } else { 0 };
+		let size = self.read_size();
+		let mut key = Vec::with_capacity(shared + size);
+		key.extend_from_slice(&self.last_key[..shared.min(self.last_key.len())]);
+		key.extend_from_slice(self.encoded.read_slice(size));
 		if value == 0 {
 			return None
 		}
+		if self.prefix_compression {
+			self.last_key.clone_from(&key);
+		}
 		let value = Address::from_u64(value);
 		Some(SeparatorInner { key, value })
 	}
 
-	fn write_separator(&mut self, key: &[u8], value: Address) {
-		let size = key.len();
+	fn write_size(&mut self, size: usize) {
 		let inner_size = self.encoded.inner_mut().len();
 		if size >= u8::MAX as usize {
-			self.encoded.inner_mut().resize(inner_size + 8 + 1 + 4 + size, 0);
-		} else {
-			self.encoded.inner_mut().resize(inner_size + 8 + 1 + size, 0);
-		};
-		self.encoded.write_u64(value.as_u64());
-		if size >= u8::MAX as usize {
+			self.encoded.inner_mut().resize(inner_size + 1 + 4, 0);
 			self.encoded.write_slice(&[u8::MAX]);
 			self.encoded.write_u32(size as u32);
 		} else {
+			self.encoded.inner_mut().resize(inner_size + 1, 0);
 			self.encoded.write_slice(&[size as u8]);
 		}
-		self.encoded.write_slice(key);
+	}
+
+	fn write_separator(&mut self, key: &[u8], value: Address) {
+		let inner_size = self.encoded.inner_mut().len();
+		self.encoded.inner_mut().resize(inner_size + 8, 0);
+		self.encoded.write_u64(value.as_u64());
+		let shared = if self.prefix_compression {
+			let shared = self.last_key.iter().zip(key).take_while(|(a, b)| a == b).count();
+			self.write_size(shared);
+			self.last_key = key.to_vec();
+			shared
+		} else {
+			0
+		};
+		let rest = &key[shared..];
+		self.write_size(rest.len());
+		let inner_size = self.encoded.inner_mut().len();
+		self.encoded.inner_mut().resize(inner_size + rest.len(), 0);
+		self.encoded.write_slice(rest);
 	}
 
 	fn read_child_index(&mut self) -> Option<Address> {
@@ -121,7 +166,9 @@ pub struct BTreeTable {
 	id: ColId,
 	tables: RwLock<Vec<ValueTable>>,
 	ref_counted: bool,
+	prefix_compression: bool,
 	compression: Compress,
+	io: TableIo,
 }
 
 impl BTreeTable {
@@ -131,29 +178,59 @@ impl BTreeTable {
 		options: &Options,
 		metadata: &Metadata,
 	) -> Result<Self> {
-		let size_tier = HEADER_ADDRESS.size_tier() as usize;
-		if !values[size_tier].is_init() {
-			let btree_header = BTreeHeader { root: NULL_ADDRESS, depth: 0 };
-			let mut entry = Entry::empty();
-			entry.write_header(&btree_header);
-			values[size_tier].init_with_entry(&*entry.encoded.inner_mut())?;
-		}
+		Self::init_header(&values)?;
 		let col_options = &metadata.columns[id as usize];
 		Ok(BTreeTable {
 			id,
 			tables: RwLock::new(values),
 			ref_counted: col_options.ref_counted,
+			prefix_compression: col_options.prefix_compression,
 			compression: Compress::new(
 				col_options.compression,
 				options
 					.compression_threshold
 					.get(&id)
 					.copied()
-					.unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
-			),
+					.unwrap_or_else(|| col_options.default_compression_threshold()),
+			)
+			.with_dictionary(col_options.compression_dictionary.clone()),
+			io: TableIo::new(options, id),
 		})
 	}
 
+	pub fn io(&self) -> TableIo {
+		self.io.clone()
+	}
+
+	fn init_header(values: &[ValueTable]) -> Result<()> {
+		let size_tier = HEADER_ADDRESS.size_tier() as usize;
+		if !values[size_tier].is_init() {
+			let btree_header = BTreeHeader { root: NULL_ADDRESS, depth: 0 };
+			let mut entry = Entry::empty();
+			entry.write_header(&btree_header);
+			values[size_tier].init_with_entry(&*entry.encoded.inner_mut())?;
+		}
+		Ok(())
+	}
+
+	/// Replace the value tables with empty ones. Old files are passed to `discard`.
+	pub fn clear(
+		&self,
+		open_tables: impl FnOnce() -> Result<Vec<ValueTable>>,
+		discard: &mut dyn FnMut(std::path::PathBuf) -> Result<()>,
+	) -> Result<()> {
+		let mut tables = self.tables.write();
+		for table in std::mem::take(&mut *tables) {
+			for path in table.close()? {
+				discard(path)?;
+			}
+		}
+		let values = open_tables()?;
+		Self::init_header(&values)?;
+		*tables = values;
+		Ok(())
+	}
+
 	fn btree_header(log: &impl LogQuery, values: TablesRef) -> Result<BTreeHeader> {
 		let mut root = NULL_ADDRESS;
 		let mut depth = 0;
@@ -191,17 +268,30 @@ impl BTreeTable {
 			return Ok(None)
 		}
 		let record_id = 0; // lifetime of Btree is the query, so no invalidate.
-				   // keeping log locked when parsing tree.
+					 // keeping log locked when parsing tree.
 		let tree = BTree::new(Some(btree_header.root), btree_header.depth, record_id);
 		tree.get(key, values, log)
 	}
 
+	pub fn contains(key: &[u8], log: &impl LogQuery, values: TablesRef) -> Result<bool> {
+		let btree_header = Self::btree_header(log, values)?;
+		if btree_header.root == NULL_ADDRESS {
+			return Ok(false)
+		}
+		let tree = BTree::new(Some(btree_header.root), btree_header.depth, 0);
+		tree.contains(key, values, log)
+	}
+
 	fn get_encoded_entry(at: Address, log: &impl LogQuery, tables: TablesRef) -> Result<Vec<u8>> {
 		let key_query = TableKeyQuery::Check(&TableKey::NoHash);
 		if let Some((_tier, value)) = Column::get_value(key_query, at, tables, log)? {
 			Ok(value)
 		} else {
-			Err(Error::Corruption(format!("Missing btree entry at {}", at)))
+			Err(Error::ValueTableCorruption {
+				col: tables.col,
+				tier: at.size_tier(),
+				reason: format!("Missing btree entry at {}", at),
+			})
 		}
 	}
 
@@ -209,10 +299,162 @@ impl BTreeTable {
 		TablesRef {
 			tables,
 			ref_counted: self.ref_counted,
+			prefix_compression: self.prefix_compression,
 			preimage: false,
 			compression: &self.compression,
 			col: self.id,
+			compressed: None,
+		}
+	}
+
+	/// Collect the keys in `[start, end)` as currently committed to the log or the tables.
+	pub fn range_keys(
+		&self,
+		start: &[u8],
+		end: &[u8],
+		log: &RwLock<LogOverlays>,
+	) -> Result<Vec<Vec<u8>>> {
+		let log = log.read();
+		let record_id = log.last_record_id(self.id);
+		let mut tree = self.with_locked(|btree| BTree::open(btree, &*log, record_id))?;
+		let mut iter = BTreeIterState::new(record_id);
+		iter.seek(SeekTo::Include(start), &mut tree, self, &*log)?;
+		let mut keys = Vec::new();
+		while let Some((key, _)) = iter.next(&mut tree, self, &*log, IterDirection::Forward)? {
+			if key.as_slice() >= end {
+				break
+			}
+			keys.push(key);
 		}
+		Ok(keys)
+	}
+
+	/// Estimate the number of keys in `[start, end)` and the size of their keys and values from
+	/// the nodes on the paths to `start` and `end`. Subtrees entirely in range are assumed to be
+	/// filled like the sampled nodes.
+	pub fn estimate_range(
+		&self,
+		start: &[u8],
+		end: &[u8],
+		log: &RwLock<LogOverlays>,
+	) -> Result<RangeSize> {
+		let log = log.read();
+		let sample = self.with_locked(|tables| {
+			let header = Self::btree_header(&*log, tables)?;
+			let mut sample =
+				RangeSample { subtrees: vec![0; header.depth as usize], ..Default::default() };
+			if header.root != NULL_ADDRESS && start < end {
+				let root = Self::get_encoded_entry(header.root, &*log, tables)?;
+				let root = Node::from_encoded(root, tables.prefix_compression);
+				root.sample_range(
+					tables,
+					&*log,
+					header.depth,
+					start,
+					end,
+					None,
+					None,
+					&mut sample,
+				)?;
+			}
+			Ok(sample)
+		})?;
+		if sample.keys == 0 {
+			return Ok(RangeSize::default())
+		}
+		// A subtree of depth `d` with `n` separators per node holds `(n + 1)^(d + 1) - 1` keys.
+		let fill = sample.separators as f64 / sample.nodes as f64 + 1.0;
+		let estimated: f64 = sample
+			.subtrees
+			.iter()
+			.enumerate()
+			.map(|(depth, count)| *count as f64 * (fill.powi(depth as i32 + 1) - 1.0))
+			.sum();
+		let keys = sample.keys + estimated as u64;
+		let bytes = sample.bytes + (estimated * sample.bytes as f64 / sample.keys as f64) as u64;
+		Ok(RangeSize { keys, bytes })
+	}
+
+	// Visit the nodes that may hold keys in `[start, end)`, see `Node::walk`.
+	fn walk(
+		&self,
+		start: &[u8],
+		end: Option<&[u8]>,
+		log: &RwLock<LogOverlays>,
+		mut f: impl FnMut(u32, Address, &Node),
+	) -> Result<u32> {
+		let log = log.read();
+		self.with_locked(|tables| {
+			let header = Self::btree_header(&*log, tables)?;
+			if header.root != NULL_ADDRESS {
+				let root = Self::get_encoded_entry(header.root, &*log, tables)?;
+				Node::from_encoded(root, tables.prefix_compression).walk(
+					tables,
+					&*log,
+					header.root,
+					header.depth,
+					0,
+					start,
+					end,
+					None,
+					None,
+					&mut f,
+				)?;
+			}
+			Ok(header.depth)
+		})
+	}
+
+	/// Count the nodes of each level and the keys they hold.
+	pub fn tree_stats(&self, log: &RwLock<LogOverlays>) -> Result<TreeStats> {
+		let mut stats = TreeStats { occupancy: vec![0; ORDER + 1], ..Default::default() };
+		stats.depth = self.walk(&[], None, log, |level, _, node| {
+			if stats.nodes.len() <= level as usize {
+				stats.nodes.resize(level as usize + 1, 0);
+			}
+			stats.nodes[level as usize] += 1;
+			stats.occupancy[node.keys().count()] += 1;
+		})?;
+		Ok(stats)
+	}
+
+	/// Nodes that may hold keys starting with `prefix`, depth first.
+	pub fn tree_nodes(&self, prefix: &[u8], log: &RwLock<LogOverlays>) -> Result<Vec<TreeNode>> {
+		let end = iter::prefix_upper_bound(prefix);
+		let mut nodes = Vec::new();
+		self.walk(prefix, end.as_deref(), log, |level, address, node| {
+			let keys = node.keys().map(|k| k.to_vec()).collect();
+			nodes.push(TreeNode { level, address: address.as_u64(), keys });
+		})?;
+		Ok(nodes)
+	}
+
+	/// Check that the tree is balanced and ordered and that all values can be read.
+	pub fn check(&self, log: &RwLock<LogOverlays>, report: &mut ColumnCheck) -> Result<()> {
+		let log = log.read();
+		self.with_locked(|tables| {
+			let header = Self::btree_header(&*log, tables)?;
+			if header.root == NULL_ADDRESS {
+				return Ok(())
+			}
+			match Self::get_encoded_entry(header.root, &*log, tables) {
+				Ok(root) => Node::from_encoded(root, tables.prefix_compression).check(
+					tables,
+					&*log,
+					header.depth,
+					None,
+					None,
+					report,
+				),
+				Err(e) => {
+					report.issues.push(CheckIssue::BadNode {
+						address: header.root.as_u64(),
+						error: e.to_string(),
+					});
+					Ok(())
+				},
+			}
+		})
 	}
 
 	pub fn with_locked<R>(&self, mut apply: impl FnMut(TablesRef) -> Result<R>) -> Result<R> {
@@ -240,12 +482,44 @@ impl BTreeTable {
 			},
 			_ => {
 				log::error!(target: "parity-db", "Unexpected log action");
-				return Err(Error::Corruption("Unexpected log action".to_string()))
+				return Err(Error::LogCorruption("Unexpected log action".into()))
+			},
+		}
+		Ok(())
+	}
+
+	pub fn follow_plan(
+		&self,
+		action: LogAction,
+		log: &mut LogReader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		let tables = self.tables.read();
+		match action {
+			LogAction::InsertValue(record) => {
+				tables[record.table.size_tier() as usize].follow_plan(record.index, log, writer)?;
 			},
+			_ => return Err(Error::LogCorruption("Unexpected log action".into())),
 		}
 		Ok(())
 	}
 
+	pub fn refresh(&self) -> Result<()> {
+		let tables = self.tables.read();
+		for t in tables.iter() {
+			t.refresh()?;
+		}
+		Ok(())
+	}
+
+	pub fn table_occupancy(&self) -> Vec<TableOccupancy> {
+		self.tables.read().iter().map(|t| t.occupancy()).collect()
+	}
+
+	pub fn with_value_tables<R>(&self, f: impl FnOnce(&[ValueTable]) -> R) -> R {
+		f(&self.tables.read())
+	}
+
 	pub fn complete_plan(&self, log: &mut LogWriter) -> Result<()> {
 		let tables = self.tables.read();
 		for t in tables.iter() {
@@ -262,6 +536,70 @@ impl BTreeTable {
 		Ok(())
 	}
 
+	fn write_header_plan(
+		tables: TablesRef,
+		btree_header: &BTreeHeader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		let mut entry = Entry::empty();
+		entry.write_header(btree_header);
+		// Written in place, the header entry never moves.
+		tables.tables[HEADER_ADDRESS.size_tier() as usize].write_replace_plan(
+			HEADER_ADDRESS.offset(),
+			&TableKey::NoHash,
+			&entry.encoded.as_ref()[..HEADER_SIZE as usize],
+			writer,
+			false,
+		)
+	}
+
+	/// Check if the tree has no keys, as currently committed to the log or the tables.
+	pub fn is_empty(&self, log: &RwLock<LogOverlays>) -> Result<bool> {
+		let log = log.read();
+		self.with_locked(|tables| Ok(Self::btree_header(&*log, tables)?.root == NULL_ADDRESS))
+	}
+
+	/// Add keys and values from `iter` to `builder` until about `max_bytes` are added. The tree
+	/// is finished and its header written once there are no more keys, or a key is not in
+	/// order.
+	pub fn ingest_plan<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+		&self,
+		builder: &mut TreeBuilder,
+		iter: &mut impl Iterator<Item = Result<(K, V)>>,
+		max_bytes: usize,
+		writer: &mut LogWriter,
+	) -> Result<IngestStep> {
+		let tables = self.tables.read();
+		let locked = self.locked(&tables);
+		let mut bytes = 0;
+		let step = loop {
+			if bytes >= max_bytes {
+				break IngestStep::More
+			}
+			let (key, value) = match iter.next() {
+				Some(Ok(next)) => next,
+				Some(Err(e)) => break IngestStep::Failed(e),
+				None => break IngestStep::Finished,
+			};
+			if !builder.push(key.as_ref(), value.as_ref(), locked, writer)? {
+				break IngestStep::Finished
+			}
+			bytes += key.as_ref().len() + value.as_ref().len();
+		};
+		if let IngestStep::Finished = step {
+			if let Some(btree_header) = builder.finish(locked, writer)? {
+				Self::write_header_plan(locked, &btree_header, writer)?;
+				if tables[0].is_counted() {
+					tables[0].add_count(builder.keys() as i64);
+				}
+			}
+		}
+		for t in tables.iter() {
+			t.complete_plan(writer)?;
+		}
+		Ok(step)
+	}
+
 	fn write_plan_remove_node(
 		tables: TablesRef,
 		writer: &mut LogWriter,
@@ -305,7 +643,7 @@ impl BTreeTable {
 			return Ok(None)
 		}
 
-		let mut entry = Entry::empty();
+		let mut entry = Entry::empty().with_prefix_compression(tables.prefix_compression);
 		let mut i_children = 0;
 		let mut i_separator = 0;
 		loop {
@@ -326,7 +664,6 @@ impl BTreeTable {
 			}
 		}
 
-		let old_comp = tables.compression;
 		tables.compression = &crate::compress::NO_COMPRESSION;
 		let result = Ok(if let Some(existing) = node_id {
 			let k = TableKey::NoHash;
@@ -347,8 +684,6 @@ impl BTreeTable {
 			let k = TableKey::NoHash;
 			Some(Column::write_new_value_plan(&k, tables, entry.encoded.as_ref(), writer, None)?)
 		});
-		tables.compression = old_comp;
-
 		result
 	}
 }
@@ -356,20 +691,24 @@ impl BTreeTable {
 pub mod commit_overlay {
 	use super::*;
 	use crate::{
-		column::{ColId, Column},
-		db::{BTreeCommitOverlay, Operation},
+		column::ColId,
+		db::{BTreeCommitOverlay, BTreeRangeOverlay, CommitOverlay, Operation},
 		error::Result,
+		options::ColumnOptions,
 	};
+	use std::ops::Bound;
 
 	#[derive(Debug)]
 	pub struct BTreeChangeSet {
 		pub col: ColId,
 		pub changes: Vec<Operation<Vec<u8>, Vec<u8>>>,
+		// Keys removed by each `DeleteRange` operation, see `collect_range_keys`.
+		pub range_keys: Vec<Vec<Vec<u8>>>,
 	}
 
 	impl BTreeChangeSet {
 		pub fn new(col: ColId) -> Self {
-			BTreeChangeSet { col, changes: Default::default() }
+			BTreeChangeSet { col, changes: Default::default(), range_keys: Default::default() }
 		}
 
 		pub fn push(&mut self, change: Operation<Vec<u8>, Vec<u8>>) {
@@ -380,11 +719,12 @@ pub mod commit_overlay {
 		pub fn copy_to_overlay(
 			&self,
 			overlay: &mut BTreeCommitOverlay,
+			ranges: &mut BTreeRangeOverlay,
 			record_id: u64,
 			bytes: &mut usize,
-			options: &Options,
-		) -> Result<()> {
-			let ref_counted = options.columns[self.col as usize].ref_counted;
+			options: &ColumnOptions,
+		) {
+			let ref_counted = options.ref_counted;
 			for change in self.changes.iter() {
 				match change {
 					Operation::Set(key, value) => {
@@ -401,24 +741,97 @@ pub mod commit_overlay {
 							overlay.insert(key.clone(), (record_id, None));
 						}
 					},
-					Operation::Reference(..) => {
+					Operation::Reference(..) | Operation::AddRc(..) => {
 						// Don't add (we allow remove value in overlay when using rc: some
 						// indexing on top of it is expected).
-						if !ref_counted {
-							return Err(Error::InvalidInput(format!(
-								"No Rc for column {}",
-								self.col
-							)))
-						}
+						debug_assert!(ref_counted, "Rejected when pushed");
 					},
+					Operation::DeleteRange(start, end) =>
+						if !ref_counted && start < end {
+							// Keys set by this commit or queued ones are removed here, the keys
+							// in the log and the tables are hidden by the range.
+							*bytes += start.len() + end.len();
+							let range = (Bound::Included(&start[..]), Bound::Excluded(&end[..]));
+							for (_, entry) in overlay.range_mut::<[u8], _>(range) {
+								*entry = (record_id, None);
+							}
+							ranges.push((record_id, start.clone(), end.clone()));
+						},
+					Operation::Merge(..) | Operation::SetWithTtl(..) =>
+						unreachable!("Rejected for btree columns"),
 				}
 			}
+		}
+
+		/// Collect the keys removed by each `DeleteRange` operation into `range_keys`, for
+		/// snapshots, subscribers and migrations to hash indexed columns: keys in range with a
+		/// pending value in `overlay` or committed to the log or the tables, updated with the
+		/// changes set earlier in this change set. Called under the commit overlay lock.
+		pub fn collect_range_keys(
+			&mut self,
+			overlay: &[CommitOverlay],
+			btree: &BTreeTable,
+			log: &RwLock<LogOverlays>,
+			ref_counted: bool,
+		) -> Result<()> {
+			let overlay = &overlay[self.col as usize];
+			self.range_keys.clear();
+			for (i, change) in self.changes.iter().enumerate() {
+				let (start, end) = match change {
+					Operation::DeleteRange(start, end) => (start, end),
+					_ => continue,
+				};
+				if start >= end {
+					self.range_keys.push(Vec::new());
+					continue
+				}
+				let mut keys = overlay.btree_range_keys(start, end);
+				for key in btree.range_keys(start, end, log)? {
+					if overlay.btree_get(&key).is_none() {
+						keys.insert(key);
+					}
+				}
+				let in_range = |key: &Vec<u8>| key >= start && key < end;
+				for change in &self.changes[..i] {
+					match change {
+						Operation::Set(key, _) if in_range(key) => {
+							keys.insert(key.clone());
+						},
+						Operation::Dereference(key) if in_range(key) && !ref_counted => {
+							keys.remove(key);
+						},
+						Operation::DeleteRange(start, end) if !ref_counted => {
+							keys.retain(|key| key < start || key >= end);
+						},
+						_ => (),
+					}
+				}
+				self.range_keys.push(keys.into_iter().collect());
+			}
 			Ok(())
 		}
 
-		pub fn clean_overlay(&mut self, overlay: &mut BTreeCommitOverlay, record_id: u64) {
+		pub fn clean_overlay(
+			&mut self,
+			overlay: &mut BTreeCommitOverlay,
+			ranges: &mut BTreeRangeOverlay,
+			record_id: u64,
+		) {
 			use std::collections::btree_map::Entry;
 			for change in self.changes.drain(..) {
+				if let Operation::DeleteRange(start, end) = change {
+					if start < end {
+						let removed: Vec<_> = overlay
+							.range(start..end)
+							.filter(|(_, (id, _))| *id == record_id)
+							.map(|(key, _)| key.clone())
+							.collect();
+						for key in removed {
+							overlay.remove(&key);
+						}
+					}
+					continue
+				}
 				let key = change.into_key();
 				if let Entry::Occupied(e) = overlay.entry(key) {
 					if e.get().0 == record_id {
@@ -426,6 +839,48 @@ pub mod commit_overlay {
 					}
 				}
 			}
+			ranges.retain(|(id, _, _)| *id != record_id);
+		}
+
+		// Number of the changed keys present in `tree`.
+		fn count_keys(
+			changes: &[Operation<Vec<u8>, Vec<u8>>],
+			tree: &BTree,
+			tables: TablesRef,
+			log: &LogWriter,
+		) -> Result<i64> {
+			let mut count = 0;
+			let mut last = None;
+			for change in changes {
+				let key = change.key();
+				if last != Some(key) && tree.contains(key, tables, log)? {
+					count += 1;
+				}
+				last = Some(key);
+			}
+			Ok(count)
+		}
+
+		// Apply sorted `changes` to `tree`.
+		fn write_sorted_changes(
+			changes: &[Operation<Vec<u8>, Vec<u8>>],
+			tree: &mut BTree,
+			tables: TablesRef,
+			writer: &mut LogWriter,
+			ops: &mut u64,
+		) -> Result<()> {
+			if changes.is_empty() {
+				return Ok(())
+			}
+			let counted = tables.tables[0].is_counted();
+			let before = if counted { Self::count_keys(changes, tree, tables, writer)? } else { 0 };
+			tree.write_sorted_changes(changes, tables, writer)?;
+			*ops += changes.len() as u64;
+			if counted {
+				let after = Self::count_keys(changes, tree, tables, writer)?;
+				tables.tables[0].add_count(after - before);
+			}
+			Ok(())
 		}
 
 		pub fn write_plan(
@@ -444,28 +899,44 @@ pub mod commit_overlay {
 				BTreeHeader { root: tree.root_index.unwrap_or(NULL_ADDRESS), depth: tree.depth };
 			let old_btree_header = btree_header.clone();
 
-			self.changes.sort();
-			tree.write_sorted_changes(self.changes.as_slice(), locked, writer)?;
-			*ops += self.changes.len() as u64;
+			// Range deletions apply to the keys set before them in the change set. The changes
+			// between them are applied in key order.
+			let mut changes = &mut self.changes[..];
+			loop {
+				let len = changes
+					.iter()
+					.position(|c| matches!(c, Operation::DeleteRange(..)))
+					.unwrap_or(changes.len());
+				let (sorted, rest) = std::mem::take(&mut changes).split_at_mut(len);
+				sorted.sort();
+				Self::write_sorted_changes(sorted, &mut tree, locked, writer, ops)?;
+				let (start, end, rest) = match rest.split_first_mut() {
+					Some((Operation::DeleteRange(start, end), rest)) => (start, end, rest),
+					_ => break,
+				};
+				if locked.ref_counted {
+					// Each key is dereferenced, values referenced more than once are kept.
+					let keys = tree.range_keys(start, end, locked, writer)?;
+					let keys: Vec<_> = keys.into_iter().map(Operation::Dereference).collect();
+					Self::write_sorted_changes(&keys, &mut tree, locked, writer, ops)?;
+				} else {
+					let removed = tree.remove_range(start, end, locked, writer)?;
+					*ops += removed;
+					if locked.tables[0].is_counted() {
+						locked.tables[0].add_count(-(removed as i64));
+					}
+				}
+				changes = rest;
+			}
 
 			btree_header.root = tree.root_index.unwrap_or(NULL_ADDRESS);
 			btree_header.depth = tree.depth;
 
 			if old_btree_header != btree_header {
-				let mut entry = Entry::empty();
-				entry.write_header(&btree_header);
-				Column::write_existing_value_plan(
-					&TableKey::NoHash,
-					locked,
-					HEADER_ADDRESS,
-					&Operation::Set((), &entry.encoded.as_ref()[..HEADER_SIZE as usize]),
-					writer,
-					None,
-					false,
-				)?;
+				BTreeTable::write_header_plan(locked, &btree_header, writer)?;
 			}
 			#[cfg(test)]
-			tree.is_balanced(locked, writer)?;
+			debug_assert!(tree.is_balanced(locked, writer)?);
 			Ok(())
 		}
 	}
@@ -9,7 +9,7 @@ use super::{
 };
 use crate::{
 	column::Column,
-	error::Result,
+	error::{Error, Result},
 	index::Address,
 	log::{LogQuery, LogWriter},
 	table::key::TableKey,
@@ -17,6 +17,9 @@ use crate::{
 };
 use std::cmp::Ordering;
 
+/// Root address and depth of a tree, `None` if it has no keys.
+pub type Subtree = Option<(Address, u32)>;
+
 impl Node {
 	pub(crate) fn last_separator_index(&self) -> Option<usize> {
 		self.separators.iter().rposition(|separator| separator.separator.is_some())
@@ -471,6 +474,377 @@ impl Node {
 		}
 	}
 
+	/// Check if the subtree holds a key in `[start, end)`.
+	pub fn has_key_in_range(
+		&self,
+		depth: u32,
+		start: &[u8],
+		end: &[u8],
+		values: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<bool> {
+		let (at, i) = self.position(start)?;
+		if at {
+			return Ok(true)
+		}
+		if self
+			.separators
+			.get(i)
+			.and_then(|s| s.separator.as_ref())
+			.is_some_and(|s| s.key[..] < *end)
+		{
+			return Ok(true)
+		}
+		if depth == 0 {
+			return Ok(false)
+		}
+		match self.fetch_child(i, values, log)? {
+			Some(child) => child.has_key_in_range(depth - 1, start, end, values, log),
+			None => Ok(false),
+		}
+	}
+
+	/// Split the subtree of this node, written at `index`, into the trees of its keys before
+	/// `start` and of its keys from `end`. The keys in between are removed, with the nodes of
+	/// the subtrees in range.
+	#[allow(clippy::too_many_arguments)]
+	pub fn split_range(
+		self,
+		index: Address,
+		depth: u32,
+		start: &[u8],
+		end: &[u8],
+		values: TablesRef,
+		log: &mut LogWriter,
+		removed: &mut u64,
+	) -> Result<(Subtree, Subtree)> {
+		let (_, first) = self.position(start)?;
+		let (_, last) = self.position(end)?;
+		let (mut separators, mut children) = self.into_parts(depth != 0);
+		let mut right_separators = separators.split_off(last);
+		for separator in separators.drain(first..) {
+			Self::remove_value(separator, values, log)?;
+			*removed += 1;
+		}
+		if depth == 0 {
+			let left = Self::write_subtree(separators, children, 0, Some(index), values, log)?;
+			let right = Self::write_subtree(right_separators, Vec::new(), 0, None, values, log)?;
+			return Ok((left, right))
+		}
+
+		// Children `first` and `last` hold keys on both sides of `start` and `end`, the ones in
+		// between are in range.
+		let right_children = children.split_off(last + 1);
+		let last_child = children.pop();
+		let (left_inner, right_inner) = if first == last {
+			Self::split_child(last_child, depth, start, end, values, log, removed)?
+		} else {
+			let mut in_range = children.split_off(first);
+			let first_child = in_range.remove(0);
+			for child in in_range {
+				if let Some(index) = child.entry_index {
+					Self::remove_subtree(index, depth - 1, values, log, removed)?;
+				}
+			}
+			let (left, _) =
+				Self::split_child(Some(first_child), depth, start, end, values, log, removed)?;
+			let (_, right) =
+				Self::split_child(last_child, depth, start, end, values, log, removed)?;
+			(left, right)
+		};
+
+		let left = match separators.pop() {
+			Some(separator) => {
+				let tree =
+					Self::write_subtree(separators, children, depth, Some(index), values, log)?;
+				Self::join(tree, separator, left_inner, values, log)?
+			},
+			None => {
+				BTreeTable::write_plan_remove_node(values, log, index)?;
+				left_inner
+			},
+		};
+		let right = if right_separators.is_empty() {
+			right_inner
+		} else {
+			let separator = right_separators.remove(0);
+			let tree =
+				Self::write_subtree(right_separators, right_children, depth, None, values, log)?;
+			Self::join(right_inner, separator, tree, values, log)?
+		};
+		Ok((left, right))
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	fn split_child(
+		child: Option<Child>,
+		depth: u32,
+		start: &[u8],
+		end: &[u8],
+		values: TablesRef,
+		log: &mut LogWriter,
+		removed: &mut u64,
+	) -> Result<(Subtree, Subtree)> {
+		match child.and_then(|child| child.entry_index) {
+			Some(index) => {
+				let node = BTreeTable::get_encoded_entry(index, log, values)?;
+				Self::from_encoded(node, values.prefix_compression).split_range(
+					index,
+					depth - 1,
+					start,
+					end,
+					values,
+					log,
+					removed,
+				)
+			},
+			None => Err(Error::Corruption("A btree node is missing a child".into())),
+		}
+	}
+
+	// Remove the nodes and values of the subtree at `index`.
+	fn remove_subtree(
+		index: Address,
+		depth: u32,
+		values: TablesRef,
+		log: &mut LogWriter,
+		removed: &mut u64,
+	) -> Result<()> {
+		let node = BTreeTable::get_encoded_entry(index, log, values)?;
+		let (separators, children) =
+			Self::from_encoded(node, values.prefix_compression).into_parts(depth != 0);
+		for separator in separators {
+			Self::remove_value(separator, values, log)?;
+			*removed += 1;
+		}
+		for child in children {
+			if let Some(child) = child.entry_index {
+				Self::remove_subtree(child, depth - 1, values, log, removed)?;
+			}
+		}
+		BTreeTable::write_plan_remove_node(values, log, index)
+	}
+
+	fn remove_value(separator: Separator, values: TablesRef, log: &mut LogWriter) -> Result<()> {
+		if let Some(separator) = separator.separator {
+			Column::write_existing_value_plan::<_, Vec<u8>>(
+				&TableKey::NoHash,
+				values,
+				separator.value,
+				&Operation::Dereference(()),
+				log,
+				None,
+				false,
+			)?;
+		}
+		Ok(())
+	}
+
+	// Write a node with `separators` and `children` at `index`, or as a new node. A node
+	// without separators is removed and replaced by its child, if any.
+	fn write_subtree(
+		separators: Vec<Separator>,
+		children: Vec<Child>,
+		depth: u32,
+		index: Option<Address>,
+		values: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<Subtree> {
+		if separators.is_empty() {
+			if let Some(index) = index {
+				BTreeTable::write_plan_remove_node(values, log, index)?;
+			}
+			return Ok(children.first().and_then(|c| c.entry_index).map(|c| (c, depth - 1)))
+		}
+		let node = Self::from_parts(separators, children);
+		let new_index = BTreeTable::write_node_plan(values, node, log, index)?;
+		Ok(new_index.or(index).map(|index| (index, depth)))
+	}
+
+	/// Join the trees `left` and `right` with `separator`, a key between their keys. The
+	/// smaller tree is merged into the node at its depth along the side of the larger one.
+	pub fn join(
+		left: Subtree,
+		separator: Separator,
+		right: Subtree,
+		values: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<Subtree> {
+		let (mut left, left_index, left_depth) = Self::fetch_subtree(left, values, log)?;
+		let (mut right, right_index, right_depth) = Self::fetch_subtree(right, values, log)?;
+		let (root, index, depth, split) = if left_depth >= right_depth {
+			let split =
+				left.append(left_depth, separator, right, right_index, right_depth, values, log)?;
+			(left, left_index, left_depth, split)
+		} else {
+			let split =
+				right.prepend(right_depth, left, left_index, left_depth, separator, values, log)?;
+			(right, right_index, right_depth, split)
+		};
+		let index = BTreeTable::write_node_plan(values, root, log, index)?.or(index);
+		let index = index.expect("Nodes without an index are new and written");
+		Ok(Some(match split {
+			Some((separator, right)) => {
+				let mut root = Self::default();
+				root.set_child(0, Self::new_child(Some(index)));
+				root.set_child(1, right);
+				root.set_separator(0, separator);
+				let root = BTreeTable::write_node_plan(values, root, log, None)?;
+				(root.expect("New nodes are written"), depth + 1)
+			},
+			None => (index, depth),
+		}))
+	}
+
+	/// Remove the last key of the tree.
+	pub fn pop_last(
+		index: Address,
+		depth: u32,
+		values: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<(Subtree, Separator)> {
+		let mut root = BTree::fetch_root(index, values, log)?;
+		let (_, separator) = root.remove_last(depth, values, log)?;
+		let separator =
+			separator.ok_or_else(|| Error::Corruption("A btree node has no keys".into()))?;
+		let (separators, children) = root.into_parts(depth != 0);
+		let tree = Self::write_subtree(separators, children, depth, Some(index), values, log)?;
+		Ok((tree, separator))
+	}
+
+	fn fetch_subtree(
+		tree: Subtree,
+		values: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<(Self, Option<Address>, u32)> {
+		Ok(match tree {
+			Some((index, depth)) => (BTree::fetch_root(index, values, log)?, Some(index), depth),
+			None => (Self::default(), None, 0),
+		})
+	}
+
+	// Append `separator` and the tree `right`, with keys after the keys of this node, along the
+	// last children. Returns the separator and child to insert in the parent if the node is split.
+	#[allow(clippy::too_many_arguments)]
+	fn append(
+		&mut self,
+		depth: u32,
+		separator: Separator,
+		right: Self,
+		right_index: Option<Address>,
+		right_depth: u32,
+		values: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<Option<(Separator, Child)>> {
+		if depth == right_depth {
+			if let Some(index) = right_index {
+				BTreeTable::write_plan_remove_node(values, log, index)?;
+			}
+			let (node, split) = Self::concat(std::mem::take(self), separator, right, depth != 0);
+			*self = node;
+			return split
+				.map(|(separator, right)| {
+					Ok((separator, Self::write_split_child(None, right, values, log)?))
+				})
+				.transpose()
+		}
+		let last = self.number_separator();
+		let mut child = self
+			.fetch_child(last, values, log)?
+			.ok_or_else(|| Error::Corruption("A btree node is missing a child".into()))?;
+		let split =
+			child.append(depth - 1, separator, right, right_index, right_depth, values, log)?;
+		self.write_child(last, child, values, log)?;
+		match split {
+			Some((separator, right)) =>
+				self.insert_node(depth, last, separator, right, values, log),
+			None => Ok(None),
+		}
+	}
+
+	// Prepend the tree `left` and `separator`, with keys before the keys of this node, along the
+	// first children.
+	#[allow(clippy::too_many_arguments)]
+	fn prepend(
+		&mut self,
+		depth: u32,
+		left: Self,
+		left_index: Option<Address>,
+		left_depth: u32,
+		separator: Separator,
+		values: TablesRef,
+		log: &mut LogWriter,
+	) -> Result<Option<(Separator, Child)>> {
+		if depth == left_depth {
+			if let Some(index) = left_index {
+				BTreeTable::write_plan_remove_node(values, log, index)?;
+			}
+			let (node, split) = Self::concat(left, separator, std::mem::take(self), depth != 0);
+			*self = node;
+			return split
+				.map(|(separator, right)| {
+					Ok((separator, Self::write_split_child(None, right, values, log)?))
+				})
+				.transpose()
+		}
+		let mut child = self
+			.fetch_child(0, values, log)?
+			.ok_or_else(|| Error::Corruption("A btree node is missing a child".into()))?;
+		let split =
+			child.prepend(depth - 1, left, left_index, left_depth, separator, values, log)?;
+		self.write_child(0, child, values, log)?;
+		match split {
+			Some((separator, right)) => self.insert_node(depth, 0, separator, right, values, log),
+			None => Ok(None),
+		}
+	}
+
+	// Merge `left`, `separator` and `right`, nodes at the same depth. The merged node is split in
+	// two if it holds more than `ORDER` separators.
+	fn concat(
+		left: Self,
+		separator: Separator,
+		right: Self,
+		has_child: bool,
+	) -> (Self, Option<(Separator, Self)>) {
+		let (mut separators, mut children) = left.into_parts(has_child);
+		let (right_separators, right_children) = right.into_parts(has_child);
+		separators.push(separator);
+		separators.extend(right_separators);
+		children.extend(right_children);
+		if separators.len() <= ORDER {
+			return (Self::from_parts(separators, children), None)
+		}
+		let middle = separators.len() / 2;
+		let right_separators = separators.split_off(middle + 1);
+		let separator = separators.pop().expect("More than ORDER separators");
+		let right_children = if has_child { children.split_off(middle + 1) } else { Vec::new() };
+		let right = Self::from_parts(right_separators, right_children);
+		(Self::from_parts(separators, children), Some((separator, right)))
+	}
+
+	fn into_parts(mut self, has_child: bool) -> (Vec<Separator>, Vec<Child>) {
+		let count = self.number_separator();
+		let separators = self.separators[..count].iter_mut().map(std::mem::take).collect();
+		let children = if has_child {
+			self.children[..=count].iter_mut().map(std::mem::take).collect()
+		} else {
+			Vec::new()
+		};
+		(separators, children)
+	}
+
+	fn from_parts(separators: Vec<Separator>, children: Vec<Child>) -> Self {
+		let mut node = Self::default();
+		for (i, separator) in separators.into_iter().enumerate() {
+			node.set_separator(i, separator);
+		}
+		for (i, child) in children.into_iter().enumerate() {
+			node.set_child(i, child);
+		}
+		node
+	}
+
 	pub fn get(
 		&self,
 		key: &[u8],
@@ -526,6 +900,199 @@ impl Node {
 		}
 	}
 
+	/// Check the subtree: keys are ordered and within `(lower, upper)`, leaves are at depth 0
+	/// and all nodes and values can be read.
+	pub fn check(
+		&self,
+		tables: TablesRef,
+		log: &impl LogQuery,
+		depth: u32,
+		mut lower: Option<Vec<u8>>,
+		upper: Option<&[u8]>,
+		report: &mut ColumnCheck,
+	) -> Result<()> {
+		for i in 0..ORDER_CHILD {
+			let separator = self.separators.get(i).and_then(|s| s.separator.as_ref());
+			let child_upper = separator.map(|s| &s.key[..]).or(upper);
+			match self.children[i].entry_index {
+				Some(address) if depth == 0 =>
+					report.issues.push(CheckIssue::Unbalanced { address: address.as_u64() }),
+				Some(address) => match BTreeTable::get_encoded_entry(address, log, tables) {
+					Ok(child) => Self::from_encoded(child, tables.prefix_compression).check(
+						tables,
+						log,
+						depth - 1,
+						lower.clone(),
+						child_upper,
+						report,
+					)?,
+					Err(e) => report.issues.push(CheckIssue::BadNode {
+						address: address.as_u64(),
+						error: e.to_string(),
+					}),
+				},
+				None if depth != 0 && (i == 0 || self.has_separator(i - 1)) => {
+					let address = self.separators[i.saturating_sub(1)]
+						.separator
+						.as_ref()
+						.map_or(0, |s| s.value.as_u64());
+					report.issues.push(CheckIssue::Unbalanced { address });
+				},
+				None => (),
+			}
+			let separator = match separator {
+				Some(separator) => separator,
+				None => break,
+			};
+			if lower.as_ref().is_some_and(|l| l[..] >= separator.key[..]) ||
+				upper.is_some_and(|u| separator.key[..] >= *u)
+			{
+				report.issues.push(CheckIssue::UnorderedKey { key: separator.key.clone() });
+			}
+			match Column::get_value(TableKeyQuery::Fetch(None), separator.value, tables, log) {
+				Ok(Some((_, value))) => {
+					report.entries += 1;
+					report.bytes += value.len() as u64;
+				},
+				Ok(None) => report.issues.push(CheckIssue::BadNode {
+					address: separator.value.as_u64(),
+					error: "Missing value".into(),
+				}),
+				Err(e) => report.issues.push(CheckIssue::BadNode {
+					address: separator.value.as_u64(),
+					error: e.to_string(),
+				}),
+			}
+			lower = Some(separator.key.clone());
+		}
+		Ok(())
+	}
+
+	/// Call `f` with this node and, depth first, with the nodes below it that may hold keys in
+	/// `[start, end)`, along with their level and address. `end` of `None` is unbounded.
+	#[allow(clippy::too_many_arguments)]
+	pub fn walk(
+		&self,
+		tables: TablesRef,
+		log: &impl LogQuery,
+		address: Address,
+		depth: u32,
+		level: u32,
+		start: &[u8],
+		end: Option<&[u8]>,
+		lower: Option<&[u8]>,
+		upper: Option<&[u8]>,
+		f: &mut impl FnMut(u32, Address, &Node),
+	) -> Result<()> {
+		f(level, address, self);
+		if depth == 0 {
+			return Ok(())
+		}
+		let count = self.number_separator();
+		for i in 0..=count {
+			let child_lower = if i == 0 {
+				lower
+			} else {
+				self.separators[i - 1].separator.as_ref().map(|s| &s.key[..])
+			};
+			let child_upper = if i == count {
+				upper
+			} else {
+				self.separators[i].separator.as_ref().map(|s| &s.key[..])
+			};
+			if child_upper.is_none_or(|u| u > start) &&
+				child_lower.is_none_or(|l| end.is_none_or(|e| l < e))
+			{
+				if let Some(child_address) = self.children[i].entry_index {
+					let child = BTreeTable::get_encoded_entry(child_address, log, tables)?;
+					Self::from_encoded(child, tables.prefix_compression).walk(
+						tables,
+						log,
+						child_address,
+						depth - 1,
+						level + 1,
+						start,
+						end,
+						child_lower,
+						child_upper,
+						f,
+					)?;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Keys held by the node, in order.
+	pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+		self.separators.iter().map_while(|s| s.separator.as_ref().map(|s| &s.key[..]))
+	}
+
+	/// Sample the subtree for keys in `[start, end)`. Only nodes on the paths to `start` and
+	/// `end` are read, subtrees entirely in range are counted by depth.
+	#[allow(clippy::too_many_arguments)]
+	pub fn sample_range(
+		&self,
+		tables: TablesRef,
+		log: &impl LogQuery,
+		depth: u32,
+		start: &[u8],
+		end: &[u8],
+		lower: Option<&[u8]>,
+		upper: Option<&[u8]>,
+		sample: &mut RangeSample,
+	) -> Result<()> {
+		let count = self.number_separator();
+		sample.nodes += 1;
+		sample.separators += count as u64;
+		for i in 0..=count {
+			let child_lower = if i == 0 {
+				lower
+			} else {
+				self.separators[i - 1].separator.as_ref().map(|s| &s.key[..])
+			};
+			let child_upper = if i == count {
+				upper
+			} else {
+				self.separators[i].separator.as_ref().map(|s| &s.key[..])
+			};
+			// Keys of the child are within `(child_lower, child_upper)`.
+			if depth > 0 &&
+				child_upper.is_none_or(|u| u > start) &&
+				child_lower.is_none_or(|l| l < end)
+			{
+				let contained = child_lower.map_or(start.is_empty(), |l| l >= start) &&
+					child_upper.is_some_and(|u| u <= end);
+				if contained {
+					sample.subtrees[depth as usize - 1] += 1;
+				} else if let Some(child) = self.fetch_child(i, tables, log)? {
+					child.sample_range(
+						tables,
+						log,
+						depth - 1,
+						start,
+						end,
+						child_lower,
+						child_upper,
+						sample,
+					)?;
+				}
+			}
+			if let Some(separator) = self.separators.get(i).and_then(|s| s.separator.as_ref()) {
+				if separator.key[..] >= *start && separator.key[..] < *end {
+					sample.keys += 1;
+					sample.bytes += separator.key.len() as u64;
+					if let Some((_, value)) =
+						Column::get_value(TableKeyQuery::Fetch(None), separator.value, tables, log)?
+					{
+						sample.bytes += value.len() as u64;
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
 	#[cfg(test)]
 	pub fn is_balanced(
 		&self,
@@ -554,6 +1121,19 @@ impl Node {
 	}
 }
 
+/// Keys in range found by `Node::sample_range`.
+#[derive(Default)]
+pub struct RangeSample {
+	/// Keys in range read from the sampled nodes, and the size of their keys and values.
+	pub keys: u64,
+	pub bytes: u64,
+	/// Subtrees entirely in range, by depth of their root.
+	pub subtrees: Vec<u64>,
+	/// Sampled nodes and their separators.
+	pub nodes: u64,
+	pub separators: u64,
+}
+
 /// Nodes with data loaded in memory.
 /// Nodes get only serialized when flushed in the global overlay
 /// (there we need one entry per record id).
@@ -589,8 +1169,8 @@ pub struct Child {
 }
 
 impl Node {
-	pub fn from_encoded(enc: Vec<u8>) -> Self {
-		let mut entry = Entry::from_encoded(enc);
+	pub fn from_encoded(enc: Vec<u8>, prefix_compression: bool) -> Self {
+		let mut entry = Entry::from_encoded(enc).with_prefix_compression(prefix_compression);
 		let mut node =
 			Node { separators: Default::default(), children: Default::default(), changed: false };
 		let mut i_children = 0;
@@ -820,7 +1400,7 @@ impl Node {
 	) -> Result<Option<Self>> {
 		if let Some(ix) = self.children[i].entry_index {
 			let entry = BTreeTable::get_encoded_entry(ix, log, values)?;
-			return Ok(Some(Self::from_encoded(entry)))
+			return Ok(Some(Self::from_encoded(entry, values.prefix_compression)))
 		}
 		Ok(None)
 	}
@@ -0,0 +1,169 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Size bounded LRU caches of values and index chunks.
+
+use crate::{index::CHUNK_LEN, parking_lot::Mutex, table::Value, Key};
+use std::{
+	collections::{BTreeMap, HashMap},
+	hash::Hash,
+};
+
+// Approximate memory used by an entry besides the value, counted against the budget.
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Decompressed values of a hash indexed column.
+pub type ValueCache = LruCache<Key, Value>;
+/// On-disk contents of index chunks, by chunk index.
+pub type ChunkCache = LruCache<u64, [u8; CHUNK_LEN]>;
+
+#[derive(Debug)]
+struct Entries<K, V> {
+	values: HashMap<K, (V, usize, u64)>,
+	// Keys by last access, least recent first.
+	lru: BTreeMap<u64, K>,
+	tick: u64,
+	bytes: usize,
+	epoch: u64,
+}
+
+impl<K: Hash + Eq + Copy, V> Entries<K, V> {
+	fn remove(&mut self, key: &K) {
+		if let Some((_, size, tick)) = self.values.remove(key) {
+			self.lru.remove(&tick);
+			self.bytes -= size;
+		}
+	}
+}
+
+/// Least recently used entries, bounded by their total size.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+	budget: usize,
+	entries: Mutex<Entries<K, V>>,
+}
+
+impl<K: Hash + Eq + Copy, V: Clone> LruCache<K, V> {
+	pub fn new(budget: usize) -> Self {
+		LruCache {
+			budget,
+			entries: Mutex::new(Entries {
+				values: Default::default(),
+				lru: Default::default(),
+				tick: 0,
+				bytes: 0,
+				epoch: 0,
+			}),
+		}
+	}
+
+	pub fn get(&self, key: &K) -> Option<V> {
+		let mut entries = self.entries.lock();
+		let entries = &mut *entries;
+		let (value, _, tick) = entries.values.get_mut(key)?;
+		entries.tick += 1;
+		entries.lru.remove(tick);
+		entries.lru.insert(entries.tick, *key);
+		*tick = entries.tick;
+		Some(value.clone())
+	}
+
+	/// Insert a value of `size` bytes, evicting least recently used ones to stay within the
+	/// budget. Values larger than the budget are not cached.
+	pub fn insert(&self, key: K, value: V, size: usize) {
+		self.insert_inner(None, key, value, size)
+	}
+
+	/// Number of `invalidate` calls so far.
+	pub fn epoch(&self) -> u64 {
+		self.entries.lock().epoch
+	}
+
+	/// Same as `insert`, unless anything was invalidated since `epoch`. Lets readers cache
+	/// data read without holding a lock that excludes writers.
+	pub fn insert_unchanged(&self, epoch: u64, key: K, value: V, size: usize) {
+		self.insert_inner(Some(epoch), key, value, size)
+	}
+
+	fn insert_inner(&self, epoch: Option<u64>, key: K, value: V, size: usize) {
+		let size = size + ENTRY_OVERHEAD;
+		if size > self.budget {
+			return
+		}
+		let mut entries = self.entries.lock();
+		if epoch.is_some_and(|e| e != entries.epoch) {
+			return
+		}
+		entries.remove(&key);
+		while entries.bytes + size > self.budget {
+			match entries.lru.first_key_value() {
+				Some((_, oldest)) => {
+					let oldest = *oldest;
+					entries.remove(&oldest);
+				},
+				None => break,
+			}
+		}
+		entries.tick += 1;
+		let tick = entries.tick;
+		entries.lru.insert(tick, key);
+		entries.values.insert(key, (value, size, tick));
+		entries.bytes += size;
+	}
+
+	pub fn remove(&self, key: &K) {
+		self.entries.lock().remove(key);
+	}
+
+	/// Remove an entry and fail pending `insert_unchanged` calls.
+	pub fn invalidate(&self, key: &K) {
+		let mut entries = self.entries.lock();
+		entries.epoch += 1;
+		entries.remove(key);
+	}
+
+	pub fn clear(&self) {
+		let mut entries = self.entries.lock();
+		entries.epoch += 1;
+		entries.values.clear();
+		entries.lru.clear();
+		entries.bytes = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ValueCache, ENTRY_OVERHEAD};
+
+	#[test]
+	fn evicts_least_recently_used() {
+		let cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 10));
+		for i in 0..3u8 {
+			cache.insert([i; 32], vec![i; 10], 10);
+		}
+		assert_eq!(cache.get(&[0; 32]), Some(vec![0; 10]));
+		cache.insert([3; 32], vec![3; 10], 10);
+		assert_eq!(cache.get(&[1; 32]), None);
+		assert_eq!(cache.get(&[0; 32]), Some(vec![0; 10]));
+		assert_eq!(cache.get(&[3; 32]), Some(vec![3; 10]));
+
+		// Replacing a value accounts for the new size.
+		cache.insert([0; 32], vec![0; 20], 20);
+		assert_eq!(cache.get(&[2; 32]), None);
+		assert_eq!(cache.get(&[0; 32]), Some(vec![0; 20]));
+
+		cache.insert([4; 32], vec![4; 10 * ENTRY_OVERHEAD], 10 * ENTRY_OVERHEAD);
+		assert_eq!(cache.get(&[4; 32]), None);
+		cache.remove(&[3; 32]);
+		assert_eq!(cache.get(&[3; 32]), None);
+
+		let epoch = cache.epoch();
+		cache.invalidate(&[0; 32]);
+		cache.insert_unchanged(epoch, [5; 32], vec![5], 1);
+		assert_eq!(cache.get(&[5; 32]), None);
+		cache.insert_unchanged(cache.epoch(), [5; 32], vec![5], 1);
+		assert_eq!(cache.get(&[5; 32]), Some(vec![5]));
+		cache.clear();
+		assert_eq!(cache.get(&[5; 32]), None);
+	}
+}
@@ -2,16 +2,27 @@
 // This file is dual-licensed as Apache-2.0 or MIT.
 
 use crate::{
+	blob::BlobWriter,
+	bloom::BloomFilter,
 	btree::BTreeTable,
-	compress::Compress,
-	db::{check::CheckDisplay, Operation},
+	cache::ValueCache,
+	compress::{Compress, CompressionType},
+	db::{
+		check::{CheckDisplay, CheckIssue, ColumnCheck},
+		Operation,
+	},
 	display::hex,
-	error::{Error, Result},
-	index::{Address, IndexTable, PlanOutcome, TableId as IndexTableId},
+	error::{try_io, Error, Result},
+	file::TableIo,
+	index::{Address, IndexIo, IndexTable, PlanOutcome, TableId as IndexTableId},
 	log::{Log, LogAction, LogOverlays, LogQuery, LogReader, LogWriter},
-	options::{ColumnOptions, Metadata, Options, DEFAULT_COMPRESSION_THRESHOLD},
+	options::{AccessHint, ColumnOptions, KeyHash, Metadata, Options},
 	parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
-	stats::{ColumnStatSummary, ColumnStats},
+	reader::ValueReader,
+	stats::{
+		ColumnDiskUsage, ColumnStatCounters, ColumnStatSummary, ColumnStats, SpaceUsage, ValueMeta,
+	},
+	storage::Files,
 	table::{
 		key::{TableKey, TableKeyQuery},
 		TableId as ValueTableId, Value, ValueTable, SIZE_TIERS,
@@ -19,16 +30,25 @@ use crate::{
 	Key,
 };
 use std::{
+	borrow::Cow,
 	collections::VecDeque,
 	sync::{
-		atomic::{AtomicU64, Ordering},
-		Arc,
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+		Arc, OnceLock,
 	},
 };
 
 const MIN_INDEX_BITS: u8 = 16;
+/// Suffix of detached table files that are pending deletion.
+pub const DROPPED_FILE_SUFFIX: &str = ".dropped";
+// Columns may be replaced up to 256 times while the database is open.
+const MAX_COLUMN_ENTRIES: usize = 2 * (ColId::MAX as usize + 1);
 // Measured in index entries
 const MAX_REINDEX_BATCH: usize = 8192;
+// Minimum number of entries of a reindex batch to plan them on multiple threads.
+const MIN_PARALLEL_REINDEX_ENTRIES: usize = 1024;
+// Minimum size of the values of a commit to compress them on multiple threads.
+const MIN_PARALLEL_COMPRESSION_BYTES: usize = 64 * 1024;
 
 pub type ColId = u8;
 pub type Salt = [u8; 32];
@@ -97,12 +117,130 @@ pub struct HashColumn {
 	path: std::path::PathBuf,
 	preimage: bool,
 	uniform_keys: bool,
+	key_hash: KeyHash,
+	store_keys: bool,
 	collect_stats: bool,
 	ref_counted: bool,
 	salt: Salt,
-	stats: ColumnStats,
+	stats: ColumnStatCounters,
 	compression: Compress,
 	db_version: u32,
+	io: TableIo,
+	cache: Option<ValueCache>,
+	filter: Option<BloomFilter>,
+}
+
+/// List of columns, allowing columns to be added to or replaced in an open database.
+/// Replaced columns are kept allocated until the database is closed, so that references
+/// to them stay valid.
+#[derive(Debug)]
+pub struct Columns {
+	entries: Box<[OnceLock<(Column, ColumnOptions)>]>,
+	next_entry: AtomicUsize,
+	// Index of the current entry for each column.
+	slots: Box<[AtomicUsize]>,
+	dropped: Box<[AtomicBool]>,
+	len: AtomicUsize,
+}
+
+impl Columns {
+	pub fn new(columns: impl IntoIterator<Item = (Column, ColumnOptions)>) -> Self {
+		let entries: Box<[OnceLock<(Column, ColumnOptions)>]> =
+			(0..MAX_COLUMN_ENTRIES).map(|_| OnceLock::new()).collect();
+		let mut len = 0;
+		for column in columns {
+			let _ = entries[len].set(column);
+			len += 1;
+		}
+		Columns {
+			entries,
+			next_entry: AtomicUsize::new(len),
+			slots: (0..=ColId::MAX as usize).map(AtomicUsize::new).collect(),
+			dropped: (0..=ColId::MAX as usize).map(|_| AtomicBool::new(false)).collect(),
+			len: AtomicUsize::new(len),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len.load(Ordering::Acquire)
+	}
+
+	pub fn get(&self, col: usize) -> Option<&Column> {
+		self.get_with_options(col).map(|(column, _)| column)
+	}
+
+	fn get_with_options(&self, col: usize) -> Option<&(Column, ColumnOptions)> {
+		if col < self.len() {
+			self.entries[self.slots[col].load(Ordering::Acquire)].get()
+		} else {
+			None
+		}
+	}
+
+	/// Options of an existing column.
+	pub fn options(&self, col: ColId) -> &ColumnOptions {
+		&self.get_with_options(col as usize).expect("Invalid column").1
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Column> {
+		(0..self.len()).map(move |c| &self[c])
+	}
+
+	pub fn iter_options(&self) -> impl Iterator<Item = &ColumnOptions> {
+		(0..self.len()).map(move |c| self.options(c as ColId))
+	}
+
+	pub fn is_dropped(&self, col: ColId) -> bool {
+		self.dropped[col as usize].load(Ordering::Acquire)
+	}
+
+	/// Mark a column as dropped. Dropped columns keep their id until the database is reopened.
+	pub fn set_dropped(&self, col: ColId) {
+		self.dropped[col as usize].store(true, Ordering::Release);
+	}
+
+	pub fn dropped(&self) -> std::collections::BTreeSet<ColId> {
+		(0..self.len() as ColId).filter(|c| self.is_dropped(*c)).collect()
+	}
+
+	/// Append a column. Calls must be serialized by the caller.
+	pub fn push(&self, column: Column, options: ColumnOptions) -> Result<ColId> {
+		let col = self.len();
+		if col > ColId::MAX as usize {
+			return Err(Error::InvalidInput("Too many columns".into()))
+		}
+		let entry = self.insert_entry(column, options)?;
+		self.slots[col].store(entry, Ordering::Release);
+		self.len.store(col + 1, Ordering::Release);
+		Ok(col as ColId)
+	}
+
+	/// Replace an existing column. Calls must be serialized by the caller.
+	pub fn replace(&self, col: ColId, column: Column, options: ColumnOptions) -> Result<()> {
+		let entry = self.insert_entry(column, options)?;
+		self.slots[col as usize].store(entry, Ordering::Release);
+		Ok(())
+	}
+
+	fn insert_entry(&self, column: Column, options: ColumnOptions) -> Result<usize> {
+		let entry = self.next_entry.load(Ordering::Acquire);
+		if entry >= self.entries.len() {
+			return Err(Error::InvalidInput(
+				"Too many column changes, the database must be reopened".into(),
+			))
+		}
+		let _ = self.entries[entry].set((column, options));
+		self.next_entry.store(entry + 1, Ordering::Release);
+		Ok(entry)
+	}
+}
+
+impl std::ops::Index<usize> for Columns {
+	type Output = Column;
+
+	fn index(&self, col: usize) -> &Column {
+		self.get(col).expect("Invalid column")
+	}
 }
 
 #[derive(Clone, Copy)]
@@ -112,22 +250,30 @@ pub struct TablesRef<'a> {
 	pub col: ColId,
 	pub preimage: bool,
 	pub ref_counted: bool,
+	/// Btree node keys are prefix compressed, see `ColumnOptions::prefix_compression`.
+	pub prefix_compression: bool,
+	/// Value to write, already compressed with `Column::compress_value`.
+	pub compressed: Option<&'a Compressed>,
 }
 
+/// Result of `Column::compress_value`: the compressed value, or `None` to store it as is.
+pub type Compressed = Option<Vec<u8>>;
+
 pub struct IterState {
 	pub chunk_index: u64,
 	pub key: Key,
-	pub rc: u32,
+	pub rc: u64,
 	pub value: Vec<u8>,
 }
 
 enum IterStateOrCorrupted {
 	Item(IterState),
-	Corrupted(crate::index::Entry, Option<Error>),
+	// Chunk index, index entry and the error reading the value, if any.
+	Corrupted(u64, crate::index::Entry, Option<Error>),
 }
 
 #[inline]
-pub fn hash_key(key: &[u8], salt: &Salt, uniform: bool, db_version: u32) -> Key {
+pub fn hash_key(key: &[u8], salt: &Salt, uniform: bool, key_hash: KeyHash, db_version: u32) -> Key {
 	use blake2::{
 		digest::{typenum::U32, FixedOutput, Update},
 		Blake2bMac,
@@ -145,11 +291,32 @@ pub fn hash_key(key: &[u8], salt: &Salt, uniform: bool, db_version: u32) -> Key
 			}
 		}
 	} else {
-		let mut ctx = Blake2bMac::<U32>::new_with_salt_and_personal(salt, &[], &[])
-			.expect("Salt length (32) is a valid key length (<= 64)");
-		ctx.update(key);
-		let hash = ctx.finalize_fixed();
-		k.copy_from_slice(&hash);
+		match key_hash {
+			KeyHash::Blake2b => {
+				let mut ctx = Blake2bMac::<U32>::new_with_salt_and_personal(salt, &[], &[])
+					.expect("Salt length (32) is a valid key length (<= 64)");
+				ctx.update(key);
+				let hash = ctx.finalize_fixed();
+				k.copy_from_slice(&hash);
+			},
+			KeyHash::SipHash24 => {
+				#[allow(deprecated)]
+				use std::hash::{Hasher, SipHasher};
+				let k0 = u64::from_le_bytes(salt[0..8].try_into().expect("8 bytes"));
+				let k1 = u64::from_le_bytes(salt[8..16].try_into().expect("8 bytes"));
+				// 64-bit hashes with distinct keys fill the 32 bytes.
+				for (i, part) in k.chunks_mut(8).enumerate() {
+					#[allow(deprecated)]
+					let mut hasher = SipHasher::new_with_keys(k0 ^ i as u64, k1);
+					hasher.write(key);
+					part.copy_from_slice(&hasher.finish().to_be_bytes());
+				}
+			},
+			KeyHash::Identity => {
+				let len = key.len().min(k.len());
+				k[..len].copy_from_slice(&key[..len]);
+			},
+		}
 	}
 	k
 }
@@ -162,15 +329,123 @@ pub struct ReindexBatch {
 impl HashColumn {
 	pub fn get(&self, key: &Key, log: &impl LogQuery) -> Result<Option<Value>> {
 		let tables = self.tables.read();
+		self.get_in_tables(key, &tables, log)
+	}
+
+	/// Same as `get`, going through the value cache if enabled. Changes to the column must
+	/// invalidate the cache before they become visible.
+	pub fn get_cached(&self, key: &Key, log: &impl LogQuery) -> Result<Option<Value>> {
+		let cache = match &self.cache {
+			Some(cache) => cache,
+			None => return self.get(key, log),
+		};
+		if let Some(value) = cache.get(key) {
+			return Ok(Some(value))
+		}
+		let value = self.get(key, log)?;
+		if let Some(value) = &value {
+			cache.insert(*key, value.clone(), value.len());
+		}
+		Ok(value)
+	}
+
+	/// Same as `get`, along with the reference count of the value and how it is stored.
+	pub fn get_with_meta(
+		&self,
+		key: &Key,
+		log: &impl LogQuery,
+	) -> Result<Option<(Value, ValueMeta)>> {
+		let tables = self.tables.read();
+		self.find_in_tables(key, &tables, log, |address, values| {
+			Column::get_value_with_meta(&TableKey::Partial(*key), address, values, log)
+		})
+	}
+
+	pub fn cache(&self) -> Option<&ValueCache> {
+		self.cache.as_ref()
+	}
+
+	fn filtered_out(&self, key: &Key) -> bool {
+		self.filter.as_ref().is_some_and(|filter| !filter.may_contain(key))
+	}
+
+	/// Insert all keys of the index tables into the Bloom filter, if enabled.
+	pub fn build_filter(&self, log: &impl LogQuery) -> Result<()> {
+		let filter = match &self.filter {
+			Some(filter) => filter,
+			None => return Ok(()),
+		};
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			if !index.exists() {
+				continue
+			}
+			for chunk in 0..index.id.total_chunks() {
+				for entry in index.entries(chunk, log)? {
+					if !entry.is_empty() {
+						filter.insert(&index.recover_key_prefix(chunk, entry));
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn filter_bytes(&self) -> Option<Vec<u8>> {
+		self.filter.as_ref().map(BloomFilter::to_bytes)
+	}
+
+	fn restore_filter(&self, data: &[u8]) -> bool {
+		self.filter.as_ref().is_some_and(|filter| filter.restore(data))
+	}
+
+	/// Same as `get`, streaming values stored in their own file, see `ValueReader`.
+	pub fn get_reader(&self, key: &Key, log: &impl LogQuery) -> Result<Option<ValueReader>> {
+		if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(key)) {
+			return Ok(Some(ValueReader::memory(value)))
+		}
+		let tables = self.tables.read();
+		self.find_in_tables(key, &tables, log, |address, values| {
+			Column::get_value_reader(&TableKey::Partial(*key), address, values, log)
+		})
+	}
+
+	fn get_in_tables(
+		&self,
+		key: &Key,
+		tables: &Tables,
+		log: &impl LogQuery,
+	) -> Result<Option<Value>> {
+		self.find_in_tables(key, tables, log, |address, values| {
+			Column::get_value(TableKeyQuery::Check(&TableKey::Partial(*key)), address, values, log)
+		})
+	}
+
+	// Look up `key` in the index and the indexes being merged into it, passing the address of
+	// each entry found to `read` until it returns a value.
+	fn find_in_tables<T>(
+		&self,
+		key: &Key,
+		tables: &Tables,
+		log: &impl LogQuery,
+		read: impl Fn(Address, TablesRef) -> Result<Option<(u8, T)>>,
+	) -> Result<Option<T>> {
+		if self.filtered_out(key) {
+			if self.collect_stats {
+				self.stats.query_miss();
+			}
+			return Ok(None)
+		}
 		let values = self.as_ref(&tables.value);
-		if let Some((tier, value)) = self.get_in_index(key, &tables.index, values, log)? {
+		if let Some((tier, value)) = self.get_in_index(key, &tables.index, values, log, &read)? {
 			if self.collect_stats {
 				self.stats.query_hit(tier);
 			}
 			return Ok(Some(value))
 		}
 		for r in &self.reindex.read().queue {
-			if let Some((tier, value)) = self.get_in_index(key, r, values, log)? {
+			if let Some((tier, value)) = self.get_in_index(key, r, values, log, &read)? {
 				if self.collect_stats {
 					self.stats.query_hit(tier);
 				}
@@ -183,27 +458,127 @@ impl HashColumn {
 		Ok(None)
 	}
 
+	/// Query multiple keys at once. Keys are sorted, which matches index chunk order, so that
+	/// each index chunk is read once, and value reads are then sorted by value table and offset.
+	/// With `Options::io_uring` the entries of each round of value reads are read in a single
+	/// batch. Keys that are not resolved by their first index entry are tried with the next one
+	/// in the following round.
+	pub fn get_many(&self, keys: &[Key], log: &impl LogQuery) -> Result<Vec<Option<Value>>> {
+		let tables = self.tables.read();
+		let values = self.as_ref(&tables.value);
+
+		let mut order: Vec<usize> =
+			(0..keys.len()).filter(|i| !self.filtered_out(&keys[*i])).collect();
+		order.sort_unstable_by_key(|i| &keys[*i]);
+		// Addresses of the entries matching each key, in the index and then in the indexes
+		// being merged into it, the order `find_in_tables` tries them in.
+		let mut candidates = vec![Vec::new(); keys.len()];
+		for index in std::iter::once(&tables.index).chain(self.reindex.read().queue.iter()) {
+			let index_bits = index.id.index_bits();
+			index.get_sorted(order.iter().map(|i| &keys[*i]), log, |n, entry| {
+				candidates[order[n]].push(entry.address(index_bits))
+			})?;
+		}
+
+		let mut result = vec![None; keys.len()];
+		for round in 0.. {
+			let mut probes: Vec<(Address, usize)> = order
+				.iter()
+				.filter(|i| result[**i].is_none())
+				.filter_map(|i| candidates[*i].get(round).map(|address| (*address, *i)))
+				.collect();
+			if probes.is_empty() {
+				break
+			}
+			probes.sort_unstable_by_key(|(address, _)| (address.size_tier(), address.offset()));
+			let mut heads = Vec::new();
+			if self.io.uring {
+				for group in probes.chunk_by(|a, b| a.0.size_tier() == b.0.size_tier()) {
+					let offsets: Vec<_> =
+						group.iter().map(|(address, _)| address.offset()).collect();
+					let table = &tables.value[group[0].0.size_tier() as usize];
+					heads.extend(table.read_heads(&offsets, log)?);
+				}
+			}
+			for (n, (address, i)) in probes.into_iter().enumerate() {
+				let key_query = TableKeyQuery::Check(&TableKey::Partial(keys[i]));
+				let head = heads.get(n).filter(|h| !h.is_empty()).map(Vec::as_slice);
+				if let Some((tier, value)) =
+					Column::get_value_with_head(key_query, address, head, values, log)?
+				{
+					if self.collect_stats {
+						self.stats.query_hit(tier);
+					}
+					result[i] = Some(value);
+				}
+			}
+		}
+		if self.collect_stats {
+			for _ in result.iter().filter(|v| v.is_none()) {
+				self.stats.query_miss();
+			}
+		}
+		Ok(result)
+	}
+
+	/// Check if a value exists for the key. Only the index and value entry headers are read.
+	pub fn contains(&self, key: &Key, log: &impl LogQuery) -> Result<bool> {
+		if self.filtered_out(key) {
+			return Ok(false)
+		}
+		let tables = self.tables.read();
+		if self.contains_in_index(key, &tables.index, &tables, log)? {
+			return Ok(true)
+		}
+		for r in &self.reindex.read().queue {
+			if self.contains_in_index(key, r, &tables, log)? {
+				return Ok(true)
+			}
+		}
+		Ok(false)
+	}
+
+	fn contains_in_index(
+		&self,
+		key: &Key,
+		index: &IndexTable,
+		tables: &Tables,
+		log: &impl LogQuery,
+	) -> Result<bool> {
+		let (mut entry, mut sub_index) = index.get(key, 0, log)?;
+		while !entry.is_empty() {
+			let address = entry.address(index.id.index_bits());
+			let table_key = TableKey::Partial(*key);
+			if tables.value[address.size_tier() as usize].has_key_at(
+				address.offset(),
+				&table_key,
+				log,
+			)? {
+				return Ok(true)
+			}
+			let (next_entry, next_index) = index.get(key, sub_index + 1, log)?;
+			entry = next_entry;
+			sub_index = next_index;
+		}
+		Ok(false)
+	}
+
 	pub fn get_size(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<u32>> {
 		self.get(key, log).map(|v| v.map(|v| v.len() as u32))
 	}
 
-	fn get_in_index(
+	fn get_in_index<T>(
 		&self,
 		key: &Key,
 		index: &IndexTable,
 		tables: TablesRef,
 		log: &impl LogQuery,
-	) -> Result<Option<(u8, Value)>> {
+		read: &impl Fn(Address, TablesRef) -> Result<Option<(u8, T)>>,
+	) -> Result<Option<(u8, T)>> {
 		let (mut entry, mut sub_index) = index.get(key, 0, log)?;
 		while !entry.is_empty() {
 			let address = entry.address(index.id.index_bits());
-			let value = Column::get_value(
-				TableKeyQuery::Check(&TableKey::Partial(*key)),
-				address,
-				tables,
-				log,
-			)?;
-			match value {
+			match read(address, tables)? {
 				Some(result) => return Ok(Some(result)),
 				None => {
 					let (next_entry, next_index) = index.get(key, sub_index + 1, log)?;
@@ -221,21 +596,87 @@ impl HashColumn {
 			preimage: self.preimage,
 			col: self.col,
 			ref_counted: self.ref_counted,
+			prefix_compression: false,
 			compression: &self.compression,
+			compressed: None,
 		}
 	}
+
+	/// Compress the values set by `changes` ahead of `write_plan`, on up to `threads` threads.
+	/// Returns an empty list when there is too little to compress for the threads to pay off.
+	pub fn compress_values(
+		&self,
+		changes: &[Operation<Key, Vec<u8>>],
+		threads: usize,
+	) -> Vec<Option<Compressed>> {
+		let threshold = self.compression.threshold as usize;
+		let compressible = |change: &Operation<Key, Vec<u8>>| match change {
+			Operation::Set(_, value) if value.len() > threshold => Some(value.len()),
+			_ => None,
+		};
+		let bytes: usize = changes.iter().filter_map(compressible).sum();
+		if threads < 2 ||
+			bytes < MIN_PARALLEL_COMPRESSION_BYTES ||
+			CompressionType::from(&self.compression) == CompressionType::NoCompression
+		{
+			return Vec::new()
+		}
+		let mut result: Vec<Option<Compressed>> = changes.iter().map(|_| None).collect();
+		let chunk = changes.len().div_ceil(threads);
+		std::thread::scope(|s| {
+			for (changes, result) in changes.chunks(chunk).zip(result.chunks_mut(chunk)) {
+				s.spawn(move || {
+					for (change, result) in changes.iter().zip(result.iter_mut()) {
+						if let (Operation::Set(_, value), Some(_)) = (change, compressible(change))
+						{
+							*result = Some(Column::compress_value(&self.compression, value));
+						}
+					}
+				});
+			}
+		});
+		result
+	}
 }
 
 impl Column {
 	pub fn get_value(
+		key: TableKeyQuery,
+		address: Address,
+		tables: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<Option<(u8, Value)>> {
+		Self::get_value_with_head(key, address, None, tables, log)
+	}
+
+	/// Same as `get_value`, streaming values stored in their own file, see `ValueReader`.
+	pub fn get_value_reader(
+		key: &TableKey,
+		address: Address,
+		tables: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<Option<(u8, ValueReader)>> {
+		let size_tier = address.size_tier();
+		let table = &tables.tables[size_tier as usize];
+		if let Some((file, len, compressed)) = table.open_blob(key, address.offset(), log)? {
+			let reader = ValueReader::file(file, len, compressed, tables.compression)?;
+			return Ok(Some((size_tier, reader)))
+		}
+		let value = Self::get_value(TableKeyQuery::Check(key), address, tables, log)?;
+		Ok(value.map(|(tier, value)| (tier, ValueReader::memory(value))))
+	}
+
+	/// Same as `get_value`, with the first value entry already read.
+	pub fn get_value_with_head(
 		mut key: TableKeyQuery,
 		address: Address,
+		head: Option<&[u8]>,
 		tables: TablesRef,
 		log: &impl LogQuery,
 	) -> Result<Option<(u8, Value)>> {
 		let size_tier = address.size_tier() as usize;
 		if let Some((value, compressed, _rc)) =
-			tables.tables[size_tier].query(&mut key, address.offset(), log)?
+			tables.tables[size_tier].query_with_head(&mut key, address.offset(), head, log)?
 		{
 			let value = if compressed { tables.compression.decompress(&value)? } else { value };
 			return Ok(Some((size_tier as u8, value)))
@@ -243,25 +684,51 @@ impl Column {
 		Ok(None)
 	}
 
-	pub fn compress(
-		compression: &Compress,
+	/// Same as `get_value`, along with the reference count of the value and how it is stored.
+	pub fn get_value_with_meta(
 		key: &TableKey,
-		value: &[u8],
-		tables: &[ValueTable],
-	) -> (Option<Vec<u8>>, usize) {
-		let (len, result) = if value.len() > compression.threshold as usize {
+		address: Address,
+		tables: TablesRef,
+		log: &impl LogQuery,
+	) -> Result<Option<(u8, (Value, ValueMeta))>> {
+		let size_tier = address.size_tier();
+		let offset = address.offset();
+		let mut key = TableKeyQuery::Check(key);
+		if let Some((value, compressed, rc)) =
+			tables.tables[size_tier as usize].query(&mut key, offset, log)?
+		{
+			let value = if compressed { tables.compression.decompress(&value)? } else { value };
+			let meta = ValueMeta { rc, size_tier, compressed, offset };
+			return Ok(Some((size_tier, (value, meta))))
+		}
+		Ok(None)
+	}
+
+	/// Compressed `value`, if it is above the compression threshold and compression makes it
+	/// smaller.
+	pub fn compress_value(compression: &Compress, value: &[u8]) -> Compressed {
+		if value.len() > compression.threshold as usize {
 			let cvalue = compression.compress(value);
 			if cvalue.len() < value.len() {
-				(cvalue.len(), Some(cvalue))
-			} else {
-				(value.len(), None)
+				return Some(cvalue)
 			}
-		} else {
-			(value.len(), None)
+		}
+		None
+	}
+
+	pub fn compress<'a>(
+		tables: &TablesRef<'a>,
+		key: &TableKey,
+		value: &[u8],
+	) -> (Option<Cow<'a, [u8]>>, usize) {
+		let result = match tables.compressed {
+			Some(compressed) => compressed.as_deref().map(Cow::Borrowed),
+			None => Self::compress_value(tables.compression, value).map(Cow::Owned),
 		};
-		let target_tier = tables
-			.iter()
-			.position(|t| t.value_size(key).map_or(false, |s| len <= s as usize));
+		let len = result.as_ref().map_or(value.len(), |cvalue| cvalue.len());
+		let tables = tables.tables;
+		let target_tier =
+			tables.iter().position(|t| t.value_size(key).is_some_and(|s| len <= s as usize));
 		let target_tier = target_tier.unwrap_or_else(|| {
 			log::trace!(target: "parity-db", "Using blob {}", key);
 			tables.len() - 1
@@ -275,8 +742,18 @@ impl Column {
 		let arc_path = Arc::new(path.clone());
 		let column_options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
+		let io = TableIo::new(options, col);
 		let value = (0..SIZE_TIERS)
-			.map(|i| Self::open_table(arc_path.clone(), col, i as u8, column_options, db_version))
+			.map(|i| {
+				Self::open_table(
+					arc_path.clone(),
+					col,
+					i as u8,
+					column_options,
+					db_version,
+					io.clone(),
+				)
+			})
 			.collect::<Result<_>>()?;
 
 		if column_options.btree_index {
@@ -292,10 +769,16 @@ impl Column {
 		tier: u8,
 		options: &ColumnOptions,
 		db_version: u32,
+		io: TableIo,
 	) -> Result<ValueTable> {
 		let id = ValueTableId::new(col, tier);
 		let entry_size = SIZES.get(tier as usize).cloned();
-		ValueTable::open(path, id, entry_size, options, db_version)
+		ValueTable::open(path, id, entry_size, options, db_version, io)
+	}
+
+	/// Entry size of the value table for size tier `tier`.
+	pub fn value_entry_size(tier: u8) -> u16 {
+		SIZES.get(tier as usize).cloned().unwrap_or(crate::table::MULTIPART_ENTRY_SIZE)
 	}
 }
 
@@ -306,10 +789,17 @@ impl HashColumn {
 		options: &Options,
 		metadata: &Metadata,
 	) -> Result<HashColumn> {
-		let (index, reindexing, stats) = Self::open_index(&options.path, col)?;
+		let io = TableIo::new(options, col);
+		let col_options = &metadata.columns[col as usize];
+		let index_io = IndexIo {
+			hint: io.hint,
+			cache_bytes: col_options.index_cache_bytes,
+			files: io.files.clone(),
+			preallocate: io.preallocate,
+		};
+		let (index, reindexing, stats) = Self::open_index(&options.path, col, index_io)?;
 		let collect_stats = options.stats;
 		let path = &options.path;
-		let col_options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
 		Ok(HashColumn {
 			col,
@@ -318,6 +808,8 @@ impl HashColumn {
 			path: path.into(),
 			preimage: col_options.preimage,
 			uniform_keys: col_options.uniform,
+			key_hash: col_options.key_hash,
+			store_keys: col_options.store_keys,
 			ref_counted: col_options.ref_counted,
 			collect_stats,
 			salt: metadata.salt,
@@ -328,19 +820,30 @@ impl HashColumn {
 					.compression_threshold
 					.get(&col)
 					.copied()
-					.unwrap_or(DEFAULT_COMPRESSION_THRESHOLD),
-			),
+					.unwrap_or_else(|| col_options.default_compression_threshold()),
+			)
+			.with_dictionary(col_options.compression_dictionary.clone()),
 			db_version,
+			io,
+			cache: (col_options.value_cache_bytes > 0)
+				.then(|| ValueCache::new(col_options.value_cache_bytes)),
+			filter: (col_options.bloom_filter_bytes > 0)
+				.then(|| BloomFilter::new(col_options.bloom_filter_bytes)),
 		})
 	}
 
 	pub fn hash_key(&self, key: &[u8]) -> Key {
-		hash_key(key, &self.salt, self.uniform_keys, self.db_version)
+		hash_key(key, &self.salt, self.uniform_keys, self.key_hash, self.db_version)
 	}
 
 	pub fn flush(&self) -> Result<()> {
 		let tables = self.tables.read();
+		let start = self.io.slow_io.start();
 		tables.index.flush()?;
+		if start.is_some() {
+			let path = self.path.join(tables.index.id.file_name());
+			self.io.slow_io.check(start, "fsync", Some(&path), 0);
+		}
 		for t in tables.value.iter() {
 			t.flush()?;
 		}
@@ -350,13 +853,14 @@ impl HashColumn {
 	fn open_index(
 		path: &std::path::Path,
 		col: ColId,
-	) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStats)> {
+		io: IndexIo,
+	) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStatCounters)> {
 		let mut reindexing = VecDeque::new();
 		let mut top = None;
-		let mut stats = ColumnStats::empty();
+		let mut stats = ColumnStatCounters::empty();
 		for bits in (MIN_INDEX_BITS..65).rev() {
 			let id = IndexTableId::new(col, bits);
-			if let Some(table) = IndexTable::open_existing(path, id)? {
+			if let Some(table) = IndexTable::open_existing(path, id, io.clone())? {
 				if top.is_none() {
 					stats = table.load_stats()?;
 					top = Some(table);
@@ -367,7 +871,7 @@ impl HashColumn {
 		}
 		let table = match top {
 			Some(table) => table,
-			None => IndexTable::create_new(path, IndexTableId::new(col, MIN_INDEX_BITS)),
+			None => IndexTable::create_new(path, IndexTableId::new(col, MIN_INDEX_BITS), io),
 		};
 		Ok((table, reindexing, stats))
 	}
@@ -387,7 +891,7 @@ impl HashColumn {
 		// Start reindex
 		let new_index_id =
 			IndexTableId::new(tables.index.id.col(), tables.index.id.index_bits() + 1);
-		let new_table = IndexTable::create_new(path, new_index_id);
+		let new_table = IndexTable::create_new(path, new_index_id, tables.index.io());
 		let old_table = std::mem::replace(&mut tables.index, new_table);
 		reindex.queue.push_back(old_table);
 		(
@@ -407,6 +911,77 @@ impl HashColumn {
 		self.write_reindex_plan_locked(tables, reindex, key, address, log)
 	}
 
+	/// Plan reindexing `batch` on up to `threads` threads, each inserting into a disjoint range
+	/// of index chunks. Returns `true` if the index filled up and another reindex was started.
+	pub fn write_reindex_batch_plan(
+		&self,
+		mut batch: Vec<(Key, Address)>,
+		threads: usize,
+		log: &mut LogWriter,
+	) -> Result<bool> {
+		// Entries left for the calling thread, those of full chunks when planned in parallel.
+		let mut remaining = Vec::new();
+		if threads < 2 || batch.len() < MIN_PARALLEL_REINDEX_ENTRIES {
+			remaining = batch;
+		} else {
+			let tables = self.tables.read();
+			let index = &tables.index;
+			batch.sort_unstable_by_key(|(key, _)| index.key_chunk(key));
+			let mut ranges = Vec::new();
+			let mut start = 0;
+			while start < batch.len() {
+				let mut end = (start + batch.len().div_ceil(threads)).min(batch.len());
+				while end < batch.len() &&
+					index.key_chunk(&batch[end].0) == index.key_chunk(&batch[end - 1].0)
+				{
+					end += 1;
+				}
+				ranges.push(&batch[start..end]);
+				start = end;
+			}
+			std::thread::scope(|s| -> Result<()> {
+				let tables = &*tables;
+				let handles: Vec<_> =
+					ranges
+						.into_iter()
+						.map(|entries| {
+							let mut writer = log.fork();
+							s.spawn(move || -> Result<_> {
+								let mut full = Vec::new();
+								for (key, address) in entries {
+									if Self::search_index(key, &tables.index, tables, &writer)?
+										.is_some()
+									{
+										continue
+									}
+									if let PlanOutcome::NeedReindex = tables
+										.index
+										.write_insert_plan(key, *address, None, &mut writer)?
+									{
+										full.push((*key, *address));
+									}
+								}
+								Ok((writer, full))
+							})
+						})
+						.collect();
+				for handle in handles {
+					let (writer, full) = handle.join().expect("Reindex thread panicked")?;
+					log.merge(writer);
+					remaining.extend(full);
+				}
+				Ok(())
+			})?;
+		}
+		let mut next_reindex = false;
+		for (key, address) in remaining {
+			if let PlanOutcome::NeedReindex = self.write_reindex_plan(&key, address, log)? {
+				next_reindex = true;
+			}
+		}
+		Ok(next_reindex)
+	}
+
 	fn write_reindex_plan_locked<'a, 'b>(
 		&self,
 		tables: RwLockUpgradableReadGuard<'a, Tables>,
@@ -474,20 +1049,33 @@ impl HashColumn {
 		Ok(None)
 	}
 
+	/// Plan `change`. `compressed` is the value of a `Set`, if already compressed with
+	/// `compress_values`.
 	pub fn write_plan(
 		&self,
 		change: &Operation<Key, Vec<u8>>,
+		compressed: Option<&Compressed>,
 		log: &mut LogWriter,
 	) -> Result<PlanOutcome> {
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
 		let existing = Self::search_all_indexes(change.key(), &tables, &reindex, log)?;
 		if let Some((table, sub_index, existing_address)) = existing {
-			self.write_plan_existing(&tables, change, log, table, sub_index, existing_address)
+			self.write_plan_existing(
+				&tables,
+				change,
+				compressed,
+				log,
+				table,
+				sub_index,
+				existing_address,
+			)
 		} else {
 			match change {
 				Operation::Set(key, value) => {
-					let (r, _, _) = self.write_plan_new(tables, reindex, key, value, log)?;
+					let (r, tables, _) =
+						self.write_plan_new(tables, reindex, key, value, compressed, log)?;
+					tables.value[0].add_count(1);
 					Ok(r)
 				},
 				Operation::Dereference(key) => {
@@ -504,6 +1092,23 @@ impl HashColumn {
 					}
 					Ok(PlanOutcome::Skipped)
 				},
+				Operation::AddRc(key, delta) => {
+					log::trace!(target: "parity-db", "{}: Ignoring rc change, missing key {}", tables.index.id, hex(key));
+					if self.collect_stats {
+						if *delta > 0 {
+							self.stats.reference_increase_miss();
+						} else {
+							self.stats.remove_miss();
+						}
+					}
+					Ok(PlanOutcome::Skipped)
+				},
+				Operation::DeleteRange(..) =>
+					Err(Error::InvalidInput("Range deletion in a hash column".to_string())),
+				Operation::Merge(..) =>
+					Err(Error::InvalidInput("Unresolved merge in a hash column".to_string())),
+				Operation::SetWithTtl(..) =>
+					Err(Error::InvalidInput("Unencoded ttl value in a hash column".to_string())),
 			}
 		}
 	}
@@ -513,6 +1118,7 @@ impl HashColumn {
 		&self,
 		tables: &Tables,
 		change: &Operation<Key, Vec<u8>>,
+		compressed: Option<&Compressed>,
 		log: &mut LogWriter,
 		index: &IndexTable,
 		sub_index: usize,
@@ -524,7 +1130,7 @@ impl HashColumn {
 		let table_key = TableKey::Partial(*key);
 		match Column::write_existing_value_plan(
 			&table_key,
-			self.as_ref(&tables.value),
+			TablesRef { compressed, ..self.as_ref(&tables.value) },
 			existing_address,
 			change,
 			log,
@@ -541,6 +1147,7 @@ impl HashColumn {
 			(None, None) => {
 				log::trace!(target: "parity-db", "{}: Removing from index {}", tables.index.id, hex(key));
 				index.write_remove_plan(key, sub_index, log)?;
+				tables.value[0].add_count(-1);
 				Ok(PlanOutcome::Written)
 			},
 		}
@@ -552,6 +1159,7 @@ impl HashColumn {
 		reindex: RwLockUpgradableReadGuard<'b, Reindex>,
 		key: &Key,
 		value: &[u8],
+		compressed: Option<&Compressed>,
 		log: &mut LogWriter,
 	) -> Result<(
 		PlanOutcome,
@@ -559,10 +1167,13 @@ impl HashColumn {
 		RwLockUpgradableReadGuard<'b, Reindex>,
 	)> {
 		let stats = self.collect_stats.then_some(&self.stats);
+		if let Some(filter) = &self.filter {
+			filter.insert(key);
+		}
 		let table_key = TableKey::Partial(*key);
 		let address = Column::write_new_value_plan(
 			&table_key,
-			self.as_ref(&tables.value),
+			TablesRef { compressed, ..self.as_ref(&tables.value) },
 			value,
 			log,
 			stats,
@@ -571,7 +1182,8 @@ impl HashColumn {
 			PlanOutcome::NeedReindex => {
 				log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
 				let (tables, reindex) = Self::trigger_reindex(tables, reindex, self.path.as_path());
-				let (_, t, r) = self.write_plan_new(tables, reindex, key, value, log)?;
+				let (_, t, r) =
+					self.write_plan_new(tables, reindex, key, value, compressed, log)?;
 				Ok((PlanOutcome::NeedReindex, t, r))
 			},
 			_ => Ok((PlanOutcome::Written, tables, reindex)),
@@ -605,7 +1217,7 @@ impl HashColumn {
 			// This should never happen, unless something has modified the log file while the
 			// database is running. Existing logs should be validated with `validate_plan` on
 			// startup.
-			_ => return Err(Error::Corruption("Unexpected log action".into())),
+			_ => return Err(Error::LogCorruption("Unexpected log action".into())),
 		}
 		Ok(())
 	}
@@ -636,12 +1248,63 @@ impl HashColumn {
 			},
 			_ => {
 				log::error!(target: "parity-db", "Unexpected log action");
-				return Err(Error::Corruption("Unexpected log action".to_string()))
+				return Err(Error::LogCorruption("Unexpected log action".into()))
 			},
 		}
 		Ok(())
 	}
 
+	pub fn follow_plan(
+		&self,
+		action: LogAction,
+		log: &mut LogReader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		match action {
+			LogAction::InsertIndex(record) =>
+				if tables.index.id == record.table {
+					tables.index.follow_plan(record.index, log, writer)?;
+				} else if let Some(table) = reindex.queue.iter().find(|r| r.id == record.table) {
+					table.follow_plan(record.index, log, writer)?;
+				} else {
+					IndexTable::skip_plan(log)?;
+				},
+			LogAction::InsertValue(record) => {
+				tables.value[record.table.size_tier() as usize].follow_plan(
+					record.index,
+					log,
+					writer,
+				)?;
+			},
+			LogAction::DropTable(_) => (),
+			_ => return Err(Error::LogCorruption("Unexpected log action".into())),
+		}
+		Ok(())
+	}
+
+	// Pick up table files created, reindexed or dropped by another process.
+	fn refresh(&self) -> Result<()> {
+		let mut tables = self.tables.write();
+		let mut reindex = self.reindex.write();
+		for t in tables.value.iter() {
+			t.refresh()?;
+		}
+		tables.index.refresh()?;
+		for bits in (tables.index.id.index_bits() + 1..65).rev() {
+			let id = IndexTableId::new(self.col, bits);
+			if let Some(table) = IndexTable::open_existing(&self.path, id, tables.index.io())? {
+				log::debug!(target: "parity-db", "{}: Following reindex to {}", self.col, id);
+				let old_table = std::mem::replace(&mut tables.index, table);
+				reindex.queue.push_back(old_table);
+				break
+			}
+		}
+		reindex.queue.retain(|table| table.exists());
+		Ok(())
+	}
+
 	pub fn complete_plan(&self, log: &mut LogWriter) -> Result<()> {
 		let tables = self.tables.read();
 		for t in tables.value.iter() {
@@ -653,6 +1316,16 @@ impl HashColumn {
 		Ok(())
 	}
 
+	#[cfg(any(feature = "metrics", feature = "tracing"))]
+	pub fn col(&self) -> ColId {
+		self.col
+	}
+
+	fn column_stats(&self) -> ColumnStats {
+		let tables = self.tables.read();
+		self.stats.details(tables.value.iter().map(|t| t.occupancy()).collect())
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
 		let tables = self.tables.read();
 		for t in tables.value.iter() {
@@ -661,6 +1334,14 @@ impl HashColumn {
 		Ok(())
 	}
 
+	fn collect_blobs(&self) -> Result<()> {
+		let tables = self.tables.read();
+		for t in tables.value.iter() {
+			t.collect_blobs()?;
+		}
+		Ok(())
+	}
+
 	pub fn write_stats_text(&self, writer: &mut impl std::io::Write) -> Result<()> {
 		let tables = self.tables.read();
 		tables.index.write_stats(&self.stats)?;
@@ -680,10 +1361,71 @@ impl HashColumn {
 	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
 		let action = |state| match state {
 			IterStateOrCorrupted::Item(item) => Ok(f(item)),
-			IterStateOrCorrupted::Corrupted(..) =>
-				Err(Error::Corruption("Missing indexed value".into())),
+			IterStateOrCorrupted::Corrupted(..) => Err(Error::IndexCorruption {
+				col: self.col,
+				reason: "Missing indexed value".into(),
+			}),
 		};
-		self.iter_while_inner(log, action, 0, true)
+		self.iter_while_inner(log, action, 0, None, true)
+	}
+
+	/// Iterate over index entries, from `start_chunk` up to `end_chunk`, excluded. Unlike
+	/// `iter_while`, this always goes through the index, so that iteration may be resumed at
+	/// a given chunk.
+	pub fn iter_index_while(
+		&self,
+		log: &Log,
+		start_chunk: u64,
+		end_chunk: Option<u64>,
+		mut f: impl FnMut(IterState) -> bool,
+	) -> Result<()> {
+		let action = |state| match state {
+			IterStateOrCorrupted::Item(item) => Ok(f(item)),
+			IterStateOrCorrupted::Corrupted(..) => Err(Error::IndexCorruption {
+				col: self.col,
+				reason: "Missing indexed value".into(),
+			}),
+		};
+		self.iter_while_inner(log, action, start_chunk, end_chunk, false)
+	}
+
+	/// Estimate the number of keys from the occupancy of up to `max_chunks` evenly spaced
+	/// chunks of each index.
+	pub fn estimate_len(&self, log: &Log, max_chunks: u64) -> Result<u64> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		// Entries of the first index being reindexed are moved to the new index up to `progress`.
+		let progress = reindex.progress.load(Ordering::Relaxed);
+		let indexes = std::iter::once((&tables.index, 0)).chain(
+			reindex
+				.queue
+				.iter()
+				.enumerate()
+				.map(|(i, index)| (index, if i == 0 { progress } else { 0 })),
+		);
+		let mut total = 0;
+		for (index, start) in indexes {
+			let chunks = index.id.total_chunks().saturating_sub(start);
+			let step = (chunks / max_chunks.max(1)).max(1);
+			let (mut sampled, mut filled) = (0u64, 0u64);
+			let mut chunk = start;
+			while chunk < start + chunks {
+				let entries = index.entries(chunk, log.overlays())?;
+				filled += entries.iter().filter(|e| !e.is_empty()).count() as u64;
+				sampled += 1;
+				chunk += step;
+			}
+			if sampled > 0 {
+				total += (filled as u128 * chunks as u128 / sampled as u128) as u64;
+			}
+		}
+		Ok(total)
+	}
+
+	/// Current index size and whether reindexing is in progress.
+	pub fn index_state(&self) -> (u8, bool) {
+		let index_bits = self.tables.read().index.id.index_bits();
+		(index_bits, !self.reindex.read().queue.is_empty())
 	}
 
 	fn iter_while_inner(
@@ -691,6 +1433,7 @@ impl HashColumn {
 		log: &Log,
 		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
 		start_chunk: u64,
+		end_chunk: Option<u64>,
 		skip_preimage_indexes: bool,
 	) -> Result<()> {
 		use blake2::{digest::typenum::U32, Blake2b, Digest};
@@ -703,7 +1446,7 @@ impl HashColumn {
 			// We have to assume hashing scheme however.
 			for table in &tables.value[..tables.value.len() - 1] {
 				log::debug!( target: "parity-db", "{}: Iterating table {}", source.id, table.id);
-				table.iter_while(log.overlays(), |index, rc, value, compressed| {
+				table.iter_while(log.overlays(), |index, rc, _pk, value, compressed| {
 					let value = if compressed {
 						if let Ok(value) = self.compression.decompress(&value) {
 							value
@@ -727,7 +1470,9 @@ impl HashColumn {
 			}
 		}
 
-		for c in start_chunk..source.id.total_chunks() {
+		let end_chunk = end_chunk
+			.map_or(source.id.total_chunks(), |end| std::cmp::min(end, source.id.total_chunks()));
+		for c in start_chunk..end_chunk {
 			let entries = source.entries(c, log.overlays())?;
 			for entry in entries.iter() {
 				if entry.is_empty() {
@@ -753,17 +1498,27 @@ impl HashColumn {
 				let (value, rc, pk, compressed) = match value {
 					Ok(Some(v)) => v,
 					Ok(None) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, None))?;
+						f(IterStateOrCorrupted::Corrupted(c, *entry, None))?;
 						continue
 					},
 					Err(e) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, Some(e)))?;
+						f(IterStateOrCorrupted::Corrupted(c, *entry, Some(e)))?;
 						continue
 					},
 				};
 				let mut key = source.recover_key_prefix(c, *entry);
 				key[6..].copy_from_slice(&pk);
-				let value = if compressed { self.compression.decompress(&value)? } else { value };
+				let value = if compressed {
+					match self.compression.decompress(&value) {
+						Ok(value) => value,
+						Err(e) => {
+							f(IterStateOrCorrupted::Corrupted(c, *entry, Some(e)))?;
+							continue
+						},
+					}
+				} else {
+					value
+				};
 				log::debug!(
 					target: "parity-db",
 					"{}: Iterating at {}/{}, key={:?}, pk={:?}",
@@ -824,12 +1579,13 @@ impl HashColumn {
 					}
 					Ok(true)
 				},
-				IterStateOrCorrupted::Corrupted(entry, e) => {
+				IterStateOrCorrupted::Corrupted(_, entry, e) => {
 					log::info!("Corrupted value for index entry: {}:\n\t{:?}", entry.as_u64(), e);
 					Ok(true)
 				},
 			},
 			start_chunk,
+			None,
 			false,
 		)?;
 
@@ -837,6 +1593,280 @@ impl HashColumn {
 		Ok(())
 	}
 
+	fn check(
+		&self,
+		log: &Log,
+		check_param: &crate::CheckOptions,
+		report: &mut ColumnCheck,
+	) -> Result<()> {
+		self.iter_while_inner(
+			log,
+			|state| {
+				match state {
+					IterStateOrCorrupted::Item(IterState { chunk_index, key, value, .. }) => {
+						report.entries += 1;
+						report.bytes += value.len() as u64;
+						if self.store_keys {
+							// Index entries only keep the key hash past the first 6 bytes.
+							match crate::db::split_keyed(&value) {
+								Some((stored, _)) if self.hash_key(stored)[6..] == key[6..] => (),
+								stored => report.issues.push(CheckIssue::BadStoredKey {
+									chunk: chunk_index,
+									key: stored.map(|(key, _)| key.to_vec()),
+								}),
+							}
+						}
+					},
+					IterStateOrCorrupted::Corrupted(chunk, entry, e) => {
+						report.issues.push(CheckIssue::BadIndexEntry {
+							chunk,
+							entry: entry.as_u64(),
+							error: e.map(|e| e.to_string()),
+						});
+					},
+				}
+				Ok(true)
+			},
+			check_param.from.unwrap_or(0),
+			check_param.bound,
+			false,
+		)
+	}
+
+	// Remove index entries that point to unreadable values. For `preimage` columns, also index
+	// values that are missing from the index, assuming keys are blake2 hashes of the values.
+	// Returns the number of removed and restored entries.
+	fn repair(
+		&self,
+		log: &Log,
+		writer: &mut LogWriter,
+		report: &mut ColumnCheck,
+	) -> Result<(u64, u64)> {
+		use blake2::{digest::typenum::U32, Blake2b, Digest};
+
+		self.check(log, &crate::CheckOptions::new(None, None, None, false, None), report)?;
+		let tables = self.tables.read();
+		let index = &tables.index;
+		let mut removed = 0;
+		for issue in &report.issues {
+			if let CheckIssue::BadIndexEntry { chunk, entry, .. } = issue {
+				let entries = index.entries(*chunk, &*writer)?;
+				if let Some(sub_index) = entries.iter().position(|e| e.as_u64() == *entry) {
+					let key = index.recover_key_prefix(*chunk, entries[sub_index]);
+					if matches!(
+						index.write_remove_plan(&key, sub_index, writer)?,
+						PlanOutcome::Written
+					) {
+						log::warn!(target: "parity-db", "{}: Removed bad index entry {}.{}", index.id, chunk, sub_index);
+						removed += 1;
+					}
+				}
+			}
+		}
+		let mut restored = 0;
+		if self.preimage {
+			// The last table holds multipart values, which are not iterated.
+			for (tier, table) in tables.value[..tables.value.len() - 1].iter().enumerate() {
+				let mut result = Ok(());
+				let iterated =
+					table.iter_while(log.overlays(), |offset, _rc, pk, value, compressed| {
+						let value = match (compressed, &self.compression) {
+							(false, _) => value,
+							(true, compression) => match compression.decompress(&value) {
+								Ok(value) => value,
+								Err(_) => return true,
+							},
+						};
+						let key = self.hash_key(&Blake2b::<U32>::digest(&value));
+						if key[6..] != pk {
+							return true
+						}
+						let address = Address::new(offset, tier as u8);
+						result = self.contains_in_index(&key, index, &tables, &*writer).and_then(
+							|found| {
+								if !found &&
+									matches!(
+										index.write_insert_plan(&key, address, None, writer)?,
+										PlanOutcome::Written
+									) {
+									log::warn!(target: "parity-db", "{}: Restored index entry for {}", index.id, address);
+									restored += 1;
+								}
+								Ok(())
+							},
+						);
+						result.is_ok()
+					});
+				if let Err(e) = iterated {
+					log::warn!(target: "parity-db", "{}: Stopped restoring index entries: {}", table.id, e);
+				}
+				result?;
+			}
+		}
+		Ok((removed, restored))
+	}
+
+	/// Number of chunks in the current index table.
+	pub fn index_chunks(&self) -> u64 {
+		self.tables.read().index.id.total_chunks()
+	}
+
+	/// Collect values stored past `ends`, the end of each value table if it was dense, starting
+	/// at index chunk `start_chunk`, until at least `max` are found. Returns their keys and
+	/// addresses, and the next chunk to check.
+	pub fn relocation_candidates(
+		&self,
+		log: &Log,
+		start_chunk: u64,
+		ends: &[u64],
+		max: usize,
+	) -> Result<(Vec<(Key, Address)>, u64)> {
+		let tables = self.tables.read();
+		let index = &tables.index;
+		let mut candidates = Vec::new();
+		let mut chunk = start_chunk;
+		while chunk < index.id.total_chunks() && candidates.len() < max {
+			for entry in index.entries(chunk, log.overlays())?.iter() {
+				if entry.is_empty() {
+					continue
+				}
+				let address = entry.address(index.id.index_bits());
+				let tier = address.size_tier() as usize;
+				if ends.get(tier).is_none_or(|end| address.offset() < *end) {
+					continue
+				}
+				if let Some(pk) =
+					tables.value[tier].partial_key_at(address.offset(), log.overlays())?
+				{
+					let mut key = index.recover_key_prefix(chunk, *entry);
+					key[6..].copy_from_slice(&pk);
+					candidates.push((key, address));
+				}
+			}
+			chunk += 1;
+		}
+		Ok((candidates, chunk))
+	}
+
+	/// Move the value of `key` to a lower entry of its value table, if it is still stored at
+	/// `address`. Returns `false` if the value was not moved.
+	pub fn write_relocation_plan(
+		&self,
+		key: &Key,
+		address: Address,
+		log: &mut LogWriter,
+	) -> Result<bool> {
+		let tables = self.tables.read();
+		let sub_index = match Self::search_index(key, &tables.index, &tables, log)? {
+			Some((_, sub_index, existing)) if existing == address => sub_index,
+			_ => return Ok(false),
+		};
+		let tier = address.size_tier();
+		let table_key = TableKey::Partial(*key);
+		match tables.value[tier as usize].write_relocate_plan(address.offset(), &table_key, log)? {
+			Some(offset) => {
+				tables.index.write_insert_plan(
+					key,
+					Address::new(offset, tier),
+					Some(sub_index),
+					log,
+				)?;
+				Ok(true)
+			},
+			None => Ok(false),
+		}
+	}
+
+	/// Create a blob for a value written in pieces, see `Db::begin_value`. Returns `None` if
+	/// the column does not store blobs.
+	pub fn create_blob_writer(&self) -> Result<Option<BlobWriter>> {
+		match self.tables.read().value.last() {
+			Some(table) => table.create_blob_writer(),
+			None => Ok(None),
+		}
+	}
+
+	/// Remove a blob created with `create_blob_writer` that will not be referenced.
+	pub fn discard_blob(&self, id: u64) -> Result<()> {
+		match self.tables.read().value.last() {
+			Some(table) => table.discard_blob(id),
+			None => Ok(()),
+		}
+	}
+
+	/// Plan setting `key` to blob `id` of `len` bytes, created with `create_blob_writer`.
+	/// `value_len` is the length of the value. Returns `false` along with the outcome if the
+	/// blob is not referenced, e.g. when the reference count of an existing value is increased
+	/// instead.
+	pub fn write_blob_plan(
+		&self,
+		key: &Key,
+		id: u64,
+		len: u64,
+		value_len: u64,
+		log: &mut LogWriter,
+	) -> Result<(PlanOutcome, bool)> {
+		let tables = self.tables.upgradable_read();
+		let reindex = self.reindex.upgradable_read();
+		let stats = self.collect_stats.then_some(&self.stats);
+		let tier = tables.value.len() - 1;
+		let table_key = TableKey::Partial(*key);
+		let value_len = value_len as u32;
+		let threshold = self.compression.threshold;
+		if let Some((index, sub_index, address)) =
+			Self::search_all_indexes(key, &tables, &reindex, log)?
+		{
+			let current = &tables.value[address.size_tier() as usize];
+			if self.ref_counted {
+				log::trace!(target: "parity-db", "{}: Increment ref {}", self.col, table_key);
+				current.write_inc_ref(address.offset(), log)?;
+				return Ok((PlanOutcome::Written, false))
+			}
+			if self.preimage {
+				return Ok((PlanOutcome::Skipped, false))
+			}
+			if let Some(stats) = stats {
+				let (cur_size, uncompressed) =
+					Column::stored_size(&table_key, &self.as_ref(&tables.value), address, log)?;
+				stats.replace_val(uncompressed, cur_size, value_len, value_len, threshold);
+			}
+			if address.size_tier() as usize == tier {
+				tables.value[tier].write_blob_plan(
+					Some(address.offset()),
+					&table_key,
+					id,
+					len,
+					log,
+				)?;
+				return Ok((PlanOutcome::Written, true))
+			}
+			current.write_remove_plan(address.offset(), log)?;
+			let offset = tables.value[tier].write_blob_plan(None, &table_key, id, len, log)?;
+			let sub_index = if index.id == tables.index.id { Some(sub_index) } else { None };
+			let address = Address::new(offset, tier as u8);
+			return Ok((tables.index.write_insert_plan(key, address, sub_index, log)?, true))
+		}
+		if let Some(filter) = &self.filter {
+			filter.insert(key);
+		}
+		let offset = tables.value[tier].write_blob_plan(None, &table_key, id, len, log)?;
+		if let Some(stats) = stats {
+			stats.insert_val(value_len, value_len, threshold);
+		}
+		tables.value[0].add_count(1);
+		let address = Address::new(offset, tier as u8);
+		let (mut tables, mut reindex) = (tables, reindex);
+		let mut outcome = PlanOutcome::Written;
+		while let PlanOutcome::NeedReindex =
+			tables.index.write_insert_plan(key, address, None, log)?
+		{
+			log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
+			(tables, reindex) = Self::trigger_reindex(tables, reindex, self.path.as_path());
+			outcome = PlanOutcome::NeedReindex;
+		}
+		Ok((outcome, true))
+	}
+
 	pub fn reindex(&self, log: &Log) -> Result<ReindexBatch> {
 		let tables = self.tables.read();
 		let reindex = self.reindex.read();
@@ -846,7 +1876,7 @@ impl HashColumn {
 			let progress = reindex.progress.load(Ordering::Relaxed);
 			if progress != source.id.total_chunks() {
 				let mut source_index = progress;
-				if source_index % 500 == 0 {
+				if source_index.is_multiple_of(500) {
 					log::debug!(target: "parity-db", "{}: Reindexing at {}/{}", tables.index.id, source_index, source.id.total_chunks());
 				}
 				log::debug!(target: "parity-db", "{}: Continue reindex at {}/{}", tables.index.id, source_index, source.id.total_chunks());
@@ -874,10 +1904,43 @@ impl HashColumn {
 		Ok(ReindexBatch { drop_index, batch: plan })
 	}
 
+	fn clear(
+		&self,
+		open_tables: impl FnOnce() -> Result<Vec<ValueTable>>,
+		discard: &mut dyn FnMut(std::path::PathBuf) -> Result<()>,
+	) -> Result<()> {
+		let mut tables = self.tables.write();
+		let mut reindex = self.reindex.write();
+		if let Some(cache) = &self.cache {
+			cache.clear();
+		}
+		if let Some(filter) = &self.filter {
+			filter.clear();
+		}
+		for index in reindex.queue.drain(..) {
+			discard(index.close())?;
+		}
+		reindex.progress.store(0, Ordering::Relaxed);
+		let io = tables.index.io();
+		let index = std::mem::replace(
+			&mut tables.index,
+			IndexTable::create_new(&self.path, IndexTableId::new(self.col, MIN_INDEX_BITS), io),
+		);
+		discard(index.close())?;
+		for table in std::mem::take(&mut tables.value) {
+			for path in table.close()? {
+				discard(path)?;
+			}
+		}
+		tables.value = open_tables()?;
+		self.stats.clear();
+		Ok(())
+	}
+
 	pub fn drop_index(&self, id: IndexTableId) -> Result<()> {
 		log::debug!(target: "parity-db", "Dropping {}", id);
 		let mut reindex = self.reindex.write();
-		if reindex.queue.front_mut().map_or(false, |index| index.id == id) {
+		if reindex.queue.front_mut().is_some_and(|index| index.id == id) {
 			let table = reindex.queue.pop_front();
 			reindex.progress.store(0, Ordering::Relaxed);
 			table.unwrap().drop_file()?;
@@ -885,6 +1948,14 @@ impl HashColumn {
 			log::warn!(target: "parity-db", "Dropping invalid index {}", id);
 			return Ok(())
 		}
+		drop(reindex);
+		if self.io.hint == AccessHint::DontNeed {
+			let tables = self.tables.read();
+			tables.index.drop_cache();
+			for table in &tables.value {
+				table.drop_cache()?;
+			}
+		}
 		log::debug!(target: "parity-db", "Dropped {}", id);
 		Ok(())
 	}
@@ -897,29 +1968,20 @@ impl Column {
 		address: Address,
 		change: &Operation<K, V>,
 		log: &mut LogWriter,
-		stats: Option<&ColumnStats>,
+		stats: Option<&ColumnStatCounters>,
 		ref_counted: bool,
 	) -> Result<(Option<PlanOutcome>, Option<Address>)> {
 		let tier = address.size_tier() as usize;
 
-		let fetch_size = || -> Result<(u32, u32)> {
-			let (cur_size, compressed) =
-				tables.tables[tier].size(key, address.offset(), log)?.unwrap_or((0, false));
-			Ok(if compressed {
-				// This is very costly.
-				let compressed = tables.tables[tier]
-					.get(key, address.offset(), log)?
-					.expect("Same query as size")
-					.0;
-				let uncompressed = tables.compression.decompress(compressed.as_slice())?;
-
-				(cur_size, uncompressed.len() as u32)
-			} else {
-				(cur_size, cur_size)
-			})
-		};
+		let fetch_size = || Column::stored_size(key, &tables, address, log);
 
 		match change {
+			Operation::DeleteRange(..) =>
+				Err(Error::InvalidInput("Range deletion of an existing value".to_string())),
+			Operation::Merge(..) =>
+				Err(Error::InvalidInput("Unresolved merge of an existing value".to_string())),
+			Operation::SetWithTtl(..) =>
+				Err(Error::InvalidInput("Unencoded ttl value of an existing value".to_string())),
 			Operation::Reference(_) =>
 				if ref_counted {
 					log::trace!(target: "parity-db", "{}: Increment ref {}", tables.col, key);
@@ -931,6 +1993,16 @@ impl Column {
 				} else {
 					Ok((Some(PlanOutcome::Skipped), None))
 				},
+			Operation::AddRc(_, delta) if !ref_counted || *delta == 0 =>
+				Ok((Some(PlanOutcome::Skipped), None)),
+			Operation::AddRc(_, delta) if *delta > 0 => {
+				log::trace!(target: "parity-db", "{}: Increase ref {} by {}", tables.col, key, delta);
+				tables.tables[tier].write_change_ref(address.offset(), *delta, log)?;
+				if let Some(stats) = stats {
+					stats.reference_increase();
+				}
+				Ok((Some(PlanOutcome::Written), None))
+			},
 			Operation::Set(_, val) => {
 				if ref_counted {
 					log::trace!(target: "parity-db", "{}: Increment ref {}", tables.col, key);
@@ -942,20 +2014,18 @@ impl Column {
 					return Ok((Some(PlanOutcome::Skipped), None))
 				}
 
-				let (cval, target_tier) =
-					Column::compress(tables.compression, key, val.as_ref(), tables.tables);
-				let (cval, compressed) = cval
-					.as_ref()
-					.map(|cval| (cval.as_slice(), true))
-					.unwrap_or((val.as_ref(), false));
+				let (cval, target_tier) = Column::compress(&tables, key, val.as_ref());
+				let (cval, compressed) =
+					cval.as_deref().map(|cval| (cval, true)).unwrap_or((val.as_ref(), false));
 
 				if let Some(stats) = stats {
 					let (cur_size, uncompressed) = fetch_size()?;
 					stats.replace_val(
-						cur_size,
 						uncompressed,
+						cur_size,
 						val.as_ref().len() as u32,
 						cval.len() as u32,
+						tables.compression.threshold,
 					);
 				}
 				if tier == target_tier {
@@ -977,11 +2047,13 @@ impl Column {
 					Ok((None, Some(new_address)))
 				}
 			},
-			Operation::Dereference(_) => {
+			Operation::Dereference(_) | Operation::AddRc(..) => {
 				// Deletion
 				let cur_size = if stats.is_some() { Some(fetch_size()?) } else { None };
 				let remove = if ref_counted {
-					let removed = !tables.tables[tier].write_dec_ref(address.offset(), log)?;
+					let delta = if let Operation::AddRc(_, delta) = change { *delta } else { -1 };
+					let removed =
+						!tables.tables[tier].write_change_ref(address.offset(), delta, log)?;
 					log::trace!(target: "parity-db", "{}: Dereference {}, deleted={}", tables.col, key, removed);
 					removed
 				} else {
@@ -992,7 +2064,11 @@ impl Column {
 				if remove {
 					if let Some((compressed_size, uncompressed_size)) = cur_size {
 						if let Some(stats) = stats {
-							stats.remove_val(uncompressed_size, compressed_size);
+							stats.remove_val(
+								uncompressed_size,
+								compressed_size,
+								tables.compression.threshold,
+							);
 						}
 					}
 					Ok((None, None))
@@ -1003,23 +2079,46 @@ impl Column {
 		}
 	}
 
+	// Stored and uncompressed size of the value at `address`.
+	fn stored_size(
+		key: &TableKey,
+		tables: &TablesRef,
+		address: Address,
+		log: &LogWriter,
+	) -> Result<(u32, u32)> {
+		let tier = address.size_tier() as usize;
+		let (cur_size, compressed) =
+			tables.tables[tier].size(key, address.offset(), log)?.unwrap_or((0, false));
+		Ok(if compressed {
+			// This is very costly.
+			let compressed = tables.tables[tier]
+				.get(key, address.offset(), log)?
+				.expect("Same query as size")
+				.0;
+			let uncompressed = tables.compression.decompress(compressed.as_slice())?;
+
+			(cur_size, uncompressed.len() as u32)
+		} else {
+			(cur_size, cur_size)
+		})
+	}
+
 	pub fn write_new_value_plan(
 		key: &TableKey,
 		tables: TablesRef,
 		val: &[u8],
 		log: &mut LogWriter,
-		stats: Option<&ColumnStats>,
+		stats: Option<&ColumnStatCounters>,
 	) -> Result<Address> {
-		let (cval, target_tier) = Column::compress(tables.compression, key, val, tables.tables);
-		let (cval, compressed) =
-			cval.as_ref().map(|cval| (cval.as_slice(), true)).unwrap_or((val, false));
+		let (cval, target_tier) = Column::compress(&tables, key, val);
+		let (cval, compressed) = cval.as_deref().map(|cval| (cval, true)).unwrap_or((val, false));
 
 		log::trace!(target: "parity-db", "{}: Inserting new {}, size = {}", tables.col, key, cval.len());
 		let offset = tables.tables[target_tier].write_insert_plan(key, cval, log, compressed)?;
 		let address = Address::new(offset, target_tier as u8);
 
 		if let Some(stats) = stats {
-			stats.insert_val(val.len() as u32, cval.len() as u32);
+			stats.insert_val(val.len() as u32, cval.len() as u32, tables.compression.threshold);
 		}
 		Ok(address)
 	}
@@ -1045,6 +2144,27 @@ impl Column {
 		}
 	}
 
+	/// Read a logged change into `writer` instead of enacting it.
+	pub fn follow_plan(
+		&self,
+		action: LogAction,
+		log: &mut LogReader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.follow_plan(action, log, writer),
+			Column::Tree(column) => column.follow_plan(action, log, writer),
+		}
+	}
+
+	/// Pick up changes to the set of table files made by another process.
+	pub fn refresh(&self) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.refresh(),
+			Column::Tree(column) => column.refresh(),
+		}
+	}
+
 	pub fn flush(&self) -> Result<()> {
 		match self {
 			Column::Hash(column) => column.flush(),
@@ -1059,6 +2179,91 @@ impl Column {
 		}
 	}
 
+	/// Remove value files that are no longer used, see `ColumnOptions::blob_threshold`.
+	pub fn collect_blobs(&self) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.collect_blobs(),
+			Column::Tree(_) => Ok(()),
+		}
+	}
+
+	pub fn build_filter(&self, log: &impl LogQuery) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.build_filter(log),
+			Column::Tree(_) => Ok(()),
+		}
+	}
+
+	/// Contents of the Bloom filter, if enabled.
+	pub fn filter_bytes(&self) -> Option<Vec<u8>> {
+		match self {
+			Column::Hash(column) => column.filter_bytes(),
+			Column::Tree(_) => None,
+		}
+	}
+
+	/// Restore the Bloom filter saved with `filter_bytes`, instead of building it. Returns
+	/// `false` if the filter is not enabled or its size changed.
+	pub fn restore_filter(&self, data: &[u8]) -> bool {
+		match self {
+			Column::Hash(column) => column.restore_filter(data),
+			Column::Tree(_) => false,
+		}
+	}
+
+	pub fn column_stats(&self) -> ColumnStats {
+		match self {
+			Column::Hash(column) => column.column_stats(),
+			Column::Tree(column) =>
+				ColumnStats { tables: column.table_occupancy(), ..Default::default() },
+		}
+	}
+
+	/// Space used by the files of the column, see `Db::space_usage`.
+	pub fn space_usage(&self, log: &Log) -> Result<SpaceUsage> {
+		let index_bytes = match self {
+			Column::Hash(column) => column.tables.read().index.file_size(),
+			Column::Tree(_) => 0,
+		};
+		let mut usage = SpaceUsage { index_bytes, ..Default::default() };
+		self.with_value_tables(|tables| {
+			for table in tables {
+				let table = table.space_usage(log.overlays())?;
+				usage.live_bytes += table.live_bytes;
+				usage.allocated_bytes += table.allocated_bytes;
+				usage.free_entries += table.free_entries;
+				usage.reclaimable_bytes += table.reclaimable_bytes;
+			}
+			Ok(usage)
+		})
+	}
+
+	/// Size of the index and value table files of the column, see `Db::column_disk_usage`.
+	pub fn disk_usage(&self) -> ColumnDiskUsage {
+		let index_bytes = match self {
+			Column::Hash(column) =>
+				column.tables.read().index.file_size() +
+					column.reindex.read().queue.iter().map(|t| t.file_size()).sum::<u64>(),
+			Column::Tree(_) => 0,
+		};
+		let value_bytes =
+			self.with_value_tables(|tables| tables.iter().map(|t| t.file_size()).collect());
+		ColumnDiskUsage { index_bytes, value_bytes, log_bytes: 0 }
+	}
+
+	/// Number of keys in the column, if `ColumnOptions::count_keys` is set.
+	pub fn key_count(&self) -> Option<u64> {
+		self.with_value_tables(|tables| tables[0].count())
+	}
+
+	/// Call `f` with the value tables of the column, by size tier.
+	pub fn with_value_tables<R>(&self, f: impl FnOnce(&[ValueTable]) -> R) -> R {
+		match self {
+			Column::Hash(column) => f(&column.tables.read().value),
+			Column::Tree(column) => column.with_value_tables(f),
+		}
+	}
+
 	pub fn write_stats_text(&self, writer: &mut impl std::io::Write) -> Result<()> {
 		match self {
 			Column::Hash(column) => column.write_stats_text(writer),
@@ -1066,6 +2271,72 @@ impl Column {
 		}
 	}
 
+	/// Replace all column tables with empty ones. There must be no pending log records
+	/// for the column.
+	/// Replace all column tables with empty ones, but keep the old files around for deletion
+	/// by the caller. Old files are renamed to `<name>.dropped`, the returned list contains the
+	/// new paths.
+	pub fn detach_files(
+		&self,
+		col: ColId,
+		path: &std::path::Path,
+		column_options: &ColumnOptions,
+		db_version: u32,
+	) -> Result<Vec<std::path::PathBuf>> {
+		let mut detached = Vec::new();
+		let files = self.files();
+		self.replace_tables(col, path, column_options, db_version, &mut |file| {
+			if try_io!(files.exists(&file)) {
+				let mut dropped = file.clone().into_os_string();
+				dropped.push(DROPPED_FILE_SUFFIX);
+				try_io!(files.rename(&file, std::path::Path::new(&dropped)));
+				detached.push(std::path::PathBuf::from(dropped));
+			}
+			Ok(())
+		})?;
+		Ok(detached)
+	}
+
+	fn files(&self) -> Files {
+		match self {
+			Column::Hash(column) => column.io.files.clone(),
+			Column::Tree(column) => column.io().files,
+		}
+	}
+
+	fn replace_tables(
+		&self,
+		col: ColId,
+		path: &std::path::Path,
+		column_options: &ColumnOptions,
+		db_version: u32,
+		discard: &mut dyn FnMut(std::path::PathBuf) -> Result<()>,
+	) -> Result<()> {
+		let path = Arc::new(path.to_path_buf());
+		let io = match self {
+			Column::Hash(column) => column.io.clone(),
+			Column::Tree(column) => column.io(),
+		};
+		let open_tables = || {
+			(0..SIZE_TIERS)
+				.map(|i| {
+					Self::open_table(
+						path.clone(),
+						col,
+						i as u8,
+						column_options,
+						db_version,
+						io.clone(),
+					)
+				})
+				.collect::<Result<Vec<_>>>()
+		};
+		match self {
+			Column::Hash(column) => column.clear(open_tables, discard),
+			Column::Tree(column) => column.clear(open_tables, discard),
+		}
+	}
+
 	pub fn clear_stats(&self) -> Result<()> {
 		match self {
 			Column::Hash(column) => column.clear_stats(),
@@ -1080,6 +2351,35 @@ impl Column {
 		}
 	}
 
+	pub fn check(
+		&self,
+		log: &Log,
+		check_param: &crate::CheckOptions,
+		report: &mut ColumnCheck,
+	) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.check(log, check_param, report),
+			Column::Tree(column) => column.check(log.overlays(), report),
+		}
+	}
+
+	/// Check the column and fix what is possible, see `Db::open_repair`. Returns the number of
+	/// removed and restored index entries.
+	pub fn repair(
+		&self,
+		log: &Log,
+		writer: &mut LogWriter,
+		report: &mut ColumnCheck,
+	) -> Result<(u64, u64)> {
+		match self {
+			Column::Hash(column) => column.repair(log, writer, report),
+			Column::Tree(column) => {
+				column.check(log.overlays(), report)?;
+				Ok((0, 0))
+			},
+		}
+	}
+
 	pub fn dump(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<()> {
 		match self {
 			Column::Hash(column) => column.dump(log, check_param, col),
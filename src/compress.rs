@@ -3,16 +3,94 @@
 
 //! Compression utility and types.
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+use std::{
+	collections::{BinaryHeap, HashMap},
+	sync::Arc,
+};
+
+/// Largest compression dictionary. LZ4 ignores bytes before the last 64 KiB of a dictionary.
+pub const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+
+// Length of the byte strings counted by `train_dictionary`.
+const DICT_KGRAM: usize = 8;
+// Length of the sample segments that `train_dictionary` assembles dictionaries from.
+const DICT_SEGMENT: usize = 256;
 
 /// Different compression type
 /// allowed and their u8 representation.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-#[repr(u8)]
 pub enum CompressionType {
-	NoCompression = 0,
-	Lz4 = 1,
-	Snappy = 2,
+	NoCompression,
+	Lz4,
+	Snappy,
+	/// LZ4 high compression mode. Slower to compress than `Lz4` for a better ratio, and as fast
+	/// to decompress. Levels range from 1 to `LZ4HC_MAX_LEVEL`.
+	Lz4Hc {
+		level: u8,
+	},
+	/// Zstandard, for a better ratio than LZ4 at a higher CPU cost. Levels range from 1 to
+	/// `ZSTD_MAX_LEVEL`. Requires the `zstd` feature.
+	#[cfg(feature = "zstd")]
+	Zstd {
+		level: u8,
+	},
+}
+
+/// Default level of `CompressionType::Lz4Hc`.
+pub const LZ4HC_DEFAULT_LEVEL: u8 = 9;
+/// Highest level of `CompressionType::Lz4Hc`.
+pub const LZ4HC_MAX_LEVEL: u8 = 12;
+/// Default level of `CompressionType::Zstd`.
+#[cfg(feature = "zstd")]
+pub const ZSTD_DEFAULT_LEVEL: u8 = 3;
+/// Highest level of `CompressionType::Zstd`.
+#[cfg(feature = "zstd")]
+pub const ZSTD_MAX_LEVEL: u8 = 22;
+
+impl CompressionType {
+	/// Identifier of the algorithm, as stored in the metadata.
+	pub fn id(&self) -> u8 {
+		match self {
+			CompressionType::NoCompression => 0,
+			CompressionType::Lz4 => 1,
+			CompressionType::Snappy => 2,
+			CompressionType::Lz4Hc { .. } => 3,
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { .. } => 4,
+		}
+	}
+
+	/// Level of algorithms that have one.
+	pub fn level(&self) -> Option<u8> {
+		match self {
+			CompressionType::Lz4Hc { level } => Some(*level),
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { level } => Some(*level),
+			_ => None,
+		}
+	}
+
+	pub(crate) fn from_id(id: u8, level: Option<u8>) -> Option<Self> {
+		Some(match id {
+			0 => CompressionType::NoCompression,
+			1 => CompressionType::Lz4,
+			2 => CompressionType::Snappy,
+			3 => CompressionType::Lz4Hc { level: level.unwrap_or(LZ4HC_DEFAULT_LEVEL) },
+			#[cfg(feature = "zstd")]
+			4 => CompressionType::Zstd { level: level.unwrap_or(ZSTD_DEFAULT_LEVEL) },
+			_ => return None,
+		})
+	}
+
+	pub(crate) fn is_valid(&self) -> bool {
+		match self {
+			CompressionType::Lz4Hc { level } => (1..=LZ4HC_MAX_LEVEL).contains(level),
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { level } => (1..=ZSTD_MAX_LEVEL).contains(level),
+			_ => true,
+		}
+	}
 }
 
 /// Compression implementation.
@@ -26,6 +104,18 @@ impl Compress {
 	pub fn new(kind: CompressionType, threshold: u32) -> Self {
 		Compress { inner: kind.into(), threshold }
 	}
+
+	/// Use a dictionary of byte strings common in values. Only used by `CompressionType::Lz4`,
+	/// `CompressionType::Lz4Hc` and `CompressionType::Zstd`.
+	pub fn with_dictionary(mut self, dict: Option<Arc<[u8]>>) -> Self {
+		match &mut self.inner {
+			Compressor::Lz4(inner) => inner.dict = dict,
+			#[cfg(feature = "zstd")]
+			Compressor::Zstd(inner) => inner.set_dictionary(dict.as_deref()),
+			_ => (),
+		}
+		self
+	}
 }
 
 pub const NO_COMPRESSION: Compress =
@@ -36,16 +126,13 @@ enum Compressor {
 	NoCompression(NoCompression),
 	Lz4(lz4::Lz4),
 	Snappy(snappy::Snappy),
+	#[cfg(feature = "zstd")]
+	Zstd(zstd::Zstd),
 }
 
 impl From<u8> for CompressionType {
 	fn from(comp_type: u8) -> Self {
-		match comp_type {
-			a if a == CompressionType::NoCompression as u8 => CompressionType::NoCompression,
-			a if a == CompressionType::Lz4 as u8 => CompressionType::Lz4,
-			a if a == CompressionType::Snappy as u8 => CompressionType::Snappy,
-			_ => panic!("Unknown compression."),
-		}
+		CompressionType::from_id(comp_type, None).expect("Unknown compression.")
 	}
 }
 
@@ -53,22 +140,25 @@ impl From<CompressionType> for Compressor {
 	fn from(comp_type: CompressionType) -> Self {
 		match comp_type {
 			CompressionType::NoCompression => Compressor::NoCompression(NoCompression),
-			CompressionType::Lz4 => Compressor::Lz4(lz4::Lz4::new()),
+			CompressionType::Lz4 => Compressor::Lz4(lz4::Lz4::new(None)),
 			CompressionType::Snappy => Compressor::Snappy(snappy::Snappy::new()),
-			#[allow(unreachable_patterns)]
-			_ => unimplemented!("Missing compression implementation."),
+			CompressionType::Lz4Hc { level } => Compressor::Lz4(lz4::Lz4::new(Some(level))),
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { level } => Compressor::Zstd(zstd::Zstd::new(level)),
 		}
 	}
 }
 
 impl From<&Compress> for CompressionType {
 	fn from(compression: &Compress) -> Self {
-		match compression.inner {
+		match &compression.inner {
 			Compressor::NoCompression(_) => CompressionType::NoCompression,
-			Compressor::Lz4(_) => CompressionType::Lz4,
+			Compressor::Lz4(lz4::Lz4 { level: None, .. }) => CompressionType::Lz4,
+			Compressor::Lz4(lz4::Lz4 { level: Some(level), .. }) =>
+				CompressionType::Lz4Hc { level: *level },
 			Compressor::Snappy(_) => CompressionType::Snappy,
-			#[allow(unreachable_patterns)]
-			_ => unimplemented!("Missing compression implementation."),
+			#[cfg(feature = "zstd")]
+			Compressor::Zstd(inner) => CompressionType::Zstd { level: inner.level },
 		}
 	}
 }
@@ -79,6 +169,8 @@ impl Compress {
 			Compressor::NoCompression(inner) => inner.compress(buf),
 			Compressor::Lz4(inner) => inner.compress(buf),
 			Compressor::Snappy(inner) => inner.compress(buf),
+			#[cfg(feature = "zstd")]
+			Compressor::Zstd(inner) => inner.compress(buf),
 			#[allow(unreachable_patterns)]
 			_ => unimplemented!("Missing compression implementation."),
 		}
@@ -89,10 +181,29 @@ impl Compress {
 			Compressor::NoCompression(inner) => inner.decompress(buf)?,
 			Compressor::Lz4(inner) => inner.decompress(buf)?,
 			Compressor::Snappy(inner) => inner.decompress(buf)?,
+			#[cfg(feature = "zstd")]
+			Compressor::Zstd(inner) => inner.decompress(buf)?,
 			#[allow(unreachable_patterns)]
 			_ => unimplemented!("Missing compression implementation."),
 		})
 	}
+
+	/// Decompress the data read from `reader`. Snappy frames are decompressed as they are read,
+	/// LZ4 blocks are read and decompressed at once.
+	pub fn decompress_reader(
+		&self,
+		mut reader: impl std::io::Read + Send + 'static,
+	) -> Result<Box<dyn std::io::Read + Send>> {
+		Ok(match &self.inner {
+			Compressor::NoCompression(_) => Box::new(reader),
+			Compressor::Snappy(_) => Box::new(snap::read::FrameDecoder::new(reader)),
+			_ => {
+				let mut buf = Vec::new();
+				reader.read_to_end(&mut buf).map_err(Error::Io)?;
+				Box::new(std::io::Cursor::new(self.decompress(&buf)?))
+			},
+		})
+	}
 }
 
 #[derive(Debug)]
@@ -110,23 +221,389 @@ impl NoCompression {
 
 mod lz4 {
 	use crate::error::{Error, Result};
+	use std::{
+		os::raw::{c_char, c_int, c_void},
+		sync::Arc,
+	};
+
+	// Part of the LZ4 library linked by `lz4-sys`, which does not declare them.
+	extern "C" {
+		fn LZ4_createStream() -> *mut c_void;
+		fn LZ4_freeStream(stream: *mut c_void) -> c_int;
+		fn LZ4_loadDict(stream: *mut c_void, dict: *const c_char, dict_size: c_int) -> c_int;
+		fn LZ4_compress_fast_continue(
+			stream: *mut c_void,
+			src: *const c_char,
+			dst: *mut c_char,
+			src_size: c_int,
+			dst_capacity: c_int,
+			acceleration: c_int,
+		) -> c_int;
+		fn LZ4_decompress_safe_usingDict(
+			src: *const c_char,
+			dst: *mut c_char,
+			src_size: c_int,
+			dst_capacity: c_int,
+			dict: *const c_char,
+			dict_size: c_int,
+		) -> c_int;
+		fn LZ4_compressBound(input_size: c_int) -> c_int;
+		fn LZ4_createStreamHC() -> *mut c_void;
+		fn LZ4_freeStreamHC(stream: *mut c_void) -> c_int;
+		fn LZ4_resetStreamHC_fast(stream: *mut c_void, level: c_int);
+		fn LZ4_loadDictHC(stream: *mut c_void, dict: *const c_char, dict_size: c_int) -> c_int;
+		fn LZ4_compress_HC_continue(
+			stream: *mut c_void,
+			src: *const c_char,
+			dst: *mut c_char,
+			src_size: c_int,
+			dst_capacity: c_int,
+		) -> c_int;
+	}
 
 	#[derive(Debug)]
-	pub(super) struct Lz4;
+	pub(super) struct Lz4 {
+		// High compression level, if any.
+		pub(super) level: Option<u8>,
+		pub(super) dict: Option<Arc<[u8]>>,
+	}
 
 	impl Lz4 {
-		pub(super) fn new() -> Self {
-			Lz4
+		pub(super) fn new(level: Option<u8>) -> Self {
+			Lz4 { level, dict: None }
 		}
 
 		pub(super) fn compress(&self, buf: &[u8]) -> Vec<u8> {
-			lz4::block::compress(buf, Some(lz4::block::CompressionMode::DEFAULT), true).unwrap()
+			match (&self.dict, self.level) {
+				(Some(dict), level) => compress_with_dict(buf, dict, level),
+				(None, None) =>
+					lz4::block::compress(buf, Some(lz4::block::CompressionMode::DEFAULT), true)
+						.unwrap(),
+				(None, Some(level)) => lz4::block::compress(
+					buf,
+					Some(lz4::block::CompressionMode::HIGHCOMPRESSION(level as i32)),
+					true,
+				)
+				.unwrap(),
+			}
 		}
 
 		pub(super) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
-			lz4::block::decompress(buf, None).map_err(|_| Error::Compression)
+			match &self.dict {
+				Some(dict) => decompress_with_dict(buf, dict),
+				None => lz4::block::decompress(buf, None).map_err(|_| Error::Compression),
+			}
 		}
 	}
+
+	// Same layout as `lz4::block::compress` with a prepended size: the uncompressed size as u32
+	// LE followed by the block.
+	fn compress_with_dict(buf: &[u8], dict: &[u8], level: Option<u8>) -> Vec<u8> {
+		let bound = unsafe { LZ4_compressBound(buf.len() as c_int) };
+		assert!(bound > 0, "Value too large to compress");
+		let mut out = vec![0u8; 4 + bound as usize];
+		out[..4].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+		let (src, dst) = (buf.as_ptr() as *const c_char, out[4..].as_mut_ptr() as *mut c_char);
+		let (dict_ptr, dict_len) = (dict.as_ptr() as *const c_char, dict.len() as c_int);
+		let len = unsafe {
+			match level {
+				None => {
+					let stream = LZ4_createStream();
+					assert!(!stream.is_null(), "Failed to allocate LZ4 stream");
+					LZ4_loadDict(stream, dict_ptr, dict_len);
+					let len =
+						LZ4_compress_fast_continue(stream, src, dst, buf.len() as c_int, bound, 1);
+					LZ4_freeStream(stream);
+					len
+				},
+				Some(level) => {
+					let stream = LZ4_createStreamHC();
+					assert!(!stream.is_null(), "Failed to allocate LZ4 stream");
+					LZ4_resetStreamHC_fast(stream, level as c_int);
+					LZ4_loadDictHC(stream, dict_ptr, dict_len);
+					let len = LZ4_compress_HC_continue(stream, src, dst, buf.len() as c_int, bound);
+					LZ4_freeStreamHC(stream);
+					len
+				},
+			}
+		};
+		assert!(len > 0, "Compression into a bound sized buffer can't fail");
+		out.truncate(4 + len as usize);
+		out
+	}
+
+	fn decompress_with_dict(buf: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+		if buf.len() < 4 {
+			return Err(Error::Compression)
+		}
+		let size = u32::from_le_bytes(buf[..4].try_into().expect("4 bytes")) as usize;
+		if size > i32::MAX as usize {
+			return Err(Error::Compression)
+		}
+		let mut out = vec![0u8; size];
+		let len = unsafe {
+			LZ4_decompress_safe_usingDict(
+				buf[4..].as_ptr() as *const c_char,
+				out.as_mut_ptr() as *mut c_char,
+				(buf.len() - 4) as c_int,
+				size as c_int,
+				dict.as_ptr() as *const c_char,
+				dict.len() as c_int,
+			)
+		};
+		if len < 0 || len as usize != size {
+			return Err(Error::Compression)
+		}
+		Ok(out)
+	}
+}
+
+/// Build a dictionary of at most `max_size` bytes for `CompressionType::Lz4` from sample values.
+/// Picks the sample segments that contain the most byte strings repeated across samples. Helps
+/// with small values that share structure but compress poorly on their own. Zstd accepts these
+/// dictionaries as well, see `train_zstd_dictionary` for dictionaries made for it.
+pub fn train_dictionary<V: AsRef<[u8]>>(samples: &[V], max_size: usize) -> Vec<u8> {
+	let max_size = max_size.min(MAX_DICTIONARY_SIZE);
+	let kgram = |s: &[u8]| u64::from_le_bytes(s.try_into().expect("DICT_KGRAM bytes"));
+	let mut counts: HashMap<u64, u32> = HashMap::new();
+	for sample in samples {
+		for window in sample.as_ref().windows(DICT_KGRAM) {
+			*counts.entry(kgram(window)).or_default() += 1;
+		}
+	}
+	// Byte strings seen once can't help compress other values.
+	counts.retain(|_, count| *count > 1);
+	let score = |counts: &HashMap<u64, u32>, segment: &[u8]| -> u64 {
+		segment
+			.windows(DICT_KGRAM)
+			.map(|w| counts.get(&kgram(w)).copied().unwrap_or(0) as u64)
+			.sum()
+	};
+
+	let mut heap = BinaryHeap::new();
+	for (s, sample) in samples.iter().enumerate() {
+		let sample = sample.as_ref();
+		let mut offset = 0;
+		while offset + DICT_KGRAM <= sample.len() {
+			let end = (offset + DICT_SEGMENT).min(sample.len());
+			let segment_score = score(&counts, &sample[offset..end]);
+			if segment_score > 0 {
+				heap.push((segment_score, s, offset));
+			}
+			offset += DICT_SEGMENT / 2;
+		}
+	}
+
+	// Greedily take the best scoring segment. Byte strings already in the dictionary no longer
+	// count, so scores only decrease and stale entries are rescored when popped.
+	let mut segments = Vec::new();
+	let mut size = 0;
+	while let Some((segment_score, s, offset)) = heap.pop() {
+		if size >= max_size {
+			break
+		}
+		let sample = samples[s].as_ref();
+		let segment = &sample[offset..(offset + DICT_SEGMENT).min(sample.len())];
+		let current = score(&counts, segment);
+		if current == 0 {
+			continue
+		}
+		if current < segment_score {
+			heap.push((current, s, offset));
+			continue
+		}
+		let segment = &segment[..segment.len().min(max_size - size)];
+		for window in segment.windows(DICT_KGRAM) {
+			counts.remove(&kgram(window));
+		}
+		size += segment.len();
+		segments.push(segment);
+	}
+	// LZ4 matches cost the same at any offset, but the best segments are placed last to be
+	// kept should the dictionary be truncated from the front.
+	segments.iter().rev().flat_map(|s| s.iter().copied()).collect()
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+	use crate::{
+		error::{Error, Result},
+		options::MAX_VALUE_SIZE,
+	};
+	use std::{ffi::c_void, os::raw::c_int};
+	use zstd_sys::*;
+
+	// Compression and decompression contexts, reused by all calls on a thread.
+	struct Contexts {
+		cctx: *mut ZSTD_CCtx,
+		dctx: *mut ZSTD_DCtx,
+	}
+
+	impl Contexts {
+		fn new() -> Self {
+			// SAFETY: plain allocations, checked below.
+			let (cctx, dctx) = unsafe { (ZSTD_createCCtx(), ZSTD_createDCtx()) };
+			assert!(!cctx.is_null() && !dctx.is_null(), "Failed to allocate zstd contexts");
+			Contexts { cctx, dctx }
+		}
+	}
+
+	impl Drop for Contexts {
+		fn drop(&mut self) {
+			// SAFETY: allocated in `new` and not used afterwards.
+			unsafe {
+				ZSTD_freeCCtx(self.cctx);
+				ZSTD_freeDCtx(self.dctx);
+			}
+		}
+	}
+
+	thread_local! {
+		static CONTEXTS: Contexts = Contexts::new();
+	}
+
+	// Dictionary digested for the column level. Zstd only reads digested dictionaries, so they
+	// are shared between threads.
+	struct Dictionary {
+		cdict: *mut ZSTD_CDict,
+		ddict: *mut ZSTD_DDict,
+	}
+
+	// SAFETY: see above, the pointers are only freed on drop.
+	unsafe impl Send for Dictionary {}
+	unsafe impl Sync for Dictionary {}
+
+	impl Drop for Dictionary {
+		fn drop(&mut self) {
+			// SAFETY: allocated in `set_dictionary` and not used afterwards.
+			unsafe {
+				ZSTD_freeCDict(self.cdict);
+				ZSTD_freeDDict(self.ddict);
+			}
+		}
+	}
+
+	pub(super) struct Zstd {
+		pub(super) level: u8,
+		dict: Option<Dictionary>,
+	}
+
+	impl std::fmt::Debug for Zstd {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			f.debug_struct("Zstd")
+				.field("level", &self.level)
+				.field("dict", &self.dict.is_some())
+				.finish()
+		}
+	}
+
+	impl Zstd {
+		pub(super) fn new(level: u8) -> Self {
+			Zstd { level, dict: None }
+		}
+
+		pub(super) fn set_dictionary(&mut self, dict: Option<&[u8]>) {
+			self.dict = dict.map(|dict| {
+				let (ptr, len) = (dict.as_ptr() as *const c_void, dict.len());
+				// SAFETY: zstd copies the dictionary content.
+				let (cdict, ddict) = unsafe {
+					(ZSTD_createCDict(ptr, len, self.level as c_int), ZSTD_createDDict(ptr, len))
+				};
+				assert!(!cdict.is_null() && !ddict.is_null(), "Failed to load zstd dictionary");
+				Dictionary { cdict, ddict }
+			});
+		}
+
+		pub(super) fn compress(&self, buf: &[u8]) -> Vec<u8> {
+			// SAFETY: pure function.
+			let bound = unsafe { ZSTD_compressBound(buf.len()) };
+			let mut out = Vec::with_capacity(bound);
+			let (src, dst) = (buf.as_ptr() as *const c_void, out.as_mut_ptr() as *mut c_void);
+			// SAFETY: `dst` has room for `bound` bytes and the context is used by this thread only.
+			let len = CONTEXTS.with(|c| unsafe {
+				match &self.dict {
+					Some(dict) =>
+						ZSTD_compress_usingCDict(c.cctx, dst, bound, src, buf.len(), dict.cdict),
+					None =>
+						ZSTD_compressCCtx(c.cctx, dst, bound, src, buf.len(), self.level as c_int),
+				}
+			});
+			// SAFETY: pure function.
+			assert!(
+				unsafe { ZSTD_isError(len) } == 0,
+				"Compression into a bound sized buffer can't fail"
+			);
+			// SAFETY: zstd initialized `len` bytes.
+			unsafe { out.set_len(len) };
+			out
+		}
+
+		pub(super) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+			let src = buf.as_ptr() as *const c_void;
+			// SAFETY: reads at most `buf.len()` bytes.
+			let size = unsafe { ZSTD_getFrameContentSize(src, buf.len()) };
+			// Unknown sizes and errors are above the limit as well.
+			if size > MAX_VALUE_SIZE as u64 {
+				return Err(Error::Compression)
+			}
+			let size = size as usize;
+			let mut out = Vec::with_capacity(size);
+			let dst = out.as_mut_ptr() as *mut c_void;
+			// SAFETY: `dst` has room for `size` bytes and the context is used by this thread only.
+			let len = CONTEXTS.with(|c| unsafe {
+				match &self.dict {
+					Some(dict) =>
+						ZSTD_decompress_usingDDict(c.dctx, dst, size, src, buf.len(), dict.ddict),
+					None => ZSTD_decompressDCtx(c.dctx, dst, size, src, buf.len()),
+				}
+			});
+			// SAFETY: pure function.
+			if unsafe { ZSTD_isError(len) } != 0 || len != size {
+				return Err(Error::Compression)
+			}
+			// SAFETY: zstd initialized `len` bytes.
+			unsafe { out.set_len(len) };
+			Ok(out)
+		}
+	}
+
+	pub(super) fn train_dictionary<V: AsRef<[u8]>>(
+		samples: &[V],
+		max_size: usize,
+	) -> Result<Vec<u8>> {
+		let sizes: Vec<usize> = samples.iter().map(|s| s.as_ref().len()).collect();
+		let content: Vec<u8> = samples.iter().flat_map(|s| s.as_ref().iter().copied()).collect();
+		let mut dict = vec![0u8; max_size];
+		// SAFETY: `content` holds the concatenation of `sizes.len()` samples of the given sizes,
+		// and `dict` has room for `max_size` bytes.
+		let len = unsafe {
+			ZDICT_trainFromBuffer(
+				dict.as_mut_ptr() as *mut c_void,
+				max_size,
+				content.as_ptr() as *const c_void,
+				sizes.as_ptr(),
+				sizes.len() as u32,
+			)
+		};
+		// SAFETY: pure functions, error names are static strings.
+		if unsafe { ZDICT_isError(len) } != 0 {
+			let name = unsafe { std::ffi::CStr::from_ptr(ZDICT_getErrorName(len)) };
+			return Err(Error::InvalidInput(format!(
+				"Can't train a zstd dictionary: {}",
+				name.to_string_lossy()
+			)))
+		}
+		dict.truncate(len);
+		Ok(dict)
+	}
+}
+
+/// Build a dictionary of at most `max_size` bytes for `CompressionType::Zstd` from sample
+/// values with the zstd trainer. Besides common byte strings, zstd dictionaries hold entropy
+/// tables tuned to the samples. Fails if there are too few samples, a few hundred are needed.
+#[cfg(feature = "zstd")]
+pub fn train_zstd_dictionary<V: AsRef<[u8]>>(samples: &[V], max_size: usize) -> Result<Vec<u8>> {
+	zstd::train_dictionary(samples, max_size.min(MAX_DICTIONARY_SIZE))
 }
 
 mod snappy {
@@ -166,8 +643,16 @@ mod tests {
 	#[test]
 	fn test_compression_interfaces() {
 		let original = vec![42; 100];
-		let types =
-			vec![CompressionType::NoCompression, CompressionType::Snappy, CompressionType::Lz4];
+		let types = vec![
+			CompressionType::NoCompression,
+			CompressionType::Snappy,
+			CompressionType::Lz4,
+			CompressionType::Lz4Hc { level: LZ4HC_DEFAULT_LEVEL },
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { level: ZSTD_DEFAULT_LEVEL },
+			#[cfg(feature = "zstd")]
+			CompressionType::Zstd { level: ZSTD_MAX_LEVEL },
+		];
 
 		for compression_type in types {
 			let compress = Compress::new(compression_type, 0);
@@ -177,4 +662,133 @@ mod tests {
 			assert_eq!(original, round_tripped);
 		}
 	}
+
+	#[test]
+	fn dictionary_compression() {
+		let value = |i: u32| {
+			let mut v = b"{\"kind\":\"branch\",\"children\":[".to_vec();
+			v.extend_from_slice(&i.to_le_bytes());
+			v.extend_from_slice(b"],\"value\":null,\"partial\":\"0000\"}");
+			v
+		};
+		let samples: Vec<_> = (0..100).map(value).collect();
+		let dict = train_dictionary(&samples, 1024);
+		assert!(!dict.is_empty() && dict.len() <= 1024);
+
+		let plain = Compress::new(CompressionType::Lz4, 0);
+		let dict: std::sync::Arc<[u8]> = dict.into();
+		let compress = Compress::new(CompressionType::Lz4, 0).with_dictionary(Some(dict.clone()));
+		let original = value(1000);
+		let v = compress.compress(&original);
+		assert!(v.len() < plain.compress(&original).len());
+		assert!(v.len() < original.len() / 2);
+		assert_eq!(compress.decompress(&v).unwrap(), original);
+		assert!(compress.decompress(&v[..v.len() - 1]).is_err());
+		assert!(plain.decompress(&v).map_or(true, |d| d != original));
+
+		let high = Compress::new(CompressionType::Lz4Hc { level: LZ4HC_MAX_LEVEL }, 0)
+			.with_dictionary(Some(dict));
+		let h = high.compress(&original);
+		assert!(h.len() <= v.len());
+		assert_eq!(high.decompress(&h).unwrap(), original);
+		// High compression output is decompressed by the same decoder.
+		assert_eq!(compress.decompress(&h).unwrap(), original);
+	}
+
+	// Records of 100 to 300 bytes, with shared field names and varying contents.
+	fn records(count: usize) -> Vec<Vec<u8>> {
+		let mut seed = 0x2545_f491_4f6c_dd1du64;
+		let mut random = move |n: u64| {
+			seed ^= seed << 13;
+			seed ^= seed >> 7;
+			seed ^= seed << 17;
+			seed % n
+		};
+		let hash = |random: &mut dyn FnMut(u64) -> u64| {
+			format!("0x{:016x}{:016x}", random(u64::MAX), random(u64::MAX))
+		};
+		// Several kinds of values are stored in the same column.
+		let mut record = || {
+			match random(3) {
+			0 => format!(
+				"{{\"kind\":\"account\",\"address\":\"{}\",\"nonce\":{},\"balance\":\"{}\",\"code_hash\":\"{}\",\"storage\":null}}",
+				hash(&mut random),
+				random(1000),
+				random(1 << 40),
+				hash(&mut random),
+			),
+			1 => format!(
+				"{{\"kind\":\"transfer\",\"from\":\"{}\",\"to\":\"{}\",\"amount\":\"{}\",\"memo\":\"{}\"}}",
+				hash(&mut random),
+				hash(&mut random),
+				random(1 << 30),
+				["staking reward", "treasury payout", "bounty", "fee refund"][random(4) as usize],
+			),
+			_ => format!(
+				"{{\"kind\":\"validator\",\"stash\":\"{}\",\"commission\":{},\"blocked\":{},\"era\":{},\"points\":{}}}",
+				hash(&mut random),
+				random(100),
+				random(2) == 0,
+				random(5000),
+				random(100_000),
+			),
+		}
+		.into_bytes()
+		};
+		(0..count).map(|_| record()).collect()
+	}
+
+	#[test]
+	fn trained_dictionary_ratio() {
+		let mut samples = records(2000);
+		let values = samples.split_off(1000);
+		assert!(values.iter().all(|v| (100..=300).contains(&v.len())));
+
+		let dict: std::sync::Arc<[u8]> = train_dictionary(&samples, 4096).into();
+		let plain = Compress::new(CompressionType::Lz4, 0);
+		let compress = Compress::new(CompressionType::Lz4, 0).with_dictionary(Some(dict.clone()));
+		// A dictionary of the same size made of whole samples.
+		let naive: std::sync::Arc<[u8]> = samples.concat()[..dict.len()].into();
+		let naive = Compress::new(CompressionType::Lz4, 0).with_dictionary(Some(naive));
+		let size = |c: &Compress| values.iter().map(|v| c.compress(v).len()).sum::<usize>();
+		let original = values.iter().map(Vec::len).sum::<usize>();
+		let (plain, trained, naive) = (size(&plain), size(&compress), size(&naive));
+		// Values barely compress on their own.
+		assert!(plain * 10 > original * 9, "{} {}", plain, original);
+		assert!(trained * 10 < plain * 7, "{} {}", trained, plain);
+		assert!(trained < naive, "{} {}", trained, naive);
+		for v in &values {
+			assert_eq!(compress.decompress(&compress.compress(v)).unwrap(), *v);
+		}
+	}
+
+	#[cfg(feature = "zstd")]
+	#[test]
+	fn zstd_dictionary_ratio() {
+		let mut samples = records(2000);
+		let values = samples.split_off(1000);
+		let dict: std::sync::Arc<[u8]> = train_zstd_dictionary(&samples, 4096).unwrap().into();
+		assert!(dict.len() <= 4096);
+		let kind = CompressionType::Zstd { level: ZSTD_DEFAULT_LEVEL };
+		let plain = Compress::new(kind, 0);
+		let compress = Compress::new(kind, 0).with_dictionary(Some(dict.clone()));
+		let lz4 = Compress::new(CompressionType::Lz4, 0)
+			.with_dictionary(Some(train_dictionary(&samples, 4096).into()));
+		let size = |c: &Compress| values.iter().map(|v| c.compress(v).len()).sum::<usize>();
+		let (plain, trained, lz4) = (size(&plain), size(&compress), size(&lz4));
+		assert!(trained * 10 < plain * 6, "{} {}", trained, plain);
+		assert!(trained < lz4, "{} {}", trained, lz4);
+		for v in &values {
+			let c = compress.compress(v);
+			assert_eq!(compress.decompress(&c).unwrap(), *v);
+			assert!(compress.decompress(&c[..c.len() - 1]).is_err());
+			// Values need the dictionary.
+			assert!(Compress::new(kind, 0).decompress(&c).is_err());
+		}
+		// A dictionary made of raw sample bytes works too.
+		let raw =
+			Compress::new(kind, 0).with_dictionary(Some(train_dictionary(&samples, 4096).into()));
+		assert_eq!(raw.decompress(&raw.compress(&values[0])).unwrap(), values[0]);
+		assert!(train_zstd_dictionary(&samples[..2], 4096).is_err());
+	}
 }
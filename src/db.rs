@@ -18,44 +18,186 @@
 //! Each background worker is signalled with a conditional variable once
 //! there is some work to be done.
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, MetricsSink};
 use crate::{
-	btree::{commit_overlay::BTreeChangeSet, BTreeIterator, BTreeTable},
-	column::{hash_key, ColId, Column, IterState, ReindexBatch},
+	backup::{BackupKind, ModifiedPages, TableFileId},
+	blob::BlobWriter,
+	btree::{commit_overlay::BTreeChangeSet, BTreeIterator, BTreeTable, IngestStep, TreeBuilder},
+	column::{hash_key, ColId, Column, Columns, HashColumn, IterState, ReindexBatch, Salt},
 	error::{try_io, Error, Result},
+	file::SlowIo,
 	hash::IdentityBuildHasher,
-	index::PlanOutcome,
-	log::{Log, LogAction},
-	options::{Options, CURRENT_VERSION},
-	parking_lot::{Condvar, Mutex, RwLock},
-	stats::StatSummary,
+	index::{Address, IndexTable, PlanOutcome, CHUNK_LEN},
+	iter::{ColumnIterator, Cursor, HashEntry},
+	limiter::IoLimiter,
+	log::{ArchivedLog, Log, LogAction, LogOverlays, LogReader, LogWriter},
+	options::{
+		secondary_indexes_valid, CommitOptions, Metadata, Options, RecoveryMode, ScrubFn,
+		ScrubOptions, SecondaryIndex, CURRENT_VERSION,
+	},
+	parking_lot::{Condvar, Mutex, MutexGuard, RwLock},
+	reader::ValueReader,
+	snapshot::{SnapshotIterator, SnapshotState},
+	stats::{
+		ColumnDiskUsage, ColumnStats, QueueStats, RangeSize, SpaceUsage, StatSummary, TreeNode,
+		TreeStats, ValueMeta,
+	},
 	ColumnOptions, Key,
 };
-use fs2::FileExt;
 use std::{
-	collections::{BTreeMap, HashMap, VecDeque},
+	collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
 	ops::Bound,
 	sync::{
 		atomic::{AtomicBool, AtomicU64, Ordering},
-		Arc,
+		mpsc, Arc,
 	},
 	thread,
+	time::{Duration, Instant, SystemTime},
 };
 
-// Max size of commit queue. (Keys + Values). If the queue is
-// full `commit` will block.
-// These are in memory, so we use usize
-const MAX_COMMIT_QUEUE_BYTES: usize = 16 * 1024 * 1024;
-// Max size of log overlay. If the overlay is full, processing
-// of commit queue is blocked.
-const MAX_LOG_QUEUE_BYTES: i64 = 128 * 1024 * 1024;
+mod commit_handle;
+mod follower;
+
+pub use commit_handle::CommitHandle;
+
 // Minimum size of log file before it is considered full.
 const MIN_LOG_SIZE_BYTES: u64 = 64 * 1024 * 1024;
 // Number of log files to keep after flush.
 const KEEP_LOGS: usize = 16;
+// Max number of expired values removed in a single commit.
+const EXPIRY_BATCH_SIZE: usize = 4096;
+// Max number of index chunks scanned for expired values at once.
+const EXPIRY_SCAN_CHUNKS: u64 = 1024;
+// Size of the expiry timestamp prepended to values in `ttl` columns.
+const EXPIRY_SIZE: usize = 8;
+// Max number of values copied at once by a column migration.
+const MIGRATION_BATCH_SIZE: usize = 4096;
+// Max number of index chunks read to estimate the number of keys of a column.
+const LEN_SAMPLE_CHUNKS: u64 = 1024;
+// Bytes of keys and values written by a single log record of `Db::ingest_sorted`.
+const INGEST_BATCH_BYTES: usize = 16 * 1024 * 1024;
+// Number of index chunks verified by a single scrubber step.
+const SCRUB_BATCH_CHUNKS: u64 = 256;
+// Estimated log bytes of a record with no changes, see `Db::validate_commit`.
+const LOG_RECORD_BYTES: u64 = 1 + 8 + 1 + 4;
+// Estimated log bytes of an index entry change: action, table id, chunk index, entry mask and
+// entry.
+const LOG_INDEX_ACTION_BYTES: u64 = 1 + 2 + 8 + 8 + 8;
+// Estimated log bytes of a value entry change, not counting the value: action, table id, entry
+// index, and the entry header with a partial key.
+const LOG_VALUE_ACTION_BYTES: u64 = 1 + 2 + 8 + 32;
+// Size of the key length stored with values of `store_keys` columns.
+const KEY_LEN_SIZE: usize = 4;
+// Subdirectory of the database directory that holds named snapshots.
+const NAMED_SNAPSHOTS_DIR: &str = "snapshots";
+// Max number of value table entries checked by a single re-encryption step.
+#[cfg(feature = "encryption")]
+const REENCRYPTION_BATCH_SIZE: u64 = 4096;
+// Min number of values moved at once by a column compaction.
+const COMPACTION_BATCH_SIZE: usize = 4096;
+// Written when the database is closed cleanly, with the Bloom filters of the columns.
+const CLEAN_SHUTDOWN_FILE: &str = "clean_shutdown";
+// Min size of a replayed log record to enact it on multiple threads.
+const MIN_PARALLEL_REPLAY_BYTES: u64 = 64 * 1024;
 
 /// Value is just a vector of bytes. Value sizes up to 4Gb are allowed.
 pub type Value = Vec<u8>;
 
+// Milliseconds since the unix epoch.
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |d| d.as_millis() as u64)
+}
+
+// Prepend the expiry timestamp to a value. Zero means the value never expires.
+fn encode_expiring(value: &[u8], expiry: u64) -> Value {
+	let mut encoded = Vec::with_capacity(EXPIRY_SIZE + value.len());
+	encoded.extend_from_slice(&expiry.to_le_bytes());
+	encoded.extend_from_slice(value);
+	encoded
+}
+
+fn is_expired(value: &[u8], now: u64) -> bool {
+	let mut expiry = [0u8; EXPIRY_SIZE];
+	if value.len() < EXPIRY_SIZE {
+		return false
+	}
+	expiry.copy_from_slice(&value[..EXPIRY_SIZE]);
+	let expiry = u64::from_le_bytes(expiry);
+	expiry != 0 && expiry <= now
+}
+
+// Strip the expiry timestamp, returning `None` for expired values.
+fn decode_expiring(value: Option<Value>, now: u64) -> Option<Value> {
+	match value {
+		Some(value) if value.len() >= EXPIRY_SIZE && !is_expired(&value, now) =>
+			Some(value[EXPIRY_SIZE..].to_vec()),
+		_ => None,
+	}
+}
+
+// Prepend the original key to a value of a `store_keys` column.
+fn encode_keyed(key: &[u8], value: &[u8]) -> Value {
+	let mut encoded = Vec::with_capacity(KEY_LEN_SIZE + key.len() + value.len());
+	encoded.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	encoded.extend_from_slice(key);
+	encoded.extend_from_slice(value);
+	encoded
+}
+
+// Split a value of a `store_keys` column into the original key and the value.
+pub(crate) fn split_keyed(value: &[u8]) -> Option<(&[u8], &[u8])> {
+	let len = value.get(..KEY_LEN_SIZE)?;
+	let len = u32::from_le_bytes(len.try_into().expect("KEY_LEN_SIZE bytes")) as usize;
+	let rest = &value[KEY_LEN_SIZE..];
+	(len <= rest.len()).then(|| rest.split_at(len))
+}
+
+// Strip what is stored with the value by `ttl` and `store_keys` columns.
+fn decode_stored(options: &ColumnOptions, value: Option<Value>) -> Option<Value> {
+	if options.ttl {
+		return decode_expiring(value, now_millis())
+	}
+	if options.store_keys {
+		return value.and_then(|value| split_keyed(&value).map(|(_, value)| value.to_vec()))
+	}
+	value
+}
+
+/// A change to a key sent to subscribers: the key and the new value, `None` if removed.
+pub type Change = (Vec<u8>, Option<Value>);
+
+type Notifications = VecDeque<(u64, Vec<(ColId, Change)>)>;
+
+// Subscribed column, the key prefix changes are filtered by and the receiving channel.
+type Subscriber = (ColId, Vec<u8>, mpsc::Sender<Change>);
+
+/// Commit progress reported to hooks registered with `Db::on_commit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitStage {
+	/// The commit is queued and visible to readers.
+	Queued,
+	/// The commit is written to the log file, and synced if `Options::sync_wal` or
+	/// `CommitOptions::sync` is set.
+	Logged,
+	/// The commit is enacted into the column tables.
+	Enacted,
+}
+
+/// Called with a commit stage and a commit id.
+pub type CommitHook = dyn Fn(CommitStage, u64) + Send + Sync;
+
+#[derive(Default)]
+struct CommitHooks(Vec<Box<CommitHook>>);
+
+impl std::fmt::Debug for CommitHooks {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "CommitHooks({})", self.0.len())
+	}
+}
+
 // Commit data passed to `commit`
 #[derive(Debug, Default)]
 struct Commit {
@@ -67,9 +209,11 @@ struct Commit {
 	bytes: usize,
 	// Operations.
 	changeset: CommitChangeSet,
+	// Overrides `Options::sync_wal`, see `CommitOptions::sync`.
+	sync: Option<bool>,
 }
 
-// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+// Pending commits. This may not grow beyond `MemoryBudget::commit_queue_bytes` bytes.
 #[derive(Debug, Default)]
 struct CommitQueue {
 	// Log record.
@@ -78,16 +222,224 @@ struct CommitQueue {
 	bytes: usize,
 	// FIFO queue.
 	commits: VecDeque<Commit>,
+	// Threads waiting for the queue to shrink.
+	waiting: usize,
+}
+
+// Copy progress of a column migration.
+#[derive(Debug)]
+enum CopyProgress {
+	// Next index chunk and the index size the copy was started with.
+	// The copy restarts if the column is reindexed meanwhile.
+	Hash { chunk: u64, index_bits: u8 },
+	// Last copied key.
+	Tree(Option<Vec<u8>>),
+	Done,
+}
+
+// Column being rewritten with new options, see `Db::migrate_column`. The new column is built
+// in a separate database, using the same column id. All commits to the column are applied
+// to both, while the existing values are copied in the background.
+struct ColumnMigration {
+	col: ColId,
+	options: ColumnOptions,
+	path: std::path::PathBuf,
+	target: Db,
+	progress: Mutex<CopyProgress>,
+	// Number of keys copied so far.
+	copied: AtomicU64,
+}
+
+impl std::fmt::Debug for ColumnMigration {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("ColumnMigration")
+			.field("col", &self.col)
+			.field("options", &self.options)
+			.field("progress", &*self.progress.lock())
+			.finish()
+	}
+}
+
+// Rewrite of the values of a column with its current encryption key, see
+// `Db::reencrypt_column`.
+#[cfg(feature = "encryption")]
+#[derive(Debug)]
+struct Reencryption {
+	col: ColId,
+	// Next entry to check.
+	tier: usize,
+	index: u64,
+	// End of the filled entries of each value table when started.
+	ends: Vec<u64>,
+	progress: crate::stats::ReencryptionProgress,
+}
+
+// Removal of expired values from `ttl` columns, see `DbInner::expire_step`.
+#[derive(Debug)]
+struct ExpirySweep {
+	// Column and index chunk to scan next, `None` between sweeps.
+	next: Option<(ColId, u64)>,
+	// Index size the scan of the current column was started with.
+	index_bits: u8,
+	// The next sweep starts then.
+	next_sweep: Instant,
+}
+
+// Moving the values of a column to the start of its value tables, see `Db::compact_column`.
+#[derive(Debug)]
+struct Compaction {
+	col: ColId,
+	// Next index chunk and the index size the pass was started with.
+	chunk: u64,
+	index_bits: u8,
+	// End of each value table if it was dense. Values past it are moved.
+	ends: Vec<u64>,
+	// Moving the next batch is delayed until then, to limit the rate.
+	next_step: Instant,
+	progress: crate::stats::CompactionProgress,
+}
+
+impl ColumnMigration {
+	fn target_key(&self, key: &[u8]) -> Key {
+		match &self.target.inner.columns[self.col as usize] {
+			Column::Hash(column) => column.hash_key(key),
+			Column::Tree(_) => unreachable!(),
+		}
+	}
+
+	// Changes to the target column for a commit to the migrated column.
+	fn target_changes(&self, commit: &CommitChangeSet) -> CommitChangeSet {
+		let col = self.col;
+		let mut target = CommitChangeSet::default();
+		if let Some(indexed) = commit.indexed.get(&col) {
+			let changes = &mut target
+				.indexed
+				.entry(col)
+				.or_insert_with(|| IndexedChangeSet::new(col))
+				.changes;
+			changes.extend(indexed.changes.iter().cloned());
+		}
+		if let Some(btree) = commit.btree_indexed.get(&col) {
+			if self.options.btree_index {
+				let changes = &mut target
+					.btree_indexed
+					.entry(col)
+					.or_insert_with(|| BTreeChangeSet::new(col))
+					.changes;
+				changes.extend(btree.changes.iter().cloned());
+			} else {
+				let changes = &mut target
+					.indexed
+					.entry(col)
+					.or_insert_with(|| IndexedChangeSet::new(col))
+					.changes;
+				let mut range_keys = btree.range_keys.iter();
+				for change in btree.changes.iter() {
+					changes.push(match change {
+						Operation::Set(key, value) =>
+							Operation::Set(self.target_key(key), value.clone()),
+						Operation::Dereference(key) => Operation::Dereference(self.target_key(key)),
+						Operation::Reference(key) => Operation::Reference(self.target_key(key)),
+						Operation::AddRc(key, delta) =>
+							Operation::AddRc(self.target_key(key), *delta),
+						Operation::DeleteRange(..) => {
+							// Keys collected when committed, see `Db::commit_raw_if`.
+							let keys = range_keys.next().into_iter().flatten();
+							changes.extend(
+								keys.map(|key| Operation::Dereference(self.target_key(key))),
+							);
+							continue
+						},
+						_ => unreachable!("Rejected for btree columns"),
+					});
+				}
+			}
+		}
+		target
+	}
+
+	fn set(&self, target: &mut CommitChangeSet, key: &[u8], value: Value) {
+		let col = self.col;
+		if self.options.btree_index {
+			target
+				.btree_indexed
+				.entry(col)
+				.or_insert_with(|| BTreeChangeSet::new(col))
+				.push(Operation::Set(key.to_vec(), value));
+		} else {
+			target
+				.indexed
+				.entry(col)
+				.or_insert_with(|| IndexedChangeSet::new(col))
+				.push_change_hashed(Operation::Set(self.target_key(key), value));
+		}
+	}
+
+	// Changes making the reference count of hashed `key` in the target match `source`, the
+	// value and count in the migrated column. Requires both databases to have no queued
+	// commits.
+	fn set_ref_counted(
+		&self,
+		target: &mut CommitChangeSet,
+		key: Key,
+		source: Option<(Value, ValueMeta)>,
+	) -> Result<()> {
+		let col = self.col;
+		let current = match &self.target.inner.columns[col as usize] {
+			Column::Hash(column) => column.get_with_meta(&key, self.target.inner.log.overlays())?,
+			Column::Tree(_) => unreachable!("Reference counted columns are hash indexed"),
+		};
+		let current = current.map_or(0, |(_, meta)| meta.rc);
+		let changes =
+			&mut target.indexed.entry(col).or_insert_with(|| IndexedChangeSet::new(col)).changes;
+		let rc = match source {
+			Some((value, meta)) if current == 0 => {
+				changes.push(Operation::Set(key, value));
+				meta.rc - 1
+			},
+			Some((_, meta)) => meta.rc,
+			None => 0,
+		};
+		let delta = if rc >= current {
+			(rc - current).min(i64::MAX as u64) as i64
+		} else {
+			-((current - rc).min(i64::MAX as u64) as i64)
+		};
+		if delta != 0 {
+			changes.push(Operation::AddRc(key, delta));
+		}
+		Ok(())
+	}
+}
+
+// Log durability progress, in commit ids.
+#[derive(Debug, Default)]
+struct Durability {
+	// Last commit written to the log.
+	logged: u64,
+	// Last commit flushed to the log file.
+	flushed: u64,
+	// Last commit enacted.
+	enacted: u64,
+	// Number of threads waiting for a commit to be flushed.
+	waiters: usize,
 }
 
 #[derive(Debug)]
 struct DbInner {
-	columns: Vec<Column>,
+	columns: Columns,
+	// Column options are kept in `columns`, since columns may be added.
 	options: Options,
 	shutdown: AtomicBool,
 	log: Log,
 	commit_queue: Mutex<CommitQueue>,
 	commit_queue_full_cv: Condvar,
+	// Max size of commit queue. (Keys + Values). If the queue is
+	// full `commit` will block.
+	max_commit_queue_bytes: usize,
+	// Max size of log overlay. If the overlay is full, processing
+	// of commit queue is blocked.
+	max_log_queue_bytes: i64,
 	log_worker_wait: WaitCondvar<bool>,
 	commit_worker_wait: Arc<WaitCondvar<bool>>,
 	// Overlay of most recent values in the commit queue.
@@ -96,11 +448,44 @@ struct DbInner {
 	log_queue_wait: WaitCondvar<i64>,
 	flush_worker_wait: Arc<WaitCondvar<bool>>,
 	cleanup_worker_wait: WaitCondvar<bool>,
+	scrub_wait: WaitCondvar<bool>,
+	// Set with `Options::background_io_limit`.
+	io_limiter: Option<IoLimiter>,
 	last_enacted: AtomicU64,
+	// Set when opened with `Db::open_repair`.
+	repair: Option<Mutex<check::RepairReport>>,
+	recovery: Mutex<check::RecoveryReport>,
 	next_reindex: AtomicU64,
 	bg_err: Mutex<Option<Arc<Error>>>,
+	// Live snapshots, recording values replaced by new commits.
+	snapshots: Mutex<Vec<Arc<SnapshotState>>>,
+	durability: Mutex<Durability>,
+	durability_cv: Condvar,
+	// Detached table files of dropped or migrated columns, pending deletion.
+	dropped_files: Mutex<Vec<std::path::PathBuf>>,
+	migration: Mutex<Option<Arc<ColumnMigration>>>,
+	#[cfg(feature = "encryption")]
+	reencryption: Mutex<Option<Reencryption>>,
+	compaction: Mutex<Option<Compaction>>,
+	expiry: Mutex<ExpirySweep>,
+	// Held shared while table files are written or replaced, exclusively by backups.
+	tables_lock: RwLock<()>,
+	modified_pages: Mutex<ModifiedPages>,
+	subscribers: Mutex<Vec<Subscriber>>,
+	// Changes of subscribed columns by log record, sent once the record is enacted.
+	notifications: Mutex<Notifications>,
+	commit_hooks: RwLock<CommitHooks>,
+	// Commit ids by log record, marked enacted and reported to hooks once the record is
+	// enacted.
+	commit_records: Mutex<VecDeque<(u64, u64)>>,
 	db_version: u32,
-	_lock_file: std::fs::File,
+	// The database was closed cleanly before it was opened.
+	clean_shutdown: bool,
+	// Opened with `Db::open_read_only` or `Db::open_follower`.
+	read_only: bool,
+	// Opened with `Db::open_follower`.
+	follower: bool,
+	_lock_file: Option<std::fs::File>,
 }
 
 #[derive(Debug)]
@@ -129,77 +514,196 @@ impl WaitCondvar<bool> {
 		}
 		*work = false;
 	}
+
+	fn wait_for(&self, timeout: Duration) {
+		let mut work = self.work.lock();
+		if !*work {
+			self.cv.wait_for(&mut work, timeout);
+		}
+		*work = false;
+	}
 }
 
 impl DbInner {
 	fn open(options: &Options, opening_mode: OpeningMode) -> Result<DbInner> {
-		if opening_mode == OpeningMode::Create {
+		let follower = opening_mode == OpeningMode::Follower;
+		let read_only = follower || opening_mode == OpeningMode::ReadOnly;
+		let in_memory;
+		let options = if options.in_memory {
+			in_memory = Options {
+				storage: Some(Arc::new(crate::storage::MemoryStorage::default())),
+				..options.clone()
+			};
+			&in_memory
+		} else {
+			options
+		};
+		if options.storage.is_some() {
+			if read_only || options.log_archive.is_some() {
+				return Err(Error::InvalidConfiguration(
+					"Databases in memory or custom storage can't be opened read-only or archive logs"
+						.into(),
+				))
+			}
+		} else if opening_mode == OpeningMode::Create {
 			try_io!(std::fs::create_dir_all(&options.path));
 		} else if !options.path.is_dir() {
 			return Err(Error::DatabaseNotFound)
 		}
 
-		let mut lock_path: std::path::PathBuf = options.path.clone();
-		lock_path.push("lock");
-		let lock_file = try_io!(std::fs::OpenOptions::new()
-			.create(true)
-			.read(true)
-			.write(true)
-			.open(lock_path.as_path()));
-		lock_file.try_lock_exclusive().map_err(Error::Locked)?;
+		// Read-only databases may be used next to a process that has the database open for
+		// writing, and take no lock.
+		let lock_file = if read_only || options.storage.is_some() {
+			None
+		} else {
+			Some(crate::lock::lock(&options.path)?)
+		};
 
-		let metadata = options.load_and_validate_metadata(opening_mode == OpeningMode::Create)?;
+		if !read_only && options.storage.is_none() {
+			// A backup that is opened as a database can no longer be updated incrementally, since
+			// log replay changes its tables.
+			let seq_path = options.path.join(crate::backup::BACKUP_SEQUENCE_FILE);
+			if seq_path.exists() {
+				try_io!(std::fs::remove_file(seq_path));
+			}
+			crate::migration::recover_live_migrations(&options.path)?;
+			crate::migration::recover_cleared_columns(&options.path)?;
+		}
+		let mut metadata = options.load_and_validate_metadata(
+			opening_mode == OpeningMode::Create || options.storage.is_some(),
+		)?;
+		if follower {
+			// Followers see changes made by another process, which would not invalidate caches.
+			for column in metadata.columns.iter_mut() {
+				column.value_cache_bytes = 0;
+				column.index_cache_bytes = 0;
+			}
+		}
+		if read_only {
+			// Logs are not replayed, so filters built from the index would miss logged keys.
+			for column in metadata.columns.iter_mut() {
+				column.bloom_filter_bytes = 0;
+			}
+		}
+		let (max_commit_queue_bytes, max_log_queue_bytes) =
+			options.memory_budget.queue_limits(&metadata.columns);
+		if !metadata.dropped.is_empty() && !read_only {
+			// Ids of dropped columns are reused with the new options. Any files not removed
+			// before the database was closed are removed first.
+			for c in std::mem::take(&mut metadata.dropped) {
+				crate::migration::remove_column_files(options, c)?;
+			}
+			options.write_metadata_with_version(
+				&options.path,
+				&metadata.salt,
+				Some(metadata.version),
+			)?;
+		}
 		let mut columns = Vec::with_capacity(metadata.columns.len());
 		let mut commit_overlay = Vec::with_capacity(metadata.columns.len());
-		let log = Log::open(options)?;
+		let log = if read_only { Log::open_read_only(options)? } else { Log::open(options)? };
 		let last_enacted = log.replay_record_id().unwrap_or(2) - 1;
 		for c in 0..metadata.columns.len() {
 			let column = Column::open(c as ColId, options, &metadata)?;
 			commit_overlay.push(CommitOverlay::new());
-			columns.push(column);
+			columns.push((column, options.columns[c].clone()));
 		}
 		log::debug!(target: "parity-db", "Opened db {:?}, metadata={:?}", options, metadata);
 		let mut options = options.clone();
 		if options.salt.is_none() {
 			options.salt = Some(metadata.salt);
 		}
+		let io_limiter = options.background_io_limit.map(IoLimiter::new);
+		let next_sweep = Instant::now() + options.expiry_interval;
 
 		Ok(DbInner {
-			columns,
+			columns: {
+				let columns = Columns::new(columns);
+				for c in &metadata.dropped {
+					columns.set_dropped(*c);
+				}
+				columns
+			},
 			options,
 			shutdown: AtomicBool::new(false),
 			log,
 			commit_queue: Mutex::new(Default::default()),
 			commit_queue_full_cv: Condvar::new(),
+			max_commit_queue_bytes,
+			max_log_queue_bytes: max_log_queue_bytes as i64,
 			log_worker_wait: WaitCondvar::new(),
 			commit_worker_wait: Arc::new(WaitCondvar::new()),
 			commit_overlay: RwLock::new(commit_overlay),
 			log_queue_wait: WaitCondvar::new(),
 			flush_worker_wait: Arc::new(WaitCondvar::new()),
 			cleanup_worker_wait: WaitCondvar::new(),
+			scrub_wait: WaitCondvar::new(),
+			io_limiter,
 			next_reindex: AtomicU64::new(1),
 			last_enacted: AtomicU64::new(last_enacted),
+			repair: (opening_mode == OpeningMode::Repair).then(Default::default),
+			recovery: Default::default(),
 			bg_err: Mutex::new(None),
+			snapshots: Mutex::new(Vec::new()),
+			durability: Mutex::new(Default::default()),
+			durability_cv: Condvar::new(),
+			dropped_files: Mutex::new(Vec::new()),
+			migration: Mutex::new(None),
+			#[cfg(feature = "encryption")]
+			reencryption: Mutex::new(None),
+			compaction: Mutex::new(None),
+			expiry: Mutex::new(ExpirySweep { next: None, index_bits: 0, next_sweep }),
+			tables_lock: RwLock::new(()),
+			modified_pages: Mutex::new(ModifiedPages::new(last_enacted)),
+			subscribers: Mutex::new(Vec::new()),
+			notifications: Mutex::new(VecDeque::new()),
+			commit_hooks: RwLock::new(Default::default()),
+			commit_records: Mutex::new(VecDeque::new()),
 			db_version: metadata.version,
+			clean_shutdown: false,
+			read_only,
+			follower,
 			_lock_file: lock_file,
 		})
 	}
 
 	fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		let overlay = self.commit_overlay.read();
+		self.get_with_overlay(col, key, &overlay)
+	}
+
+	// Query a value given the locked commit overlay.
+	fn get_with_overlay(
+		&self,
+		col: ColId,
+		key: &[u8],
+		overlay: &[CommitOverlay],
+	) -> Result<Option<Value>> {
 		match &self.columns[col as usize] {
 			Column::Hash(column) => {
 				let key = column.hash_key(key);
-				let overlay = self.commit_overlay.read();
 				// Check commit overlay first
-				if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key)) {
-					return Ok(v)
-				}
-				// Go into tables and log overlay.
-				let log = self.log.overlays();
-				column.get(&key, log)
+				let value = match overlay.get(col as usize).and_then(|o| o.get(&key)) {
+					Some(v) => {
+						#[cfg(feature = "metrics")]
+						self.metrics(|m| m.increment(metrics::OVERLAY_HITS, Some(col), 1));
+						v
+					},
+					// Go into tables and log overlay.
+					None => {
+						#[cfg(feature = "metrics")]
+						self.metrics(|m| m.increment(metrics::OVERLAY_MISSES, Some(col), 1));
+						column.get_cached(&key, self.log.overlays())?
+					},
+				};
+				let options = self.columns.options(col);
+				let value = match overlay.get(col as usize) {
+					Some(overlay) => overlay.apply_merges(&key, value, options),
+					None => value,
+				};
+				Ok(decode_stored(options, value))
 			},
 			Column::Tree(column) => {
-				let overlay = self.commit_overlay.read();
 				if let Some(l) = overlay.get(col as usize).and_then(|o| o.btree_get(key)) {
 					return Ok(l.cloned())
 				}
@@ -210,18 +714,154 @@ impl DbInner {
 		}
 	}
 
-	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+	fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader>> {
+		match &self.columns[col as usize] {
+			Column::Hash(column)
+				if !self.columns.options(col).ttl && !self.columns.options(col).store_keys =>
+			{
+				let hashed = column.hash_key(key);
+				let overlay = self.commit_overlay.read();
+				if overlay.get(col as usize).is_some_and(|o| o.has_merges(&hashed)) {
+					let value = self.get_with_overlay(col, key, &overlay)?;
+					return Ok(value.map(ValueReader::memory))
+				}
+				if let Some(value) = overlay.get(col as usize).and_then(|o| o.get(&hashed)) {
+					return Ok(value.map(ValueReader::memory))
+				}
+				column.get_reader(&hashed, self.log.overlays())
+			},
+			_ => Ok(self.get(col, key)?.map(ValueReader::memory)),
+		}
+	}
+
+	fn get_many<K: AsRef<[u8]>>(&self, col: ColId, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		match &self.columns[col as usize] {
+			Column::Hash(column) => {
+				let mut result = vec![None; keys.len()];
+				let overlay = self.commit_overlay.read();
+				let mut missing = Vec::new();
+				let mut missing_keys = Vec::new();
+				let mut merged = Vec::new();
+				for (i, key) in keys.iter().enumerate() {
+					let key = column.hash_key(key.as_ref());
+					if overlay.get(col as usize).is_some_and(|o| o.has_merges(&key)) {
+						merged.push((i, key));
+					}
+					// Check commit overlay first
+					match overlay.get(col as usize).and_then(|o| o.get(&key)) {
+						Some(v) => result[i] = v,
+						None => {
+							missing.push(i);
+							missing_keys.push(key);
+						},
+					}
+				}
+				// Check the value cache, then go into tables and log overlay.
+				let cache = column.cache();
+				if let Some(cache) = cache {
+					let mut uncached = 0;
+					for n in 0..missing.len() {
+						match cache.get(&missing_keys[n]) {
+							Some(value) => result[missing[n]] = Some(value),
+							None => {
+								missing.swap(uncached, n);
+								missing_keys.swap(uncached, n);
+								uncached += 1;
+							},
+						}
+					}
+					missing.truncate(uncached);
+					missing_keys.truncate(uncached);
+				}
+				let log = self.log.overlays();
+				let values = column.get_many(&missing_keys, log)?;
+				for ((i, key), value) in missing.into_iter().zip(missing_keys).zip(values) {
+					if let (Some(cache), Some(value)) = (cache, &value) {
+						cache.insert(key, value.clone(), value.len());
+					}
+					result[i] = value;
+				}
+				let options = self.columns.options(col);
+				for (i, key) in merged {
+					result[i] = overlay[col as usize].apply_merges(&key, result[i].take(), options);
+				}
+				if options.ttl || options.store_keys {
+					result = result.into_iter().map(|v| decode_stored(options, v)).collect();
+				}
+				Ok(result)
+			},
+			Column::Tree(column) => {
+				let mut result = vec![None; keys.len()];
+				let mut order: Vec<usize> = (0..keys.len()).collect();
+				order.sort_unstable_by_key(|i| keys[*i].as_ref());
+				let overlay = self.commit_overlay.read();
+				// We lock log, if btree structure changed while reading that would be an issue.
+				let log = self.log.overlays().read();
+				column.with_locked(|btree| {
+					for i in order.iter().copied() {
+						let key = keys[i].as_ref();
+						result[i] = match overlay.get(col as usize).and_then(|o| o.btree_get(key)) {
+							Some(v) => v.cloned(),
+							None => BTreeTable::get(key, &*log, btree)?,
+						};
+					}
+					Ok(())
+				})?;
+				Ok(result)
+			},
+		}
+	}
+
+	fn contains_key(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		if self.columns.options(col).ttl {
+			// Expiry is only known from the value.
+			return Ok(self.get(col, key)?.is_some())
+		}
 		match &self.columns[col as usize] {
 			Column::Hash(column) => {
 				let key = column.hash_key(key);
 				let overlay = self.commit_overlay.read();
+				// Check commit overlay first, merges always produce a value.
+				if overlay.get(col as usize).is_some_and(|o| o.has_merges(&key)) {
+					return Ok(true)
+				}
+				if let Some(v) = overlay.get(col as usize).and_then(|o| o.get_ref(&key)) {
+					return Ok(v.is_some())
+				}
+				// Go into tables and log overlay.
+				let log = self.log.overlays();
+				column.contains(&key, log)
+			},
+			Column::Tree(column) => {
+				let overlay = self.commit_overlay.read();
+				if let Some(v) = overlay.get(col as usize).and_then(|o| o.btree_get(key)) {
+					return Ok(v.is_some())
+				}
+				let log = self.log.overlays().read();
+				column.with_locked(|btree| BTreeTable::contains(key, &*log, btree))
+			},
+		}
+	}
+
+	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+		if self.columns.options(col).ttl || self.columns.options(col).store_keys {
+			return Ok(self.get(col, key)?.map(|v| v.len() as u32))
+		}
+		match &self.columns[col as usize] {
+			Column::Hash(column) => {
+				let hashed = column.hash_key(key);
+				let overlay = self.commit_overlay.read();
+				if overlay.get(col as usize).is_some_and(|o| o.has_merges(&hashed)) {
+					let value = self.get_with_overlay(col, key, &overlay)?;
+					return Ok(value.map(|v| v.len() as u32))
+				}
 				// Check commit overlay first
-				if let Some(l) = overlay.get(col as usize).and_then(|o| o.get_size(&key)) {
+				if let Some(l) = overlay.get(col as usize).and_then(|o| o.get_size(&hashed)) {
 					return Ok(l)
 				}
 				// Go into tables and log overlay.
 				let log = self.log.overlays();
-				column.get_size(&key, log)
+				column.get_size(&hashed, log)
 			},
 			Column::Tree(column) => {
 				let overlay = self.commit_overlay.read();
@@ -235,7 +875,7 @@ impl DbInner {
 		}
 	}
 
-	fn btree_iter(&self, col: ColId) -> Result<BTreeIterator> {
+	fn btree_iter(&self, col: ColId) -> Result<BTreeIterator<'_>> {
 		match &self.columns[col as usize] {
 			Column::Hash(_column) =>
 				Err(Error::InvalidConfiguration("Not an indexed column.".to_string())),
@@ -248,7 +888,7 @@ impl DbInner {
 
 	// Commit simply adds the data to the queue and to the overlay and
 	// exits as early as possible.
-	fn commit<I, K>(&self, tx: I) -> Result<()>
+	fn commit<I, K>(&self, tx: I) -> Result<u64>
 	where
 		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
 		K: AsRef<[u8]>,
@@ -264,40 +904,186 @@ impl DbInner {
 		}))
 	}
 
-	fn commit_changes<I>(&self, tx: I) -> Result<()>
+	fn commit_changes<I>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		self.commit_changes_if(&[], tx, None)
+	}
+
+	// Commit only if all `expected` values match the current state.
+	fn commit_changes_if<I>(
+		&self,
+		expected: &[(ColId, Vec<u8>, Option<Value>)],
+		tx: I,
+		sync: Option<bool>,
+	) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		for (col, _, _) in expected {
+			if *col as usize >= self.columns.len() {
+				return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+			}
+		}
+		let commit = self.change_set(tx)?;
+		self.commit_raw_if(commit, expected, sync)
+	}
+
+	// Check the changes of a commit and sort them by column.
+	fn change_set<I>(&self, tx: I) -> Result<CommitChangeSet>
 	where
 		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
 	{
 		let mut commit: CommitChangeSet = Default::default();
+		let subscribed = self.subscribed_columns();
+		let mut commit_bytes = 0;
 		for (col, change) in tx.into_iter() {
-			if self.options.columns[col as usize].btree_index {
+			if col as usize >= self.columns.len() {
+				return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+			}
+			if let Some(limit) = self.options.max_commit_bytes {
+				commit_bytes += match &change {
+					Operation::Set(k, v) |
+					Operation::Merge(k, v) |
+					Operation::SetWithTtl(k, v, _) |
+					Operation::DeleteRange(k, v) => k.len() + v.len(),
+					Operation::Dereference(k) |
+					Operation::Reference(k) |
+					Operation::AddRc(k, _) => k.len(),
+				};
+				if commit_bytes > limit {
+					return Err(Error::CommitTooLarge { bytes: commit_bytes, limit })
+				}
+			}
+			if let Operation::Set(_, v) | Operation::Merge(_, v) | Operation::SetWithTtl(_, v, _) =
+				&change
+			{
+				self.columns.options(col).check_value_size(v.len(), false)?;
+			}
+			if self.columns.options(col).btree_index {
+				if let Operation::Merge(..) | Operation::SetWithTtl(..) = change {
+					return Err(Error::InvalidInput(format!(
+						"Operation is not supported for column {}",
+						col
+					)))
+				}
+				if let Operation::Reference(..) | Operation::AddRc(..) = change {
+					if !self.columns.options(col).ref_counted {
+						return Err(Error::InvalidInput(format!("No Rc for column {}", col)))
+					}
+				}
 				commit
 					.btree_indexed
 					.entry(col)
 					.or_insert_with(|| BTreeChangeSet::new(col))
 					.push(change)
 			} else {
-				commit.indexed.entry(col).or_insert_with(|| IndexedChangeSet::new(col)).push(
+				let indexed = commit.indexed.entry(col).or_insert_with(|| {
+					let mut indexed = IndexedChangeSet::new(col);
+					if subscribed.contains(&col) ||
+						!self.columns.options(col).secondary_indexes.is_empty()
+					{
+						indexed.raw_keys = Some(Default::default());
+					}
+					indexed
+				});
+				indexed.push(
 					change,
-					&self.options,
+					&self.options.salt.unwrap_or_default(),
+					self.columns.options(col),
 					self.db_version,
-				)
+				)?
+			}
+		}
+		Ok(commit)
+	}
+
+	fn validate_commit<I>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		self.check_writable()?;
+		// Keys of hash indexed columns are hashed when pushed, count them as given.
+		let mut key_bytes: HashMap<ColId, u64> = HashMap::new();
+		let commit = self.change_set(tx.into_iter().inspect(|(col, change)| {
+			*key_bytes.entry(*col).or_default() += change.key().len() as u64;
+		}))?;
+		let mut bytes = LOG_RECORD_BYTES;
+		for (col, indexed) in &commit.indexed {
+			if self.columns.is_dropped(*col) {
+				return Err(Error::InvalidInput(format!("Column {} is dropped", col)))
+			}
+			if !self.columns.options(*col).store_keys {
+				bytes += key_bytes.get(col).copied().unwrap_or_default();
+			}
+			for change in &indexed.changes {
+				bytes += LOG_INDEX_ACTION_BYTES + LOG_VALUE_ACTION_BYTES;
+				// `SetWithTtl` is pushed as a `Set` with the expiry prepended to the value.
+				if let Operation::Set(_, value) | Operation::Merge(_, value) = change {
+					bytes += value.len() as u64;
+				}
+			}
+		}
+		for (col, btree) in &commit.btree_indexed {
+			if self.columns.is_dropped(*col) {
+				return Err(Error::InvalidInput(format!("Column {} is dropped", col)))
+			}
+			for change in &btree.changes {
+				bytes += LOG_VALUE_ACTION_BYTES;
+				bytes += match change {
+					Operation::Set(key, value) => (key.len() + value.len()) as u64,
+					change => change.key().len() as u64,
+				};
 			}
 		}
+		Ok(bytes)
+	}
+
+	fn commit_raw(&self, commit: CommitChangeSet) -> Result<u64> {
+		self.commit_raw_if(commit, &[], None)
+	}
+
+	fn check_on_disk(&self) -> Result<()> {
+		if self.options.storage.is_some() {
+			return Err(Error::InvalidInput(
+				"Not supported for databases in memory or custom storage".into(),
+			))
+		}
+		Ok(())
+	}
 
-		self.commit_raw(commit)
+	fn check_writable(&self) -> Result<()> {
+		if self.follower {
+			return Err(Error::InvalidInput("Database is opened as a follower".into()))
+		}
+		if self.read_only {
+			return Err(Error::InvalidInput("Database is opened read-only".into()))
+		}
+		Ok(())
 	}
 
-	fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
+	// Queue the commit and return its id.
+	fn commit_raw_if(
+		&self,
+		mut commit: CommitChangeSet,
+		expected: &[(ColId, Vec<u8>, Option<Value>)],
+		sync: Option<bool>,
+	) -> Result<u64> {
+		self.check_writable()?;
+		let slow_io = SlowIo(self.options.slow_io_warn);
+		let slow_start = slow_io.start();
+		#[cfg(feature = "metrics")]
+		let start = Instant::now();
+
 		let mut queue = self.commit_queue.lock();
 
-		#[cfg(any(test, feature = "instrumentation"))]
 		let might_wait_because_the_queue_is_full = self.options.with_background_thread;
-		#[cfg(not(any(test, feature = "instrumentation")))]
-		let might_wait_because_the_queue_is_full = true;
-		if might_wait_because_the_queue_is_full && queue.bytes > MAX_COMMIT_QUEUE_BYTES {
+		if might_wait_because_the_queue_is_full && queue.bytes > self.max_commit_queue_bytes {
 			log::debug!(target: "parity-db", "Waiting, queue size={}", queue.bytes);
+			queue.waiting += 1;
 			self.commit_queue_full_cv.wait(&mut queue);
+			queue.waiting -= 1;
 		}
 
 		{
@@ -307,53 +1093,424 @@ impl DbInner {
 			}
 		}
 
-		let mut overlay = self.commit_overlay.write();
+		// Checked under the queue lock, see `drop_column`.
+		let cols = commit.indexed.keys().chain(commit.btree_indexed.keys());
+		let cols = cols.chain(commit.expired.keys()).chain(commit.relocated.keys());
+		for col in cols.chain(commit.staged.keys()) {
+			if self.columns.is_dropped(*col) {
+				return Err(Error::InvalidInput(format!("Column {} is dropped", col)))
+			}
+		}
 
-		queue.record_id += 1;
-		let record_id = queue.record_id + 1;
+		let mut overlay = self.commit_overlay.write();
 
-		let mut bytes = 0;
-		for (c, indexed) in &commit.indexed {
-			indexed.copy_to_overlay(
-				&mut overlay[*c as usize],
-				record_id,
-				&mut bytes,
-				&self.options,
-			)?;
+		// Conditions are checked under the overlay lock, so that no other commit can
+		// change the values before this one is added to the overlay.
+		for (col, key, value) in expected {
+			if self.get_with_overlay(*col, key, &overlay)? != *value {
+				return Err(Error::ConditionFailed { col: *col, key: key.clone() })
+			}
 		}
 
-		for (c, iterset) in &commit.btree_indexed {
-			iterset.copy_to_overlay(
-				&mut overlay[*c as usize].btree_indexed,
-				record_id,
-				&mut bytes,
-				&self.options,
-			)?;
+		// Everything that may fail is done before the commit id is allocated and the commit is
+		// added to the overlay.
+		for (c, keys) in std::mem::take(&mut commit.expired) {
+			if let Column::Hash(column) = &self.columns[c as usize] {
+				// Values may have been replaced since the expiry check.
+				let now = now_millis();
+				for key in keys {
+					let value = match overlay[c as usize].get(&key) {
+						Some(value) => value,
+						None => column.get(&key, self.log.overlays())?,
+					};
+					if value.is_some_and(|v| is_expired(&v, now)) {
+						commit
+							.indexed
+							.entry(c)
+							.or_insert_with(|| IndexedChangeSet::new(c))
+							.push_change_hashed(Operation::Dereference(key));
+					}
+				}
+			}
 		}
 
-		let commit = Commit { id: record_id, changeset: commit, bytes };
-
-		log::debug!(
-			target: "parity-db",
-			"Queued commit {}, {} bytes",
-			commit.id,
+		let migration = self.migration.lock();
+		let migrated = migration.as_ref().map(|m| m.col);
+
+		// Keys removed by range deletions are only collected for snapshots, subscribers and
+		// migrations to hash indexed columns, under the same lock as the conditions.
+		let snapshots = !self.snapshots.lock().is_empty();
+		let subscribed = self.subscribed_columns();
+		let to_hash = migration.as_ref().filter(|m| !m.options.btree_index).map(|m| m.col);
+		for (c, iterset) in &mut commit.btree_indexed {
+			if !snapshots && !subscribed.contains(c) && to_hash != Some(*c) {
+				continue
+			}
+			if let Column::Tree(column) = &self.columns[*c as usize] {
+				iterset.collect_range_keys(
+					&overlay,
+					column,
+					self.log.overlays(),
+					self.columns.options(*c).ref_counted,
+				)?;
+			}
+		}
+		for (c, indexed) in &mut commit.indexed {
+			if let Column::Hash(column) = &self.columns[*c as usize] {
+				let options = self.columns.options(*c);
+				let col_overlay = &overlay[*c as usize];
+				// Operands are applied to values of queued commits here. Otherwise they are left
+				// to the log writer, unless the merged value is needed for secondary indexes or
+				// the migration target.
+				let resolve = !options.secondary_indexes.is_empty() || migrated == Some(*c);
+				indexed.resolve_merges(options, |key| {
+					if resolve {
+						let value = match col_overlay.get(key) {
+							Some(value) => value,
+							None => column.get(key, self.log.overlays())?,
+						};
+						Ok(Some(col_overlay.apply_merges(key, value, options)))
+					} else if col_overlay.has_merges(key) {
+						Ok(None)
+					} else {
+						Ok(col_overlay.get(key))
+					}
+				})?;
+			}
+		}
+
+		for (c, indexed) in &commit.indexed {
+			let indexes = &self.columns.options(*c).secondary_indexes;
+			if indexes.is_empty() {
+				continue
+			}
+			if let Some(index) = indexes.iter().find(|i| self.columns.is_dropped(i.column)) {
+				return Err(Error::InvalidInput(format!("Column {} is dropped", index.column)))
+			}
+			if let Column::Hash(column) = &self.columns[*c as usize] {
+				// Index entries of replaced values are removed in the same commit.
+				indexed.secondary_changes(
+					&overlay[*c as usize],
+					column,
+					self.log.overlays(),
+					indexes,
+					&mut commit.btree_indexed,
+				)?;
+			}
+		}
+
+		if let Some(migration) = &*migration {
+			if commit.staged.contains_key(&migration.col) {
+				return Err(Error::InvalidInput(format!("Column {} is migrated", migration.col)))
+			}
+		}
+
+		{
+			let snapshots = self.snapshots.lock();
+			if !snapshots.is_empty() {
+				self.record_replaced_values(&snapshots, &commit, &overlay)?;
+			}
+		}
+
+		// The target is committed last, the commit can't fail once it is applied there.
+		if let Some(migration) = &*migration {
+			let changes = migration.target_changes(&commit);
+			if !changes.indexed.is_empty() || !changes.btree_indexed.is_empty() {
+				migration.target.inner.commit_raw(changes)?;
+			}
+		}
+		drop(migration);
+
+		queue.record_id += 1;
+		let record_id = queue.record_id + 1;
+
+		let mut bytes = 0;
+		for staged in commit.staged.values().flatten() {
+			bytes += staged.key.len();
+		}
+		for (c, indexed) in &commit.indexed {
+			indexed.copy_to_overlay(
+				&mut overlay[*c as usize],
+				record_id,
+				&mut bytes,
+				self.columns.options(*c),
+			);
+		}
+
+		for (c, iterset) in &commit.btree_indexed {
+			let overlay = &mut overlay[*c as usize];
+			iterset.copy_to_overlay(
+				&mut overlay.btree_indexed,
+				&mut overlay.btree_ranges,
+				record_id,
+				&mut bytes,
+				self.columns.options(*c),
+			);
+		}
+
+		// Cached values are invalidated while the overlay is locked, readers fill the cache
+		// under the same lock.
+		for (c, indexed) in &commit.indexed {
+			if let Column::Hash(column) = &self.columns[*c as usize] {
+				if let Some(cache) = column.cache() {
+					for change in &indexed.changes {
+						cache.remove(change.key());
+					}
+				}
+			}
+		}
+
+		let commit = Commit { id: record_id, changeset: commit, bytes, sync };
+
+		log::debug!(
+			target: "parity-db",
+			"Queued commit {}, {} bytes",
+			commit.id,
 			bytes,
 		);
 		queue.commits.push_back(commit);
 		queue.bytes += bytes;
+		#[cfg(feature = "metrics")]
+		self.metrics(|m| {
+			m.set(metrics::COMMIT_QUEUE_BYTES, None, queue.bytes as f64);
+			m.observe(metrics::COMMIT_SECONDS, None, start.elapsed().as_secs_f64());
+		});
+		slow_io.check(slow_start, "commit", None, 0);
 		self.log_worker_wait.signal();
+		drop(queue);
+		drop(overlay);
+		self.report_commit(CommitStage::Queued, record_id);
+		Ok(record_id)
+	}
+
+	fn begin_value(&self, col: ColId, key: &[u8]) -> Result<ValueWriter<'_>> {
+		self.check_writable()?;
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		let options = self.columns.options(col);
+		let streamed = !options.btree_index &&
+			!options.ttl &&
+			!options.store_keys &&
+			options.secondary_indexes.is_empty() &&
+			!self.subscribed_columns().contains(&col) &&
+			self.migration.lock().as_ref().is_none_or(|m| m.col != col);
+		Ok(ValueWriter {
+			db: self,
+			col,
+			key: key.to_vec(),
+			buf: Vec::new(),
+			blob_threshold: options.blob_threshold.filter(|_| streamed).map(|t| t as usize),
+			blob: None,
+			len: 0,
+		})
+	}
+
+	// Commit a value written to a blob file by `ValueWriter`, and wait until it is visible.
+	fn commit_blob(&self, col: ColId, key: &[u8], blob: BlobWriter) -> Result<u64> {
+		let column = match &self.columns[col as usize] {
+			Column::Hash(column) => column,
+			Column::Tree(_) => unreachable!("Blobs are only written to hash columns"),
+		};
+		let id = blob.id;
+		let value_len = blob.len();
+		let staged =
+			blob.finish()
+				.map(|len| StagedBlob { key: column.hash_key(key), id, len, value_len });
+		let commit_id = match staged.and_then(|staged| {
+			self.commit_raw(CommitChangeSet {
+				staged: [(col, vec![staged])].into(),
+				..Default::default()
+			})
+		}) {
+			Ok(commit_id) => commit_id,
+			Err(e) => {
+				column.discard_blob(id)?;
+				return Err(e)
+			},
+		};
+		self.wait_logged(commit_id)?;
+		Ok(commit_id)
+	}
+
+	// Id of the last commit queued so far, 0 if none.
+	fn last_commit_id(&self) -> u64 {
+		// Commit ids are one past the commit queue record id.
+		match self.commit_queue.lock().record_id {
+			0 => 0,
+			id => id + 1,
+		}
+	}
+
+	// Block until the commit with the given id is written to the log. Without a background
+	// thread, queued commits are processed by the caller.
+	fn wait_logged(&self, commit_id: u64) -> Result<()> {
+		let background = self.has_background_threads();
+		loop {
+			if self.durability.lock().logged >= commit_id {
+				return Ok(())
+			}
+			if let Some(err) = &*self.bg_err.lock() {
+				return Err(Error::Background(err.clone()))
+			}
+			if !background {
+				self.process_commits()?;
+				continue
+			}
+			if self.shutdown.load(Ordering::SeqCst) {
+				return Err(Error::InvalidInput("Database is shutting down".to_string()))
+			}
+			self.log_worker_wait.signal();
+			let mut durability = self.durability.lock();
+			self.durability_cv.wait_for(&mut durability, Duration::from_millis(10));
+		}
+	}
+
+	// Wait until all commits queued so far are written to the log and the log files are synced,
+	// and with `enacted`, until they are enacted.
+	fn flush(&self, enacted: bool) -> Result<()> {
+		self.check_writable()?;
+		let last = self.last_commit_id();
+		self.wait_flushed(last)?;
+		self.log.sync_flushed()?;
+		if enacted {
+			self.wait_enacted(last)?;
+		}
+		Ok(())
+	}
+
+	// Whether commits are processed by background threads. Otherwise waiting for a commit
+	// processes it.
+	fn has_background_threads(&self) -> bool {
+		self.options.with_background_thread
+	}
+
+	// Block until the commit with the given id is enacted.
+	fn wait_enacted(&self, commit_id: u64) -> Result<()> {
+		loop {
+			if self.durability.lock().enacted >= commit_id {
+				return Ok(())
+			}
+			if let Some(err) = &*self.bg_err.lock() {
+				return Err(Error::Background(err.clone()))
+			}
+			if !self.has_background_threads() {
+				while self.process_commits()? {}
+				self.flush_logs(0)?;
+				while self.enact_logs(false)? {}
+				continue
+			}
+			if self.shutdown.load(Ordering::SeqCst) {
+				return Err(Error::InvalidInput("Database is shutting down".to_string()))
+			}
+			let mut durability = self.durability.lock();
+			// Waiters make the flush worker flush the log regardless of its size.
+			durability.waiters += 1;
+			self.flush_worker_wait.signal();
+			self.durability_cv.wait_for(&mut durability, Duration::from_millis(10));
+			durability.waiters -= 1;
+		}
+	}
+
+	// Block until the commit with the given id is flushed to the log file and the file is
+	// synced, so that the commit survives a crash. The log is synced here if
+	// `Options::sync_wal` is not set.
+	fn wait_durable(&self, commit_id: u64) -> Result<()> {
+		self.wait_flushed(commit_id)?;
+		if !self.options.sync_wal {
+			self.log.sync_flushed()?;
+		}
+		Ok(())
+	}
+
+	// Block until the commit with the given id is flushed to the log file.
+	fn wait_flushed(&self, commit_id: u64) -> Result<()> {
+		if !self.has_background_threads() {
+			while self.durability.lock().flushed < commit_id && self.process_commits()? {}
+			self.flush_logs(0)?;
+		}
+		let mut durability = self.durability.lock();
+		durability.waiters += 1;
+		let result = loop {
+			if durability.flushed >= commit_id {
+				break Ok(())
+			}
+			if let Some(err) = &*self.bg_err.lock() {
+				break Err(Error::Background(err.clone()))
+			}
+			if self.shutdown.load(Ordering::SeqCst) {
+				break Err(Error::InvalidInput("Database is shutting down".to_string()))
+			}
+			self.flush_worker_wait.signal();
+			self.durability_cv
+				.wait_for(&mut durability, std::time::Duration::from_millis(100));
+		};
+		durability.waiters -= 1;
+		result
+	}
+
+	// Save values about to be replaced by `commit` into live snapshots.
+	fn record_replaced_values(
+		&self,
+		snapshots: &[Arc<SnapshotState>],
+		commit: &CommitChangeSet,
+		overlay: &[CommitOverlay],
+	) -> Result<()> {
+		for (c, indexed) in &commit.indexed {
+			let column = match &self.columns[*c as usize] {
+				Column::Hash(column) => column,
+				Column::Tree(_) => continue,
+			};
+			let options = self.columns.options(*c);
+			for change in &indexed.changes {
+				if let Operation::Set(key, _) |
+				Operation::Dereference(key) |
+				Operation::AddRc(key, _) |
+				Operation::Merge(key, _) = change
+				{
+					let value = match overlay[*c as usize].get(key) {
+						Some(value) => value,
+						None => column.get(key, self.log.overlays())?,
+					};
+					let value = overlay[*c as usize].apply_merges(key, value, options);
+					let value = decode_stored(options, value);
+					for snapshot in snapshots {
+						snapshot.record(*c, *key, value.clone());
+					}
+				}
+			}
+		}
+		for (c, iterset) in &commit.btree_indexed {
+			let column = match &self.columns[*c as usize] {
+				Column::Tree(column) => column,
+				Column::Hash(_) => continue,
+			};
+			let log = self.log.overlays().read();
+			let changed = iterset.changes.iter().filter_map(|change| match change {
+				Operation::Set(key, _) | Operation::Dereference(key) | Operation::AddRc(key, _) =>
+					Some(key),
+				_ => None,
+			});
+			// Keys removed by range deletions, see `BTreeChangeSet::collect_range_keys`.
+			for key in changed.chain(iterset.range_keys.iter().flatten()) {
+				let value = match overlay[*c as usize].btree_get(key) {
+					Some(value) => value.cloned(),
+					None => column.with_locked(|btree| BTreeTable::get(key, &*log, btree))?,
+				};
+				for snapshot in snapshots {
+					snapshot.record_btree(*c, key.clone(), value.clone());
+				}
+			}
+		}
 		Ok(())
 	}
 
 	fn process_commits(&self) -> Result<bool> {
-		#[cfg(any(test, feature = "instrumentation"))]
 		let might_wait_because_the_queue_is_full = self.options.with_background_thread;
-		#[cfg(not(any(test, feature = "instrumentation")))]
-		let might_wait_because_the_queue_is_full = true;
 		if might_wait_because_the_queue_is_full {
 			// Wait if the queue is full.
 			let mut queue = self.log_queue_wait.work.lock();
-			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
+			if !self.shutdown.load(Ordering::Relaxed) && *queue > self.max_log_queue_bytes {
 				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
 				self.log_queue_wait.cv.wait(&mut queue);
 			}
@@ -362,14 +1519,16 @@ impl DbInner {
 			let mut queue = self.commit_queue.lock();
 			if let Some(commit) = queue.commits.pop_front() {
 				queue.bytes -= commit.bytes;
+				#[cfg(feature = "metrics")]
+				self.metrics(|m| m.set(metrics::COMMIT_QUEUE_BYTES, None, queue.bytes as f64));
 				log::debug!(
 					target: "parity-db",
 					"Removed {}. Still queued commits {} bytes",
 					commit.bytes,
 					queue.bytes,
 				);
-				if queue.bytes <= MAX_COMMIT_QUEUE_BYTES &&
-					(queue.bytes + commit.bytes) > MAX_COMMIT_QUEUE_BYTES
+				if queue.bytes <= self.max_commit_queue_bytes &&
+					(queue.bytes + commit.bytes) > self.max_commit_queue_bytes
 				{
 					// Past the waiting threshold.
 					log::debug!(
@@ -387,6 +1546,7 @@ impl DbInner {
 		if let Some(mut commit) = commit {
 			let mut reindex = false;
 			let mut writer = self.log.begin_record();
+			writer.set_sync(commit.sync);
 			log::debug!(
 				target: "parity-db",
 				"Processing commit {}, record {}, {} bytes",
@@ -394,13 +1554,57 @@ impl DbInner {
 				writer.record_id(),
 				commit.bytes,
 			);
+			#[cfg(feature = "tracing")]
+			let _span = tracing::debug_span!(
+				"process_commit",
+				commit = commit.id,
+				record = writer.record_id(),
+				bytes = commit.bytes,
+			)
+			.entered();
+			let mut merged = Vec::new();
+			for (c, key_values) in commit.changeset.indexed.iter_mut() {
+				if let Column::Hash(column) = &self.columns[*c as usize] {
+					// All previous commits are in the log.
+					let resolved = key_values.resolve_merges(self.columns.options(*c), |key| {
+						Ok(Some(column.get(key, self.log.overlays())?))
+					})?;
+					if !resolved.is_empty() {
+						merged.push((*c, resolved));
+					}
+				}
+			}
+			if !merged.is_empty() {
+				// Merged values replace the pending operands before the commit is written to the
+				// log, readers apply the operands to the logged value under the overlay lock.
+				let mut overlay = self.commit_overlay.write();
+				for (c, resolved) in merged {
+					let cache = match &self.columns[c as usize] {
+						Column::Hash(column) => column.cache(),
+						Column::Tree(_) => None,
+					};
+					for i in resolved {
+						if let Operation::Set(key, value) = &commit.changeset.indexed[&c].changes[i]
+						{
+							overlay[c as usize].resolve_merge(key, commit.id, value.clone());
+							if let Some(cache) = cache {
+								cache.remove(key);
+							}
+						}
+					}
+				}
+			}
+
 			let mut ops: u64 = 0;
 			for (c, key_values) in commit.changeset.indexed.iter() {
+				#[cfg(feature = "tracing")]
+				tracing::trace!(col = c, changes = key_values.changes.len(), "Writing hash plan");
 				key_values.write_plan(
 					&self.columns[*c as usize],
 					&mut writer,
 					&mut ops,
 					&mut reindex,
+					self.options.compression_threads,
 				)?;
 			}
 
@@ -416,6 +1620,27 @@ impl DbInner {
 				}
 			}
 
+			for (c, staged) in commit.changeset.staged.iter() {
+				if let Column::Hash(column) = &self.columns[*c as usize] {
+					self.write_staged_plan(*c, column, staged, &mut writer, &mut reindex)?;
+					ops += staged.len() as u64;
+				}
+			}
+
+			for (c, relocated) in commit.changeset.relocated.iter() {
+				if let Column::Hash(column) = &self.columns[*c as usize] {
+					let mut moved = 0;
+					for (key, address) in relocated {
+						if column.write_relocation_plan(key, *address, &mut writer)? {
+							moved += 1;
+						}
+					}
+					if let Some(compaction) = self.compaction.lock().as_mut() {
+						compaction.progress.moved_values += moved;
+					}
+				}
+			}
+
 			// Collect final changes to value tables
 			for c in self.columns.iter() {
 				c.complete_plan(&mut writer)?;
@@ -423,10 +1648,31 @@ impl DbInner {
 			let record_id = writer.record_id();
 			let l = writer.drain();
 
+			self.queue_notifications(record_id, &commit.changeset);
+			self.commit_records.lock().push_back((record_id, commit.id));
 			let bytes = {
 				let bytes = self.log.end_record(l)?;
+				#[cfg(feature = "tracing")]
+				tracing::debug!(record = record_id, log_bytes = bytes, ops, "Logged commit");
+				if !commit.changeset.staged.is_empty() {
+					// Staged values are visible now. Readers fill the cache under the overlay
+					// lock.
+					let _overlay = self.commit_overlay.write();
+					for (c, staged) in commit.changeset.staged.iter() {
+						if let Column::Hash(column) = &self.columns[*c as usize] {
+							if let Some(cache) = column.cache() {
+								for blob in staged {
+									cache.remove(&blob.key);
+								}
+							}
+						}
+					}
+				}
+				self.durability.lock().logged = commit.id;
 				let mut logged_bytes = self.log_queue_wait.work.lock();
 				*logged_bytes += bytes as i64;
+				#[cfg(feature = "metrics")]
+				self.metrics(|m| m.set(metrics::LOG_QUEUE_BYTES, None, *logged_bytes as f64));
 				self.flush_worker_wait.signal();
 				bytes
 			};
@@ -438,7 +1684,12 @@ impl DbInner {
 					key_values.clean_overlay(&mut overlay[*c as usize], commit.id);
 				}
 				for (c, iterset) in commit.changeset.btree_indexed.iter_mut() {
-					iterset.clean_overlay(&mut overlay[*c as usize].btree_indexed, commit.id);
+					let overlay = &mut overlay[*c as usize];
+					iterset.clean_overlay(
+						&mut overlay.btree_indexed,
+						&mut overlay.btree_ranges,
+						commit.id,
+					);
 				}
 			}
 
@@ -460,6 +1711,193 @@ impl DbInner {
 		}
 	}
 
+	// Plan values written by `ValueWriter`. The values replaced are saved into live snapshots
+	// first, since the commit was not added to the commit overlay.
+	fn write_staged_plan(
+		&self,
+		col: ColId,
+		column: &HashColumn,
+		staged: &[StagedBlob],
+		writer: &mut LogWriter,
+		reindex: &mut bool,
+	) -> Result<()> {
+		{
+			let snapshots = self.snapshots.lock();
+			if !snapshots.is_empty() {
+				for blob in staged {
+					let value = column.get(&blob.key, self.log.overlays())?;
+					for snapshot in snapshots.iter() {
+						snapshot.record(col, blob.key, value.clone());
+					}
+				}
+			}
+		}
+		for blob in staged {
+			let (outcome, referenced) =
+				column.write_blob_plan(&blob.key, blob.id, blob.len, blob.value_len, writer)?;
+			if !referenced {
+				column.discard_blob(blob.id)?;
+			}
+			if let PlanOutcome::NeedReindex = outcome {
+				*reindex = true;
+			}
+		}
+		Ok(())
+	}
+
+	fn queue_stats(&self) -> QueueStats {
+		let queue = self.commit_queue.lock();
+		let log_queue_bytes = *self.log_queue_wait.work.lock();
+		let logged_commits = self.commit_records.lock().len();
+		QueueStats {
+			commit_queue_bytes: queue.bytes as u64,
+			commit_queue_limit: self.max_commit_queue_bytes as u64,
+			queued_commits: queue.commits.len() as u64,
+			commits_in_flight: (queue.commits.len() + logged_commits) as u64,
+			log_queue_bytes: log_queue_bytes.max(0) as u64,
+			log_queue_limit: self.max_log_queue_bytes as u64,
+			throttled_writers: queue.waiting as u64,
+			log_throttled: log_queue_bytes > self.max_log_queue_bytes,
+		}
+	}
+
+	fn subscribed_columns(&self) -> Vec<ColId> {
+		self.subscribers.lock().iter().map(|(col, _, _)| *col).collect()
+	}
+
+	fn subscribe(&self, col: ColId, prefix: &[u8]) -> Result<mpsc::Receiver<Change>> {
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		let (sender, receiver) = mpsc::channel();
+		self.subscribers.lock().push((col, prefix.to_vec(), sender));
+		Ok(receiver)
+	}
+
+	// Collect the changes of subscribed columns written in log record `record_id`.
+	fn queue_notifications(&self, record_id: u64, commit: &CommitChangeSet) {
+		let subscribed = self.subscribed_columns();
+		if subscribed.is_empty() {
+			return
+		}
+		let mut changes = Vec::new();
+		for (c, indexed) in &commit.indexed {
+			if !subscribed.contains(c) {
+				continue
+			}
+			let options = self.columns.options(*c);
+			for change in &indexed.changes {
+				let value = match change {
+					Operation::Set(_, value) if options.ttl =>
+						decode_expiring(Some(value.clone()), 0),
+					Operation::Set(_, value) if options.store_keys =>
+						decode_stored(options, Some(value.clone())),
+					Operation::Set(_, value) => Some(value.clone()),
+					Operation::Dereference(_) => None,
+					_ => continue,
+				};
+				let key = change.key();
+				let key = indexed
+					.raw_keys
+					.as_ref()
+					.and_then(|keys| keys.get(key).cloned())
+					.unwrap_or_else(|| key.to_vec());
+				changes.push((*c, (key, value)));
+			}
+		}
+		for (c, iterset) in &commit.btree_indexed {
+			if !subscribed.contains(c) {
+				continue
+			}
+			let mut range_keys = iterset.range_keys.iter();
+			for change in &iterset.changes {
+				match change {
+					Operation::Set(key, value) =>
+						changes.push((*c, (key.clone(), Some(value.clone())))),
+					Operation::Dereference(key) => changes.push((*c, (key.clone(), None))),
+					Operation::DeleteRange(..) => changes.extend(
+						range_keys
+							.next()
+							.into_iter()
+							.flatten()
+							.map(|key| (*c, (key.clone(), None))),
+					),
+					_ => (),
+				}
+			}
+		}
+		if !changes.is_empty() {
+			let mut notifications = self.notifications.lock();
+			let at = notifications.partition_point(|(id, _)| *id < record_id);
+			notifications.insert(at, (record_id, changes));
+		}
+	}
+
+	// Send changes of records up to `record_id` to subscribers.
+	fn send_notifications(&self, record_id: u64) {
+		let mut notifications = self.notifications.lock();
+		if notifications.front().is_none_or(|(id, _)| *id > record_id) {
+			return
+		}
+		let mut subscribers = self.subscribers.lock();
+		while notifications.front().is_some_and(|(id, _)| *id <= record_id) {
+			let (_, changes) = notifications.pop_front().expect("Checked above");
+			for (c, change) in changes {
+				// Receivers that were dropped are removed.
+				subscribers.retain(|(col, prefix, sender)| {
+					*col != c ||
+						!change.0.starts_with(prefix) ||
+						sender.send(change.clone()).is_ok()
+				});
+			}
+		}
+	}
+
+	fn on_commit(&self, hook: impl Fn(CommitStage, u64) + Send + Sync + 'static) {
+		self.commit_hooks.write().0.push(Box::new(hook));
+	}
+
+	fn report_commit(&self, stage: CommitStage, commit_id: u64) {
+		for hook in self.commit_hooks.read().0.iter() {
+			hook(stage, commit_id);
+		}
+	}
+
+	// Mark commits up to `commit_id` as flushed to the log file.
+	fn set_flushed(&self, commit_id: u64) {
+		let mut durability = self.durability.lock();
+		if durability.flushed < commit_id {
+			durability.flushed = commit_id;
+			// Reported under the lock to keep the order.
+			self.report_commit(CommitStage::Logged, commit_id);
+		}
+		self.durability_cv.notify_all();
+	}
+
+	// Report the last commit enacted with log records up to `record_id`.
+	fn report_enacted(&self, record_id: u64) {
+		let mut last = None;
+		{
+			let mut records = self.commit_records.lock();
+			while records.front().is_some_and(|(id, _)| *id <= record_id) {
+				last = records.pop_front().map(|(_, commit_id)| commit_id);
+			}
+		}
+		if let Some(commit_id) = last {
+			// Only flushed records are enacted, but the flush may not be reported yet.
+			self.set_flushed(commit_id);
+			self.durability.lock().enacted = commit_id;
+			self.durability_cv.notify_all();
+			self.report_commit(CommitStage::Enacted, commit_id);
+		}
+	}
+
+	/// Time background threads should wait before writing to the tables, according to
+	/// `Options::background_io_limit`.
+	fn io_delay(&self) -> Option<Duration> {
+		self.io_limiter.as_ref().and_then(|limiter| limiter.delay())
+	}
+
 	fn start_reindex(&self, record_id: u64) {
 		self.next_reindex.store(record_id, Ordering::SeqCst);
 	}
@@ -469,25 +1907,40 @@ impl DbInner {
 		if next_reindex == 0 || next_reindex > self.last_enacted.load(Ordering::SeqCst) {
 			return Ok(false)
 		}
+		if self.io_delay().is_some() {
+			return Ok(false)
+		}
 		// Process any pending reindexes
 		for column in self.columns.iter() {
 			let column = if let Column::Hash(c) = column { c } else { continue };
+			let slow_io = SlowIo(self.options.slow_io_warn);
+			let start = slow_io.start();
 			let ReindexBatch { drop_index, batch } = column.reindex(&self.log)?;
 			if !batch.is_empty() || drop_index.is_some() {
-				let mut next_reindex = false;
+				let entries = batch.len();
+				#[cfg(feature = "tracing")]
+				let _span = tracing::debug_span!(
+					"reindex",
+					col = column.col(),
+					entries = batch.len(),
+					drop_index = drop_index.is_some(),
+				)
+				.entered();
+				#[cfg(feature = "metrics")]
+				self.metrics(|m| {
+					m.increment(metrics::REINDEX_ENTRIES, Some(column.col()), batch.len() as u64)
+				});
 				let mut writer = self.log.begin_record();
 				log::debug!(
 					target: "parity-db",
 					"Creating reindex record {}",
 					writer.record_id(),
 				);
-				for (key, address) in batch.into_iter() {
-					if let PlanOutcome::NeedReindex =
-						column.write_reindex_plan(&key, address, &mut writer)?
-					{
-						next_reindex = true
-					}
-				}
+				let next_reindex = column.write_reindex_batch_plan(
+					batch,
+					self.options.reindex_threads,
+					&mut writer,
+				)?;
 				if let Some(table) = drop_index {
 					writer.drop_table(table);
 				}
@@ -502,11 +1955,17 @@ impl DbInner {
 					record_id,
 					bytes,
 				);
+				if let Some(limiter) = &self.io_limiter {
+					limiter.consume(bytes, entries as u64);
+				}
 				*logged_bytes += bytes as i64;
+				#[cfg(feature = "metrics")]
+				self.metrics(|m| m.set(metrics::LOG_QUEUE_BYTES, None, *logged_bytes as f64));
 				if next_reindex {
 					self.start_reindex(record_id);
 				}
 				self.flush_worker_wait.signal();
+				slow_io.check(start, "reindex", None, 0);
 				return Ok(true)
 			}
 		}
@@ -514,13 +1973,113 @@ impl DbInner {
 		Ok(false)
 	}
 
+	// Record the table file range written by `action` of record `record_id`, for incremental
+	// backups.
+	fn record_modified(&self, modified: &mut ModifiedPages, action: LogAction, record_id: u64) {
+		match action {
+			LogAction::InsertIndex(insertion) => modified.record(
+				TableFileId::Index(insertion.table),
+				IndexTable::chunk_offset(insertion.index),
+				CHUNK_LEN as u64,
+				record_id,
+			),
+			LogAction::InsertValue(insertion) => {
+				let entry_size = Column::value_entry_size(insertion.table.size_tier());
+				let segment_entries = crate::table::segment_entries(
+					self.columns.options(insertion.table.col()).max_file_size,
+					entry_size,
+				);
+				let entry_size = entry_size as u64;
+				modified.record(
+					TableFileId::Value(insertion.table, insertion.index / segment_entries),
+					insertion.index % segment_entries * entry_size,
+					entry_size,
+					record_id,
+				);
+			},
+			_ => (),
+		}
+	}
+
+	// Enact replayed record `reader`, validated up to its end, with a thread per column, up to
+	// `Options::replay_threads`. `actions` are the table changes of the record, with the range
+	// of their data in the record. Returns `false` if the record is to be enacted sequentially.
+	fn enact_replayed_parallel(
+		&self,
+		reader: &mut LogReader,
+		actions: &[(LogAction, u64, u64)],
+	) -> Result<bool> {
+		let threads = self.options.replay_threads;
+		if threads < 2 ||
+			reader.read_bytes() < MIN_PARALLEL_REPLAY_BYTES ||
+			actions.iter().any(|(action, ..)| matches!(action, LogAction::DropTable(_)))
+		{
+			return Ok(false)
+		}
+		let mut columns: BTreeMap<ColId, Vec<(LogAction, u64, u64)>> = BTreeMap::new();
+		for &(action, start, end) in actions {
+			let col = match action {
+				LogAction::InsertIndex(insertion) => insertion.table.col(),
+				LogAction::InsertValue(insertion) => insertion.table.col(),
+				_ => continue,
+			};
+			columns.entry(col).or_default().push((action, start, end));
+		}
+		if columns.len() < 2 {
+			return Ok(false)
+		}
+		let record_id = reader.record_id();
+		log::debug!(
+			target: "parity-db",
+			"Replaying record {} on {} threads",
+			record_id,
+			threads.min(columns.len()),
+		);
+		let data = reader.reread()?;
+		let mut modified = self.modified_pages.lock();
+		for &(action, ..) in actions {
+			self.record_modified(&mut modified, action, record_id);
+		}
+		let threads = threads.min(columns.len());
+		let mut groups = vec![Vec::new(); threads];
+		for (i, (col, actions)) in columns.into_iter().enumerate() {
+			groups[i % threads].push((col, actions));
+		}
+		let data = &data;
+		std::thread::scope(|s| -> Result<()> {
+			let handles: Vec<_> = groups
+				.into_iter()
+				.map(|group| {
+					s.spawn(move || -> Result<()> {
+						for (col, actions) in group {
+							for (action, start, end) in actions {
+								let mut payload = LogReader::memory(
+									record_id,
+									&data[start as usize..end as usize],
+								);
+								self.columns[col as usize].enact_plan(action, &mut payload)?;
+							}
+						}
+						Ok(())
+					})
+				})
+				.collect();
+			for handle in handles {
+				handle.join().expect("Replay thread panicked")?;
+			}
+			Ok(())
+		})?;
+		Ok(true)
+	}
+
 	fn enact_logs(&self, validation_mode: bool) -> Result<bool> {
+		let _tables = self.tables_lock.read();
 		let cleared = {
 			let reader = match self.log.read_next(validation_mode) {
 				Ok(reader) => reader,
-				Err(Error::Corruption(_)) if validation_mode => {
+				Err(Error::LogCorruption(e)) if validation_mode => {
 					log::debug!(target: "parity-db", "Bad log header");
-					self.log.clear_replay_logs();
+					self.replay_failed(format!("Bad log header: {}", e))?;
 					return Ok(false)
 				},
 				Err(e) => return Err(e),
@@ -531,41 +2090,62 @@ impl DbInner {
 					"Enacting log {}",
 					reader.record_id(),
 				);
+				let mut replayed = false;
 				if validation_mode {
-					if reader.record_id() != self.last_enacted.load(Ordering::Relaxed) + 1 {
+					let expected = self.last_enacted.load(Ordering::Relaxed) + 1;
+					// When repairing, records lost with a skipped log file leave a gap.
+					if reader.record_id() != expected &&
+						(self.repair.is_none() || reader.record_id() < expected)
+					{
 						log::warn!(
 							target: "parity-db",
 							"Log sequence error. Expected record {}, got {}",
-							self.last_enacted.load(Ordering::Relaxed) + 1,
+							expected,
 							reader.record_id(),
 						);
+						let reason = format!(
+							"Log sequence error. Expected record {}, got {}",
+							expected,
+							reader.record_id()
+						);
 						drop(reader);
-						self.log.clear_replay_logs();
+						self.replay_failed(reason)?;
 						return Ok(false)
 					}
 					// Validate all records before applying anything
+					let mut actions = Vec::new();
 					loop {
 						let next = match reader.next() {
 							Ok(next) => next,
 							Err(e) => {
 								log::debug!(target: "parity-db", "Error reading log: {:?}", e);
+								let reason =
+									format!("Error reading record {}: {}", reader.record_id(), e);
 								drop(reader);
-								self.log.clear_replay_logs();
+								self.replay_failed(reason)?;
 								return Ok(false)
 							},
 						};
+						let start = reader.read_bytes();
 						match next {
 							LogAction::BeginRecord => {
 								log::debug!(target: "parity-db", "Unexpected log header");
+								let reason =
+									format!("Unexpected header in record {}", reader.record_id());
 								drop(reader);
-								self.log.clear_replay_logs();
+								self.replay_failed(reason)?;
 								return Ok(false)
 							},
 							LogAction::EndRecord => break,
 							LogAction::InsertIndex(insertion) => {
 								let col = insertion.table.col() as usize;
 								if let Err(e) = self.columns.get(col).map_or_else(
-									|| Err(Error::Corruption(format!("Invalid column id {}", col))),
+									|| {
+										Err(Error::LogCorruption(format!(
+											"Invalid column id {}",
+											col
+										)))
+									},
 									|col| {
 										col.validate_plan(
 											LogAction::InsertIndex(insertion),
@@ -574,15 +2154,26 @@ impl DbInner {
 									},
 								) {
 									log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
+									let reason = format!(
+										"Error validating record {}: {}",
+										reader.record_id(),
+										e
+									);
 									drop(reader);
-									self.log.clear_replay_logs();
+									self.replay_failed(reason)?;
 									return Ok(false)
 								}
+								actions.push((next, start, reader.read_bytes()));
 							},
 							LogAction::InsertValue(insertion) => {
 								let col = insertion.table.col() as usize;
 								if let Err(e) = self.columns.get(col).map_or_else(
-									|| Err(Error::Corruption(format!("Invalid column id {}", col))),
+									|| {
+										Err(Error::LogCorruption(format!(
+											"Invalid column id {}",
+											col
+										)))
+									},
 									|col| {
 										col.validate_plan(
 											LogAction::InsertValue(insertion),
@@ -591,57 +2182,105 @@ impl DbInner {
 									},
 								) {
 									log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
+									let reason = format!(
+										"Error validating record {}: {}",
+										reader.record_id(),
+										e
+									);
 									drop(reader);
-									self.log.clear_replay_logs();
+									self.replay_failed(reason)?;
 									return Ok(false)
 								}
+								actions.push((next, start, reader.read_bytes()));
 							},
-							LogAction::DropTable(_) => continue,
+							LogAction::DropTable(_) => actions.push((next, start, start)),
 						}
 					}
-					reader.reset()?;
-					reader.next()?;
-				}
-				loop {
-					match reader.next()? {
-						LogAction::BeginRecord =>
-							return Err(Error::Corruption("Bad log record".into())),
-						LogAction::EndRecord => break,
-						LogAction::InsertIndex(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
-						},
-						LogAction::InsertValue(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
-						},
-						LogAction::DropTable(id) => {
-							log::debug!(
-								target: "parity-db",
-								"Dropping index {}",
-								id,
-							);
-							match &self.columns[id.col() as usize] {
-								Column::Hash(col) => {
-									col.drop_index(id)?;
-									// Check if there's another reindex on the next iteration
-									self.start_reindex(reader.record_id());
-								},
-								Column::Tree(_) => (),
-							}
-						},
+					replayed = self.enact_replayed_parallel(&mut reader, &actions)?;
+					if !replayed {
+						reader.reset()?;
+						reader.next()?;
 					}
 				}
-				log::debug!(
-					target: "parity-db",
-					"Enacted log record {}, {} bytes",
-					reader.record_id(),
+				let record_id = reader.record_id();
+				#[cfg(feature = "tracing")]
+				let _span = tracing::debug_span!("enact", record = record_id).entered();
+				let mut modified = self.modified_pages.lock();
+				let mut writes = 0u64;
+				if !replayed {
+					loop {
+						match reader.next()? {
+							LogAction::BeginRecord =>
+								return Err(Error::LogCorruption("Bad log record".into())),
+							LogAction::EndRecord => break,
+							LogAction::InsertIndex(insertion) => {
+								#[cfg(feature = "tracing")]
+								tracing::trace!(
+									col = insertion.table.col(),
+									index = insertion.index,
+									"Enacting index chunk",
+								);
+								self.record_modified(
+									&mut modified,
+									LogAction::InsertIndex(insertion),
+									record_id,
+								);
+								writes += 1;
+								self.columns[insertion.table.col() as usize]
+									.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
+							},
+							LogAction::InsertValue(insertion) => {
+								#[cfg(feature = "tracing")]
+								tracing::trace!(
+									col = insertion.table.col(),
+									tier = insertion.table.size_tier(),
+									index = insertion.index,
+									"Enacting value",
+								);
+								self.record_modified(
+									&mut modified,
+									LogAction::InsertValue(insertion),
+									record_id,
+								);
+								writes += 1;
+								self.columns[insertion.table.col() as usize]
+									.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
+							},
+							LogAction::DropTable(id) => {
+								log::debug!(
+									target: "parity-db",
+									"Dropping index {}",
+									id,
+								);
+								match &self.columns[id.col() as usize] {
+									Column::Hash(col) => {
+										col.drop_index(id)?;
+										// Check if there's another reindex on the next iteration
+										self.start_reindex(reader.record_id());
+									},
+									Column::Tree(_) => (),
+								}
+							},
+						}
+					}
+				}
+				log::debug!(
+					target: "parity-db",
+					"Enacted log record {}, {} bytes",
+					reader.record_id(),
 					reader.read_bytes(),
 				);
-				let record_id = reader.record_id();
+				drop(modified);
 				let bytes = reader.read_bytes();
+				if let (Some(limiter), false) = (&self.io_limiter, validation_mode) {
+					limiter.consume(bytes, writes);
+				}
+				#[cfg(feature = "tracing")]
+				tracing::debug!(bytes, "Enacted record");
 				let cleared = reader.drain();
 				self.last_enacted.store(record_id, Ordering::SeqCst);
+				self.send_notifications(record_id);
+				self.report_enacted(record_id);
 				Some((record_id, cleared, bytes))
 			} else {
 				log::debug!(target: "parity-db", "End of log");
@@ -665,8 +2304,10 @@ impl DbInner {
 						);
 					}
 					*queue -= bytes as i64;
-					if *queue <= MAX_LOG_QUEUE_BYTES &&
-						(*queue + bytes as i64) > MAX_LOG_QUEUE_BYTES
+					#[cfg(feature = "metrics")]
+					self.metrics(|m| m.set(metrics::LOG_QUEUE_BYTES, None, *queue as f64));
+					if *queue <= self.max_log_queue_bytes &&
+						(*queue + bytes as i64) > self.max_log_queue_bytes
 					{
 						self.log_queue_wait.cv.notify_one();
 					}
@@ -680,7 +2321,16 @@ impl DbInner {
 	}
 
 	fn flush_logs(&self, min_log_size: u64) -> Result<bool> {
+		let (logged, min_log_size) = {
+			let durability = self.durability.lock();
+			// Flush everything if anyone is waiting for durability.
+			(durability.logged, if durability.waiters > 0 { 0 } else { min_log_size })
+		};
 		let has_flushed = self.log.flush_one(min_log_size)?;
+		// With no minimum size, not flushing means everything logged is already flushed.
+		if has_flushed || min_log_size == 0 {
+			self.set_flushed(logged);
+		}
 		if has_flushed {
 			self.commit_worker_wait.signal();
 		}
@@ -696,909 +2346,7213 @@ impl DbInner {
 					c.flush()?;
 				}
 			}
-			self.log.clean_logs(num_cleanup - keep_logs)
+			let cleaned = self.log.clean_logs(num_cleanup - keep_logs)?;
+			#[cfg(feature = "metrics")]
+			self.report_metrics();
+			Ok(cleaned)
 		} else {
 			Ok(false)
 		}
 	}
 
-	fn clean_all_logs(&self) -> Result<()> {
-		for c in self.columns.iter() {
-			c.flush()?;
-		}
-		let num_cleanup = self.log.num_dirty_logs();
-		self.log.clean_logs(num_cleanup)?;
-		Ok(())
-	}
-
-	fn replay_all_logs(&mut self) -> Result<()> {
-		while let Some(id) = self.log.replay_next()? {
-			log::debug!(target: "parity-db", "Replaying database log {}", id);
-			while self.enact_logs(true)? {}
-		}
-		// Re-read any cached metadata
-		for c in self.columns.iter() {
-			c.refresh_metadata()?;
+	// Append a copy of column `col`, see `Db::clone_column`.
+	fn clone_column(&self, col: ColId, background: bool) -> Result<ColId> {
+		self.check_writable()?;
+		self.check_on_disk()?;
+		self.check_column(col)?;
+		self.check_not_migrating(col)?;
+		let column_options = self.columns.options(col).clone();
+		let indexed = self
+			.columns
+			.iter_options()
+			.any(|o| o.secondary_indexes.iter().any(|i| i.column == col));
+		if !column_options.secondary_indexes.is_empty() || indexed {
+			return Err(Error::InvalidInput(
+				"Cloning columns with or of secondary indexes is not supported".into(),
+			))
 		}
-		log::debug!(target: "parity-db", "Replay is complete.");
-		Ok(())
+		// The table files are complete once all commits are enacted.
+		let _queue = self.lock_idle(background)?;
+		self.add_column_from(column_options, Some(col))
 	}
 
-	fn shutdown(&self) {
-		self.shutdown.store(true, Ordering::SeqCst);
-		self.log_queue_wait.cv.notify_one();
-		self.flush_worker_wait.signal();
-		self.log_worker_wait.signal();
-		self.commit_worker_wait.signal();
-		self.cleanup_worker_wait.signal();
+	// Append a new column, updating the metadata file.
+	fn add_column(&self, column_options: ColumnOptions) -> Result<ColId> {
+		self.check_writable()?;
+		self.add_column_from(column_options, None)
 	}
 
-	fn kill_logs(&self) -> Result<()> {
-		{
-			if let Some(err) = self.bg_err.lock().as_ref() {
-				// On error the log reader may be left in inconsistent state. So it is important
-				// to no attempt any further log enactment.
-				log::debug!(target: "parity-db", "Shutdown with error state {}", err);
-				self.log.clean_logs(self.log.num_dirty_logs())?;
-				return self.log.kill_logs()
+	// Append a new column, with the table files of column `source` if set.
+	fn add_column_from(
+		&self,
+		column_options: ColumnOptions,
+		source: Option<ColId>,
+	) -> Result<ColId> {
+		column_options.validate()?;
+		// Holding the overlay lock serializes additions and keeps out commits to the new
+		// column until it is fully initialized.
+		let mut overlay = self.commit_overlay.write();
+		let _tables = self.tables_lock.read();
+		let col = self.columns.len();
+		if col > ColId::MAX as usize {
+			return Err(Error::InvalidInput("Too many columns".into()))
+		}
+		let mut options = self.options.clone();
+		options.columns = self.columns.iter_options().cloned().collect();
+		options.columns.push(column_options.clone());
+		if !secondary_indexes_valid(&options.columns) {
+			return Err(Error::InvalidConfiguration("Invalid secondary indexes".into()))
+		}
+		let salt = self.options.salt.unwrap_or_default();
+		crate::migration::remove_column_files(&options, col as ColId)?;
+		if let Some(source) = source {
+			if let Err(e) =
+				crate::migration::clone_column_files(&options.path, source, col as ColId)
+			{
+				crate::migration::remove_column_files(&options, col as ColId)?;
+				return Err(e)
 			}
 		}
-		log::debug!(target: "parity-db", "Processing leftover commits");
-		// Finish logged records and proceed to log and enact queued commits.
-		while self.enact_logs(false)? {}
-		self.flush_logs(0)?;
-		while self.process_commits()? {}
-		while self.enact_logs(false)? {}
-		self.flush_logs(0)?;
-		while self.enact_logs(false)? {}
-		self.clean_all_logs()?;
-		self.log.kill_logs()?;
-		if self.options.stats {
-			let mut path = self.options.path.clone();
-			path.push("stats.txt");
-			match std::fs::File::create(path) {
-				Ok(file) => {
-					let mut writer = std::io::BufWriter::new(file);
-					if let Err(e) = self.write_stats_text(&mut writer, None) {
-						log::warn!(target: "parity-db", "Error writing stats file: {:?}", e)
-					}
-				},
-				Err(e) => log::warn!(target: "parity-db", "Error creating stats file: {:?}", e),
+		let dropped = self.columns.dropped();
+		options.write_metadata_with_dropped(
+			&options.path,
+			&salt,
+			Some(self.db_version),
+			&dropped,
+		)?;
+		let metadata =
+			Metadata { salt, version: self.db_version, columns: options.columns.clone(), dropped };
+		let column = Column::open(col as ColId, &options, &metadata)?;
+		if let Some(source) = source {
+			let filter = self.columns[source as usize].filter_bytes();
+			if !filter.is_some_and(|filter| column.restore_filter(&filter)) {
+				column.build_filter(self.log.overlays())?;
 			}
 		}
-		Ok(())
+		overlay.push(CommitOverlay::new());
+		let col = self.columns.push(column, column_options)?;
+		log::info!(target: "parity-db", "Added column {}", col);
+		Ok(col)
 	}
 
-	fn write_stats_text(&self, writer: &mut impl std::io::Write, column: Option<u8>) -> Result<()> {
-		if let Some(col) = column {
-			self.columns[col as usize].write_stats_text(writer)
+	// Remove all data from a column. Blocks new commits until all pending ones are enacted
+	// and the column tables are replaced. The old tables are detached while a marker is
+	// written, so that an interrupted clear is rolled back when the database is opened.
+	fn clear_column(&self, col: ColId, background: bool) -> Result<()> {
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		self.check_not_migrating(col)?;
+		if !self.snapshots.lock().is_empty() {
+			return Err(Error::InvalidInput("Can't clear a column with open snapshots".into()))
+		}
+		// Make sure detached files of earlier changes are not confused with the ones
+		// detached here.
+		self.remove_dropped_files()?;
+		let _queue = self.lock_idle(background)?;
+		let _tables = self.tables_lock.read();
+		self.modified_pages.lock().reset(self.last_enacted.load(Ordering::SeqCst));
+		// Enacted logs must not be replayed on top of the new tables.
+		self.clean_all_logs()?;
+		let indexes = self.columns.options(col).secondary_indexes.iter().map(|i| i.column);
+		let cols: Vec<ColId> = std::iter::once(col).chain(indexes).collect();
+		let files = self.options.files();
+		let marker = self.options.path.join(crate::migration::CLEAR_MARKER);
+		try_io!(files.write(&marker, &cols));
+		try_io!(files.sync_dir(&self.options.path));
+		let mut detached = Vec::new();
+		for c in cols {
+			detached.extend(self.columns[c as usize].detach_files(
+				c,
+				&self.options.path,
+				self.columns.options(c),
+				self.db_version,
+			)?);
+			self.log.invalidate_column(c);
+		}
+		try_io!(files.sync_dir(&self.options.path));
+		try_io!(files.remove(&marker));
+		try_io!(files.sync_dir(&self.options.path));
+		self.dropped_files.lock().extend(detached);
+		log::info!(target: "parity-db", "Cleared column {}", col);
+		if background {
+			self.cleanup_worker_wait.signal();
 		} else {
-			for c in self.columns.iter() {
-				c.write_stats_text(writer)?;
-			}
-			Ok(())
+			self.remove_dropped_files()?;
 		}
+		Ok(())
 	}
 
-	fn clear_stats(&self, column: Option<u8>) -> Result<()> {
-		if let Some(col) = column {
-			self.columns[col as usize].clear_stats()
-		} else {
-			for c in self.columns.iter() {
-				c.clear_stats()?;
+	// Return the space of long runs of free value table entries to the file system. The free
+	// lists are rewritten first so that no entry in the punched ranges is referenced anymore.
+	fn punch_holes(&self, col: ColId, background: bool) -> Result<u64> {
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		self.check_not_migrating(col)?;
+		let _queue = self.lock_idle(background)?;
+		let column = &self.columns[col as usize];
+		let holes = self.write_table_record(background, |writer| {
+			column.with_value_tables(|tables| -> Result<Vec<Vec<(u64, u64)>>> {
+				let mut holes = Vec::with_capacity(tables.len());
+				for table in tables {
+					holes.push(table.write_free_runs_plan(writer)?);
+					table.complete_plan(writer)?;
+				}
+				Ok(holes)
+			})
+		})?;
+		let mut punched = 0;
+		column.with_value_tables(|tables| -> Result<()> {
+			for (table, holes) in tables.iter().zip(&holes) {
+				table.punch_holes(holes)?;
+				punched += holes.iter().map(|(_, len)| len).sum::<u64>();
 			}
 			Ok(())
-		}
+		})?;
+		log::info!(target: "parity-db", "Punched {} bytes of holes in column {}", punched, col);
+		Ok(punched)
 	}
 
-	fn stats(&self) -> StatSummary {
-		StatSummary { columns: self.columns.iter().map(|c| c.stats()).collect() }
+	// Build the tree of empty btree indexed column `col` from keys in ascending order. Blocks
+	// new commits until done.
+	fn ingest_sorted<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+		&self,
+		col: ColId,
+		mut iter: impl Iterator<Item = Result<(K, V)>>,
+		background: bool,
+	) -> Result<u64> {
+		let column = match self.check_column(col)? {
+			Column::Tree(column) => column,
+			Column::Hash(_) =>
+				return Err(Error::InvalidInput(format!("Column {} is not btree indexed", col))),
+		};
+		let options = self.columns.options(col);
+		if options.ttl || !options.secondary_indexes.is_empty() {
+			return Err(Error::InvalidInput(
+				"Can't ingest into a column with expiring values or secondary indexes".into(),
+			))
+		}
+		self.check_not_migrating(col)?;
+		let _queue = self.lock_idle(background)?;
+		if !column.is_empty(self.log.overlays())? {
+			return Err(Error::InvalidInput(format!("Column {} is not empty", col)))
+		}
+		let mut builder = TreeBuilder::default();
+		loop {
+			match self.write_table_record(background, |writer| {
+				column.ingest_plan(&mut builder, &mut iter, INGEST_BATCH_BYTES, writer)
+			})? {
+				IngestStep::More => (),
+				IngestStep::Finished => break,
+				IngestStep::Failed(e) => return Err(e),
+			}
+		}
+		if let Some(key) = builder.unordered_key() {
+			return Err(Error::InvalidInput(format!(
+				"Key {} is not in ascending order, {} keys were ingested",
+				crate::display::hex(key),
+				builder.keys()
+			)))
+		}
+		log::info!(target: "parity-db", "Ingested {} keys into column {}", builder.keys(), col);
+		Ok(builder.keys())
 	}
 
-	fn store_err(&self, result: Result<()>) {
-		if let Err(e) = result {
-			log::warn!(target: "parity-db", "Background worker error: {}", e);
-			let mut err = self.bg_err.lock();
-			if err.is_none() {
-				*err = Some(Arc::new(e));
-				self.shutdown();
+	// Write a log record of changes to the tables made by `f` and wait until it is enacted and
+	// the tables are flushed. The commit queue must be locked with `lock_idle`.
+	fn write_table_record<R>(
+		&self,
+		background: bool,
+		f: impl FnOnce(&mut LogWriter) -> Result<R>,
+	) -> Result<R> {
+		let result = {
+			// Re-encryption writes log records outside of the commit queue.
+			#[cfg(feature = "encryption")]
+			let _reencryption = self.reencryption.lock();
+			let mut writer = self.log.begin_record();
+			log::debug!(target: "parity-db", "Creating table record {}", writer.record_id());
+			let result = f(&mut writer)?;
+			let l = writer.drain();
+			let mut logged_bytes = self.log_queue_wait.work.lock();
+			*logged_bytes += self.log.end_record(l)? as i64;
+			result
+		};
+		while !self.log.overlays().read().is_empty() || self.log.has_log_files_to_read() {
+			if let Some(err) = &*self.bg_err.lock() {
+				return Err(Error::Background(err.clone()))
+			}
+			if background {
+				if self.shutdown.load(Ordering::SeqCst) {
+					return Err(Error::InvalidInput("Database is shutting down".to_string()))
+				}
+				let mut durability = self.durability.lock();
+				durability.waiters += 1;
+				self.flush_worker_wait.signal();
+				self.durability_cv.wait_for(&mut durability, Duration::from_millis(10));
+				durability.waiters -= 1;
+			} else {
+				self.log.flush_one(0)?;
+				while self.enact_logs(false)? {}
 			}
-			self.commit_queue_full_cv.notify_all();
 		}
+		self.clean_all_logs()?;
+		Ok(result)
 	}
 
-	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		match &self.columns[c as usize] {
-			Column::Hash(column) => column.iter_while(&self.log, f),
-			Column::Tree(_) => unimplemented!(),
+	// Start moving the values of a column to the start of its value tables.
+	fn start_compaction(&self, col: ColId, background: bool) -> Result<()> {
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
 		}
+		let column = match &self.columns[col as usize] {
+			Column::Hash(column) => column,
+			Column::Tree(_) =>
+				return Err(Error::InvalidInput(
+					"Compacting btree indexed columns is not supported".into(),
+				)),
+		};
+		if self.db_version < 4 {
+			return Err(Error::InvalidInput(
+				"Compaction is not supported by this database version".into(),
+			))
+		}
+		self.check_not_migrating(col)?;
+		if self
+			.compaction
+			.lock()
+			.as_ref()
+			.is_some_and(|c| c.col != col && !c.progress.done)
+		{
+			return Err(Error::InvalidInput("A column compaction is already in progress".into()))
+		}
+		let (ends, released_bytes) = self.compact_tables(col, background)?;
+		let (index_bits, _) = column.index_state();
+		*self.compaction.lock() = Some(Compaction {
+			col,
+			chunk: 0,
+			index_bits,
+			ends,
+			next_step: Instant::now(),
+			progress: crate::stats::CompactionProgress {
+				total_chunks: column.index_chunks(),
+				released_bytes,
+				..Default::default()
+			},
+		});
+		log::info!(target: "parity-db", "Started compaction of column {}", col);
+		Ok(())
 	}
-}
 
-pub struct Db {
-	inner: Arc<DbInner>,
-	commit_thread: Option<thread::JoinHandle<()>>,
-	flush_thread: Option<thread::JoinHandle<()>>,
-	log_thread: Option<thread::JoinHandle<()>>,
-	cleanup_thread: Option<thread::JoinHandle<()>>,
-	join_on_shutdown: bool,
-}
-
-impl Db {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
-		let options = Options::with_columns(path, num_columns);
-		Self::open_inner(&options, OpeningMode::Create)
+	// Rebuild the free lists of the value tables of a column in ascending order, and truncate
+	// their files after the last value. Returns the ends of the tables if they were dense, and
+	// the number of bytes released.
+	fn compact_tables(&self, col: ColId, background: bool) -> Result<(Vec<u64>, u64)> {
+		let _queue = self.lock_idle(background)?;
+		let column = &self.columns[col as usize];
+		let ends = self.write_table_record(background, |writer| {
+			column.with_value_tables(|tables| {
+				tables
+					.iter()
+					.map(|table| {
+						let end = table.write_compact_plan(writer)?;
+						table.complete_plan(writer)?;
+						Ok(end)
+					})
+					.collect::<Result<Vec<_>>>()
+			})
+		})?;
+		let released = column.with_value_tables(|tables| {
+			tables.iter().map(|table| table.shrink_file()).sum::<Result<u64>>()
+		})?;
+		Ok((ends, released))
 	}
 
-	/// Open the database with given options.
-	pub fn open(options: &Options) -> Result<Db> {
-		Self::open_inner(options, OpeningMode::Write)
+	// Move the next batch of values of the compacted column, or truncate the value tables once
+	// all index chunks are checked. Returns `true` if there is more work to do.
+	fn compact_step(&self, background: bool) -> Result<bool> {
+		let mut guard = self.compaction.lock();
+		let compaction = match guard.as_mut() {
+			Some(compaction) if !compaction.progress.done => compaction,
+			_ => return Ok(false),
+		};
+		let col = compaction.col;
+		let column = match self.columns.get(col as usize) {
+			Some(Column::Hash(column)) if !self.columns.is_dropped(col) => column,
+			_ => {
+				log::info!(target: "parity-db", "Stopped compaction of column {}", col);
+				*guard = None;
+				return Ok(false)
+			},
+		};
+		let pause = compaction.next_step.saturating_duration_since(Instant::now());
+		if !pause.is_zero() {
+			drop(guard);
+			if background {
+				self.cleanup_worker_wait.wait_for(pause);
+			} else {
+				thread::sleep(pause);
+			}
+			return Ok(!self.shutdown.load(Ordering::SeqCst))
+		}
+		let (bits, reindexing) = column.index_state();
+		if reindexing {
+			// Entries move between index tables while reindexing.
+			drop(guard);
+			if background {
+				thread::sleep(Duration::from_millis(10));
+			} else {
+				drop(self.lock_idle(false)?);
+			}
+			return Ok(!self.shutdown.load(Ordering::SeqCst))
+		}
+		if bits != compaction.index_bits {
+			log::debug!(target: "parity-db", "{}: Restarting compaction pass", col);
+			compaction.chunk = 0;
+			compaction.index_bits = bits;
+			compaction.progress.total_chunks = column.index_chunks();
+		}
+		let (candidates, next_chunk) = column.relocation_candidates(
+			&self.log,
+			compaction.chunk,
+			&compaction.ends,
+			COMPACTION_BATCH_SIZE,
+		)?;
+		compaction.chunk = next_chunk;
+		compaction.progress.scanned_chunks = next_chunk;
+		let pass_done = next_chunk >= compaction.progress.total_chunks;
+		let bytes: u64 = candidates
+			.iter()
+			.map(|(_, address)| Column::value_entry_size(address.size_tier()) as u64)
+			.sum();
+		if self.options.compaction_rate > 0 {
+			compaction.next_step = Instant::now() +
+				Duration::from_secs_f64(bytes as f64 / self.options.compaction_rate as f64);
+		}
+		// Relocations are applied by the log worker, which updates the progress.
+		drop(guard);
+		if !candidates.is_empty() {
+			log::debug!(target: "parity-db", "{}: Moving {} values", col, candidates.len());
+			let mut commit = CommitChangeSet::default();
+			commit.relocated.insert(col, candidates);
+			self.commit_raw(commit)?;
+		}
+		if !pass_done {
+			return Ok(!self.shutdown.load(Ordering::SeqCst))
+		}
+		let (_, released_bytes) = self.compact_tables(col, background)?;
+		if let Some(compaction) = self.compaction.lock().as_mut() {
+			compaction.progress.released_bytes += released_bytes;
+			compaction.progress.done = true;
+			log::info!(
+				target: "parity-db",
+				"Compacted column {}: moved {} values, released {} bytes",
+				col,
+				compaction.progress.moved_values,
+				compaction.progress.released_bytes,
+			);
+		}
+		Ok(false)
 	}
 
-	/// Create the database using given options.
-	pub fn open_or_create(options: &Options) -> Result<Db> {
-		Self::open_inner(options, OpeningMode::Create)
+	fn compaction_progress(&self, col: ColId) -> Option<crate::stats::CompactionProgress> {
+		self.compaction.lock().as_ref().filter(|c| c.col == col).map(|c| c.progress)
 	}
 
-	/// Read the database using given options
-	pub fn open_read_only(options: &Options) -> Result<Db> {
-		Self::open_inner(options, OpeningMode::ReadOnly)
+	fn migration_progress(&self, col: ColId) -> Option<crate::stats::MigrationProgress> {
+		let migration = self.migration.lock().clone().filter(|m| m.col == col)?;
+		let mut progress = crate::stats::MigrationProgress {
+			copied_keys: migration.copied.load(Ordering::Relaxed),
+			..Default::default()
+		};
+		match &*migration.progress.lock() {
+			CopyProgress::Hash { chunk, index_bits } => {
+				progress.scanned_chunks = *chunk;
+				progress.total_chunks = if *index_bits == 0 { 0 } else { 1 << *index_bits };
+			},
+			CopyProgress::Tree(_) => (),
+			CopyProgress::Done => progress.copy_done = true,
+		}
+		Some(progress)
 	}
 
-	fn open_inner(options: &Options, opening_mode: OpeningMode) -> Result<Db> {
-		assert!(options.is_valid());
-		let mut db = DbInner::open(options, opening_mode)?;
-		// This needs to be call before log thread: so first reindexing
-		// will run in correct state.
-		if let Err(e) = db.replay_all_logs() {
-			log::debug!(target: "parity-db", "Error during log replay, doing log cleanup");
-			db.log.clean_logs(db.log.num_dirty_logs())?;
-			db.log.kill_logs()?;
-			return Err(e)
+	// Remove a column. The column is tombstoned in the metadata and its tables are replaced
+	// with empty ones. Old files are deleted in the background.
+	fn drop_column(&self, col: ColId, background: bool) -> Result<()> {
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
 		}
-		let db = Arc::new(db);
-		#[cfg(any(test, feature = "instrumentation"))]
-		let start_threads = opening_mode != OpeningMode::ReadOnly && options.with_background_thread;
-		#[cfg(not(any(test, feature = "instrumentation")))]
-		let start_threads = opening_mode != OpeningMode::ReadOnly;
-		let commit_thread = if start_threads {
-			let commit_worker_db = db.clone();
-			Some(thread::spawn(move || {
-				commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
-			}))
-		} else {
-			None
-		};
-		let flush_thread = if start_threads {
-			let flush_worker_db = db.clone();
-			#[cfg(any(test, feature = "instrumentation"))]
-			let min_log_size = if options.always_flush { 0 } else { MIN_LOG_SIZE_BYTES };
-			#[cfg(not(any(test, feature = "instrumentation")))]
-			let min_log_size = MIN_LOG_SIZE_BYTES;
-			Some(thread::spawn(move || {
-				flush_worker_db.store_err(Self::flush_worker(flush_worker_db.clone(), min_log_size))
-			}))
-		} else {
-			None
-		};
-		let log_thread = if start_threads {
-			let log_worker_db = db.clone();
-			Some(thread::spawn(move || {
-				log_worker_db.store_err(Self::log_worker(log_worker_db.clone()))
-			}))
-		} else {
-			None
-		};
-		let cleanup_thread = if start_threads {
-			let cleanup_worker_db = db.clone();
-			Some(thread::spawn(move || {
-				cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
-			}))
+		self.check_not_migrating(col)?;
+		if !self.snapshots.lock().is_empty() {
+			return Err(Error::InvalidInput("Can't drop a column with open snapshots".into()))
+		}
+		let _queue = self.lock_idle(background)?;
+		// Serializes metadata updates with `add_column`.
+		let _overlay = self.commit_overlay.write();
+		let _tables = self.tables_lock.read();
+		self.modified_pages.lock().reset(self.last_enacted.load(Ordering::SeqCst));
+		self.clean_all_logs()?;
+		let mut options = self.options.clone();
+		options.columns = self.columns.iter_options().cloned().collect();
+		let mut dropped = self.columns.dropped();
+		dropped.insert(col);
+		options.write_metadata_with_dropped(
+			&options.path,
+			&self.options.salt.unwrap_or_default(),
+			Some(self.db_version),
+			&dropped,
+		)?;
+		self.columns.set_dropped(col);
+		let files = self.columns[col as usize].detach_files(
+			col,
+			&self.options.path,
+			self.columns.options(col),
+			self.db_version,
+		)?;
+		self.log.invalidate_column(col);
+		self.dropped_files.lock().extend(files);
+		log::info!(target: "parity-db", "Dropped column {}", col);
+		if background {
+			self.cleanup_worker_wait.signal();
 		} else {
-			None
-		};
-		Ok(Db {
-			inner: db,
-			commit_thread,
-			flush_thread,
-			log_thread,
-			cleanup_thread,
-			join_on_shutdown: start_threads,
-		})
+			self.remove_dropped_files()?;
+		}
+		Ok(())
 	}
 
-	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		self.inner.get(col, key)
+	fn check_not_migrating(&self, col: ColId) -> Result<()> {
+		if self.is_migrating(col) {
+			return Err(Error::InvalidInput(format!("Column {} is being migrated", col)))
+		}
+		Ok(())
 	}
 
-	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
-		self.inner.get_size(col, key)
+	fn is_migrating(&self, col: ColId) -> bool {
+		self.migration.lock().as_ref().is_some_and(|m| m.col == col)
 	}
 
-	pub fn iter(&self, col: ColId) -> Result<BTreeIterator> {
-		self.inner.btree_iter(col)
+	// Start rewriting the values of an encrypted column with the current key.
+	#[cfg(feature = "encryption")]
+	fn start_reencryption(&self, col: ColId) -> Result<()> {
+		self.check_writable()?;
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		if self.columns.options(col).encryption.is_none() {
+			return Err(Error::InvalidInput(format!("Column {} is not encrypted", col)))
+		}
+		let tables = self.columns[col as usize]
+			.with_value_tables(|tables| tables.iter().map(|t| t.occupancy()).collect::<Vec<_>>());
+		let mut reencryption = self.reencryption.lock();
+		if reencryption.as_ref().is_some_and(|r| r.col != col && !r.progress.done) {
+			return Err(Error::InvalidInput("A column re-encryption is already in progress".into()))
+		}
+		*reencryption = Some(Reencryption {
+			col,
+			tier: 0,
+			index: 1,
+			ends: tables.iter().map(|t| t.filled + 1).collect(),
+			progress: crate::stats::ReencryptionProgress {
+				total_entries: tables.iter().map(|t| t.filled).sum(),
+				..Default::default()
+			},
+		});
+		Ok(())
 	}
 
-	pub fn commit<I, K>(&self, tx: I) -> Result<()>
-	where
-		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
-		K: AsRef<[u8]>,
-	{
-		self.inner.commit(tx)
-	}
+	// Rewrite the next batch of values encrypted with an old key. Runs on the log worker, so that
+	// the values are read and written in order with commits.
+	#[cfg(feature = "encryption")]
+	fn process_reencryption(&self) -> Result<bool> {
+		let mut guard = self.reencryption.lock();
+		let reencryption = match guard.as_mut() {
+			Some(reencryption) if !reencryption.progress.done => reencryption,
+			_ => return Ok(false),
+		};
+		let column = &self.columns[reencryption.col as usize];
+		column.with_value_tables(|tables| -> Result<()> {
+			let mut stale = Vec::new();
+			let mut scanned = 0;
+			while scanned < REENCRYPTION_BATCH_SIZE && reencryption.tier < tables.len() {
+				let tier = reencryption.tier;
+				if reencryption.index >= reencryption.ends[tier] {
+					reencryption.tier += 1;
+					reencryption.index = 1;
+					continue
+				}
+				if tables[tier].has_stale_key(reencryption.index, self.log.overlays())? {
+					stale.push((tier, reencryption.index));
+				}
+				reencryption.index += 1;
+				scanned += 1;
+			}
+			reencryption.progress.scanned_entries += scanned;
+			reencryption.progress.done = reencryption.tier == tables.len();
+			if stale.is_empty() {
+				return Ok(())
+			}
 
-	pub fn commit_changes<I>(&self, tx: I) -> Result<()>
-	where
-		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
-	{
-		self.inner.commit_changes(tx)
+			let mut writer = self.log.begin_record();
+			log::debug!(
+				target: "parity-db",
+				"Creating re-encryption record {}",
+				writer.record_id(),
+			);
+			let mut rewritten = 0;
+			for (tier, index) in stale {
+				if tables[tier].write_reencrypt_plan(index, &mut writer)? {
+					rewritten += 1;
+				}
+			}
+			for table in tables {
+				table.complete_plan(&mut writer)?;
+			}
+			let l = writer.drain();
+			let mut logged_bytes = self.log_queue_wait.work.lock();
+			*logged_bytes += self.log.end_record(l)? as i64;
+			reencryption.progress.reencrypted_entries += rewritten;
+			self.flush_worker_wait.signal();
+			Ok(())
+		})?;
+		Ok(!reencryption.progress.done)
 	}
 
-	pub(crate) fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
-		self.inner.commit_raw(commit)
+	#[cfg(feature = "encryption")]
+	fn reencryption_progress(&self, col: ColId) -> Option<crate::stats::ReencryptionProgress> {
+		self.reencryption.lock().as_ref().filter(|r| r.col == col).map(|r| r.progress)
 	}
 
-	pub fn num_columns(&self) -> u8 {
-		self.inner.columns.len() as u8
-	}
+	// Start rewriting a column with new options.
+	fn start_migration(&self, col: ColId, options: ColumnOptions, background: bool) -> Result<()> {
+		self.check_writable()?;
+		self.check_on_disk()?;
+		if col as usize >= self.columns.len() || self.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		options.validate()?;
+		let current = self.columns.options(col);
+		if current.as_string() == options.as_string() {
+			return Err(Error::InvalidInput("Column options are unchanged".into()))
+		}
+		if current.ref_counted != options.ref_counted ||
+			(current.ref_counted && current.btree_index)
+		{
+			return Err(Error::InvalidInput(
+				"Changing `ref_counted` or migrating reference counted btree indexed columns is not supported".into(),
+			))
+		}
+		if !current.secondary_indexes.is_empty() || !options.secondary_indexes.is_empty() {
+			return Err(Error::InvalidInput(
+				"Migrating columns with secondary indexes is not supported".into(),
+			))
+		}
+		if !current.btree_index && options.btree_index {
+			return Err(Error::InvalidInput(
+				"Hash indexed columns can't be migrated to btree, keys are not stored".into(),
+			))
+		}
+		if current.ttl != options.ttl ||
+			current.store_keys != options.store_keys ||
+			(!current.btree_index &&
+				(current.uniform != options.uniform || current.key_hash != options.key_hash))
+		{
+			return Err(Error::InvalidInput(
+				"Changing `ttl`, `store_keys`, `uniform` or `key_hash` of a hash indexed column is not supported"
+					.into(),
+			))
+		}
+		if !self.snapshots.lock().is_empty() {
+			return Err(Error::InvalidInput("Can't migrate a column with open snapshots".into()))
+		}
+		let mut migration = self.migration.lock();
+		if migration.is_some() {
+			return Err(Error::InvalidInput("A column migration is already in progress".into()))
+		}
 
-	pub(crate) fn iter_column_while(
-		&self,
-		c: ColId,
-		f: impl FnMut(IterState) -> bool,
-	) -> Result<()> {
-		self.inner.iter_column_while(c, f)
+		let path = crate::migration::live_migration_path(&self.options.path, col);
+		if path.exists() {
+			try_io!(std::fs::remove_dir_all(&path));
+		}
+		try_io!(std::fs::create_dir_all(&path));
+		let mut target_options = self.options.clone();
+		target_options.path = path.clone();
+		target_options.columns = vec![ColumnOptions::default(); col as usize + 1];
+		target_options.columns[col as usize] = options.clone();
+		let salt = self.options.salt.unwrap_or_default();
+		// Tables are moved to this database once done, so they must use the same version.
+		target_options.write_metadata_with_version(&path, &salt, Some(self.db_version))?;
+		let target = Db::open(&target_options)?;
+
+		let progress = if current.btree_index {
+			CopyProgress::Tree(None)
+		} else {
+			CopyProgress::Hash { chunk: 0, index_bits: 0 }
+		};
+		// Values not yet written to the column tables are not copied. Commits are applied to
+		// the target once the migration is set.
+		let _queue = self.lock_idle(background)?;
+		let _overlay = self.commit_overlay.write();
+		*migration = Some(Arc::new(ColumnMigration {
+			col,
+			options,
+			path,
+			target,
+			progress: Mutex::new(progress),
+			copied: AtomicU64::new(0),
+		}));
+		log::info!(target: "parity-db", "Started migration of column {}", col);
+		Ok(())
 	}
 
-	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.cleanup_worker_wait.signal();
-				if !db.log.has_log_files_to_read() {
-					db.commit_worker_wait.wait();
+	// Copy a batch of values to the migration target, or complete the migration once everything
+	// is copied. Returns `true` if there is more work to do.
+	fn migrate_step(&self, background: bool) -> Result<bool> {
+		let migration = match self.migration.lock().clone() {
+			Some(migration) => migration,
+			None => return Ok(false),
+		};
+		let col = migration.col;
+		let mut progress = migration.progress.lock();
+		let keys = match (&mut *progress, &self.columns[col as usize]) {
+			(CopyProgress::Hash { chunk, index_bits }, Column::Hash(column)) => {
+				let (bits, reindexing) = column.index_state();
+				if reindexing {
+					// Entries move between index tables while reindexing.
+					if background {
+						thread::sleep(Duration::from_millis(10));
+					} else {
+						drop(self.lock_idle(false)?);
+					}
+					return Ok(true)
+				}
+				if bits != *index_bits {
+					log::debug!(target: "parity-db", "{}: Restarting migration copy", col);
+					*chunk = 0;
+					*index_bits = bits;
+				}
+				let mut keys = Vec::new();
+				let mut next_chunk = *chunk;
+				column.iter_index_while(&self.log, *chunk, None, |state| {
+					keys.push(state.key.to_vec());
+					next_chunk = state.chunk_index;
+					keys.len() < MIGRATION_BATCH_SIZE
+				})?;
+				if keys.len() < MIGRATION_BATCH_SIZE && column.index_state() == (bits, false) {
+					*progress = CopyProgress::Done;
+				} else {
+					// The last chunk may be partially copied, start with it next time.
+					*chunk = next_chunk;
+				}
+				keys
+			},
+			(CopyProgress::Tree(last), Column::Tree(_)) => {
+				let mut iter = self.btree_iter(col)?;
+				match last {
+					Some(key) => iter.seek(key)?,
+					None => iter.seek_to_first()?,
+				}
+				let mut keys = Vec::new();
+				while keys.len() < MIGRATION_BATCH_SIZE {
+					match iter.next()? {
+						Some((key, _)) if Some(&key) == last.as_ref() => (),
+						Some((key, _)) => keys.push(key),
+						None => break,
+					}
+				}
+				if keys.len() < MIGRATION_BATCH_SIZE {
+					*progress = CopyProgress::Done;
+				} else {
+					*last = keys.last().cloned();
+				}
+				keys
+			},
+			(CopyProgress::Done, _) => Vec::new(),
+			_ => unreachable!("Column index type does not change during migration"),
+		};
+
+		if !keys.is_empty() {
+			migration.copied.fetch_add(keys.len() as u64, Ordering::Relaxed);
+			// Values are read again under the overlay lock, so that the copy does not overwrite
+			// more recent commits, which are applied to the target directly. Reference counts
+			// are copied as the difference to the target, with no commits queued on either side.
+			let ref_counted = self.columns.options(col).ref_counted;
+			let _queue = if ref_counted { Some(self.lock_idle(background)?) } else { None };
+			let overlay = self.commit_overlay.write();
+			if ref_counted {
+				drop(migration.target.inner.lock_idle(migration.target.join_on_shutdown)?);
+			}
+			let mut target = CommitChangeSet::default();
+			for key in keys {
+				let value = match &self.columns[col as usize] {
+					Column::Hash(column) => {
+						let mut hashed = Key::default();
+						hashed.copy_from_slice(&key);
+						if ref_counted {
+							let source = column.get_with_meta(&hashed, self.log.overlays())?;
+							migration.set_ref_counted(&mut target, hashed, source)?;
+							continue
+						}
+						let value = match overlay[col as usize].get(&hashed) {
+							Some(value) => value,
+							None => column.get(&hashed, self.log.overlays())?,
+						};
+						if let Some(value) = value {
+							target
+								.indexed
+								.entry(col)
+								.or_insert_with(|| IndexedChangeSet::new(col))
+								.push_change_hashed(Operation::Set(hashed, value));
+						}
+						continue
+					},
+					Column::Tree(_) => self.get_with_overlay(col, &key, &overlay)?,
+				};
+				if let Some(value) = value {
+					migration.set(&mut target, &key, value);
 				}
 			}
+			migration.target.inner.commit_raw(target)?;
+		}
+		if !migration.target.join_on_shutdown {
+			drop(migration.target.inner.lock_idle(false)?);
+		}
 
-			more_work = db.enact_logs(false)?;
+		if matches!(*progress, CopyProgress::Done) {
+			drop(progress);
+			drop(migration);
+			return self.finish_migration(background)
 		}
-		log::debug!(target: "parity-db", "Commit worker shutdown");
-		Ok(())
+		Ok(!self.shutdown.load(Ordering::SeqCst))
 	}
 
-	fn log_worker(db: Arc<DbInner>) -> Result<()> {
-		// Start with pending reindex.
-		let mut more_work = db.process_reindex()?;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.log_worker_wait.wait();
+	// Replace the migrated column with the target one. Returns `true` if this needs to be
+	// retried later.
+	fn finish_migration(&self, background: bool) -> Result<bool> {
+		// Make sure detached files of earlier changes are not confused with the ones
+		// detached here.
+		self.remove_dropped_files()?;
+		let _queue = self.lock_idle(background)?;
+		let _overlay = self.commit_overlay.write();
+		if !self.snapshots.lock().is_empty() {
+			if !background {
+				self.abort_migration()?;
+				return Err(Error::InvalidInput("Can't migrate a column with open snapshots".into()))
 			}
+			// Snapshot values are tracked per column, the column can't change under them.
+			thread::sleep(Duration::from_millis(10));
+			return Ok(!self.shutdown.load(Ordering::SeqCst))
+		}
+		// Held until the column is replaced, so that `is_migrating` does not return early.
+		let mut current_migration = self.migration.lock();
+		let migration = match current_migration.take() {
+			Some(migration) => migration,
+			None => return Ok(false),
+		};
+		let ColumnMigration { col, options, path, target, .. } = Arc::try_unwrap(migration)
+			.expect("Migration is not referenced outside of `migrate_step`");
+		{
+			let _target_queue = target.inner.lock_idle(target.join_on_shutdown)?;
+			target.inner.clean_all_logs()?;
+		}
+		drop(target);
+		let _tables = self.tables_lock.read();
+		self.modified_pages.lock().reset(self.last_enacted.load(Ordering::SeqCst));
+		self.clean_all_logs()?;
 
-			let more_commits = db.process_commits()?;
-			let more_reindex = db.process_reindex()?;
-			more_work = more_commits || more_reindex;
+		try_io!(std::fs::write(
+			path.join(crate::migration::LIVE_MIGRATION_MARKER),
+			options.as_string()
+		));
+		let files = self.columns[col as usize].detach_files(
+			col,
+			&self.options.path,
+			self.columns.options(col),
+			self.db_version,
+		)?;
+		crate::migration::move_column(col, &path, &self.options.path)?;
+		let mut db_options = self.options.clone();
+		db_options.columns = self.columns.iter_options().cloned().collect();
+		db_options.columns[col as usize] = options.clone();
+		let salt = self.options.salt.unwrap_or_default();
+		let dropped = self.columns.dropped();
+		db_options.write_metadata_with_dropped(
+			&db_options.path,
+			&salt,
+			Some(self.db_version),
+			&dropped,
+		)?;
+		let metadata = Metadata {
+			salt,
+			version: self.db_version,
+			columns: db_options.columns.clone(),
+			dropped,
+		};
+		let column = Column::open(col, &db_options, &metadata)?;
+		column.build_filter(self.log.overlays())?;
+		self.columns.replace(col, column, options)?;
+		self.log.invalidate_column(col);
+		try_io!(std::fs::remove_dir_all(&path));
+		self.dropped_files.lock().extend(files);
+		if !background {
+			self.remove_dropped_files()?;
+		}
+		drop(current_migration);
+		log::info!(target: "parity-db", "Migrated column {}", col);
+		Ok(false)
+	}
+
+	fn abort_migration(&self) -> Result<()> {
+		if let Some(migration) = self.migration.lock().take() {
+			let path = migration.path.clone();
+			drop(migration);
+			try_io!(std::fs::remove_dir_all(path));
 		}
-		log::debug!(target: "parity-db", "Log worker shutdown");
 		Ok(())
 	}
 
-	fn flush_worker(db: Arc<DbInner>, min_log_size: u64) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) {
-			if !more_work {
-				db.flush_worker_wait.wait();
-			}
-			more_work = db.flush_logs(min_log_size)?;
+	fn remove_dropped_files(&self) -> Result<()> {
+		let files = std::mem::take(&mut *self.dropped_files.lock());
+		for file in files {
+			log::debug!(target: "parity-db", "Removing {}", file.display());
+			try_io!(self.options.files().remove(&file));
 		}
-		log::debug!(target: "parity-db", "Flush worker shutdown");
 		Ok(())
 	}
 
-	fn cleanup_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = true;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.cleanup_worker_wait.wait();
+	// Wait until all queued commits are written to the log and enacted. Returns the locked
+	// commit queue, so that no new commits are accepted while the guard is held.
+	fn lock_idle(&self, background: bool) -> Result<MutexGuard<'_, CommitQueue>> {
+		self.check_writable()?;
+		loop {
+			{
+				let queue = self.commit_queue.lock();
+				let logged = self.durability.lock().logged;
+				if queue.commits.is_empty() &&
+					(queue.record_id == 0 || logged > queue.record_id) &&
+					self.next_reindex.load(Ordering::SeqCst) == 0 &&
+					!self.log.has_log_files_to_read() &&
+					self.log.overlays().read().is_empty()
+				{
+					return Ok(queue)
+				}
+			}
+			if let Some(err) = &*self.bg_err.lock() {
+				return Err(Error::Background(err.clone()))
+			}
+			if background {
+				if self.shutdown.load(Ordering::SeqCst) {
+					return Err(Error::InvalidInput("Database is shutting down".to_string()))
+				}
+				let mut durability = self.durability.lock();
+				// Waiters make the flush worker flush the log regardless of its size.
+				durability.waiters += 1;
+				self.log_worker_wait.signal();
+				self.flush_worker_wait.signal();
+				self.durability_cv.wait_for(&mut durability, Duration::from_millis(10));
+				durability.waiters -= 1;
+			} else {
+				while self.process_commits()? {}
+				while self.process_reindex()? {}
+				self.log.flush_one(0)?;
+				while self.enact_logs(false)? {}
 			}
-			more_work = db.clean_logs()?;
 		}
-		log::debug!(target: "parity-db", "Cleanup worker shutdown");
-		Ok(())
 	}
 
-	pub fn write_stats_text(
-		&self,
-		writer: &mut impl std::io::Write,
-		column: Option<u8>,
-	) -> Result<()> {
-		self.inner.write_stats_text(writer, column)
+	// Remove expired values from `ttl` columns, scanning up to `EXPIRY_SCAN_CHUNKS` index
+	// chunks and removing up to `EXPIRY_BATCH_SIZE` values in a single commit. A sweep over all
+	// columns starts `Options::expiry_interval` after the previous one ended. Returns whether
+	// the sweep is in progress.
+	fn expire_step(&self) -> Result<bool> {
+		let mut sweep = self.expiry.lock();
+		let (start_col, start_chunk) = match sweep.next {
+			Some(next) => next,
+			None if Instant::now() >= sweep.next_sweep => (0, 0),
+			None => return Ok(false),
+		};
+		let next = (start_col as usize..self.columns.len()).find_map(|c| match &self.columns[c] {
+			Column::Hash(column) if self.columns.options(c as ColId).ttl => Some((c, column)),
+			_ => None,
+		});
+		let (col, column) = match next {
+			Some((c, column)) => (c as ColId, column),
+			None => {
+				sweep.next = None;
+				sweep.next_sweep = Instant::now() + self.options.expiry_interval;
+				return Ok(false)
+			},
+		};
+		let (bits, reindexing) = column.index_state();
+		if reindexing {
+			// Entries move between index tables while reindexing, resume once done.
+			sweep.next = Some((col, start_chunk));
+			return Ok(false)
+		}
+		let mut chunk = start_chunk;
+		if col != start_col || bits != sweep.index_bits {
+			chunk = 0;
+			sweep.index_bits = bits;
+		}
+		let now = now_millis();
+		let end = chunk + EXPIRY_SCAN_CHUNKS;
+		let mut expired = Vec::new();
+		let mut next_chunk = end;
+		column.iter_index_while(&self.log, chunk, Some(end), |state| {
+			if is_expired(&state.value, now) {
+				expired.push(state.key);
+			}
+			// The last chunk may be partially scanned, start with it next time.
+			next_chunk = state.chunk_index;
+			expired.len() < EXPIRY_BATCH_SIZE
+		})?;
+		if expired.len() < EXPIRY_BATCH_SIZE {
+			next_chunk = end;
+		}
+		sweep.next = if next_chunk >= column.index_chunks() {
+			Some((col + 1, 0))
+		} else {
+			Some((col, next_chunk))
+		};
+		drop(sweep);
+		if !expired.is_empty() {
+			log::debug!(target: "parity-db", "{}: Removing {} expired values", col, expired.len());
+			let mut commit = CommitChangeSet::default();
+			commit.expired.insert(col, expired);
+			self.commit_raw(commit)?;
+		}
+		Ok(true)
 	}
 
-	pub fn clear_stats(&self, column: Option<u8>) -> Result<()> {
-		self.inner.clear_stats(column)
+	// Remove all expired values now.
+	#[cfg(test)]
+	fn expire_values(&self) -> Result<()> {
+		self.expiry.lock().next_sweep = Instant::now();
+		while self.expire_step()? {}
+		Ok(())
 	}
 
-	pub fn dump(&self, check_param: check::CheckOptions) -> Result<()> {
-		if let Some(col) = check_param.column {
-			self.inner.columns[col as usize].dump(&self.inner.log, &check_param, col)?;
+	// Copy the database to a backup directory and return the backup sequence number. Enactment
+	// is paused while the table files are copied, so they stay consistent with the copied logs
+	// that were not enacted yet. An incremental backup updates an earlier backup in `path`,
+	// only copying the table pages that changed since. A checkpoint clones the files where the
+	// filesystem supports it and is not a base for incremental backups.
+	fn backup_to(&self, path: &std::path::Path, kind: BackupKind) -> Result<u64> {
+		self.check_writable()?;
+		self.check_on_disk()?;
+		let seq_path = path.join(crate::backup::BACKUP_SEQUENCE_FILE);
+		let base = if kind == BackupKind::Incremental {
+			let seq = std::fs::read_to_string(&seq_path).map_err(|_| {
+				Error::InvalidInput(format!("No backup found at {}", path.display()))
+			})?;
+			let base = crate::backup::parse_sequence(&seq)
+				.ok_or_else(|| Error::Corruption("Bad backup sequence number".into()))?;
+			// Interrupted updates must not be used as a base.
+			try_io!(std::fs::remove_file(&seq_path));
+			Some(base)
 		} else {
-			for (ix, c) in self.inner.columns.iter().enumerate() {
-				c.dump(&self.inner.log, &check_param, ix as ColId)?;
+			if path.exists() && try_io!(std::fs::read_dir(path)).next().is_some() {
+				return Err(Error::InvalidInput(format!(
+					"Backup directory {} is not empty",
+					path.display()
+				)))
+			}
+			try_io!(std::fs::create_dir_all(path));
+			None
+		};
+		let _tables = self.tables_lock.write();
+		// Move the tail of the log to a flushed file, so that it is included.
+		self.flush_logs(0)?;
+		let seq = self.last_enacted.load(Ordering::SeqCst);
+		let mut tables = Vec::new();
+		for entry in try_io!(std::fs::read_dir(&self.options.path)) {
+			let entry = try_io!(entry);
+			if let Some(name) = entry.file_name().to_str() {
+				if !name.ends_with(crate::column::DROPPED_FILE_SUFFIX) &&
+					(0..self.columns.len() as ColId).any(|c| {
+						!self.columns.is_dropped(c) &&
+							(crate::index::TableId::is_file_name(c, name) ||
+								crate::table::TableId::is_file_name(c, name))
+					}) {
+					tables.push(entry.path());
+				}
 			}
 		}
-		Ok(())
+		let logs = self.log.unenacted_log_paths();
+		if base.is_some() {
+			// Remove old logs and tables that no longer exist.
+			for entry in try_io!(std::fs::read_dir(path)) {
+				let entry = try_io!(entry);
+				let name = entry.file_name();
+				if let Some(name) = name.to_str() {
+					let stale =
+						name.strip_prefix("log").is_some_and(|id| id.parse::<u32>().is_ok()) ||
+							((name.starts_with("index_") || name.starts_with("table_")) &&
+								!tables
+									.iter()
+									.any(|t| t.file_name() == Some(&*entry.file_name())));
+					if stale {
+						try_io!(std::fs::remove_file(entry.path()));
+					}
+				}
+			}
+		}
+		let modified = self.modified_pages.lock();
+		for file in tables.iter().chain(logs.iter()) {
+			if let Some(name) = file.file_name() {
+				let dest = path.join(name);
+				let pages = match (base, name.to_str()) {
+					(Some((base, session)), Some(name)) if dest.exists() =>
+						modified.modified_since(name, base, session),
+					_ => None,
+				};
+				if let Some(pages) = pages {
+					log::debug!(
+						target: "parity-db",
+						"Backup: copying {} pages of {}",
+						pages.len(),
+						file.display(),
+					);
+					crate::backup::copy_pages(file, &dest, &pages)?;
+				} else if kind == BackupKind::Checkpoint {
+					log::debug!(target: "parity-db", "Checkpoint: cloning {}", file.display());
+					try_io!(crate::backup::clone_file(file, &dest));
+				} else {
+					log::debug!(target: "parity-db", "Backup: copying {}", file.display());
+					try_io!(std::fs::copy(file, dest));
+				}
+			}
+		}
+		try_io!(std::fs::copy(self.options.path.join("metadata"), path.join("metadata")));
+		if kind == BackupKind::Checkpoint {
+			log::info!(target: "parity-db", "Created checkpoint {} at {}", seq, path.display());
+			return Ok(seq)
+		}
+		let session = modified.session();
+		drop(modified);
+		try_io!(std::fs::write(&seq_path, crate::backup::format_sequence(seq, session)));
+		log::info!(target: "parity-db", "Created backup {} at {}", seq, path.display());
+		Ok(seq)
 	}
 
-	pub fn stats(&self) -> StatSummary {
-		self.inner.stats()
+	fn clean_all_logs(&self) -> Result<()> {
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		let num_cleanup = self.log.num_dirty_logs();
+		self.log.clean_logs(num_cleanup)?;
+		Ok(())
 	}
 
-	/// Add a new column with options specified by `new_column_options`.
-	pub fn add_column(options: &mut Options, new_column_options: ColumnOptions) -> Result<()> {
-		// We open the DB before to check metadata validity and make sure there are no pending WAL
-		// logs.
-		let db = Db::open(options)?;
-		let salt = db.inner.options.salt;
-		drop(db);
-
-		options.columns.push(new_column_options);
-		options.write_metadata_with_version(
-			&options.path,
-			&salt.expect("`salt` is always `Some` after opening the DB; qed"),
-			Some(CURRENT_VERSION),
-		)?;
-
-		Ok(())
+	// Handle a log record that can't be replayed. All remaining logs are discarded, unless
+	// repairing, in which case only the rest of the current log file is skipped.
+	fn replay_failed(&self, reason: String) -> Result<()> {
+		if let Some(report) = &self.repair {
+			log::warn!(target: "parity-db", "Skipping the rest of log file: {}", reason);
+			report.lock().skipped_logs.push(reason);
+			return Ok(())
+		}
+		match self.options.recovery_mode {
+			RecoveryMode::Strict => (),
+			RecoveryMode::TolerateTail if !self.log.is_last_replay_log() => (),
+			RecoveryMode::TolerateTail | RecoveryMode::Salvage => {
+				log::warn!(target: "parity-db", "Discarding the rest of the log: {}", reason);
+				let discarded_logs = self.log.clear_replay_logs();
+				let mut recovery = self.recovery.lock();
+				recovery.error = Some(reason);
+				recovery.discarded_logs = discarded_logs;
+				return Ok(())
+			},
+		}
+		self.log.abort_replay();
+		Err(Error::LogCorruption(format!("Can't replay: {}", reason)))
 	}
 
-	#[cfg(feature = "instrumentation")]
-	pub fn process_reindex(&self) -> Result<()> {
-		self.inner.process_reindex()?;
+	// Check all columns, remove hash index entries of unreadable values and restore missing
+	// entries of `preimage` columns. Changes are written as a single log record and enacted.
+	fn repair(&self) -> Result<()> {
+		let mut report = match &self.repair {
+			Some(report) => report.lock(),
+			None => return Ok(()),
+		};
+		let mut writer = self.log.begin_record();
+		for (col, column) in self.columns.iter().enumerate() {
+			let col = col as ColId;
+			if self.columns.is_dropped(col) {
+				continue
+			}
+			let mut column_check = check::ColumnCheck { col, ..Default::default() };
+			let (removed, restored) = column.repair(&self.log, &mut writer, &mut column_check)?;
+			report.removed += removed;
+			report.restored += restored;
+			report.columns.push(column_check);
+		}
+		for c in self.columns.iter() {
+			c.complete_plan(&mut writer)?;
+		}
+		self.log.end_record(writer.drain())?;
+		self.flush_logs(0)?;
+		while self.enact_logs(false)? {}
+		self.clean_all_logs()?;
 		Ok(())
 	}
 
-	#[cfg(feature = "instrumentation")]
-	pub fn process_commits(&self) -> Result<()> {
-		self.inner.process_commits()?;
+	fn replay_all_logs(&mut self) -> Result<()> {
+		let clean_shutdown = self.take_clean_shutdown()?;
+		let mut replayed = false;
+		while let Some(id) = self.log.replay_next()? {
+			log::debug!(target: "parity-db", "Replaying database log {}", id);
+			while self.enact_logs(true)? {}
+			replayed = true;
+		}
+		if replayed {
+			self.recovery.lock().last_replayed = self.last_enacted.load(Ordering::SeqCst);
+		}
+		// Filters saved on a clean shutdown are only up to date if there was nothing to replay.
+		let filters = clean_shutdown.filter(|_| !replayed && self.repair.is_none());
+		// Re-read any cached metadata
+		for (col, c) in self.columns.iter().enumerate() {
+			c.refresh_metadata()?;
+			if let Some(filters) = &filters {
+				// All blobs were committed or removed before the shutdown.
+				if filters.get(&(col as ColId)).is_some_and(|filter| c.restore_filter(filter)) {
+					continue
+				}
+			} else {
+				c.collect_blobs()?;
+			}
+			c.build_filter(self.log.overlays())?;
+		}
+		self.clean_shutdown = filters.is_some();
+		log::debug!(target: "parity-db", "Replay is complete.");
 		Ok(())
 	}
 
-	#[cfg(feature = "instrumentation")]
-	pub fn flush_logs(&self) -> Result<()> {
-		self.inner.flush_logs(0)?;
-		Ok(())
+	// Remove the clean shutdown marker. Returns the Bloom filters saved with it by column, or
+	// `None` if the database was not closed cleanly.
+	fn take_clean_shutdown(&self) -> Result<Option<HashMap<ColId, Vec<u8>>>> {
+		let files = self.options.files();
+		let path = self.options.path.join(CLEAN_SHUTDOWN_FILE);
+		let data = match try_io!(files.read(&path)) {
+			Some(data) => data,
+			None => return Ok(None),
+		};
+		try_io!(files.remove(&path));
+		// Must not be found again if the database is not closed cleanly this time.
+		try_io!(files.sync_dir(&self.options.path));
+		if data.len() < 4 || crc32fast::hash(&data[4..]).to_le_bytes() != data[..4] {
+			log::warn!(target: "parity-db", "Ignoring corrupted clean shutdown marker");
+			return Ok(None)
+		}
+		let mut filters = HashMap::new();
+		let mut rest = &data[4..];
+		while let Some((&col, tail)) = rest.split_first() {
+			let len =
+				u64::from_le_bytes(tail[..8].try_into().expect("Checked by the crc")) as usize;
+			filters.insert(col, tail[8..8 + len].to_vec());
+			rest = &tail[8 + len..];
+		}
+		Ok(Some(filters))
 	}
 
-	#[cfg(feature = "instrumentation")]
-	pub fn enact_logs(&self) -> Result<()> {
-		while self.inner.enact_logs(false)? {}
+	// Write the clean shutdown marker, once all logs are enacted and removed. See
+	// `discard_clean_shutdown`.
+	fn write_clean_shutdown(&self) -> Result<()> {
+		let mut data = vec![0; 4];
+		for (col, c) in self.columns.iter().enumerate() {
+			if let Some(filter) = c.filter_bytes() {
+				data.push(col as ColId);
+				data.extend_from_slice(&(filter.len() as u64).to_le_bytes());
+				data.extend_from_slice(&filter);
+			}
+		}
+		let crc = crc32fast::hash(&data[4..]);
+		data[..4].copy_from_slice(&crc.to_le_bytes());
+		try_io!(self.options.files().write(&self.options.path.join(CLEAN_SHUTDOWN_FILE), &data));
 		Ok(())
 	}
 
-	#[cfg(feature = "instrumentation")]
-	pub fn clean_logs(&self) -> Result<()> {
-		self.inner.clean_logs()?;
-		Ok(())
+	fn shutdown(&self) {
+		self.shutdown.store(true, Ordering::SeqCst);
+		self.log_queue_wait.cv.notify_one();
+		self.flush_worker_wait.signal();
+		self.log_worker_wait.signal();
+		self.commit_worker_wait.signal();
+		self.cleanup_worker_wait.signal();
+		self.scrub_wait.signal();
 	}
-}
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		if self.join_on_shutdown {
-			self.inner.shutdown();
-			if let Some(t) = self.log_thread.take() {
-				if let Err(e) = t.join() {
-					log::warn!(target: "parity-db", "Log thread shutdown error: {:?}", e);
-				}
-			}
-			if let Some(t) = self.flush_thread.take() {
-				if let Err(e) = t.join() {
-					log::warn!(target: "parity-db", "Flush thread shutdown error: {:?}", e);
-				}
-			}
-			if let Some(t) = self.commit_thread.take() {
-				if let Err(e) = t.join() {
-					log::warn!(target: "parity-db", "Commit thread shutdown error: {:?}", e);
-				}
+	fn kill_logs(&self) -> Result<()> {
+		{
+			if let Some(err) = self.bg_err.lock().as_ref() {
+				// On error the log reader may be left in inconsistent state. So it is important
+				// to no attempt any further log enactment.
+				log::debug!(target: "parity-db", "Shutdown with error state {}", err);
+				self.log.clean_logs(self.log.num_dirty_logs())?;
+				return self.log.kill_logs()
 			}
-			if let Some(t) = self.cleanup_thread.take() {
-				if let Err(e) = t.join() {
-					log::warn!(target: "parity-db", "Cleanup thread shutdown error: {:?}", e);
-				}
+		}
+		log::debug!(target: "parity-db", "Processing leftover commits");
+		// Finish logged records and proceed to log and enact queued commits.
+		while self.enact_logs(false)? {}
+		self.flush_logs(0)?;
+		while self.process_commits()? {}
+		while self.enact_logs(false)? {}
+		self.flush_logs(0)?;
+		while self.enact_logs(false)? {}
+		self.clean_all_logs()?;
+		self.log.kill_logs()?;
+		self.write_clean_shutdown()?;
+		if self.options.stats && self.options.storage.is_none() {
+			let mut path = self.options.path.clone();
+			path.push("stats.txt");
+			match std::fs::File::create(path) {
+				Ok(file) => {
+					let mut writer = std::io::BufWriter::new(file);
+					if let Err(e) = self.write_stats_text(&mut writer, None) {
+						log::warn!(target: "parity-db", "Error writing stats file: {:?}", e)
+					}
+				},
+				Err(e) => log::warn!(target: "parity-db", "Error creating stats file: {:?}", e),
 			}
-			if let Err(e) = self.inner.kill_logs() {
-				log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
+		}
+		Ok(())
+	}
+
+	fn write_stats_text(&self, writer: &mut impl std::io::Write, column: Option<u8>) -> Result<()> {
+		if let Some(col) = column {
+			self.columns[col as usize].write_stats_text(writer)
+		} else {
+			for c in self.columns.iter() {
+				c.write_stats_text(writer)?;
 			}
+			Ok(())
 		}
 	}
-}
 
-pub type IndexedCommitOverlay = HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>;
-pub type BTreeCommitOverlay = BTreeMap<Vec<u8>, (u64, Option<Value>)>;
+	fn clear_stats(&self, column: Option<u8>) -> Result<()> {
+		self.check_writable()?;
+		if let Some(col) = column {
+			self.columns[col as usize].clear_stats()
+		} else {
+			for c in self.columns.iter() {
+				c.clear_stats()?;
+			}
+			Ok(())
+		}
+	}
 
-#[derive(Debug)]
-pub struct CommitOverlay {
-	indexed: IndexedCommitOverlay,
-	btree_indexed: BTreeCommitOverlay,
-}
+	fn stats(&self) -> StatSummary {
+		StatSummary { columns: self.columns.iter().map(|c| c.stats()).collect() }
+	}
 
-impl CommitOverlay {
-	fn new() -> Self {
-		CommitOverlay { indexed: Default::default(), btree_indexed: Default::default() }
+	#[cfg(feature = "metrics")]
+	fn metrics(&self, f: impl FnOnce(&dyn MetricsSink)) {
+		if let Some(metrics) = &self.options.metrics {
+			f(&*metrics.0)
+		}
 	}
 
-	#[cfg(test)]
-	fn is_empty(&self) -> bool {
-		self.indexed.is_empty() && self.btree_indexed.is_empty()
+	// Report value table and statistics gauges.
+	#[cfg(feature = "metrics")]
+	fn report_metrics(&self) {
+		self.metrics(|m| {
+			for (col, column) in self.columns.iter().enumerate() {
+				let col = Some(col as ColId);
+				let stats = column.column_stats();
+				let filled = stats.tables.iter().map(|t| t.filled).sum::<u64>();
+				let capacity = stats.tables.iter().map(|t| t.capacity).sum::<u64>();
+				m.set(metrics::VALUE_TABLE_ENTRIES, col, filled as f64);
+				m.set(metrics::VALUE_TABLE_CAPACITY, col, capacity as f64);
+				let hits = stats.query_hits.iter().sum::<u64>();
+				if hits + stats.query_misses > 0 {
+					let ratio = hits as f64 / (hits + stats.query_misses) as f64;
+					m.set(metrics::QUERY_HIT_RATIO, col, ratio);
+				}
+				if stats.total_bytes > 0 {
+					let ratio = stats.uncompressed_bytes as f64 / stats.total_bytes as f64;
+					m.set(metrics::COMPRESSION_RATIO, col, ratio);
+				}
+				if stats.total_values > 0 {
+					let ratio = stats.below_threshold_values as f64 / stats.total_values as f64;
+					m.set(metrics::BELOW_COMPRESSION_THRESHOLD_RATIO, col, ratio);
+				}
+			}
+		});
 	}
-}
 
-impl CommitOverlay {
-	fn get_ref(&self, key: &[u8]) -> Option<Option<&Value>> {
-		self.indexed.get(key).map(|(_, v)| v.as_ref())
+	fn store_err(&self, result: Result<()>) {
+		if let Err(e) = result {
+			log::warn!(target: "parity-db", "Background worker error: {}", e);
+			let mut err = self.bg_err.lock();
+			if err.is_none() {
+				*err = Some(Arc::new(e));
+				self.shutdown();
+			}
+			self.commit_queue_full_cv.notify_all();
+			self.durability_cv.notify_all();
+		}
 	}
 
-	fn get(&self, key: &[u8]) -> Option<Option<Value>> {
-		self.get_ref(key).map(|v| v.cloned())
+	// Verify up to `SCRUB_BATCH_CHUNKS` index chunks of column `col` starting at `from`, or the
+	// whole btree. Returns the number of bytes read and the next chunk, if any.
+	fn scrub_step(&self, col: ColId, from: u64, on_issue: &ScrubFn) -> (u64, Option<u64>) {
+		if self.columns.is_dropped(col) {
+			return (0, None)
+		}
+		let column = &self.columns[col as usize];
+		let bound = from + SCRUB_BATCH_CHUNKS;
+		let (index_bytes, next) = match column {
+			Column::Hash(c) => {
+				let chunks = c.index_chunks();
+				let read = std::cmp::min(bound, chunks).saturating_sub(from);
+				(read * CHUNK_LEN as u64, if bound < chunks { Some(bound) } else { None })
+			},
+			Column::Tree(_) => (0, None),
+		};
+		let check_param = check::CheckOptions::new(Some(col), Some(from), Some(bound), false, None);
+		let mut report = check::ColumnCheck { col, ..Default::default() };
+		if let Err(e) = column.check(&self.log, &check_param, &mut report) {
+			log::warn!(target: "parity-db", "Scrubbing column {} failed: {}", col, e);
+		}
+		for issue in &report.issues {
+			on_issue(col, issue);
+		}
+		(index_bytes + report.bytes, next)
 	}
 
-	fn get_size(&self, key: &[u8]) -> Option<Option<u32>> {
-		self.get_ref(key).map(|res| res.as_ref().map(|b| b.len() as u32))
+	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		match &self.columns[c as usize] {
+			Column::Hash(column) => column.iter_while(&self.log, f),
+			Column::Tree(_) => unimplemented!(),
+		}
 	}
 
-	fn btree_get(&self, key: &[u8]) -> Option<Option<&Value>> {
-		self.btree_indexed.get(key).map(|(_, v)| v.as_ref())
+	fn exact_len(&self, col: ColId) -> Result<u64> {
+		let column = self.check_column(col)?;
+		if column.key_count().is_none() {
+			return Err(Error::InvalidInput(format!("Keys of column {} are not counted", col)))
+		}
+		if !self.read_only {
+			// Keys are counted as commits are written to the log.
+			self.wait_logged(self.last_commit_id())?;
+		}
+		Ok(column.key_count().unwrap_or_default())
 	}
 
-	pub fn btree_next(&self, last_key: &crate::btree::LastKey) -> Option<(Value, Option<Value>)> {
-		use crate::btree::LastKey;
-		match &last_key {
-			LastKey::Start => self
-				.btree_indexed
-				.range::<Vec<u8>, _>(..)
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
-			LastKey::End => None,
-			LastKey::At(key) => self
-				.btree_indexed
-				.range::<Vec<u8>, _>((Bound::Excluded(key), Bound::Unbounded))
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
-			LastKey::Seeked(key) => self
-				.btree_indexed
-				.range::<Vec<u8>, _>(key..)
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
+	fn approximate_len(&self, col: ColId) -> Result<u64> {
+		match self.check_column(col)? {
+			column if column.key_count().is_some() => Ok(column.key_count().unwrap_or_default()),
+			Column::Hash(column) => column.estimate_len(&self.log, LEN_SAMPLE_CHUNKS),
+			Column::Tree(_) => Err(Error::InvalidInput(format!(
+				"Keys of btree indexed column {} are not counted",
+				col
+			))),
 		}
 	}
 
-	pub fn btree_prev(&self, last_key: &crate::btree::LastKey) -> Option<(Value, Option<Value>)> {
-		use crate::btree::LastKey;
-		match &last_key {
-			LastKey::End => self
-				.btree_indexed
-				.range::<Vec<u8>, _>(..)
-				.rev()
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
-			LastKey::Start => None,
-			LastKey::At(key) => self
-				.btree_indexed
-				.range::<Vec<u8>, _>(..key)
-				.rev()
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
-			LastKey::Seeked(key) => self
-				.btree_indexed
-				.range::<Vec<u8>, _>(..=key)
-				.rev()
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone())),
+	fn estimate_range_size(&self, col: ColId, start: &[u8], end: &[u8]) -> Result<RangeSize> {
+		self.check_tree_column(col)?.estimate_range(start, end, self.log.overlays())
+	}
+
+	fn check_tree_column(&self, col: ColId) -> Result<&BTreeTable> {
+		match self.check_column(col)? {
+			Column::Tree(column) => Ok(column),
+			Column::Hash(_) =>
+				Err(Error::InvalidInput(format!("Column {} is not btree indexed", col))),
 		}
 	}
-}
 
-/// Different operations allowed for a commit.
-/// Behavior may differs depending on column configuration.
-#[derive(Debug, PartialEq, Eq)]
-pub enum Operation<Key, Value> {
-	/// Insert or update the value for a given key.
-	Set(Key, Value),
+	fn tree_stats(&self, col: ColId) -> Result<TreeStats> {
+		self.check_tree_column(col)?.tree_stats(self.log.overlays())
+	}
 
-	/// Dereference at a given key, resulting in
-	/// either removal of a key value or decrement of its
-	/// reference count counter.
-	Dereference(Key),
+	fn tree_nodes(&self, col: ColId, prefix: &[u8]) -> Result<Vec<TreeNode>> {
+		self.check_tree_column(col)?.tree_nodes(prefix, self.log.overlays())
+	}
 
-	/// Increment the reference count counter of an existing value for a given key.
-	/// If no value exists for the key, this operation is skipped.
-	Reference(Key),
-}
+	fn get_with_meta(&self, col: ColId, key: &[u8]) -> Result<Option<(Value, ValueMeta)>> {
+		let key = self.check_hash_column(col)?.hash_key(key);
+		self.get_hashed_with_meta(col, &key)
+	}
 
-impl<Key: Ord, Value: Eq> PartialOrd<Self> for Operation<Key, Value> {
-	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-		Some(self.cmp(other))
+	fn get_hashed_with_meta(&self, col: ColId, key: &Key) -> Result<Option<(Value, ValueMeta)>> {
+		let column = self.check_hash_column(col)?;
+		let value = column.get_with_meta(key, self.log.overlays())?;
+		let options = self.columns.options(col);
+		Ok(value.and_then(|(value, meta)| {
+			decode_stored(options, Some(value)).map(|value| (value, meta))
+		}))
 	}
-}
 
-impl<Key: Ord, Value: Eq> Ord for Operation<Key, Value> {
-	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-		self.key().cmp(other.key())
+	fn column_disk_usage(&self, col: ColId) -> Result<ColumnDiskUsage> {
+		let mut usage = self.check_column(col)?.disk_usage();
+		usage.log_bytes = self.log.overlays().read().column_bytes(col);
+		Ok(usage)
 	}
-}
 
-impl<Key, Value> Operation<Key, Value> {
-	pub fn key(&self) -> &Key {
-		match self {
-			Operation::Set(k, _) | Operation::Dereference(k) | Operation::Reference(k) => k,
+	fn check_column(&self, col: ColId) -> Result<&Column> {
+		match self.columns.get(col as usize) {
+			Some(column) if !self.columns.is_dropped(col) => Ok(column),
+			_ => Err(Error::InvalidInput(format!("Invalid column {}", col))),
 		}
 	}
 
-	pub fn into_key(self) -> Key {
-		match self {
-			Operation::Set(k, _) | Operation::Dereference(k) | Operation::Reference(k) => k,
+	fn check_hash_column(&self, col: ColId) -> Result<&HashColumn> {
+		match self.check_column(col)? {
+			Column::Hash(column) => Ok(column),
+			Column::Tree(_) => Err(Error::InvalidInput(format!("Column {} is btree indexed", col))),
 		}
 	}
-}
 
-impl<K: AsRef<[u8]>, Value> Operation<K, Value> {
-	pub fn to_key_vec(self) -> Operation<Vec<u8>, Value> {
-		match self {
-			Operation::Set(k, v) => Operation::Set(k.as_ref().to_vec(), v),
-			Operation::Dereference(k) => Operation::Dereference(k.as_ref().to_vec()),
-			Operation::Reference(k) => Operation::Reference(k.as_ref().to_vec()),
+	// Read the values of hash indexed column `col` with key hashes after `after`, in the order
+	// of key hashes, until at least `max` values are read. Whole index chunks are read, so that
+	// values queued in the commit overlay can be merged in. Returns the values and whether
+	// there are no more.
+	fn iter_column_batch(
+		&self,
+		col: ColId,
+		after: Option<&Key>,
+		max: usize,
+	) -> Result<(VecDeque<HashEntry>, bool)> {
+		let column = self.check_hash_column(col)?;
+		loop {
+			let (bits, reindexing) = column.index_state();
+			if reindexing {
+				// Entries move between index tables while reindexing.
+				if self.has_background_threads() {
+					thread::sleep(Duration::from_millis(10));
+				} else {
+					drop(self.lock_idle(false)?);
+				}
+				continue
+			}
+			let start = after.map_or(0, |key| crate::index::key_chunk(key, bits));
+			let mut end = 1u64 << bits;
+			let mut values = BTreeMap::new();
+			// Commits are not moved from the overlay to the log while it is locked.
+			let overlay = self.commit_overlay.read();
+			let mut chunk = start;
+			column.iter_index_while(&self.log, start, None, |state| {
+				if state.chunk_index != chunk {
+					if values.len() >= max {
+						end = state.chunk_index;
+						return false
+					}
+					chunk = state.chunk_index;
+				}
+				if after.is_none_or(|after| state.key > *after) {
+					values.insert(state.key, state.value);
+				}
+				true
+			})?;
+			if column.index_state() != (bits, false) {
+				// The index was replaced while reading.
+				continue
+			}
+			for (key, (_, value)) in overlay[col as usize].indexed.iter() {
+				let chunk = crate::index::key_chunk(key, bits);
+				if chunk < start || chunk >= end || after.is_some_and(|after| key <= after) {
+					continue
+				}
+				match value {
+					Some(value) => values.insert(*key, value.clone()),
+					None => values.remove(key),
+				};
+			}
+			let options = self.columns.options(col);
+			for key in overlay[col as usize].merges.keys() {
+				let chunk = crate::index::key_chunk(key, bits);
+				if chunk < start || chunk >= end || after.is_some_and(|after| key <= after) {
+					continue
+				}
+				let value = overlay[col as usize].apply_merges(key, values.remove(key), options);
+				values.extend(value.map(|value| (*key, value)));
+			}
+			drop(overlay);
+			let values = values
+				.into_iter()
+				.filter_map(|(key, value)| {
+					if options.store_keys {
+						let (raw_key, value) = split_keyed(&value)?;
+						return Some((key, Some(raw_key.to_vec()), value.to_vec()))
+					}
+					decode_stored(options, Some(value)).map(|value| (key, None, value))
+				})
+				.collect();
+			return Ok((values, end == 1u64 << bits))
 		}
 	}
 }
 
-#[derive(Debug, Default)]
-pub struct CommitChangeSet {
-	pub indexed: HashMap<ColId, IndexedChangeSet>,
-	pub btree_indexed: HashMap<ColId, BTreeChangeSet>,
+pub struct Db {
+	inner: Arc<DbInner>,
+	commit_thread: Option<thread::JoinHandle<()>>,
+	flush_thread: Option<thread::JoinHandle<()>>,
+	log_thread: Option<thread::JoinHandle<()>>,
+	cleanup_thread: Option<thread::JoinHandle<()>>,
+	scrub_thread: Option<thread::JoinHandle<()>>,
+	join_on_shutdown: bool,
+	#[cfg(feature = "asyncdb")]
+	async_pool: crate::asyncdb::BlockingPool,
 }
 
-#[derive(Debug)]
-pub struct IndexedChangeSet {
-	pub col: ColId,
-	pub changes: Vec<Operation<Key, Vec<u8>>>,
-}
+impl Db {
+	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
+		let options = Options::with_columns(path, num_columns);
+		Self::open_inner(&options, OpeningMode::Create)
+	}
 
-impl IndexedChangeSet {
-	pub fn new(col: ColId) -> Self {
-		IndexedChangeSet { col, changes: Default::default() }
+	/// Open the database with given options.
+	pub fn open(options: &Options) -> Result<Db> {
+		Self::open_inner(options, OpeningMode::Write)
 	}
 
-	fn push<K: AsRef<[u8]>>(
-		&mut self,
-		change: Operation<K, Vec<u8>>,
-		options: &Options,
-		db_version: u32,
-	) {
-		let salt = options.salt.unwrap_or_default();
-		let hash_key = |key: &[u8]| -> Key {
-			hash_key(key, &salt, options.columns[self.col as usize].uniform, db_version)
-		};
+	/// Create the database using given options.
+	pub fn open_or_create(options: &Options) -> Result<Db> {
+		Self::open_inner(options, OpeningMode::Create)
+	}
 
-		self.push_change_hashed(match change {
-			Operation::Set(k, v) => Operation::Set(hash_key(k.as_ref()), v),
-			Operation::Dereference(k) => Operation::Dereference(hash_key(k.as_ref())),
-			Operation::Reference(k) => Operation::Reference(hash_key(k.as_ref())),
-		})
+	/// Open the database for reading only. No lock is taken and no files are created or
+	/// modified, so another process may have the database open for writing meanwhile. Commits
+	/// are refused. Log records that are not enacted yet are read into memory.
+	pub fn open_read_only(options: &Options) -> Result<Db> {
+		Self::open_inner(options, OpeningMode::ReadOnly)
 	}
 
-	fn push_change_hashed(&mut self, change: Operation<Key, Vec<u8>>) {
-		self.changes.push(change);
+	/// Open the database, salvaging what is possible after corruption. Log records that can't
+	/// be replayed only cause the rest of their log file to be skipped, instead of all following
+	/// logs. Records after a skipped one may still depend on it, so this is best effort. Hash
+	/// index entries that point to unreadable values are removed. Values of `preimage` columns
+	/// that are missing from the index are indexed again, assuming keys are blake2 hashes of the
+	/// values. Btree columns are checked but not repaired.
+	pub fn open_repair(options: &Options) -> Result<(Db, check::RepairReport)> {
+		let db = Self::open_inner(options, OpeningMode::Repair)?;
+		let report = db.inner.repair.as_ref().map(|r| std::mem::take(&mut *r.lock()));
+		Ok((db, report.unwrap_or_default()))
 	}
 
-	fn copy_to_overlay(
-		&self,
-		overlay: &mut CommitOverlay,
-		record_id: u64,
-		bytes: &mut usize,
-		options: &Options,
-	) -> Result<()> {
-		let ref_counted = options.columns[self.col as usize].ref_counted;
+	/// Remove the lock on the database directory at `path`, left by a process that is gone but
+	/// whose lock is still held, as happens on network file systems. Returns the holder recorded
+	/// in the lock file, or `None` if the directory was not locked. Fails with `Error::Locked`
+	/// if the holder is a process on this host that is still running. Processes on other hosts
+	/// can't be checked: the database is corrupted if it is opened while they still use it.
+	pub fn force_unlock(path: &std::path::Path) -> Result<Option<crate::LockHolder>> {
+		crate::lock::force_unlock(path)
+	}
+
+	/// Log records replayed and discarded when the database was opened, see
+	/// `Options::recovery_mode`.
+	pub fn recovery_report(&self) -> check::RecoveryReport {
+		self.inner.recovery.lock().clone()
+	}
+
+	fn open_inner(options: &Options, opening_mode: OpeningMode) -> Result<Db> {
+		options.validate()?;
+		let db = DbInner::open(options, opening_mode)?;
+		if db.read_only {
+			db.catch_up()?;
+			return Ok(Db {
+				inner: Arc::new(db),
+				commit_thread: None,
+				flush_thread: None,
+				log_thread: None,
+				cleanup_thread: None,
+				scrub_thread: None,
+				join_on_shutdown: false,
+				#[cfg(feature = "asyncdb")]
+				async_pool: crate::asyncdb::BlockingPool::new(),
+			})
+		}
+		let mut db = db;
+		// This needs to be call before log thread: so first reindexing
+		// will run in correct state.
+		if let Err(e) = db.replay_all_logs() {
+			log::debug!(target: "parity-db", "Error during log replay, doing log cleanup");
+			db.log.clean_logs(db.log.num_dirty_logs())?;
+			db.log.kill_logs()?;
+			return Err(e)
+		}
+		db.repair()?;
+		let db = Arc::new(db);
+		let start_threads = options.with_background_thread;
+		let commit_thread = if start_threads {
+			let commit_worker_db = db.clone();
+			Some(thread::spawn(move || {
+				commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
+			}))
+		} else {
+			None
+		};
+		let flush_thread = if start_threads {
+			let flush_worker_db = db.clone();
+			// Flush logs well before the overlay limit is reached, or commits would stall.
+			let min_log_size = MIN_LOG_SIZE_BYTES.min(db.max_log_queue_bytes as u64 / 2);
+			#[cfg(any(test, feature = "instrumentation"))]
+			let min_log_size = if options.always_flush { 0 } else { min_log_size };
+			Some(thread::spawn(move || {
+				flush_worker_db.store_err(Self::flush_worker(flush_worker_db.clone(), min_log_size))
+			}))
+		} else {
+			None
+		};
+		let log_thread = if start_threads {
+			let log_worker_db = db.clone();
+			Some(thread::spawn(move || {
+				log_worker_db.store_err(Self::log_worker(log_worker_db.clone()))
+			}))
+		} else {
+			None
+		};
+		let cleanup_thread = if start_threads {
+			let cleanup_worker_db = db.clone();
+			Some(thread::spawn(move || {
+				cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
+			}))
+		} else {
+			None
+		};
+		let scrub_thread = match &options.scrub {
+			Some(scrub) if start_threads => {
+				let scrub_worker_db = db.clone();
+				let scrub = scrub.clone();
+				Some(thread::spawn(move || {
+					scrub_worker_db.store_err(Self::scrub_worker(scrub_worker_db.clone(), scrub))
+				}))
+			},
+			_ => None,
+		};
+		Ok(Db {
+			inner: db,
+			commit_thread,
+			flush_thread,
+			log_thread,
+			cleanup_thread,
+			scrub_thread,
+			join_on_shutdown: start_threads,
+			#[cfg(feature = "asyncdb")]
+			async_pool: crate::asyncdb::BlockingPool::new(),
+		})
+	}
+
+	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		self.inner.get(col, key)
+	}
+
+	/// Start writing the value of `key` in pieces, with [`std::io::Write`]. The value is
+	/// committed with [`ValueWriter::commit`], and discarded if the writer is dropped.
+	///
+	/// In hash columns with `ColumnOptions::blob_threshold`, a value growing past the threshold
+	/// is written to its own file as it is written, rather than held in memory, and stored
+	/// uncompressed. It becomes visible once it is written to the log, which `commit` waits
+	/// for, and subscribers are not notified. This does not apply to columns with `ttl` or
+	/// secondary indexes. Other values are committed as with [`Db::commit`].
+	pub fn begin_value(&self, col: ColId, key: &[u8]) -> Result<ValueWriter<'_>> {
+		self.inner.begin_value(col, key)
+	}
+
+	/// Query multiple keys of a column at once. Values are returned in the order of `keys`.
+	pub fn get_many<K: AsRef<[u8]>>(&self, col: ColId, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		self.inner.get_many(col, keys)
+	}
+
+	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+		self.inner.get_size(col, key)
+	}
+
+	/// Same as `get`, returning a reader of the value. Values stored in their own file, see
+	/// `ColumnOptions::blob_threshold`, are streamed from the file instead of being read into
+	/// memory at once. The file stays readable when the value is replaced or removed.
+	pub fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader>> {
+		self.inner.get_reader(col, key)
+	}
+
+	/// Same as `get`, along with the reference count of the value and how it is stored, for
+	/// hash indexed columns. Commits not yet written to the log are not included.
+	pub fn get_with_meta(&self, col: ColId, key: &[u8]) -> Result<Option<(Value, ValueMeta)>> {
+		self.inner.get_with_meta(col, key)
+	}
+
+	/// Same as `get_with_meta`, looking up the value by the key hash stored in the index, as
+	/// exported for hash indexed columns that don't keep their keys, see `export`.
+	pub fn get_hashed_with_meta(
+		&self,
+		col: ColId,
+		key_hash: &Key,
+	) -> Result<Option<(Value, ValueMeta)>> {
+		self.inner.get_hashed_with_meta(col, key_hash)
+	}
+
+	/// Get the reference count of the value of `key` in a `ref_counted` hash indexed column,
+	/// e.g. to find out why a value was not removed. Values of other hash indexed columns have a
+	/// count of 1. Commits not yet written to the log are not included.
+	pub fn get_rc(&self, col: ColId, key: &[u8]) -> Result<Option<u64>> {
+		Ok(self.inner.get_with_meta(col, key)?.map(|(_, meta)| meta.rc))
+	}
+
+	/// Check if a value exists for the key without reading the value.
+	pub fn contains_key(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		self.inner.contains_key(col, key)
+	}
+
+	/// Access column `col` with keys of type `K` and values of type `V`, encoded with `Codec`.
+	/// Keeping the handle rather than the column id ties the id to its types.
+	pub fn typed_column<K: AsRef<[u8]>, V: crate::Codec>(
+		&self,
+		col: ColId,
+	) -> Result<crate::TypedColumn<'_, K, V>> {
+		crate::TypedColumn::new(self, col)
+	}
+
+	pub fn iter(&self, col: ColId) -> Result<BTreeIterator<'_>> {
+		self.inner.btree_iter(col)
+	}
+
+	/// Iterate over a hash indexed column. Keys are returned as key hashes, in the order of
+	/// key hashes. Values of commits made while iterating may or may not be returned.
+	pub fn iter_column(&self, col: ColId) -> Result<ColumnIterator<'_>> {
+		self.inner.check_hash_column(col)?;
+		Ok(ColumnIterator::new(self, col))
+	}
+
+	/// Resume a scan of a hash indexed column at a cursor returned by
+	/// `ColumnIterator::cursor`.
+	pub fn resume_iter_column(&self, cursor: &Cursor) -> Result<ColumnIterator<'_>> {
+		self.inner.check_hash_column(cursor.col())?;
+		ColumnIterator::resume(self, cursor)
+	}
+
+	/// Resume a scan of a btree indexed column at a cursor returned by `BTreeIterator::cursor`.
+	pub fn resume_iter(&self, cursor: &Cursor) -> Result<BTreeIterator<'_>> {
+		if self.column_options(cursor.col()).is_none() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", cursor.col())))
+		}
+		self.inner.btree_iter(cursor.col())?.resume(cursor)
+	}
+
+	/// Iterate over a btree indexed column, ignoring any commit made after
+	/// the iterator was created.
+	pub fn iter_consistent(&self, col: ColId) -> Result<SnapshotIterator<'_>> {
+		let snapshot = self.snapshot();
+		let iter = SnapshotIterator::new(self.inner.btree_iter(col)?, snapshot.state.clone(), col);
+		Ok(iter.with_snapshot(snapshot))
+	}
+
+	/// Iterate over the keys of column `col` that have a value with secondary index key `key`
+	/// in index `index` of `ColumnOptions::secondary_indexes`.
+	pub fn iter_secondary(
+		&self,
+		col: ColId,
+		index: usize,
+		key: &[u8],
+	) -> Result<crate::SecondaryIterator<'_>> {
+		let column = self
+			.column_options(col)
+			.and_then(|options| options.secondary_indexes.get(index).map(|index| index.column))
+			.ok_or_else(|| {
+				Error::InvalidInput(format!("No secondary index {} for column {}", index, col))
+			})?;
+		let iter = self.inner.btree_iter(column)?.with_prefix(key)?;
+		Ok(crate::SecondaryIterator::new(iter, key.len()))
+	}
+
+	/// Iterate over keys of a btree indexed column that start with `prefix`.
+	/// The returned iterator is positioned at the first matching key and stops
+	/// once keys no longer match the prefix.
+	pub fn iter_prefix(&self, col: ColId, prefix: &[u8]) -> Result<BTreeIterator<'_>> {
+		self.inner.btree_iter(col)?.with_prefix(prefix)
+	}
+
+	/// Take a point-in-time view of the database. Reads from the snapshot never observe
+	/// commits made after it was created.
+	pub fn snapshot(&self) -> Snapshot<'_> {
+		// Make sure no commit is being added to the overlay.
+		let _overlay = self.inner.commit_overlay.read();
+		let state = Arc::new(SnapshotState::new(self.inner.columns.len()));
+		self.inner.snapshots.lock().push(state.clone());
+		Snapshot { db: &self.inner, state }
+	}
+
+	/// Queue a commit. Returns the commit id, see `on_commit`.
+	pub fn commit<I, K>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit(tx)
+	}
+
+	/// Queue a commit. Returns the commit id, see `on_commit`.
+	pub fn commit_changes<I>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		self.inner.commit_changes(tx)
+	}
+
+	/// Commit `tx` only if every `(col, key, value)` in `expected` matches the current
+	/// value, with `None` expecting the key to be missing. Fails with
+	/// `Error::ConditionFailed` otherwise, leaving the database unchanged. Returns the commit
+	/// id.
+	pub fn commit_if<K, I>(&self, expected: Vec<(ColId, K, Option<Value>)>, tx: I) -> Result<u64>
+	where
+		K: AsRef<[u8]>,
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		let expected: Vec<_> =
+			expected.into_iter().map(|(c, k, v)| (c, k.as_ref().to_vec(), v)).collect();
+		self.inner.commit_changes_if(&expected, tx, None)
+	}
+
+	/// Write all commits queued so far to the log and sync the log files, regardless of
+	/// `Options::sync_wal`. Returns once the commits are durable.
+	pub fn flush(&self) -> Result<()> {
+		self.inner.flush(false)
+	}
+
+	/// Same as [`Db::flush`], and also wait until the commits are enacted into the column
+	/// tables.
+	pub fn flush_all(&self) -> Result<()> {
+		self.inner.flush(true)
+	}
+
+	/// Check a commit without applying it. Returns the same errors `commit_changes` would for
+	/// invalid changes, such as operations the column does not support or commits over
+	/// `Options::max_commit_bytes`, and otherwise an estimate of the bytes the commit would
+	/// write to the log. The estimate ignores compression and the btree nodes rewritten.
+	pub fn validate_commit<I>(&self, tx: I) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		self.inner.validate_commit(tx)
+	}
+
+	/// Queue a commit with `options`, overriding database options for this commit only.
+	/// Returns the commit id, see `on_commit`.
+	pub fn commit_with<I>(&self, tx: I, options: CommitOptions) -> Result<u64>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		let id = self.inner.commit_changes_if(&[], tx, options.sync)?;
+		if options.wait {
+			self.inner.wait_flushed(id)?;
+		}
+		Ok(id)
+	}
+
+	pub(crate) fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
+		self.inner.commit_raw(commit)?;
+		Ok(())
+	}
+
+	// Commit and wait until the commit is flushed to the log file.
+	#[cfg(any(feature = "rocksdb-migrate", test))]
+	pub(crate) fn commit_changes_durable<I>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)>,
+	{
+		let id = self.inner.commit_changes(tx)?;
+		self.inner.wait_durable(id)
+	}
+
+	/// Remove all data from a column without closing the database. Waits for pending
+	/// commits to be written and blocks new commits until the column is cleared.
+	/// Fails if there are open snapshots.
+	pub fn clear_column(&self, col: ColId) -> Result<()> {
+		self.inner.clear_column(col, self.join_on_shutdown)
+	}
+
+	/// Remove a column and delete its data. The column id stays reserved until the database is
+	/// reopened: the column reads as empty and commits to it fail. On the next open the id may be
+	/// reused with any column options.
+	pub fn drop_column(&self, col: ColId) -> Result<()> {
+		self.inner.drop_column(col, self.join_on_shutdown)
+	}
+
+	/// Change options of an existing column. The column is rewritten in the background and
+	/// remains readable and writable meanwhile. Once the migration completes, the database must
+	/// be opened with the new options. A migration that does not complete before the database
+	/// is closed is discarded.
+	///
+	/// Only one column may be migrated at a time. Reference counted btree indexed columns,
+	/// changes to `ref_counted`, migrations from hash indexed to btree indexed columns, and
+	/// changes to `ttl` or `uniform` of hash indexed columns are not supported. Copying values
+	/// of reference counted columns waits for queued commits to be written. The final switch to
+	/// the new column waits for all snapshots to be released, btree iterators created before it
+	/// see no further values.
+	pub fn migrate_column(&self, col: ColId, options: ColumnOptions) -> Result<()> {
+		self.inner.start_migration(col, options, self.join_on_shutdown)?;
+		if self.join_on_shutdown {
+			self.inner.cleanup_worker_wait.signal();
+			return Ok(())
+		}
+		while self.inner.migrate_step(false)? {}
+		Ok(())
+	}
+
+	/// Rewrite the values of an encrypted column that are encrypted with a key other than the
+	/// current one of its `KeyProvider`, e.g. after `KeyRing::rotate`. New values always use the
+	/// current key. Existing values are rewritten in the background and stay readable meanwhile,
+	/// so the old keys must remain available until `ColumnStats::reencryption` reports the
+	/// rewrite as done. A rewrite that does not complete before the database is closed must be
+	/// started again. Only one column may be rewritten at a time.
+	#[cfg(feature = "encryption")]
+	pub fn reencrypt_column(&self, col: ColId) -> Result<()> {
+		self.inner.start_reencryption(col)?;
+		if self.join_on_shutdown {
+			self.inner.log_worker_wait.signal();
+			return Ok(())
+		}
+		while self.inner.process_reencryption()? {}
+		Ok(())
+	}
+
+	/// Return the space of long runs of deleted values in `col` to the file system, by punching
+	/// holes in the value table files. Only ranges of whole file system blocks that hold no
+	/// value are released, the file sizes are unchanged. Does nothing on file systems without
+	/// support for it. Waits for queued commits to be written. Returns the number of bytes
+	/// released.
+	pub fn punch_holes(&self, col: ColId) -> Result<u64> {
+		self.inner.punch_holes(col, self.join_on_shutdown)
+	}
+
+	/// Fill empty btree indexed column `col` with keys and values from `iter`, which must be in
+	/// strictly ascending key order. The tree is built bottom-up from full nodes and written in
+	/// large log records, which is much faster than committing the keys. New commits are
+	/// blocked until done. Returns the number of keys ingested. If a key is not in order,
+	/// the keys before it are ingested and an error is returned. Columns with expiring values or
+	/// secondary indexes are not supported.
+	pub fn ingest_sorted<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+		&self,
+		col: ColId,
+		iter: impl IntoIterator<Item = (K, V)>,
+	) -> Result<u64> {
+		self.inner.ingest_sorted(col, iter.into_iter().map(Ok), self.join_on_shutdown)
+	}
+
+	/// Attach an ingest file written with `IngestFileWriter` to empty btree indexed column `col`,
+	/// like `ingest_sorted`. The file is verified first. The keys become visible at once when
+	/// the tree is complete; if the database is closed or fails before that, the column stays
+	/// empty. Returns the number of keys ingested.
+	pub fn ingest_file(&self, col: ColId, path: impl AsRef<std::path::Path>) -> Result<u64> {
+		let path = path.as_ref();
+		crate::ingest::verify(path)?;
+		let reader = crate::ingest::IngestFileReader::open(path)?;
+		self.inner.ingest_sorted(col, reader, self.join_on_shutdown)
+	}
+
+	/// Move the values of `col` to the start of its value tables, filling the space of removed
+	/// values, and truncate the table files. Values are moved in the background in batches,
+	/// at most `Options::compaction_rate` bytes per second, and stay readable and writable
+	/// meanwhile. `ColumnStats::compaction` reports the progress. A compaction that does not
+	/// complete before the database is closed must be started again. Values already moved are
+	/// kept in place. Only hash indexed columns are supported, one at a time.
+	pub fn compact_column(&self, col: ColId) -> Result<()> {
+		self.inner.start_compaction(col, self.join_on_shutdown)?;
+		if self.join_on_shutdown {
+			self.inner.cleanup_worker_wait.signal();
+			return Ok(())
+		}
+		while self.inner.compact_step(false)? {}
+		Ok(())
+	}
+
+	/// Check if `col` is being migrated with `migrate_column`.
+	pub fn is_migrating(&self, col: ColId) -> bool {
+		self.inner.is_migrating(col)
+	}
+
+	/// Write a consistent copy of the database to `path`, which must be empty or not exist.
+	/// The database stays open for reads and writes. The copy contains all commits written to
+	/// the log before the call and can be opened as a regular database. Log enactment is
+	/// paused while the files are copied; table files are updated in place and are never
+	/// hard-linked. Returns the backup sequence number, which is also recorded in `path`.
+	pub fn backup_to(&self, path: impl AsRef<std::path::Path>) -> Result<u64> {
+		self.inner.backup_to(path.as_ref(), BackupKind::Full)
+	}
+
+	/// Update a backup created with `backup_to`, copying only the table pages modified since
+	/// it was taken. Pages modified before the database was opened, or before a column was
+	/// cleared, dropped or migrated, are not tracked; files are then copied in full. In
+	/// particular, the first update after the database is reopened copies all files. Opening
+	/// the backup as a database makes it unusable as a base for further updates.
+	pub fn backup_incremental(&self, path: impl AsRef<std::path::Path>) -> Result<u64> {
+		self.inner.backup_to(path.as_ref(), BackupKind::Incremental)
+	}
+
+	/// Create a checkpoint of the database in `path`, which must be empty or not exist. Unlike
+	/// `backup_to`, table files are cloned rather than copied on filesystems with copy-on-write
+	/// support, which makes this close to instant. Table and log files are modified in place,
+	/// so they are never hard-linked. The checkpoint is meant to be opened with
+	/// `Db::open_read_only` and contains the commits written to the log before the call.
+	pub fn checkpoint(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+		self.inner.backup_to(path.as_ref(), BackupKind::Checkpoint)?;
+		Ok(())
+	}
+
+	/// Create a named snapshot of the database. The snapshot is a checkpoint stored in the
+	/// database directory, so it persists across restarts until released with
+	/// `release_named_snapshot`. Names may contain ASCII letters, digits, `_`, `-` and `.`.
+	/// The snapshot includes all commits queued before the call. Returns the sequence number
+	/// of the last log record included in the snapshot.
+	pub fn create_named_snapshot(&self, name: &str) -> Result<u64> {
+		let path = self.named_snapshot_path(name)?;
+		if path.exists() {
+			return Err(Error::InvalidInput(format!("Snapshot {name} already exists")))
+		}
+		self.inner.flush(false)?;
+		self.inner.backup_to(&path, BackupKind::Checkpoint)
+	}
+
+	/// Names of the snapshots created with `create_named_snapshot`, in sorted order.
+	pub fn named_snapshots(&self) -> Result<Vec<String>> {
+		let dir = self.inner.options.path.join(NAMED_SNAPSHOTS_DIR);
+		let mut names = Vec::new();
+		if dir.exists() {
+			for entry in try_io!(std::fs::read_dir(&dir)) {
+				let entry = try_io!(entry);
+				if let Some(name) = entry.file_name().to_str() {
+					names.push(name.to_owned());
+				}
+			}
+		}
+		names.sort();
+		Ok(names)
+	}
+
+	/// Open a named snapshot for reading. `get` and `iter` on the returned database see the
+	/// state at the time the snapshot was created. Columns that were added or changed since
+	/// are opened with the options recorded in the snapshot.
+	pub fn open_named_snapshot(&self, name: &str) -> Result<Db> {
+		let path = self.named_snapshot_path(name)?;
+		let meta = Options::load_metadata(&path)?
+			.ok_or_else(|| Error::InvalidInput(format!("No snapshot named {name}")))?;
+		let mut options = self.inner.options.clone();
+		options.path = path;
+		options.log_archive = None;
+		options.columns = meta
+			.columns
+			.into_iter()
+			.enumerate()
+			.map(|(c, column)| match self.inner.options.columns.get(c) {
+				// Keep options that are not persisted, such as merge operators.
+				Some(current) if current.as_string() == column.as_string() => current.clone(),
+				_ => column,
+			})
+			.collect();
+		Db::open_read_only(&options)
+	}
+
+	/// Remove a named snapshot, freeing the disk space used by data it retained.
+	pub fn release_named_snapshot(&self, name: &str) -> Result<()> {
+		let path = self.named_snapshot_path(name)?;
+		if !path.exists() {
+			return Err(Error::InvalidInput(format!("No snapshot named {name}")))
+		}
+		try_io!(std::fs::remove_dir_all(&path));
+		Ok(())
+	}
+
+	fn named_snapshot_path(&self, name: &str) -> Result<std::path::PathBuf> {
+		let valid = !name.is_empty() &&
+			name != "." &&
+			name != ".." &&
+			name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+		if !valid {
+			return Err(Error::InvalidInput(format!("Invalid snapshot name {name:?}")))
+		}
+		Ok(self.inner.options.path.join(NAMED_SNAPSHOTS_DIR).join(name))
+	}
+
+	/// Log files copied to `Options::log_archive`, in the order they were enacted.
+	pub fn archived_logs(&self) -> Result<Vec<ArchivedLog>> {
+		self.inner.log.archived_logs()
+	}
+
+	/// Remove archived log files with index below `before`. Returns the number of files removed.
+	pub fn prune_archived_logs(&self, before: u64) -> Result<usize> {
+		self.inner.log.prune_archived_logs(before)
+	}
+
+	/// Receive the changes to column `col` as `(key, value)` pairs, in commit order. Changes are
+	/// sent once they are enacted from the log into the column tables; `None` values are
+	/// removals, or dereferences in `ref_counted` columns. Keys of hash indexed columns removed
+	/// by `ttl` expiry are reported as 32-byte key hashes. Commits are only enacted after the log
+	/// is flushed, so changes may be delayed while the log is small.
+	pub fn subscribe(&self, col: ColId) -> Result<mpsc::Receiver<Change>> {
+		self.inner.subscribe(col, &[])
+	}
+
+	/// Same as `subscribe`, but only receive changes to keys of column `col` that are equal to
+	/// or start with `key_or_prefix`. Keys removed by `ttl` expiry from hash indexed columns are
+	/// hashed and are not matched.
+	pub fn watch(&self, col: ColId, key_or_prefix: &[u8]) -> Result<mpsc::Receiver<Change>> {
+		self.inner.subscribe(col, key_or_prefix)
+	}
+
+	/// Register a hook called as commits progress. Commit ids are returned by `commit` and
+	/// increase in commit order. `Queued` is reported for each commit. `Logged` and `Enacted`
+	/// are reported in commit order and cover all commits with lower ids. Hooks are called from
+	/// database threads and should not block.
+	pub fn on_commit(&self, hook: impl Fn(CommitStage, u64) + Send + Sync + 'static) {
+		self.inner.on_commit(hook)
+	}
+
+	/// Write all entries of column `col` to `writer` in a portable dump format. Hash indexed
+	/// columns are not exported from a consistent state if written to concurrently. Returns
+	/// the number of entries written.
+	pub fn export(&self, col: ColId, mut writer: impl std::io::Write) -> Result<u64> {
+		crate::export::export(self, col, &mut writer)
+	}
+
+	/// Write all keys and values of btree indexed column `col` to `writer` in key order, as of
+	/// the time of the call. The output is an ingest file, see `IngestFileWriter`, that can be
+	/// attached to an empty column with `ingest_file`. Returns the number of entries written.
+	pub fn export_sorted(&self, col: ColId, mut writer: impl std::io::Write) -> Result<u64> {
+		crate::ingest::export_sorted(self, col, &mut writer)
+	}
+
+	/// Insert all entries of a dump created with `export` into column `col`. Dumps of btree
+	/// indexed, `uniform` and `store_keys` columns can be imported into any column. Other hash
+	/// indexed columns only store salted key hashes; their dumps require a hash indexed column
+	/// with the same settings in a database with the same salt. Dumps of `ttl` columns require a
+	/// `ttl` column. Returns the number of entries read.
+	pub fn import(&self, col: ColId, mut reader: impl std::io::Read) -> Result<u64> {
+		crate::export::import(self, col, &mut reader)
+	}
+
+	pub(crate) fn column_options(&self, col: ColId) -> Option<ColumnOptions> {
+		if col as usize >= self.inner.columns.len() || self.inner.columns.is_dropped(col) {
+			return None
+		}
+		Some(self.inner.columns.options(col).clone())
+	}
+
+	pub(crate) fn salt(&self) -> Salt {
+		self.inner.options.salt.unwrap_or_default()
+	}
+
+	/// Hash `key` for hash indexed column `col`.
+	pub(crate) fn hash_key(&self, col: ColId, key: &[u8]) -> Key {
+		let options = self.inner.columns.options(col);
+		hash_key(key, &self.salt(), options.uniform, options.key_hash, self.inner.db_version)
+	}
+
+	pub fn num_columns(&self) -> u8 {
+		self.inner.columns.len() as u8
+	}
+
+	pub(crate) fn iter_column_while(
+		&self,
+		c: ColId,
+		f: impl FnMut(IterState) -> bool,
+	) -> Result<()> {
+		self.inner.iter_column_while(c, f)
+	}
+
+	pub(crate) fn iter_column_batch(
+		&self,
+		col: ColId,
+		after: Option<&Key>,
+		max: usize,
+	) -> Result<(VecDeque<HashEntry>, bool)> {
+		self.inner.iter_column_batch(col, after, max)
+	}
+
+	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
+		let mut more_work = false;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				db.cleanup_worker_wait.signal();
+				if !db.log.has_log_files_to_read() {
+					db.commit_worker_wait.wait();
+				}
+			}
+
+			more_work = db.enact_logs(false)?;
+			while let Some(delay) = db.io_delay() {
+				if !more_work || db.shutdown.load(Ordering::SeqCst) {
+					break
+				}
+				db.commit_worker_wait.wait_for(delay);
+			}
+		}
+		log::debug!(target: "parity-db", "Commit worker shutdown");
+		Ok(())
+	}
+
+	fn log_worker(db: Arc<DbInner>) -> Result<()> {
+		// Start with pending reindex.
+		let mut more_work = db.process_reindex()?;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				match db.io_delay() {
+					// Reindex is paused by the background I/O limit.
+					Some(delay) if db.next_reindex.load(Ordering::SeqCst) != 0 =>
+						db.log_worker_wait.wait_for(delay),
+					_ => db.log_worker_wait.wait(),
+				}
+			}
+
+			let more_commits = db.process_commits()?;
+			let more_reindex = db.process_reindex()?;
+			more_work = more_commits || more_reindex;
+			#[cfg(feature = "encryption")]
+			if !db.shutdown.load(Ordering::SeqCst) {
+				more_work |= db.process_reencryption()?;
+			}
+		}
+		log::debug!(target: "parity-db", "Log worker shutdown");
+		Ok(())
+	}
+
+	fn flush_worker(db: Arc<DbInner>, min_log_size: u64) -> Result<()> {
+		let mut more_work = false;
+		while !db.shutdown.load(Ordering::SeqCst) {
+			if !more_work {
+				db.flush_worker_wait.wait();
+			}
+			more_work = db.flush_logs(min_log_size)?;
+		}
+		log::debug!(target: "parity-db", "Flush worker shutdown");
+		Ok(())
+	}
+
+	fn cleanup_worker(db: Arc<DbInner>) -> Result<()> {
+		let mut more_work = true;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				db.cleanup_worker_wait.wait();
+			}
+			more_work = db.clean_logs()?;
+			db.remove_dropped_files()?;
+			if !db.shutdown.load(Ordering::SeqCst) {
+				more_work |= db.migrate_step(true)?;
+				more_work |= db.compact_step(true)?;
+			}
+			if !db.shutdown.load(Ordering::SeqCst) {
+				more_work |= db.expire_step()?;
+			}
+		}
+		log::debug!(target: "parity-db", "Cleanup worker shutdown");
+		Ok(())
+	}
+
+	fn scrub_worker(db: Arc<DbInner>, scrub: ScrubOptions) -> Result<()> {
+		let mut col = 0;
+		let mut chunk = 0;
+		while !db.shutdown.load(Ordering::SeqCst) {
+			let start = Instant::now();
+			let (bytes, next) = db.scrub_step(col, chunk, &*scrub.on_issue);
+			let mut pause =
+				Duration::from_secs_f64(bytes as f64 / scrub.bytes_per_second.max(1) as f64)
+					.saturating_sub(start.elapsed());
+			match next {
+				Some(next) => chunk = next,
+				None => {
+					chunk = 0;
+					col += 1;
+					if col as usize >= db.columns.len() {
+						log::debug!(target: "parity-db", "Scrub pass complete");
+						col = 0;
+						pause = std::cmp::max(pause, scrub.interval);
+					}
+				},
+			}
+			db.scrub_wait.wait_for(pause);
+		}
+		log::debug!(target: "parity-db", "Scrub worker shutdown");
+		Ok(())
+	}
+
+	pub fn write_stats_text(
+		&self,
+		writer: &mut impl std::io::Write,
+		column: Option<u8>,
+	) -> Result<()> {
+		self.inner.write_stats_text(writer, column)
+	}
+
+	pub fn clear_stats(&self, column: Option<u8>) -> Result<()> {
+		self.inner.clear_stats(column)
+	}
+
+	pub fn dump(&self, check_param: check::CheckOptions) -> Result<()> {
+		if let Some(col) = check_param.column {
+			self.inner.columns[col as usize].dump(&self.inner.log, &check_param, col)?;
+		} else {
+			for (ix, c) in self.inner.columns.iter().enumerate() {
+				c.dump(&self.inner.log, &check_param, ix as ColId)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Verify column `check_param.column`, or all columns. Hash index entries must point to
+	/// readable values with non-zero reference counts, and btree nodes must be balanced and
+	/// ordered. Hash indexes are checked between chunks `check_param.from` and
+	/// `check_param.bound`. Problems are reported per column, only I/O errors fail the check.
+	pub fn check(&self, check_param: check::CheckOptions) -> Result<check::CheckReport> {
+		let mut report = check::CheckReport::default();
+		for (col, column) in self.inner.columns.iter().enumerate() {
+			let col = col as ColId;
+			if check_param.column.is_some_and(|c| c != col) || self.inner.columns.is_dropped(col) {
+				continue
+			}
+			let mut column_check = check::ColumnCheck { col, ..Default::default() };
+			column.check(&self.inner.log, &check_param, &mut column_check)?;
+			report.columns.push(column_check);
+		}
+		Ok(report)
+	}
+
+	pub fn stats(&self) -> StatSummary {
+		self.inner.stats()
+	}
+
+	/// Get statistics of a column, see `ColumnStats`.
+	pub fn column_stats(&self, col: ColId) -> Result<ColumnStats> {
+		if col as usize >= self.inner.columns.len() || self.inner.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		#[allow(unused_mut)]
+		let mut stats = self.inner.columns[col as usize].column_stats();
+		#[cfg(feature = "encryption")]
+		{
+			stats.reencryption = self.inner.reencryption_progress(col);
+		}
+		stats.compaction = self.inner.compaction_progress(col);
+		stats.migration = self.inner.migration_progress(col);
+		Ok(stats)
+	}
+
+	/// Number of keys in a column with `ColumnOptions::count_keys`. Waits until queued commits
+	/// are written to the log, so that they are counted.
+	pub fn exact_len(&self, col: ColId) -> Result<u64> {
+		self.inner.exact_len(col)
+	}
+
+	/// Estimated number of keys in a column, from a sample of the index chunks, without waiting
+	/// for queued commits. Exact for columns with `ColumnOptions::count_keys`, which is required
+	/// for btree indexed columns.
+	pub fn approximate_len(&self, col: ColId) -> Result<u64> {
+		self.inner.approximate_len(col)
+	}
+
+	/// Estimate the number of keys in `[start, end)` of btree indexed column `col` and the size
+	/// of their keys and values, reading only a few nodes of the tree. Commits not yet written to
+	/// the log are not included.
+	pub fn estimate_range_size(&self, col: ColId, start: &[u8], end: &[u8]) -> Result<RangeSize> {
+		self.inner.estimate_range_size(col, start, end)
+	}
+
+	/// Get the shape of btree indexed column `col`: its depth and the number of nodes of each
+	/// level and of each fill. Reads the whole tree. Commits not yet written to the log are not
+	/// included.
+	pub fn tree_stats(&self, col: ColId) -> Result<TreeStats> {
+		self.inner.tree_stats(col)
+	}
+
+	/// Get the nodes of btree indexed column `col` that may hold keys starting with `prefix`,
+	/// root first, depth first. Commits not yet written to the log are not included.
+	pub fn tree_nodes(&self, col: ColId, prefix: &[u8]) -> Result<Vec<TreeNode>> {
+		self.inner.tree_nodes(col, prefix)
+	}
+
+	/// Get the state of the write pipeline, to tune the rate of commits. See `QueueStats`.
+	pub fn queue_stats(&self) -> QueueStats {
+		self.inner.queue_stats()
+	}
+
+	/// Get the space used by the files of a column, to decide if `compact_column` is
+	/// worthwhile. See `SpaceUsage`.
+	pub fn space_usage(&self, col: ColId) -> Result<SpaceUsage> {
+		if col as usize >= self.inner.columns.len() || self.inner.columns.is_dropped(col) {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		self.inner.columns[col as usize].space_usage(&self.inner.log)
+	}
+
+	/// Get the size of the files of a column: the index, the value table of each size tier and
+	/// the part of the shared log files holding changes to the column. See `ColumnDiskUsage`.
+	pub fn column_disk_usage(&self, col: ColId) -> Result<ColumnDiskUsage> {
+		self.inner.column_disk_usage(col)
+	}
+
+	/// Report value table and statistics gauges to `Options::metrics`. They are otherwise only
+	/// reported when logs are cleaned up.
+	#[cfg(feature = "metrics")]
+	pub fn report_metrics(&self) {
+		self.inner.report_metrics()
+	}
+
+	/// Add a new column to the open database and return its id. The column is immediately
+	/// available. `Options` passed when opening the database next time must include it.
+	pub fn add_column_online(&self, column_options: ColumnOptions) -> Result<ColId> {
+		self.inner.add_column(column_options)
+	}
+
+	/// Add a new column holding a copy of the keys and values of column `col`, and return its
+	/// id. The columns are independent from then on. On file systems with copy-on-write support,
+	/// such as btrfs or XFS, the table files are cloned and share their data until either column
+	/// changes it, on others they are copied. Values stored in their own file, see
+	/// `ColumnOptions::blob_threshold`, are hard linked. Blocks commits until all queued ones are
+	/// enacted. `Options` passed when opening the database next time must include the column,
+	/// with the options of `col`. Columns with secondary indexes or indexing another column are
+	/// not supported.
+	pub fn clone_column(&self, col: ColId) -> Result<ColId> {
+		self.inner.clone_column(col, self.join_on_shutdown)
+	}
+
+	/// Add a new column with options specified by `new_column_options`.
+	/// The database must be closed, see `add_column_online` for open databases.
+	pub fn add_column(options: &mut Options, new_column_options: ColumnOptions) -> Result<()> {
+		// We open the DB before to check metadata validity and make sure there are no pending WAL
+		// logs.
+		let db = Db::open(options)?;
+		let salt = db.inner.options.salt;
+		drop(db);
+
+		options.columns.push(new_column_options);
+		options.write_metadata_with_version(
+			&options.path,
+			&salt.expect("`salt` is always `Some` after opening the DB; qed"),
+			Some(CURRENT_VERSION),
+		)?;
+
+		Ok(())
+	}
+
+	/// Write the next queued commit to the log. Returns `false` if the queue was empty.
+	///
+	/// This and the following maintenance steps are done by background threads, unless the
+	/// database is opened with `Options::with_background_thread` unset. Each step does a
+	/// bounded amount of work; commits are on disk once they are written to the log and the
+	/// log is flushed, and reach the tables once the log is enacted.
+	pub fn process_commits(&self) -> Result<bool> {
+		self.inner.process_commits()
+	}
+
+	/// Flush the log file being written, handing it over to `enact_logs`. Returns `false` if
+	/// there was nothing to flush.
+	pub fn flush_logs(&self) -> Result<bool> {
+		self.inner.flush_logs(0)
+	}
+
+	/// Write the next flushed log record to the tables. Returns `false` if all records are
+	/// enacted.
+	pub fn enact_logs(&self) -> Result<bool> {
+		self.inner.enact_logs(false)
+	}
+
+	/// Sync the tables and recycle the log files whose records are all enacted. Returns `true`
+	/// if more files are left to clean.
+	pub fn clean_logs(&self) -> Result<bool> {
+		self.inner.clean_logs()
+	}
+
+	/// Move the next batch of entries of a hash indexed column that grew to its new index. The
+	/// batch is written to the log as a record of its own. Returns `false` if no column is
+	/// being reindexed, or the move waits for earlier records to be enacted.
+	pub fn process_reindex(&self) -> Result<bool> {
+		self.inner.process_reindex()
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		if self.join_on_shutdown {
+			self.inner.shutdown();
+			if let Some(t) = self.log_thread.take() {
+				if let Err(e) = t.join() {
+					log::warn!(target: "parity-db", "Log thread shutdown error: {:?}", e);
+				}
+			}
+			if let Some(t) = self.flush_thread.take() {
+				if let Err(e) = t.join() {
+					log::warn!(target: "parity-db", "Flush thread shutdown error: {:?}", e);
+				}
+			}
+			if let Some(t) = self.commit_thread.take() {
+				if let Err(e) = t.join() {
+					log::warn!(target: "parity-db", "Commit thread shutdown error: {:?}", e);
+				}
+			}
+			if let Some(t) = self.cleanup_thread.take() {
+				if let Err(e) = t.join() {
+					log::warn!(target: "parity-db", "Cleanup thread shutdown error: {:?}", e);
+				}
+			}
+			if let Some(t) = self.scrub_thread.take() {
+				if let Err(e) = t.join() {
+					log::warn!(target: "parity-db", "Scrub thread shutdown error: {:?}", e);
+				}
+			}
+			if let Err(e) = self.inner.kill_logs() {
+				log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
+			}
+		}
+	}
+}
+
+/// Remove the clean shutdown marker of the closed database at `path`, so that the state saved
+/// with it is not used for tables that are changed while the database is closed.
+pub(crate) fn discard_clean_shutdown(
+	files: &crate::storage::Files,
+	path: &std::path::Path,
+) -> Result<()> {
+	match files.remove(&path.join(CLEAN_SHUTDOWN_FILE)) {
+		Ok(()) => Ok(()),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(e) => Err(Error::Io(e)),
+	}
+}
+
+/// Value written in pieces, created with [`Db::begin_value`].
+pub struct ValueWriter<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	key: Vec<u8>,
+	// Data written so far, unless written to `blob`.
+	buf: Vec<u8>,
+	// Size past which data is written to a blob file.
+	blob_threshold: Option<usize>,
+	blob: Option<BlobWriter>,
+	// Bytes written so far.
+	len: usize,
+}
+
+impl<'a> ValueWriter<'a> {
+	/// Commit the value. Returns the commit id, see `Db::on_commit`.
+	pub fn commit(mut self) -> Result<u64> {
+		match self.blob.take() {
+			Some(blob) => self.db.commit_blob(self.col, &self.key, blob),
+			None => {
+				let value = std::mem::take(&mut self.buf);
+				self.db.commit_changes([(self.col, Operation::Set(self.key.clone(), value))])
+			},
+		}
+	}
+
+	fn write_data(&mut self, data: &[u8]) -> Result<()> {
+		self.len += data.len();
+		self.db.columns.options(self.col).check_value_size(self.len, true)?;
+		if let Some(blob) = &mut self.blob {
+			return blob.write(data)
+		}
+		self.buf.extend_from_slice(data);
+		if self.blob_threshold.is_some_and(|threshold| self.buf.len() > threshold) {
+			if let Column::Hash(column) = &self.db.columns[self.col as usize] {
+				// Discarded on drop if writing fails.
+				let blob = self.blob.insert(match column.create_blob_writer()? {
+					Some(blob) => blob,
+					None => return Ok(()),
+				});
+				blob.write(&std::mem::take(&mut self.buf))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<'a> std::io::Write for ValueWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.write_data(buf).map_err(|e| match e {
+			Error::Io(e) => e,
+			e => std::io::Error::other(e),
+		})?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl<'a> Drop for ValueWriter<'a> {
+	fn drop(&mut self) {
+		if let (Some(blob), Column::Hash(column)) =
+			(self.blob.take(), &self.db.columns[self.col as usize])
+		{
+			if let Err(e) = column.discard_blob(blob.id) {
+				log::warn!(target: "parity-db", "Error removing uncommitted blob: {}", e);
+			}
+		}
+	}
+}
+
+impl<'a> std::fmt::Debug for ValueWriter<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ValueWriter")
+			.field("col", &self.col)
+			.field("len", &self.blob.as_ref().map_or(self.buf.len() as u64, |b| b.len()))
+			.finish()
+	}
+}
+
+/// Point-in-time view of the database, created with [`Db::snapshot`].
+pub struct Snapshot<'a> {
+	db: &'a DbInner,
+	state: Arc<SnapshotState>,
+}
+
+impl<'a> Snapshot<'a> {
+	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		// Current value is queried first, any change made after that is recorded in the snapshot.
+		let current = self.db.get(col, key)?;
+		Ok(match &self.db.columns[col as usize] {
+			Column::Hash(column) => self.state.get(col, &column.hash_key(key), current),
+			Column::Tree(_) => self.state.btree_get(col, key, current),
+		})
+	}
+
+	/// Iterate over a btree indexed column as of the snapshot.
+	pub fn iter(&self, col: ColId) -> Result<SnapshotIterator<'_>> {
+		Ok(SnapshotIterator::new(self.db.btree_iter(col)?, self.state.clone(), col))
+	}
+}
+
+impl<'a> Drop for Snapshot<'a> {
+	fn drop(&mut self) {
+		self.db.snapshots.lock().retain(|s| !Arc::ptr_eq(s, &self.state));
+	}
+}
+
+pub type IndexedCommitOverlay = HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>;
+pub type BTreeCommitOverlay = BTreeMap<Vec<u8>, (u64, Option<Value>)>;
+// Key ranges removed by queued commits, with the commit id.
+pub type BTreeRangeOverlay = Vec<(u64, Vec<u8>, Vec<u8>)>;
+pub type MergeCommitOverlay = HashMap<Key, Vec<(u64, Value)>, IdentityBuildHasher>;
+
+#[derive(Debug)]
+pub struct CommitOverlay {
+	indexed: IndexedCommitOverlay,
+	btree_indexed: BTreeCommitOverlay,
+	// Keys in these ranges are removed, unless set in `btree_indexed`.
+	btree_ranges: BTreeRangeOverlay,
+	// Merge operands of queued commits that are resolved by the log writer, applied on top of
+	// the value in `indexed` or the log and tables. Operands are always more recent than the
+	// value in `indexed`.
+	merges: MergeCommitOverlay,
+}
+
+impl CommitOverlay {
+	fn new() -> Self {
+		CommitOverlay {
+			indexed: Default::default(),
+			btree_indexed: Default::default(),
+			btree_ranges: Default::default(),
+			merges: Default::default(),
+		}
+	}
+
+	#[cfg(test)]
+	fn is_empty(&self) -> bool {
+		self.indexed.is_empty() &&
+			self.btree_indexed.is_empty() &&
+			self.btree_ranges.is_empty() &&
+			self.merges.is_empty()
+	}
+}
+
+impl CommitOverlay {
+	fn get_ref(&self, key: &[u8]) -> Option<Option<&Value>> {
+		self.indexed.get(key).map(|(_, v)| v.as_ref())
+	}
+
+	fn get(&self, key: &[u8]) -> Option<Option<Value>> {
+		self.get_ref(key).map(|v| v.cloned())
+	}
+
+	fn get_size(&self, key: &[u8]) -> Option<Option<u32>> {
+		self.get_ref(key).map(|res| res.as_ref().map(|b| b.len() as u32))
+	}
+
+	fn has_merges(&self, key: &[u8]) -> bool {
+		self.merges.contains_key(key)
+	}
+
+	// Apply the pending merge operands of `key` to `value`, the latest value otherwise.
+	fn apply_merges(
+		&self,
+		key: &[u8],
+		value: Option<Value>,
+		options: &ColumnOptions,
+	) -> Option<Value> {
+		match (self.merges.get(key), &options.merge) {
+			(Some(operands), Some(merge)) => operands
+				.iter()
+				.fold(value, |value, (_, operand)| Some(merge.merge(value.as_deref(), operand))),
+			_ => value,
+		}
+	}
+
+	// Replace the operands of commit `record_id` for `key` with the merged `value`, unless a
+	// more recent commit set the key.
+	fn resolve_merge(&mut self, key: &Key, record_id: u64, value: Value) {
+		if let Some(operands) = self.merges.get_mut(key) {
+			operands.retain(|(id, _)| *id > record_id);
+			if operands.is_empty() {
+				self.merges.remove(key);
+			}
+		}
+		if self.indexed.get(key).is_none_or(|(id, _)| *id <= record_id) {
+			self.indexed.insert(*key, (record_id, Some(value)));
+		}
+	}
+
+	pub fn btree_get(&self, key: &[u8]) -> Option<Option<&Value>> {
+		match self.btree_indexed.get(key) {
+			Some((_, value)) => Some(value.as_ref()),
+			None => self.btree_removed(key).then_some(None),
+		}
+	}
+
+	// Check if a key of the log or the tables is removed by a queued range deletion.
+	fn btree_removed(&self, key: &[u8]) -> bool {
+		self.btree_ranges
+			.iter()
+			.any(|(_, start, end)| key >= &start[..] && key < &end[..])
+	}
+
+	/// Ranges removed by queued commits. Keys of the log and the tables in these ranges are
+	/// removed, unless they have a pending value.
+	pub fn btree_removed_ranges(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.btree_ranges
+			.iter()
+			.map(|(_, start, end)| (start.clone(), end.clone()))
+			.collect()
+	}
+
+	/// Keys in `[start, end)` with a pending value.
+	pub fn btree_range_keys(&self, start: &[u8], end: &[u8]) -> BTreeSet<Vec<u8>> {
+		self.btree_indexed
+			.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+			.filter(|(_, (_, value))| value.is_some())
+			.map(|(key, _)| key.clone())
+			.collect()
+	}
+
+	pub fn btree_next(&self, last_key: &crate::btree::LastKey) -> Option<(Value, Option<Value>)> {
+		use crate::btree::LastKey;
+		match &last_key {
+			LastKey::Start => self
+				.btree_indexed
+				.range::<Vec<u8>, _>(..)
+				.next()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+			LastKey::End => None,
+			LastKey::At(key) => self
+				.btree_indexed
+				.range::<Vec<u8>, _>((Bound::Excluded(key), Bound::Unbounded))
+				.next()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+			LastKey::Seeked(key) => self
+				.btree_indexed
+				.range::<Vec<u8>, _>(key..)
+				.next()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+		}
+	}
+
+	pub fn btree_prev(&self, last_key: &crate::btree::LastKey) -> Option<(Value, Option<Value>)> {
+		use crate::btree::LastKey;
+		match &last_key {
+			LastKey::End => self
+				.btree_indexed
+				.range::<Vec<u8>, _>(..)
+				.next_back()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+			LastKey::Start => None,
+			LastKey::At(key) => self
+				.btree_indexed
+				.range::<Vec<u8>, _>(..key)
+				.next_back()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+			LastKey::Seeked(key) => self
+				.btree_indexed
+				.range::<Vec<u8>, _>(..=key)
+				.next_back()
+				.map(|(k, (_, v))| (k.clone(), v.clone())),
+		}
+	}
+}
+
+/// Different operations allowed for a commit.
+/// Behavior may differs depending on column configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation<Key, Value> {
+	/// Insert or update the value for a given key.
+	Set(Key, Value),
+
+	/// Dereference at a given key, resulting in
+	/// either removal of a key value or decrement of its
+	/// reference count counter.
+	Dereference(Key),
+
+	/// Increment the reference count counter of an existing value for a given key.
+	/// If no value exists for the key, this operation is skipped.
+	Reference(Key),
+
+	/// Change the reference count of an existing value for a given key by the given amount, as
+	/// the same number of `Reference` or `Dereference` operations would. The value is removed
+	/// once its count reaches zero. If no value exists for the key, this operation is skipped.
+	/// Only supported on `ref_counted` columns.
+	AddRc(Key, i64),
+
+	/// Dereference all keys in the range `[start, end)`, including keys set earlier in the same
+	/// commit. Subtrees in range are removed without reading their keys, unless the column is
+	/// `ref_counted`. Only supported on btree indexed columns.
+	DeleteRange(Key, Key),
+
+	/// Combine the existing value for a given key with an operand using
+	/// the column merge function. Only supported on hash indexed columns.
+	///
+	/// Operands on a key with a queued value are applied when committed. Otherwise the
+	/// operands are kept in the commit overlay, applied by reads, and the merged value is
+	/// written by the log writer, so committing does not read the existing value. Reads of such
+	/// keys hold the commit overlay lock while reading the existing value. The log writer fails
+	/// with a background error if the merged value is larger than `max_value_size`. Operands are
+	/// applied when committed on columns with secondary indexes or being migrated.
+	Merge(Key, Value),
+
+	/// Insert or update the value for a given key, expiring after the given duration.
+	/// Only supported on columns with `ttl` enabled.
+	SetWithTtl(Key, Value, Duration),
+}
+
+impl<Key: Ord, Value: Eq> PartialOrd<Self> for Operation<Key, Value> {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<Key: Ord, Value: Eq> Ord for Operation<Key, Value> {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.key().cmp(other.key())
+	}
+}
+
+impl<Key, Value> Operation<Key, Value> {
+	pub fn key(&self) -> &Key {
+		match self {
+			Operation::Set(k, _) |
+			Operation::Dereference(k) |
+			Operation::Reference(k) |
+			Operation::AddRc(k, _) |
+			Operation::DeleteRange(k, _) |
+			Operation::Merge(k, _) |
+			Operation::SetWithTtl(k, _, _) => k,
+		}
+	}
+
+	pub fn into_key(self) -> Key {
+		match self {
+			Operation::Set(k, _) |
+			Operation::Dereference(k) |
+			Operation::Reference(k) |
+			Operation::AddRc(k, _) |
+			Operation::DeleteRange(k, _) |
+			Operation::Merge(k, _) |
+			Operation::SetWithTtl(k, _, _) => k,
+		}
+	}
+}
+
+impl<K: AsRef<[u8]>, Value> Operation<K, Value> {
+	pub fn to_key_vec(self) -> Operation<Vec<u8>, Value> {
+		match self {
+			Operation::Set(k, v) => Operation::Set(k.as_ref().to_vec(), v),
+			Operation::Dereference(k) => Operation::Dereference(k.as_ref().to_vec()),
+			Operation::Reference(k) => Operation::Reference(k.as_ref().to_vec()),
+			Operation::AddRc(k, delta) => Operation::AddRc(k.as_ref().to_vec(), delta),
+			Operation::DeleteRange(s, e) =>
+				Operation::DeleteRange(s.as_ref().to_vec(), e.as_ref().to_vec()),
+			Operation::Merge(k, v) => Operation::Merge(k.as_ref().to_vec(), v),
+			Operation::SetWithTtl(k, v, ttl) => Operation::SetWithTtl(k.as_ref().to_vec(), v, ttl),
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct CommitChangeSet {
+	pub indexed: HashMap<ColId, IndexedChangeSet>,
+	pub btree_indexed: HashMap<ColId, BTreeChangeSet>,
+	// Keys to remove if their values are still expired when committed.
+	pub expired: HashMap<ColId, Vec<Key>>,
+	// Values to move to lower value table entries if they are still at the given address when
+	// committed.
+	pub relocated: HashMap<ColId, Vec<(Key, Address)>>,
+	// Values already written to blob files, see `Db::begin_value`.
+	pub staged: HashMap<ColId, Vec<StagedBlob>>,
+}
+
+// Value written to a blob file with `ValueWriter`. It is not added to the commit overlay, and
+// becomes visible once the commit is written to the log.
+#[derive(Debug)]
+pub struct StagedBlob {
+	pub key: Key,
+	pub id: u64,
+	// Blob file length.
+	pub len: u64,
+	pub value_len: u64,
+}
+
+#[derive(Debug)]
+pub struct IndexedChangeSet {
+	pub col: ColId,
+	pub changes: Vec<Operation<Key, Vec<u8>>>,
+	// Original keys of hashed changes, kept for subscribers.
+	pub raw_keys: Option<HashMap<Key, Vec<u8>>>,
+}
+
+impl IndexedChangeSet {
+	pub fn new(col: ColId) -> Self {
+		IndexedChangeSet { col, changes: Default::default(), raw_keys: None }
+	}
+
+	fn push<K: AsRef<[u8]>>(
+		&mut self,
+		change: Operation<K, Vec<u8>>,
+		salt: &Salt,
+		options: &ColumnOptions,
+		db_version: u32,
+	) -> Result<()> {
+		let hash_key = |key: &[u8]| -> Key {
+			hash_key(key, salt, options.uniform, options.key_hash, db_version)
+		};
+
+		if options.uniform && change.key().as_ref().len() < 32 {
+			return Err(Error::InvalidInput(format!(
+				"Keys of uniform column {} must be at least 32 bytes",
+				self.col
+			)))
+		}
+		if let Some(raw_keys) = &mut self.raw_keys {
+			let key = change.key().as_ref();
+			raw_keys.insert(hash_key(key), key.to_vec());
+		}
+		let ttl = options.ttl;
+		self.push_change_hashed(match change {
+			Operation::Set(k, v) if ttl =>
+				Operation::Set(hash_key(k.as_ref()), encode_expiring(&v, 0)),
+			Operation::SetWithTtl(k, v, duration) if ttl => {
+				let expiry = now_millis().saturating_add(duration.as_millis() as u64).max(1);
+				Operation::Set(hash_key(k.as_ref()), encode_expiring(&v, expiry))
+			},
+			Operation::SetWithTtl(..) =>
+				return Err(Error::InvalidInput(format!("No ttl for column {}", self.col))),
+			Operation::Set(k, v) if options.store_keys =>
+				Operation::Set(hash_key(k.as_ref()), encode_keyed(k.as_ref(), &v)),
+			Operation::Set(k, v) => Operation::Set(hash_key(k.as_ref()), v),
+			Operation::Dereference(k) => Operation::Dereference(hash_key(k.as_ref())),
+			Operation::Reference(..) | Operation::AddRc(..) if !options.ref_counted =>
+				return Err(Error::InvalidInput(format!("No Rc for column {}", self.col))),
+			Operation::Reference(k) => Operation::Reference(hash_key(k.as_ref())),
+			Operation::AddRc(k, delta) => Operation::AddRc(hash_key(k.as_ref()), delta),
+			Operation::Merge(..) if options.merge.is_none() =>
+				return Err(Error::InvalidInput(format!(
+					"No merge operator for column {}",
+					self.col
+				))),
+			Operation::Merge(k, v) => Operation::Merge(hash_key(k.as_ref()), v),
+			Operation::DeleteRange(..) =>
+				return Err(Error::InvalidInput(format!(
+					"Range deletion is not supported for column {}",
+					self.col
+				))),
+		});
+		Ok(())
+	}
+
+	fn push_change_hashed(&mut self, change: Operation<Key, Vec<u8>>) {
+		self.changes.push(change);
+	}
+
+	/// Replace `Merge` operations with the merged value. Operands apply to the value set earlier
+	/// in this change set, or otherwise to the value returned by `existing`, which returns
+	/// `None` to leave the operands of the key pending. Returns the positions of the replaced
+	/// operations.
+	fn resolve_merges(
+		&mut self,
+		options: &ColumnOptions,
+		mut existing: impl FnMut(&Key) -> Result<Option<Option<Value>>>,
+	) -> Result<Vec<usize>> {
+		if !self.changes.iter().any(|change| matches!(change, Operation::Merge(..))) {
+			return Ok(Vec::new())
+		}
+		let merge = options.merge.as_ref().ok_or_else(|| {
+			Error::InvalidInput(format!("No merge operator for column {}", self.col))
+		})?;
+		// Latest value of each key, `None` while its operands are pending.
+		let mut latest: HashMap<Key, Option<Option<Value>>> = Default::default();
+		let mut resolved = Vec::new();
+		for (i, change) in self.changes.iter_mut().enumerate() {
+			match change {
+				Operation::Set(k, v) => {
+					latest.insert(*k, Some(Some(v.clone())));
+				},
+				Operation::Dereference(k) => {
+					latest.insert(*k, Some(None));
+				},
+				Operation::Merge(k, operand) => {
+					let existing = match latest.get(k) {
+						Some(value) => value.clone(),
+						None => existing(k)?,
+					};
+					let Some(existing) = existing else {
+						latest.insert(*k, None);
+						continue
+					};
+					let value = merge.merge(existing.as_deref(), operand);
+					options.check_value_size(value.len(), false)?;
+					latest.insert(*k, Some(Some(value.clone())));
+					*change = Operation::Set(*k, value);
+					resolved.push(i);
+				},
+				Operation::Reference(..) |
+				Operation::AddRc(..) |
+				Operation::DeleteRange(..) |
+				Operation::SetWithTtl(..) => (),
+			}
+		}
+		Ok(resolved)
+	}
+
+	/// Add changes to the columns of `indexes` for the values set or removed by this change set,
+	/// compared to the latest values in `overlay` or the column.
+	fn secondary_changes(
+		&self,
+		overlay: &CommitOverlay,
+		column: &HashColumn,
+		log: &RwLock<LogOverlays>,
+		indexes: &[SecondaryIndex],
+		btree_indexed: &mut HashMap<ColId, BTreeChangeSet>,
+	) -> Result<()> {
+		let missing_keys = || {
+			Error::InvalidInput(format!("Secondary indexes of column {} require keys", self.col))
+		};
+		let raw_keys = self.raw_keys.as_ref().ok_or_else(missing_keys)?;
+		// Only the last change of each key matters.
+		let mut last: HashMap<Key, Option<&Vec<u8>>> = Default::default();
+		for change in self.changes.iter() {
+			match change {
+				Operation::Set(k, v) => last.insert(*k, Some(v)),
+				Operation::Dereference(k) => last.insert(*k, None),
+				_ => continue,
+			};
+		}
+		for (key, value) in last {
+			let raw_key = raw_keys.get(&key).ok_or_else(missing_keys)?;
+			let existing = match overlay.get(&key) {
+				Some(value) => value,
+				None => column.get(&key, log)?,
+			};
+			for index in indexes {
+				let old = existing.as_deref().map(|v| index.keys(v)).unwrap_or_default();
+				let new = value.map(|v| index.keys(v)).unwrap_or_default();
+				let changes = btree_indexed
+					.entry(index.column)
+					.or_insert_with(|| BTreeChangeSet::new(index.column));
+				for k in old.iter().filter(|k| !new.contains(k)) {
+					changes.push(Operation::Dereference(crate::secondary::entry_key(k, raw_key)));
+				}
+				for k in new.iter().filter(|k| !old.contains(k)) {
+					changes
+						.push(Operation::Set(crate::secondary::entry_key(k, raw_key), Vec::new()));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn copy_to_overlay(
+		&self,
+		overlay: &mut CommitOverlay,
+		record_id: u64,
+		bytes: &mut usize,
+		options: &ColumnOptions,
+	) {
+		let ref_counted = options.ref_counted;
 		for change in self.changes.iter() {
 			match &change {
 				Operation::Set(k, v) => {
 					*bytes += k.len();
 					*bytes += v.len();
 					overlay.indexed.insert(*k, (record_id, Some(v.clone())));
+					overlay.merges.remove(k);
 				},
 				Operation::Dereference(k) => {
 					// Don't add removed ref-counted values to overlay.
 					if !ref_counted {
 						overlay.indexed.insert(*k, (record_id, None));
+						overlay.merges.remove(k);
 					}
 				},
-				Operation::Reference(..) => {
+				Operation::Reference(..) | Operation::AddRc(..) => {
 					// Don't add (we allow remove value in overlay when using rc: some
 					// indexing on top of it is expected).
-					if !ref_counted {
-						return Err(Error::InvalidInput(format!("No Rc for column {}", self.col)))
+					debug_assert!(ref_counted, "Rejected when pushed");
+				},
+				Operation::DeleteRange(..) => unreachable!("Rejected when pushed"),
+				Operation::Merge(k, operand) => {
+					*bytes += k.len();
+					*bytes += operand.len();
+					overlay.merges.entry(*k).or_default().push((record_id, operand.clone()));
+				},
+				Operation::SetWithTtl(..) => unreachable!("Converted when pushed"),
+			}
+		}
+	}
+
+	fn write_plan(
+		&self,
+		column: &Column,
+		writer: &mut crate::log::LogWriter,
+		ops: &mut u64,
+		reindex: &mut bool,
+		compression_threads: usize,
+	) -> Result<()> {
+		let column = match column {
+			Column::Hash(column) => column,
+			Column::Tree(_) => {
+				log::warn!(target: "parity-db", "Skipping unindex commit in indexed column");
+				return Ok(())
+			},
+		};
+		let compressed = column.compress_values(&self.changes, compression_threads);
+		for (i, change) in self.changes.iter().enumerate() {
+			let compressed = compressed.get(i).and_then(Option::as_ref);
+			if let PlanOutcome::NeedReindex = column.write_plan(change, compressed, writer)? {
+				// Reindex has triggered another reindex.
+				*reindex = true;
+			}
+			*ops += 1;
+		}
+		Ok(())
+	}
+
+	fn clean_overlay(&self, overlay: &mut CommitOverlay, record_id: u64) {
+		use std::collections::hash_map::Entry;
+		for change in self.changes.iter() {
+			match change {
+				Operation::Set(k, _) | Operation::Dereference(k) =>
+					if let Entry::Occupied(e) = overlay.indexed.entry(*k) {
+						if e.get().0 == record_id {
+							e.remove_entry();
+						}
+					},
+				Operation::Reference(..) |
+				Operation::AddRc(..) |
+				Operation::DeleteRange(..) |
+				Operation::Merge(..) |
+				Operation::SetWithTtl(..) => (),
+			}
+		}
+	}
+}
+
+/// Verification operation utilities.
+pub mod check {
+	pub enum CheckDisplay {
+		None,
+		Full,
+		Short(u64),
+	}
+
+	pub struct CheckOptions {
+		pub column: Option<u8>,
+		pub from: Option<u64>,
+		pub bound: Option<u64>,
+		pub display: CheckDisplay,
+	}
+
+	impl CheckOptions {
+		pub fn new(
+			column: Option<u8>,
+			from: Option<u64>,
+			bound: Option<u64>,
+			display_content: bool,
+			truncate_value_display: Option<u64>,
+		) -> Self {
+			let display = if display_content {
+				match truncate_value_display {
+					Some(t) => CheckDisplay::Short(t),
+					None => CheckDisplay::Full,
+				}
+			} else {
+				CheckDisplay::None
+			};
+			CheckOptions { column, from, bound, display }
+		}
+	}
+
+	/// Problem found by `Db::check`.
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub enum CheckIssue {
+		/// An index entry points to a value table slot that is empty, has a zero reference
+		/// count or can't be read.
+		BadIndexEntry { chunk: u64, entry: u64, error: Option<String> },
+		/// A value of a `store_keys` column holds a key that does not match its key hash, or no
+		/// key at all.
+		BadStoredKey { chunk: u64, key: Option<Vec<u8>> },
+		/// A btree node or value can't be read.
+		BadNode { address: u64, error: String },
+		/// A btree key is out of order.
+		UnorderedKey { key: Vec<u8> },
+		/// A btree node is not at the depth of the tree.
+		Unbalanced { address: u64 },
+	}
+
+	/// Findings for a single column.
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct ColumnCheck {
+		pub col: u8,
+		/// Number of values checked.
+		pub entries: u64,
+		/// Bytes of values read.
+		pub bytes: u64,
+		pub issues: Vec<CheckIssue>,
+	}
+
+	/// Result of `Db::check`.
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct CheckReport {
+		pub columns: Vec<ColumnCheck>,
+	}
+
+	impl CheckReport {
+		/// Returns `true` if no issues were found.
+		pub fn is_ok(&self) -> bool {
+			self.columns.iter().all(|c| c.issues.is_empty())
+		}
+	}
+
+	/// Result of `Db::open_repair`.
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct RepairReport {
+		/// Reasons for skipping the rest of a log file during replay. Later records in the same
+		/// file are lost.
+		pub skipped_logs: Vec<String>,
+		/// Issues found in each column before repairing.
+		pub columns: Vec<ColumnCheck>,
+		/// Number of hash index entries removed because their value could not be read.
+		pub removed: u64,
+		/// Number of hash index entries restored from value tables.
+		pub restored: u64,
+	}
+
+	/// Log replay when the database was opened, see `Db::recovery_report`.
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct RecoveryReport {
+		/// Id of the last log record replayed, 0 if there was nothing to replay.
+		pub last_replayed: u64,
+		/// Why a record could not be replayed. It was discarded, along with all records after it.
+		pub error: Option<String>,
+		/// Number of log files, the damaged one included, that were not replayed to the end.
+		pub discarded_logs: usize,
+	}
+}
+
+#[derive(Eq, PartialEq, Clone, Copy)]
+enum OpeningMode {
+	Create,
+	Write,
+	ReadOnly,
+	Follower,
+	Repair,
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{ColumnOptions, Value};
+
+	use super::{Change, CommitStage, Cursor, Db, Operation, Options};
+	use crate::{
+		column::ColId,
+		db::{DbInner, OpeningMode},
+		CompressionType, GrowPolicy, RecoveryMode,
+	};
+	use rand::Rng;
+	use std::{
+		collections::{BTreeMap, HashMap, HashSet},
+		path::Path,
+		sync::{mpsc, Arc},
+		thread,
+		time::{Duration, Instant},
+	};
+	use tempfile::tempdir;
+
+	// This is used in tests to disable certain commit stages.
+	#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+	pub(super) enum EnableCommitPipelineStages {
+		// No threads started, data stays in commit overlay.
+		#[allow(dead_code)]
+		CommitOverlay,
+		// Log worker run, data processed up to the log overlay.
+		#[allow(dead_code)]
+		LogOverlay,
+		// Runing all.
+		#[allow(dead_code)]
+		DbFile,
+		// Default run mode.
+		Standard,
+	}
+
+	impl EnableCommitPipelineStages {
+		pub(super) fn options(&self, path: &Path, num_columns: u8) -> Options {
+			Options {
+				path: path.into(),
+				sync_wal: true,
+				sync_data: true,
+				stats: true,
+				salt: None,
+				columns: (0..num_columns).map(|_| Default::default()).collect(),
+				compression_threshold: HashMap::new(),
+				log_archive: None,
+				slow_io_warn: None,
+				direct_io: false,
+				io_uring: false,
+				scrub: None,
+				compaction_rate: 0,
+				background_io_limit: None,
+				grow_policy: Default::default(),
+				preallocate: false,
+				memory_budget: Default::default(),
+				max_commit_bytes: None,
+				compression_threads: 4,
+				reindex_threads: 4,
+				replay_threads: 4,
+				recovery_mode: Default::default(),
+				in_memory: false,
+				storage: None,
+				expiry_interval: Duration::from_secs(60),
+				#[cfg(feature = "metrics")]
+				metrics: None,
+				with_background_thread: *self == Self::Standard,
+				always_flush: *self == Self::DbFile,
+			}
+		}
+
+		pub(super) fn run_stages(&self, db: &Db) {
+			let db = &db.inner;
+			if *self == EnableCommitPipelineStages::DbFile ||
+				*self == EnableCommitPipelineStages::LogOverlay
+			{
+				while db.process_commits().unwrap() {}
+				while db.process_reindex().unwrap() {}
+			}
+			if *self == EnableCommitPipelineStages::DbFile {
+				let _ = db.flush_logs(0).unwrap();
+				while db.enact_logs(false).unwrap() {}
+				let _ = db.clean_logs().unwrap();
+			}
+		}
+
+		fn check_empty_overlay(&self, db: &DbInner, col: ColId) -> bool {
+			match self {
+				EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
+					if let Some(overlay) = db.commit_overlay.read().get(col as usize) {
+						if !overlay.is_empty() {
+							let mut replayed = 5;
+							while !overlay.is_empty() {
+								if replayed > 0 {
+									replayed -= 1;
+									// the signal is triggered just before cleaning the overlay, so
+									// we wait a bit.
+									// Warning this is still rather flaky and should be ignored
+									// or removed.
+									std::thread::sleep(std::time::Duration::from_millis(100));
+								} else {
+									return false
+								}
+							}
+						}
 					}
 				},
+				_ => (),
+			}
+			true
+		}
+	}
+
+	#[test]
+	fn test_db_open_should_fail() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(matches!(Db::open(&options), Err(crate::Error::DatabaseNotFound)));
+	}
+
+	#[test]
+	fn test_db_open_fail_then_recursively_create() {
+		let tmp = tempdir().unwrap();
+		let (db_path_first, db_path_last) = {
+			let mut db_path_first = tmp.path().to_owned();
+			db_path_first.push("nope");
+
+			let mut db_path_last = db_path_first.to_owned();
+
+			for p in ["does", "not", "yet", "exist"] {
+				db_path_last.push(p);
+			}
+
+			(db_path_first, db_path_last)
+		};
+
+		assert!(
+			!db_path_first.exists(),
+			"That directory should not have existed at this point (dir: {:?})",
+			db_path_first
+		);
+
+		let options = Options::with_columns(&db_path_last, 5);
+		assert!(matches!(Db::open(&options), Err(crate::Error::DatabaseNotFound)));
+
+		assert!(!db_path_first.exists(), "That directory should remain non-existent. Did the `open(create: false)` nonetheless create a directory? (dir: {:?})", db_path_first);
+		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
+
+		assert!(
+			db_path_first.is_dir(),
+			"A directory should have been been created (dir: {:?})",
+			db_path_first
+		);
+		assert!(
+			db_path_last.is_dir(),
+			"A directory should have been been created (dir: {:?})",
+			db_path_last
+		);
+	}
+
+	#[test]
+	fn test_db_open_or_create() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
+		assert!(Db::open(&options).is_ok(), "Existing database should be reopened");
+	}
+
+	#[test]
+	fn test_indexed_keyvalues() {
+		test_indexed_keyvalues_inner(EnableCommitPipelineStages::CommitOverlay);
+		test_indexed_keyvalues_inner(EnableCommitPipelineStages::LogOverlay);
+		test_indexed_keyvalues_inner(EnableCommitPipelineStages::DbFile);
+		test_indexed_keyvalues_inner(EnableCommitPipelineStages::Standard);
+	}
+	fn test_indexed_keyvalues_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let options = db_test.options(tmp.path(), 5);
+		let col_nb = 0;
+
+		let key1 = b"key1".to_vec();
+		let key2 = b"key2".to_vec();
+		let key3 = b"key3".to_vec();
+
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
+
+		db.commit(vec![(col_nb, key1.clone(), Some(b"value1".to_vec()))]).unwrap();
+		db_test.run_stages(&db);
+		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
+
+		assert_eq!(db.get(col_nb, key1.as_slice()).unwrap(), Some(b"value1".to_vec()));
+
+		db.commit(vec![
+			(col_nb, key1.clone(), None),
+			(col_nb, key2.clone(), Some(b"value2".to_vec())),
+			(col_nb, key3.clone(), Some(b"value3".to_vec())),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
+
+		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
+		assert_eq!(db.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2".to_vec()));
+		assert_eq!(db.get(col_nb, key3.as_slice()).unwrap(), Some(b"value3".to_vec()));
+
+		db.commit(vec![
+			(col_nb, key2.clone(), Some(b"value2b".to_vec())),
+			(col_nb, key3.clone(), None),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
+
+		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
+		assert_eq!(db.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2b".to_vec()));
+		assert_eq!(db.get(col_nb, key3.as_slice()).unwrap(), None);
+	}
+
+	#[test]
+	fn test_get_many() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			for io_uring in [false, true] {
+				test_get_many_inner(stage, false, io_uring);
+				test_get_many_inner(stage, true, io_uring);
+			}
+		}
+	}
+
+	fn test_get_many_inner(db_test: EnableCommitPipelineStages, btree_index: bool, io_uring: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].btree_index = btree_index;
+		options.io_uring = io_uring;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit(
+			(0u32..200)
+				.map(|i| (0, i.to_be_bytes(), Some(vec![i as u8; i as usize])))
+				.chain(std::iter::once((0, 400u32.to_be_bytes(), Some(vec![4; 70000])))),
+		)
+		.unwrap();
+		db_test.run_stages(&db);
+		db.commit(vec![(0, 5u32.to_be_bytes(), None), (0, 300u32.to_be_bytes(), Some(vec![3]))])
+			.unwrap();
+
+		let keys: Vec<[u8; 4]> = [300u32, 150, 5, 1000, 0, 199, 150, 400]
+			.iter()
+			.map(|i| i.to_be_bytes())
+			.collect();
+		let values = db.get_many(0, &keys).unwrap();
+		assert_eq!(values.len(), keys.len());
+		assert_eq!(values[0], Some(vec![3]));
+		assert_eq!(values[1], Some(vec![150; 150]));
+		assert_eq!(values[2], None);
+		assert_eq!(values[3], None);
+		assert_eq!(values[7], Some(vec![4; 70000]));
+		for (key, value) in keys.iter().zip(values) {
+			assert_eq!(db.get(0, key).unwrap(), value);
+		}
+		assert!(db.get_many::<Vec<u8>>(0, &[]).unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_get_many_reads() {
+		use crate::index::CHUNK_READS;
+
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		// Uniform keys are not hashed, the first bytes select the index chunk.
+		let key = |chunk: u8, i: u8| {
+			let mut key = [0u8; 32];
+			key[0] = chunk;
+			key[2] = i;
+			key
+		};
+		db.commit((0..4).flat_map(|c| (0..10).map(move |i| (0, key(c, i), Some(vec![c, i])))))
+			.unwrap();
+		db_test.run_stages(&db);
+
+		// Present and missing keys of 4 chunks, and missing keys of an empty chunk.
+		let keys: Vec<_> = (0..4)
+			.flat_map(|c| (0..15).map(move |i| key(c, i)))
+			.chain([key(9, 0), key(9, 1)])
+			.collect();
+		let reads = CHUNK_READS.with(|reads| reads.get());
+		let values = db.get_many(0, &keys).unwrap();
+		assert_eq!(CHUNK_READS.with(|reads| reads.get()) - reads, 5);
+		for (key, value) in keys.iter().zip(values) {
+			let expected = (key[2] < 10 && key[0] < 4).then(|| vec![key[0], key[2]]);
+			assert_eq!(value, expected);
+		}
+	}
+
+	#[test]
+	fn test_contains_key() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			for (btree_index, ref_counted) in [(false, false), (false, true), (true, false)] {
+				test_contains_key_inner(stage, btree_index, ref_counted);
+			}
+		}
+	}
+
+	fn test_contains_key_inner(
+		db_test: EnableCommitPipelineStages,
+		btree_index: bool,
+		ref_counted: bool,
+	) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].btree_index = btree_index;
+		options.columns[0].ref_counted = ref_counted;
+		options.columns[0].preimage = ref_counted;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let small = b"small".to_vec();
+		let large = b"large".to_vec();
+		assert!(!db.contains_key(0, &small).unwrap());
+		db.commit(vec![
+			(0, small.clone(), Some(vec![1; 10])),
+			(0, large.clone(), Some(vec![2; 10000])),
+		])
+		.unwrap();
+		assert!(db.contains_key(0, &small).unwrap());
+		db_test.run_stages(&db);
+		assert!(db.contains_key(0, &small).unwrap());
+		assert!(db.contains_key(0, &large).unwrap());
+		assert!(!db.contains_key(0, b"missing").unwrap());
+
+		db.commit::<_, Vec<u8>>(vec![(0, large.clone(), None)]).unwrap();
+		db_test.run_stages(&db);
+		// Ref counted removals are not visible until processed.
+		assert_eq!(db.contains_key(0, &large).unwrap(), db.get(0, &large).unwrap().is_some());
+		assert!(db.contains_key(0, &small).unwrap());
+	}
+
+	#[test]
+	fn test_commit_if() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_commit_if_inner(stage, false);
+			test_commit_if_inner(stage, true);
+		}
+	}
+
+	fn test_commit_if_inner(db_test: EnableCommitPipelineStages, btree_index: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].btree_index = btree_index;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let set = |k: &[u8], v: &[u8]| (0, Operation::Set(k.to_vec(), v.to_vec()));
+
+		db.commit_if(vec![(0, b"a", None)], vec![set(b"a", b"1")]).unwrap();
+		db_test.run_stages(&db);
+		assert!(matches!(
+			db.commit_if(vec![(0, b"a", None)], vec![set(b"a", b"2")]),
+			Err(crate::Error::ConditionFailed { col: 0, .. })
+		));
+		assert!(db
+			.commit_if(
+				vec![(0, b"a", Some(b"1".to_vec())), (0, b"b", Some(vec![]))],
+				vec![set(b"b", b"2")]
+			)
+			.is_err());
+		db.commit_if(vec![(0, b"a", Some(b"1".to_vec()))], vec![set(b"a", b"2"), set(b"b", b"2")])
+			.unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+	}
+
+	#[test]
+	fn test_ttl() {
+		test_ttl_inner(EnableCommitPipelineStages::CommitOverlay);
+		test_ttl_inner(EnableCommitPipelineStages::LogOverlay);
+		test_ttl_inner(EnableCommitPipelineStages::DbFile);
+		test_ttl_inner(EnableCommitPipelineStages::Standard);
+	}
+
+	fn test_ttl_inner(db_test: EnableCommitPipelineStages) {
+		use std::time::Duration;
+
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].ttl = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit_changes(vec![
+			(0, Operation::SetWithTtl(b"expired".to_vec(), b"1".to_vec(), Duration::ZERO)),
+			(0, Operation::SetWithTtl(b"live".to_vec(), b"2".to_vec(), Duration::from_secs(3600))),
+			(0, Operation::Set(b"forever".to_vec(), b"3".to_vec())),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"expired").unwrap(), None);
+		assert!(!db.contains_key(0, b"expired").unwrap());
+		assert_eq!(db.get(0, b"live").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get_size(0, b"live").unwrap(), Some(1));
+		assert_eq!(
+			db.get_many(0, &[b"forever", b"expired"]).unwrap(),
+			vec![Some(b"3".to_vec()), None]
+		);
+
+		if db_test == EnableCommitPipelineStages::DbFile {
+			let count = |db: &Db| {
+				let mut count = 0;
+				db.iter_column_while(0, |_| {
+					count += 1;
+					true
+				})
+				.unwrap();
+				count
+			};
+			assert_eq!(count(&db), 3);
+			db.inner.expire_values().unwrap();
+			db_test.run_stages(&db);
+			assert_eq!(count(&db), 2);
+			assert_eq!(db.get(0, b"live").unwrap(), Some(b"2".to_vec()));
+		}
+
+		assert!(db
+			.commit_changes(vec![(1, Operation::SetWithTtl(vec![1], vec![1], Duration::ZERO))])
+			.is_err());
+	}
+
+	#[test]
+	fn test_expiry_batches() {
+		use std::time::Duration;
+
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].ttl = true;
+		options.expiry_interval = Duration::from_secs(3600);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let expired = super::EXPIRY_BATCH_SIZE as u32 * 2 + 100;
+		db.commit_changes((0..expired).map(|i: u32| {
+			(0, Operation::SetWithTtl(i.to_be_bytes().to_vec(), vec![1], Duration::ZERO))
+		}))
+		.unwrap();
+		db.commit_changes(vec![(0, Operation::Set(b"forever".to_vec(), b"3".to_vec()))])
+			.unwrap();
+		db_test.run_stages(&db);
+		let count = |db: &Db| {
+			let mut count = 0;
+			db.iter_column_while(0, |_| {
+				count += 1;
+				true
+			})
+			.unwrap();
+			count
+		};
+		assert_eq!(count(&db), expired + 1);
+
+		// Nothing is removed before the interval elapsed.
+		assert!(!db.inner.expire_step().unwrap());
+		assert_eq!(count(&db), expired + 1);
+
+		// Each step removes at most a batch.
+		db.inner.expiry.lock().next_sweep = std::time::Instant::now();
+		let mut steps = 0;
+		let mut remaining = expired + 1;
+		while db.inner.expire_step().unwrap() {
+			db_test.run_stages(&db);
+			let left = count(&db);
+			assert!(remaining - left <= super::EXPIRY_BATCH_SIZE as u32);
+			remaining = left;
+			steps += 1;
+		}
+		assert!(steps >= 3);
+		assert_eq!(count(&db), 1);
+		assert_eq!(db.get(0, b"forever").unwrap(), Some(b"3".to_vec()));
+		assert!(db.inner.expiry.lock().next_sweep > std::time::Instant::now());
+	}
+
+	#[test]
+	fn test_clear_column() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_clear_column_inner(stage, false);
+			test_clear_column_inner(stage, true);
+		}
+	}
+
+	fn test_clear_column_inner(db_test: EnableCommitPipelineStages, btree_index: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].btree_index = btree_index;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit((0..100u32).flat_map(|i| {
+			[(0, i.to_be_bytes(), Some(vec![i as u8; 100])), (1, i.to_be_bytes(), Some(vec![1]))]
+		}))
+		.unwrap();
+		db_test.run_stages(&db);
+
+		let snapshot = db.snapshot();
+		assert!(db.clear_column(0).is_err());
+		drop(snapshot);
+
+		db.clear_column(0).unwrap();
+		assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &5u32.to_be_bytes()).unwrap(), Some(vec![1]));
+		if btree_index {
+			let mut iter = db.iter(0).unwrap();
+			iter.seek_to_first().unwrap();
+			assert_eq!(iter.next().unwrap(), None);
+		}
+
+		db.commit(vec![(0, b"new", Some(vec![2]))]).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &99u32.to_be_bytes()).unwrap(), Some(vec![1]));
+		if db_test == EnableCommitPipelineStages::DbFile ||
+			db_test == EnableCommitPipelineStages::Standard
+		{
+			assert_eq!(db.get(0, b"new").unwrap(), Some(vec![2]));
+		}
+	}
+
+	#[test]
+	fn test_clear_column_rollback() {
+		let tmp = tempdir().unwrap();
+		let options = EnableCommitPipelineStages::DbFile.options(tmp.path(), 2);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, b"key", Some(vec![1])), (1, b"key", Some(vec![2]))]).unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		db.clear_column(1).unwrap();
+		assert!(!tmp.path().join(crate::migration::CLEAR_MARKER).exists());
+		drop(db);
+
+		// Interrupted after the index was detached, before the value tables.
+		std::fs::write(tmp.path().join(crate::migration::CLEAR_MARKER), [0]).unwrap();
+		let mut detached = 0;
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			let name = path.file_name().unwrap().to_str().unwrap().to_string();
+			if crate::index::TableId::is_file_name(0, &name) {
+				let dropped = format!("{}{}", name, crate::column::DROPPED_FILE_SUFFIX);
+				std::fs::rename(&path, tmp.path().join(dropped)).unwrap();
+				detached += 1;
+			}
+		}
+		assert_eq!(detached, 1);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(1, b"key").unwrap(), None);
+		assert!(!tmp.path().join(crate::migration::CLEAR_MARKER).exists());
+	}
+
+	#[test]
+	fn test_punch_holes() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_punch_holes_inner(stage, false);
+			test_punch_holes_inner(stage, true);
+		}
+	}
+
+	fn test_punch_holes_inner(db_test: EnableCommitPipelineStages, btree_index: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].btree_index = btree_index;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let value = |i: u32| vec![i as u8; 1000];
+
+		db.commit((0..100u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.punch_holes(0).unwrap(), 0);
+		db.commit((10..90u32).map(|i| (0, i.to_be_bytes(), None))).unwrap();
+		db_test.run_stages(&db);
+		assert!(db.punch_holes(0).unwrap() > 0);
+		assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), Some(value(5)));
+		assert_eq!(db.get(0, &50u32.to_be_bytes()).unwrap(), None);
+		assert_eq!(db.get(0, &95u32.to_be_bytes()).unwrap(), Some(value(95)));
+
+		db.commit((100..120u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		let mut count = 0;
+		if btree_index {
+			let mut iter = db.iter(0).unwrap();
+			iter.seek_to_first().unwrap();
+			while iter.next().unwrap().is_some() {
+				count += 1;
+			}
+		} else {
+			db.iter_column_while(0, |_| {
+				count += 1;
+				true
+			})
+			.unwrap();
+		}
+		let persisted = db_test == EnableCommitPipelineStages::DbFile ||
+			db_test == EnableCommitPipelineStages::Standard;
+		let end = if persisted { 120 } else { 100 };
+		assert_eq!(count, end - 80);
+		for i in (0..10u32).chain(90..end) {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+		}
+	}
+
+	#[test]
+	fn test_grow_policy() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.grow_policy = GrowPolicy::Exponential { min: 2, max: 1 };
+		assert!(!options.is_valid());
+
+		let occupancy = |options: &Options, count: u32| {
+			std::fs::remove_dir_all(tmp.path()).unwrap();
+			let db = Db::open_inner(options, OpeningMode::Create).unwrap();
+			db.commit((0..count).map(|i| (0, i.to_be_bytes(), Some(vec![i as u8; 1000]))))
+				.unwrap();
+			db_test.run_stages(&db);
+			let stats = db.column_stats(0).unwrap();
+			let table = *stats.tables.iter().find(|t| t.filled > 0).unwrap();
+			assert_eq!(table.filled, count as u64);
+			for i in 0..count {
+				assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 1000]));
+			}
+			table
+		};
+
+		options.grow_policy = GrowPolicy::Append;
+		options.preallocate = true;
+		let table = occupancy(&options, 300);
+		assert_eq!(table.capacity, 300);
+
+		let entry_size = table.entry_size as u64;
+		options.grow_policy = GrowPolicy::Exponential { min: 16 * entry_size, max: 1 << 20 };
+		let table = occupancy(&options, 300);
+		// 16 entries, doubled to 512 including the header entry.
+		assert_eq!(table.capacity, 511);
+
+		options.grow_policy = GrowPolicy::Fixed(100 * entry_size);
+		options.preallocate = false;
+		let table = occupancy(&options, 300);
+		assert_eq!(table.capacity, 399);
+	}
+
+	#[test]
+	fn test_options_builder() {
+		let tmp = tempdir().unwrap();
+		let options = Options::builder(tmp.path())
+			.sync_wal(false)
+			.column(ColumnOptions::builder().preimage(true).ref_counted(true).build().unwrap())
+			.column(
+				ColumnOptions::builder()
+					.compression(CompressionType::Lz4)
+					.btree_index(true)
+					.build()
+					.unwrap(),
+			)
+			.compression_threshold(1, 128)
+			.build()
+			.unwrap();
+		assert_eq!(options.columns.len(), 2);
+		assert!(!options.sync_wal);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(1, b"key", Some(vec![1]))]).unwrap();
+		assert_eq!(db.get(1, b"key").unwrap(), Some(vec![1]));
+		drop(db);
+
+		// Unsupported combinations, with the reason.
+		match ColumnOptions::builder().ref_counted(true).build() {
+			Err(crate::Error::InvalidConfiguration(reason)) => assert!(reason.contains("preimage")),
+			r => panic!("Unexpected result {:?}", r),
+		}
+		// Combinations that would be ignored.
+		assert!(ColumnOptions::builder().btree_index(true).uniform(true).build().is_err());
+		let no_compression = Options::builder(tmp.path())
+			.column(Default::default())
+			.compression_threshold(0, 128)
+			.build();
+		assert!(matches!(
+			no_compression,
+			Err(crate::Error::IncompatibleColumnConfig { id: 0, .. })
+		));
+		assert!(Options::builder(tmp.path()).compression_threshold(0, 128).build().is_err());
+
+		// Invalid options fail to open instead of panicking.
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ttl = true;
+		options.columns[0].btree_index = true;
+		assert!(matches!(
+			Db::open(&options),
+			Err(crate::Error::IncompatibleColumnConfig { id: 0, .. })
+		));
+	}
+
+	#[test]
+	fn test_typed_column() {
+		let tmp = tempdir().unwrap();
+		let db = Db::with_columns(tmp.path(), 2).unwrap();
+		let names = db.typed_column::<[u8; 4], String>(0).unwrap();
+		let counts = db.typed_column::<String, u64>(1).unwrap();
+		assert!(db.typed_column::<[u8; 4], String>(2).is_err());
+
+		names.insert([1; 4], &"one".to_string()).unwrap();
+		db.commit([counts.change("a".into(), Some(&5))]).unwrap();
+		assert_eq!(names.get(&[1; 4]).unwrap(), Some("one".to_string()));
+		assert_eq!(names.get(&[2; 4]).unwrap(), None);
+		assert_eq!(counts.get(&"a".into()).unwrap(), Some(5));
+		assert!(counts.contains_key(&"a".into()).unwrap());
+		assert_eq!(db.get(1, b"a").unwrap(), Some(5u64.to_be_bytes().to_vec()));
+
+		// Values that don't decode are reported.
+		db.commit([(1, b"b", Some(vec![1]))]).unwrap();
+		assert!(matches!(counts.get(&"b".into()), Err(crate::Error::InvalidValueData)));
+
+		names.remove([1; 4]).unwrap();
+		assert_eq!(names.get(&[1; 4]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_iter_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.with_background_thread = false;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.iter_column(1).is_err());
+		assert!(db.iter_column(2).is_err());
+
+		db.commit((0..600u32).map(|i| (0, i.to_be_bytes(), Some(i.to_le_bytes().to_vec()))))
+			.unwrap();
+		while db.process_commits().unwrap() {}
+		db.flush_logs().unwrap();
+		while db.enact_logs().unwrap() {}
+		// Queued commits are merged in.
+		db.commit((0..100u32).map(|i| (0, i.to_be_bytes(), None))).unwrap();
+		db.commit((600..700u32).map(|i| (0, i.to_be_bytes(), Some(i.to_le_bytes().to_vec()))))
+			.unwrap();
+
+		let expected: BTreeMap<crate::Key, Value> = (100..700u32)
+			.map(|i| (db.hash_key(0, &i.to_be_bytes()), i.to_le_bytes().to_vec()))
+			.collect();
+		let mut iter = db.iter_column(0).unwrap();
+		let mut values = Vec::new();
+		while values.len() < 300 {
+			values.push(iter.next().unwrap().unwrap());
+		}
+		let cursor = iter.cursor();
+		drop(iter);
+		while db.process_commits().unwrap() {}
+		let mut iter = db.resume_iter_column(&cursor).unwrap();
+		while let Some(value) = iter.next().unwrap() {
+			values.push(value);
+		}
+		assert_eq!(values, expected.into_iter().collect::<Vec<_>>());
+
+		let mut iter = db.resume_iter_column(&iter.cursor()).unwrap();
+		assert_eq!(iter.next().unwrap(), None);
+		assert!(db.resume_iter(&cursor).is_err());
+	}
+
+	#[test]
+	fn test_scan_cursor() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let keys: Vec<_> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+		let cursors = {
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(keys.iter().map(|k| (0, k.clone(), Some(k.clone())))).unwrap();
+			db.commit(keys.iter().map(|k| (1, k.clone(), Some(k.clone())))).unwrap();
+			let mut hash = db.iter_column(0).unwrap();
+			let mut tree = db.iter(1).unwrap();
+			tree.seek_to_first().unwrap();
+			for _ in 0..40 {
+				hash.next().unwrap().unwrap();
+				tree.next().unwrap().unwrap();
+			}
+			let mut prefix = db.iter_prefix(1, &[0, 0, 0]).unwrap();
+			prefix.next().unwrap().unwrap();
+			[hash.cursor(), tree.cursor(), prefix.cursor()].map(|cursor| cursor.to_bytes())
+		};
+
+		// Cursors survive reopening the database.
+		let db = Db::open(&options).unwrap();
+		let [hash, tree, prefix] = cursors.map(|bytes| Cursor::from_bytes(&bytes).unwrap());
+		let mut iter = db.resume_iter_column(&hash).unwrap();
+		let mut count = 0;
+		while iter.next().unwrap().is_some() {
+			count += 1;
+		}
+		assert_eq!(count, 60);
+		let mut iter = db.resume_iter(&tree).unwrap();
+		for key in &keys[40..] {
+			assert_eq!(iter.next().unwrap().map(|(k, _)| k).as_ref(), Some(key));
+		}
+		assert_eq!(iter.next().unwrap(), None);
+		let mut done = db.resume_iter(&iter.cursor()).unwrap();
+		assert_eq!(done.next().unwrap(), None);
+		let mut iter = db.resume_iter(&prefix).unwrap();
+		for key in &keys[1..] {
+			assert_eq!(iter.next().unwrap().map(|(k, _)| k).as_ref(), Some(key));
+		}
+		assert_eq!(iter.next().unwrap(), None);
+
+		assert!(db.resume_iter_column(&tree).is_err());
+		let mut bytes = hash.to_bytes();
+		bytes[4] ^= 1;
+		assert!(Cursor::from_bytes(&bytes).is_err());
+		assert!(Cursor::from_bytes(&[]).is_err());
+	}
+
+	#[test]
+	fn test_key_count() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 4);
+		options.salt = Some(Default::default());
+		options.columns[0].count_keys = true;
+		options.columns[1].count_keys = true;
+		options.columns[1].btree_index = true;
+		options.columns[3].btree_index = true;
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for col in 0..3 {
+				db.commit((0..20000).map(|i| (col, key(i), Some(key(i))))).unwrap();
+				// Replaced, removed and missing keys.
+				db.commit(
+					(0..100)
+						.map(|i| (col, key(i), Some(vec![1])))
+						.chain((100..1000).chain(30000..30100).map(|i| (col, key(i), None))),
+				)
+				.unwrap();
+			}
+			db.commit_changes([(1, Operation::DeleteRange(key(1000), key(2000)))]).unwrap();
+			assert_eq!(db.exact_len(0).unwrap(), 19100);
+			assert_eq!(db.exact_len(1).unwrap(), 18100);
+			assert_eq!(db.approximate_len(1).unwrap(), 18100);
+			assert!(db.exact_len(2).is_err());
+			assert!(db.exact_len(4).is_err());
+			assert!(db.approximate_len(3).is_err());
+			let estimate = db.approximate_len(2).unwrap();
+			assert!(estimate > 19100 * 7 / 10 && estimate < 19100 * 13 / 10, "{}", estimate);
+		}
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.exact_len(0).unwrap(), 19100);
+		assert_eq!(db.exact_len(1).unwrap(), 18100);
+		let mut other = options.clone();
+		other.columns[0].count_keys = false;
+		drop(db);
+		assert!(Db::open(&other).is_err());
+	}
+
+	#[test]
+	fn test_tree_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			assert_eq!(db.tree_stats(0).unwrap().nodes, Vec::<u64>::new());
+			db.commit((0..1000).map(|i| (0, key(i), Some(vec![0; 12])))).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		let stats = db.tree_stats(0).unwrap();
+		assert!(stats.depth > 0);
+		assert_eq!(stats.nodes.len(), stats.depth as usize + 1);
+		assert_eq!(stats.nodes[0], 1);
+		assert_eq!(stats.occupancy.iter().sum::<u64>(), stats.nodes.iter().sum::<u64>());
+		let keys: u64 = stats.occupancy.iter().enumerate().map(|(n, c)| n as u64 * c).sum();
+		assert_eq!(keys, 1000);
+
+		let prefix = [0, 0, 1];
+		let nodes = db.tree_nodes(0, &prefix).unwrap();
+		assert_eq!(nodes[0].level, 0);
+		assert!(nodes.len() < stats.nodes.iter().sum::<u64>() as usize);
+		let found = nodes
+			.iter()
+			.flat_map(|n| n.keys.iter())
+			.filter(|k| k.starts_with(&prefix))
+			.count();
+		assert_eq!(found, 256);
+		assert!(db.tree_stats(1).is_err());
+		assert!(db.tree_nodes(1, &[]).is_err());
+	}
+
+	#[test]
+	fn test_estimate_range_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit((0..10000).map(|i| (0, key(i), Some(vec![0; 12])))).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		let check = |start: u32, end: u32| {
+			let size = db.estimate_range_size(0, &key(start), &key(end)).unwrap();
+			let keys = (end - start) as u64;
+			assert!(size.keys >= keys / 2 && size.keys <= keys * 2, "{:?} {}", size, keys);
+			assert!(size.bytes >= keys * 8 && size.bytes <= keys * 32, "{:?} {}", size, keys);
+		};
+		check(0, 10000);
+		check(2500, 7500);
+		check(100, 600);
+		let size = db.estimate_range_size(0, &key(10), &key(12)).unwrap();
+		assert_eq!(size, crate::RangeSize { keys: 2, bytes: 32 });
+		let size = db.estimate_range_size(0, &key(20000), &key(30000)).unwrap();
+		assert_eq!(size, Default::default());
+		let size = db.estimate_range_size(0, &[], &[0xff; 5]).unwrap();
+		assert!(size.keys >= 5000 && size.keys <= 20000, "{:?}", size);
+		assert!(db.estimate_range_size(1, &[], &[0xff]).is_err());
+	}
+
+	#[test]
+	fn test_get_rc() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.with_background_thread = false;
+		options.columns[0].ref_counted = true;
+		options.columns[0].preimage = true;
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = [1u8; 32];
+		let value = vec![7u8; 10000];
+		let logged = |change: Operation<Vec<u8>, Vec<u8>>| {
+			db.commit_changes([(0, change)]).unwrap();
+			while db.process_commits().unwrap() {}
+		};
+		logged(Operation::Set(key.to_vec(), value.clone()));
+		logged(Operation::Set(key.to_vec(), value.clone()));
+		logged(Operation::Reference(key.to_vec()));
+		assert_eq!(db.get_rc(0, &key).unwrap(), Some(3));
+		db.flush_logs().unwrap();
+		while db.enact_logs().unwrap() {}
+		let (stored, meta) = db.get_with_meta(0, &key).unwrap().unwrap();
+		assert_eq!(stored, value);
+		assert_eq!(meta.rc, 3);
+		assert!(meta.compressed);
+		let hashed = db.get_hashed_with_meta(0, &db.hash_key(0, &key)).unwrap();
+		assert_eq!(hashed, Some((value.clone(), meta)));
+		assert_eq!(db.get_hashed_with_meta(0, &[0u8; 32]).unwrap(), None);
+		logged(Operation::Dereference(key.to_vec()));
+		logged(Operation::Dereference(key.to_vec()));
+		assert_eq!(db.get_rc(0, &key).unwrap(), Some(1));
+		logged(Operation::Dereference(key.to_vec()));
+		assert_eq!(db.get_rc(0, &key).unwrap(), None);
+
+		db.commit([(1, key, Some(value.clone()))]).unwrap();
+		while db.process_commits().unwrap() {}
+		assert_eq!(db.get_rc(1, &key).unwrap(), Some(1));
+		assert!(db.get_rc(2, &key).is_err());
+		assert!(db.get_rc(3, &key).is_err());
+	}
+
+	#[test]
+	fn test_add_rc() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.with_background_thread = false;
+		for c in [0, 1] {
+			options.columns[c].ref_counted = true;
+			options.columns[c].preimage = true;
+		}
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = b"key".to_vec();
+		let change = |col: u8, change: Operation<Vec<u8>, Vec<u8>>| {
+			db.commit_changes([(col, change)]).unwrap();
+			while db.process_commits().unwrap() {}
+			db.flush_logs().unwrap();
+			while db.enact_logs().unwrap() {}
+		};
+		for col in [0, 1] {
+			change(col, Operation::AddRc(key.clone(), 5));
+			assert_eq!(db.get(col, &key).unwrap(), None);
+			change(col, Operation::Set(key.clone(), b"value".to_vec()));
+			change(col, Operation::AddRc(key.clone(), 1000));
+			change(col, Operation::AddRc(key.clone(), -1000));
+			change(col, Operation::AddRc(key.clone(), 0));
+			change(col, Operation::Dereference(key.clone()));
+			assert_eq!(db.get(col, &key).unwrap(), None);
+			change(col, Operation::Set(key.clone(), b"value".to_vec()));
+			change(col, Operation::AddRc(key.clone(), 3));
+			change(col, Operation::AddRc(key.clone(), -3));
+			assert_eq!(db.get(col, &key).unwrap(), Some(b"value".to_vec()));
+			change(col, Operation::AddRc(key.clone(), i64::MIN));
+			assert_eq!(db.get(col, &key).unwrap(), None);
+		}
+		change(0, Operation::Set(key.clone(), b"value".to_vec()));
+		change(0, Operation::AddRc(key.clone(), i64::MAX));
+		change(0, Operation::AddRc(key.clone(), -(u32::MAX as i64)));
+		// The count is locked once it reaches the maximum.
+		assert_eq!(db.get_rc(0, &key).unwrap(), Some(u32::MAX as u64));
+		assert!(db.commit_changes([(2, Operation::AddRc(key.clone(), 1))]).is_err());
+	}
+
+	#[test]
+	fn test_column_disk_usage() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.with_background_thread = false;
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let empty = db.column_disk_usage(0).unwrap();
+		assert_eq!(empty.log_bytes, 0);
+		assert!(empty.value_bytes.iter().all(|b| *b == 0));
+		db.commit((0..100u32).map(|i| (0, i.to_le_bytes().to_vec(), Some(vec![1; 100]))))
+			.unwrap();
+		while db.process_commits().unwrap() {}
+		db.flush_logs().unwrap();
+		let logged = db.column_disk_usage(0).unwrap();
+		assert!(logged.log_bytes > 100 * 100, "{:?}", logged);
+		assert_eq!(db.column_disk_usage(1).unwrap().log_bytes, 0);
+		while db.enact_logs().unwrap() {}
+		let usage = db.column_disk_usage(0).unwrap();
+		assert_eq!(usage.log_bytes, 0);
+		assert!(usage.index_bytes > 0);
+		assert!(usage.value_bytes.iter().sum::<u64>() >= 100 * 100, "{:?}", usage);
+		assert_eq!(db.column_disk_usage(1).unwrap().index_bytes, 0);
+		assert!(db.column_disk_usage(2).is_err());
+	}
+
+	#[test]
+	fn test_ingest_sorted() {
+		use super::check::CheckOptions;
+
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		let value = |i: u32| vec![i as u8; i as usize % 300];
+		for count in [0, 1, 8, 9, 10, 17, 81, 5000] {
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 2);
+			options.columns[0].btree_index = true;
+			options.columns[0].count_keys = true;
+			{
+				let db = Db::open_or_create(&options).unwrap();
+				assert!(db.ingest_sorted(1, [(key(0), value(0))]).is_err());
+				let ingested =
+					db.ingest_sorted(0, (0..count).map(|i| (key(i * 2), value(i)))).unwrap();
+				assert_eq!(ingested, count as u64);
+				assert_eq!(db.exact_len(0).unwrap(), count as u64);
+				for i in 0..count {
+					assert_eq!(db.get(0, &key(i * 2)).unwrap(), Some(value(i)));
+					assert_eq!(db.get(0, &key(i * 2 + 1)).unwrap(), None);
+				}
+				// The tree accepts regular commits.
+				db.commit((0..count / 2).map(|i| (0, key(i * 2 + 1), Some(value(i))))).unwrap();
+				db.commit((0..count / 4).map(|i| (0, key(i * 2), None))).unwrap();
+			}
+			let db = Db::open(&options).unwrap();
+			let report = db.check(CheckOptions::new(Some(0), None, None, false, None)).unwrap();
+			assert!(report.is_ok(), "{:?}", report);
+			let mut iter = db.iter(0).unwrap();
+			iter.seek_to_first().unwrap();
+			let mut keys = 0;
+			while let Some((k, _)) = iter.next().unwrap() {
+				let i = u32::from_be_bytes(k.try_into().unwrap());
+				assert!(i % 2 == 1 || i / 2 >= count / 4);
+				keys += 1;
+			}
+			assert_eq!(keys, count - count / 4 + count / 2);
+			if count > 0 {
+				assert!(db.ingest_sorted(0, [(key(count * 2), value(0))]).is_err());
+			}
+		}
+
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys = [1u32, 2, 3, 3, 4];
+		assert!(db.ingest_sorted(0, keys.iter().map(|i| (key(*i), value(*i)))).is_err());
+		assert_eq!(db.get(0, &key(3)).unwrap(), Some(value(3)));
+		assert_eq!(db.get(0, &key(4)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_btree_prefix_compression() {
+		use super::check::CheckOptions;
+
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		options.columns[1].btree_index = true;
+		options.columns[1].prefix_compression = true;
+		let key = |i: u32| {
+			let mut key = vec![7u8; 40 + i as usize % 5];
+			key.extend_from_slice(&i.to_be_bytes());
+			key
+		};
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for col in 0..2 {
+				db.commit((0..3000).map(|i| (col, key(i), Some(vec![i as u8; 8])))).unwrap();
+				db.commit((0..1000).map(|i| (col, key(i * 3), None))).unwrap();
+			}
+		}
+		let db = Db::open(&options).unwrap();
+		let report = db.check(CheckOptions::new(None, None, None, false, None)).unwrap();
+		assert!(report.is_ok(), "{:?}", report);
+		let mut iters = (db.iter(0).unwrap(), db.iter(1).unwrap());
+		iters.0.seek_to_first().unwrap();
+		iters.1.seek_to_first().unwrap();
+		let mut count = 0;
+		while let Some(next) = iters.0.next().unwrap() {
+			assert_eq!(iters.1.next().unwrap(), Some(next));
+			count += 1;
+		}
+		assert_eq!(iters.1.next().unwrap(), None);
+		assert_eq!(count, 2000);
+		let size = |col| db.space_usage(col).unwrap().live_bytes;
+		assert!(size(1) < size(0), "{} {}", size(1), size(0));
+		drop(iters);
+		drop(db);
+
+		options.columns[1].prefix_compression = false;
+		assert!(Db::open(&options).is_err());
+		options.columns[1].btree_index = false;
+		options.columns[1].prefix_compression = true;
+		assert!(!options.columns[1].is_valid());
+	}
+
+	#[test]
+	fn test_manual_maintenance() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.with_background_thread = false;
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0..3u8 {
+			db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+		}
+		assert_eq!(db.queue_stats().queued_commits, 3);
+		let mut processed = 0;
+		while db.process_commits().unwrap() {
+			processed += 1;
+		}
+		assert_eq!(processed, 3);
+		assert_eq!(db.queue_stats().queued_commits, 0);
+		assert!(db.flush_logs().unwrap());
+		assert!(!db.flush_logs().unwrap());
+		let mut enacted = 0;
+		while db.enact_logs().unwrap() {
+			enacted += 1;
+		}
+		assert_eq!(enacted, 3);
+		assert_eq!(db.queue_stats().commits_in_flight, 0);
+		assert!(!db.clean_logs().unwrap());
+		assert_eq!(db.inner.log.num_dirty_logs(), 0);
+		assert!(!db.process_reindex().unwrap());
+		for i in 0..3u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &[2]).unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_max_file_size() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].max_file_size = Some(16 * 1024);
+		assert!(!options.is_valid());
+		options.columns[0].max_file_size = Some(64 * 1024);
+		let value = |i: u32| vec![i as u8; 1000];
+		let segments = || {
+			let mut names: Vec<_> = std::fs::read_dir(tmp.path())
+				.unwrap()
+				.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+				.filter(|name| name.starts_with("table_00_"))
+				.collect();
+			names.sort();
+			names
+		};
+
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit((0..300u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+		let table = db.column_stats(0).unwrap().tables.into_iter().find(|t| t.filled > 0).unwrap();
+		let segment_entries = 64 * 1024 / table.entry_size as u64;
+		let names = segments();
+		assert_eq!(names.len() as u64, 301u64.div_ceil(segment_entries));
+		let name = crate::table::TableId::new(0, table.tier).file_name();
+		assert_eq!(names[0], name);
+		assert_eq!(names[1], format!("{}.1", name));
+		for name in &names {
+			let len = std::fs::metadata(tmp.path().join(name)).unwrap().len();
+			assert!(len <= segment_entries * table.entry_size as u64);
+		}
+		drop(db);
+
+		let mut other = options.clone();
+		other.columns[0].max_file_size = None;
+		assert!(Db::open_inner(&other, OpeningMode::Write).is_err());
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in 0..300u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+		}
+		db.commit((10..300u32).map(|i| (0, i.to_be_bytes(), None))).unwrap();
+		db_test.run_stages(&db);
+		db.compact_column(0).unwrap();
+		assert_eq!(segments().len(), 1);
+		for i in 0..10u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+		}
+		db.commit((300..400u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(segments().len() as u64, 111u64.div_ceil(segment_entries));
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in (0..10u32).chain(300..400) {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+		}
+	}
+
+	#[test]
+	fn test_blob_values() {
+		for stage in [EnableCommitPipelineStages::LogOverlay, EnableCommitPipelineStages::DbFile] {
+			test_blob_values_inner(stage, false);
+			test_blob_values_inner(stage, true);
+		}
+	}
+
+	fn test_blob_values_inner(db_test: EnableCommitPipelineStages, ref_counted: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].ref_counted = ref_counted;
+		options.columns[0].preimage = ref_counted;
+		options.columns[0].blob_threshold = Some(16 * 1024);
+		assert!(!options.is_valid());
+		options.columns[0].blob_threshold = Some(64 * 1024);
+		let value = |i: u8, len: usize| vec![i; len];
+		let blobs = || {
+			std::fs::read_dir(tmp.path())
+				.unwrap()
+				.filter(|entry| {
+					entry.as_ref().unwrap().file_name().to_str().unwrap().contains(".blob_")
+				})
+				.count()
+		};
+
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit([
+			(0, vec![0], Some(value(0, 200_000))),
+			(0, vec![1], Some(value(1, 300_000))),
+			(0, vec![2], Some(value(2, 40_000))),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(value(0, 200_000)));
+		assert_eq!(db.get(0, &[1]).unwrap(), Some(value(1, 300_000)));
+		assert_eq!(db.get(0, &[2]).unwrap(), Some(value(2, 40_000)));
+		assert_eq!(db.get_size(0, &[1]).unwrap(), Some(300_000));
+		assert_eq!(blobs(), 2);
+
+		if ref_counted {
+			db.commit_changes(vec![
+				(0, Operation::Reference(vec![0])),
+				(0, Operation::Dereference(vec![0])),
+				(0, Operation::Dereference(vec![1])),
+			])
+			.unwrap();
+		} else {
+			db.commit([
+				(0, vec![0], Some(value(10, 200_001))),
+				(0, vec![1], None),
+				(0, vec![2], Some(value(12, 500_000))),
+			])
+			.unwrap();
+		}
+		db_test.run_stages(&db);
+		let expected = |i: u8| match (ref_counted, i) {
+			(true, 0) => Some(value(0, 200_000)),
+			(true, 2) => Some(value(2, 40_000)),
+			(false, 0) => Some(value(10, 200_001)),
+			(false, 2) => Some(value(12, 500_000)),
+			_ => None,
+		};
+		for i in 0..3 {
+			assert_eq!(db.get(0, &[i]).unwrap(), expected(i));
+		}
+		if db_test == EnableCommitPipelineStages::DbFile {
+			assert_eq!(blobs(), if ref_counted { 1 } else { 2 });
+		}
+		drop(db);
+
+		// Left by a commit that was not logged.
+		let uncommitted = tmp.path().join(format!(
+			"{}.blob_00000000000000ff.tmp",
+			crate::table::TableId::new(0, 255).file_name()
+		));
+		std::fs::write(&uncommitted, [0; 10]).unwrap();
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert!(!uncommitted.exists());
+		assert_eq!(blobs(), if ref_counted { 1 } else { 2 });
+		for i in 0..3 {
+			assert_eq!(db.get(0, &[i]).unwrap(), expected(i));
+		}
+		db.commit([(0, vec![0], None), (0, vec![2], None)]).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(blobs(), 0);
+		assert_eq!(db.get(0, &[0]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_get_reader() {
+		for stage in [EnableCommitPipelineStages::LogOverlay, EnableCommitPipelineStages::DbFile] {
+			test_get_reader_inner(stage);
+		}
+	}
+
+	fn test_get_reader_inner(db_test: EnableCommitPipelineStages) {
+		use std::io::Read;
+
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].blob_threshold = Some(64 * 1024);
+		options.columns[1].blob_threshold = Some(64 * 1024);
+		options.columns[1].compression = CompressionType::Snappy;
+		let value = |i: u32, len: u32| (0..len).map(|n| (n % 251 + i) as u8).collect::<Vec<_>>();
+		let read = |db: &Db, col: ColId, key: &[u8]| {
+			db.get_reader(col, key).unwrap().map(|mut reader| {
+				let mut buf = Vec::new();
+				reader.read_to_end(&mut buf).unwrap();
+				buf
+			})
+		};
+
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		for col in 0..2 {
+			db.commit([
+				(col, vec![0], Some(value(0, 1_000_000))),
+				(col, vec![1], Some(value(1, 100))),
+				(col, vec![2], Some(value(2, 20_000))),
+			])
+			.unwrap();
+		}
+		// Still in the commit overlay.
+		assert_eq!(read(&db, 0, &[0]), Some(value(0, 1_000_000)));
+		db_test.run_stages(&db);
+		for col in 0..2 {
+			assert_eq!(read(&db, col, &[0]), Some(value(0, 1_000_000)));
+			assert_eq!(read(&db, col, &[1]), Some(value(1, 100)));
+			assert_eq!(read(&db, col, &[2]), Some(value(2, 20_000)));
+			assert_eq!(read(&db, col, &[3]), None);
+		}
+
+		let mut reader = db.get_reader(0, &[0]).unwrap().unwrap();
+		let mut start = vec![0; 1000];
+		reader.read_exact(&mut start).unwrap();
+		db.commit([(0, vec![0], None)]).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(read(&db, 0, &[0]), None);
+		let mut rest = Vec::new();
+		reader.read_to_end(&mut rest).unwrap();
+		start.extend(rest);
+		assert_eq!(start, value(0, 1_000_000));
+	}
+
+	#[test]
+	fn test_begin_value() {
+		for stage in [
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_begin_value_inner(stage);
+		}
+	}
+
+	fn test_begin_value_inner(db_test: EnableCommitPipelineStages) {
+		use std::io::Write;
+
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 3);
+		options.columns[0].blob_threshold = Some(64 * 1024);
+		options.columns[1].blob_threshold = Some(64 * 1024);
+		options.columns[1].value_checksums = true;
+		let value = |i: u32, len: u32| (0..len).map(|n| (n % 251 + i) as u8).collect::<Vec<_>>();
+		fn write<'a>(db: &'a Db, col: ColId, key: &[u8], value: &[u8]) -> super::ValueWriter<'a> {
+			let mut writer = db.begin_value(col, key).unwrap();
+			for chunk in value.chunks(10_000) {
+				writer.write_all(chunk).unwrap();
+			}
+			writer
+		}
+		let blob_files = || {
+			std::fs::read_dir(tmp.path())
+				.unwrap()
+				.filter(|f| f.as_ref().unwrap().file_name().to_string_lossy().contains(".blob_"))
+				.count()
+		};
+
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		for col in 0..3 {
+			let writer = write(&db, col, &[0], &value(0, 1_000_000));
+			assert_eq!(db.get(col, &[0]).unwrap(), None);
+			writer.commit().unwrap();
+			write(&db, col, &[1], &value(1, 100)).commit().unwrap();
+			assert_eq!(db.get(col, &[0]).unwrap(), Some(value(0, 1_000_000)));
+		}
+		db_test.run_stages(&db);
+		assert_eq!(blob_files(), 2);
+
+		// Dropped writers leave nothing behind.
+		drop(write(&db, 0, &[0], &value(2, 500_000)));
+		drop(write(&db, 0, &[2], &value(2, 500_000)));
+		assert_eq!(blob_files(), 2);
+
+		for col in 0..3 {
+			write(&db, col, &[0], &value(3, 200_000)).commit().unwrap();
+			write(&db, col, &[1], &value(4, 300_000)).commit().unwrap();
+		}
+		db_test.run_stages(&db);
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for col in 0..3 {
+			assert_eq!(db.get(col, &[0]).unwrap(), Some(value(3, 200_000)));
+			assert_eq!(db.get(col, &[1]).unwrap(), Some(value(4, 300_000)));
+			assert_eq!(db.get(col, &[2]).unwrap(), None);
+		}
+		assert_eq!(blob_files(), 4);
+	}
+
+	#[test]
+	fn test_compact_column() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_compact_column_inner(stage, false);
+			test_compact_column_inner(stage, true);
+		}
+	}
+
+	fn test_compact_column_inner(db_test: EnableCommitPipelineStages, ref_counted: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].ref_counted = ref_counted;
+		options.columns[0].preimage = ref_counted;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let value = |i: u32| vec![i as u8; 1000];
+		let removed = |i: u32| i < 560 && !i.is_multiple_of(8);
+		let live = (0..600u32).filter(|i| !removed(*i)).count() as u64;
+		let filled =
+			|db: &Db| db.column_stats(0).unwrap().tables.iter().map(|t| t.filled).sum::<u64>();
+
+		db.commit((0..600u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		if ref_counted {
+			db.commit_changes(vec![(0, Operation::Reference(599u32.to_be_bytes().to_vec()))])
+				.unwrap();
+		}
+		db_test.run_stages(&db);
+		db.commit((0..600u32).filter(|i| removed(*i)).map(|i| (0, i.to_be_bytes(), None)))
+			.unwrap();
+		db_test.run_stages(&db);
+		let compact = |col: ColId| {
+			db.compact_column(col).unwrap();
+			loop {
+				let progress = db.column_stats(col).unwrap().compaction.unwrap();
+				if progress.done {
+					break progress
+				}
+				std::thread::sleep(Duration::from_millis(10));
+			}
+		};
+		assert!(db.compact_column(2).is_err());
+		compact(1);
+
+		let before = db.space_usage(0).unwrap();
+		assert_eq!(before.free_entries, 600 - live);
+		assert!(before.index_bytes > 0);
+		let progress = compact(0);
+		let after = db.space_usage(0).unwrap();
+		assert_eq!(after.free_entries, 0);
+		assert_eq!(after.reclaimable_bytes, 0);
+		assert_eq!(after.live_bytes, before.live_bytes);
+		assert_eq!(before.reclaimable_bytes, progress.released_bytes);
+		assert_eq!(before.allocated_bytes - after.allocated_bytes, progress.released_bytes);
+		// Value `i` is initially stored in entry `i + 1`.
+		assert_eq!(
+			progress.moved_values,
+			(0..600u32).filter(|i| !removed(*i) && *i as u64 >= live).count() as u64
+		);
+		assert!(progress.released_bytes > 0);
+		assert_eq!(filled(&db), live);
+		for i in 0..600u32 {
+			let expected = if removed(i) { None } else { Some(value(i)) };
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), expected);
+		}
+		if ref_counted {
+			db.commit(vec![(0, 599u32.to_be_bytes(), None)]).unwrap();
+			assert_eq!(db.get(0, &599u32.to_be_bytes()).unwrap(), Some(value(599)));
+		}
+
+		db.commit((600..610u32).map(|i| (0, i.to_be_bytes(), Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		let persisted = db_test == EnableCommitPipelineStages::DbFile ||
+			db_test == EnableCommitPipelineStages::Standard;
+		assert_eq!(filled(&db), if persisted { live + 10 } else { live });
+		for i in 0..600u32 {
+			let expected = if removed(i) { None } else { Some(value(i)) };
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), expected);
+		}
+	}
+
+	#[test]
+	fn test_migrate_column() {
+		let hash = ColumnOptions::default();
+		let hash_lz4 = ColumnOptions { compression: CompressionType::Lz4, ..Default::default() };
+		let btree = ColumnOptions { btree_index: true, ..Default::default() };
+		let btree_lz4 = ColumnOptions { compression: CompressionType::Lz4, ..btree.clone() };
+		let btree_prefix = ColumnOptions { prefix_compression: true, ..btree.clone() };
+		let hash_lz4hc = ColumnOptions {
+			compression: CompressionType::Lz4Hc { level: crate::compress::LZ4HC_MAX_LEVEL },
+			..Default::default()
+		};
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_migrate_column_inner(stage, &hash, &hash_lz4);
+			test_migrate_column_inner(stage, &btree, &btree_lz4);
+			test_migrate_column_inner(stage, &btree, &btree_prefix);
+			test_migrate_column_inner(stage, &btree, &hash);
+			test_migrate_column_inner(stage, &hash_lz4, &hash_lz4hc);
+		}
+		let invalid = ColumnOptions { compression: CompressionType::Lz4Hc { level: 0 }, ..hash };
+		assert!(!invalid.is_valid());
+	}
+
+	#[test]
+	fn test_wide_ref_counts() {
+		let tmp = tempdir().unwrap();
+		let narrow = ColumnOptions { ref_counted: true, preimage: true, ..Default::default() };
+		let wide = ColumnOptions { wide_ref_counts: true, ..narrow.clone() };
+		assert!(!ColumnOptions { ref_counted: false, ..wide.clone() }.is_valid());
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0] = narrow;
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit_changes((0..1000).map(|i| (0, Operation::Set(key(i), vec![i as u8; 100]))))
+			.unwrap();
+		db.commit_changes((0..1000).map(|i| (0, Operation::AddRc(key(i), i as i64 % 5))))
+			.unwrap();
+		let unref = ColumnOptions { preimage: true, ..Default::default() };
+		assert!(db.migrate_column(0, unref).is_err());
+		db.migrate_column(0, wide.clone()).unwrap();
+		// Applied to both columns while migrating.
+		db.commit_changes((0..1000).map(|i| (0, Operation::Reference(key(i))))).unwrap();
+		db.commit_changes((0..100).map(|i| (0, Operation::AddRc(key(i), -10)))).unwrap();
+		let start = Instant::now();
+		while db.is_migrating(0) {
+			assert!(start.elapsed() < Duration::from_secs(60));
+			thread::sleep(Duration::from_millis(10));
+		}
+		drop(db);
+
+		options.columns[0] = wide;
+		let reopen = |change: Option<i64>| {
+			let db = Db::open(&options).unwrap();
+			if let Some(delta) = change {
+				db.commit_changes([(0, Operation::AddRc(key(500), delta))]).unwrap();
+			}
+			db
+		};
+		let db = reopen(None);
+		for i in 0..1000 {
+			let expected = if i < 100 { None } else { Some(i as u64 % 5 + 2) };
+			assert_eq!(db.get_rc(0, &key(i)).unwrap(), expected);
+			assert_eq!(db.get(0, &key(i)).unwrap().is_some(), expected.is_some());
+		}
+		drop(db);
+		let large = 3 * u32::MAX as i64;
+		drop(reopen(Some(large)));
+		assert_eq!(reopen(None).get_rc(0, &key(500)).unwrap(), Some(2 + large as u64));
+		drop(reopen(Some(-large)));
+		assert_eq!(reopen(None).get_rc(0, &key(500)).unwrap(), Some(2));
+	}
+
+	fn test_migrate_column_inner(
+		db_test: EnableCommitPipelineStages,
+		from: &ColumnOptions,
+		to: &ColumnOptions,
+	) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0] = from.clone();
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let value = |i: u32, v: u8| Some(vec![v; 50 + (i as usize % 200)]);
+		db.commit((0..5000u32).map(|i| (0, i.to_be_bytes(), value(i, 1)))).unwrap();
+		db.commit(vec![(1, b"key", Some(vec![1]))]).unwrap();
+		db_test.run_stages(&db);
+
+		assert!(db.migrate_column(0, from.clone()).is_err());
+		if !from.btree_index {
+			assert!(db
+				.migrate_column(0, ColumnOptions { btree_index: true, ..Default::default() })
+				.is_err());
+		}
+		db.migrate_column(0, to.clone()).unwrap();
+		// Applied to both columns while migrating.
+		db.commit((0..100u32).map(|i| (0, i.to_be_bytes(), value(i, 2)))).unwrap();
+		db.commit((100..200u32).map(|i| (0, i.to_be_bytes(), None))).unwrap();
+		let start = Instant::now();
+		while db.is_migrating(0) {
+			assert!(start.elapsed() < Duration::from_secs(60));
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert!(db.migrate_column(0, to.clone()).is_err());
+
+		let persisted = db_test == EnableCommitPipelineStages::DbFile ||
+			db_test == EnableCommitPipelineStages::Standard;
+		// Commits made after the migration started are lost on close in some stages.
+		let check = |db: &Db, updated: bool| {
+			for i in 0..5000u32 {
+				let expected = match i {
+					0..=99 if updated => value(i, 2),
+					100..=199 if updated => None,
+					0..=199 => continue,
+					_ => value(i, 1),
+				};
+				assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), expected);
+			}
+			assert_eq!(db.get(1, b"key").unwrap(), Some(vec![1]));
+		};
+		check(&db, true);
+		db.commit(vec![(0, b"new", Some(vec![3]))]).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		assert!(Db::open_inner(&options, OpeningMode::Write).is_err());
+		options.columns[0] = to.clone();
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		check(&db, persisted);
+		if persisted {
+			assert_eq!(db.get(0, b"new").unwrap(), Some(vec![3]));
+		}
+		drop(db);
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let name = entry.unwrap().file_name().into_string().unwrap();
+			assert!(!name.ends_with(crate::column::DROPPED_FILE_SUFFIX));
+			assert!(!name.starts_with(crate::migration::LIVE_MIGRATION_DIR_PREFIX));
+		}
+	}
+
+	#[test]
+	fn test_migration_progress() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit((0..5000u32).map(|i| (0, i.to_be_bytes(), Some(vec![1; 20]))))
+			.unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.column_stats(0).unwrap().migration, None);
+
+		let to = ColumnOptions { compression: CompressionType::Lz4, ..Default::default() };
+		db.inner.start_migration(0, to, false).unwrap();
+		assert!(db.inner.migrate_step(false).unwrap());
+		let progress = db.column_stats(0).unwrap().migration.unwrap();
+		assert!(progress.copied_keys > 0 && progress.copied_keys < 5000);
+		assert!(progress.scanned_chunks > 0 && progress.scanned_chunks < progress.total_chunks);
+		assert!(!progress.copy_done);
+		while db.inner.migrate_step(false).unwrap() {}
+		assert_eq!(db.column_stats(0).unwrap().migration, None);
+	}
+
+	#[test]
+	fn test_migrate_column_rollback() {
+		let tmp = tempdir().unwrap();
+		let options = EnableCommitPipelineStages::DbFile.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, b"key", Some(vec![1]))]).unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		drop(db);
+
+		// Interrupted after the original tables were detached.
+		let migration_path = crate::migration::live_migration_path(tmp.path(), 0);
+		std::fs::create_dir(&migration_path).unwrap();
+		let new_options = ColumnOptions { compression: CompressionType::Lz4, ..Default::default() };
+		std::fs::write(
+			migration_path.join(crate::migration::LIVE_MIGRATION_MARKER),
+			new_options.as_string(),
+		)
+		.unwrap();
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			let name = path.file_name().unwrap().to_str().unwrap().to_string();
+			if crate::table::TableId::is_file_name(0, &name) ||
+				crate::index::TableId::is_file_name(0, &name)
+			{
+				let dropped = format!("{}{}", name, crate::column::DROPPED_FILE_SUFFIX);
+				std::fs::rename(&path, tmp.path().join(dropped)).unwrap();
+				std::fs::write(&path, b"partial").unwrap();
+			}
+		}
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(vec![1]));
+		assert!(!migration_path.exists());
+	}
+
+	#[test]
+	fn test_drop_column() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_drop_column_inner(stage, false);
+			test_drop_column_inner(stage, true);
+		}
+	}
+
+	fn test_drop_column_inner(db_test: EnableCommitPipelineStages, btree_index: bool) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].btree_index = btree_index;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit((0..100u32).flat_map(|i| {
+			[(0, i.to_be_bytes(), Some(vec![i as u8; 100])), (1, i.to_be_bytes(), Some(vec![1]))]
+		}))
+		.unwrap();
+		db_test.run_stages(&db);
+
+		let snapshot = db.snapshot();
+		assert!(db.drop_column(0).is_err());
+		drop(snapshot);
+
+		db.drop_column(0).unwrap();
+		assert!(db.drop_column(0).is_err());
+		assert!(db.commit(vec![(0, b"new", Some(vec![2]))]).is_err());
+		assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &5u32.to_be_bytes()).unwrap(), Some(vec![1]));
+		// Metadata updates keep the tombstone.
+		assert_eq!(db.add_column_online(ColumnOptions::default()).unwrap(), 2);
+		drop(db);
+
+		// Reopening with the old options reuses the id for an empty column.
+		options.columns.push(ColumnOptions::default());
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), None);
+		assert_eq!(db.get(1, &99u32.to_be_bytes()).unwrap(), Some(vec![1]));
+		db.commit(vec![(0, b"new", Some(vec![2]))]).unwrap();
+		assert_eq!(db.get(0, b"new").unwrap(), Some(vec![2]));
+		drop(db);
+
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let name = entry.unwrap().file_name().into_string().unwrap();
+			assert!(!name.ends_with(crate::column::DROPPED_FILE_SUFFIX));
+		}
+		// The tombstone is removed once the id is reused.
+		options.columns[0].btree_index = !btree_index;
+		assert!(Db::open_inner(&options, OpeningMode::Write).is_err());
+	}
+
+	#[test]
+	fn test_backup() {
+		test_backup_inner(EnableCommitPipelineStages::LogOverlay, false);
+		test_backup_inner(EnableCommitPipelineStages::DbFile, false);
+		test_backup_inner(EnableCommitPipelineStages::DbFile, true);
+		test_backup_inner(EnableCommitPipelineStages::Standard, false);
+		test_backup_inner(EnableCommitPipelineStages::Standard, true);
+	}
+
+	fn test_backup_inner(db_test: EnableCommitPipelineStages, btree_index: bool) {
+		let tmp = tempdir().unwrap();
+		let backup = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = btree_index;
+		let db = Arc::new(Db::open_inner(&options, OpeningMode::Create).unwrap());
+
+		db.commit((0..1000u32).flat_map(|i| {
+			[(0, i.to_be_bytes(), Some(vec![i as u8; 100])), (1, i.to_be_bytes(), Some(vec![1]))]
+		}))
+		.unwrap();
+		db_test.run_stages(&db);
+		if db_test == EnableCommitPipelineStages::Standard {
+			drop(db.inner.lock_idle(true).unwrap());
+		}
+
+		let writer = {
+			let db = db.clone();
+			thread::spawn(move || {
+				for i in 1000..1100u32 {
+					db.commit(vec![(1, i.to_be_bytes(), Some(vec![2]))]).unwrap();
+				}
+			})
+		};
+		let seq = db.backup_to(backup.path()).unwrap();
+		writer.join().unwrap();
+		assert!(db.backup_to(backup.path()).is_err());
+
+		db.commit((0..1000u32).step_by(2).map(|i| (0, i.to_be_bytes(), None))).unwrap();
+		db.commit((0..1000u32).step_by(3).map(|i| (1, i.to_be_bytes(), Some(vec![3; 200]))))
+			.unwrap();
+		db_test.run_stages(&db);
+		if db_test == EnableCommitPipelineStages::Standard {
+			drop(db.inner.lock_idle(true).unwrap());
+		}
+		assert!(db.backup_incremental(backup.path()).unwrap() >= seq);
+		drop(db);
+
+		let mut backup_options = options.clone();
+		backup_options.path = backup.path().to_path_buf();
+		let db = Db::open_inner(&backup_options, OpeningMode::Write).unwrap();
+		for i in 0..1000u32 {
+			let value0 = if i % 2 == 0 { None } else { Some(vec![i as u8; 100]) };
+			let value1 = if i % 3 == 0 { vec![3; 200] } else { vec![1] };
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), value0);
+			assert_eq!(db.get(1, &i.to_be_bytes()).unwrap(), Some(value1));
+		}
+		for i in 1000..1100u32 {
+			assert_eq!(db.get(1, &i.to_be_bytes()).unwrap(), Some(vec![2]));
+		}
+		db.commit(vec![(0, b"new", Some(vec![2]))]).unwrap();
+		assert_eq!(db.get(0, b"new").unwrap(), Some(vec![2]));
+		drop(db);
+		// The opened backup is no longer a base for incremental backups.
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert!(db.backup_incremental(backup.path()).is_err());
+	}
+
+	#[test]
+	fn test_backup_after_reopen() {
+		let tmp = tempdir().unwrap();
+		let backup = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		for i in 0..200u32 {
+			db.commit(vec![(0, i.to_be_bytes(), Some(vec![1; 20_000]))]).unwrap();
+		}
+		db_test.run_stages(&db);
+		let seq = db.backup_to(backup.path()).unwrap();
+		drop(db);
+
+		// Record ids start over, the pages written now are marked with ids below `seq`.
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		db.commit((0..200u32).map(|i| (0, i.to_be_bytes(), Some(vec![2; 20_000]))))
+			.unwrap();
+		db_test.run_stages(&db);
+		assert!(db.inner.last_enacted.load(std::sync::atomic::Ordering::SeqCst) < seq);
+		db.backup_incremental(backup.path()).unwrap();
+		drop(db);
+
+		let mut backup_options = options.clone();
+		backup_options.path = backup.path().to_path_buf();
+		let db = Db::open_inner(&backup_options, OpeningMode::Write).unwrap();
+		for i in 0..200u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(vec![2; 20_000]));
+		}
+	}
+
+	#[test]
+	fn test_checkpoint() {
+		test_checkpoint_inner(EnableCommitPipelineStages::LogOverlay);
+		test_checkpoint_inner(EnableCommitPipelineStages::DbFile);
+		test_checkpoint_inner(EnableCommitPipelineStages::Standard);
+	}
+
+	fn test_checkpoint_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let checkpoint = tmp.path().join("checkpoint");
+		let options = db_test.options(&tmp.path().join("db"), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit((0..100u32).map(|i| (0, i.to_be_bytes(), Some(vec![1])))).unwrap();
+		db_test.run_stages(&db);
+		if db_test == EnableCommitPipelineStages::Standard {
+			drop(db.inner.lock_idle(true).unwrap());
+		}
+		db.checkpoint(&checkpoint).unwrap();
+		assert!(db.checkpoint(&checkpoint).is_err());
+		assert!(db.backup_incremental(&checkpoint).is_err());
+
+		db.commit((0..100u32).map(|i| (0, i.to_be_bytes(), Some(vec![2])))).unwrap();
+		db_test.run_stages(&db);
+		drop(db);
+
+		let mut checkpoint_options = options.clone();
+		checkpoint_options.path = checkpoint;
+		let db = Db::open_read_only(&checkpoint_options).unwrap();
+		for i in 0..100u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(vec![1]));
+		}
+	}
+
+	#[test]
+	fn test_named_snapshots() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(
+			(0..10u8).flat_map(|i| [(0, vec![i], Some(vec![1])), (1, vec![i], Some(vec![1]))]),
+		)
+		.unwrap();
+		assert!(db.create_named_snapshot("../x").is_err());
+		assert!(db.create_named_snapshot("").is_err());
+		db.create_named_snapshot("first").unwrap();
+		assert!(db.create_named_snapshot("first").is_err());
+		db.commit((0..10u8).flat_map(|i| [(0, vec![i], Some(vec![2])), (1, vec![i], None)]))
+			.unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.named_snapshots().unwrap(), vec!["first".to_string()]);
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![2]));
+		let snapshot = db.open_named_snapshot("first").unwrap();
+		for i in 0..10u8 {
+			assert_eq!(snapshot.get(0, &[i]).unwrap(), Some(vec![1]));
+		}
+		let mut iter = snapshot.iter(1).unwrap();
+		iter.seek_to_first().unwrap();
+		let mut count = 0;
+		while let Some((key, value)) = iter.next().unwrap() {
+			assert_eq!((key, value), (vec![count], vec![1]));
+			count += 1;
+		}
+		assert_eq!(count, 10);
+		drop(iter);
+		drop(snapshot);
+
+		db.release_named_snapshot("first").unwrap();
+		assert!(db.named_snapshots().unwrap().is_empty());
+		assert!(db.open_named_snapshot("first").is_err());
+		assert!(db.release_named_snapshot("first").is_err());
+	}
+
+	#[test]
+	fn test_log_archive() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(&tmp.path().join("db"), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		assert!(db.archived_logs().is_err());
+		drop(db);
+
+		options.log_archive = Some(tmp.path().join("archive"));
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in 0..2u8 {
+			db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+			db_test.run_stages(&db);
+		}
+		let archived = db.archived_logs().unwrap();
+		assert_eq!(archived.iter().map(|log| log.index).collect::<Vec<_>>(), vec![0, 1]);
+		assert!(archived.iter().all(|log| log.path.metadata().unwrap().len() > 0));
+		drop(db);
+
+		// Numbering continues after reopening.
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		db.commit(vec![(0, vec![2], Some(vec![2]))]).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.archived_logs().unwrap().last().unwrap().index, 2);
+		assert_eq!(db.prune_archived_logs(2).unwrap(), 2);
+		assert_eq!(db.prune_archived_logs(2).unwrap(), 0);
+		assert_eq!(db.archived_logs().unwrap().len(), 1);
+		assert_eq!(db.get(0, &[1]).unwrap(), Some(vec![1]));
+	}
+
+	#[test]
+	fn test_read_only() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::LogOverlay;
+		let options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, b"key", Some(vec![1]))]).unwrap();
+		db_test.run_stages(&db);
+		db.inner.log.flush_one(0).unwrap();
+
+		let files = || {
+			let mut files: Vec<_> = std::fs::read_dir(tmp.path())
+				.unwrap()
+				.map(|entry| {
+					let entry = entry.unwrap();
+					(entry.file_name(), entry.metadata().unwrap().len())
+				})
+				.collect();
+			files.sort();
+			files
+		};
+		let before = files();
+		// The writer holds the lock.
+		let read_only = Db::open_read_only(&options).unwrap();
+		assert_eq!(read_only.get(0, b"key").unwrap(), Some(vec![1]));
+		assert!(read_only.commit(vec![(0, b"key", Some(vec![2]))]).is_err());
+		assert!(read_only.clear_stats(None).is_err());
+		assert!(read_only.catch_up().is_err());
+		drop(read_only);
+		assert_eq!(files(), before);
+	}
+
+	#[test]
+	fn test_force_unlock() {
+		use fs2::FileExt;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		match Db::open(&options) {
+			Err(e @ crate::Error::Locked { .. }) => {
+				assert!(e.is_retryable() && !e.is_corruption());
+				let crate::Error::Locked { holder: Some(h), .. } = e else { panic!() };
+				assert_eq!(h.pid, std::process::id());
+			},
+			r => panic!("Unexpected result {:?}", r.map(|_| ())),
+		}
+		// The holder is still running.
+		assert!(matches!(Db::force_unlock(tmp.path()), Err(crate::Error::Locked { .. })));
+		drop(db);
+		assert_eq!(Db::force_unlock(tmp.path()).unwrap(), None);
+
+		// Lock left by a process on another host.
+		let lock = std::fs::File::create(tmp.path().join("lock")).unwrap();
+		lock.try_lock_exclusive().unwrap();
+		std::fs::write(tmp.path().join("lock"), "1234\nother-host\n").unwrap();
+		assert!(Db::open(&options).is_err());
+		let holder = crate::LockHolder { pid: 1234, hostname: "other-host".into() };
+		assert_eq!(Db::force_unlock(tmp.path()).unwrap(), Some(holder));
+		Db::open(&options).unwrap();
+		drop(lock);
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_metrics() {
+		use crate::metrics::{Metrics, PrometheusMetrics};
+
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let sink = Arc::new(PrometheusMetrics::new());
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.stats = true;
+		options.metrics = Some(Metrics(sink.clone()));
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, b"a", Some(vec![1; 100])), (1, b"a", Some(vec![1]))])
+			.unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1; 100]));
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1; 100]));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		db.report_metrics();
+
+		let text = sink.render();
+		assert!(text.contains("parity_db_commit_seconds_count 1\n"));
+		assert!(text.contains("parity_db_commit_queue_bytes 0\n"));
+		assert!(text.contains("parity_db_log_queue_bytes 0\n"));
+		assert!(text.contains("parity_db_commit_overlay_hits_total{column=\"0\"} 1\n"));
+		assert!(text.contains("parity_db_commit_overlay_misses_total{column=\"0\"} 2\n"));
+		assert!(text.contains("parity_db_value_table_entries{column=\"1\"}"));
+		assert!(text.contains("parity_db_query_hit_ratio{column=\"0\"} 0.5\n"));
+		assert!(text.contains("# TYPE parity_db_compression_ratio gauge\n"));
+	}
+
+	#[test]
+	fn test_column_stats() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		options.stats = true;
+		options.columns[0].compression = CompressionType::Lz4;
+		options.columns[1].btree_index = true;
+		options.compression_threshold.insert(0, 32);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![
+			(0, b"a", Some(vec![0; 100])),
+			(0, b"b", Some(vec![0; 100])),
+			(0, b"c", Some(vec![1; 10])),
+			(1, b"a", Some(vec![1; 10])),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		db.commit(vec![(0, b"c", None)]).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![0; 100]));
+		assert_eq!(db.get(0, b"d").unwrap(), None);
+
+		let stats = db.column_stats(0).unwrap();
+		assert_eq!(stats.total_values, 2);
+		assert_eq!(stats.uncompressed_bytes, 200);
+		assert!(stats.total_bytes < 200);
+		assert_eq!(stats.value_histogram, vec![(96, 2)]);
+		assert_eq!(stats.compression_savings, vec![(96, 200 - stats.total_bytes as i64)]);
+		assert_eq!((stats.inserted_new, stats.removed_hit), (3, 1));
+		assert_eq!((stats.query_hits.iter().sum::<u64>(), stats.query_misses), (1, 1));
+		// The removed entry stays filled until reused.
+		assert_eq!(stats.tables.iter().map(|t| t.filled).sum::<u64>(), 3);
+		assert!(stats.tables.iter().all(|t| t.filled <= t.capacity));
+		assert_eq!((stats.compressed_values, stats.below_threshold_values), (2, 0));
+
+		// Values no larger than the threshold are stored uncompressed.
+		let mut random = vec![0u8; 64];
+		rand::thread_rng().fill(&mut random[..]);
+		db.commit(vec![(0, b"d", Some(vec![2; 10])), (0, b"e", Some(random))]).unwrap();
+		db_test.run_stages(&db);
+		let stats = db.column_stats(0).unwrap();
+		assert_eq!(stats.total_values, 4);
+		assert_eq!((stats.compressed_values, stats.below_threshold_values), (2, 1));
+
+		let stats = db.column_stats(1).unwrap();
+		assert_eq!(stats.total_values, 0);
+		assert!(stats.tables.iter().map(|t| t.filled).sum::<u64>() > 0);
+		assert!(db.column_stats(2).is_err());
+	}
+
+	#[test]
+	fn test_parallel_compression() {
+		let mut total_bytes = Vec::new();
+		for threads in [0, 4] {
+			let tmp = tempdir().unwrap();
+			let db_test = EnableCommitPipelineStages::DbFile;
+			let mut options = db_test.options(tmp.path(), 1);
+			options.stats = true;
+			options.compression_threads = threads;
+			options.columns[0].compression = CompressionType::Lz4;
+			options.compression_threshold.insert(0, 32);
+			let value = |i: u32| {
+				let mut v = vec![(i % 7) as u8; 200 + (i as usize % 100)];
+				v[..4].copy_from_slice(&i.to_le_bytes());
+				v
+			};
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			// Also the same key set twice and values below the threshold.
+			db.commit(
+				(0..1000u32).map(|i| (0, i.to_be_bytes().to_vec(), Some(value(i)))).chain([(
+					0,
+					0u32.to_be_bytes().to_vec(),
+					Some(vec![1; 10]),
+				)]),
+			)
+			.unwrap();
+			db_test.run_stages(&db);
+			assert_eq!(db.get(0, &0u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+			for i in 1..1000u32 {
+				assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+			}
+			let stats = db.column_stats(0).unwrap();
+			assert_eq!(stats.compressed_values, 999);
+			total_bytes.push(stats.total_bytes);
+		}
+		assert_eq!(total_bytes[0], total_bytes[1]);
+	}
+
+	#[test]
+	fn test_commit_hooks() {
+		test_commit_hooks_inner(EnableCommitPipelineStages::DbFile);
+		test_commit_hooks_inner(EnableCommitPipelineStages::Standard);
+	}
+
+	fn test_commit_hooks_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let (sender, receiver) = mpsc::channel();
+		db.on_commit(move |stage, id| sender.send((stage, id)).unwrap());
+
+		let first = db.commit(vec![(0, b"a", Some(vec![1]))]).unwrap();
+		let changes = vec![(0, Operation::Set(b"b".to_vec(), vec![2]))];
+		let second = if db_test == EnableCommitPipelineStages::Standard {
+			db.commit_changes_durable(changes).unwrap();
+			first + 1
+		} else {
+			db.commit_changes(changes).unwrap()
+		};
+		assert_eq!(second, first + 1);
+		db_test.run_stages(&db);
+
+		let recv = || receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert_eq!(recv(), (CommitStage::Queued, first));
+		assert_eq!(recv(), (CommitStage::Queued, second));
+		let mut logged = 0;
+		loop {
+			match recv() {
+				(CommitStage::Logged, id) => logged = id,
+				(CommitStage::Enacted, id) if id == second => break,
+				(CommitStage::Enacted, id) => assert!(id < second),
+				event => panic!("Unexpected {:?}", event),
+			}
+		}
+		// Commits are enacted after they are logged.
+		assert_eq!(logged, second);
+	}
+
+	#[test]
+	fn test_queue_stats() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::CommitOverlay;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.memory_budget.commit_queue_bytes = 1000;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, vec![1; 32], Some(vec![1; 100]))]).unwrap();
+		db.commit(vec![(0, vec![2; 32], Some(vec![2; 100]))]).unwrap();
+		let stats = db.queue_stats();
+		assert_eq!(stats.commit_queue_bytes, 264);
+		assert_eq!(stats.commit_queue_limit, 1000);
+		assert_eq!(stats.queued_commits, 2);
+		assert_eq!(stats.commits_in_flight, 2);
+		assert_eq!(stats.log_queue_bytes, 0);
+		assert_eq!(stats.throttled_writers, 0);
+		assert!(!stats.log_throttled);
+
+		while db.inner.process_commits().unwrap() {}
+		let stats = db.queue_stats();
+		assert_eq!(stats.commit_queue_bytes, 0);
+		assert_eq!(stats.queued_commits, 0);
+		assert_eq!(stats.commits_in_flight, 2);
+		assert!(stats.log_queue_bytes > 0);
+
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		let stats = db.queue_stats();
+		assert_eq!(stats.commits_in_flight, 0);
+		assert_eq!(stats.log_queue_bytes, 0);
+	}
+
+	#[test]
+	fn test_check() {
+		use super::check::{CheckIssue, CheckOptions};
+		use std::os::unix::fs::FileExt;
+
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[1].preimage = true;
+		options.columns[2].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit((0..3).map(|c| (c, [c; 32], Some(vec![c; 100])))).unwrap();
+		db.commit((0..50u8).map(|i| (2, vec![i], Some(vec![i])))).unwrap();
+		db_test.run_stages(&db);
+
+		let report = db.check(CheckOptions::new(None, None, None, false, None)).unwrap();
+		assert!(report.is_ok());
+		let entries: Vec<_> = report.columns.iter().map(|c| c.entries).collect();
+		assert_eq!(entries, vec![1, 1, 51]);
+
+		// Corrupt the only value of columns 0 and 1.
+		let corrupt = |col: ColId, offset: u64, bytes: &[u8]| {
+			let name = format!("table_{:02}_", col);
+			let path = std::fs::read_dir(tmp.path())
+				.unwrap()
+				.map(|e| e.unwrap().path())
+				.find(|p| p.file_name().unwrap().to_str().unwrap().starts_with(&name))
+				.unwrap();
+			let tier =
+				u8::from_str_radix(&path.to_str().unwrap()[path.to_str().unwrap().len() - 2..], 16)
+					.unwrap();
+			let entry_size = crate::column::Column::value_entry_size(tier) as u64;
+			let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+			file.write_all_at(bytes, entry_size + offset).unwrap();
+		};
+		corrupt(0, 0, &[0xff, 0xff]);
+		corrupt(1, 2, &[0, 0, 0, 0]);
+		let report = db.check(CheckOptions::new(None, None, None, false, None)).unwrap();
+		assert!(!report.is_ok());
+		assert!(matches!(report.columns[0].issues[..], [CheckIssue::BadIndexEntry { .. }]));
+		assert!(matches!(report.columns[1].issues[..], [CheckIssue::BadIndexEntry { .. }]));
+		assert!(report.columns[2].issues.is_empty());
+		let report = db.check(CheckOptions::new(Some(2), None, None, false, None)).unwrap();
+		assert_eq!(report.columns.len(), 1);
+		assert!(report.is_ok());
+	}
+
+	#[test]
+	fn test_scrub() {
+		use super::check::CheckIssue;
+		use crate::ScrubOptions;
+		use std::os::unix::fs::FileExt;
+
+		let tmp = tempdir().unwrap();
+		let mut options = EnableCommitPipelineStages::Standard.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			db.commit((0..2).map(|c| (c, [c; 32], Some(vec![c; 100])))).unwrap();
+		}
+		let path = std::fs::read_dir(tmp.path())
+			.unwrap()
+			.map(|e| e.unwrap().path())
+			.find(|p| p.file_name().unwrap().to_str().unwrap().starts_with("table_00_"))
+			.unwrap();
+		let name = path.to_str().unwrap();
+		let tier = u8::from_str_radix(&name[name.len() - 2..], 16).unwrap();
+		let entry_size = crate::column::Column::value_entry_size(tier) as u64;
+		let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+		file.write_all_at(&[0xff, 0xff], entry_size).unwrap();
+
+		let (sender, receiver) = mpsc::channel();
+		let mut scrub = ScrubOptions::new(move |col, issue| {
+			let _ = sender.send((col, issue.clone()));
+		});
+		scrub.bytes_per_second = 1 << 30;
+		scrub.interval = Duration::from_millis(10);
+		options.scrub = Some(scrub);
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		let (col, issue) = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert_eq!(col, 0);
+		assert!(matches!(issue, CheckIssue::BadIndexEntry { .. }));
+		// Reported again on the next pass.
+		assert_eq!(receiver.recv_timeout(Duration::from_secs(10)).unwrap().0, 0);
+		drop(db);
+	}
+
+	#[test]
+	fn test_value_checksums() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		for c in options.columns.iter_mut() {
+			c.value_checksums = true;
+			c.compression = CompressionType::Lz4;
+		}
+		options.columns[1].btree_index = true;
+		let values: Vec<_> = [0, 1, 100, 5000, 70000]
+			.iter()
+			.map(|len| vec![(*len % 251) as u8; *len])
+			.collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(
+				values
+					.iter()
+					.enumerate()
+					.flat_map(|(i, v)| (0..2).map(move |c| (c, vec![i as u8], Some(v.clone())))),
+			)
+			.unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		for (i, v) in values.iter().enumerate() {
+			for c in 0..2 {
+				assert_eq!(db.get(c, &[i as u8]).unwrap().as_ref(), Some(v));
+				assert_eq!(db.get_size(c, &[i as u8]).unwrap(), Some(v.len() as u32));
+			}
+		}
+	}
+
+	#[test]
+	fn test_direct_io() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.direct_io = true;
+		options.columns[1].btree_index = true;
+		let values: Vec<_> = [0, 1, 33, 100, 4000, 5000, 70000]
+			.iter()
+			.map(|len| vec![*len as u8; *len])
+			.collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(
+				values
+					.iter()
+					.enumerate()
+					.flat_map(|(i, v)| (0..2).map(move |c| (c, vec![i as u8], Some(v.clone())))),
+			)
+			.unwrap();
+			db.commit((0..2).map(|c| (c, vec![1u8], None))).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		for (i, v) in values.iter().enumerate() {
+			for c in 0..2 {
+				let expected = if i == 1 { None } else { Some(v) };
+				assert_eq!(db.get(c, &[i as u8]).unwrap().as_ref(), expected);
+			}
+		}
+	}
+
+	#[test]
+	fn test_mmap_read_mode() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		for c in options.columns.iter_mut() {
+			c.read_mode = crate::ReadMode::Mmap;
+		}
+		options.columns[1].btree_index = true;
+		let value = |i: u32| vec![i as u8; (i as usize * 7) % 5000];
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		for batch in 0..4u32 {
+			// Each batch grows the value tables past the mapped length.
+			db.commit(
+				(batch * 500..(batch + 1) * 500).flat_map(|i| {
+					(0..2).map(move |c| (c, i.to_be_bytes().to_vec(), Some(value(i))))
+				}),
+			)
+			.unwrap();
+			db_test.run_stages(&db);
+			for i in (0..(batch + 1) * 500).step_by(37) {
+				for c in 0..2 {
+					assert_eq!(db.get(c, &i.to_be_bytes()).unwrap(), Some(value(i)));
+				}
+			}
+		}
+		drop(db);
+
+		// The read mode is not part of the metadata.
+		options.columns[0].read_mode = crate::ReadMode::Pread;
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in (0..2000u32).step_by(13) {
+			for c in 0..2 {
+				assert_eq!(db.get(c, &i.to_be_bytes()).unwrap(), Some(value(i)));
+			}
+		}
+	}
+
+	#[test]
+	fn test_access_hints() {
+		use crate::AccessHint;
+		for hint in [AccessHint::Random, AccessHint::Sequential, AccessHint::DontNeed] {
+			let tmp = tempdir().unwrap();
+			let db_test = EnableCommitPipelineStages::DbFile;
+			let mut options = db_test.options(tmp.path(), 1);
+			options.columns[0].uniform = true;
+			options.columns[0].access_hint = hint;
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			// Keys with the same prefix fill a single index chunk and trigger a reindex.
+			let key = |i: u8| {
+				let mut key = [0u8; 32];
+				key[2] = i;
+				key
+			};
+			db.commit((0..100).map(|i| (0, key(i), Some(vec![i; 100])))).unwrap();
+			db_test.run_stages(&db);
+			assert!(!tmp.path().join("index_00_16").exists());
+			assert!(tmp.path().join("index_00_17").exists());
+			for i in 0..100 {
+				assert_eq!(db.get(0, &key(i)).unwrap(), Some(vec![i; 100]));
+			}
+		}
+	}
+
+	#[test]
+	fn test_value_cache() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = stage.options(tmp.path(), 1);
+			options.columns[0].value_cache_bytes = 1024;
+			options.columns[0].compression = CompressionType::Lz4;
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			let keys: Vec<[u8; 1]> = (0..10u8).map(|i| [i]).collect();
+			db.commit(keys.iter().map(|k| (0, k.to_vec(), Some(vec![k[0]; 100])))).unwrap();
+			stage.run_stages(&db);
+			for k in &keys {
+				assert_eq!(db.get(0, k).unwrap(), Some(vec![k[0]; 100]));
+			}
+			// Cached values are replaced and removed by commits.
+			db.commit(vec![(0, vec![1], Some(vec![2])), (0, vec![2], None)]).unwrap();
+			assert_eq!(db.get(0, &[1]).unwrap(), Some(vec![2]));
+			assert_eq!(db.get(0, &[2]).unwrap(), None);
+			stage.run_stages(&db);
+			assert_eq!(
+				db.get_many(0, &keys[..4]).unwrap(),
+				vec![Some(vec![0; 100]), Some(vec![2]), None, Some(vec![3; 100])]
+			);
+			db.commit(vec![(0, vec![3], Some(vec![4]))]).unwrap();
+			stage.run_stages(&db);
+			assert_eq!(db.get_many(0, &keys[3..4]).unwrap(), vec![Some(vec![4])]);
+			db.clear_column(0).unwrap();
+			assert_eq!(db.get(0, &[0]).unwrap(), None);
+		}
+	}
+
+	#[test]
+	fn test_index_cache() {
+		for stage in [
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = stage.options(tmp.path(), 1);
+			options.columns[0].uniform = true;
+			// Room for a few chunks only.
+			options.columns[0].index_cache_bytes = 4096;
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			let key = |i: u8| {
+				let mut key = [0u8; 32];
+				key[2] = i;
+				key[3] = i;
+				key
+			};
+			db.commit((0..50).map(|i| (0, key(i), Some(vec![i; 10])))).unwrap();
+			stage.run_stages(&db);
+			for i in 0..50 {
+				assert_eq!(db.get(0, &key(i)).unwrap(), Some(vec![i; 10]));
+			}
+			// Cached chunks are updated by later commits and the reindex these trigger.
+			db.commit((0..10).map(|i| (0, key(i), None))).unwrap();
+			stage.run_stages(&db);
+			db.commit((50..100).map(|i| (0, key(i), Some(vec![i; 10])))).unwrap();
+			stage.run_stages(&db);
+			for i in 0..100 {
+				let expected = if i < 10 { None } else { Some(vec![i; 10]) };
+				assert_eq!(db.get(0, &key(i)).unwrap(), expected);
+			}
+			assert_eq!(db.get_many(0, &[key(5), key(60)]).unwrap(), vec![None, Some(vec![60; 10])]);
+		}
+	}
+
+	#[test]
+	fn test_secondary_index() {
+		use crate::SecondaryIndex;
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = stage.options(tmp.path(), 2);
+			options.columns[1].btree_index = true;
+			// Index values by each of their bytes.
+			options.columns[0].secondary_indexes =
+				vec![SecondaryIndex::new(1, |value| value.iter().map(|b| vec![*b]).collect())];
+			options.columns[0].merge = Some(crate::MergeOperator::new(|existing, operand| {
+				[existing.unwrap_or_default(), operand].concat()
+			}));
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			let lookup = |db: &Db, key: u8| {
+				let mut iter = db.iter_secondary(0, 0, &[key]).unwrap();
+				let mut keys = Vec::new();
+				while let Some(key) = iter.next().unwrap() {
+					keys.push(key);
+				}
+				keys
+			};
+			db.commit(vec![
+				(0, b"a".to_vec(), Some(vec![1, 2])),
+				(0, b"b".to_vec(), Some(vec![2])),
+				(0, b"c".to_vec(), Some(vec![3])),
+			])
+			.unwrap();
+			stage.run_stages(&db);
+			assert_eq!(lookup(&db, 1), vec![b"a".to_vec()]);
+			assert_eq!(lookup(&db, 2), vec![b"a".to_vec(), b"b".to_vec()]);
+			assert_eq!(lookup(&db, 4), Vec::<Vec<u8>>::new());
+
+			// Replaced and removed values update the index.
+			db.commit_changes(vec![
+				(0, Operation::Set(b"a".to_vec(), vec![3])),
+				(0, Operation::Dereference(b"b".to_vec())),
+				(0, Operation::Merge(b"c".to_vec(), vec![4])),
+			])
+			.unwrap();
+			stage.run_stages(&db);
+			assert_eq!(lookup(&db, 1), Vec::<Vec<u8>>::new());
+			assert_eq!(lookup(&db, 2), Vec::<Vec<u8>>::new());
+			assert_eq!(lookup(&db, 3), vec![b"a".to_vec(), b"c".to_vec()]);
+			assert_eq!(lookup(&db, 4), vec![b"c".to_vec()]);
+			assert!(db.iter_secondary(0, 1, &[3]).is_err());
+
+			db.clear_column(0).unwrap();
+			assert_eq!(lookup(&db, 3), Vec::<Vec<u8>>::new());
+		}
+
+		let mut options = Options::with_columns(Path::new(""), 2);
+		options.columns[0].secondary_indexes = vec![SecondaryIndex::new(1, |_| Vec::new())];
+		assert!(!options.is_valid());
+		options.columns[1].btree_index = true;
+		assert!(options.is_valid());
+		options.columns[0].ttl = true;
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_key_hash() {
+		use crate::KeyHash;
+		for key_hash in [KeyHash::Blake2b, KeyHash::SipHash24, KeyHash::Identity] {
+			let tmp = tempdir().unwrap();
+			let db_test = EnableCommitPipelineStages::DbFile;
+			let mut options = db_test.options(tmp.path(), 1);
+			options.columns[0].key_hash = key_hash;
+			let keys: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 1 + i as usize]).collect();
+			{
+				let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+				db.commit(keys.iter().map(|k| (0, k.clone(), Some(k.clone())))).unwrap();
+				db_test.run_stages(&db);
+			}
+			let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+			for k in &keys {
+				assert_eq!(db.get(0, k).unwrap(), Some(k.clone()));
+			}
+			assert_eq!(db.get(0, b"missing").unwrap(), None);
+			drop(db);
+
+			// The hash is recorded in the metadata.
+			options.columns[0].key_hash = match key_hash {
+				KeyHash::Blake2b => KeyHash::Identity,
+				_ => KeyHash::Blake2b,
+			};
+			assert!(matches!(
+				Db::open_inner(&options, OpeningMode::Write),
+				Err(crate::Error::IncompatibleColumnConfig { .. })
+			));
+		}
+		let mut options =
+			ColumnOptions { key_hash: KeyHash::SipHash24, ..ColumnOptions::default() };
+		assert!(options.is_valid());
+		options.uniform = true;
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_compression_dictionary() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		let value = |i: u32| {
+			let mut v = vec![7u8; 100];
+			v[50..54].copy_from_slice(&i.to_le_bytes());
+			v
+		};
+		let samples: Vec<_> = (0..10).map(value).collect();
+		let dict: Arc<[u8]> = crate::train_dictionary(&samples, 256).into();
+		for c in options.columns.iter_mut() {
+			c.compression = CompressionType::Lz4;
+			c.compression_dictionary = Some(dict.clone());
+		}
+		options.columns[1].btree_index = true;
+		let keys: Vec<[u8; 4]> = (0..100u32).map(|i| i.to_be_bytes()).collect();
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			db.commit(
+				keys.iter()
+					.enumerate()
+					.flat_map(|(i, k)| [0, 1].map(|c| (c, k.to_vec(), Some(value(i as u32))))),
+			)
+			.unwrap();
+			db_test.run_stages(&db);
+		}
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for (i, k) in keys.iter().enumerate() {
+			assert_eq!(db.get(0, k).unwrap(), Some(value(i as u32)));
+			assert_eq!(db.get(1, k).unwrap(), Some(value(i as u32)));
+		}
+		drop(db);
+
+		// The dictionary is recorded in the metadata.
+		options.columns[0].compression_dictionary = Some(vec![1, 2, 3].into());
+		assert!(matches!(
+			Db::open_inner(&options, OpeningMode::Write),
+			Err(crate::Error::IncompatibleColumnConfig { .. })
+		));
+		let options =
+			ColumnOptions { compression_dictionary: Some(dict), ..ColumnOptions::default() };
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_compression_level_metadata() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].compression = CompressionType::Lz4Hc { level: 4 };
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![(0, b"key".to_vec(), Some(vec![3u8; 1000]))]).unwrap();
+		}
+		let meta = Options::load_metadata(tmp.path()).unwrap().unwrap();
+		assert_eq!(meta.columns[0].compression, CompressionType::Lz4Hc { level: 4 });
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(vec![3u8; 1000]));
+		drop(db);
+
+		// The level is part of the column config.
+		options.columns[0].compression = CompressionType::Lz4Hc { level: 5 };
+		assert!(matches!(Db::open(&options), Err(crate::Error::IncompatibleColumnConfig { .. })));
+		// Metadata written without a level uses the default one.
+		assert_eq!(
+			CompressionType::from_id(3, None),
+			Some(CompressionType::Lz4Hc { level: crate::compress::LZ4HC_DEFAULT_LEVEL })
+		);
+		assert_eq!(CompressionType::from_id(5, None), None);
+	}
+
+	#[cfg(feature = "zstd")]
+	#[test]
+	fn test_zstd_compression() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		let value =
+			|i: u32| format!("{{\"kind\":\"node\",\"index\":{},\"children\":[]}}", i).into_bytes();
+		let samples: Vec<_> = (0..1000).map(value).collect();
+		let dict: Arc<[u8]> = crate::train_zstd_dictionary(&samples, 1024).unwrap().into();
+		options.columns[0].compression = CompressionType::Zstd { level: 19 };
+		options.columns[1].compression = CompressionType::Zstd { level: 1 };
+		options.columns[1].compression_dictionary = Some(dict);
+		options.columns[1].btree_index = true;
+		let keys: Vec<[u8; 4]> = (0..100u32).map(|i| i.to_be_bytes()).collect();
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			db.commit(keys.iter().enumerate().flat_map(|(i, k)| {
+				[(0, k.to_vec(), Some(vec![i as u8; 5000])), (1, k.to_vec(), Some(value(i as u32)))]
+			}))
+			.unwrap();
+			db_test.run_stages(&db);
+		}
+		let meta = Options::load_metadata(tmp.path()).unwrap().unwrap();
+		assert_eq!(meta.columns[0].compression, CompressionType::Zstd { level: 19 });
+		assert_eq!(meta.columns[1], options.columns[1]);
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for (i, k) in keys.iter().enumerate() {
+			assert_eq!(db.get(0, k).unwrap(), Some(vec![i as u8; 5000]));
+			assert_eq!(db.get(1, k).unwrap(), Some(value(i as u32)));
+		}
+		drop(db);
+
+		// The level is part of the column config.
+		options.columns[0].compression = CompressionType::Zstd { level: 3 };
+		assert!(matches!(
+			Db::open_inner(&options, OpeningMode::Write),
+			Err(crate::Error::IncompatibleColumnConfig { .. })
+		));
+		assert!(!ColumnOptions {
+			compression: CompressionType::Zstd { level: crate::ZSTD_MAX_LEVEL + 1 },
+			..Default::default()
+		}
+		.is_valid());
+	}
+
+	#[cfg(feature = "encryption")]
+	#[test]
+	fn test_encryption() {
+		use crate::{Encryption, StaticKey};
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		for c in options.columns.iter_mut() {
+			c.encryption = Some(Encryption::new(StaticKey([7; 32])));
+		}
+		let marker = b"plaintext marker";
+		let value = |i: u8| [&marker[..], &vec![i; i as usize * 20]].concat();
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			db.commit((0..100u8).flat_map(|i| {
+				[(0, vec![i], Some(value(i))), (1, [&marker[..], &[i]].concat(), Some(value(i)))]
+			}))
+			.unwrap();
+			db_test.run_stages(&db);
+			assert_eq!(db.get(0, &[5]).unwrap(), Some(value(5)));
+		}
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let data = std::fs::read(entry.unwrap().path()).unwrap();
+			assert!(!data.windows(marker.len()).any(|w| w == marker));
+		}
+		// Key bytes would be stored in the index unencrypted.
+		let mut column = options.columns[0].clone();
+		column.uniform = true;
+		assert!(!column.is_valid());
+		column.uniform = false;
+		column.key_hash = crate::KeyHash::Identity;
+		assert!(!column.is_valid());
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in 0..100u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(value(i)));
+			assert_eq!(db.get(1, &[&marker[..], &[i]].concat()).unwrap(), Some(value(i)));
+		}
+		drop(db);
+
+		options.columns[0].encryption = Some(Encryption::new(StaticKey([8; 32])));
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert!(matches!(db.get(0, &[1]), Err(crate::Error::Decryption { col: 0, .. })));
+		drop(db);
+		options.columns[0].encryption = None;
+		assert!(matches!(
+			Db::open_inner(&options, OpeningMode::Write),
+			Err(crate::Error::IncompatibleColumnConfig { .. })
+		));
+	}
+
+	#[test]
+	fn test_in_memory() {
+		let mut options = Options::memory(2);
+		options.columns[1].btree_index = true;
+		options.columns[0].compression = CompressionType::Lz4;
+		options.with_background_thread = false;
+		let value = |i: u32| vec![i as u8; (i as usize % 50) * 100];
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			for batch in 0..10u32 {
+				db.commit((batch * 1000..(batch + 1) * 1000).flat_map(|i| {
+					let key = i.to_be_bytes().to_vec();
+					[(0, key.clone(), Some(value(i))), (1, key, Some(value(i)))]
+				}))
+				.unwrap();
+			}
+			db.commit(vec![(0, 5u32.to_be_bytes().to_vec(), None)]).unwrap();
+			EnableCommitPipelineStages::DbFile.run_stages(&db);
+			assert_eq!(db.get(0, &5u32.to_be_bytes()).unwrap(), None);
+			for i in (0..10000u32).filter(|i| *i != 5) {
+				assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+			}
+			let mut iter = db.iter(1).unwrap();
+			iter.seek_to_first().unwrap();
+			let mut count = 0;
+			while let Some((key, v)) = iter.next().unwrap() {
+				assert_eq!(v, value(u32::from_be_bytes(key.try_into().unwrap())));
+				count += 1;
+			}
+			assert_eq!(count, 10000);
+			let backup = tempdir().unwrap();
+			assert!(db.backup_to(backup.path().join("backup")).is_err());
+			assert!(db.migrate_column(0, ColumnOptions::default()).is_err());
+		}
+		assert!(!options.path.exists());
+		assert!(Db::open_read_only(&options).is_err());
+		// Contents are not kept.
+		options.with_background_thread = true;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &1u32.to_be_bytes()).unwrap(), None);
+	}
+
+	// Counts syncs of log files.
+	#[derive(Debug, Default)]
+	struct LogSyncs(crate::MemoryStorage, Arc<std::sync::atomic::AtomicUsize>);
+	struct LogFile(Arc<dyn crate::StorageFile>, Arc<std::sync::atomic::AtomicUsize>);
+
+	impl crate::StorageFile for LogFile {
+		fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+			self.0.read_at(buf, offset)
+		}
+		fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+			self.0.write_at(buf, offset)
+		}
+		fn size(&self) -> std::io::Result<u64> {
+			self.0.size()
+		}
+		fn set_size(&self, size: u64) -> std::io::Result<()> {
+			self.0.set_size(size)
+		}
+		fn sync(&self) -> std::io::Result<()> {
+			self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+			self.0.sync()
+		}
+	}
+
+	impl crate::Storage for LogSyncs {
+		fn open(
+			&self,
+			name: &str,
+			create: bool,
+		) -> std::io::Result<Option<Arc<dyn crate::StorageFile>>> {
+			let file = self.0.open(name, create)?;
+			Ok(match file {
+				Some(file) if name.starts_with("log") =>
+					Some(Arc::new(LogFile(file, self.1.clone()))),
+				file => file,
+			})
+		}
+		fn remove(&self, name: &str) -> std::io::Result<()> {
+			self.0.remove(name)
+		}
+		fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+			self.0.rename(from, to)
+		}
+		fn list(&self) -> std::io::Result<Vec<String>> {
+			self.0.list()
+		}
+	}
+
+	#[test]
+	fn test_commit_with_sync() {
+		use crate::CommitOptions;
+		use std::sync::atomic::Ordering;
+
+		let tmp = tempdir().unwrap();
+		let storage = Arc::new(LogSyncs::default());
+		let syncs = storage.1.clone();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.storage = Some(storage);
+		let set = |i: u8| vec![(0, Operation::Set(vec![i], vec![i]))];
+		let with = |sync| CommitOptions { sync, wait: true };
+
+		options.sync_wal = false;
+		let db = Db::open_or_create(&options).unwrap();
+		let start = syncs.load(Ordering::SeqCst);
+		db.commit_with(set(0), with(None)).unwrap();
+		db.commit_with(set(1), with(Some(false))).unwrap();
+		assert_eq!(syncs.load(Ordering::SeqCst), start);
+		db.commit_with(set(2), with(Some(true))).unwrap();
+		assert_eq!(syncs.load(Ordering::SeqCst), start + 1);
+		drop(db);
+
+		options.sync_wal = true;
+		let db = Db::open(&options).unwrap();
+		let start = syncs.load(Ordering::SeqCst);
+		db.commit_with(set(3), with(Some(false))).unwrap();
+		assert_eq!(syncs.load(Ordering::SeqCst), start);
+		db.commit_with(set(4), with(None)).unwrap();
+		assert_eq!(syncs.load(Ordering::SeqCst), start + 1);
+		for i in 0..5 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn test_flush() {
+		use std::sync::atomic::Ordering;
+
+		let tmp = tempdir().unwrap();
+		let storage = Arc::new(LogSyncs::default());
+		let syncs = storage.1.clone();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.storage = Some(storage);
+		options.sync_wal = false;
+		let db = Db::open_or_create(&options).unwrap();
+		db.flush().unwrap();
+		let start = syncs.load(Ordering::SeqCst);
+		db.commit([(0, vec![0], Some(vec![0]))]).unwrap();
+		db.flush().unwrap();
+		assert!(syncs.load(Ordering::SeqCst) > start);
+
+		db.commit([(0, vec![1], Some(vec![1]))]).unwrap();
+		db.flush_all().unwrap();
+		assert_eq!(db.queue_stats().commits_in_flight, 0);
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![0]));
+		assert_eq!(db.get(0, &[1]).unwrap(), Some(vec![1]));
+	}
+
+	#[test]
+	fn test_reindex_threads() {
+		for threads in [1, 4] {
+			let tmp = tempdir().unwrap();
+			let mut options = EnableCommitPipelineStages::DbFile.options(tmp.path(), 1);
+			options.columns[0].uniform = true;
+			options.reindex_threads = threads;
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			let key = |i: u32| {
+				let mut key = [0xff; 32];
+				if i < 65 {
+					// More than a chunk of keys sharing the first 16 bits.
+					key[..3].copy_from_slice(&[0, 0, i as u8]);
+				} else {
+					key[..4].copy_from_slice(&(i << 16).to_be_bytes());
+				}
+				key
+			};
+			db.commit((0..4000u32).map(|i| (0, key(i).to_vec(), Some(i.to_le_bytes().to_vec()))))
+				.unwrap();
+			let inner = &db.inner;
+			while inner.process_commits().unwrap() {}
+			loop {
+				inner.flush_logs(0).unwrap();
+				while inner.enact_logs(false).unwrap() {}
+				if !inner.process_reindex().unwrap() {
+					break
+				}
+			}
+			let crate::column::Column::Hash(column) = &inner.columns[0] else {
+				panic!("Hash column expected")
+			};
+			assert_eq!(column.index_state(), (18, false));
+			for i in 0..4000u32 {
+				assert_eq!(db.get(0, &key(i)).unwrap(), Some(i.to_le_bytes().to_vec()));
+			}
+		}
+	}
+
+	#[test]
+	fn test_parallel_replay() {
+		for threads in [1, 4] {
+			let tmp = tempdir().unwrap();
+			let mut options = EnableCommitPipelineStages::DbFile.options(tmp.path(), 3);
+			options.columns[2].btree_index = true;
+			options.replay_threads = threads;
+			let value = |c: u8, i: u32| vec![c.wrapping_add(i as u8); 1000 + i as usize];
+			{
+				let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+				db.commit((0..3u8).flat_map(|c| {
+					(0..100u32).map(move |i| (c, i.to_be_bytes().to_vec(), Some(value(c, i))))
+				}))
+				.unwrap();
+				db.inner.process_commits().unwrap();
+				db.inner.flush_logs(0).unwrap();
+				// Leave the record in the log, as if the process was killed.
+				*db.inner.bg_err.lock() = Some(Arc::new(crate::Error::Corruption("Killed".into())));
+			}
+			let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+			for c in 0..3u8 {
+				for i in 0..100u32 {
+					assert_eq!(db.get(c, &i.to_be_bytes()).unwrap(), Some(value(c, i)));
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_background_io_limit() {
+		use crate::{CommitOptions, IoLimit};
+
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.background_io_limit = Some(IoLimit { bytes_per_second: 0, ops_per_second: 100 });
+		let db = Db::open_or_create(&options).unwrap();
+		let start = std::time::Instant::now();
+		for batch in 0..3u32 {
+			// At least 20 value writes each, logged as separate records.
+			let changes = (batch * 20..(batch + 1) * 20)
+				.map(|i| (0, Operation::Set(i.to_be_bytes().to_vec(), vec![i as u8; 100])));
+			db.commit_with(changes, CommitOptions { sync: None, wait: true }).unwrap();
+		}
+		db.flush_all().unwrap();
+		// The last record waits for the first two to be paid for, less the allowed burst.
+		assert!(start.elapsed() >= Duration::from_millis(250));
+		for i in 0..60u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(vec![i as u8; 100]));
+		}
+	}
+
+	#[test]
+	fn test_custom_storage() {
+		use crate::Storage;
+		let tmp = tempdir().unwrap();
+		let storage = Arc::new(crate::MemoryStorage::default());
+		let mut options = Options::with_columns(&tmp.path().join("db"), 3);
+		options.columns[1].btree_index = true;
+		options.storage = Some(storage.clone());
+		let value = |i: u32| vec![i as u8; (i as usize % 50) * 100];
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for batch in 0..5u32 {
+				db.commit((batch * 1000..(batch + 1) * 1000).flat_map(|i| {
+					let key = i.to_be_bytes().to_vec();
+					[
+						(0, key.clone(), Some(value(i))),
+						(1, key.clone(), Some(value(i))),
+						(2, key, None),
+					]
+				}))
+				.unwrap();
+			}
+			db.commit(vec![(2, vec![1], Some(vec![1]))]).unwrap();
+			db.clear_column(2).unwrap();
+		}
+		assert!(!tmp.path().join("db").exists());
+		let names = storage.list().unwrap();
+		assert!(names.contains(&"metadata".to_string()));
+		assert!(names.iter().any(|name| name.starts_with("index_00_")));
+
+		let db = Db::open(&options).unwrap();
+		for i in 0..5000u32 {
+			assert_eq!(db.get(0, &i.to_be_bytes()).unwrap(), Some(value(i)));
+			assert_eq!(db.get(1, &i.to_be_bytes()).unwrap(), Some(value(i)));
+		}
+		assert_eq!(db.get(2, &[1]).unwrap(), None);
+		assert!(db.backup_to(tmp.path().join("backup")).is_err());
+		drop(db);
+		options.storage = None;
+		assert!(matches!(Db::open(&options), Err(crate::Error::DatabaseNotFound)));
+	}
+
+	#[test]
+	fn test_injected_faults() {
+		use crate::{Fault, FaultRule, FaultyStorage, IoOp};
+		let tmp = tempdir().unwrap();
+		let storage = Arc::new(FaultyStorage::new(Arc::new(crate::MemoryStorage::default())));
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.storage = Some(storage.clone());
+		options.with_background_thread = false;
+		let rule = |op, file_prefix: &str, fault| FaultRule {
+			op,
+			file_prefix: file_prefix.into(),
+			skip: 0,
+			times: Some(1),
+			fault,
+		};
+		let write = |db: &Db| -> crate::Result<()> {
+			while db.process_commits()? {}
+			db.flush_logs()?;
+			while db.enact_logs()? {}
+			Ok(())
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1", Some(vec![1; 100]))]).unwrap();
+		write(&db).unwrap();
+
+		// A failed read is reported and does not affect later reads.
+		storage.inject(rule(IoOp::Read, "table_00", Fault::Fail(std::io::ErrorKind::Other)));
+		assert!(db.get(0, b"key1").is_err());
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(vec![1; 100]));
+
+		// Torn log write, with the log unwritable until the database is closed.
+		storage.inject(rule(IoOp::Write, "log", Fault::Short(10)));
+		storage.inject(FaultRule {
+			times: None,
+			..rule(IoOp::Write, "log", Fault::Fail(std::io::ErrorKind::Other))
+		});
+		db.commit(vec![(0, b"key2", Some(vec![2; 100]))]).unwrap();
+		assert!(write(&db).is_err());
+		drop(db);
+		assert!(storage.injected() >= 2);
+		storage.clear();
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(vec![1; 100]));
+		assert_eq!(db.get(0, b"key2").unwrap(), None);
+
+		// Full disk on sync.
+		storage.inject(FaultRule {
+			times: None,
+			..rule(IoOp::Sync, "", Fault::Fail(std::io::ErrorKind::StorageFull))
+		});
+		db.commit(vec![(0, b"key3", Some(vec![3; 100]))]).unwrap();
+		assert!(write(&db).is_err());
+		storage.clear();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(vec![1; 100]));
+		assert!(db.get(0, b"key3").is_ok());
+
+		// Delays only slow operations down.
+		storage.inject(rule(IoOp::Read, "table_00", Fault::Delay(Duration::from_millis(50))));
+		let start = Instant::now();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(vec![1; 100]));
+		assert!(start.elapsed() >= Duration::from_millis(50));
+	}
+
+	#[cfg(feature = "encryption")]
+	#[test]
+	fn test_key_rotation() {
+		use crate::{Encryption, KeyRing};
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].ref_counted = true;
+		options.columns[0].preimage = true;
+		options.columns[1].btree_index = true;
+		let ring = Arc::new(KeyRing::new([(0, [1; 32])]));
+		for c in options.columns.iter_mut() {
+			c.encryption = Some(Encryption(ring.clone()));
+		}
+		// Includes a multipart value.
+		let value = |i: u8| vec![i; if i == 0 { 100_000 } else { i as usize * 20 }];
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(
+			(0..100u8).flat_map(|i| [(0, vec![i], Some(value(i))), (1, vec![i], Some(value(i)))]),
+		)
+		.unwrap();
+		db.commit((0..10u8).map(|i| (0, vec![i], Some(value(i))))).unwrap();
+		db_test.run_stages(&db);
+
+		assert_eq!(ring.rotate([2; 32]), 1);
+		db.commit(vec![(0, vec![200], Some(value(200))), (1, vec![200], Some(value(200)))])
+			.unwrap();
+		db_test.run_stages(&db);
+		assert!(db.reencrypt_column(2).is_err());
+		for col in 0..2 {
+			assert_eq!(db.column_stats(col).unwrap().reencryption, None);
+			db.reencrypt_column(col).unwrap();
+			db_test.run_stages(&db);
+			let progress = db.column_stats(col).unwrap().reencryption.unwrap();
+			assert!(progress.done);
+			assert_eq!(progress.scanned_entries, progress.total_entries);
+			// Btree nodes are encrypted as well.
+			assert!(progress.reencrypted_entries >= 100, "{:?}", progress);
+			db.reencrypt_column(col).unwrap();
+			assert_eq!(db.column_stats(col).unwrap().reencryption.unwrap().reencrypted_entries, 0);
+		}
+		assert!(ring.remove(0));
+		// Reference counts are kept.
+		db.commit((0..10u8).map(|i| (0, vec![i], None))).unwrap();
+		db_test.run_stages(&db);
+		for i in (0..100u8).chain([200]) {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(value(i)));
+			assert_eq!(db.get(1, &[i]).unwrap(), Some(value(i)));
+		}
+		drop(db);
+
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		for i in (0..100u8).chain([200]) {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(value(i)));
+			assert_eq!(db.get(1, &[i]).unwrap(), Some(value(i)));
+		}
+	}
+
+	#[test]
+	fn test_clean_shutdown() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].bloom_filter_bytes = 1024;
+		let marker = tmp.path().join(super::CLEAN_SHUTDOWN_FILE);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			assert!(!db.inner.clean_shutdown);
+			db.commit((0..100u32).map(|i| (0, i.to_le_bytes().to_vec(), Some(vec![1]))))
+				.unwrap();
+		}
+		assert!(marker.exists());
+		{
+			let db = Db::open(&options).unwrap();
+			assert!(db.inner.clean_shutdown);
+			assert!(!marker.exists());
+			for i in 0..100u32 {
+				assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![1]));
 			}
+			assert_eq!(db.get(0, &100u32.to_le_bytes()).unwrap(), None);
+			*db.inner.bg_err.lock() = Some(Arc::new(crate::Error::Corruption("Killed".into())));
 		}
-		Ok(())
+		assert!(!marker.exists());
+		{
+			let db = Db::open(&options).unwrap();
+			assert!(!db.inner.clean_shutdown);
+		}
+		// A damaged marker is ignored.
+		let mut data = std::fs::read(&marker).unwrap();
+		data[0] ^= 1;
+		std::fs::write(&marker, data).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert!(!db.inner.clean_shutdown);
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), Some(vec![1]));
 	}
 
-	fn write_plan(
-		&self,
-		column: &Column,
-		writer: &mut crate::log::LogWriter,
-		ops: &mut u64,
-		reindex: &mut bool,
-	) -> Result<()> {
-		let column = match column {
-			Column::Hash(column) => column,
-			Column::Tree(_) => {
-				log::warn!(target: "parity-db", "Skipping unindex commit in indexed column");
-				return Ok(())
-			},
+	#[test]
+	fn test_recovery_mode() {
+		let stage = EnableCommitPipelineStages::LogOverlay;
+		// Leaves two log files, one commit each, that were never enacted.
+		let write_logs = |options: &Options| {
+			let db = Db::open_inner(options, OpeningMode::Create).unwrap();
+			for i in 0..2u8 {
+				db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+				stage.run_stages(&db);
+				db.inner.flush_logs(0).unwrap();
+			}
+			*db.inner.bg_err.lock() = Some(Arc::new(crate::Error::Corruption("Killed".into())));
 		};
-		for change in self.changes.iter() {
-			if let PlanOutcome::NeedReindex = column.write_plan(change, writer)? {
-				// Reindex has triggered another reindex.
-				*reindex = true;
+		let damage = |path: &Path, log: &str, truncate: bool| {
+			let path = path.join(log);
+			let mut data = std::fs::read(&path).unwrap();
+			if truncate {
+				data.truncate(data.len() - 4);
+			} else {
+				let len = data.len();
+				data[len - 8] ^= 1;
+			}
+			std::fs::write(&path, data).unwrap();
+		};
+
+		// Torn tail: only the last log file is damaged.
+		let tmp = tempdir().unwrap();
+		let mut options = stage.options(tmp.path(), 1);
+		write_logs(&options);
+		damage(tmp.path(), "log1", true);
+		options.recovery_mode = RecoveryMode::Strict;
+		for _ in 0..2 {
+			match Db::open_inner(&options, OpeningMode::Write) {
+				Err(e @ crate::Error::LogCorruption(_)) =>
+					assert!(e.is_corruption() && !e.is_retryable()),
+				r => panic!("Unexpected result {:?}", r.map(|_| ())),
 			}
-			*ops += 1;
 		}
-		Ok(())
+		options.recovery_mode = RecoveryMode::TolerateTail;
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![0]));
+		assert_eq!(db.get(0, &[1]).unwrap(), None);
+		let report = db.recovery_report();
+		assert!(report.error.is_some());
+		assert_eq!(report.discarded_logs, 1);
+		assert!(report.last_replayed > 0);
+		drop(db);
+
+		// Damage followed by another log file.
+		let tmp = tempdir().unwrap();
+		let mut options = stage.options(tmp.path(), 1);
+		write_logs(&options);
+		damage(tmp.path(), "log0", false);
+		options.recovery_mode = RecoveryMode::TolerateTail;
+		assert!(matches!(
+			Db::open_inner(&options, OpeningMode::Write),
+			Err(crate::Error::LogCorruption(_))
+		));
+		options.recovery_mode = RecoveryMode::Salvage;
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		assert_eq!(db.get(0, &[0]).unwrap(), None);
+		assert_eq!(db.get(0, &[1]).unwrap(), None);
+		let report = db.recovery_report();
+		assert!(report.error.is_some());
+		assert_eq!(report.discarded_logs, 2);
 	}
 
-	fn clean_overlay(&self, overlay: &mut CommitOverlay, record_id: u64) {
-		use std::collections::hash_map::Entry;
-		for change in self.changes.iter() {
-			match change {
-				Operation::Set(k, _) | Operation::Dereference(k) =>
-					if let Entry::Occupied(e) = overlay.indexed.entry(*k) {
-						if e.get().0 == record_id {
-							e.remove_entry();
-						}
-					},
-				Operation::Reference(..) => (),
+	#[test]
+	fn test_bloom_filter() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = stage.options(tmp.path(), 1);
+			options.columns[0].bloom_filter_bytes = 1024;
+			let keys: Vec<[u8; 4]> = (0..200u32).map(|i| i.to_le_bytes()).collect();
+			{
+				let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+				db.commit(keys[..100].iter().map(|k| (0, k.to_vec(), Some(k.to_vec()))))
+					.unwrap();
+				stage.run_stages(&db);
+				db.commit(vec![(0, keys[0].to_vec(), None)]).unwrap();
+				stage.run_stages(&db);
+				assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+				for k in &keys[1..100] {
+					assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+				}
+				for k in &keys[100..] {
+					assert_eq!(db.get(0, k).unwrap(), None);
+				}
+				assert_eq!(
+					db.get_many(0, &keys[99..101]).unwrap(),
+					vec![Some(keys[99].to_vec()), None]
+				);
+			}
+			if stage != EnableCommitPipelineStages::DbFile &&
+				stage != EnableCommitPipelineStages::Standard
+			{
+				continue
+			}
+			// The filter is restored, or built from the index on reopen.
+			let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+			for k in &keys[1..100] {
+				assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
 			}
+			db.commit(vec![(0, keys[150].to_vec(), Some(vec![1]))]).unwrap();
+			stage.run_stages(&db);
+			assert_eq!(db.get(0, &keys[150]).unwrap(), Some(vec![1]));
+			assert_eq!(db.get(0, &keys[151]).unwrap(), None);
 		}
 	}
-}
 
-/// Verification operation utilities.
-pub mod check {
-	pub enum CheckDisplay {
-		None,
-		Full,
-		Short(u64),
-	}
+	#[test]
+	fn test_memory_budget() {
+		use crate::MemoryBudget;
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::Standard;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].value_cache_bytes = 16 * 1024;
+		options.memory_budget.total_bytes = Some(16 * 1024);
+		assert!(!options.is_valid());
+		options.memory_budget.total_bytes = Some(80 * 1024);
+		let budget = options.memory_budget;
+		assert_eq!(budget.queue_limits(&options.columns), (7281, 58255));
+		assert_eq!(
+			MemoryBudget::default().queue_limits(&options.columns),
+			(budget.commit_queue_bytes, budget.log_overlay_bytes)
+		);
 
-	pub struct CheckOptions {
-		pub column: Option<u8>,
-		pub from: Option<u64>,
-		pub bound: Option<u64>,
-		pub display: CheckDisplay,
+		// Commits are held back, but all eventually go through.
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		for i in 0..200u32 {
+			db.commit(vec![(0, i.to_le_bytes().to_vec(), Some(vec![i as u8; 4000]))])
+				.unwrap();
+		}
+		for i in 0..200u32 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 4000]));
+		}
 	}
 
-	impl CheckOptions {
-		pub fn new(
-			column: Option<u8>,
-			from: Option<u64>,
-			bound: Option<u64>,
-			display_content: bool,
-			truncate_value_display: Option<u64>,
-		) -> Self {
-			let display = if display_content {
-				match truncate_value_display {
-					Some(t) => CheckDisplay::Short(t),
-					None => CheckDisplay::Full,
-				}
-			} else {
-				CheckDisplay::None
-			};
-			CheckOptions { column, from, bound, display }
+	#[test]
+	fn test_repair() {
+		use super::check::CheckIssue;
+		use blake2::{digest::typenum::U32, Blake2b, Digest};
+		use std::os::unix::fs::FileExt;
+
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 3);
+		options.columns[0].preimage = true;
+		options.columns[2].btree_index = true;
+		let hash = |v: &[u8]| -> Vec<u8> { Blake2b::<U32>::digest(v).to_vec() };
+		let values: Vec<_> = (0..10u8).map(|i| vec![i; 50]).collect();
+		{
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+			db.commit(values.iter().flat_map(|v| {
+				[
+					(0, hash(v), Some(v.clone())),
+					(1, v[..1].to_vec(), Some(v.clone())),
+					(2, v[..1].to_vec(), Some(v.clone())),
+				]
+			}))
+			.unwrap();
+			db_test.run_stages(&db);
+			// Two records left in separate log files.
+			db.commit(vec![(1, vec![100], Some(vec![100; 50]))]).unwrap();
+			db.inner.process_commits().unwrap();
+			db.inner.flush_logs(0).unwrap();
+			db.commit(vec![(1, vec![101], Some(vec![101; 50]))]).unwrap();
+			db.inner.process_commits().unwrap();
+			db.inner.flush_logs(0).unwrap();
 		}
+		let file = |prefix: &str| {
+			let mut paths: Vec<_> = std::fs::read_dir(tmp.path())
+				.unwrap()
+				.map(|e| e.unwrap().path())
+				.filter(|p| p.file_name().unwrap().to_str().unwrap().starts_with(prefix))
+				.filter(|p| p.metadata().unwrap().len() > 0)
+				.collect();
+			paths.sort();
+			let file = std::fs::OpenOptions::new().write(true).open(&paths[0]).unwrap();
+			(paths[0].to_str().unwrap().to_owned(), file)
+		};
+		// Corrupt the first log record.
+		let (_, log) = file("log");
+		log.write_all_at(&[0xaa; 8], log.metadata().unwrap().len() / 2).unwrap();
+		// Drop all index entries of the preimage column.
+		let (_, index) = file("index_00_");
+		let len = index.metadata().unwrap().len() - 16 * 1024;
+		index.write_all_at(&vec![0; len as usize], 16 * 1024).unwrap();
+		// Corrupt a value of column 1.
+		let (name, table) = file("table_01_");
+		let tier = u8::from_str_radix(&name[name.len() - 2..], 16).unwrap();
+		let entry_size = crate::column::Column::value_entry_size(tier) as u64;
+		table.write_all_at(&[0xff, 0xff], entry_size).unwrap();
+
+		let (db, report) = Db::open_repair(&options).unwrap();
+		assert_eq!(report.skipped_logs.len(), 1);
+		assert_eq!(report.removed, 1);
+		assert_eq!(report.restored, 10);
+		assert_eq!(report.columns[0].entries, 0);
+		assert!(matches!(report.columns[1].issues[..], [CheckIssue::BadIndexEntry { .. }]));
+		assert_eq!(report.columns[2].entries, 10);
+		assert!(report.columns[2].issues.is_empty());
+
+		assert_eq!(db.get(1, &[100]).unwrap(), None);
+		assert_eq!(db.get(1, &[101]).unwrap(), Some(vec![101; 50]));
+		let mut missing = 0;
+		for v in &values {
+			assert_eq!(db.get(0, &hash(v)).unwrap().as_ref(), Some(v));
+			match db.get(1, &v[..1]).unwrap() {
+				Some(value) => assert_eq!(&value, v),
+				None => missing += 1,
+			}
+		}
+		assert_eq!(missing, 1);
+		let check = db.check(super::check::CheckOptions::new(None, None, None, false, None));
+		assert!(check.unwrap().is_ok());
 	}
-}
 
-#[derive(Eq, PartialEq, Clone, Copy)]
-enum OpeningMode {
-	Create,
-	Write,
-	ReadOnly,
-}
+	#[test]
+	fn test_subscribe() {
+		test_subscribe_inner(EnableCommitPipelineStages::DbFile);
+		test_subscribe_inner(EnableCommitPipelineStages::Standard);
+	}
 
-#[cfg(test)]
-mod tests {
-	use crate::{ColumnOptions, Value};
+	fn test_subscribe_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 3);
+		options.columns[1].btree_index = true;
+		options.columns[2].ttl = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let hash = db.subscribe(0).unwrap();
+		let btree = db.subscribe(1).unwrap();
+		let ttl = db.subscribe(2).unwrap();
+		drop(db.subscribe(2).unwrap());
+		assert!(db.subscribe(3).is_err());
 
-	use super::{Db, Options};
-	use crate::{
-		column::ColId,
-		db::{DbInner, OpeningMode},
-	};
-	use rand::Rng;
-	use std::{
-		collections::{BTreeMap, HashMap, HashSet},
-		path::Path,
-	};
-	use tempfile::tempdir;
+		db.commit(vec![
+			(0, b"a", Some(vec![1])),
+			(1, b"a", Some(vec![1])),
+			(2, b"a", Some(vec![1])),
+		])
+		.unwrap();
+		db.commit(vec![(0, b"b", Some(vec![2])), (1, b"b", Some(vec![2]))]).unwrap();
+		db.commit(vec![(0, b"a", None), (1, b"a", None)]).unwrap();
+		let delete = vec![(1, Operation::DeleteRange(b"a".to_vec(), b"z".to_vec()))];
+		if db_test == EnableCommitPipelineStages::Standard {
+			// Flushing the log lets the background threads enact all commits.
+			db.commit_changes_durable(delete).unwrap();
+		} else {
+			db.commit_changes(delete).unwrap();
+		}
+		db_test.run_stages(&db);
 
-	// This is used in tests to disable certain commit stages.
-	#[derive(Eq, PartialEq, Debug, Clone, Copy)]
-	enum EnableCommitPipelineStages {
-		// No threads started, data stays in commit overlay.
-		#[allow(dead_code)]
-		CommitOverlay,
-		// Log worker run, data processed up to the log overlay.
-		#[allow(dead_code)]
-		LogOverlay,
-		// Runing all.
-		#[allow(dead_code)]
-		DbFile,
-		// Default run mode.
-		Standard,
+		let recv = |receiver: &mpsc::Receiver<Change>| {
+			receiver.recv_timeout(Duration::from_secs(10)).unwrap()
+		};
+		assert_eq!(recv(&hash), (b"a".to_vec(), Some(vec![1])));
+		assert_eq!(recv(&hash), (b"b".to_vec(), Some(vec![2])));
+		assert_eq!(recv(&hash), (b"a".to_vec(), None));
+		assert_eq!(recv(&btree), (b"a".to_vec(), Some(vec![1])));
+		assert_eq!(recv(&btree), (b"b".to_vec(), Some(vec![2])));
+		assert_eq!(recv(&btree), (b"a".to_vec(), None));
+		assert_eq!(recv(&btree), (b"b".to_vec(), None));
+		assert_eq!(recv(&ttl), (b"a".to_vec(), Some(vec![1])));
+		assert!(hash.try_recv().is_err());
+		assert!(btree.try_recv().is_err());
+		assert_eq!(db.inner.subscribers.lock().len(), 3);
 	}
 
-	impl EnableCommitPipelineStages {
-		fn options(&self, path: &Path, num_columns: u8) -> Options {
-			Options {
-				path: path.into(),
-				sync_wal: true,
-				sync_data: true,
-				stats: true,
-				salt: None,
-				columns: (0..num_columns).map(|_| Default::default()).collect(),
-				compression_threshold: HashMap::new(),
-				with_background_thread: *self == Self::Standard,
-				always_flush: *self == Self::DbFile,
-			}
-		}
+	#[test]
+	fn test_max_commit_bytes() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.max_commit_bytes = Some(100);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, vec![1; 10], Some(vec![1; 90]))]).unwrap();
+		let err = db
+			.commit(vec![(0, vec![2; 10], Some(vec![2; 50])), (0, vec![3; 10], Some(vec![3; 50]))])
+			.unwrap_err();
+		assert!(matches!(err, crate::Error::CommitTooLarge { bytes: 120, limit: 100 }));
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, &[1; 10]).unwrap(), Some(vec![1; 90]));
+		assert_eq!(db.get(0, &[2; 10]).unwrap(), None);
+	}
 
-		fn run_stages(&self, db: &Db) {
-			let db = &db.inner;
-			if *self == EnableCommitPipelineStages::DbFile ||
-				*self == EnableCommitPipelineStages::LogOverlay
-			{
-				while db.process_commits().unwrap() {}
-				while db.process_reindex().unwrap() {}
-			}
-			if *self == EnableCommitPipelineStages::DbFile {
-				let _ = db.log.flush_one(0).unwrap();
-				while db.enact_logs(false).unwrap() {}
-				let _ = db.clean_logs().unwrap();
-			}
-		}
+	#[test]
+	fn test_validate_commit() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 4);
+		options.columns[1].btree_index = true;
+		options.columns[2].uniform = true;
+		options.columns[3].ttl = true;
+		options.max_commit_bytes = Some(1000);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
 
-		fn check_empty_overlay(&self, db: &DbInner, col: ColId) -> bool {
-			match self {
-				EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
-					if let Some(overlay) = db.commit_overlay.read().get(col as usize) {
-						if !overlay.is_empty() {
-							let mut replayed = 5;
-							while !overlay.is_empty() {
-								if replayed > 0 {
-									replayed -= 1;
-									// the signal is triggered just before cleaning the overlay, so
-									// we wait a bit.
-									// Warning this is still rather flaky and should be ignored
-									// or removed.
-									std::thread::sleep(std::time::Duration::from_millis(100));
-								} else {
-									return false
-								}
-							}
-						}
-					}
-				},
-				_ => (),
-			}
-			true
+		let set =
+			|col, key: &[u8], value: &[u8]| (col, Operation::Set(key.to_vec(), value.to_vec()));
+		let small = db.validate_commit(vec![set(0, b"a", &[1; 10])]).unwrap();
+		let large = db.validate_commit(vec![set(0, b"a", &[1; 100])]).unwrap();
+		assert_eq!(large - small, 90);
+		assert!(db.validate_commit(vec![set(1, b"a", &[1; 10])]).unwrap() > 0);
+		assert!(db.validate_commit(vec![set(2, &[1; 32], &[1])]).is_ok());
+
+		// Hash indexed keys and expiring values count.
+		let long_key = db.validate_commit(vec![set(0, &[1; 101], &[1; 10])]).unwrap();
+		assert_eq!(long_key - small, 100);
+		let ttl = |value: &[u8]| {
+			let op = Operation::SetWithTtl(b"a".to_vec(), value.to_vec(), Duration::from_secs(1));
+			db.validate_commit(vec![(3, op)]).unwrap()
+		};
+		assert_eq!(ttl(&[1; 100]) - ttl(&[1; 10]), 90);
+		assert!(ttl(&[1; 10]) >= small + 8);
+		assert!(db
+			.validate_commit(vec![(
+				3,
+				Operation::SetWithTtl(b"a".to_vec(), vec![1; 1000], Duration::from_secs(1))
+			)])
+			.is_err());
+
+		assert!(db.validate_commit(vec![set(4, b"a", &[1])]).is_err());
+		assert!(db.validate_commit(vec![set(2, b"a", &[1])]).is_err());
+		assert!(db.validate_commit(vec![set(0, b"a", &[1; 1000])]).is_err());
+		assert!(db.validate_commit(vec![(0, Operation::AddRc(b"a".to_vec(), 1))]).is_err());
+		assert!(db
+			.validate_commit(vec![(0, Operation::DeleteRange(b"a".to_vec(), b"b".to_vec()))])
+			.is_err());
+		assert!(db.validate_commit(vec![(1, Operation::Merge(b"a".to_vec(), vec![1]))]).is_err());
+		assert!(db.validate_commit(vec![(0, Operation::Merge(b"a".to_vec(), vec![1]))]).is_err());
+		assert!(db.commit(vec![(2, b"a".to_vec(), Some(vec![1]))]).is_err());
+
+		// Nothing is applied.
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(1, b"a").unwrap(), None);
+	}
+
+	#[test]
+	fn test_max_value_size() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 3);
+		options.columns[0].max_value_size = Some(100);
+		options.columns[1].btree_index = true;
+		options.columns[1].max_value_size = Some(100);
+		options.columns[2].max_value_size = Some(crate::MAX_VALUE_SIZE + 1);
+		assert!(Db::open_inner(&options, OpeningMode::Create).is_err());
+		options.columns[2].max_value_size = None;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit(vec![(0, b"a", Some(vec![1; 100])), (1, b"a", Some(vec![1; 100]))])
+			.unwrap();
+		for col in 0..2 {
+			let err = db.commit(vec![(col, b"b", Some(vec![2; 101]))]).unwrap_err();
+			assert!(matches!(err, crate::Error::ValueTooLarge { limit: 100, actual: 101 }));
 		}
+		let mut writer = db.begin_value(0, b"c").unwrap();
+		std::io::Write::write_all(&mut writer, &[3; 100]).unwrap();
+		assert!(std::io::Write::write_all(&mut writer, &[3]).is_err());
+		drop(writer);
+
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1; 100]));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		assert_eq!(db.get(0, b"c").unwrap(), None);
+		assert_eq!(db.get(1, b"b").unwrap(), None);
 	}
 
 	#[test]
-	fn test_db_open_should_fail() {
+	fn test_store_keys() {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(matches!(Db::open(&options), Err(crate::Error::DatabaseNotFound)));
+		let mut options = Options::with_columns(&tmp.path().join("db"), 3);
+		options.columns[0].store_keys = true;
+		options.columns[0].compression = CompressionType::Lz4;
+		options.columns[1].btree_index = true;
+		options.columns[2].store_keys = true;
+		options.columns[2].uniform = true;
+		assert!(Db::open_or_create(&options).is_err());
+		options.columns[2].uniform = false;
+		let db = Db::open_or_create(&options).unwrap();
+		let subscription = db.subscribe(0).unwrap();
+
+		let keys: Vec<Vec<u8>> = (0..100u32).map(|i| format!("key{i}").into_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k.clone(), Some(k.repeat(10))))).unwrap();
+		db.commit(vec![(0, keys[0].clone(), None)]).unwrap();
+		assert_eq!(db.get(0, &keys[1]).unwrap(), Some(keys[1].repeat(10)));
+		assert_eq!(db.get_size(0, &keys[1]).unwrap(), Some(keys[1].len() as u32 * 10));
+		assert_eq!(
+			db.get_many(0, &[&keys[0], &keys[2]]).unwrap(),
+			vec![None, Some(keys[2].repeat(10))]
+		);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &keys[1]).unwrap(), Some(keys[1].repeat(10)));
+		assert_eq!(
+			db.get_with_meta(0, &keys[1]).unwrap().map(|(value, _)| value),
+			Some(keys[1].repeat(10))
+		);
+		let mut found = Vec::new();
+		let mut iter = db.iter_column(0).unwrap();
+		while let Some((key, value)) = iter.next_keyed().unwrap() {
+			assert_eq!(value, key.repeat(10));
+			found.push(key);
+		}
+		found.sort();
+		let mut expected = keys[1..].to_vec();
+		expected.sort();
+		assert_eq!(found, expected);
+		assert!(db.iter_column(2).unwrap().next_keyed().is_ok());
+		assert!(db
+			.check(crate::CheckOptions::new(None, None, None, false, None))
+			.unwrap()
+			.is_ok());
+
+		// Dumps hold the original keys and can be imported into a btree column.
+		let mut dump = Vec::new();
+		assert_eq!(db.export(0, &mut dump).unwrap(), 99);
+		assert_eq!(db.import(1, &dump[..]).unwrap(), 99);
+		assert_eq!(db.get(1, &keys[1]).unwrap(), Some(keys[1].repeat(10)));
+
+		let mut migrated = options.columns[0].clone();
+		migrated.store_keys = false;
+		assert!(db.migrate_column(0, migrated).is_err());
+		drop(db);
+		assert_eq!(
+			subscription.recv_timeout(Duration::from_secs(10)).unwrap(),
+			(keys[0].clone(), Some(keys[0].repeat(10)))
+		);
 	}
 
 	#[test]
-	fn test_db_open_fail_then_recursively_create() {
+	fn test_watch() {
 		let tmp = tempdir().unwrap();
-		let (db_path_first, db_path_last) = {
-			let mut db_path_first = tmp.path().to_owned();
-			db_path_first.push("nope");
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let key = db.watch(0, b"ab").unwrap();
+		let prefix = db.watch(1, b"a").unwrap();
+		assert!(db.watch(2, b"a").is_err());
 
-			let mut db_path_last = db_path_first.to_owned();
+		db.commit(vec![
+			(0, b"a".to_vec(), Some(vec![1])),
+			(0, b"ab".to_vec(), Some(vec![2])),
+			(1, b"ab".to_vec(), Some(vec![3])),
+			(1, b"b".to_vec(), Some(vec![4])),
+		])
+		.unwrap();
+		db.commit(vec![(0, b"ab".to_vec(), None), (1, b"ac".to_vec(), Some(vec![5]))])
+			.unwrap();
+		db_test.run_stages(&db);
 
-			for p in ["does", "not", "yet", "exist"] {
-				db_path_last.push(p);
-			}
+		assert_eq!(key.try_recv().unwrap(), (b"ab".to_vec(), Some(vec![2])));
+		assert_eq!(key.try_recv().unwrap(), (b"ab".to_vec(), None));
+		assert!(key.try_recv().is_err());
+		assert_eq!(prefix.try_recv().unwrap(), (b"ab".to_vec(), Some(vec![3])));
+		assert_eq!(prefix.try_recv().unwrap(), (b"ac".to_vec(), Some(vec![5])));
+		assert!(prefix.try_recv().is_err());
+	}
 
-			(db_path_first, db_path_last)
-		};
+	#[test]
+	fn test_add_column_online() {
+		test_add_column_online_inner(EnableCommitPipelineStages::CommitOverlay);
+		test_add_column_online_inner(EnableCommitPipelineStages::LogOverlay);
+		test_add_column_online_inner(EnableCommitPipelineStages::DbFile);
+		test_add_column_online_inner(EnableCommitPipelineStages::Standard);
+	}
 
-		assert!(
-			!db_path_first.exists(),
-			"That directory should not have existed at this point (dir: {:?})",
-			db_path_first
-		);
+	fn test_add_column_online_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit(vec![(0, b"key", Some(vec![0]))]).unwrap();
+		let snapshot = db.snapshot();
 
-		let options = Options::with_columns(&db_path_last, 5);
-		assert!(matches!(Db::open(&options), Err(crate::Error::DatabaseNotFound)));
+		let btree_options = ColumnOptions { btree_index: true, ..Default::default() };
+		assert_eq!(db.add_column_online(ColumnOptions::default()).unwrap(), 1);
+		assert_eq!(db.add_column_online(btree_options.clone()).unwrap(), 2);
+		assert_eq!(db.num_columns(), 3);
+		assert!(db
+			.add_column_online(ColumnOptions { ref_counted: true, ..Default::default() })
+			.is_err());
 
-		assert!(!db_path_first.exists(), "That directory should remain non-existent. Did the `open(create: false)` nonetheless create a directory? (dir: {:?})", db_path_first);
-		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
+		db.commit(vec![(1, b"key", Some(vec![1])), (2, b"key", Some(vec![2]))]).unwrap();
+		db_test.run_stages(&db);
+		assert_eq!(db.get(1, b"key").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(2, b"key").unwrap(), Some(vec![2]));
+		assert_eq!(snapshot.get(1, b"key").unwrap(), None);
+		let mut iter = db.iter(2).unwrap();
+		iter.seek_to_first().unwrap();
+		assert_eq!(iter.next().unwrap(), Some((b"key".to_vec(), vec![2])));
+		drop(iter);
+		drop(snapshot);
+		drop(db);
 
-		assert!(
-			db_path_first.is_dir(),
-			"A directory should have been been created (dir: {:?})",
-			db_path_first
-		);
-		assert!(
-			db_path_last.is_dir(),
-			"A directory should have been been created (dir: {:?})",
-			db_path_last
-		);
+		assert!(Db::open_inner(&options, OpeningMode::Write).is_err());
+		options.columns.push(ColumnOptions::default());
+		options.columns.push(btree_options);
+		let db = Db::open_inner(&options, OpeningMode::Write).unwrap();
+		if db_test == EnableCommitPipelineStages::DbFile ||
+			db_test == EnableCommitPipelineStages::Standard
+		{
+			assert_eq!(db.get(0, b"key").unwrap(), Some(vec![0]));
+			assert_eq!(db.get(1, b"key").unwrap(), Some(vec![1]));
+			assert_eq!(db.get(2, b"key").unwrap(), Some(vec![2]));
+		}
 	}
 
 	#[test]
-	fn test_db_open_or_create() {
+	fn test_clone_column() {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
-		assert!(Db::open(&options).is_ok(), "Existing database should be reopened");
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].bloom_filter_bytes = 1024;
+		options.columns[0].blob_threshold = Some(32 * 1024);
+		options.columns[0].count_keys = true;
+		options.columns[1].btree_index = true;
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		let value = |i: u32| vec![i as u8; if i.is_multiple_of(10) { 40_000 } else { 100 }];
+		let db = Db::open_or_create(&options).unwrap();
+		for c in [0, 1] {
+			db.commit((0..200).map(|i| (c, key(i), Some(value(i))))).unwrap();
+		}
+		assert_eq!(db.clone_column(0).unwrap(), 2);
+		assert_eq!(db.clone_column(1).unwrap(), 3);
+		assert!(db.clone_column(4).is_err());
+		// The columns diverge.
+		for (c, clone) in [(0, 2), (1, 3)] {
+			db.commit((0..100).map(|i| (c, key(i), None))).unwrap();
+			db.commit((100..200).map(|i| (clone, key(i), Some(vec![1])))).unwrap();
+		}
+		let check = |db: &Db| {
+			for (c, clone) in [(0, 2), (1, 3)] {
+				for i in 0..200 {
+					let (source, cloned) = if i < 100 {
+						(None, Some(value(i)))
+					} else {
+						(Some(value(i)), Some(vec![1]))
+					};
+					assert_eq!(db.get(c, &key(i)).unwrap(), source);
+					assert_eq!(db.get(clone, &key(i)).unwrap(), cloned);
+				}
+			}
+			assert_eq!(db.exact_len(2).unwrap(), 200);
+		};
+		check(&db);
+		drop(db);
+
+		options.columns.push(options.columns[0].clone());
+		options.columns.push(options.columns[1].clone());
+		let db = Db::open(&options).unwrap();
+		check(&db);
 	}
 
 	#[test]
-	fn test_indexed_keyvalues() {
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::CommitOverlay);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::LogOverlay);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::DbFile);
-		test_indexed_keyvalues_inner(EnableCommitPipelineStages::Standard);
+	fn test_merge() {
+		test_merge_inner(EnableCommitPipelineStages::CommitOverlay);
+		test_merge_inner(EnableCommitPipelineStages::LogOverlay);
+		test_merge_inner(EnableCommitPipelineStages::DbFile);
+		test_merge_inner(EnableCommitPipelineStages::Standard);
 	}
-	fn test_indexed_keyvalues_inner(db_test: EnableCommitPipelineStages) {
-		let tmp = tempdir().unwrap();
-		let options = db_test.options(tmp.path(), 5);
-		let col_nb = 0;
-
-		let key1 = b"key1".to_vec();
-		let key2 = b"key2".to_vec();
-		let key3 = b"key3".to_vec();
 
+	fn test_merge_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].merge = Some(crate::MergeOperator::new(|existing, operand| {
+			let existing = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+			let operand = u64::from_le_bytes(operand.try_into().unwrap());
+			(existing + operand).to_le_bytes().to_vec()
+		}));
 		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
-		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
-
-		db.commit(vec![(col_nb, key1.clone(), Some(b"value1".to_vec()))]).unwrap();
-		db_test.run_stages(&db);
-		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
-
-		assert_eq!(db.get(col_nb, key1.as_slice()).unwrap(), Some(b"value1".to_vec()));
+		let counter = |v: u64| v.to_le_bytes().to_vec();
 
-		db.commit(vec![
-			(col_nb, key1.clone(), None),
-			(col_nb, key2.clone(), Some(b"value2".to_vec())),
-			(col_nb, key3.clone(), Some(b"value3".to_vec())),
+		db.commit_changes(vec![
+			(0, Operation::Merge(b"a".to_vec(), counter(1))),
+			(0, Operation::Merge(b"a".to_vec(), counter(2))),
+			(0, Operation::Set(b"b".to_vec(), counter(10))),
 		])
 		.unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(counter(3)));
 		db_test.run_stages(&db);
-		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
-
-		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
-		assert_eq!(db.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2".to_vec()));
-		assert_eq!(db.get(col_nb, key3.as_slice()).unwrap(), Some(b"value3".to_vec()));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(counter(3)));
 
-		db.commit(vec![
-			(col_nb, key2.clone(), Some(b"value2b".to_vec())),
-			(col_nb, key3.clone(), None),
+		db.commit_changes(vec![
+			(0, Operation::Merge(b"a".to_vec(), counter(4))),
+			(0, Operation::Merge(b"b".to_vec(), counter(5))),
+			(0, Operation::Dereference(b"b".to_vec())),
+			(0, Operation::Merge(b"b".to_vec(), counter(6))),
 		])
 		.unwrap();
 		db_test.run_stages(&db);
-		assert!(db_test.check_empty_overlay(&db.inner, col_nb));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(counter(7)));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(counter(6)));
 
-		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
-		assert_eq!(db.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2b".to_vec()));
-		assert_eq!(db.get(col_nb, key3.as_slice()).unwrap(), None);
+		// Operands of separate commits are kept pending until written.
+		db.commit_changes(vec![(0, Operation::Merge(b"a".to_vec(), counter(8)))])
+			.unwrap();
+		db.commit_changes(vec![(0, Operation::Merge(b"a".to_vec(), counter(9)))])
+			.unwrap();
+		db.commit_changes(vec![(0, Operation::Merge(b"c".to_vec(), counter(1)))])
+			.unwrap();
+		if matches!(db_test, EnableCommitPipelineStages::CommitOverlay) {
+			let overlay = db.inner.commit_overlay.read();
+			// No commit is written, all operands of `a` are pending.
+			assert_eq!(overlay[0].merges[&db.hash_key(0, b"a")].len(), 5);
+			assert!(!overlay[0].indexed.contains_key(&db.hash_key(0, b"a")));
+		}
+		assert_eq!(db.get(0, b"a").unwrap(), Some(counter(24)));
+		assert_eq!(
+			db.get_many(0, &[b"c", b"a", b"d"]).unwrap(),
+			vec![Some(counter(1)), Some(counter(24)), None]
+		);
+		assert!(db.contains_key(0, b"c").unwrap());
+		assert_eq!(db.get_size(0, b"a").unwrap(), Some(8));
+		db_test.run_stages(&db);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(counter(24)));
+		assert_eq!(db.get(0, b"c").unwrap(), Some(counter(1)));
+
+		// No merge operator registered.
+		assert!(db
+			.commit_changes(vec![(1, Operation::Merge(b"a".to_vec(), counter(1)))])
+			.is_err());
 	}
 
 	#[test]
@@ -2391,6 +10345,335 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_btree_iter_prefix() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_btree_iter_prefix_inner(stage);
+		}
+	}
+
+	fn test_btree_iter_prefix_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let col_nb = 0;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[col_nb as usize].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let keys: Vec<Vec<u8>> = vec![
+			b"a".to_vec(),
+			b"ab".to_vec(),
+			b"b".to_vec(),
+			b"b\0".to_vec(),
+			b"ba".to_vec(),
+			b"bb".to_vec(),
+			b"b\xff".to_vec(),
+			b"c".to_vec(),
+			b"\xff".to_vec(),
+			b"\xff\xff".to_vec(),
+		];
+		db.commit(keys.iter().map(|k| (col_nb, k.clone(), Some(k.clone())))).unwrap();
+		db_test.run_stages(&db);
+
+		let collect = |prefix: &[u8], reverse: bool| {
+			let mut iter = db.iter_prefix(col_nb, prefix).unwrap();
+			let mut result = Vec::new();
+			if reverse {
+				iter.seek_to_last().unwrap();
+				while let Some((k, _)) = iter.prev().unwrap() {
+					result.push(k);
+				}
+			} else {
+				while let Some((k, _)) = iter.next().unwrap() {
+					result.push(k);
+				}
+			}
+			result
+		};
+		let expected = |prefix: &[u8], reverse: bool| {
+			let mut result: Vec<Vec<u8>> =
+				keys.iter().filter(|k| k.starts_with(prefix)).cloned().collect();
+			if reverse {
+				result.reverse();
+			}
+			result
+		};
+
+		for prefix in [&b""[..], b"a", b"b", b"ba", b"bc", b"d", b"\xff"] {
+			assert_eq!(collect(prefix, false), expected(prefix, false));
+			assert_eq!(collect(prefix, true), expected(prefix, true));
+		}
+
+		// Seeking before the prefix clamps to the prefix start.
+		let mut iter = db.iter_prefix(col_nb, b"b").unwrap();
+		iter.seek(b"a").unwrap();
+		assert_eq!(iter.next().unwrap().map(|(k, _)| k), Some(b"b".to_vec()));
+		iter.seek(b"bb").unwrap();
+		assert_eq!(iter.next().unwrap().map(|(k, _)| k), Some(b"bb".to_vec()));
+		assert_eq!(iter.next().unwrap().map(|(k, _)| k), Some(b"b\xff".to_vec()));
+		assert_eq!(iter.next().unwrap(), None);
+	}
+
+	#[test]
+	fn test_btree_delete_range() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_btree_delete_range_inner(stage);
+		}
+	}
+
+	fn test_btree_delete_range_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		db.commit((0u8..100).map(|i| (0, vec![i], Some(vec![i])))).unwrap();
+		db_test.run_stages(&db);
+		// Pending changes in the commit overlay are also covered by the range.
+		db.commit(vec![(0, vec![100], Some(vec![100])), (0, vec![20], None)]).unwrap();
+
+		db.commit_changes(vec![
+			(0, Operation::Set(vec![50, 0], vec![0])),
+			(0, Operation::DeleteRange(vec![10], vec![60])),
+			(0, Operation::Set(vec![30], vec![30])),
+			(0, Operation::DeleteRange(vec![90], vec![255])),
+			(0, Operation::DeleteRange(vec![5], vec![5])),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+
+		let expected: Vec<u8> = (0u8..10).chain(std::iter::once(30)).chain(60..90).collect();
+		let mut iter = db.iter(0).unwrap();
+		let mut keys = Vec::new();
+		while let Some((k, v)) = iter.next().unwrap() {
+			assert_eq!(k, v);
+			keys.push(k[0]);
+		}
+		assert_eq!(keys, expected);
+		assert_eq!(db.get(0, &[50, 0]).unwrap(), None);
+		assert_eq!(db.get(0, &[100]).unwrap(), None);
+
+		assert!(db.commit_changes(vec![(1, Operation::DeleteRange(vec![0], vec![1]))]).is_err());
+
+		// Ranges are not limited in size. Keys of the log and the tables are hidden by the range
+		// until it is written.
+		let key = |i: u32| [&[200][..], &i.to_be_bytes()].concat();
+		let expected: Vec<_> = (0..10).chain(19_990..20_000).map(key).collect();
+		let range_keys = || {
+			let mut iter = db.iter(0).unwrap();
+			iter.seek(&[200]).unwrap();
+			let mut keys = Vec::new();
+			while let Some((k, _)) = iter.next().unwrap() {
+				keys.push(k);
+			}
+			keys
+		};
+		db.commit((0..20_000).map(|i| (0, key(i), Some(key(i))))).unwrap();
+		db_test.run_stages(&db);
+		db.commit_changes(vec![(0, Operation::DeleteRange(key(10), key(19_990)))])
+			.unwrap();
+		assert_eq!(db.get(0, &key(10)).unwrap(), None);
+		assert_eq!(range_keys(), expected);
+		db_test.run_stages(&db);
+		assert_eq!(range_keys(), expected);
+		assert_eq!(db.get(0, &key(9)).unwrap(), Some(key(9)));
+		assert_eq!(db.get(0, &key(10_000)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_btree_delete_range_random() {
+		use super::check::CheckOptions;
+		use rand::{Rng, SeedableRng};
+		let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].btree_index = true;
+		options.columns[0].count_keys = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let key = |i: u32| i.to_be_bytes().to_vec();
+		let mut expected = BTreeMap::new();
+		for _ in 0..200 {
+			let mut changes = Vec::new();
+			for _ in 0..rng.gen_range(0..2000) {
+				let i = rng.gen_range(0..20_000);
+				changes.push((0, Operation::Set(key(i), key(i))));
+				expected.insert(key(i), key(i));
+			}
+			for _ in 0..rng.gen_range(1..3) {
+				let start = rng.gen_range(0..20_000);
+				let end = start + rng.gen_range(0..5_000);
+				changes.push((0, Operation::DeleteRange(key(start), key(end))));
+				expected.retain(|k, _| *k < key(start) || *k >= key(end));
+			}
+			db.commit_changes(changes).unwrap();
+			db_test.run_stages(&db);
+		}
+
+		let mut iter = db.iter(0).unwrap();
+		let mut keys = Vec::new();
+		while let Some((k, v)) = iter.next().unwrap() {
+			assert_eq!(k, v);
+			keys.push(k);
+		}
+		assert_eq!(keys, expected.keys().cloned().collect::<Vec<_>>());
+		assert_eq!(db.exact_len(0).unwrap(), expected.len() as u64);
+		let report = db.check(CheckOptions::new(Some(0), None, None, false, None)).unwrap();
+		assert!(report.is_ok(), "{:?}", report);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			test_snapshot_inner(stage);
+		}
+	}
+
+	#[test]
+	fn test_failed_commit_is_not_applied() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::CommitOverlay;
+		let mut options = db_test.options(tmp.path(), 1);
+		options.columns[0].max_value_size = Some(32);
+		options.columns[0].merge = Some(crate::MergeOperator::new(|existing, operand| {
+			[existing.unwrap_or_default(), operand].concat()
+		}));
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let id = db
+			.commit_changes(vec![(0, Operation::Set(b"a".to_vec(), vec![1; 20]))])
+			.unwrap();
+		let snapshot = db.snapshot();
+
+		// The merged value is too large, which is only known under the commit locks.
+		assert!(matches!(
+			db.commit_changes(vec![
+				(0, Operation::Set(b"b".to_vec(), vec![2])),
+				(0, Operation::Merge(b"a".to_vec(), vec![3; 20])),
+			]),
+			Err(crate::Error::ValueTooLarge { .. })
+		));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		assert_eq!(snapshot.get(0, b"a").unwrap(), Some(vec![1; 20]));
+		assert_eq!(
+			db.commit_changes(vec![(0, Operation::Set(b"a".to_vec(), vec![4]))]).unwrap(),
+			id + 1
+		);
+		assert_eq!(snapshot.get(0, b"a").unwrap(), Some(vec![1; 20]));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![4]));
+	}
+
+	fn test_snapshot_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let state: BTreeMap<Vec<u8>, Vec<u8>> = (0u8..20).map(|i| (vec![i * 2], vec![i])).collect();
+		for col in [0, 1] {
+			db.commit(state.iter().map(|(k, v)| (col, k.clone(), Some(v.clone())))).unwrap();
+		}
+		db_test.run_stages(&db);
+
+		let snapshot = db.snapshot();
+		let mut iter = snapshot.iter(1).unwrap();
+		assert_eq!(iter.next().unwrap(), Some((vec![0], vec![0])));
+
+		for col in [0, 1] {
+			db.commit(vec![
+				(col, vec![2], None),
+				(col, vec![3], Some(vec![3])),
+				(col, vec![4], Some(vec![44])),
+				(col, vec![38], None),
+				(col, vec![50], Some(vec![50])),
+			])
+			.unwrap();
+		}
+		db_test.run_stages(&db);
+		db.commit(vec![(1, vec![6], None), (1, vec![1], Some(vec![1]))]).unwrap();
+		db_test.run_stages(&db);
+
+		for col in [0, 1] {
+			assert_eq!(db.get(col, &[2]).unwrap(), None);
+			assert_eq!(snapshot.get(col, &[2]).unwrap(), Some(vec![1]));
+			assert_eq!(snapshot.get(col, &[3]).unwrap(), None);
+			assert_eq!(snapshot.get(col, &[4]).unwrap(), Some(vec![2]));
+			assert_eq!(snapshot.get(col, &[38]).unwrap(), Some(vec![19]));
+			assert_eq!(snapshot.get(col, &[50]).unwrap(), None);
+		}
+
+		let mut expected = state.iter().skip(1);
+		while let Some(item) = iter.next().unwrap() {
+			assert_eq!(expected.next().map(|(k, v)| (k.clone(), v.clone())), Some(item));
+		}
+		assert_eq!(expected.next(), None);
+		drop(iter);
+
+		let mut iter = snapshot.iter(1).unwrap();
+		iter.seek_to_last().unwrap();
+		let mut expected = state.iter().rev();
+		for _ in 0..10 {
+			let item = iter.prev().unwrap();
+			assert_eq!(expected.next().map(|(k, v)| (k.clone(), v.clone())), item);
+		}
+		// Change direction mid way.
+		assert_eq!(iter.next().unwrap(), Some((vec![22], vec![11])));
+		iter.seek(&[5]).unwrap();
+		assert_eq!(iter.next().unwrap(), Some((vec![6], vec![3])));
+		assert_eq!(iter.prev().unwrap(), Some((vec![4], vec![2])));
+		drop(iter);
+		drop(snapshot);
+		assert!(db.inner.snapshots.lock().is_empty());
+	}
+
+	#[test]
+	fn test_iter_consistent() {
+		for stage in [
+			EnableCommitPipelineStages::CommitOverlay,
+			EnableCommitPipelineStages::LogOverlay,
+			EnableCommitPipelineStages::DbFile,
+			EnableCommitPipelineStages::Standard,
+		] {
+			let tmp = tempdir().unwrap();
+			let mut options = stage.options(tmp.path(), 1);
+			options.columns[0].btree_index = true;
+			let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+			let state: BTreeMap<Vec<u8>, Vec<u8>> =
+				(0u8..100).map(|i| (vec![i], vec![i])).collect();
+			db.commit(state.iter().map(|(k, v)| (0, k.clone(), Some(v.clone())))).unwrap();
+			stage.run_stages(&db);
+
+			let mut iter = db.iter_consistent(0).unwrap();
+			let mut expected = state.iter().map(|(k, v)| (k.clone(), v.clone()));
+			for i in 0u8..100 {
+				assert_eq!(iter.next().unwrap(), expected.next());
+				let key = 99 - i;
+				db.commit(vec![(0, vec![key], None), (0, vec![key, 0], Some(vec![key]))])
+					.unwrap();
+				stage.run_stages(&db);
+			}
+			assert_eq!(iter.next().unwrap(), None);
+			drop(iter);
+			assert!(db.inner.snapshots.lock().is_empty());
+			assert_eq!(db.get(0, &[0]).unwrap(), None);
+		}
+	}
+
 	#[cfg(feature = "instrumentation")]
 	#[test]
 	fn test_partial_log_recovery() {
@@ -0,0 +1,161 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Tracking the progress of commits and committing from async code.
+
+use super::{Db, DbInner};
+use crate::error::Result;
+#[cfg(feature = "asyncdb")]
+use crate::{column::ColId, db::Operation, Value};
+
+/// Progress of a commit, created with [`Db::commit_handle`].
+#[derive(Debug)]
+pub struct CommitHandle<'a> {
+	db: &'a DbInner,
+	id: u64,
+}
+
+impl<'a> CommitHandle<'a> {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	/// Whether the commit is written and flushed to the log file. It is only crash-safe once
+	/// the log file is synced, see `Options::sync_wal`.
+	pub fn is_logged(&self) -> bool {
+		self.db.durability.lock().flushed >= self.id
+	}
+
+	/// Block until the commit is written to the log file and the file is synced, so that the
+	/// commit survives a crash. The log is synced if `Options::sync_wal` is not set.
+	pub fn wait_durable(&self) -> Result<()> {
+		self.db.wait_durable(self.id)
+	}
+
+	/// Whether the commit is enacted into the column tables.
+	pub fn is_enacted(&self) -> bool {
+		self.db.durability.lock().enacted >= self.id
+	}
+
+	/// Block until the commit is enacted into the column tables.
+	pub fn wait_enacted(&self) -> Result<()> {
+		self.db.wait_enacted(self.id)
+	}
+}
+
+impl Db {
+	/// Track the progress of commit `commit_id`, as returned by a commit to this database since
+	/// it was opened.
+	pub fn commit_handle(&self, commit_id: u64) -> CommitHandle<'_> {
+		CommitHandle { db: &self.inner, id: commit_id }
+	}
+
+	/// Commit on a background thread. The returned future resolves once the commit is durable,
+	/// as with `CommitHandle::wait_durable`.
+	#[cfg(feature = "asyncdb")]
+	pub fn commit_async<I>(&self, tx: I) -> crate::asyncdb::DbFuture<Result<()>>
+	where
+		I: IntoIterator<Item = (ColId, Operation<Vec<u8>, Vec<u8>>)> + Send + 'static,
+	{
+		let db = self.inner.clone();
+		self.async_pool.spawn(move || {
+			let id = db.commit_changes(tx)?;
+			db.wait_durable(id)
+		})
+	}
+
+	/// Query a value on a background thread.
+	#[cfg(feature = "asyncdb")]
+	pub fn get_async(
+		&self,
+		col: ColId,
+		key: Vec<u8>,
+	) -> crate::asyncdb::DbFuture<Result<Option<Value>>> {
+		let db = self.inner.clone();
+		self.async_pool.spawn(move || db.get(col, &key))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::EnableCommitPipelineStages;
+	#[cfg(feature = "asyncdb")]
+	use crate::asyncdb::tests::block_on;
+	use crate::db::{Db, OpeningMode, Operation};
+	use std::{path::Path, sync::Arc};
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_commit_handle() {
+		test_commit_handle_inner(EnableCommitPipelineStages::CommitOverlay);
+		test_commit_handle_inner(EnableCommitPipelineStages::Standard);
+	}
+
+	fn test_commit_handle_inner(db_test: EnableCommitPipelineStages) {
+		let tmp = tempdir().unwrap();
+		let options = db_test.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let first = db.commit_handle(db.commit(vec![(0, b"a", Some(vec![1]))]).unwrap());
+		let second = db.commit_handle(db.commit(vec![(0, b"b", Some(vec![2]))]).unwrap());
+		if db_test == EnableCommitPipelineStages::CommitOverlay {
+			assert!(!first.is_logged());
+			assert!(!first.is_enacted());
+		}
+		first.wait_durable().unwrap();
+		assert!(first.is_logged());
+		second.wait_enacted().unwrap();
+		assert!(first.is_enacted());
+		assert!(second.is_logged());
+		assert!(second.is_enacted());
+		assert!(db.inner.commit_overlay.read()[0].is_empty());
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_wait_durable_syncs() {
+		use crate::{Fault, FaultRule, FaultyStorage, IoOp, MemoryStorage};
+
+		let storage = Arc::new(FaultyStorage::new(Arc::new(MemoryStorage::default())));
+		let mut options = EnableCommitPipelineStages::Standard.options(Path::new("db"), 1);
+		options.storage = Some(storage.clone());
+		options.sync_wal = false;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		storage.inject(FaultRule {
+			op: IoOp::Sync,
+			file_prefix: "log".into(),
+			skip: 0,
+			times: None,
+			fault: Fault::Fail(std::io::ErrorKind::Other),
+		});
+
+		// Waiting for a flush does not sync the log without `sync_wal`, waiting for
+		// durability does.
+		let options = crate::CommitOptions { sync: None, wait: true };
+		db.commit_with(vec![(0, Operation::Set(b"a".to_vec(), vec![1]))], options)
+			.unwrap();
+		let id = db.commit(vec![(0, b"b", Some(vec![2]))]).unwrap();
+		assert!(db.commit_handle(id).wait_durable().is_err());
+		#[cfg(feature = "asyncdb")]
+		{
+			let commit = db.commit_async(vec![(0, Operation::Set(b"c".to_vec(), vec![3]))]);
+			assert!(block_on(commit).is_err());
+		}
+		storage.clear();
+		assert!(db.commit_handle(id).wait_durable().is_ok());
+	}
+
+	#[cfg(feature = "asyncdb")]
+	#[test]
+	fn test_async_commit_and_get() {
+		let tmp = tempdir().unwrap();
+		let options = EnableCommitPipelineStages::Standard.options(tmp.path(), 1);
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+
+		let commit = db.commit_async(vec![(0, Operation::Set(b"key".to_vec(), b"value".to_vec()))]);
+		block_on(commit).unwrap();
+		assert!(db.inner.durability.lock().flushed >= 1);
+		assert_eq!(block_on(db.get_async(0, b"key".to_vec())).unwrap(), Some(b"value".to_vec()));
+		assert_eq!(block_on(db.get_async(0, b"other".to_vec())).unwrap(), None);
+	}
+}
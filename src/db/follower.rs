@@ -0,0 +1,170 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Databases opened with `Db::open_follower`, which track another process writing to the same
+//! directory.
+
+use super::{Db, DbInner, OpeningMode};
+use crate::{
+	error::{Error, Result},
+	log::LogAction,
+	options::Options,
+};
+
+impl Db {
+	/// Open the database as a follower of another process that has it open for writing. The
+	/// follower takes no lock, never modifies any files and refuses commits. It sees at least
+	/// the commits written to the log up to the last call to `catch_up`. This is not a
+	/// snapshot: reads go to the live table files, so commits the owner enacted since may be
+	/// visible too, and a value the owner changes or removes concurrently may be read as its
+	/// new state, as missing, or fail with an error. Values the owner does not touch are read
+	/// consistently, including while it reindexes. Columns added, cleared or dropped by the
+	/// owner require reopening the follower.
+	pub fn open_follower(options: &Options) -> Result<Db> {
+		Self::open_inner(options, OpeningMode::Follower)
+	}
+
+	/// Update the view of a database opened with `open_follower` with the new log records of
+	/// the owner.
+	pub fn catch_up(&self) -> Result<()> {
+		if !self.inner.follower {
+			return Err(Error::InvalidInput("Database is not opened as a follower".into()))
+		}
+		self.inner.catch_up()
+	}
+}
+
+impl DbInner {
+	// Reload the log overlays of a read-only database from the log files, which are owned by
+	// another process or left over from it.
+	pub(super) fn catch_up(&self) -> Result<()> {
+		for column in self.columns.iter() {
+			column.refresh()?;
+		}
+		self.log.follow(self.columns.len(), |action, reader, writer| {
+			let col = match &action {
+				LogAction::InsertIndex(record) => record.table.col(),
+				LogAction::InsertValue(record) => record.table.col(),
+				LogAction::DropTable(id) => id.col(),
+				_ => return Err(Error::LogCorruption("Unexpected log action".into())),
+			};
+			match self.columns.get(col as usize) {
+				Some(column) => column.follow_plan(action, reader, writer),
+				None => Err(Error::InvalidConfiguration(format!(
+					"Column {} was added, the follower must be reopened",
+					col
+				))),
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::super::tests::EnableCommitPipelineStages;
+	use crate::db::{Db, OpeningMode};
+	use std::sync::Arc;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_follower() {
+		let tmp = tempdir().unwrap();
+		let db_test = EnableCommitPipelineStages::DbFile;
+		let mut options = db_test.options(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		let follower = Db::open_follower(&options).unwrap();
+		assert!(follower.commit(vec![(0, b"key", Some(vec![0]))]).is_err());
+		assert!(db.catch_up().is_err());
+
+		db.commit(vec![(0, b"key", Some(vec![1])), (1, b"key", Some(vec![1]))]).unwrap();
+		// Logged, but not enacted.
+		while db.inner.process_commits().unwrap() {}
+		assert_eq!(follower.get(0, b"key").unwrap(), None);
+		follower.catch_up().unwrap();
+		assert_eq!(follower.get(0, b"key").unwrap(), Some(vec![1]));
+		assert_eq!(follower.get(1, b"key").unwrap(), Some(vec![1]));
+
+		db.commit(vec![
+			(0, b"key".to_vec(), Some(vec![2])),
+			(1, b"key".to_vec(), None),
+			(1, b"other".to_vec(), Some(vec![2])),
+		])
+		.unwrap();
+		db_test.run_stages(&db);
+		// All logs are enacted and recycled.
+		follower.catch_up().unwrap();
+		assert!(follower.inner.log.overlays().read().is_empty());
+		assert_eq!(follower.get(0, b"key").unwrap(), Some(vec![2]));
+		assert_eq!(follower.get(1, b"key").unwrap(), None);
+		let mut iter = follower.iter(1).unwrap();
+		iter.seek_to_first().unwrap();
+		assert_eq!(iter.next().unwrap(), Some((b"other".to_vec(), vec![2])));
+		assert_eq!(iter.next().unwrap(), None);
+	}
+
+	#[test]
+	fn test_follower_concurrent_reindex() {
+		use std::sync::atomic::{AtomicBool, Ordering};
+
+		let tmp = tempdir().unwrap();
+		let mut options = EnableCommitPipelineStages::DbFile.options(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		let key = |i: u32| {
+			let mut key = [0xff; 32];
+			if i < 65 {
+				// More than a chunk of keys sharing the first 16 bits, forcing a reindex.
+				key[..3].copy_from_slice(&[0, 0, i as u8]);
+			} else {
+				key[..4].copy_from_slice(&(i << 16).to_be_bytes());
+			}
+			key
+		};
+		let db = Db::open_inner(&options, OpeningMode::Create).unwrap();
+		db.commit((0..60u32).map(|i| (0, key(i).to_vec(), Some(i.to_le_bytes().to_vec()))))
+			.unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		let follower = Db::open_follower(&options).unwrap();
+		let done = Arc::new(AtomicBool::new(false));
+		let reader = {
+			let done = done.clone();
+			std::thread::spawn(move || {
+				let mut rounds = 0;
+				while !done.load(Ordering::Relaxed) || rounds < 10 {
+					follower.catch_up().unwrap();
+					for i in 0..60u32 {
+						assert_eq!(
+							follower.get(0, &key(i)).unwrap(),
+							Some(i.to_le_bytes().to_vec())
+						);
+					}
+					rounds += 1;
+				}
+				follower.catch_up().unwrap();
+				for i in 0..2000u32 {
+					assert_eq!(follower.get(0, &key(i)).unwrap(), Some(i.to_le_bytes().to_vec()));
+				}
+			})
+		};
+		let inner = &db.inner;
+		for batch in 0..20u32 {
+			let keys = if batch == 0 { 60..100 } else { batch * 100..batch * 100 + 100 };
+			db.commit(keys.map(|i| (0, key(i).to_vec(), Some(i.to_le_bytes().to_vec()))))
+				.unwrap();
+			while inner.process_commits().unwrap() {}
+			loop {
+				inner.flush_logs(0).unwrap();
+				while inner.enact_logs(false).unwrap() {}
+				if !inner.process_reindex().unwrap() {
+					break
+				}
+			}
+		}
+		let crate::column::Column::Hash(column) = &inner.columns[0] else {
+			panic!("Hash column expected")
+		};
+		assert_ne!(column.index_state().0, 16);
+		done.store(true, Ordering::Relaxed);
+		reader.join().unwrap();
+	}
+}
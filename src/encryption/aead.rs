@@ -0,0 +1,325 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! XChaCha20-Poly1305 authenticated encryption, the ChaCha20-Poly1305 construction of RFC 8439
+//! with the extended nonce of draft-irtf-cfrg-xchacha-03.
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn le32(bytes: &[u8]) -> u32 {
+	u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes"))
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+	u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes"))
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	s[a] = s[a].wrapping_add(s[b]);
+	s[d] = (s[d] ^ s[a]).rotate_left(16);
+	s[c] = s[c].wrapping_add(s[d]);
+	s[b] = (s[b] ^ s[c]).rotate_left(12);
+	s[a] = s[a].wrapping_add(s[b]);
+	s[d] = (s[d] ^ s[a]).rotate_left(8);
+	s[c] = s[c].wrapping_add(s[d]);
+	s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+// ChaCha state for `key` and the counter and nonce words in `input`, after the 20 rounds.
+fn chacha_rounds(key: &[u8; KEY_SIZE], input: &[u8; 16]) -> ([u32; 16], [u32; 16]) {
+	let mut init = [0u32; 16];
+	init[..4].copy_from_slice(&SIGMA);
+	for i in 0..8 {
+		init[4 + i] = le32(&key[4 * i..]);
+	}
+	for i in 0..4 {
+		init[12 + i] = le32(&input[4 * i..]);
+	}
+	let mut s = init;
+	for _ in 0..10 {
+		quarter_round(&mut s, 0, 4, 8, 12);
+		quarter_round(&mut s, 1, 5, 9, 13);
+		quarter_round(&mut s, 2, 6, 10, 14);
+		quarter_round(&mut s, 3, 7, 11, 15);
+		quarter_round(&mut s, 0, 5, 10, 15);
+		quarter_round(&mut s, 1, 6, 11, 12);
+		quarter_round(&mut s, 2, 7, 8, 13);
+		quarter_round(&mut s, 3, 4, 9, 14);
+	}
+	(init, s)
+}
+
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+	let mut input = [0u8; 16];
+	input[..4].copy_from_slice(&counter.to_le_bytes());
+	input[4..].copy_from_slice(nonce);
+	let (init, s) = chacha_rounds(key, &input);
+	let mut block = [0u8; 64];
+	for i in 0..16 {
+		block[4 * i..4 * i + 4].copy_from_slice(&s[i].wrapping_add(init[i]).to_le_bytes());
+	}
+	block
+}
+
+fn chacha20_xor(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; 12], data: &mut [u8]) {
+	for (i, chunk) in data.chunks_mut(64).enumerate() {
+		let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+		for (b, k) in chunk.iter_mut().zip(block) {
+			*b ^= k;
+		}
+	}
+}
+
+fn hchacha20(key: &[u8; KEY_SIZE], nonce: &[u8; 16]) -> [u8; KEY_SIZE] {
+	let (_, s) = chacha_rounds(key, nonce);
+	let mut subkey = [0u8; KEY_SIZE];
+	for (i, word) in s[..4].iter().chain(&s[12..]).enumerate() {
+		subkey[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+	}
+	subkey
+}
+
+const MASK44: u64 = (1 << 44) - 1;
+const MASK42: u64 = (1 << 42) - 1;
+
+// Poly1305 with 44, 44 and 42 bit limbs.
+struct Poly1305 {
+	r: [u64; 3],
+	h: [u64; 3],
+	pad: [u64; 2],
+}
+
+impl Poly1305 {
+	fn new(key: &[u8; 32]) -> Self {
+		let (t0, t1) = (le64(key), le64(&key[8..]));
+		Poly1305 {
+			r: [
+				t0 & 0xffc_0fff_ffff,
+				((t0 >> 44) | (t1 << 20)) & 0xfff_ffc0_ffff,
+				(t1 >> 24) & 0x00f_ffff_fc0f,
+			],
+			h: [0; 3],
+			pad: [le64(&key[16..]), le64(&key[24..])],
+		}
+	}
+
+	fn block(&mut self, m: &[u8; 16], hibit: u64) {
+		let [r0, r1, r2] = self.r.map(u128::from);
+		let (s1, s2) = (r1 * 20, r2 * 20);
+		let (t0, t1) = (le64(m), le64(&m[8..]));
+		let h0 = u128::from(self.h[0] + (t0 & MASK44));
+		let h1 = u128::from(self.h[1] + (((t0 >> 44) | (t1 << 20)) & MASK44));
+		let h2 = u128::from(self.h[2] + (((t1 >> 24) & MASK42) | hibit));
+		let d0 = h0 * r0 + h1 * s2 + h2 * s1;
+		let mut d1 = h0 * r1 + h1 * r0 + h2 * s2;
+		let mut d2 = h0 * r2 + h1 * r1 + h2 * r0;
+		d1 += d0 >> 44;
+		d2 += d1 >> 44;
+		let mut h0 = (d0 as u64 & MASK44) + (d2 >> 42) as u64 * 5;
+		let h1 = (d1 as u64 & MASK44) + (h0 >> 44);
+		h0 &= MASK44;
+		self.h = [h0, h1, d2 as u64 & MASK42];
+	}
+
+	// Process `data`, the last partial block padded as the RFC 8439 Poly1305 algorithm does.
+	fn update(&mut self, data: &[u8]) {
+		let mut chunks = data.chunks_exact(16);
+		for chunk in &mut chunks {
+			self.block(chunk.try_into().expect("16 bytes"), 1 << 40);
+		}
+		let rest = chunks.remainder();
+		if !rest.is_empty() {
+			let mut last = [0u8; 16];
+			last[..rest.len()].copy_from_slice(rest);
+			last[rest.len()] = 1;
+			self.block(&last, 0);
+		}
+	}
+
+	// Process `data` zero padded to a multiple of 16 bytes, as the AEAD construction does.
+	fn update_padded(&mut self, data: &[u8]) {
+		let mut chunks = data.chunks_exact(16);
+		for chunk in &mut chunks {
+			self.block(chunk.try_into().expect("16 bytes"), 1 << 40);
+		}
+		let rest = chunks.remainder();
+		if !rest.is_empty() {
+			let mut last = [0u8; 16];
+			last[..rest.len()].copy_from_slice(rest);
+			self.block(&last, 1 << 40);
+		}
+	}
+
+	fn finalize(self) -> [u8; TAG_SIZE] {
+		let [mut h0, mut h1, mut h2] = self.h;
+		let mut c = h1 >> 44;
+		h1 &= MASK44;
+		h2 += c;
+		c = h2 >> 42;
+		h2 &= MASK42;
+		h0 += c * 5;
+		c = h0 >> 44;
+		h0 &= MASK44;
+		h1 += c;
+		c = h1 >> 44;
+		h1 &= MASK44;
+		h2 += c;
+		c = h2 >> 42;
+		h2 &= MASK42;
+		h0 += c * 5;
+		c = h0 >> 44;
+		h0 &= MASK44;
+		h1 += c;
+
+		// h - p, selected in constant time if h >= p.
+		let mut g0 = h0 + 5;
+		c = g0 >> 44;
+		g0 &= MASK44;
+		let mut g1 = h1 + c;
+		c = g1 >> 44;
+		g1 &= MASK44;
+		let mut g2 = (h2 + c).wrapping_sub(1 << 42);
+		let select = (g2 >> 63).wrapping_sub(1);
+		g0 &= select;
+		g1 &= select;
+		g2 &= select;
+		h0 = (h0 & !select) | g0;
+		h1 = (h1 & !select) | g1;
+		h2 = (h2 & !select) | g2;
+
+		let [t0, t1] = self.pad;
+		h0 += t0 & MASK44;
+		c = h0 >> 44;
+		h0 &= MASK44;
+		h1 += (((t0 >> 44) | (t1 << 20)) & MASK44) + c;
+		c = h1 >> 44;
+		h1 &= MASK44;
+		h2 = (h2 + ((t1 >> 24) & MASK42) + c) & MASK42;
+
+		let mut tag = [0u8; TAG_SIZE];
+		tag[..8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+		tag[8..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+		tag
+	}
+}
+
+// Subkey and ChaCha20 nonce for an extended nonce.
+fn derive(key: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> ([u8; KEY_SIZE], [u8; 12]) {
+	let subkey = hchacha20(key, nonce[..16].try_into().expect("16 bytes"));
+	let mut chacha_nonce = [0u8; 12];
+	chacha_nonce[4..].copy_from_slice(&nonce[16..]);
+	(subkey, chacha_nonce)
+}
+
+fn tag(subkey: &[u8; KEY_SIZE], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+	let block = chacha20_block(subkey, 0, nonce);
+	let mut poly = Poly1305::new(block[..32].try_into().expect("32 bytes"));
+	poly.update_padded(aad);
+	poly.update_padded(ciphertext);
+	let mut lengths = [0u8; 16];
+	lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+	lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+	poly.update(&lengths);
+	poly.finalize()
+}
+
+/// Encrypt `data` in place and return the authentication tag of it and `aad`.
+pub fn seal(
+	key: &[u8; KEY_SIZE],
+	nonce: &[u8; NONCE_SIZE],
+	aad: &[u8],
+	data: &mut [u8],
+) -> [u8; TAG_SIZE] {
+	let (subkey, nonce) = derive(key, nonce);
+	chacha20_xor(&subkey, 1, &nonce, data);
+	tag(&subkey, &nonce, aad, data)
+}
+
+/// Check the tag of `data` and `aad` and decrypt `data` in place. Returns `false`, leaving
+/// `data` unchanged, if the tag does not match.
+pub fn open(
+	key: &[u8; KEY_SIZE],
+	nonce: &[u8; NONCE_SIZE],
+	aad: &[u8],
+	data: &mut [u8],
+	expected: &[u8],
+) -> bool {
+	let (subkey, nonce) = derive(key, nonce);
+	let tag = tag(&subkey, &nonce, aad, data);
+	if expected.len() != TAG_SIZE ||
+		tag.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0
+	{
+		return false
+	}
+	chacha20_xor(&subkey, 1, &nonce, data);
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+	fn unhex(s: &str) -> Vec<u8> {
+		hex::decode(s).unwrap()
+	}
+
+	#[test]
+	fn rfc8439_vectors() {
+		// Section 2.4.2
+		let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+		let mut data = SUNSCREEN.to_vec();
+		chacha20_xor(&key, 1, &unhex("000000000000004a00000000").try_into().unwrap(), &mut data);
+		assert_eq!(data[..16], unhex("6e2e359a2568f98041ba0728dd0d6981"));
+
+		// Section 2.5.2
+		let mut poly = Poly1305::new(
+			&unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b")
+				.try_into()
+				.unwrap(),
+		);
+		poly.update(b"Cryptographic Forum Research Group");
+		assert_eq!(poly.finalize().to_vec(), unhex("a8061dc1305136c6c22b8baf0c0127a9"));
+	}
+
+	#[test]
+	fn xchacha20_poly1305_vectors() {
+		// draft-irtf-cfrg-xchacha-03 section 2.2.1
+		let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+		let nonce = unhex("000000090000004a0000000031415927");
+		assert_eq!(
+			hchacha20(&key, &nonce.try_into().unwrap()).to_vec(),
+			unhex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc")
+		);
+
+		// Section A.3.1
+		let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+		let nonce: [u8; 24] = std::array::from_fn(|i| 0x40 + i as u8);
+		let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+		let mut data = SUNSCREEN.to_vec();
+		let tag = seal(&key, &nonce, &aad, &mut data);
+		assert_eq!(
+			data,
+			unhex(
+				"bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+				 731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+				 2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+				 21f9664c97637da9768812f615c68b13b52e"
+			)
+		);
+		assert_eq!(tag.to_vec(), unhex("c0875924c1c7987947deafd8780acf49"));
+		assert!(open(&key, &nonce, &aad, &mut data, &tag));
+		assert_eq!(data, SUNSCREEN);
+
+		let mut modified = data.clone();
+		let tag = seal(&key, &nonce, &aad, &mut modified);
+		assert!(!open(&key, &nonce, b"other", &mut modified.clone(), &tag));
+		modified[0] ^= 1;
+		assert!(!open(&key, &nonce, &aad, &mut modified, &tag));
+	}
+}
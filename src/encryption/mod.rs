@@ -0,0 +1,265 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Encryption of column values at rest.
+//!
+//! Each value is encrypted with XChaCha20-Poly1305 under the column key and a random 192-bit
+//! nonce. The key id, column, size tier and entry index are authenticated with the value, so
+//! that values can't be swapped between entries. Encrypted values are stored as
+//! `key id ++ nonce ++ ciphertext ++ tag`.
+
+mod aead;
+
+use crate::{
+	column::ColId,
+	error::{Error, Result},
+};
+use aead::{NONCE_SIZE, TAG_SIZE};
+use parking_lot::RwLock;
+use rand::Rng;
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Size of an encryption key in bytes.
+pub const KEY_SIZE: usize = aead::KEY_SIZE;
+const KEY_ID_SIZE: usize = 4;
+/// Bytes added to each encrypted value.
+pub const OVERHEAD: usize = KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// Column encryption key.
+pub type EncryptionKey = [u8; KEY_SIZE];
+
+/// Supplies the keys of an encrypted column. Keys are identified by a number stored with each
+/// value, so that values encrypted with a previous key can still be read.
+pub trait KeyProvider: Send + Sync {
+	/// Id of the key used to encrypt new values.
+	fn current_key_id(&self) -> u32;
+	/// Key with the given id, if known.
+	fn key(&self, id: u32) -> Option<EncryptionKey>;
+}
+
+/// A single key, with id 0.
+pub struct StaticKey(pub EncryptionKey);
+
+impl KeyProvider for StaticKey {
+	fn current_key_id(&self) -> u32 {
+		0
+	}
+
+	fn key(&self, id: u32) -> Option<EncryptionKey> {
+		(id == 0).then_some(self.0)
+	}
+}
+
+/// A set of keys that may be rotated while the database is open. The key with the highest id
+/// is the current one.
+pub struct KeyRing(RwLock<BTreeMap<u32, EncryptionKey>>);
+
+impl KeyRing {
+	/// Create a key ring with the given keys. At least one key is required.
+	pub fn new(keys: impl IntoIterator<Item = (u32, EncryptionKey)>) -> Self {
+		let keys: BTreeMap<_, _> = keys.into_iter().collect();
+		assert!(!keys.is_empty(), "A key ring requires at least one key");
+		KeyRing(RwLock::new(keys))
+	}
+
+	/// Add a new current key and return its id. Values are encrypted with it from now on, see
+	/// `Db::reencrypt_column` for rewriting existing values.
+	pub fn rotate(&self, key: EncryptionKey) -> u32 {
+		let mut keys = self.0.write();
+		let id = keys.keys().next_back().map_or(0, |id| id + 1);
+		keys.insert(id, key);
+		id
+	}
+
+	/// Forget a key that is no longer used. The current key can't be removed.
+	pub fn remove(&self, id: u32) -> bool {
+		let mut keys = self.0.write();
+		if keys.keys().next_back() == Some(&id) {
+			return false
+		}
+		keys.remove(&id).is_some()
+	}
+}
+
+impl KeyProvider for KeyRing {
+	fn current_key_id(&self) -> u32 {
+		*self.0.read().keys().next_back().expect("Key rings are not empty")
+	}
+
+	fn key(&self, id: u32) -> Option<EncryptionKey> {
+		self.0.read().get(&id).copied()
+	}
+}
+
+// Stands for the key provider of an encrypted column loaded from the metadata.
+struct NoKeys;
+
+impl KeyProvider for NoKeys {
+	fn current_key_id(&self) -> u32 {
+		0
+	}
+
+	fn key(&self, _id: u32) -> Option<EncryptionKey> {
+		None
+	}
+}
+
+/// Encryption of the values of a column, see `ColumnOptions::encryption`.
+#[derive(Clone)]
+pub struct Encryption(pub Arc<dyn KeyProvider>);
+
+impl Encryption {
+	pub fn new(provider: impl KeyProvider + 'static) -> Self {
+		Encryption(Arc::new(provider))
+	}
+
+	pub(crate) fn missing_keys() -> Self {
+		Encryption(Arc::new(NoKeys))
+	}
+
+	fn key(&self, id: u32) -> Result<EncryptionKey> {
+		self.0
+			.key(id)
+			.ok_or_else(|| Error::InvalidConfiguration(format!("Missing encryption key {}", id)))
+	}
+
+	// Associated data binding a value to its key and location.
+	fn aad(id: u32, col: ColId, tier: u8, index: u64) -> [u8; 14] {
+		let mut aad = [0u8; 14];
+		aad[..4].copy_from_slice(&id.to_le_bytes());
+		aad[4] = col;
+		aad[5] = tier;
+		aad[6..].copy_from_slice(&index.to_le_bytes());
+		aad
+	}
+
+	/// Check if `data` written by `encrypt` uses a known key other than the current one.
+	pub fn is_stale(&self, data: &[u8]) -> bool {
+		if data.len() < KEY_ID_SIZE {
+			return false
+		}
+		let id = u32::from_le_bytes(data[..KEY_ID_SIZE].try_into().expect("4 bytes"));
+		id != self.0.current_key_id() && self.0.key(id).is_some()
+	}
+
+	/// Encrypt `value`, stored at entry `index` of value table `tier` of column `col`, with the
+	/// current key.
+	pub fn encrypt(&self, value: &[u8], col: ColId, tier: u8, index: u64) -> Result<Vec<u8>> {
+		let id = self.0.current_key_id();
+		let key = self.key(id)?;
+		let nonce: [u8; NONCE_SIZE] = rand::thread_rng().gen();
+		let mut data = Vec::with_capacity(value.len() + OVERHEAD);
+		data.extend_from_slice(&id.to_le_bytes());
+		data.extend_from_slice(&nonce);
+		data.extend_from_slice(value);
+		let aad = Self::aad(id, col, tier, index);
+		let tag = aead::seal(&key, &nonce, &aad, &mut data[KEY_ID_SIZE + NONCE_SIZE..]);
+		data.extend_from_slice(&tag);
+		Ok(data)
+	}
+
+	/// Authenticate and decrypt a value written by `encrypt` for the same location. Returns
+	/// `Ok(None)` if the data was modified, moved or encrypted with a different key.
+	pub fn decrypt(
+		&self,
+		data: &[u8],
+		col: ColId,
+		tier: u8,
+		index: u64,
+	) -> Result<Option<Vec<u8>>> {
+		if data.len() < OVERHEAD {
+			return Ok(None)
+		}
+		let id = u32::from_le_bytes(data[..KEY_ID_SIZE].try_into().expect("4 bytes"));
+		let key = self.key(id)?;
+		let nonce: [u8; NONCE_SIZE] =
+			data[KEY_ID_SIZE..KEY_ID_SIZE + NONCE_SIZE].try_into().expect("Nonce size");
+		let tag_at = data.len() - TAG_SIZE;
+		let mut value = data[KEY_ID_SIZE + NONCE_SIZE..tag_at].to_vec();
+		let aad = Self::aad(id, col, tier, index);
+		if !aead::open(&key, &nonce, &aad, &mut value, &data[tag_at..]) {
+			return Ok(None)
+		}
+		Ok(Some(value))
+	}
+}
+
+impl std::fmt::Debug for Encryption {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("Encryption")
+	}
+}
+
+// Providers are equal if they encrypt new values with the same key.
+impl PartialEq for Encryption {
+	fn eq(&self, other: &Self) -> bool {
+		if Arc::ptr_eq(&self.0, &other.0) {
+			return true
+		}
+		let id = self.0.current_key_id();
+		id == other.0.current_key_id() && self.0.key(id) == other.0.key(id)
+	}
+}
+
+impl Eq for Encryption {}
+
+#[cfg(test)]
+mod tests {
+	use super::{Encryption, KeyRing, StaticKey, OVERHEAD};
+	use std::sync::Arc;
+
+	#[test]
+	fn round_trip() {
+		let encryption = Encryption::new(StaticKey([1; 32]));
+		let value = b"secret value".to_vec();
+		let data = encryption.encrypt(&value, 1, 2, 3).unwrap();
+		assert_eq!(data.len(), value.len() + OVERHEAD);
+		assert!(!data.windows(value.len()).any(|w| w == value));
+		assert_eq!(encryption.decrypt(&data, 1, 2, 3).unwrap(), Some(value.clone()));
+		// Nonces are random.
+		assert_ne!(encryption.encrypt(&value, 1, 2, 3).unwrap(), data);
+		let empty = encryption.encrypt(&[], 1, 2, 3).unwrap();
+		assert_eq!(encryption.decrypt(&empty, 1, 2, 3).unwrap(), Some(vec![]));
+
+		let mut modified = data.clone();
+		modified[OVERHEAD] ^= 1;
+		assert_eq!(encryption.decrypt(&modified, 1, 2, 3).unwrap(), None);
+		assert_eq!(encryption.decrypt(&data[..OVERHEAD - 1], 1, 2, 3).unwrap(), None);
+		// Values are bound to their location.
+		assert_eq!(encryption.decrypt(&data, 0, 2, 3).unwrap(), None);
+		assert_eq!(encryption.decrypt(&data, 1, 0, 3).unwrap(), None);
+		assert_eq!(encryption.decrypt(&data, 1, 2, 4).unwrap(), None);
+		let other = Encryption::new(StaticKey([2; 32]));
+		assert_eq!(other.decrypt(&data, 1, 2, 3).unwrap(), None);
+		assert!(Encryption::missing_keys().decrypt(&data, 1, 2, 3).is_err());
+	}
+
+	#[test]
+	fn key_rotation() {
+		let ring = Arc::new(KeyRing::new([(3, [1; 32])]));
+		let encryption = Encryption(ring.clone());
+		let old = encryption.encrypt(b"value", 0, 0, 1).unwrap();
+		assert!(!encryption.is_stale(&old));
+		assert_eq!(ring.rotate([2; 32]), 4);
+		assert!(encryption.is_stale(&old));
+		let new = encryption.encrypt(b"value", 0, 0, 1).unwrap();
+		assert!(!encryption.is_stale(&new));
+		assert_eq!(encryption.decrypt(&old, 0, 0, 1).unwrap(), Some(b"value".to_vec()));
+		assert!(!ring.remove(4));
+		assert!(ring.remove(3));
+		assert!(encryption.decrypt(&old, 0, 0, 1).is_err());
+		assert_eq!(encryption.decrypt(&new, 0, 0, 1).unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn equality() {
+		let ring = Arc::new(KeyRing::new([(0, [1; 32])]));
+		let encryption = Encryption(ring.clone());
+		assert_eq!(encryption, encryption.clone());
+		assert_eq!(encryption, Encryption::new(StaticKey([1; 32])));
+		assert_ne!(encryption, Encryption::new(StaticKey([2; 32])));
+		assert_ne!(encryption, Encryption::missing_keys());
+		ring.rotate([1; 32]);
+		assert_ne!(encryption, Encryption::new(StaticKey([1; 32])));
+	}
+}
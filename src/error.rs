@@ -1,43 +1,127 @@
 // Copyright 2021-2022 Parity Technologies (UK) Ltd.
 // This file is dual-licensed as Apache-2.0 or MIT.
 
-use crate::column::ColId;
+use crate::{column::ColId, lock::LockHolder};
 #[cfg(feature = "instrumentation")]
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::{fmt, io, sync::Arc};
+use std::{fmt, io, path::PathBuf, sync::Arc};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
 	Io(io::Error),
+	/// I/O error on a database file, with the operation that failed.
+	FileIo {
+		path: PathBuf,
+		operation: &'static str,
+		source: io::Error,
+	},
+	/// Damaged database data not covered by the variants below, such as metadata, backups and
+	/// dumps.
 	Corruption(String),
+	/// A hash index entry points to a value that can't be found.
+	IndexCorruption {
+		col: ColId,
+		reason: String,
+	},
+	/// A value table entry is malformed.
+	ValueTableCorruption {
+		col: ColId,
+		tier: u8,
+		reason: String,
+	},
+	/// A log record is damaged. See `Options::recovery_mode` for records found when the
+	/// database is opened.
+	LogCorruption(String),
 	InvalidConfiguration(String),
-	IncompatibleColumnConfig { id: ColId, reason: String },
+	IncompatibleColumnConfig {
+		id: ColId,
+		reason: String,
+	},
 	InvalidInput(String),
 	InvalidValueData,
 	Background(Arc<Error>),
-	Locked(io::Error),
+	Locked {
+		holder: Option<LockHolder>,
+		source: io::Error,
+	},
 	Migration(String),
 	Compression,
 	DatabaseNotFound,
+	ConditionFailed {
+		col: ColId,
+		key: Vec<u8>,
+	},
+	/// A value is larger than `ColumnOptions::max_value_size` or `MAX_VALUE_SIZE`.
+	ValueTooLarge {
+		limit: usize,
+		actual: usize,
+	},
+	/// The commit has more bytes of keys and values than `Options::max_commit_bytes`. `bytes`
+	/// are counted up to the change that exceeded the limit.
+	CommitTooLarge {
+		bytes: usize,
+		limit: usize,
+	},
+	ChecksumMismatch {
+		col: ColId,
+		tier: u8,
+		index: u64,
+	},
+	Decryption {
+		col: ColId,
+		tier: u8,
+		index: u64,
+	},
 }
 
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Error::Io(e) => write!(f, "IO Error: {}", e),
+			Error::FileIo { path, operation, source } =>
+				write!(f, "IO Error: {} {}: {}", operation, path.display(), source),
 			Error::Corruption(e) => write!(f, "Corruption: {}", e),
+			Error::IndexCorruption { col, reason } =>
+				write!(f, "Corruption: index of column {}: {}", col, reason),
+			Error::ValueTableCorruption { col, tier, reason } =>
+				write!(f, "Corruption: column {} table {:02x}: {}", col, tier, reason),
+			Error::LogCorruption(e) => write!(f, "Corruption: log: {}", e),
 			Error::InvalidConfiguration(e) => write!(f, "Invalid configuration: {}", e),
 			Error::IncompatibleColumnConfig { id, reason } =>
 				write!(f, "Invalid column {} configuration : {}", id, reason),
 			Error::InvalidInput(e) => write!(f, "Invalid input: {}", e),
 			Error::InvalidValueData => write!(f, "Invalid data in value table"),
 			Error::Background(e) => write!(f, "Background worker error: {}", e),
-			Error::Locked(e) => write!(f, "Database file is in use. ({})", e),
+			Error::Locked { holder: Some(holder), source } =>
+				write!(f, "Database file is in use by {}. ({})", holder, source),
+			Error::Locked { holder: None, source } =>
+				write!(f, "Database file is in use. ({})", source),
 			Error::Migration(e) => write!(f, "Migration error: {}", e),
 			Error::Compression => write!(f, "Compression error"),
 			Error::DatabaseNotFound => write!(f, "Database does not exist"),
+			Error::ConditionFailed { col, key } => write!(
+				f,
+				"Commit condition failed for column {} key {}",
+				col,
+				crate::display::hex(key)
+			),
+			Error::ValueTooLarge { limit, actual } =>
+				write!(f, "Value of {} bytes exceeds the limit of {} bytes", actual, limit),
+			Error::CommitTooLarge { bytes, limit } =>
+				write!(f, "Commit of {} bytes exceeds the limit of {} bytes", bytes, limit),
+			Error::ChecksumMismatch { col, tier, index } => write!(
+				f,
+				"Corruption: value checksum mismatch in column {} table {:02x} entry {}",
+				col, tier, index
+			),
+			Error::Decryption { col, tier, index } => write!(
+				f,
+				"Value in column {} table {:02x} entry {} failed to decrypt, it was modified or the key is wrong",
+				col, tier, index
+			),
 		}
 	}
 }
@@ -46,13 +130,63 @@ impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
 			Error::Io(e) => Some(e),
+			Error::FileIo { source, .. } => Some(source),
 			Error::Background(e) => e.source(),
-			Error::Locked(e) => Some(e),
+			Error::Locked { source, .. } => Some(source),
 			_ => None,
 		}
 	}
 }
 
+impl Error {
+	pub(crate) fn file_io(
+		path: &std::path::Path,
+		operation: &'static str,
+		source: io::Error,
+	) -> Self {
+		Error::FileIo { path: path.into(), operation, source }
+	}
+
+	/// Underlying I/O error kind, if this is an I/O error.
+	pub fn io_kind(&self) -> Option<io::ErrorKind> {
+		match self {
+			Error::Io(e) | Error::FileIo { source: e, .. } | Error::Locked { source: e, .. } =>
+				Some(e.kind()),
+			Error::Background(e) => e.io_kind(),
+			_ => None,
+		}
+	}
+
+	/// Whether the same call may succeed if it is retried later: transient I/O errors, a
+	/// database locked by another process, or a failed commit condition. Errors of background
+	/// threads are not, the database must be reopened.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			Error::Io(e) | Error::FileIo { source: e, .. } => matches!(
+				e.kind(),
+				io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+			),
+			Error::Locked { .. } | Error::ConditionFailed { .. } => true,
+			_ => false,
+		}
+	}
+
+	/// Whether database files are damaged. The database may be salvaged with
+	/// `Db::open_repair`, or removed and synced again.
+	pub fn is_corruption(&self) -> bool {
+		match self {
+			Error::Corruption(_) |
+			Error::IndexCorruption { .. } |
+			Error::ValueTableCorruption { .. } |
+			Error::LogCorruption(_) |
+			Error::InvalidValueData |
+			Error::ChecksumMismatch { .. } => true,
+			Error::Background(e) => e.is_corruption(),
+			_ => false,
+		}
+	}
+}
+
 #[cfg(feature = "instrumentation")]
 pub static IO_COUNTER_BEFORE_ERROR: AtomicUsize = AtomicUsize::new(usize::MAX);
 
@@ -61,32 +195,39 @@ pub fn set_number_of_allowed_io_operations(val: usize) {
 	IO_COUNTER_BEFORE_ERROR.store(val, Ordering::Relaxed);
 }
 
+#[cfg(feature = "instrumentation")]
+pub(crate) fn instrumented_failure() -> Result<()> {
+	if IO_COUNTER_BEFORE_ERROR
+		.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(v.saturating_sub(1)))
+		.unwrap() ==
+		0
+	{
+		return Err(Error::Io(io::Error::other("Instrumented failure")))
+	}
+	Ok(())
+}
+
 #[cfg(feature = "instrumentation")]
 macro_rules! try_io {
 	($e:expr) => {{
-		if crate::error::IO_COUNTER_BEFORE_ERROR
-			.fetch_update(
-				::std::sync::atomic::Ordering::SeqCst,
-				::std::sync::atomic::Ordering::SeqCst,
-				|v| Some(v.saturating_sub(1)),
-			)
-			.unwrap() == 0
-		{
-			Err(crate::error::Error::Io(::std::io::Error::new(
-				::std::io::ErrorKind::Other,
-				"Instrumented failure",
-			)))?
-		} else {
-			$e.map_err(crate::error::Error::Io)?
-		}
+		crate::error::instrumented_failure()?;
+		$e.map_err(crate::error::Error::Io)?
+	}};
+	($e:expr, $path:expr, $operation:expr) => {{
+		crate::error::instrumented_failure()?;
+		$e.map_err(|e| crate::error::Error::file_io($path, $operation, e))?
 	}};
 }
 
+/// Convert an I/O error, with the file `path` and the `operation` that failed if given.
 #[cfg(not(feature = "instrumentation"))]
 macro_rules! try_io {
 	($e:expr) => {{
 		$e.map_err(crate::error::Error::Io)?
 	}};
+	($e:expr, $path:expr, $operation:expr) => {{
+		$e.map_err(|e| crate::error::Error::file_io($path, $operation, e))?
+	}};
 }
 
 pub(crate) use try_io;
@@ -0,0 +1,369 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Portable column dump format.
+//!
+//! A dump starts with a header: magic, format version, flags, the salt used to hash the keys
+//! and a CRC32 of all that. It is followed by entry records, each holding the column id, the
+//! key, the reference count and the value, all length-prefixed and protected by a CRC32.
+//! Reference counts take 4 bytes, or 8 in dumps of columns with `wide_ref_counts`. A final
+//! record holds the number of entries. All integers are little endian.
+//!
+//! Keys of btree indexed, `uniform` and `store_keys` hash indexed columns are exported as is
+//! and can be imported into any column. Other hash indexed columns only keep salted key
+//! hashes, so their dumps can only be imported into such columns of a database with the same
+//! salt and `key_hash`. Values of `ttl` columns are exported with their expiry time and
+//! require a `ttl` column on import.
+
+use crate::{
+	column::{ColId, IterState, Salt},
+	db::{CommitChangeSet, Db, IndexedChangeSet, Operation},
+	error::{try_io, Error, Result},
+	Key,
+};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"PDBDUMP\0";
+const FORMAT_VERSION: u8 = 1;
+const COMMIT_SIZE: usize = 10240;
+
+const FLAG_RAW_KEYS: u8 = 1;
+const FLAG_TTL: u8 = 2;
+const FLAG_WIDE_RC: u8 = 4;
+// `KeyHash` of hashed keys.
+const KEY_HASH_SHIFT: u8 = 4;
+
+const RECORD_END: u8 = 0;
+const RECORD_ENTRY: u8 = 1;
+
+struct Header {
+	flags: u8,
+	salt: Salt,
+}
+
+impl Header {
+	fn write(&self, writer: &mut impl Write) -> Result<()> {
+		let mut buf = Vec::with_capacity(MAGIC.len() + 2 + self.salt.len() + 4);
+		buf.extend_from_slice(MAGIC);
+		buf.push(FORMAT_VERSION);
+		buf.push(self.flags);
+		buf.extend_from_slice(&self.salt);
+		let crc = crc32fast::hash(&buf);
+		buf.extend_from_slice(&crc.to_le_bytes());
+		try_io!(writer.write_all(&buf));
+		Ok(())
+	}
+
+	fn read(reader: &mut impl Read) -> Result<Header> {
+		let mut buf = [0u8; 8 + 2 + 32];
+		try_io!(reader.read_exact(&mut buf));
+		let crc = read_u32(reader)?;
+		if &buf[..8] != MAGIC {
+			return Err(Error::InvalidInput("Not a database dump".into()))
+		}
+		if crc32fast::hash(&buf) != crc {
+			return Err(Error::Corruption("Dump header checksum mismatch".into()))
+		}
+		if buf[8] != FORMAT_VERSION {
+			return Err(Error::InvalidInput(format!("Unsupported dump version {}", buf[8])))
+		}
+		let mut salt = Salt::default();
+		salt.copy_from_slice(&buf[10..]);
+		Ok(Header { flags: buf[9], salt })
+	}
+}
+
+struct Entry {
+	col: ColId,
+	key: Vec<u8>,
+	rc: u64,
+	value: Vec<u8>,
+}
+
+fn write_entry(writer: &mut impl Write, entry: &Entry, wide_rc: bool) -> Result<()> {
+	let mut buf = Vec::with_capacity(14 + entry.key.len() + entry.value.len());
+	buf.push(RECORD_ENTRY);
+	buf.push(entry.col);
+	buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+	buf.extend_from_slice(&entry.key);
+	if wide_rc {
+		buf.extend_from_slice(&entry.rc.to_le_bytes());
+	} else {
+		buf.extend_from_slice(&(entry.rc as u32).to_le_bytes());
+	}
+	buf.extend_from_slice(&(entry.value.len() as u32).to_le_bytes());
+	buf.extend_from_slice(&entry.value);
+	let crc = crc32fast::hash(&buf);
+	buf.extend_from_slice(&crc.to_le_bytes());
+	try_io!(writer.write_all(&buf));
+	Ok(())
+}
+
+fn write_end(writer: &mut impl Write, count: u64) -> Result<()> {
+	let mut buf = vec![RECORD_END];
+	buf.extend_from_slice(&count.to_le_bytes());
+	let crc = crc32fast::hash(&buf);
+	buf.extend_from_slice(&crc.to_le_bytes());
+	try_io!(writer.write_all(&buf));
+	Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+	let mut buf = [0u8; 4];
+	try_io!(reader.read_exact(&mut buf));
+	Ok(u32::from_le_bytes(buf))
+}
+
+fn read_bytes(
+	reader: &mut impl Read,
+	hasher: &mut crc32fast::Hasher,
+	len: usize,
+) -> Result<Vec<u8>> {
+	let mut buf = vec![0u8; len];
+	try_io!(reader.read_exact(&mut buf));
+	hasher.update(&buf);
+	Ok(buf)
+}
+
+fn read_len(reader: &mut impl Read, hasher: &mut crc32fast::Hasher) -> Result<usize> {
+	let buf = read_bytes(reader, hasher, 4)?;
+	Ok(u32::from_le_bytes(buf.try_into().expect("4 bytes read")) as usize)
+}
+
+/// Read the next entry, or `None` after the end record. Fails on checksum or count mismatch.
+fn read_entry(reader: &mut impl Read, count: u64, wide_rc: bool) -> Result<Option<Entry>> {
+	let mut hasher = crc32fast::Hasher::new();
+	let tag = read_bytes(reader, &mut hasher, 1)?[0];
+	let entry = match tag {
+		RECORD_END => {
+			let mut buf = [0u8; 8];
+			try_io!(reader.read_exact(&mut buf));
+			hasher.update(&buf);
+			if u64::from_le_bytes(buf) != count {
+				return Err(Error::Corruption("Dump entry count mismatch".into()))
+			}
+			None
+		},
+		RECORD_ENTRY => {
+			let col = read_bytes(reader, &mut hasher, 1)?[0];
+			let len = read_len(reader, &mut hasher)?;
+			let key = read_bytes(reader, &mut hasher, len)?;
+			let rc = if wide_rc {
+				let buf = read_bytes(reader, &mut hasher, 8)?;
+				u64::from_le_bytes(buf.try_into().expect("8 bytes read"))
+			} else {
+				read_len(reader, &mut hasher)? as u64
+			};
+			let len = read_len(reader, &mut hasher)?;
+			let value = read_bytes(reader, &mut hasher, len)?;
+			Some(Entry { col, key, rc, value })
+		},
+		_ => return Err(Error::Corruption(format!("Bad dump record tag {}", tag))),
+	};
+	if read_u32(reader)? != hasher.finalize() {
+		return Err(Error::Corruption("Dump record checksum mismatch".into()))
+	}
+	Ok(entry)
+}
+
+/// Write all entries of column `col` to `writer`. Returns the number of entries.
+pub fn export(db: &Db, col: ColId, writer: &mut impl Write) -> Result<u64> {
+	let options = db
+		.column_options(col)
+		.ok_or_else(|| Error::InvalidInput(format!("Invalid column {}", col)))?;
+	let mut flags = 0;
+	if options.btree_index || options.uniform || options.store_keys {
+		flags |= FLAG_RAW_KEYS;
+	} else {
+		flags |= (options.key_hash as u8) << KEY_HASH_SHIFT;
+	}
+	if options.ttl {
+		flags |= FLAG_TTL;
+	}
+	let wide_rc = options.ref_counted && options.wide_ref_counts;
+	if wide_rc {
+		flags |= FLAG_WIDE_RC;
+	}
+	Header { flags, salt: db.salt() }.write(writer)?;
+	let mut count = 0;
+	if options.btree_index {
+		let mut iter = db.iter_consistent(col)?;
+		while let Some((key, value)) = iter.next()? {
+			write_entry(writer, &Entry { col, key, rc: 1, value }, wide_rc)?;
+			count += 1;
+		}
+	} else {
+		let mut result = Ok(());
+		db.iter_column_while(col, |IterState { key, rc, value, .. }| {
+			let entry = if options.store_keys {
+				match crate::db::split_keyed(&value) {
+					Some((key, value)) =>
+						Entry { col, key: key.to_vec(), rc, value: value.to_vec() },
+					None => {
+						result = Err(Error::Corruption("Value without a stored key".into()));
+						return false
+					},
+				}
+			} else {
+				// Uniform keys are stored xored with the salt.
+				let key = if options.uniform { db.hash_key(col, &key) } else { key };
+				Entry { col, key: key.to_vec(), rc, value }
+			};
+			result = write_entry(writer, &entry, wide_rc);
+			count += 1;
+			result.is_ok()
+		})?;
+		result?;
+	}
+	write_end(writer, count)?;
+	try_io!(writer.flush());
+	Ok(count)
+}
+
+/// Read a dump from `reader` and insert all entries into column `col`. Returns the number of
+/// entries.
+pub fn import(db: &Db, col: ColId, reader: &mut impl Read) -> Result<u64> {
+	let options = db
+		.column_options(col)
+		.ok_or_else(|| Error::InvalidInput(format!("Invalid column {}", col)))?;
+	let header = Header::read(reader)?;
+	let raw_keys = header.flags & FLAG_RAW_KEYS != 0;
+	let ttl = header.flags & FLAG_TTL != 0;
+	let wide_rc = header.flags & FLAG_WIDE_RC != 0;
+	if ttl && !options.ttl {
+		return Err(Error::InvalidInput("Dumps of `ttl` columns require a `ttl` column".into()))
+	}
+	if !raw_keys {
+		if options.btree_index || options.uniform || options.store_keys || options.ttl != ttl {
+			return Err(Error::InvalidInput(
+				"Dumps of hashed keys require a hash indexed column with the same settings".into(),
+			))
+		}
+		if header.salt != db.salt() {
+			return Err(Error::InvalidInput("Dump was made with a different salt".into()))
+		}
+		if header.flags >> KEY_HASH_SHIFT != options.key_hash as u8 {
+			return Err(Error::InvalidInput("Dump was made with a different `key_hash`".into()))
+		}
+	}
+	let mut count = 0;
+	let mut raw = Vec::new();
+	let mut hashed = IndexedChangeSet::new(col);
+	loop {
+		let entry = read_entry(reader, count, wide_rc)?;
+		let done = entry.is_none();
+		if let Some(Entry { key, rc, value, .. }) = entry {
+			count += 1;
+			if raw_keys && options.uniform && key.len() < 32 {
+				return Err(Error::InvalidInput("Keys of `uniform` columns are 32 bytes".into()))
+			}
+			// Further references to the value are only kept by `ref_counted` columns.
+			let extra_refs = if options.ref_counted {
+				rc.saturating_sub(1).min(i64::MAX as u64) as i64
+			} else {
+				0
+			};
+			if raw_keys && !ttl {
+				raw.push((col, Operation::Set(key.clone(), value)));
+				if extra_refs > 0 {
+					raw.push((col, Operation::AddRc(key, extra_refs)));
+				}
+			} else {
+				// Values with expiry times are inserted as is.
+				let key: Key = if raw_keys {
+					db.hash_key(col, &key)
+				} else {
+					key.as_slice()
+						.try_into()
+						.map_err(|_| Error::Corruption("Bad key hash length".into()))?
+				};
+				hashed.changes.push(Operation::Set(key, value));
+				if extra_refs > 0 {
+					hashed.changes.push(Operation::AddRc(key, extra_refs));
+				}
+			}
+		}
+		if done || raw.len() + hashed.changes.len() >= COMMIT_SIZE {
+			if !raw.is_empty() {
+				db.commit_changes(std::mem::take(&mut raw))?;
+			}
+			if !hashed.changes.is_empty() {
+				let mut commit = CommitChangeSet::default();
+				commit
+					.indexed
+					.insert(col, std::mem::replace(&mut hashed, IndexedChangeSet::new(col)));
+				db.commit_raw(commit)?;
+			}
+		}
+		if done {
+			return Ok(count)
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::{Db, Options};
+	use tempfile::tempdir;
+
+	#[test]
+	fn export_import() {
+		let dir = tempdir().unwrap();
+		let mut options = Options::with_columns(&dir.path().join("source"), 3);
+		options.columns[0].btree_index = true;
+		options.columns[1].uniform = true;
+		options.salt = Some([1; 32]);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit((0..100u32).flat_map(|i| {
+				let key = [i as u8; 32].to_vec();
+				(0..3).map(move |c| (c, key.clone(), Some(vec![c; i as usize])))
+			}))
+			.unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		let mut dumps = Vec::new();
+		for c in 0..3 {
+			let mut dump = Vec::new();
+			assert_eq!(db.export(c, &mut dump).unwrap(), 100);
+			dumps.push(dump);
+		}
+		drop(db);
+
+		let mut dest_options = Options::with_columns(&dir.path().join("dest"), 3);
+		dest_options.columns[0].uniform = true;
+		dest_options.salt = Some([2; 32]);
+		let db = Db::open_or_create(&dest_options).unwrap();
+		// Btree and uniform keys are imported with a different salt and column type.
+		assert_eq!(db.import(0, dumps[0].as_slice()).unwrap(), 100);
+		assert_eq!(db.import(1, dumps[1].as_slice()).unwrap(), 100);
+		assert!(db.import(2, dumps[2].as_slice()).is_err());
+		for i in 0..100u32 {
+			let key = [i as u8; 32];
+			assert_eq!(db.get(0, &key).unwrap(), Some(vec![0; i as usize]));
+			assert_eq!(db.get(1, &key).unwrap(), Some(vec![1; i as usize]));
+		}
+
+		let mut corrupted = dumps[0].clone();
+		let at = corrupted.len() / 2;
+		corrupted[at] ^= 1;
+		assert!(db.import(2, corrupted.as_slice()).is_err());
+		let truncated = &dumps[0][..dumps[0].len() - 4];
+		assert!(db.import(2, truncated).is_err());
+		drop(db);
+
+		// Same salt
+		dest_options.path = dir.path().join("dest_salt");
+		dest_options.salt = options.salt;
+		let db = Db::open_or_create(&dest_options).unwrap();
+		assert_eq!(db.import(2, dumps[2].as_slice()).unwrap(), 100);
+		assert_eq!(db.get(2, &[5; 32]).unwrap(), Some(vec![2; 5]));
+		drop(db);
+
+		// Same salt, different key hash.
+		dest_options.path = dir.path().join("dest_hash");
+		dest_options.columns[2].key_hash = crate::KeyHash::SipHash24;
+		let db = Db::open_or_create(&dest_options).unwrap();
+		assert!(db.import(2, dumps[2].as_slice()).is_err());
+	}
+}
@@ -0,0 +1,188 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Fault injection for tests and fuzzing.
+//!
+//! `FaultyStorage` wraps a `Storage` and makes selected reads, writes and syncs of its files
+//! fail, stop short or wait, according to a list of `FaultRule`s. Short writes leave the
+//! written prefix in place, like a torn write.
+
+use crate::{
+	parking_lot::Mutex,
+	storage::{Storage, StorageFile},
+};
+use std::{io, sync::Arc, time::Duration};
+
+/// File operation a fault applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOp {
+	Read,
+	Write,
+	Sync,
+}
+
+/// Effect of an injected fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+	/// Fail with an error of this kind, e.g. `io::ErrorKind::StorageFull` for a full disk.
+	Fail(io::ErrorKind),
+	/// Transfer only the first bytes of a read or write, then fail. Syncs fail.
+	Short(usize),
+	/// Wait before running the operation.
+	Delay(Duration),
+}
+
+/// When to inject a fault, see `FaultyStorage::inject`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultRule {
+	/// Operations the fault applies to.
+	pub op: IoOp,
+	/// Only files with names starting with this prefix, e.g. `log` or `table_00`. Empty for all.
+	pub file_prefix: String,
+	/// Number of matching operations to run normally before injecting the fault.
+	pub skip: u64,
+	/// Number of times to inject the fault, `None` for all further matching operations.
+	pub times: Option<u64>,
+	pub fault: Fault,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+	rules: Vec<FaultRule>,
+	injected: u64,
+}
+
+impl Faults {
+	// Fault to inject into operation `op` of file `name`, if any.
+	fn next(&mut self, op: IoOp, name: &str) -> Option<Fault> {
+		let mut fault = None;
+		for rule in &mut self.rules {
+			if rule.op != op || !name.starts_with(&rule.file_prefix) {
+				continue
+			}
+			if rule.skip > 0 {
+				rule.skip -= 1;
+				continue
+			}
+			if fault.is_none() && rule.times != Some(0) {
+				rule.times = rule.times.map(|t| t - 1);
+				fault = Some(rule.fault);
+			}
+		}
+		self.rules.retain(|r| r.times != Some(0));
+		self.injected += fault.is_some() as u64;
+		fault
+	}
+}
+
+/// Storage injecting faults into the file operations of another storage.
+#[derive(Debug)]
+pub struct FaultyStorage {
+	inner: Arc<dyn Storage>,
+	faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyStorage {
+	pub fn new(inner: Arc<dyn Storage>) -> Self {
+		FaultyStorage { inner, faults: Default::default() }
+	}
+
+	/// Add a rule. When several rules match an operation, the first one added is applied.
+	pub fn inject(&self, rule: FaultRule) {
+		self.faults.lock().rules.push(rule);
+	}
+
+	/// Remove all rules.
+	pub fn clear(&self) {
+		self.faults.lock().rules.clear();
+	}
+
+	/// Number of faults injected so far.
+	pub fn injected(&self) -> u64 {
+		self.faults.lock().injected
+	}
+}
+
+struct FaultyFile {
+	name: String,
+	inner: Arc<dyn StorageFile>,
+	faults: Arc<Mutex<Faults>>,
+}
+
+fn injected_error(kind: io::ErrorKind) -> io::Error {
+	io::Error::new(kind, "Injected fault")
+}
+
+impl FaultyFile {
+	fn fault(&self, op: IoOp) -> Option<Fault> {
+		match self.faults.lock().next(op, &self.name) {
+			Some(Fault::Delay(delay)) => {
+				std::thread::sleep(delay);
+				None
+			},
+			fault => fault,
+		}
+	}
+}
+
+impl StorageFile for FaultyFile {
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		match self.fault(IoOp::Read) {
+			Some(Fault::Fail(kind)) => Err(injected_error(kind)),
+			Some(Fault::Short(len)) => {
+				let len = len.min(buf.len());
+				self.inner.read_at(&mut buf[..len], offset)?;
+				Err(injected_error(io::ErrorKind::UnexpectedEof))
+			},
+			_ => self.inner.read_at(buf, offset),
+		}
+	}
+
+	fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+		match self.fault(IoOp::Write) {
+			Some(Fault::Fail(kind)) => Err(injected_error(kind)),
+			Some(Fault::Short(len)) => {
+				self.inner.write_at(&buf[..len.min(buf.len())], offset)?;
+				Err(injected_error(io::ErrorKind::WriteZero))
+			},
+			_ => self.inner.write_at(buf, offset),
+		}
+	}
+
+	fn size(&self) -> io::Result<u64> {
+		self.inner.size()
+	}
+
+	fn set_size(&self, size: u64) -> io::Result<()> {
+		self.inner.set_size(size)
+	}
+
+	fn sync(&self) -> io::Result<()> {
+		match self.fault(IoOp::Sync) {
+			Some(Fault::Fail(kind)) => Err(injected_error(kind)),
+			Some(Fault::Short(_)) => Err(injected_error(io::ErrorKind::Other)),
+			_ => self.inner.sync(),
+		}
+	}
+}
+
+impl Storage for FaultyStorage {
+	fn open(&self, name: &str, create: bool) -> io::Result<Option<Arc<dyn StorageFile>>> {
+		Ok(self.inner.open(name, create)?.map(|inner| {
+			Arc::new(FaultyFile { name: name.into(), inner, faults: self.faults.clone() })
+				as Arc<dyn StorageFile>
+		}))
+	}
+
+	fn remove(&self, name: &str) -> io::Result<()> {
+		self.inner.remove(name)
+	}
+
+	fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+		self.inner.rename(from, to)
+	}
+
+	fn list(&self) -> io::Result<Vec<String>> {
+		self.inner.list()
+	}
+}
@@ -4,16 +4,26 @@
 //! Utilities for db file.
 
 use crate::{
+	column::ColId,
 	error::{try_io, Result},
-	parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
+	options::{AccessHint, GrowPolicy, Options, ReadMode},
+	parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
+	storage::{Files, StorageFile},
 	table::TableId,
 };
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{
+	path::Path,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
 
 #[cfg(target_os = "linux")]
-fn disable_read_ahead(file: &std::fs::File) -> std::io::Result<()> {
+fn fadvise(file: &std::fs::File, advice: libc::c_int) -> std::io::Result<()> {
 	use std::os::unix::io::AsRawFd;
-	let err = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_RANDOM) };
+	let err = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
 	if err != 0 {
 		Err(std::io::Error::from_raw_os_error(err))
 	} else {
@@ -21,10 +31,19 @@ fn disable_read_ahead(file: &std::fs::File) -> std::io::Result<()> {
 	}
 }
 
+#[cfg(target_os = "linux")]
+fn advise(file: &std::fs::File, hint: AccessHint) -> std::io::Result<()> {
+	match hint {
+		AccessHint::Random | AccessHint::DontNeed => fadvise(file, libc::POSIX_FADV_RANDOM),
+		AccessHint::Sequential => fadvise(file, libc::POSIX_FADV_SEQUENTIAL),
+	}
+}
+
 #[cfg(target_os = "macos")]
-fn disable_read_ahead(file: &std::fs::File) -> std::io::Result<()> {
+fn advise(file: &std::fs::File, hint: AccessHint) -> std::io::Result<()> {
 	use std::os::unix::io::AsRawFd;
-	if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_RDAHEAD, 0) } != 0 {
+	let read_ahead = (hint == AccessHint::Sequential) as libc::c_int;
+	if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_RDAHEAD, read_ahead) } != 0 {
 		Err(std::io::Error::last_os_error())
 	} else {
 		Ok(())
@@ -32,7 +51,17 @@ fn disable_read_ahead(file: &std::fs::File) -> std::io::Result<()> {
 }
 
 #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-fn disable_read_ahead(_file: &std::fs::File) -> std::io::Result<()> {
+fn advise(_file: &std::fs::File, _hint: AccessHint) -> std::io::Result<()> {
+	Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn drop_file_cache(file: &std::fs::File) -> std::io::Result<()> {
+	fadvise(file, libc::POSIX_FADV_DONTNEED)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_file_cache(_file: &std::fs::File) -> std::io::Result<()> {
 	Ok(())
 }
 
@@ -58,80 +87,590 @@ fn fsync(file: &std::fs::File) -> std::io::Result<()> {
 	file.sync_data()
 }
 
-const GROW_SIZE_BYTES: u64 = 256 * 1024;
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &std::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+	let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+	if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as _, len as _) } != 0 {
+		let e = std::io::Error::last_os_error();
+		match e.raw_os_error() {
+			Some(libc::EOPNOTSUPP) => Err(std::io::ErrorKind::Unsupported.into()),
+			_ => Err(e),
+		}
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &std::fs::File, _offset: u64, _len: u64) -> std::io::Result<()> {
+	Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &std::fs::File, offset: u64, len: u64) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+	if unsafe { libc::fallocate(file.as_raw_fd(), 0, offset as _, len as _) } != 0 {
+		let e = std::io::Error::last_os_error();
+		match e.raw_os_error() {
+			Some(libc::EOPNOTSUPP) => Err(std::io::ErrorKind::Unsupported.into()),
+			_ => Err(e),
+		}
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &std::fs::File, _offset: u64, _len: u64) -> std::io::Result<()> {
+	Err(std::io::ErrorKind::Unsupported.into())
+}
 
+/// Set the length of `file`. If `preallocate` is set, disk space is allocated for the extended
+/// part where the file system supports it, see `Options::preallocate`.
+pub fn extend_file(file: &std::fs::File, len: u64, preallocate: bool) -> std::io::Result<()> {
+	if preallocate {
+		let current = file.metadata()?.len();
+		if len > current {
+			match allocate(file, current, len - current) {
+				Ok(()) => return Ok(()),
+				Err(e) if e.kind() == std::io::ErrorKind::Unsupported => (),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+	file.set_len(len)
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &mut std::fs::OpenOptions, path: &Path) -> std::io::Result<std::fs::File> {
+	use std::os::unix::fs::OpenOptionsExt;
+	options.clone().custom_flags(libc::O_DIRECT).open(path).or_else(|e| {
+		if e.raw_os_error() == Some(libc::EINVAL) {
+			log::warn!(
+				target: "parity-db",
+				"Direct I/O is not supported for {}, using buffered I/O",
+				path.display(),
+			);
+			return options.open(path)
+		}
+		Err(e)
+	})
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(options: &mut std::fs::OpenOptions, path: &Path) -> std::io::Result<std::fs::File> {
+	options.open(path)
+}
+
+#[cfg(target_os = "linux")]
+fn is_direct(file: &std::fs::File) -> bool {
+	use std::os::unix::io::AsRawFd;
+	let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+	flags != -1 && flags & libc::O_DIRECT != 0
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_direct(_file: &std::fs::File) -> bool {
+	false
+}
+
+#[cfg(unix)]
+// Offset, length and memory alignment required for direct I/O.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+#[cfg(unix)]
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct AlignedBlock([u8; DIRECT_IO_ALIGN]);
+
+#[cfg(unix)]
+// Buffer for direct I/O covering `len` bytes at `offset`. Returns the buffer, its file offset
+// and the position of `offset` in it.
+fn aligned_buffer(offset: u64, len: usize) -> (Vec<AlignedBlock>, u64, usize) {
+	let align = DIRECT_IO_ALIGN as u64;
+	let start = offset - offset % align;
+	let end = (offset + len as u64).div_ceil(align) * align;
+	let blocks = vec![AlignedBlock([0; DIRECT_IO_ALIGN]); ((end - start) / align) as usize];
+	(blocks, start, (offset - start) as usize)
+}
+
+#[cfg(unix)]
+fn block_bytes(blocks: &mut [AlignedBlock]) -> &mut [u8] {
+	unsafe {
+		std::slice::from_raw_parts_mut(
+			blocks.as_mut_ptr() as *mut u8,
+			blocks.len() * DIRECT_IO_ALIGN,
+		)
+	}
+}
+
+/// A database file, on disk or in `Options::storage`.
 #[derive(Debug)]
-pub struct TableFile {
-	pub file: RwLock<Option<std::fs::File>>,
-	pub path: std::path::PathBuf,
-	pub capacity: AtomicU64,
-	pub dirty: AtomicBool,
-	pub id: TableId,
+pub enum DbFile {
+	Disk(std::fs::File),
+	Storage(StorageHandle),
 }
 
-impl TableFile {
-	pub fn open(filepath: std::path::PathBuf, entry_size: u16, id: TableId) -> Result<Self> {
+impl DbFile {
+	pub fn storage(file: Arc<dyn StorageFile>) -> Self {
+		DbFile::Storage(StorageHandle { file, pos: 0 })
+	}
+
+	pub fn len(&self) -> std::io::Result<u64> {
+		match self {
+			DbFile::Disk(file) => Ok(file.metadata()?.len()),
+			DbFile::Storage(handle) => handle.file.size(),
+		}
+	}
+
+	pub fn set_len(&self, len: u64) -> std::io::Result<()> {
+		match self {
+			DbFile::Disk(file) => file.set_len(len),
+			DbFile::Storage(handle) => handle.file.set_size(len),
+		}
+	}
+
+	/// Extend the file to `len` bytes, see `extend_file`.
+	pub fn extend(&self, len: u64, preallocate: bool) -> std::io::Result<()> {
+		match self {
+			DbFile::Disk(file) => extend_file(file, len, preallocate),
+			DbFile::Storage(handle) => handle.file.set_size(len),
+		}
+	}
+
+	pub fn sync_data(&self) -> std::io::Result<()> {
+		match self {
+			DbFile::Disk(file) => fsync(file),
+			DbFile::Storage(handle) => handle.file.sync(),
+		}
+	}
+}
+
+impl std::io::Read for DbFile {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			DbFile::Disk(file) => file.read(buf),
+			DbFile::Storage(handle) => {
+				let len = (buf.len() as u64).min(handle.file.size()?.saturating_sub(handle.pos));
+				handle.file.read_at(&mut buf[..len as usize], handle.pos)?;
+				handle.pos += len;
+				Ok(len as usize)
+			},
+		}
+	}
+}
+
+impl std::io::Write for DbFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			DbFile::Disk(file) => file.write(buf),
+			DbFile::Storage(handle) => {
+				handle.file.write_at(buf, handle.pos)?;
+				handle.pos += buf.len() as u64;
+				Ok(buf.len())
+			},
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			DbFile::Disk(file) => file.flush(),
+			DbFile::Storage(_) => Ok(()),
+		}
+	}
+}
+
+impl std::io::Seek for DbFile {
+	fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+		match self {
+			DbFile::Disk(file) => file.seek(pos),
+			DbFile::Storage(handle) => {
+				let new_pos = match pos {
+					std::io::SeekFrom::Start(offset) => Some(offset),
+					std::io::SeekFrom::End(delta) => handle.file.size()?.checked_add_signed(delta),
+					std::io::SeekFrom::Current(delta) => handle.pos.checked_add_signed(delta),
+				};
+				handle.pos = new_pos.ok_or_else(|| {
+					std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek position")
+				})?;
+				Ok(handle.pos)
+			},
+		}
+	}
+}
+
+/// A `StorageFile`, with the position of sequential reads and writes.
+pub struct StorageHandle {
+	file: Arc<dyn StorageFile>,
+	pos: u64,
+}
+
+impl std::fmt::Debug for StorageHandle {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("StorageHandle").field("pos", &self.pos).finish_non_exhaustive()
+	}
+}
+
+/// I/O settings of value table files.
+#[derive(Debug, Clone, Default)]
+pub struct TableIo {
+	pub slow_io: SlowIo,
+	/// Open files with `O_DIRECT`, see `Options::direct_io`.
+	pub direct: bool,
+	/// Batch reads with io_uring, see `Options::io_uring`.
+	pub uring: bool,
+	/// Read through a memory map, see `ColumnOptions::read_mode`.
+	pub mmap: bool,
+	/// See `Options::grow_policy`.
+	pub grow: GrowPolicy,
+	/// See `Options::preallocate`.
+	pub preallocate: bool,
+	/// See `ColumnOptions::max_file_size`.
+	pub max_file_size: Option<u64>,
+	/// See `ColumnOptions::access_hint`.
+	pub hint: AccessHint,
+	/// Where the files are kept, see `Options::storage`.
+	pub files: Files,
+}
+
+impl TableIo {
+	pub fn new(options: &Options, col: ColId) -> Self {
+		let column = options.columns.get(col as usize);
+		let files = options.files();
+		let disk = files.is_disk();
+		TableIo {
+			slow_io: SlowIo(options.slow_io_warn),
+			direct: disk && options.direct_io,
+			uring: disk && options.io_uring,
+			mmap: disk && column.is_some_and(|c| c.read_mode == ReadMode::Mmap),
+			grow: options.grow_policy,
+			preallocate: disk && options.preallocate,
+			max_file_size: column.and_then(|c| c.max_file_size),
+			hint: column.map(|c| c.access_hint).unwrap_or_default(),
+			files,
+		}
+	}
+}
+
+/// Warns about operations that take longer than `Options::slow_io_warn`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlowIo(pub Option<Duration>);
+
+impl SlowIo {
+	/// Start timing an operation. Returns `None` if disabled.
+	pub fn start(&self) -> Option<Instant> {
+		self.0.map(|_| Instant::now())
+	}
+
+	/// Log a warning if the operation started at `start` took too long.
+	pub fn check(&self, start: Option<Instant>, op: &str, file: Option<&Path>, offset: u64) {
+		if let (Some(start), Some(threshold)) = (start, self.0) {
+			let elapsed = start.elapsed();
+			if elapsed > threshold {
+				match file {
+					Some(file) => log::warn!(
+						target: "parity-db",
+						"Slow {}: file={}, offset={}, duration={:?}",
+						op,
+						file.display(),
+						offset,
+						elapsed,
+					),
+					None => log::warn!(
+						target: "parity-db",
+						"Slow {}: duration={:?}",
+						op,
+						elapsed,
+					),
+				}
+			}
+		}
+	}
+}
+
+// A single file of a value table, see `TableFile`.
+#[derive(Debug)]
+struct Segment {
+	file: RwLock<Option<DbFile>>,
+	path: std::path::PathBuf,
+	capacity: AtomicU64,
+	// Entries the file may hold.
+	max_capacity: u64,
+	dirty: AtomicBool,
+	id: TableId,
+	io: TableIo,
+	// Serializes read-modify-write cycles of direct writes.
+	direct_write: Mutex<()>,
+	// Read-only map of the file, with `TableIo::mmap`. Remapped when reads go past its end.
+	map: RwLock<Option<memmap2::Mmap>>,
+}
+
+impl Segment {
+	fn open(
+		filepath: std::path::PathBuf,
+		entry_size: u16,
+		id: TableId,
+		io: TableIo,
+		max_capacity: u64,
+	) -> Result<Self> {
 		let mut capacity = 0u64;
-		let file = if std::fs::metadata(&filepath).is_ok() {
-			let file = try_io!(std::fs::OpenOptions::new()
-				.read(true)
-				.write(true)
-				.open(filepath.as_path()));
-			try_io!(disable_read_ahead(&file));
-			let len = try_io!(file.metadata()).len();
+		let file = if !io.files.is_disk() {
+			try_io!(io.files.open(&filepath, false), &filepath, "open")
+		} else if std::fs::metadata(&filepath).is_ok() {
+			let file = try_io!(
+				Self::open_file(
+					std::fs::OpenOptions::new().read(true).write(true),
+					&filepath,
+					io.direct
+				),
+				&filepath,
+				"open"
+			);
+			try_io!(advise(&file, io.hint));
+			Some(DbFile::Disk(file))
+		} else {
+			None
+		};
+		if let Some(file) = &file {
+			let len = try_io!(file.len());
 			if len == 0 {
 				// Preallocate.
-				capacity += GROW_SIZE_BYTES / entry_size as u64;
-				try_io!(file.set_len(capacity * entry_size as u64));
+				capacity = io.grow.grow_entries(0, entry_size).min(max_capacity);
+				try_io!(
+					file.extend(capacity * entry_size as u64, io.preallocate),
+					&filepath,
+					"extend"
+				);
 			} else {
 				capacity = len / entry_size as u64;
 			}
-			Some(file)
-		} else {
-			None
-		};
-		Ok(TableFile {
+		}
+		Ok(Segment {
 			path: filepath,
 			file: RwLock::new(file),
 			capacity: AtomicU64::new(capacity),
+			max_capacity,
 			dirty: AtomicBool::new(false),
 			id,
+			io,
+			direct_write: Mutex::new(()),
+			map: RwLock::new(None),
 		})
 	}
 
-	fn create_file(&self) -> Result<std::fs::File> {
+	fn open_file(
+		options: &mut std::fs::OpenOptions,
+		path: &Path,
+		direct: bool,
+	) -> std::io::Result<std::fs::File> {
+		if direct {
+			open_direct(options, path)
+		} else {
+			options.open(path)
+		}
+	}
+
+	fn create_file(&self) -> Result<DbFile> {
 		log::debug!(target: "parity-db", "Created value table {}", self.id);
-		let file = try_io!(std::fs::OpenOptions::new()
-			.create(true)
-			.read(true)
-			.write(true)
-			.open(self.path.as_path()));
-		try_io!(disable_read_ahead(&file));
-		Ok(file)
+		if !self.io.files.is_disk() {
+			let file = try_io!(self.io.files.open(&self.path, true), &self.path, "create");
+			return Ok(file.expect("Files are created when requested"))
+		}
+		let file = try_io!(
+			Self::open_file(
+				std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true),
+				&self.path,
+				self.io.direct
+			),
+			&self.path,
+			"create"
+		);
+		try_io!(advise(&file, self.io.hint));
+		Ok(DbFile::Disk(file))
 	}
 
+	// Read with `O_DIRECT`, through an aligned buffer. Parts past the end of the file read as
+	// zeroes.
 	#[cfg(unix)]
+	fn read_direct(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+		use std::os::unix::fs::FileExt;
+		let (mut blocks, start, skip) = aligned_buffer(offset, buf.len());
+		let bytes = block_bytes(&mut blocks);
+		let mut read = 0;
+		while read < bytes.len() {
+			match file.read_at(&mut bytes[read..], start + read as u64) {
+				Ok(0) => break,
+				Ok(n) => read += n,
+				Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+		buf.copy_from_slice(&bytes[skip..skip + buf.len()]);
+		Ok(read.saturating_sub(skip).min(buf.len()))
+	}
+
+	// Write with `O_DIRECT`. Partially written blocks are read first.
+	#[cfg(unix)]
+	fn write_direct(&self, file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+		use std::os::unix::fs::FileExt;
+		let _lock = self.direct_write.lock();
+		let (mut blocks, start, skip) = aligned_buffer(offset, buf.len());
+		let len = blocks.len() * DIRECT_IO_ALIGN;
+		if skip != 0 || buf.len() != len {
+			let first = block_bytes(&mut blocks[..1]);
+			Self::read_direct(file, first, start)?;
+			let last = blocks.len() - 1;
+			let last_offset = start + (last * DIRECT_IO_ALIGN) as u64;
+			Self::read_direct(file, block_bytes(&mut blocks[last..]), last_offset)?;
+		}
+		let bytes = block_bytes(&mut blocks);
+		bytes[skip..skip + buf.len()].copy_from_slice(buf);
+		file.write_all_at(bytes, start)
+	}
+
+	#[cfg(any(unix, windows))]
+	// Copy from the memory map, mapping the file again if it has grown. Returns `false` if the
+	// range is past the end of the file.
+	fn read_mapped(&self, file: &std::fs::File, buf: &mut [u8], offset: u64) -> Result<bool> {
+		let range = offset as usize..offset as usize + buf.len();
+		if let Some(map) = &*self.map.read() {
+			if range.end <= map.len() {
+				buf.copy_from_slice(&map[range]);
+				return Ok(true)
+			}
+		}
+		let mut map = self.map.write();
+		if map.as_ref().is_none_or(|map| range.end > map.len()) {
+			if range.end as u64 > try_io!(file.metadata()).len() {
+				return Ok(false)
+			}
+			*map = Some(try_io!(unsafe { memmap2::Mmap::map(file) }));
+		}
+		buf.copy_from_slice(&map.as_ref().expect("Mapped above")[range]);
+		Ok(true)
+	}
+
+	#[cfg(unix)]
+	#[cfg_attr(
+		feature = "tracing",
+		tracing::instrument(
+			level = "trace",
+			skip_all,
+			fields(col = self.id.col(), tier = self.id.size_tier(), offset = offset, len = buf.len()),
+		)
+	)]
 	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
-		try_io!(self.file.read().as_ref().unwrap().read_exact_at(buf, offset));
+		let start = self.io.slow_io.start();
+		let file = self.file.read();
+		let file = match file.as_ref().unwrap() {
+			DbFile::Disk(file) => file,
+			DbFile::Storage(handle) => {
+				try_io!(handle.file.read_at(buf, offset), &self.path, "read");
+				return Ok(())
+			},
+		};
+		if self.io.mmap && self.read_mapped(file, buf, offset)? {
+			return Ok(())
+		}
+		if self.direct(file) {
+			if try_io!(Self::read_direct(file, buf, offset), &self.path, "read") < buf.len() {
+				return Err(crate::error::Error::Io(std::io::Error::new(
+					std::io::ErrorKind::UnexpectedEof,
+					"failed to fill whole buffer",
+				)))
+			}
+		} else {
+			try_io!(file.read_exact_at(buf, offset), &self.path, "read");
+		}
+		self.io.slow_io.check(start, "read", Some(&self.path), offset);
 		Ok(())
 	}
 
 	#[cfg(unix)]
+	#[cfg_attr(
+		feature = "tracing",
+		tracing::instrument(
+			level = "trace",
+			skip_all,
+			fields(col = self.id.col(), tier = self.id.size_tier(), offset = offset, len = buf.len()),
+		)
+	)]
 	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
 		self.dirty.store(true, Ordering::Relaxed);
-		try_io!(self.file.read().as_ref().unwrap().write_all_at(buf, offset));
+		let file = self.file.read();
+		let file = match file.as_ref().unwrap() {
+			DbFile::Disk(file) => file,
+			DbFile::Storage(handle) => {
+				try_io!(handle.file.write_at(buf, offset), &self.path, "write");
+				return Ok(())
+			},
+		};
+		if self.direct(file) {
+			try_io!(self.write_direct(file, buf, offset), &self.path, "write");
+		} else {
+			try_io!(file.write_all_at(buf, offset), &self.path, "write");
+		}
+		Ok(())
+	}
+
+	/// Read multiple `(offset, buffer)` pairs. With `TableIo::uring` set the reads are
+	/// submitted to io_uring together.
+	pub fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Result<()> {
+		#[cfg(target_os = "linux")]
+		if self.io.uring && !self.io.mmap && reqs.len() > 1 {
+			use std::os::unix::io::AsRawFd;
+			let start = self.io.slow_io.start();
+			let done = match self.file.read().as_ref() {
+				Some(DbFile::Disk(file)) if !self.direct(file) =>
+					crate::uring::read(file.as_raw_fd(), reqs),
+				_ => None,
+			};
+			if let Some(done) = done {
+				self.io.slow_io.check(start, "batch read", Some(&self.path), reqs[0].0);
+				// Short or failed reads are retried one by one.
+				for ((offset, buf), done) in reqs.iter_mut().zip(done) {
+					if !done {
+						self.read_at(buf, *offset)?;
+					}
+				}
+				return Ok(())
+			}
+		}
+		for (offset, buf) in reqs.iter_mut() {
+			self.read_at(buf, *offset)?;
+		}
 		Ok(())
 	}
 
 	#[cfg(windows)]
+	#[cfg_attr(
+		feature = "tracing",
+		tracing::instrument(
+			level = "trace",
+			skip_all,
+			fields(col = self.id.col(), tier = self.id.size_tier(), offset = offset, len = buf.len()),
+		)
+	)]
 	pub fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
 		use crate::error::Error;
 		use std::{io, os::windows::fs::FileExt};
 
+		let start = self.io.slow_io.start();
+		let start_offset = offset;
 		let file = self.file.read();
-		let file = file.as_ref().unwrap();
+		let file = match file.as_ref().unwrap() {
+			DbFile::Disk(file) => file,
+			DbFile::Storage(handle) => {
+				try_io!(handle.file.read_at(buf, offset), &self.path, "read");
+				return Ok(())
+			},
+		};
+		if self.io.mmap && self.read_mapped(file, buf, offset)? {
+			return Ok(())
+		}
 
 		while !buf.is_empty() {
 			match file.seek_read(buf, offset) {
@@ -143,7 +682,7 @@ impl TableFile {
 				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
 					// Try again
 				},
-				Err(e) => return Err(Error::Io(e)),
+				Err(e) => return Err(Error::file_io(&self.path, "read", e)),
 			}
 		}
 
@@ -153,18 +692,33 @@ impl TableFile {
 				"failed to fill whole buffer",
 			)))
 		} else {
+			self.io.slow_io.check(start, "read", Some(&self.path), start_offset);
 			Ok(())
 		}
 	}
 
 	#[cfg(windows)]
+	#[cfg_attr(
+		feature = "tracing",
+		tracing::instrument(
+			level = "trace",
+			skip_all,
+			fields(col = self.id.col(), tier = self.id.size_tier(), offset = offset, len = buf.len()),
+		)
+	)]
 	pub fn write_at(&self, mut buf: &[u8], mut offset: u64) -> Result<()> {
 		use crate::error::Error;
 		use std::{io, os::windows::fs::FileExt};
 
 		self.dirty.store(true, Ordering::Relaxed);
 		let file = self.file.read();
-		let file = file.as_ref().unwrap();
+		let file = match file.as_ref().unwrap() {
+			DbFile::Disk(file) => file,
+			DbFile::Storage(handle) => {
+				try_io!(handle.file.write_at(buf, offset), &self.path, "write");
+				return Ok(())
+			},
+		};
 
 		while !buf.is_empty() {
 			match file.seek_write(buf, offset) {
@@ -180,16 +734,37 @@ impl TableFile {
 				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
 					// Try again
 				},
-				Err(e) => return Err(Error::Io(e)),
+				Err(e) => return Err(Error::file_io(&self.path, "write", e)),
 			}
 		}
 
 		Ok(())
 	}
 
+	// Targets without a file system only support `Options::storage`.
+	#[cfg(not(any(unix, windows)))]
+	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+		match self.file.read().as_ref().unwrap() {
+			DbFile::Storage(handle) => try_io!(handle.file.read_at(buf, offset)),
+			DbFile::Disk(_) => try_io!(Err(std::io::ErrorKind::Unsupported.into())),
+		}
+		Ok(())
+	}
+
+	#[cfg(not(any(unix, windows)))]
+	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+		self.dirty.store(true, Ordering::Relaxed);
+		match self.file.read().as_ref().unwrap() {
+			DbFile::Storage(handle) => try_io!(handle.file.write_at(buf, offset)),
+			DbFile::Disk(_) => try_io!(Err(std::io::ErrorKind::Unsupported.into())),
+		}
+		Ok(())
+	}
+
 	pub fn grow(&self, entry_size: u16) -> Result<()> {
 		let mut capacity = self.capacity.load(Ordering::Relaxed);
-		capacity += GROW_SIZE_BYTES / entry_size as u64;
+		capacity =
+			(capacity + self.io.grow.grow_entries(capacity, entry_size)).min(self.max_capacity);
 
 		self.capacity.store(capacity, Ordering::Relaxed);
 		let mut file = self.file.upgradable_read();
@@ -198,16 +773,105 @@ impl TableFile {
 			*wfile = Some(self.create_file()?);
 			file = RwLockWriteGuard::downgrade_to_upgradable(wfile);
 		}
-		try_io!(file.as_ref().unwrap().set_len(capacity * entry_size as u64));
+		try_io!(
+			file.as_ref().unwrap().extend(capacity * entry_size as u64, self.io.preallocate),
+			&self.path,
+			"extend"
+		);
 		Ok(())
 	}
 
+	/// Open the file if it was created by another process after opening.
+	pub fn refresh(&self, entry_size: u16) -> Result<()> {
+		if !self.io.files.is_disk() || self.file.read().is_some() {
+			return Ok(())
+		}
+		let file = match Self::open_file(
+			std::fs::OpenOptions::new().read(true).write(true),
+			&self.path,
+			self.io.direct,
+		) {
+			Ok(file) => file,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(crate::error::Error::Io(e)),
+		};
+		try_io!(advise(&file, self.io.hint));
+		let len = try_io!(file.metadata()).len();
+		self.capacity.store(len / entry_size as u64, Ordering::Relaxed);
+		*self.file.write() = Some(DbFile::Disk(file));
+		Ok(())
+	}
+
+	#[cfg_attr(
+		feature = "tracing",
+		tracing::instrument(level = "debug", skip_all, fields(col = self.id.col(), tier = self.id.size_tier()))
+	)]
 	pub fn flush(&self) -> Result<()> {
 		if let Ok(true) =
 			self.dirty.compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
 		{
 			if let Some(file) = self.file.read().as_ref() {
-				try_io!(fsync(file));
+				let start = self.io.slow_io.start();
+				try_io!(file.sync_data(), &self.path, "sync");
+				self.io.slow_io.check(start, "fsync", Some(&self.path), 0);
+			}
+		}
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	// Whether the file is open with `O_DIRECT`. It may not be if the file system does not
+	// support it.
+	fn direct(&self, file: &std::fs::File) -> bool {
+		self.io.direct && is_direct(file)
+	}
+
+	/// Release cached pages of the file. Pages that are not written back yet stay cached.
+	pub fn drop_cache(&self) -> Result<()> {
+		// The memory map is recreated on the next read.
+		self.map.write().take();
+		if let Some(DbFile::Disk(file)) = self.file.read().as_ref() {
+			try_io!(drop_file_cache(file));
+		}
+		Ok(())
+	}
+
+	/// Capacity of the file after `shrink` to `entries` entries.
+	pub fn shrunk_capacity(&self, entries: u64, entry_size: u16) -> u64 {
+		self.io.grow.round_capacity(entries, entry_size).min(self.max_capacity)
+	}
+
+	/// Truncate the file to hold `entries` entries, rounded up to the growth step. Returns the
+	/// number of bytes released.
+	pub fn shrink(&self, entries: u64, entry_size: u16) -> Result<u64> {
+		let capacity = self.shrunk_capacity(entries, entry_size);
+		let current = self.capacity.load(Ordering::Relaxed);
+		let file = self.file.read();
+		let file = match file.as_ref() {
+			Some(file) if capacity < current => file,
+			_ => return Ok(0),
+		};
+		// The memory map must not extend past the end of the file.
+		self.map.write().take();
+		try_io!(file.set_len(capacity * entry_size as u64), &self.path, "truncate");
+		self.capacity.store(capacity, Ordering::Relaxed);
+		Ok((current - capacity) * entry_size as u64)
+	}
+
+	/// Release the space of `len` bytes at `offset` to the file system. The range reads as
+	/// zeroes afterwards. Does nothing if the file system does not support it.
+	pub fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+		if let Some(DbFile::Disk(file)) = self.file.read().as_ref() {
+			match punch_hole(file, offset, len) {
+				Ok(()) => (),
+				Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+					log::debug!(
+						target: "parity-db",
+						"Punching holes is not supported for {}",
+						self.path.display(),
+					);
+				},
+				Err(e) => return Err(crate::error::Error::Io(e)),
 			}
 		}
 		Ok(())
@@ -215,10 +879,268 @@ impl TableFile {
 
 	pub fn remove(&self) -> Result<()> {
 		let mut file = self.file.write();
-		if let Some(file) = file.take() {
-			drop(file);
-			try_io!(std::fs::remove_file(&self.path));
+		self.map.write().take();
+		if file.take().is_some() {
+			try_io!(self.io.files.remove(&self.path), &self.path, "remove");
 		}
 		Ok(())
 	}
 }
+
+/// A value table file, split into segments of at most `ColumnOptions::max_file_size` bytes.
+/// Entry `i` is stored in segment `i / segment_entries`, so the segment is given by the entry
+/// address. Segments are created as the table grows, see `TableId::segment_file_name`.
+#[derive(Debug)]
+pub struct TableFile {
+	/// Path of the first segment.
+	pub path: std::path::PathBuf,
+	pub id: TableId,
+	entry_size: u16,
+	segment_entries: u64,
+	// The first segment always exists and is accessed without locking `more`, so a table that
+	// fits in one segment takes no more locks than a single file.
+	first: Segment,
+	more: RwLock<Vec<Segment>>,
+	// Length of `more`, read without locking it.
+	more_len: AtomicUsize,
+	io: TableIo,
+}
+
+impl TableFile {
+	pub fn open(dir: &Path, entry_size: u16, id: TableId, io: TableIo) -> Result<Self> {
+		let segment_entries = crate::table::segment_entries(io.max_file_size, entry_size);
+		let path = dir.join(id.file_name());
+		let first = Segment::open(path.clone(), entry_size, id, io.clone(), segment_entries)?;
+		let mut more = Vec::new();
+		loop {
+			let path = dir.join(id.segment_file_name(more.len() as u64 + 1));
+			if !try_io!(io.files.exists(&path)) {
+				break
+			}
+			more.push(Segment::open(path, entry_size, id, io.clone(), segment_entries)?);
+		}
+		Ok(TableFile {
+			path,
+			id,
+			entry_size,
+			segment_entries,
+			first,
+			more_len: AtomicUsize::new(more.len()),
+			more: RwLock::new(more),
+			io,
+		})
+	}
+
+	fn segment_path(&self, segment: u64) -> std::path::PathBuf {
+		self.path.with_file_name(self.id.segment_file_name(segment))
+	}
+
+	fn segment_bytes(&self) -> u64 {
+		self.segment_entries.saturating_mul(self.entry_size as u64)
+	}
+
+	// Whether all `len` bytes at `offset` are in the first segment.
+	fn in_first(&self, offset: u64, len: usize) -> bool {
+		offset.saturating_add(len as u64) <= self.segment_bytes()
+	}
+
+	fn has_more(&self) -> bool {
+		self.more_len.load(Ordering::Relaxed) > 0
+	}
+
+	/// Whether the file has been created.
+	pub fn is_init(&self) -> bool {
+		self.first.file.read().is_some()
+	}
+
+	/// Number of entries allocated in all segments.
+	pub fn capacity(&self) -> u64 {
+		if !self.has_more() {
+			return self.first.capacity.load(Ordering::Relaxed)
+		}
+		let more = self.more.read();
+		let last = more.last().unwrap_or(&self.first);
+		more.len() as u64 * self.segment_entries + last.capacity.load(Ordering::Relaxed)
+	}
+
+	// Call `f` for each segment part of the `len` bytes at `offset`, with the offset in the
+	// segment and the range of the bytes.
+	fn for_segments(
+		&self,
+		offset: u64,
+		len: usize,
+		mut f: impl FnMut(&Segment, u64, std::ops::Range<usize>) -> Result<()>,
+	) -> Result<()> {
+		if self.in_first(offset, len) {
+			return f(&self.first, offset, 0..len)
+		}
+		let more = self.more.read();
+		let segment_bytes = self.segment_bytes();
+		let mut done = 0;
+		while done < len {
+			let at = offset + done as u64;
+			let segment_offset = at % segment_bytes;
+			let part = (segment_bytes - segment_offset).min((len - done) as u64) as usize;
+			let segment = match at / segment_bytes {
+				0 => Some(&self.first),
+				segment => more.get(segment as usize - 1),
+			};
+			let segment = segment.ok_or_else(|| {
+				crate::error::Error::Io(std::io::Error::new(
+					std::io::ErrorKind::UnexpectedEof,
+					"failed to fill whole buffer",
+				))
+			})?;
+			f(segment, segment_offset, done..done + part)?;
+			done += part;
+		}
+		Ok(())
+	}
+
+	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+		self.for_segments(offset, buf.len(), |segment, offset, range| {
+			segment.read_at(&mut buf[range], offset)
+		})
+	}
+
+	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+		self.for_segments(offset, buf.len(), |segment, offset, range| {
+			segment.write_at(&buf[range], offset)
+		})
+	}
+
+	/// Read multiple `(offset, buffer)` pairs. With `TableIo::uring` set the reads are
+	/// submitted to io_uring together.
+	pub fn read_batch(&self, reqs: &mut [(u64, &mut [u8])]) -> Result<()> {
+		if reqs.iter().all(|(offset, buf)| self.in_first(*offset, buf.len())) {
+			return self.first.read_batch(reqs)
+		}
+		for (offset, buf) in reqs.iter_mut() {
+			self.read_at(buf, *offset)?;
+		}
+		Ok(())
+	}
+
+	/// Extend the file, adding a segment if the last one is full.
+	pub fn grow(&self, entry_size: u16) -> Result<()> {
+		if !self.has_more() && self.first.capacity.load(Ordering::Relaxed) < self.segment_entries {
+			return self.first.grow(entry_size)
+		}
+		let more = self.more.upgradable_read();
+		let last = more.last().unwrap_or(&self.first);
+		if last.capacity.load(Ordering::Relaxed) < self.segment_entries {
+			return last.grow(entry_size)
+		}
+		let mut more = RwLockUpgradableReadGuard::upgrade(more);
+		let path = self.segment_path(more.len() as u64 + 1);
+		let segment =
+			Segment::open(path, entry_size, self.id, self.io.clone(), self.segment_entries)?;
+		segment.grow(entry_size)?;
+		log::debug!(target: "parity-db", "{}: Added segment {}", self.id, more.len() + 1);
+		more.push(segment);
+		self.more_len.store(more.len(), Ordering::Relaxed);
+		Ok(())
+	}
+
+	/// Open the files created by another process after opening.
+	pub fn refresh(&self, entry_size: u16) -> Result<()> {
+		let mut more = self.more.write();
+		self.first.refresh(entry_size)?;
+		for segment in more.iter() {
+			segment.refresh(entry_size)?;
+		}
+		if !self.io.files.is_disk() || !self.is_init() {
+			return Ok(())
+		}
+		loop {
+			let path = self.segment_path(more.len() as u64 + 1);
+			if !path.exists() {
+				return Ok(())
+			}
+			let segment =
+				Segment::open(path, entry_size, self.id, self.io.clone(), self.segment_entries)?;
+			more.push(segment);
+			self.more_len.store(more.len(), Ordering::Relaxed);
+		}
+	}
+
+	pub fn flush(&self) -> Result<()> {
+		self.first.flush()?;
+		if self.has_more() {
+			for segment in self.more.read().iter() {
+				segment.flush()?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Release cached pages of the file. Pages that are not written back yet stay cached.
+	pub fn drop_cache(&self) -> Result<()> {
+		self.first.drop_cache()?;
+		if self.has_more() {
+			for segment in self.more.read().iter() {
+				segment.drop_cache()?;
+			}
+		}
+		Ok(())
+	}
+
+	// Segment holding entry `entries - 1` and the number of entries in it up to that entry.
+	fn last_segment(&self, entries: u64) -> (u64, u64) {
+		let segment = entries.saturating_sub(1) / self.segment_entries;
+		(segment, entries - segment * self.segment_entries)
+	}
+
+	/// Capacity of the file after `shrink` to `entries` entries.
+	pub fn shrunk_capacity(&self, entries: u64, entry_size: u16) -> u64 {
+		let (segment, entries) = self.last_segment(entries);
+		let capacity = self.io.grow.round_capacity(entries, entry_size).min(self.segment_entries);
+		segment * self.segment_entries + capacity
+	}
+
+	/// Truncate the file to hold `entries` entries, rounded up to the growth step. Segments
+	/// past the last entry are removed. Returns the number of bytes released.
+	pub fn shrink(&self, entries: u64, entry_size: u16) -> Result<u64> {
+		let (last, entries) = self.last_segment(entries);
+		let mut more = self.more.write();
+		let mut released = 0;
+		while more.len() as u64 > last {
+			let segment = more.pop().expect("Checked above");
+			self.more_len.store(more.len(), Ordering::Relaxed);
+			released += segment.capacity.load(Ordering::Relaxed) * entry_size as u64;
+			segment.remove()?;
+		}
+		let segment = match last {
+			0 => Some(&self.first),
+			last => more.get(last as usize - 1),
+		};
+		match segment {
+			Some(segment) => Ok(released + segment.shrink(entries, entry_size)?),
+			None => Ok(released),
+		}
+	}
+
+	/// Release the space of `len` bytes at `offset` to the file system. The range reads as
+	/// zeroes afterwards. Does nothing if the file system does not support it.
+	pub fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+		self.for_segments(offset, len as usize, |segment, offset, range| {
+			segment.punch_hole(offset, range.len() as u64)
+		})
+	}
+
+	pub fn remove(&self) -> Result<()> {
+		let mut more = self.more.write();
+		while let Some(segment) = more.pop() {
+			self.more_len.store(more.len(), Ordering::Relaxed);
+			segment.remove()?;
+		}
+		self.first.remove()
+	}
+
+	/// Close the files and return the paths of all segments.
+	pub fn close(self) -> Vec<std::path::PathBuf> {
+		std::iter::once(self.first.path)
+			.chain(self.more.into_inner().into_iter().map(|segment| segment.path))
+			.collect()
+	}
+}
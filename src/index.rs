@@ -2,19 +2,22 @@
 // This file is dual-licensed as Apache-2.0 or MIT.
 
 use crate::{
+	cache::ChunkCache,
 	column::ColId,
 	display::hex,
 	error::{try_io, Error, Result},
 	log::{LogQuery, LogReader, LogWriter},
-	parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
-	stats::{self, ColumnStats},
+	options::AccessHint,
+	parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
+	stats::{self, ColumnStatCounters},
+	storage::{Files, StorageFile},
 	table::{key::TableKey, SIZE_TIERS_BITS},
 	Key,
 };
-use std::convert::TryInto;
+use std::{collections::BTreeSet, convert::TryInto, sync::Arc};
 
 // Index chunk consists of 8 64-bit entries.
-const CHUNK_LEN: usize = CHUNK_ENTRIES * ENTRY_BYTES; // 512 bytes
+pub const CHUNK_LEN: usize = CHUNK_ENTRIES * ENTRY_BYTES; // 512 bytes
 const CHUNK_ENTRIES: usize = 1 << CHUNK_ENTRIES_BITS;
 const CHUNK_ENTRIES_BITS: u8 = 6;
 const HEADER_SIZE: usize = 512;
@@ -24,6 +27,12 @@ pub const ENTRY_BYTES: usize = ENTRY_LEN as usize / 8;
 
 const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
 
+#[cfg(test)]
+thread_local! {
+	// Number of index chunks read from the files by this thread.
+	pub static CHUNK_READS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
 pub type Chunk = [u8; CHUNK_LEN];
 
 #[allow(clippy::assertions_on_constants)]
@@ -122,8 +131,75 @@ pub enum PlanOutcome {
 #[derive(Debug)]
 pub struct IndexTable {
 	pub id: TableId,
-	map: RwLock<Option<memmap2::MmapMut>>,
+	map: RwLock<Option<IndexMap>>,
 	path: std::path::PathBuf,
+	io: IndexIo,
+	cache: Option<ChunkCache>,
+}
+
+/// Runtime settings of index tables of a column.
+#[derive(Debug, Clone, Default)]
+pub struct IndexIo {
+	/// See `ColumnOptions::access_hint`.
+	pub hint: AccessHint,
+	/// See `ColumnOptions::index_cache_bytes`.
+	pub cache_bytes: usize,
+	/// Where the files are kept, see `Options::storage`.
+	pub files: Files,
+	/// See `Options::preallocate`.
+	pub preallocate: bool,
+}
+
+// Contents of an index file. Files of `Options::storage` can't be mapped, they are read in
+// memory and modified chunks are written back on flush.
+enum IndexMap {
+	Mapped(memmap2::MmapMut),
+	Loaded { data: Vec<u8>, file: Arc<dyn StorageFile>, dirty: Mutex<BTreeSet<u64>> },
+}
+
+impl IndexMap {
+	fn load(file: Arc<dyn StorageFile>, index_bits: u8) -> std::io::Result<IndexMap> {
+		let mut data = vec![0; file_size(index_bits) as usize];
+		let len = (file.size()? as usize).min(data.len());
+		file.read_at(&mut data[..len], 0)?;
+		file.set_size(data.len() as u64)?;
+		Ok(IndexMap::Loaded { data, file, dirty: Default::default() })
+	}
+}
+
+impl std::ops::Deref for IndexMap {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		match self {
+			IndexMap::Mapped(map) => map,
+			IndexMap::Loaded { data, .. } => data,
+		}
+	}
+}
+
+impl std::ops::DerefMut for IndexMap {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		match self {
+			IndexMap::Mapped(map) => map,
+			IndexMap::Loaded { data, .. } => data,
+		}
+	}
+}
+
+impl std::fmt::Debug for IndexMap {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			IndexMap::Mapped(map) => map.fmt(f),
+			IndexMap::Loaded { data, .. } => write!(f, "IndexMap::Loaded({} bytes)", data.len()),
+		}
+	}
+}
+
+impl IndexIo {
+	fn cache(&self) -> Option<ChunkCache> {
+		(self.cache_bytes > 0).then(|| ChunkCache::new(self.cache_bytes))
+	}
 }
 
 fn total_entries(index_bits: u8) -> u64 {
@@ -134,6 +210,11 @@ fn total_chunks(index_bits: u8) -> u64 {
 	1u64 << index_bits
 }
 
+/// Index of the chunk holding `key` in an index of `index_bits`.
+pub fn key_chunk(key: &Key, index_bits: u8) -> u64 {
+	TableKey::index_from_partial(key) >> (ENTRY_LEN - index_bits)
+}
+
 fn file_size(index_bits: u8) -> u64 {
 	total_entries(index_bits) * 8 + META_SIZE as u64
 }
@@ -186,39 +267,65 @@ impl std::fmt::Display for TableId {
 }
 
 impl IndexTable {
-	pub fn open_existing(path: &std::path::Path, id: TableId) -> Result<Option<IndexTable>> {
+	pub fn open_existing(
+		path: &std::path::Path,
+		id: TableId,
+		io: IndexIo,
+	) -> Result<Option<IndexTable>> {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 
+		if let Some(storage) = io.files.storage() {
+			let file = match try_io!(storage.open(try_io!(Files::name(&path)), false)) {
+				Some(file) => file,
+				None => return Ok(None),
+			};
+			let map = try_io!(IndexMap::load(file, id.index_bits()));
+			log::debug!(target: "parity-db", "Opened existing index {}", id);
+			let cache = io.cache();
+			return Ok(Some(IndexTable { id, path, map: RwLock::new(Some(map)), io, cache }))
+		}
+
 		let file = match std::fs::OpenOptions::new().read(true).write(true).open(path.as_path()) {
 			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
-			Err(e) => return Err(Error::Io(e)),
+			Err(e) => return Err(Error::file_io(&path, "open", e)),
 			Ok(file) => file,
 		};
 
-		try_io!(file.set_len(file_size(id.index_bits())));
-		let map = try_io!(unsafe { memmap2::MmapMut::map_mut(&file) });
+		try_io!(
+			crate::file::extend_file(&file, file_size(id.index_bits()), io.preallocate),
+			&path,
+			"extend"
+		);
+		let mut map = try_io!(unsafe { memmap2::MmapMut::map_mut(&file) }, &path, "map");
+		Self::madvise(&mut map, io.hint);
 		log::debug!(target: "parity-db", "Opened existing index {}", id);
-		Ok(Some(IndexTable { id, path, map: RwLock::new(Some(map)) }))
+		let cache = io.cache();
+		Ok(Some(IndexTable { id, path, map: RwLock::new(Some(IndexMap::Mapped(map))), io, cache }))
 	}
 
-	pub fn create_new(path: &std::path::Path, id: TableId) -> IndexTable {
+	pub fn create_new(path: &std::path::Path, id: TableId, io: IndexIo) -> IndexTable {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
-		IndexTable { id, path, map: RwLock::new(None) }
+		let cache = io.cache();
+		IndexTable { id, path, map: RwLock::new(None), io, cache }
+	}
+
+	pub fn io(&self) -> IndexIo {
+		self.io.clone()
 	}
 
-	pub fn load_stats(&self) -> Result<ColumnStats> {
+	pub fn load_stats(&self) -> Result<ColumnStatCounters> {
 		if let Some(map) = &*self.map.read() {
-			Ok(ColumnStats::from_slice(try_io!(Ok(
+			Ok(ColumnStatCounters::from_slice(try_io!(Ok(
 				&map[HEADER_SIZE..HEADER_SIZE + stats::TOTAL_SIZE]
 			))))
 		} else {
-			Ok(ColumnStats::empty())
+			Ok(ColumnStatCounters::empty())
 		}
 	}
 
-	pub fn write_stats(&self, stats: &ColumnStats) -> Result<()> {
+	pub fn write_stats(&self, stats: &ColumnStatCounters) -> Result<()> {
 		if let Some(map) = &mut *self.map.write() {
 			let slice = try_io!(Ok(&mut map[HEADER_SIZE..HEADER_SIZE + stats::TOTAL_SIZE]));
 			stats.to_slice(slice);
@@ -226,8 +333,13 @@ impl IndexTable {
 		Ok(())
 	}
 
-	fn chunk_at(index: u64, map: &memmap2::MmapMut) -> Result<&[u8]> {
-		let offset = META_SIZE + index as usize * CHUNK_LEN;
+	/// File offset of chunk `index`.
+	pub fn chunk_offset(index: u64) -> u64 {
+		META_SIZE as u64 + index * CHUNK_LEN as u64
+	}
+
+	fn chunk_at(index: u64, map: &[u8]) -> Result<&[u8]> {
+		let offset = Self::chunk_offset(index) as usize;
 		Ok(try_io!(Ok(&map[offset..offset + CHUNK_LEN])))
 	}
 
@@ -266,12 +378,75 @@ impl IndexTable {
 			return Ok(entry)
 		}
 
-		if let Some(map) = &*self.map.read() {
-			log::trace!(target: "parity-db", "{}: Querying chunk at {}", self.id, chunk_index);
-			let chunk = Self::chunk_at(chunk_index, map)?;
-			return Ok(self.find_entry(key, sub_index, chunk))
+		log::trace!(target: "parity-db", "{}: Querying chunk at {}", self.id, chunk_index);
+		let entry = self.with_chunk(chunk_index, |chunk| self.find_entry(key, sub_index, chunk))?;
+		Ok(entry.unwrap_or((Entry::empty(), 0)))
+	}
+
+	/// Call `f` with the position of each of `keys` and each entry matching it, in order.
+	/// `keys` must be sorted, so that the keys of a chunk are next to each other and each chunk
+	/// is read once.
+	pub fn get_sorted<'a>(
+		&self,
+		keys: impl Iterator<Item = &'a Key>,
+		log: &impl LogQuery,
+		mut f: impl FnMut(usize, Entry),
+	) -> Result<()> {
+		let mut chunk: Option<(u64, Chunk)> = None;
+		for (n, key) in keys.enumerate() {
+			let key_prefix = TableKey::index_from_partial(key);
+			let chunk_index = self.chunk_index(key_prefix);
+			let data = match &chunk {
+				Some((index, data)) if *index == chunk_index => data,
+				_ => {
+					let data = match log.with_index(self.id, chunk_index, |chunk| *chunk) {
+						Some(data) => data,
+						None => self
+							.with_chunk(chunk_index, |chunk| chunk.try_into().expect("Chunk size"))?
+							.unwrap_or(EMPTY_CHUNK),
+					};
+					&chunk.insert((chunk_index, data)).1
+				},
+			};
+			let mut sub_index = 0;
+			loop {
+				let (entry, i) = self.find_entry(key_prefix, sub_index, data);
+				if entry.is_empty() {
+					break
+				}
+				f(n, entry);
+				sub_index = i + 1;
+			}
+		}
+		Ok(())
+	}
+
+	// Call `f` with the on-disk contents of a chunk, through the chunk cache if enabled.
+	// Returns `None` if the index file does not exist yet.
+	fn with_chunk<R>(&self, chunk_index: u64, f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+		#[cfg(test)]
+		CHUNK_READS.with(|reads| reads.set(reads.get() + 1));
+		let cache = match &self.cache {
+			Some(cache) => cache,
+			None => match &*self.map.read() {
+				Some(map) => return Ok(Some(f(Self::chunk_at(chunk_index, map)?))),
+				None => return Ok(None),
+			},
+		};
+		if let Some(chunk) = cache.get(&chunk_index) {
+			return Ok(Some(f(&chunk)))
+		}
+		// Taken before reading, so that a chunk enacted meanwhile is not cached.
+		let epoch = cache.epoch();
+		match &*self.map.read() {
+			Some(map) => {
+				let chunk = Self::chunk_at(chunk_index, map)?;
+				let copy = chunk.try_into().expect("Chunk size");
+				cache.insert_unchanged(epoch, chunk_index, copy, CHUNK_LEN);
+				Ok(Some(f(chunk)))
+			},
+			None => Ok(None),
 		}
-		Ok((Entry::empty(), 0))
 	}
 
 	pub fn entries(&self, chunk_index: u64, log: &impl LogQuery) -> Result<[Entry; CHUNK_ENTRIES]> {
@@ -315,6 +490,11 @@ impl IndexTable {
 		key_prefix >> (ENTRY_LEN - self.id.index_bits())
 	}
 
+	/// Index of the chunk holding `key`.
+	pub fn key_chunk(&self, key: &Key) -> u64 {
+		self.chunk_index(TableKey::index_from_partial(key))
+	}
+
 	fn plan_insert_chunk(
 		&self,
 		key_prefix: u64,
@@ -372,9 +552,10 @@ impl IndexTable {
 			return self.plan_insert_chunk(key_prefix, address, &chunk, sub_index, log)
 		}
 
-		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map)?;
-			return self.plan_insert_chunk(key_prefix, address, chunk, sub_index, log)
+		if let Some(outcome) = self.with_chunk(chunk_index, |chunk| {
+			self.plan_insert_chunk(key_prefix, address, chunk, sub_index, log)
+		})? {
+			return outcome
 		}
 
 		let chunk = &EMPTY_CHUNK;
@@ -420,9 +601,10 @@ impl IndexTable {
 			return self.plan_remove_chunk(key_prefix, &chunk, sub_index, log)
 		}
 
-		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map)?;
-			return self.plan_remove_chunk(key_prefix, chunk, sub_index, log)
+		if let Some(outcome) = self.with_chunk(chunk_index, |chunk| {
+			self.plan_remove_chunk(key_prefix, chunk, sub_index, log)
+		})? {
+			return outcome
 		}
 
 		Ok(PlanOutcome::Skipped)
@@ -432,17 +614,35 @@ impl IndexTable {
 		let mut map = self.map.upgradable_read();
 		if map.is_none() {
 			let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
-			let file = try_io!(std::fs::OpenOptions::new()
-				.write(true)
-				.read(true)
-				.create_new(true)
-				.open(self.path.as_path()));
+			let size = file_size(self.id.index_bits());
+			let new_map = if let Some(storage) = self.io.files.storage() {
+				let file = try_io!(storage.open(try_io!(Files::name(&self.path)), true))
+					.expect("Files are created when requested");
+				try_io!(file.set_size(0));
+				try_io!(IndexMap::load(file, self.id.index_bits()))
+			} else {
+				let file = try_io!(
+					std::fs::OpenOptions::new()
+						.write(true)
+						.read(true)
+						.create_new(true)
+						.open(self.path.as_path()),
+					&self.path,
+					"create"
+				);
+				//TODO: check for potential overflows on 32-bit platforms
+				try_io!(
+					crate::file::extend_file(&file, size, self.io.preallocate),
+					&self.path,
+					"extend"
+				);
+				let mut mmap =
+					try_io!(unsafe { memmap2::MmapMut::map_mut(&file) }, &self.path, "map");
+				Self::madvise(&mut mmap, self.io.hint);
+				IndexMap::Mapped(mmap)
+			};
 			log::debug!(target: "parity-db", "Created new index {}", self.id);
-			//TODO: check for potential overflows on 32-bit platforms
-			try_io!(file.set_len(file_size(self.id.index_bits())));
-			let mut mmap = try_io!(unsafe { memmap2::MmapMut::map_mut(&file) });
-			self.madvise_random(&mut mmap);
-			*wmap = Some(mmap);
+			*wmap = Some(new_map);
 			map = RwLockWriteGuard::downgrade_to_upgradable(wmap);
 		}
 
@@ -465,13 +665,19 @@ impl IndexTable {
 				&mut chunk[i as usize * ENTRY_BYTES..(i as usize + 1) * ENTRY_BYTES]
 			)))?;
 		}
+		if let IndexMap::Loaded { dirty, .. } = map {
+			dirty.lock().insert(index);
+		}
+		if let Some(cache) = &self.cache {
+			cache.invalidate(&index);
+		}
 		log::trace!(target: "parity-db", "{}: Enacted chunk {}", self.id, index);
 		Ok(())
 	}
 
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
 		if index >= self.id.total_entries() {
-			return Err(Error::Corruption("Bad index".into()))
+			return Err(Error::LogCorruption(format!("Bad index chunk {} of {}", index, self.id)))
 		}
 		let mut buf = [0u8; 8];
 		log.read(&mut buf)?;
@@ -485,6 +691,68 @@ impl IndexTable {
 		Ok(())
 	}
 
+	/// Read a logged chunk update into `writer`, on top of the current chunk contents.
+	pub fn follow_plan(
+		&self,
+		index: u64,
+		log: &mut LogReader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		if index >= self.id.total_entries() {
+			return Err(Error::LogCorruption(format!("Bad index chunk {} of {}", index, self.id)))
+		}
+		let mut chunk = match writer.with_index(self.id, index, |chunk| *chunk) {
+			Some(chunk) => chunk,
+			None => match &*self.map.read() {
+				Some(map) => Self::chunk_at(index, map)?.try_into().expect("Chunk size"),
+				None => EMPTY_CHUNK,
+			},
+		};
+		let mut buf = [0u8; 8];
+		log.read(&mut buf)?;
+		let mask = u64::from_le_bytes(buf);
+		let mut remaining = mask;
+		while remaining != 0 {
+			let i = remaining.trailing_zeros() as usize;
+			remaining &= !(1 << i);
+			log.read(&mut chunk[i * ENTRY_BYTES..(i + 1) * ENTRY_BYTES])?;
+		}
+		let mut remaining = mask;
+		while remaining != 0 {
+			let i = remaining.trailing_zeros();
+			remaining &= !(1 << i);
+			writer.insert_index(self.id, index, i as u8, &chunk);
+		}
+		Ok(())
+	}
+
+	/// Map the index file if it was created by another process after opening.
+	pub fn refresh(&self) -> Result<()> {
+		if self.map.read().is_some() {
+			return Ok(())
+		}
+		if let Some(table) =
+			Self::open_existing(self.path.parent().expect("File path"), self.id, self.io.clone())?
+		{
+			*self.map.write() = table.map.write().take();
+		}
+		Ok(())
+	}
+
+	/// Size of the index file in bytes, or 0 if it is not created yet.
+	pub fn file_size(&self) -> u64 {
+		if self.map.read().is_some() {
+			file_size(self.id.index_bits())
+		} else {
+			0
+		}
+	}
+
+	/// Check if the index file still exists.
+	pub fn exists(&self) -> bool {
+		self.io.files.exists(&self.path).unwrap_or(false)
+	}
+
 	pub fn skip_plan(log: &mut LogReader) -> Result<()> {
 		let mut buf = [0u8; 8];
 		log.read(&mut buf)?;
@@ -498,33 +766,67 @@ impl IndexTable {
 	}
 
 	pub fn drop_file(self) -> Result<()> {
-		drop(self.map);
-		try_io!(std::fs::remove_file(self.path.as_path()));
-		log::debug!(target: "parity-db", "{}: Dropped table", self.id);
+		let id = self.id;
+		let files = self.io.files.clone();
+		let path = self.close();
+		try_io!(files.remove(path.as_path()));
+		log::debug!(target: "parity-db", "{}: Dropped table", id);
 		Ok(())
 	}
 
+	/// Unmap the index file and return its path.
+	pub fn close(self) -> std::path::PathBuf {
+		drop(self.map);
+		self.path
+	}
+
 	pub fn flush(&self) -> Result<()> {
-		if let Some(map) = &*self.map.read() {
-			// Flush everything except stats.
-			try_io!(map.flush_range(META_SIZE, map.len() - META_SIZE));
+		match &*self.map.read() {
+			Some(IndexMap::Mapped(map)) => {
+				// Flush everything except stats.
+				try_io!(map.flush_range(META_SIZE, map.len() - META_SIZE), &self.path, "sync");
+			},
+			Some(IndexMap::Loaded { data, file, dirty }) => {
+				let dirty = std::mem::take(&mut *dirty.lock());
+				try_io!(file.write_at(&data[..META_SIZE], 0));
+				for index in dirty {
+					let offset = META_SIZE + index as usize * CHUNK_LEN;
+					try_io!(file.write_at(&data[offset..offset + CHUNK_LEN], offset as u64));
+				}
+				try_io!(file.sync(), &self.path, "sync");
+			},
+			None => (),
 		}
 		Ok(())
 	}
 
 	#[cfg(unix)]
-	fn madvise_random(&self, map: &mut memmap2::MmapMut) {
+	fn madvise(map: &mut memmap2::MmapMut, hint: AccessHint) {
+		let advice = match hint {
+			AccessHint::Random | AccessHint::DontNeed => libc::MADV_RANDOM,
+			AccessHint::Sequential => libc::MADV_SEQUENTIAL,
+		};
 		unsafe {
-			libc::madvise(
-				map.as_mut_ptr() as _,
-				file_size(self.id.index_bits()) as usize,
-				libc::MADV_RANDOM,
-			);
+			libc::madvise(map.as_mut_ptr() as _, map.len(), advice);
+		}
+	}
+
+	#[cfg(not(unix))]
+	fn madvise(_map: &mut memmap2::MmapMut, _hint: AccessHint) {}
+
+	/// Release resident pages of the index. Modified pages stay in the page cache and are
+	/// written back as usual.
+	#[cfg(unix)]
+	pub fn drop_cache(&self) {
+		if let Some(IndexMap::Mapped(map)) = &mut *self.map.write() {
+			unsafe {
+				libc::madvise(map.as_mut_ptr() as _, map.len(), libc::MADV_DONTNEED);
+			}
 		}
 	}
 
 	#[cfg(not(unix))]
-	fn madvise_random(&self, _map: &mut memmap2::MmapMut) {}
+	pub fn drop_cache(&self) {}
 }
 
 #[cfg(test)]
@@ -0,0 +1,322 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Ingest files hold the keys and values of a btree indexed column in ascending key order.
+//! They are written offline with `IngestFileWriter` or by `Db::export_sorted`, and attached to
+//! a database with `Db::ingest_file`, bypassing the commit pipeline.
+//!
+//! A file starts with a header: magic, format version and a CRC32 of both. It is followed by
+//! entry records, each holding the length-prefixed key and value and protected by a CRC32.
+//! A final record holds the number of entries and a CRC32 of all bytes before the record. All
+//! integers are little endian.
+
+use crate::{
+	column::ColId,
+	db::Db,
+	display::hex,
+	error::{try_io, Error, Result},
+};
+use std::{
+	fs::File,
+	io::{BufReader, BufWriter, Read, Write},
+	path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"PDBINGST";
+const FORMAT_VERSION: u8 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_ENTRY: u8 = 1;
+
+// Encoder of the records of an ingest file.
+struct Encoder<W> {
+	writer: W,
+	// All bytes written.
+	hasher: crc32fast::Hasher,
+	last_key: Option<Vec<u8>>,
+	count: u64,
+}
+
+impl<W: Write> Encoder<W> {
+	fn new(writer: W) -> Result<Self> {
+		let mut encoder =
+			Encoder { writer, hasher: crc32fast::Hasher::new(), last_key: None, count: 0 };
+		let mut header = MAGIC.to_vec();
+		header.push(FORMAT_VERSION);
+		let crc = crc32fast::hash(&header);
+		header.extend_from_slice(&crc.to_le_bytes());
+		encoder.write(&header)?;
+		Ok(encoder)
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<()> {
+		self.hasher.update(buf);
+		try_io!(self.writer.write_all(buf));
+		Ok(())
+	}
+
+	fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+		if self.last_key.as_deref().is_some_and(|last| last >= key) {
+			return Err(Error::InvalidInput(format!("Key {} is not in ascending order", hex(key))))
+		}
+		let mut buf = Vec::with_capacity(13 + key.len() + value.len());
+		buf.push(RECORD_ENTRY);
+		buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+		buf.extend_from_slice(key);
+		buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+		buf.extend_from_slice(value);
+		let crc = crc32fast::hash(&buf);
+		buf.extend_from_slice(&crc.to_le_bytes());
+		self.write(&buf)?;
+		self.last_key = Some(key.to_vec());
+		self.count += 1;
+		Ok(())
+	}
+
+	fn finish(&mut self) -> Result<u64> {
+		let mut buf = vec![RECORD_END];
+		buf.extend_from_slice(&self.count.to_le_bytes());
+		buf.extend_from_slice(&self.hasher.clone().finalize().to_le_bytes());
+		let crc = crc32fast::hash(&buf);
+		buf.extend_from_slice(&crc.to_le_bytes());
+		self.write(&buf)?;
+		try_io!(self.writer.flush());
+		Ok(self.count)
+	}
+}
+
+/// Writer of an ingest file, to be attached to a btree indexed column with `Db::ingest_file`.
+/// The file is only valid once `finish` is called.
+pub struct IngestFileWriter {
+	encoder: Encoder<BufWriter<File>>,
+	path: PathBuf,
+}
+
+impl IngestFileWriter {
+	/// Create the file at `path`, replacing any existing file.
+	pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+		let path = path.as_ref().to_path_buf();
+		let file = BufWriter::new(try_io!(File::create(&path), &path, "create"));
+		Ok(IngestFileWriter { encoder: Encoder::new(file)?, path })
+	}
+
+	/// Add `key` and `value`. Keys must be added in strictly ascending order.
+	pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+		self.encoder.add(key, value)
+	}
+
+	/// Write the end of the file and sync it to disk. Returns the number of entries.
+	pub fn finish(mut self) -> Result<u64> {
+		let count = self.encoder.finish()?;
+		try_io!(self.encoder.writer.get_ref().sync_all(), &self.path, "sync");
+		Ok(count)
+	}
+}
+
+/// Write all keys and values of btree indexed column `col` to `writer` in the ingest file
+/// format, as of a snapshot. Returns the number of entries.
+pub fn export_sorted(db: &Db, col: ColId, writer: &mut impl Write) -> Result<u64> {
+	if !db.column_options(col).is_some_and(|options| options.btree_index) {
+		return Err(Error::InvalidInput(format!("Column {} is not btree indexed", col)))
+	}
+	let mut encoder = Encoder::new(writer)?;
+	let mut iter = db.iter_consistent(col)?;
+	while let Some((key, value)) = iter.next()? {
+		encoder.add(&key, &value)?;
+	}
+	encoder.finish()
+}
+
+/// Entries of an ingest file, in file order. Fails on checksum or count mismatch.
+pub struct IngestFileReader {
+	reader: BufReader<File>,
+	path: PathBuf,
+	// All bytes read.
+	hasher: crc32fast::Hasher,
+	count: u64,
+	done: bool,
+}
+
+impl IngestFileReader {
+	pub fn open(path: &Path) -> Result<Self> {
+		let mut reader = BufReader::new(try_io!(File::open(path), path, "open"));
+		let mut header = [0u8; 8 + 1 + 4];
+		try_io!(reader.read_exact(&mut header), path, "read");
+		if &header[..8] != MAGIC {
+			return Err(Error::InvalidInput(format!("{} is not an ingest file", path.display())))
+		}
+		if crc32fast::hash(&header[..9]).to_le_bytes() != header[9..] {
+			return Err(Error::Corruption(format!(
+				"{}: ingest file header checksum mismatch",
+				path.display()
+			)))
+		}
+		if header[8] != FORMAT_VERSION {
+			return Err(Error::InvalidInput(format!(
+				"{}: unsupported ingest file version {}",
+				path.display(),
+				header[8]
+			)))
+		}
+		let mut hasher = crc32fast::Hasher::new();
+		hasher.update(&header);
+		Ok(IngestFileReader { reader, path: path.to_path_buf(), hasher, count: 0, done: false })
+	}
+
+	fn read(&mut self, len: usize, hasher: &mut crc32fast::Hasher) -> Result<Vec<u8>> {
+		let mut buf = vec![0u8; len];
+		try_io!(self.reader.read_exact(&mut buf), &self.path, "read");
+		hasher.update(&buf);
+		self.hasher.update(&buf);
+		Ok(buf)
+	}
+
+	fn read_len(&mut self, hasher: &mut crc32fast::Hasher) -> Result<usize> {
+		let buf = self.read(4, hasher)?;
+		Ok(u32::from_le_bytes(buf.try_into().expect("4 bytes read")) as usize)
+	}
+
+	/// Read the next entry, or `None` after the end record.
+	fn read_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+		let file_crc = self.hasher.clone().finalize();
+		let mut hasher = crc32fast::Hasher::new();
+		let tag = self.read(1, &mut hasher)?[0];
+		let entry = match tag {
+			RECORD_END => {
+				let count = self.read(8, &mut hasher)?;
+				if u64::from_le_bytes(count.try_into().expect("8 bytes read")) != self.count {
+					return Err(self.corrupted("entry count mismatch"))
+				}
+				if self.read(4, &mut hasher)? != file_crc.to_le_bytes() {
+					return Err(self.corrupted("checksum mismatch"))
+				}
+				None
+			},
+			RECORD_ENTRY => {
+				let len = self.read_len(&mut hasher)?;
+				let key = self.read(len, &mut hasher)?;
+				let len = self.read_len(&mut hasher)?;
+				let value = self.read(len, &mut hasher)?;
+				self.count += 1;
+				Some((key, value))
+			},
+			_ => return Err(self.corrupted("bad record tag")),
+		};
+		let crc = self.read(4, &mut crc32fast::Hasher::new())?;
+		if crc[..] != hasher.finalize().to_le_bytes() {
+			return Err(self.corrupted("record checksum mismatch"))
+		}
+		Ok(entry)
+	}
+
+	fn corrupted(&self, reason: &str) -> Error {
+		Error::Corruption(format!("{}: ingest file {}", self.path.display(), reason))
+	}
+}
+
+impl Iterator for IngestFileReader {
+	type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None
+		}
+		let entry = self.read_entry();
+		self.done = !matches!(entry, Ok(Some(_)));
+		entry.transpose()
+	}
+}
+
+/// Check the checksums, key order and entry count of the ingest file at `path`. Returns the
+/// number of entries.
+pub fn verify(path: &Path) -> Result<u64> {
+	let mut reader = IngestFileReader::open(path)?;
+	let mut last: Option<Vec<u8>> = None;
+	while let Some((key, _)) = reader.read_entry()? {
+		if last.as_ref().is_some_and(|last| *last >= key) {
+			return Err(reader.corrupted("keys are not in ascending order"))
+		}
+		last = Some(key);
+	}
+	if try_io!(reader.reader.read(&mut [0u8]), path, "read") != 0 {
+		return Err(reader.corrupted("data after the end record"))
+	}
+	Ok(reader.count)
+}
+
+#[cfg(test)]
+mod test {
+	use super::IngestFileWriter;
+	use crate::{Db, Options};
+	use tempfile::tempdir;
+
+	#[test]
+	fn ingest_file() {
+		let dir = tempdir().unwrap();
+		let path = dir.path().join("ingest");
+		let key = |i: u32| i.to_be_bytes();
+		let mut writer = IngestFileWriter::create(&path).unwrap();
+		for i in 0..1000u32 {
+			writer.add(&key(i), &vec![i as u8; i as usize % 100]).unwrap();
+		}
+		assert!(writer.add(&key(5), &[]).is_err());
+		assert_eq!(writer.finish().unwrap(), 1000);
+
+		let unfinished = dir.path().join("unfinished");
+		let mut writer = IngestFileWriter::create(&unfinished).unwrap();
+		writer.add(&key(0), &[0]).unwrap();
+		drop(writer);
+		let corrupted = dir.path().join("corrupted");
+		let mut data = std::fs::read(&path).unwrap();
+		let at = data.len() / 2;
+		data[at] ^= 1;
+		std::fs::write(&corrupted, data).unwrap();
+
+		let mut options = Options::with_columns(&dir.path().join("db"), 2);
+		options.columns[0].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(db.ingest_file(0, &unfinished).is_err());
+		assert!(db.ingest_file(0, &corrupted).is_err());
+		assert!(db.ingest_file(1, &path).is_err());
+		assert_eq!(db.get(0, &key(0)).unwrap(), None);
+		assert_eq!(db.ingest_file(0, &path).unwrap(), 1000);
+		assert!(db.ingest_file(0, &path).is_err());
+		for i in 0..1000u32 {
+			assert_eq!(db.get(0, &key(i)).unwrap(), Some(vec![i as u8; i as usize % 100]));
+		}
+	}
+
+	#[test]
+	fn export_sorted() {
+		let dir = tempdir().unwrap();
+		let mut options = Options::with_columns(&dir.path().join("db"), 3);
+		options.columns[0].btree_index = true;
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let entries: Vec<_> = (0..500u32).map(|i| (i.to_be_bytes(), vec![i as u8; 10])).collect();
+		db.commit(entries.iter().rev().map(|(k, v)| (0, k, Some(v.clone())))).unwrap();
+		let mut exported = Vec::new();
+		assert_eq!(db.export_sorted(0, &mut exported).unwrap(), 500);
+		assert!(db.export_sorted(2, Vec::new()).is_err());
+
+		// Same output as writing the entries in order.
+		let path = dir.path().join("ingest");
+		let mut writer = IngestFileWriter::create(&path).unwrap();
+		for (key, value) in &entries {
+			writer.add(key, value).unwrap();
+		}
+		writer.finish().unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), exported);
+
+		let mut corrupted = exported.clone();
+		let at = corrupted.len() - 20;
+		corrupted[at] ^= 1;
+		std::fs::write(&path, corrupted).unwrap();
+		assert!(db.ingest_file(1, &path).is_err());
+		std::fs::write(&path, &exported).unwrap();
+		assert_eq!(db.ingest_file(1, &path).unwrap(), 500);
+		for (key, value) in &entries {
+			assert_eq!(db.get(1, key).unwrap().as_ref(), Some(value));
+		}
+	}
+}
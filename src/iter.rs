@@ -0,0 +1,251 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Iteration over hash indexed columns, see `Db::iter_column`, and cursors to resume scans.
+//!
+//! Values of hash indexed columns are returned in the order of their key hashes, which is also
+//! the order of index chunks, so that the position of the iterator is just the last key hash
+//! returned. The position stays valid across reindexing and commits made in the meantime.
+//! Likewise, the position of a btree iterator is the last key returned.
+
+use crate::{
+	btree::LastKey,
+	column::ColId,
+	db::Db,
+	error::{Error, Result},
+	Key, Value,
+};
+use std::collections::VecDeque;
+
+// Key hash, original key of a `store_keys` column and value.
+pub(crate) type HashEntry = (Key, Option<Vec<u8>>, Value);
+
+// Min number of values read at once.
+const BATCH_SIZE: usize = 256;
+
+const CURSOR_VERSION: u8 = 1;
+
+const KIND_HASH: u8 = 0;
+const KIND_TREE: u8 = 1;
+
+const LAST_START: u8 = 0;
+const LAST_END: u8 = 1;
+const LAST_AT: u8 = 2;
+const LAST_SEEKED: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Position {
+	// Last key hash returned, `None` before the first one, and whether all were returned.
+	Hash { last: Option<Key>, done: bool },
+	Tree { prefix: Option<Vec<u8>>, last: LastKey },
+}
+
+/// Position of a column scan, returned by `ColumnIterator::cursor` or `BTreeIterator::cursor`.
+/// It can be stored with `to_bytes` to resume the scan later, possibly in another process, with
+/// `Db::resume_iter_column` or `Db::resume_iter`. Btree scans are resumed in forward direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+	col: ColId,
+	position: Position,
+}
+
+impl Cursor {
+	pub(crate) fn tree(col: ColId, prefix: Option<Vec<u8>>, last: LastKey) -> Cursor {
+		Cursor { col, position: Position::Tree { prefix, last } }
+	}
+
+	/// Column of the scan.
+	pub fn col(&self) -> ColId {
+		self.col
+	}
+
+	/// Prefix and position of a btree scan.
+	pub(crate) fn tree_position(&self) -> Result<(Option<&[u8]>, &LastKey)> {
+		match &self.position {
+			Position::Tree { prefix, last } => Ok((prefix.as_deref(), last)),
+			Position::Hash { .. } =>
+				Err(Error::InvalidInput("Not a cursor of a btree indexed column".into())),
+		}
+	}
+
+	/// Encode the cursor.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = vec![CURSOR_VERSION, self.col];
+		match &self.position {
+			Position::Hash { last, done } => {
+				buf.push(KIND_HASH);
+				buf.push(*done as u8);
+				write_bytes(&mut buf, last.as_ref().map(|key| &key[..]));
+			},
+			Position::Tree { prefix, last } => {
+				buf.push(KIND_TREE);
+				write_bytes(&mut buf, prefix.as_deref());
+				let (tag, key) = match last {
+					LastKey::Start => (LAST_START, None),
+					LastKey::End => (LAST_END, None),
+					LastKey::At(key) => (LAST_AT, Some(key.as_slice())),
+					LastKey::Seeked(key) => (LAST_SEEKED, Some(key.as_slice())),
+				};
+				buf.push(tag);
+				write_bytes(&mut buf, key);
+			},
+		}
+		let crc = crc32fast::hash(&buf);
+		buf.extend_from_slice(&crc.to_le_bytes());
+		buf
+	}
+
+	/// Decode a cursor encoded with `to_bytes`.
+	pub fn from_bytes(data: &[u8]) -> Result<Cursor> {
+		Self::decode(data).ok_or_else(|| Error::InvalidInput("Invalid cursor".into()))
+	}
+
+	fn decode(data: &[u8]) -> Option<Cursor> {
+		let (data, crc) = data.split_at(data.len().checked_sub(4)?);
+		if crc32fast::hash(data).to_le_bytes() != crc {
+			return None
+		}
+		let mut reader = Reader(data);
+		if reader.byte()? != CURSOR_VERSION {
+			return None
+		}
+		let col = reader.byte()?;
+		let position = match reader.byte()? {
+			KIND_HASH => {
+				let done = reader.byte()? != 0;
+				let last = match reader.bytes()? {
+					Some(key) => Some(key.try_into().ok()?),
+					None => None,
+				};
+				Position::Hash { last, done }
+			},
+			KIND_TREE => {
+				let prefix = reader.bytes()?.map(<[u8]>::to_vec);
+				let tag = reader.byte()?;
+				let last = match (tag, reader.bytes()?.map(<[u8]>::to_vec)) {
+					(LAST_START, None) => LastKey::Start,
+					(LAST_END, None) => LastKey::End,
+					(LAST_AT, Some(key)) => LastKey::At(key),
+					(LAST_SEEKED, Some(key)) => LastKey::Seeked(key),
+					_ => return None,
+				};
+				Position::Tree { prefix, last }
+			},
+			_ => return None,
+		};
+		reader.0.is_empty().then_some(Cursor { col, position })
+	}
+}
+
+// Optional length-prefixed bytes.
+fn write_bytes(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+	match bytes {
+		Some(bytes) => {
+			buf.push(1);
+			buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+			buf.extend_from_slice(bytes);
+		},
+		None => buf.push(0),
+	}
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+		if self.0.len() < len {
+			return None
+		}
+		let (data, rest) = self.0.split_at(len);
+		self.0 = rest;
+		Some(data)
+	}
+
+	fn byte(&mut self) -> Option<u8> {
+		self.take(1).map(|b| b[0])
+	}
+
+	fn bytes(&mut self) -> Option<Option<&'a [u8]>> {
+		match self.byte()? {
+			0 => Some(None),
+			1 => {
+				let len = u32::from_le_bytes(self.take(4)?.try_into().ok()?);
+				Some(Some(self.take(len as usize)?))
+			},
+			_ => None,
+		}
+	}
+}
+
+/// Iterator over a hash indexed column, returned by `Db::iter_column`.
+pub struct ColumnIterator<'a> {
+	db: &'a Db,
+	col: ColId,
+	// Last key hash returned.
+	last: Option<Key>,
+	values: VecDeque<HashEntry>,
+	// All values after the buffered ones were read.
+	done: bool,
+	store_keys: bool,
+}
+
+impl<'a> ColumnIterator<'a> {
+	pub(crate) fn new(db: &'a Db, col: ColId) -> Self {
+		let store_keys = Self::stores_keys(db, col);
+		ColumnIterator { db, col, last: None, values: VecDeque::new(), done: false, store_keys }
+	}
+
+	pub(crate) fn resume(db: &'a Db, cursor: &Cursor) -> Result<Self> {
+		match cursor.position {
+			Position::Hash { last, done } => Ok(ColumnIterator {
+				db,
+				col: cursor.col,
+				last,
+				values: VecDeque::new(),
+				done,
+				store_keys: Self::stores_keys(db, cursor.col),
+			}),
+			Position::Tree { .. } =>
+				Err(Error::InvalidInput("Not a cursor of a hash indexed column".into())),
+		}
+	}
+
+	/// Next key hash and value, in the order of key hashes.
+	#[allow(clippy::should_implement_trait)]
+	pub fn next(&mut self) -> Result<Option<(Key, Value)>> {
+		Ok(self.next_entry()?.map(|(key, _, value)| (key, value)))
+	}
+
+	/// Next key and value, in the order of key hashes. Only supported for columns with
+	/// `ColumnOptions::store_keys`.
+	pub fn next_keyed(&mut self) -> Result<Option<(Vec<u8>, Value)>> {
+		if !self.store_keys {
+			return Err(Error::InvalidInput(format!("Column {} does not store keys", self.col)))
+		}
+		Ok(self.next_entry()?.and_then(|(_, key, value)| Some((key?, value))))
+	}
+
+	fn stores_keys(db: &Db, col: ColId) -> bool {
+		db.column_options(col).is_some_and(|options| options.store_keys)
+	}
+
+	fn next_entry(&mut self) -> Result<Option<HashEntry>> {
+		if self.values.is_empty() && !self.done {
+			let (values, done) =
+				self.db.iter_column_batch(self.col, self.last.as_ref(), BATCH_SIZE)?;
+			self.values = values;
+			self.done = done;
+		}
+		let next = self.values.pop_front();
+		if let Some((key, _, _)) = &next {
+			self.last = Some(*key);
+		}
+		Ok(next)
+	}
+
+	/// Position after the last value returned.
+	pub fn cursor(&self) -> Cursor {
+		let done = self.done && self.values.is_empty();
+		Cursor { col: self.col, position: Position::Hash { last: self.last, done } }
+	}
+}
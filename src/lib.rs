@@ -1,31 +1,90 @@
 // Copyright 2021-2022 Parity Technologies (UK) Ltd.
 // This file is dual-licensed as Apache-2.0 or MIT.
 
+#[cfg(feature = "asyncdb")]
+mod asyncdb;
+mod backup;
+mod blob;
+mod bloom;
 mod btree;
+mod cache;
 mod column;
 mod compress;
 mod db;
 mod display;
+#[cfg(feature = "encryption")]
+mod encryption;
 mod error;
+mod export;
+#[cfg(any(test, feature = "instrumentation"))]
+mod fault;
 mod file;
 mod hash;
 mod index;
+mod ingest;
+mod iter;
+mod limiter;
+mod lock;
 mod log;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod migration;
 mod options;
 mod parking_lot;
+mod reader;
+mod secondary;
+mod snapshot;
 mod stats;
+mod storage;
 mod table;
+mod typed;
+#[cfg(target_os = "linux")]
+mod uring;
 
+#[cfg(feature = "asyncdb")]
+pub use asyncdb::DbFuture;
 pub use btree::BTreeIterator;
-pub use compress::CompressionType;
-pub use db::{check::CheckOptions, Db, Operation, Value};
+pub use compress::{
+	train_dictionary, CompressionType, LZ4HC_DEFAULT_LEVEL, LZ4HC_MAX_LEVEL, MAX_DICTIONARY_SIZE,
+};
+#[cfg(feature = "zstd")]
+pub use compress::{train_zstd_dictionary, ZSTD_DEFAULT_LEVEL, ZSTD_MAX_LEVEL};
+pub use db::{
+	check::{CheckIssue, CheckOptions, CheckReport, ColumnCheck, RecoveryReport, RepairReport},
+	Change, CommitHandle, CommitHook, CommitStage, Db, Operation, Snapshot, Value, ValueWriter,
+};
+#[cfg(feature = "encryption")]
+pub use encryption::{Encryption, EncryptionKey, KeyProvider, KeyRing, StaticKey};
 #[cfg(feature = "instrumentation")]
 pub use error::set_number_of_allowed_io_operations;
 pub use error::{Error, Result};
+#[cfg(any(test, feature = "instrumentation"))]
+pub use fault::{Fault, FaultRule, FaultyStorage, IoOp};
+pub use ingest::IngestFileWriter;
+pub use iter::{ColumnIterator, Cursor};
+pub use lock::LockHolder;
+pub use log::ArchivedLog;
+#[cfg(feature = "metrics")]
+pub use metrics::{Metrics, MetricsSink, PrometheusMetrics};
+#[cfg(feature = "rocksdb-migrate")]
+pub use migration::from_rocksdb;
 pub use migration::{clear_column, migrate};
-pub use options::{ColumnOptions, Options};
-pub use stats::{ColumnStatSummary, StatSummary};
+pub use options::{
+	AccessHint, ColumnOptions, ColumnOptionsBuilder, CommitOptions, GrowPolicy, IoLimit, KeyHash,
+	MemoryBudget, MergeOperator, Metadata, Options, OptionsBuilder, ReadMode, RecoveryMode,
+	ScrubFn, ScrubOptions, SecondaryIndex, MAX_VALUE_SIZE,
+};
+pub use reader::ValueReader;
+pub use secondary::SecondaryIterator;
+pub use snapshot::SnapshotIterator;
+#[cfg(feature = "encryption")]
+pub use stats::ReencryptionProgress;
+pub use stats::{
+	ColumnDiskUsage, ColumnStatSummary, ColumnStats, CompactionProgress, MigrationProgress,
+	QueueStats, RangeSize, SpaceUsage, StatSummary, TableOccupancy, TreeNode, TreeStats, ValueMeta,
+};
+pub use storage::{MemoryStorage, Storage, StorageFile};
+pub use typed::{Codec, TypedColumn};
 
 pub const KEY_SIZE: usize = 32;
 pub type Key = [u8; KEY_SIZE];
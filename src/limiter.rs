@@ -0,0 +1,43 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Rate limiting of background table writes, see `Options::background_io_limit`.
+//!
+//! Writes are accounted after they are done, pushing back the time at which the limit allows
+//! more. Background threads check `delay` before their next step and wait it out, so a large
+//! log record is written at once, followed by a long pause.
+
+use crate::{options::IoLimit, parking_lot::Mutex};
+use std::time::{Duration, Instant};
+
+// Debt allowed before writers are paused, so that small records are not paused one by one.
+const BURST: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct IoLimiter {
+	limit: IoLimit,
+	// Time at which the writes accounted so far are within the limit.
+	next: Mutex<Instant>,
+}
+
+impl IoLimiter {
+	pub fn new(limit: IoLimit) -> IoLimiter {
+		IoLimiter { limit, next: Mutex::new(Instant::now()) }
+	}
+
+	/// Account for `bytes` written in `ops` table writes.
+	pub fn consume(&self, bytes: u64, ops: u64) {
+		let secs =
+			|amount: u64, rate: u64| if rate == 0 { 0.0 } else { amount as f64 / rate as f64 };
+		let cost =
+			secs(bytes, self.limit.bytes_per_second).max(secs(ops, self.limit.ops_per_second));
+		let mut next = self.next.lock();
+		*next = (*next).max(Instant::now()) + Duration::from_secs_f64(cost);
+	}
+
+	/// Time to wait before writing more. `None` if writing is allowed now.
+	pub fn delay(&self) -> Option<Duration> {
+		let delay = self.next.lock().saturating_duration_since(Instant::now() + BURST);
+		(!delay.is_zero()).then_some(delay)
+	}
+}
@@ -0,0 +1,131 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Lock on the database directory, held by the process that has the database open for writing.
+//!
+//! The lock file records the process id and host name of the holder, so that an attempt to
+//! open the database while it is in use can tell where it is in use.
+
+use crate::error::{try_io, Error, Result};
+#[cfg(any(unix, windows))]
+use fs2::FileExt;
+use std::{
+	fs::File,
+	io::{Read, Seek, Write},
+	path::{Path, PathBuf},
+};
+
+const LOCK_FILE: &str = "lock";
+
+/// Process holding the lock on a database directory, as recorded in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+	pub pid: u32,
+	pub hostname: String,
+}
+
+impl LockHolder {
+	fn current() -> LockHolder {
+		LockHolder { pid: std::process::id(), hostname: hostname() }
+	}
+
+	fn read(file: &mut File) -> Option<LockHolder> {
+		let mut data = String::new();
+		file.read_to_string(&mut data).ok()?;
+		let mut lines = data.lines();
+		let pid = lines.next()?.parse().ok()?;
+		let hostname = lines.next()?.to_string();
+		Some(LockHolder { pid, hostname })
+	}
+
+	// Whether the process is known to be running. Processes on other hosts can't be checked.
+	fn is_running(&self) -> bool {
+		if self.hostname != hostname() {
+			return false
+		}
+		#[cfg(unix)]
+		{
+			// Signal 0 only checks that the process exists.
+			let result = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+			result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+		}
+		#[cfg(not(unix))]
+		true
+	}
+}
+
+impl std::fmt::Display for LockHolder {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "process {} on {}", self.pid, self.hostname)
+	}
+}
+
+fn hostname() -> String {
+	#[cfg(unix)]
+	{
+		let mut buf = [0u8; 256];
+		if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+			let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+			return String::from_utf8_lossy(&buf[..len]).into_owned()
+		}
+	}
+	std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+	path.join(LOCK_FILE)
+}
+
+fn open(path: &Path) -> Result<File> {
+	let path = lock_path(path);
+	Ok(try_io!(
+		std::fs::OpenOptions::new()
+			.create(true)
+			.truncate(false)
+			.read(true)
+			.write(true)
+			.open(&path),
+		&path,
+		"open"
+	))
+}
+
+/// Lock the database directory at `path` and record the current process as the holder.
+pub fn lock(path: &Path) -> Result<File> {
+	let mut file = open(path)?;
+	#[cfg(any(unix, windows))]
+	if let Err(e) = file.try_lock_exclusive() {
+		return Err(Error::Locked { holder: LockHolder::read(&mut file), source: e })
+	}
+	let holder = LockHolder::current();
+	try_io!(file.set_len(0));
+	try_io!(file.seek(std::io::SeekFrom::Start(0)));
+	try_io!(file.write_all(format!("{}\n{}\n", holder.pid, holder.hostname).as_bytes()));
+	Ok(file)
+}
+
+/// See `Db::force_unlock`.
+pub fn force_unlock(path: &Path) -> Result<Option<LockHolder>> {
+	if !try_io!(lock_path(path).try_exists()) {
+		return Ok(None)
+	}
+	let mut file = open(path)?;
+	#[cfg(any(unix, windows))]
+	if file.try_lock_exclusive().is_ok() {
+		return Ok(None)
+	}
+	let holder = LockHolder::read(&mut file);
+	drop(file);
+	if let Some(holder) = holder.as_ref().filter(|holder| holder.is_running()) {
+		return Err(Error::Locked {
+			holder: Some(holder.clone()),
+			source: std::io::Error::new(
+				std::io::ErrorKind::WouldBlock,
+				"the lock holder is still running",
+			),
+		})
+	}
+	log::warn!(target: "parity-db", "Removing lock held by {:?}", holder);
+	try_io!(std::fs::remove_file(lock_path(path)));
+	Ok(holder)
+}
@@ -4,9 +4,11 @@
 use crate::{
 	column::ColId,
 	error::{try_io, Error, Result},
+	file::{DbFile, SlowIo},
 	index::{Chunk as IndexChunk, TableId as IndexTableId, ENTRY_BYTES},
 	options::Options,
 	parking_lot::{RwLock, RwLockWriteGuard},
+	storage::Files,
 	table::TableId as ValueTableId,
 };
 use std::{
@@ -24,19 +26,19 @@ const INSERT_VALUE: u8 = 3;
 const END_RECORD: u8 = 4;
 const DROP_TABLE: u8 = 5;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct InsertIndexAction {
 	pub table: IndexTableId,
 	pub index: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct InsertValueAction {
 	pub table: ValueTableId,
 	pub index: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum LogAction {
 	BeginRecord,
 	InsertIndex(InsertIndexAction),
@@ -66,6 +68,54 @@ impl LogOverlays {
 	pub fn last_record_id(&self, col: ColId) -> u64 {
 		self.last_record_id.get(&col).cloned().unwrap_or(u64::MAX)
 	}
+
+	/// Returns true if all logged changes have been enacted.
+	pub fn is_empty(&self) -> bool {
+		self.index.is_empty() && self.value.values().all(|overlay| overlay.map.is_empty())
+	}
+
+	/// Size of the log records of changes to column `col` that are not yet enacted. Entries
+	/// changed by several records are counted once.
+	pub fn column_bytes(&self, col: ColId) -> u64 {
+		// Action tag, table id and entry index.
+		const ACTION_BYTES: u64 = 1 + 2 + 8;
+		let index: u64 = self
+			.index
+			.iter()
+			.filter(|(id, _)| id.col() == col)
+			.flat_map(|(_, overlay)| overlay.map.values())
+			.map(|(_, mask, _)| ACTION_BYTES + 8 + mask.count_ones() as u64 * ENTRY_BYTES as u64)
+			.sum();
+		let value: u64 = self
+			.value
+			.iter()
+			.filter(|(id, _)| id.col() == col)
+			.flat_map(|(_, overlay)| overlay.map.values())
+			.map(|(_, entry)| ACTION_BYTES + entry.len() as u64)
+			.sum();
+		index + value
+	}
+
+	// Add the changes of log record `record_id`. Returns the number of index and value entries.
+	fn extend(
+		&mut self,
+		record_id: u64,
+		index: HashMap<IndexTableId, IndexLogOverlay>,
+		values: HashMap<ValueTableId, ValueLogOverlay>,
+	) -> (usize, usize) {
+		let mut total_index = 0;
+		for (id, overlay) in index.into_iter() {
+			total_index += overlay.map.len();
+			self.index.entry(id).or_default().map.extend(overlay.map);
+		}
+		let mut total_value = 0;
+		for (id, overlay) in values.into_iter() {
+			total_value += overlay.map.len();
+			self.last_record_id.insert(id.col(), record_id);
+			self.value.entry(id).or_default().map.extend(overlay.map);
+		}
+		(total_index, total_value)
+	}
 }
 
 impl LogQuery for RwLock<LogOverlays> {
@@ -114,9 +164,25 @@ pub struct Cleared {
 	values: Vec<(ValueTableId, u64)>,
 }
 
+#[derive(Debug)]
+enum Source<'a> {
+	File(RwLockWriteGuard<'a, Option<Reading>>),
+	// Part of a record read in memory, see `LogReader::memory`.
+	Memory(&'a [u8]),
+}
+
+impl Source<'_> {
+	fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+		match self {
+			Source::File(reading) => reading.as_mut().unwrap().file.read_exact(buf),
+			Source::Memory(data) => data.read_exact(buf),
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct LogReader<'a> {
-	reading: RwLockWriteGuard<'a, Option<Reading>>,
+	source: Source<'a>,
 	record_id: u64,
 	read_bytes: u64,
 	crc32: crc32fast::Hasher,
@@ -129,10 +195,15 @@ impl<'a> LogReader<'a> {
 		self.record_id
 	}
 
+	/// Whether the record is replayed when the database is opened.
+	pub fn is_replay(&self) -> bool {
+		self.validate
+	}
+
 	fn new(reading: RwLockWriteGuard<'a, Option<Reading>>, validate: bool) -> LogReader<'a> {
 		LogReader {
 			cleared: Default::default(),
-			reading,
+			source: Source::File(reading),
 			record_id: 0,
 			read_bytes: 0,
 			crc32: crc32fast::Hasher::new(),
@@ -140,14 +211,43 @@ impl<'a> LogReader<'a> {
 		}
 	}
 
+	/// Reader of the table changes in `data`, part of replayed record `record_id` previously
+	/// returned by `reread`. Reads payloads only; the actions are not stored in `data`.
+	pub fn memory(record_id: u64, data: &'a [u8]) -> LogReader<'a> {
+		LogReader {
+			cleared: Default::default(),
+			source: Source::Memory(data),
+			record_id,
+			read_bytes: 0,
+			crc32: crc32fast::Hasher::new(),
+			validate: true,
+		}
+	}
+
+	fn reading(&mut self) -> &mut Option<Reading> {
+		match &mut self.source {
+			Source::File(reading) => reading,
+			Source::Memory(_) => panic!("Log reader does not read a file"),
+		}
+	}
+
+	fn file(&mut self) -> &mut std::io::BufReader<DbFile> {
+		&mut self.reading().as_mut().unwrap().file
+	}
+
+	/// Read again the part of the record read so far, leaving the position unchanged.
+	pub fn reread(&mut self) -> Result<Vec<u8>> {
+		let mut data = vec![0; self.read_bytes as usize];
+		let file = self.file();
+		try_io!(file.seek(std::io::SeekFrom::Current(-(data.len() as i64))));
+		try_io!(file.read_exact(&mut data));
+		Ok(data)
+	}
+
 	pub fn reset(&mut self) -> Result<()> {
 		self.cleared = Default::default();
-		try_io!(self
-			.reading
-			.as_mut()
-			.unwrap()
-			.file
-			.seek(std::io::SeekFrom::Current(-(self.read_bytes as i64))));
+		let read_bytes = self.read_bytes as i64;
+		try_io!(self.file().seek(std::io::SeekFrom::Current(-read_bytes)));
 		self.read_bytes = 0;
 		self.record_id = 0;
 		self.crc32 = crc32fast::Hasher::new();
@@ -156,7 +256,7 @@ impl<'a> LogReader<'a> {
 
 	pub fn next(&mut self) -> Result<LogAction> {
 		let mut read_buf = |size, buf: &mut [u8; 8]| -> Result<()> {
-			try_io!(self.reading.as_mut().unwrap().file.read_exact(&mut buf[0..size]));
+			try_io!(self.source.read_exact(&mut buf[0..size]));
 			self.read_bytes += size as u64;
 			if self.validate {
 				self.crc32.update(&buf[0..size]);
@@ -192,7 +292,7 @@ impl<'a> LogReader<'a> {
 				Ok(LogAction::InsertValue(InsertValueAction { table, index }))
 			},
 			END_RECORD => {
-				try_io!(self.reading.as_mut().unwrap().file.read_exact(&mut buf[0..4]));
+				try_io!(self.source.read_exact(&mut buf[0..4]));
 				self.read_bytes += 4;
 				if self.validate {
 					let checksum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
@@ -203,7 +303,7 @@ impl<'a> LogReader<'a> {
 						expected,
 					);
 					if checksum != expected {
-						return Err(Error::Corruption("Log record CRC-32 mismatch".into()))
+						return Err(Error::LogCorruption("Log record CRC-32 mismatch".into()))
 					}
 				} else {
 					log::trace!(target: "parity-db", "Read end of record");
@@ -216,12 +316,12 @@ impl<'a> LogReader<'a> {
 					IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
 				Ok(LogAction::DropTable(table))
 			},
-			_ => Err(Error::Corruption("Bad log entry type".into())),
+			_ => Err(Error::LogCorruption("Bad log entry type".into())),
 		}
 	}
 
 	pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-		try_io!(self.reading.as_mut().unwrap().file.read_exact(buf));
+		try_io!(self.source.read_exact(buf));
 		self.read_bytes += buf.len() as u64;
 		if self.validate {
 			self.crc32.update(buf);
@@ -244,6 +344,8 @@ pub struct LogChange {
 	local_values: HashMap<ValueTableId, ValueLogOverlay>,
 	record_id: u64,
 	dropped_tables: Vec<IndexTableId>,
+	// Overrides `Options::sync_wal`.
+	sync: Option<bool>,
 }
 
 impl LogChange {
@@ -253,6 +355,7 @@ impl LogChange {
 			local_values: Default::default(),
 			dropped_tables: Default::default(),
 			record_id,
+			sync: None,
 		}
 	}
 
@@ -260,7 +363,7 @@ impl LogChange {
 		self.local_values.get(&id)
 	}
 
-	fn flush_to_file(self, file: &mut std::io::BufWriter<std::fs::File>) -> Result<FlushedLog> {
+	fn flush_to_file(self, file: &mut std::io::BufWriter<DbFile>) -> Result<FlushedLog> {
 		let mut crc32 = crc32fast::Hasher::new();
 		let mut bytes: u64 = 0;
 
@@ -328,6 +431,12 @@ impl<'a> LogWriter<'a> {
 		LogWriter { overlays, log: LogChange::new(record_id) }
 	}
 
+	/// Sync the log file holding this record, or skip syncing it if no other record needs it,
+	/// regardless of `Options::sync_wal`.
+	pub fn set_sync(&mut self, sync: Option<bool>) {
+		self.log.sync = sync;
+	}
+
 	pub fn record_id(&self) -> u64 {
 		self.log.record_id
 	}
@@ -356,6 +465,24 @@ impl<'a> LogWriter<'a> {
 		self.log.dropped_tables.push(id);
 	}
 
+	/// Empty writer for the same record, to plan changes on another thread. It does not see the
+	/// changes of this writer, which are merged back with `merge`.
+	pub fn fork(&self) -> LogWriter<'a> {
+		LogWriter::new(self.overlays, self.log.record_id)
+	}
+
+	/// Add the changes of a forked writer. The writers must not change the same index chunks or
+	/// value entries.
+	pub fn merge(&mut self, other: LogWriter) {
+		for (table, overlay) in other.log.local_index {
+			self.log.local_index.entry(table).or_default().map.extend(overlay.map);
+		}
+		for (table, overlay) in other.log.local_values {
+			self.log.local_values.entry(table).or_default().map.extend(overlay.map);
+		}
+		self.log.dropped_tables.extend(other.log.dropped_tables);
+	}
+
 	pub fn drain(self) -> LogChange {
 		self.log
 	}
@@ -453,14 +580,24 @@ pub struct ValueLogOverlay {
 #[derive(Debug)]
 struct Appending {
 	id: u32,
-	file: std::io::BufWriter<std::fs::File>,
+	file: std::io::BufWriter<DbFile>,
 	size: u64,
+	// Some record needs the file synced.
+	sync: bool,
 }
 
 #[derive(Debug)]
 struct Reading {
 	id: u32,
-	file: std::io::BufReader<std::fs::File>,
+	file: std::io::BufReader<DbFile>,
+}
+
+/// A log file copied to `Options::log_archive`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedLog {
+	/// Archive sequence number. Logs are archived in the order they were written.
+	pub index: u64,
+	pub path: std::path::PathBuf,
 }
 
 #[derive(Debug)]
@@ -468,46 +605,92 @@ pub struct Log {
 	overlays: RwLock<LogOverlays>,
 	appending: RwLock<Option<Appending>>,
 	reading: RwLock<Option<Reading>>,
-	read_queue: RwLock<VecDeque<(u32, std::fs::File)>>,
+	read_queue: RwLock<VecDeque<(u32, DbFile)>>,
 	next_record_id: AtomicU64,
+	// Counts down from `u64::MAX`, never colliding with actual record ids.
+	next_invalidation_id: AtomicU64,
 	dirty: AtomicBool,
-	log_pool: RwLock<VecDeque<(u32, std::fs::File)>>,
-	cleanup_queue: RwLock<VecDeque<(u32, std::fs::File)>>,
-	replay_queue: RwLock<VecDeque<(u32, u64, std::fs::File)>>,
+	log_pool: RwLock<VecDeque<(u32, DbFile)>>,
+	cleanup_queue: RwLock<VecDeque<(u32, DbFile)>>,
+	replay_queue: RwLock<VecDeque<(u32, u64, DbFile)>>,
 	path: std::path::PathBuf,
 	next_log_id: AtomicU32,
 	sync: bool,
+	archive: Option<std::path::PathBuf>,
+	next_archive_index: AtomicU64,
+	slow_io: SlowIo,
+	// Where the log files are kept, see `Options::storage`.
+	files: Files,
 }
 
 impl Log {
 	pub fn open(options: &Options) -> Result<Log> {
-		let path = options.path.clone();
+		let logs = Self::log_files(&options.files(), &options.path, true)?;
+		let next_log_id = logs.iter().map(|(id, _, _)| id + 1).max().unwrap_or(0);
+		Self::with_logs(options, logs, next_log_id, options.log_archive.clone())
+	}
+
+	/// Open the log of a read-only database. Log files are only read, with `follow`.
+	pub fn open_read_only(options: &Options) -> Result<Log> {
+		Self::with_logs(options, Default::default(), 0, None)
+	}
+
+	// Non-empty log files in `path`, ordered by their first record id. Empty log files are
+	// removed if `remove_empty` is set, and skipped otherwise.
+	fn log_files(
+		files: &Files,
+		path: &std::path::Path,
+		remove_empty: bool,
+	) -> Result<VecDeque<(u32, u64, DbFile)>> {
 		let mut logs = VecDeque::new();
-		let mut max_log_id = 0;
-		for entry in try_io!(std::fs::read_dir(&path)) {
-			let entry = try_io!(entry);
-			if let Some(name) = entry.file_name().as_os_str().to_str() {
-				if try_io!(entry.metadata()).is_file() && name.starts_with("log") {
-					if let Ok(nlog) = std::str::FromStr::from_str(&name[3..]) {
-						let path = Self::log_path(&path, nlog);
-						let (file, record_id) = Self::open_log_file(&path)?;
-						if let Some(record_id) = record_id {
-							log::debug!(target: "parity-db", "Opened log {}, record {}", nlog, record_id);
-							logs.push_back((nlog, record_id, file));
-							if nlog > max_log_id {
-								max_log_id = nlog
-							}
-						} else {
-							log::debug!(target: "parity-db", "Removing log {}", nlog);
-							drop(file);
-							try_io!(std::fs::remove_file(&path));
+		for name in try_io!(files.list(path)) {
+			if let Some(id) = name.strip_prefix("log") {
+				if let Ok(nlog) = std::str::FromStr::from_str(id) {
+					let path = Self::log_path(path, nlog);
+					let (file, record_id) = if remove_empty {
+						Self::open_log_file(files, &path)?
+					} else {
+						match std::fs::File::open(&path) {
+							Ok(file) => {
+								let mut file = DbFile::Disk(file);
+								let record_id = Self::read_first_record_id(&mut file).ok();
+								try_io!(file.seek(std::io::SeekFrom::Start(0)));
+								(file, record_id)
+							},
+							// Removed by the database owner.
+							Err(e) if e.kind() == ErrorKind::NotFound => continue,
+							Err(e) => return Err(Error::Io(e)),
 						}
+					};
+					if let Some(record_id) = record_id {
+						log::debug!(target: "parity-db", "Opened log {}, record {}", nlog, record_id);
+						logs.push_back((nlog, record_id, file));
+					} else if remove_empty {
+						log::debug!(target: "parity-db", "Removing log {}", nlog);
+						drop(file);
+						try_io!(files.remove(&path));
 					}
 				}
 			}
 		}
 		logs.make_contiguous().sort_by_key(|(_id, record_id, _)| *record_id);
-		let next_log_id = if logs.is_empty() { 0 } else { max_log_id + 1 };
+		Ok(logs)
+	}
+
+	fn with_logs(
+		options: &Options,
+		logs: VecDeque<(u32, u64, DbFile)>,
+		next_log_id: u32,
+		archive: Option<std::path::PathBuf>,
+	) -> Result<Log> {
+		let path = options.path.clone();
+		let next_archive_index = match &archive {
+			Some(archive) => {
+				try_io!(std::fs::create_dir_all(archive));
+				Self::list_archive(archive)?.last().map_or(0, |log| log.index + 1)
+			},
+			None => 0,
+		};
 
 		Ok(Log {
 			overlays: Default::default(),
@@ -515,6 +698,7 @@ impl Log {
 			reading: RwLock::new(None),
 			read_queue: RwLock::default(),
 			next_record_id: AtomicU64::new(1),
+			next_invalidation_id: AtomicU64::new(u64::MAX - 1),
 			next_log_id: AtomicU32::new(next_log_id),
 			dirty: AtomicBool::new(true),
 			sync: options.sync_wal,
@@ -522,6 +706,10 @@ impl Log {
 			cleanup_queue: RwLock::default(),
 			log_pool: RwLock::default(),
 			path,
+			archive,
+			next_archive_index: AtomicU64::new(next_archive_index),
+			slow_io: SlowIo(options.slow_io_warn),
+			files: options.files(),
 		})
 	}
 
@@ -531,13 +719,69 @@ impl Log {
 		path
 	}
 
+	fn archive_path(archive: &std::path::Path, index: u64) -> std::path::PathBuf {
+		archive.join(format!("log_{:020}", index))
+	}
+
+	fn list_archive(archive: &std::path::Path) -> Result<Vec<ArchivedLog>> {
+		let mut logs = Vec::new();
+		for entry in try_io!(std::fs::read_dir(archive)) {
+			let entry = try_io!(entry);
+			if let Some(name) = entry.file_name().to_str() {
+				if let Some(Ok(index)) = name.strip_prefix("log_").map(str::parse) {
+					logs.push(ArchivedLog { index, path: entry.path() });
+				}
+			}
+		}
+		logs.sort_by_key(|log| log.index);
+		Ok(logs)
+	}
+
+	/// Archived log files, oldest first.
+	pub fn archived_logs(&self) -> Result<Vec<ArchivedLog>> {
+		match &self.archive {
+			Some(archive) => Self::list_archive(archive),
+			None => Err(Error::InvalidConfiguration("Log archive is not enabled".into())),
+		}
+	}
+
+	/// Remove archived log files with index below `before`. Returns the number of files removed.
+	pub fn prune_archived_logs(&self, before: u64) -> Result<usize> {
+		let mut removed = 0;
+		for log in self.archived_logs()? {
+			if log.index >= before {
+				break
+			}
+			try_io!(std::fs::remove_file(&log.path));
+			removed += 1;
+		}
+		Ok(removed)
+	}
+
+	// Copy a fully enacted log file to the archive. The copy is renamed into place once complete.
+	fn archive_log(&self, archive: &std::path::Path, id: u32) -> Result<()> {
+		let index = self.next_archive_index.fetch_add(1, Ordering::SeqCst);
+		let path = Self::archive_path(archive, index);
+		let tmp = path.with_extension("tmp");
+		try_io!(std::fs::copy(Self::log_path(&self.path, id), &tmp));
+		try_io!(std::fs::rename(&tmp, &path));
+		log::debug!(target: "parity-db", "Archived log {} as {}", id, path.display());
+		Ok(())
+	}
+
 	pub fn replay_record_id(&self) -> Option<u64> {
 		self.replay_queue.read().front().map(|(_id, record_id, _)| *record_id)
 	}
 
-	pub fn open_log_file(path: &std::path::Path) -> Result<(std::fs::File, Option<u64>)> {
-		let mut file = try_io!(std::fs::OpenOptions::new().read(true).write(true).open(path));
-		if try_io!(file.metadata()).len() == 0 {
+	pub fn open_log_file(files: &Files, path: &std::path::Path) -> Result<(DbFile, Option<u64>)> {
+		let mut file = try_io!(files.open(path, false), path, "open").ok_or_else(|| {
+			Error::file_io(
+				path,
+				"open",
+				std::io::Error::new(ErrorKind::NotFound, "Log file not found"),
+			)
+		})?;
+		if try_io!(file.len()) == 0 {
 			return Ok((file, None))
 		}
 		match Self::read_first_record_id(&mut file) {
@@ -554,7 +798,7 @@ impl Log {
 		}
 	}
 
-	fn read_first_record_id(file: &mut std::fs::File) -> Result<u64> {
+	fn read_first_record_id(file: &mut DbFile) -> Result<u64> {
 		let mut buf = [0; 9];
 		try_io!(file.read_exact(&mut buf));
 		Ok(u64::from_le_bytes(buf[1..].try_into().unwrap()))
@@ -563,22 +807,40 @@ impl Log {
 	fn drop_log(&self, id: u32) -> Result<()> {
 		log::debug!(target: "parity-db", "Drop log {}", id);
 		let path = Self::log_path(&self.path, id);
-		try_io!(std::fs::remove_file(&path));
+		try_io!(self.files.remove(&path), &path, "remove");
 		Ok(())
 	}
 
-	pub fn clear_replay_logs(&self) {
+	/// Discard the log file being replayed and all following ones. Returns the number of files
+	/// discarded.
+	pub fn clear_replay_logs(&self) -> usize {
+		let mut discarded = 0;
 		if let Some(reading) = self.reading.write().take() {
 			self.cleanup_queue.write().push_back((reading.id, reading.file.into_inner()));
+			discarded += 1;
 		}
 		for (id, _, file) in self.replay_queue.write().drain(0..) {
 			self.cleanup_queue.write().push_back((id, file));
+			discarded += 1;
 		}
 		let mut overlays = self.overlays.write();
 		overlays.index.clear();
 		overlays.value.clear();
 		overlays.last_record_id.clear();
 		self.dirty.store(false, Ordering::Relaxed);
+		discarded
+	}
+
+	/// Stop replaying, keeping the log file being replayed and all following ones, to be
+	/// replayed when the database is opened again.
+	pub fn abort_replay(&self) {
+		self.reading.write().take();
+		self.replay_queue.write().clear();
+	}
+
+	/// Whether the log file being replayed is the last one.
+	pub fn is_last_replay_log(&self) -> bool {
+		self.replay_queue.read().is_empty()
 	}
 
 	pub fn begin_record(&self) -> LogWriter<'_> {
@@ -599,30 +861,18 @@ impl Log {
 				// find a free id
 				let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
 				let path = Self::log_path(&self.path, id);
-				let file = try_io!(std::fs::OpenOptions::new()
-					.create(true)
-					.read(true)
-					.write(true)
-					.open(path));
+				let file = try_io!(self.files.open(&path, true), &path, "create")
+					.expect("Files are created when requested");
 				log::debug!(target: "parity-db", "Flush: Activated new writer {}", id);
 				(id, file)
 			};
-			*appending = Some(Appending { size: 0, file: std::io::BufWriter::new(file), id });
+			*appending =
+				Some(Appending { size: 0, file: std::io::BufWriter::new(file), id, sync: false });
 		}
 		let appending = appending.as_mut().unwrap();
+		appending.sync |= log.sync.unwrap_or(self.sync);
 		let FlushedLog { index, values, bytes } = log.flush_to_file(&mut appending.file)?;
-		let mut overlays = self.overlays.write();
-		let mut total_index = 0;
-		for (id, overlay) in index.into_iter() {
-			total_index += overlay.map.len();
-			overlays.index.entry(id).or_default().map.extend(overlay.map.into_iter());
-		}
-		let mut total_value = 0;
-		for (id, overlay) in values.into_iter() {
-			total_value += overlay.map.len();
-			overlays.last_record_id.insert(id.col(), record_id);
-			overlays.value.entry(id).or_default().map.extend(overlay.map.into_iter());
-		}
+		let (total_index, total_value) = self.overlays.write().extend(record_id, index, values);
 
 		log::debug!(
 			target: "parity-db",
@@ -667,11 +917,16 @@ impl Log {
 		// If it exists take the writer and flush it
 		let cur_size = self.appending.read().as_ref().map_or(0, |r| r.size);
 		if cur_size > min_size {
+			#[cfg(feature = "tracing")]
+			let _span = tracing::debug_span!("flush_log", bytes = cur_size).entered();
 			if let Some(to_flush) = self.appending.write().take() {
 				let file = try_io!(to_flush.file.into_inner().map_err(|e| e.into_error()));
-				if self.sync {
+				if to_flush.sync {
 					log::debug!(target: "parity-db", "Flush: Flushing log to disk");
-					try_io!(file.sync_data());
+					let start = self.slow_io.start();
+					let path = Self::log_path(&self.path, to_flush.id);
+					try_io!(file.sync_data(), &path, "sync");
+					self.slow_io.check(start, "fsync", Some(&path), 0);
 					log::debug!(target: "parity-db", "Flush: Flushing log completed");
 				}
 				self.read_queue.write().push_back((to_flush.id, file));
@@ -706,6 +961,9 @@ impl Log {
 			queue.drain(0..count).collect()
 		};
 		for (id, ref mut file) in cleaned.iter_mut() {
+			if let Some(archive) = &self.archive {
+				self.archive_log(archive, *id)?;
+			}
 			log::debug!(target: "parity-db", "Cleaned: {}", id);
 			try_io!(file.seek(std::io::SeekFrom::Start(0)));
 			try_io!(file.set_len(0));
@@ -725,6 +983,22 @@ impl Log {
 		Ok(!self.cleanup_queue.read().is_empty())
 	}
 
+	/// Sync all log files written and not yet cleaned, regardless of `Options::sync_wal`.
+	pub fn sync_flushed(&self) -> Result<()> {
+		// Files move from the read queue to the reader and then to the cleanup queue. Checking
+		// them in the same order makes sure none is missed.
+		for (_, file) in self.read_queue.read().iter() {
+			try_io!(file.sync_data());
+		}
+		if let Some(reading) = self.reading.read().as_ref() {
+			try_io!(reading.file.get_ref().sync_data());
+		}
+		for (_, file) in self.cleanup_queue.read().iter() {
+			try_io!(file.sync_data());
+		}
+		Ok(())
+	}
+
 	pub fn num_dirty_logs(&self) -> usize {
 		self.cleanup_queue.read().len()
 	}
@@ -743,9 +1017,9 @@ impl Log {
 		let mut reader = LogReader::new(reading, validate);
 		match reader.next() {
 			Ok(LogAction::BeginRecord) => Ok(Some(reader)),
-			Ok(_) => Err(Error::Corruption("Bad log record structure".into())),
+			Ok(_) => Err(Error::LogCorruption("Bad log record structure".into())),
 			Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
-				if let Some(reading) = reader.reading.take() {
+				if let Some(reading) = reader.reading().take() {
 					log::debug!(target: "parity-db", "Read: End of log {}", reading.id);
 					let file = reading.file.into_inner();
 					self.cleanup_queue.write().push_back((reading.id, file));
@@ -760,8 +1034,86 @@ impl Log {
 		&self.overlays
 	}
 
+	/// Force btree iterators over the column to reload the tree.
+	pub fn invalidate_column(&self, col: ColId) {
+		let id = self.next_invalidation_id.fetch_sub(1, Ordering::Relaxed);
+		self.overlays.write().last_record_id.insert(col, id);
+	}
+
+	/// Replace the overlays with the records of all log files in the database directory, without
+	/// enacting them. Used by read-only databases, which must not modify any files. `read` is
+	/// called for each table change and must read its data from the log into the writer.
+	pub fn follow(
+		&self,
+		num_columns: usize,
+		mut read: impl FnMut(LogAction, &mut LogReader, &mut LogWriter) -> Result<()>,
+	) -> Result<()> {
+		let overlays = RwLock::new(LogOverlays::default());
+		for (id, _record_id, file) in Self::log_files(&self.files, &self.path, false)? {
+			let reading = RwLock::new(Some(Reading { id, file: std::io::BufReader::new(file) }));
+			let mut reader = LogReader::new(reading.write(), true);
+			// The file may be written or recycled by the database owner meanwhile. Reading stops
+			// at the first incomplete record.
+			loop {
+				match Self::follow_record(&overlays, &mut reader, &mut read) {
+					Ok(true) => (),
+					Ok(false) | Err(Error::LogCorruption(_)) => break,
+					Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+					Err(e) => return Err(e),
+				}
+			}
+		}
+		let mut overlays = std::mem::take(&mut *overlays.write());
+		// Btree iterators must reload the trees.
+		for col in 0..num_columns {
+			let id = self.next_invalidation_id.fetch_sub(1, Ordering::Relaxed);
+			overlays.last_record_id.insert(col as ColId, id);
+		}
+		*self.overlays.write() = overlays;
+		Ok(())
+	}
+
+	// Read the next record into `overlays`. Returns `false` at the end of the file.
+	fn follow_record(
+		overlays: &RwLock<LogOverlays>,
+		reader: &mut LogReader,
+		read: &mut impl FnMut(LogAction, &mut LogReader, &mut LogWriter) -> Result<()>,
+	) -> Result<bool> {
+		match reader.next() {
+			Ok(LogAction::BeginRecord) => (),
+			Ok(_) => return Err(Error::LogCorruption("Bad log record structure".into())),
+			Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+			Err(e) => return Err(e),
+		}
+		let record_id = reader.record_id();
+		let mut writer = LogWriter::new(overlays, record_id);
+		loop {
+			match reader.next()? {
+				LogAction::EndRecord => break,
+				LogAction::BeginRecord =>
+					return Err(Error::LogCorruption("Bad log record structure".into())),
+				action => read(action, reader, &mut writer)?,
+			}
+		}
+		let LogChange { local_index, local_values, .. } = writer.drain();
+		overlays.write().extend(record_id, local_index, local_values);
+		Ok(true)
+	}
+
+	/// Paths of the flushed log files that are not fully enacted yet, in replay order.
+	pub fn unenacted_log_paths(&self) -> Vec<std::path::PathBuf> {
+		let reading = self.reading.read();
+		let read_queue = self.read_queue.read();
+		reading
+			.iter()
+			.map(|r| r.id)
+			.chain(read_queue.iter().map(|(id, _)| *id))
+			.map(|id| Self::log_path(&self.path, id))
+			.collect()
+	}
+
 	pub fn has_log_files_to_read(&self) -> bool {
-		self.read_queue.read().len() > 0
+		!self.read_queue.read().is_empty()
 	}
 
 	pub fn kill_logs(&self) -> Result<()> {
@@ -0,0 +1,166 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Runtime metrics.
+//!
+//! The database reports metrics to the `MetricsSink` set in `Options::metrics`. Per-column
+//! metrics carry the column id. Counters and samples are reported as they happen, table and
+//! statistics gauges are reported when logs are cleaned up or on `Db::report_metrics`.
+
+use crate::{column::ColId, parking_lot::Mutex};
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+/// Time to validate and queue a commit, in seconds.
+pub const COMMIT_SECONDS: &str = "parity_db_commit_seconds";
+/// Bytes of commits queued for writing to the log.
+pub const COMMIT_QUEUE_BYTES: &str = "parity_db_commit_queue_bytes";
+/// Bytes of log records waiting to be enacted.
+pub const LOG_QUEUE_BYTES: &str = "parity_db_log_queue_bytes";
+/// Index entries moved to a new index table.
+pub const REINDEX_ENTRIES: &str = "parity_db_reindex_entries_total";
+/// Value table entries in use, including freed entries waiting for reuse.
+pub const VALUE_TABLE_ENTRIES: &str = "parity_db_value_table_entries";
+/// Value table entries allocated on disk.
+pub const VALUE_TABLE_CAPACITY: &str = "parity_db_value_table_capacity";
+/// Hash column reads served from the commit overlay.
+pub const OVERLAY_HITS: &str = "parity_db_commit_overlay_hits_total";
+/// Hash column reads that had to go to the log overlay or the tables.
+pub const OVERLAY_MISSES: &str = "parity_db_commit_overlay_misses_total";
+/// Share of queries that found a value. Requires `Options::stats`.
+pub const QUERY_HIT_RATIO: &str = "parity_db_query_hit_ratio";
+/// Uncompressed over stored value bytes. Requires `Options::stats`.
+pub const COMPRESSION_RATIO: &str = "parity_db_compression_ratio";
+/// Share of values stored uncompressed because they are no larger than the compression
+/// threshold. Requires `Options::stats`.
+pub const BELOW_COMPRESSION_THRESHOLD_RATIO: &str = "parity_db_below_compression_threshold_ratio";
+
+/// Receives metrics from the database. Calls are made from commit and background threads and
+/// should not block.
+pub trait MetricsSink: Send + Sync {
+	/// Add `value` to a counter.
+	fn increment(&self, name: &'static str, col: Option<ColId>, value: u64);
+	/// Set a gauge to `value`.
+	fn set(&self, name: &'static str, col: Option<ColId>, value: f64);
+	/// Record a sample, such as a duration.
+	fn observe(&self, name: &'static str, col: Option<ColId>, value: f64);
+}
+
+/// Metrics sink registered with the database.
+#[derive(Clone)]
+pub struct Metrics(pub Arc<dyn MetricsSink>);
+
+impl Metrics {
+	pub fn new(sink: impl MetricsSink + 'static) -> Self {
+		Metrics(Arc::new(sink))
+	}
+}
+
+impl std::fmt::Debug for Metrics {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("Metrics")
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Metric {
+	Counter(u64),
+	Gauge(f64),
+	Summary { sum: f64, count: u64 },
+}
+
+impl Metric {
+	fn kind(&self) -> &'static str {
+		match self {
+			Metric::Counter(_) => "counter",
+			Metric::Gauge(_) => "gauge",
+			Metric::Summary { .. } => "summary",
+		}
+	}
+}
+
+type Series = BTreeMap<(&'static str, Option<ColId>), Metric>;
+
+/// Sink that keeps the latest values and renders them in the Prometheus text format.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+	series: Mutex<Series>,
+}
+
+impl PrometheusMetrics {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Render all metrics in the Prometheus text exposition format.
+	pub fn render(&self) -> String {
+		let series = self.series.lock();
+		let mut out = String::new();
+		let mut last = None;
+		for ((name, col), metric) in series.iter() {
+			if last != Some(*name) {
+				let _ = writeln!(out, "# TYPE {} {}", name, metric.kind());
+				last = Some(*name);
+			}
+			let labels = col.map(|c| format!("{{column=\"{}\"}}", c)).unwrap_or_default();
+			let _ = match metric {
+				Metric::Counter(v) => writeln!(out, "{}{} {}", name, labels, v),
+				Metric::Gauge(v) => writeln!(out, "{}{} {}", name, labels, v),
+				Metric::Summary { sum, count } => writeln!(
+					out,
+					"{}_sum{} {}\n{}_count{} {}",
+					name, labels, sum, name, labels, count
+				),
+			};
+		}
+		out
+	}
+}
+
+impl MetricsSink for PrometheusMetrics {
+	fn increment(&self, name: &'static str, col: Option<ColId>, value: u64) {
+		let mut series = self.series.lock();
+		match series.entry((name, col)).or_insert(Metric::Counter(0)) {
+			Metric::Counter(v) => *v += value,
+			m => *m = Metric::Counter(value),
+		}
+	}
+
+	fn set(&self, name: &'static str, col: Option<ColId>, value: f64) {
+		self.series.lock().insert((name, col), Metric::Gauge(value));
+	}
+
+	fn observe(&self, name: &'static str, col: Option<ColId>, value: f64) {
+		let mut series = self.series.lock();
+		match series.entry((name, col)).or_insert(Metric::Summary { sum: 0.0, count: 0 }) {
+			Metric::Summary { sum, count } => {
+				*sum += value;
+				*count += 1;
+			},
+			m => *m = Metric::Summary { sum: value, count: 1 },
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{MetricsSink, PrometheusMetrics, COMMIT_SECONDS, REINDEX_ENTRIES};
+
+	#[test]
+	fn render() {
+		let metrics = PrometheusMetrics::new();
+		metrics.increment(REINDEX_ENTRIES, Some(1), 2);
+		metrics.increment(REINDEX_ENTRIES, Some(1), 3);
+		metrics.increment(REINDEX_ENTRIES, Some(0), 1);
+		metrics.observe(COMMIT_SECONDS, None, 0.5);
+		metrics.observe(COMMIT_SECONDS, None, 0.25);
+		assert_eq!(
+			metrics.render(),
+			"# TYPE parity_db_commit_seconds summary\n\
+			parity_db_commit_seconds_sum 0.75\n\
+			parity_db_commit_seconds_count 2\n\
+			# TYPE parity_db_reindex_entries_total counter\n\
+			parity_db_reindex_entries_total{column=\"0\"} 1\n\
+			parity_db_reindex_entries_total{column=\"1\"} 5\n"
+		);
+	}
+}
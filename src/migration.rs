@@ -13,6 +13,13 @@ use std::path::{Path, PathBuf};
 
 const COMMIT_SIZE: usize = 10240;
 const OVERWRITE_TMP_PATH: &str = "to_revert_overwrite";
+/// Directory holding the rewritten column during `Db::migrate_column`.
+pub(crate) const LIVE_MIGRATION_DIR_PREFIX: &str = "migrate_";
+/// Written once the rewritten column starts replacing the original one.
+/// Contains the new column options.
+pub(crate) const LIVE_MIGRATION_MARKER: &str = "swap";
+/// Written while `Db::clear_column` replaces column tables. Contains the cleared column ids.
+pub(crate) const CLEAR_MARKER: &str = "clear";
 
 /// Attempt to migrate a database to a new configuration with different column settings.
 /// `from` Source database path
@@ -44,6 +51,14 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 	let mut source_options = Options::with_columns(from, source_meta.columns.len() as u8);
 	source_options.salt = Some(source_meta.salt);
 	source_options.columns = source_meta.columns;
+	// Keys are not stored in the metadata, encrypted source columns are read with the keys of
+	// the destination column.
+	#[cfg(feature = "encryption")]
+	for (source, to) in source_options.columns.iter_mut().zip(&to.columns) {
+		if let (Some(_), Some(keys)) = (&source.encryption, &to.encryption) {
+			source.encryption = Some(keys.clone());
+		}
+	}
 
 	let mut source = Db::open(&source_options)?;
 	let mut dest = Db::open_or_create(&to)?;
@@ -76,29 +91,27 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 			continue
 		}
 		log::info!("Migrating col {}", c);
-		source.iter_column_while(c, |IterState { chunk_index: index, key, rc, mut value }| {
-			//TODO: more efficient ref migration
-			for _ in 0..rc {
-				let value = std::mem::take(&mut value);
-				commit
-					.indexed
-					.entry(c)
-					.or_insert_with(|| IndexedChangeSet::new(c))
-					.changes
-					.push(Operation::Set(key, value));
-				nb_commit += 1;
-				if nb_commit == COMMIT_SIZE {
-					ncommits += 1;
-					if let Err(e) = dest.commit_raw(std::mem::take(&mut commit)) {
-						log::warn!("Migration error: {:?}", e);
-						return false
-					}
-					nb_commit = 0;
-
-					if last_time.elapsed() > std::time::Duration::from_secs(3) {
-						last_time = std::time::Instant::now();
-						log::info!("Migrating {} #{}, commit {}", c, index, ncommits);
-					}
+		let ref_counted = to.columns[c as usize].ref_counted;
+		source.iter_column_while(c, |IterState { chunk_index: index, key, rc, value }| {
+			let changes =
+				&mut commit.indexed.entry(c).or_insert_with(|| IndexedChangeSet::new(c)).changes;
+			changes.push(Operation::Set(key, value));
+			// Further references to the value are only kept by `ref_counted` columns.
+			if ref_counted && rc > 1 {
+				changes.push(Operation::AddRc(key, (rc - 1).min(i64::MAX as u64) as i64));
+			}
+			nb_commit += 1;
+			if nb_commit == COMMIT_SIZE {
+				ncommits += 1;
+				if let Err(e) = dest.commit_raw(std::mem::take(&mut commit)) {
+					log::warn!("Migration error: {:?}", e);
+					return false
+				}
+				nb_commit = 0;
+
+				if last_time.elapsed() > std::time::Duration::from_secs(3) {
+					last_time = std::time::Instant::now();
+					log::info!("Migrating {} #{}, commit {}", c, index, ncommits);
 				}
 			}
 			true
@@ -151,8 +164,170 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 	Ok(())
 }
 
+/// Written to the destination directory of `from_rocksdb`. Contains the index of the column
+/// being imported and the last imported key in hex.
+#[cfg(any(feature = "rocksdb-migrate", test))]
+const IMPORT_PROGRESS_FILE: &str = "import_progress";
+
+#[cfg(any(feature = "rocksdb-migrate", test))]
+type ImportBatch = Vec<(ColId, Operation<Vec<u8>, Vec<u8>>)>;
+
+/// Ordered key-value source for `import_ordered`.
+#[cfg(any(feature = "rocksdb-migrate", test))]
+trait OrderedSource {
+	fn num_columns(&self) -> usize;
+
+	/// Call `f` for entries of column `col` in key order, starting at `start`, until it returns
+	/// false.
+	fn iter_from(
+		&self,
+		col: usize,
+		start: Option<&[u8]>,
+		f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+	) -> Result<()>;
+}
+
+#[cfg(feature = "rocksdb-migrate")]
+struct RocksDbSource {
+	db: rocksdb::DB,
+	column_families: Vec<String>,
+}
+
+#[cfg(feature = "rocksdb-migrate")]
+impl RocksDbSource {
+	fn open(path: &Path) -> Result<RocksDbSource> {
+		let options = rocksdb::Options::default();
+		let column_families = rocksdb::DB::list_cf(&options, path)
+			.map_err(|e| Error::Migration(format!("Error listing column families: {}", e)))?;
+		let db = rocksdb::DB::open_cf_for_read_only(&options, path, &column_families, false)
+			.map_err(|e| Error::Migration(format!("Error opening RocksDB database: {}", e)))?;
+		Ok(RocksDbSource { db, column_families })
+	}
+}
+
+#[cfg(feature = "rocksdb-migrate")]
+impl OrderedSource for RocksDbSource {
+	fn num_columns(&self) -> usize {
+		self.column_families.len()
+	}
+
+	fn iter_from(
+		&self,
+		col: usize,
+		start: Option<&[u8]>,
+		f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+	) -> Result<()> {
+		let name = &self.column_families[col];
+		let cf = self
+			.db
+			.cf_handle(name)
+			.ok_or_else(|| Error::Migration(format!("Missing column family {}", name)))?;
+		let mode = match start {
+			Some(key) => rocksdb::IteratorMode::From(key, rocksdb::Direction::Forward),
+			None => rocksdb::IteratorMode::Start,
+		};
+		for item in self.db.iterator_cf(cf, mode) {
+			let (key, value) =
+				item.map_err(|e| Error::Migration(format!("Error reading RocksDB: {}", e)))?;
+			if !f(&key, &value) {
+				break
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Import a RocksDB database at `src` into a parity-db database, created if missing. Column
+/// families are imported in the order listed by RocksDB, starting with `default`, into the
+/// columns with the same index. `dst` must have as many columns as there are column families.
+///
+/// Progress is logged and recorded in the destination directory. An interrupted import
+/// resumes where it stopped when called again. Entries committed shortly before the
+/// interruption may be written twice, so destination columns should not be `ref_counted`.
+#[cfg(feature = "rocksdb-migrate")]
+pub fn from_rocksdb(src: &Path, dst: Options) -> Result<()> {
+	let source = RocksDbSource::open(src)?;
+	import_ordered(&source, dst)
+}
+
+#[cfg(any(feature = "rocksdb-migrate", test))]
+fn read_import_progress(path: &Path) -> Result<(usize, Option<Vec<u8>>)> {
+	let progress = match std::fs::read_to_string(path) {
+		Ok(progress) => progress,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, None)),
+		Err(e) => return Err(Error::Io(e)),
+	};
+	let bad_progress = || Error::Migration("Bad import progress file".into());
+	let mut lines = progress.lines();
+	let col = lines.next().and_then(|c| c.parse().ok()).ok_or_else(bad_progress)?;
+	let key = match lines.next() {
+		Some(key) if !key.is_empty() => Some(hex::decode(key).map_err(|_| bad_progress())?),
+		_ => None,
+	};
+	Ok((col, key))
+}
+
+#[cfg(any(feature = "rocksdb-migrate", test))]
+fn write_import_progress(path: &Path, col: usize, key: Option<&[u8]>) -> Result<()> {
+	let tmp = path.with_extension("tmp");
+	let key = key.map(hex::encode).unwrap_or_default();
+	try_io!(std::fs::write(&tmp, format!("{}\n{}", col, key)));
+	try_io!(std::fs::rename(&tmp, path));
+	Ok(())
+}
+
+// Copy all entries of `source` into a database with `dst` options. Progress is recorded once
+// a batch is flushed to the log.
+#[cfg(any(feature = "rocksdb-migrate", test))]
+fn import_ordered(source: &impl OrderedSource, dst: Options) -> Result<()> {
+	if source.num_columns() != dst.columns.len() {
+		return Err(Error::Migration("Source and dest columns mismatch".into()))
+	}
+	let progress_path = dst.path.join(IMPORT_PROGRESS_FILE);
+	let db = Db::open_or_create(&dst)?;
+	let (start_col, mut start_key) = read_import_progress(&progress_path)?;
+	let mut last_time = std::time::Instant::now();
+	for col in start_col..source.num_columns() {
+		log::info!("Importing col {}", col);
+		let start = start_key.take();
+		let mut batch = Vec::new();
+		let mut count = 0u64;
+		let mut result = Ok(());
+		let commit = |batch: ImportBatch| -> Result<()> {
+			let last_key = match batch.last() {
+				Some((_, op)) => op.key().to_vec(),
+				None => return Ok(()),
+			};
+			db.commit_changes_durable(batch)?;
+			write_import_progress(&progress_path, col, Some(&last_key))
+		};
+		source.iter_from(col, start.as_deref(), &mut |key, value| {
+			if start.as_deref() == Some(key) {
+				return true
+			}
+			batch.push((col as ColId, Operation::Set(key.to_vec(), value.to_vec())));
+			count += 1;
+			if batch.len() == COMMIT_SIZE {
+				result = commit(std::mem::take(&mut batch));
+				if last_time.elapsed() > std::time::Duration::from_secs(3) {
+					last_time = std::time::Instant::now();
+					log::info!("Importing col {}, {} entries", col, count);
+				}
+			}
+			result.is_ok()
+		})?;
+		result?;
+		commit(batch)?;
+		write_import_progress(&progress_path, col + 1, None)?;
+		log::info!("Imported col {}, {} entries", col, count);
+	}
+	drop(db);
+	try_io!(std::fs::remove_file(&progress_path));
+	Ok(())
+}
+
 /// Clear specified column. All data is removed and stats are reset.
-/// Database must be closed before calling this.
+/// Database must be closed before calling this. See `Db::clear_column` for open databases.
 pub fn clear_column(path: &Path, column: ColId) -> Result<()> {
 	let meta = Options::load_metadata(path)?
 		.ok_or_else(|| Error::Migration("Error loading source metadata".into()))?;
@@ -169,29 +344,161 @@ pub fn clear_column(path: &Path, column: ColId) -> Result<()> {
 	let _db = Db::open(&options)?;
 	drop(_db);
 
-	// It is not specified how read_dir behaves when deleting and iterating in the same loop
-	// We collect a list of paths to be deleted first.
-	let mut to_delete = Vec::new();
+	remove_column_files(&options, column)
+}
+
+/// Delete index and value table files of a column.
+pub(crate) fn remove_column_files(options: &Options, column: ColId) -> Result<()> {
+	let files = options.files();
+	crate::db::discard_clean_shutdown(&files, &options.path)?;
+	for file in try_io!(files.list(&options.path)) {
+		// Also remove files left over from `Db::drop_column`.
+		let name = file.strip_suffix(crate::column::DROPPED_FILE_SUFFIX).unwrap_or(&file);
+		if crate::index::TableId::is_file_name(column, name) ||
+			crate::table::TableId::is_file_name(column, name)
+		{
+			try_io!(files.remove(&options.path.join(file)));
+		}
+	}
+	Ok(())
+}
+
+/// Path of the directory used for migrating column `col` of the database at `path`.
+pub(crate) fn live_migration_path(path: &Path, col: ColId) -> PathBuf {
+	path.join(format!("{}{:02}", LIVE_MIGRATION_DIR_PREFIX, col))
+}
+
+/// Clean up after column migrations that were interrupted. Migrations that did not update
+/// the metadata are rolled back, the original column tables are restored if needed.
+pub(crate) fn recover_live_migrations(path: &Path) -> Result<()> {
+	let meta = match Options::load_metadata(path)? {
+		Some(meta) => meta,
+		None => return Ok(()),
+	};
 	for entry in try_io!(std::fs::read_dir(path)) {
 		let entry = try_io!(entry);
-		if let Some(file) = entry.path().file_name().and_then(|f| f.to_str()) {
-			if crate::index::TableId::is_file_name(column, file) ||
-				crate::table::TableId::is_file_name(column, file)
-			{
-				to_delete.push(PathBuf::from(file));
+		let dir = entry.path();
+		let col = match entry.file_name().to_str().and_then(|name| {
+			name.strip_prefix(LIVE_MIGRATION_DIR_PREFIX)
+				.and_then(|c| c.parse::<ColId>().ok())
+		}) {
+			Some(col) if dir.is_dir() => col,
+			_ => continue,
+		};
+		let completed = match std::fs::read_to_string(dir.join(LIVE_MIGRATION_MARKER)) {
+			Ok(options) => meta.columns.get(col as usize).is_some_and(|c| c.as_string() == options),
+			Err(_) => false,
+		};
+		let (current, detached) = column_files(path, col)?;
+		if completed {
+			for file in detached {
+				try_io!(std::fs::remove_file(file));
 			}
+		} else if !detached.is_empty() {
+			log::warn!(target: "parity-db", "Rolling back migration of column {}", col);
+			for file in current {
+				try_io!(std::fs::remove_file(file));
+			}
+			restore_detached(detached)?;
 		}
+		try_io!(std::fs::remove_dir_all(&dir));
 	}
+	Ok(())
+}
 
-	for file in to_delete {
-		let mut path = path.to_path_buf();
-		path.push(file);
-		try_io!(std::fs::remove_file(path));
+/// Restore the original tables of columns whose clearing was interrupted, see
+/// `Db::clear_column`.
+pub(crate) fn recover_cleared_columns(path: &Path) -> Result<()> {
+	let marker = path.join(CLEAR_MARKER);
+	let cols = match std::fs::read(&marker) {
+		Ok(cols) => cols,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(Error::Io(e)),
+	};
+	for col in cols {
+		// New tables are only created by later commits, the tables that are not detached yet
+		// are kept.
+		let (_, detached) = column_files(path, col)?;
+		if !detached.is_empty() {
+			log::warn!(target: "parity-db", "Rolling back clearing of column {}", col);
+			restore_detached(detached)?;
+		}
 	}
+	try_io!(crate::storage::Files::default().sync_dir(path));
+	try_io!(std::fs::remove_file(marker));
 	Ok(())
 }
 
-fn move_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
+// Table files of column `col`, and the ones detached with `Column::detach_files`.
+fn column_files(path: &Path, col: ColId) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+	let mut detached = Vec::new();
+	let mut current = Vec::new();
+	for entry in try_io!(std::fs::read_dir(path)) {
+		let file = try_io!(entry).path();
+		let name = match file.file_name().and_then(|f| f.to_str()) {
+			Some(name) => name,
+			None => continue,
+		};
+		if !crate::index::TableId::is_file_name(col, name) &&
+			!crate::table::TableId::is_file_name(col, name)
+		{
+			continue
+		}
+		if name.ends_with(crate::column::DROPPED_FILE_SUFFIX) {
+			detached.push(file);
+		} else {
+			current.push(file);
+		}
+	}
+	Ok((current, detached))
+}
+
+// Rename detached table files back to their original names.
+fn restore_detached(detached: Vec<PathBuf>) -> Result<()> {
+	for file in detached {
+		let name = file.to_string_lossy();
+		let original =
+			PathBuf::from(name.strip_suffix(crate::column::DROPPED_FILE_SUFFIX).unwrap_or(&name));
+		try_io!(std::fs::rename(&file, original));
+	}
+	Ok(())
+}
+
+/// Copy the index and value table files of column `from` to column `to` of the database at
+/// `path`, see `Db::clone_column`. Table files are cloned, blob files are never modified and are
+/// hard linked.
+pub(crate) fn clone_column_files(path: &Path, from: ColId, to: ColId) -> Result<()> {
+	for entry in try_io!(std::fs::read_dir(path)) {
+		let entry = try_io!(entry);
+		let name = match entry.file_name().to_str() {
+			Some(name) => name.to_owned(),
+			None => continue,
+		};
+		if name.ends_with(crate::column::DROPPED_FILE_SUFFIX) ||
+			name.ends_with(crate::blob::TMP_SUFFIX)
+		{
+			continue
+		}
+		let target = ["index", "table"].iter().find_map(|kind| {
+			let rest = name.strip_prefix(&format!("{}_{:02}_", kind, from))?;
+			Some(path.join(format!("{}_{:02}_{}", kind, to, rest)))
+		});
+		let target = match target {
+			Some(target) => target,
+			None => continue,
+		};
+		if name.contains(crate::blob::FILE_INFIX) {
+			try_io!(std::fs::hard_link(entry.path(), target));
+		} else {
+			try_io!(crate::backup::clone_file(&entry.path(), &target));
+			try_io!(std::fs::File::open(&target).and_then(|file| file.sync_all()));
+		}
+	}
+	try_io!(crate::storage::Files::default().sync_dir(path));
+	Ok(())
+}
+
+pub(crate) fn move_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
 	deplace_column(c, from, to, false)
 }
 
@@ -200,6 +507,9 @@ fn copy_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
 }
 
 fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
+	for path in [from, to] {
+		crate::db::discard_clean_shutdown(&crate::storage::Files::default(), path)?;
+	}
 	for entry in try_io!(std::fs::read_dir(from)) {
 		let entry = try_io!(entry);
 		if let Some(file) = entry.path().file_name().and_then(|f| f.to_str()) {
@@ -223,9 +533,72 @@ fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-	use crate::{migration, Db, Options};
+	use crate::{migration, Db, Error, Options, Result};
+	use std::{cell::Cell, collections::BTreeMap};
 	use tempfile::tempdir;
 
+	struct MapSource {
+		columns: Vec<BTreeMap<Vec<u8>, Vec<u8>>>,
+		// Fail after this many entries.
+		fail_after: Cell<Option<usize>>,
+	}
+
+	impl migration::OrderedSource for MapSource {
+		fn num_columns(&self) -> usize {
+			self.columns.len()
+		}
+
+		fn iter_from(
+			&self,
+			col: usize,
+			start: Option<&[u8]>,
+			f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+		) -> Result<()> {
+			let start = start.map(|s| s.to_vec()).unwrap_or_default();
+			for (key, value) in self.columns[col].range(start..) {
+				match self.fail_after.get() {
+					Some(0) => return Err(Error::Migration("Source failure".into())),
+					Some(n) => self.fail_after.set(Some(n - 1)),
+					None => (),
+				}
+				if !f(key, value) {
+					break
+				}
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn import_ordered_resumes() {
+		let dir = tempdir().unwrap();
+		let options = Options::with_columns(dir.path(), 2);
+		let columns: Vec<BTreeMap<_, _>> = (0..2u32)
+			.map(|c| {
+				(0..25000u32)
+					.map(|i| (i.to_be_bytes().to_vec(), (i + c).to_le_bytes().to_vec()))
+					.collect()
+			})
+			.collect();
+		let source = MapSource { columns, fail_after: Cell::new(Some(37000)) };
+		assert!(migration::import_ordered(&source, options.clone()).is_err());
+		let progress = dir.path().join(migration::IMPORT_PROGRESS_FILE);
+		assert_eq!(migration::read_import_progress(&progress).unwrap().0, 1);
+
+		source.fail_after.set(None);
+		migration::import_ordered(&source, options.clone()).unwrap();
+		assert!(!progress.exists());
+		let db = Db::open(&options).unwrap();
+		for c in 0..2u32 {
+			for i in (0..25000u32).step_by(7) {
+				assert_eq!(
+					db.get(c as u8, &i.to_be_bytes()).unwrap(),
+					Some((i + c).to_le_bytes().to_vec())
+				);
+			}
+		}
+	}
+
 	#[test]
 	fn migrate_simple() {
 		let dir = tempdir().unwrap();
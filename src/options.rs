@@ -3,17 +3,30 @@
 
 use crate::{
 	column::{ColId, Salt},
-	compress::CompressionType,
+	compress::{CompressionType, MAX_DICTIONARY_SIZE},
 	error::{try_io, Error, Result},
+	storage::{Files, Storage},
 };
 use rand::Rng;
-use std::{collections::HashMap, path::Path};
+use std::{
+	collections::{BTreeSet, HashMap},
+	path::Path,
+	sync::Arc,
+	time::Duration,
+};
 
 pub const CURRENT_VERSION: u32 = 7;
 // TODO on last supported 5, remove MULTIHEAD_V4 and MULTIPART_V4
 const LAST_SUPPORTED_VERSION: u32 = 4;
 
 pub const DEFAULT_COMPRESSION_THRESHOLD: u32 = 4096;
+/// Compression threshold of columns with a `compression_dictionary`.
+pub const DEFAULT_DICTIONARY_COMPRESSION_THRESHOLD: u32 = 64;
+
+// Metadata value of a dropped column.
+const DROPPED_COLUMN: &str = "dropped";
+// Btree node format of columns with `ColumnOptions::prefix_compression`.
+const BTREE_FORMAT_PREFIX: u32 = 2;
 
 /// Database configuration.
 #[derive(Clone, Debug)]
@@ -37,8 +50,67 @@ pub struct Options {
 	///
 	/// Optional. A sensible default is used if nothing is set for a given column.
 	pub compression_threshold: HashMap<ColId, u32>,
-	#[cfg(any(test, feature = "instrumentation"))]
-	/// Always starts background threads.
+	/// Copy log files to this directory once they are enacted, instead of discarding them.
+	/// Archived logs are never removed by the database, see `Db::prune_archived_logs`.
+	pub log_archive: Option<std::path::PathBuf>,
+	/// Log a warning for value table reads, fsyncs, commits and reindex steps that take longer
+	/// than this.
+	pub slow_io_warn: Option<std::time::Duration>,
+	/// Open value table files with `O_DIRECT`, bypassing the page cache. Linux only. Falls back
+	/// to buffered I/O if the file system does not support it.
+	pub direct_io: bool,
+	/// Submit the value table reads of `Db::get_many` to io_uring in batches. Linux only.
+	/// Falls back to regular reads if io_uring is not available. Index chunks are memory mapped
+	/// and writes, including log enactment, use regular I/O.
+	pub io_uring: bool,
+	/// Continuously verify index and value tables in a low priority background thread.
+	pub scrub: Option<ScrubOptions>,
+	/// Maximum bytes of values moved per second by `Db::compact_column`. 0 for no limit.
+	pub compaction_rate: u64,
+	/// Throttle log enactment and reindexing, so that background writes leave disk bandwidth
+	/// for foreground reads. `None` for no limit.
+	pub background_io_limit: Option<IoLimit>,
+	/// How value table files are extended when they are full.
+	pub grow_policy: GrowPolicy,
+	/// Allocate disk space when value table and index files are created or extended, instead
+	/// of leaving them sparse. Reduces fragmentation on copy-on-write file systems such as
+	/// btrfs. Linux only, ignored where the file system does not support it.
+	pub preallocate: bool,
+	/// Limits on memory held by pending writes and caches.
+	pub memory_budget: MemoryBudget,
+	/// Reject commits with more than this many bytes of keys and values with
+	/// `Error::CommitTooLarge`. Large commits are logged and enacted as a single record, which
+	/// stalls log flushes and holds the whole commit in memory. `None` for no limit.
+	pub max_commit_bytes: Option<usize>,
+	/// Number of threads compressing the values written to a hash indexed column by a commit.
+	/// Commits with less than 64 KiB of values to compress in a column are compressed on the
+	/// commit thread. 0 or 1 compresses all values on the commit thread.
+	pub compression_threads: usize,
+	/// Number of threads planning each batch of entries moved to a larger index when a hash
+	/// indexed column grows. 0 or 1 plans on the log thread.
+	pub reindex_threads: usize,
+	/// Number of threads enacting the log records replayed when the database is opened, each
+	/// writing the changes of different columns. 0 or 1 replays on the opening thread.
+	pub replay_threads: usize,
+	/// What to do with log records that can't be replayed when the database is opened.
+	pub recovery_mode: RecoveryMode,
+	/// Keep all files in memory, see `Options::memory`. The database starts empty each time it
+	/// is opened, and its contents are lost once it is closed. Takes precedence over `storage`.
+	pub in_memory: bool,
+	/// Keep database files in this storage instead of the file system, see `Storage`. `path`
+	/// is not accessed. Backups, checkpoints, column migrations, `log_archive` and read-only
+	/// opening are not supported.
+	pub storage: Option<Arc<dyn Storage>>,
+	/// Time between sweeps removing expired values from `ttl` columns. Each sweep scans the
+	/// columns' indexes in bounded steps, committing the removals as it goes.
+	pub expiry_interval: Duration,
+	/// Report runtime metrics to this sink.
+	#[cfg(feature = "metrics")]
+	pub metrics: Option<crate::metrics::Metrics>,
+	/// Start background threads that write commits to the log and the tables. When unset,
+	/// this is left to `Db::process_commits`, `Db::flush_logs`, `Db::enact_logs`,
+	/// `Db::clean_logs` and `Db::process_reindex`, except that waiting for a commit, as
+	/// `Db::commit_with` may, processes it. On by default.
 	pub with_background_thread: bool,
 	#[cfg(any(test, feature = "instrumentation"))]
 	/// Always flushes data from the log to the on-disk data structures.
@@ -60,10 +132,406 @@ pub struct ColumnOptions {
 	/// Reference counting do not enforce immediate removal
 	/// and user should not check for missing value.
 	pub ref_counted: bool,
+	/// Store reference counts in 64 bits instead of 32, for values referenced more than 4
+	/// billion times. A count reaching the maximum is locked and the value is never removed.
+	/// Stored in the metadata, changing it requires `Db::migrate_column`.
+	/// Only supported with `ref_counted`.
+	pub wide_ref_counts: bool,
 	/// Compression to use for this column.
 	pub compression: CompressionType,
+	/// Dictionary used to compress values, see `compress::train_dictionary` and
+	/// `compress::train_zstd_dictionary`. Each value is compressed as if it followed the
+	/// dictionary. Lets small values compress well, so the column's
+	/// `Options::compression_threshold` defaults to 64 bytes instead of 4096. Stored in the
+	/// metadata, changing it requires `Db::migrate_column`.
+	/// Only supported with `CompressionType::Lz4`, `Lz4Hc` and `Zstd`, up to
+	/// `MAX_DICTIONARY_SIZE` bytes.
+	pub compression_dictionary: Option<Arc<[u8]>>,
 	/// Column is using a btree indexing.
 	pub btree_index: bool,
+	/// Merge function applied to `Operation::Merge` operands, when committed, read or written
+	/// to the log. Must be deterministic.
+	///
+	/// Not persisted in the metadata, must be set each time the database is opened.
+	/// Not supported for `ref_counted` or `btree_index` columns.
+	pub merge: Option<MergeOperator>,
+	/// Secondary indexes maintained on each commit, see `Db::iter_secondary`.
+	///
+	/// Not persisted in the metadata, must be set each time the database is opened.
+	/// Not supported for `ref_counted`, `btree_index` or `ttl` columns.
+	pub secondary_indexes: Vec<SecondaryIndex>,
+	/// Allow `Operation::SetWithTtl`. Values are stored with an expiry timestamp,
+	/// expired values are not returned and are periodically removed.
+	/// Not supported for `ref_counted` or `btree_index` columns.
+	pub ttl: bool,
+	/// Store a CRC32 checksum with each value and verify it on every read. Values that fail
+	/// verification are reported as `Error::ChecksumMismatch`.
+	pub value_checksums: bool,
+	/// Function used to hash keys into the index. Can't be combined with `uniform` and is not
+	/// used by `btree_index` columns.
+	pub key_hash: KeyHash,
+	/// Split each value table file into segments of at most this many bytes, instead of a
+	/// single file that keeps growing. Suits file systems and backup tools that handle very
+	/// large files poorly. Stored in the metadata, changing it requires `Db::migrate_column`.
+	/// Must be at least 32 KiB.
+	pub max_file_size: Option<u64>,
+	/// Store values larger than this many bytes, after compression, in a file of their own
+	/// instead of a chain of 4 KiB value table entries, so that they are read with a single
+	/// request. The file is removed when the value is removed or replaced. Stored in the
+	/// metadata, changing it requires `Db::migrate_column`. Must be at least 32 KiB.
+	/// Not supported for `btree_index` or encrypted columns.
+	pub blob_threshold: Option<u32>,
+	/// Keep an exact count of the keys in the column, see `Db::exact_len`. The count is
+	/// updated as commits are written to the log, at the cost of an extra lookup of each
+	/// changed key in btree indexed columns. Stored in the metadata, changing it requires
+	/// `Db::migrate_column`.
+	pub count_keys: bool,
+	/// Store the keys of btree nodes without the prefix they share with the previous key of
+	/// the node, which shrinks nodes of keys with long common prefixes. Recorded in the
+	/// metadata as the btree node format version, changing it requires `Db::migrate_column`.
+	/// Only supported with `btree_index`.
+	pub prefix_compression: bool,
+	/// Store the original key with each value, so that `ColumnIterator::next_keyed` and
+	/// `Db::export` return keys instead of key hashes. Stored in the metadata, can't be
+	/// changed once the column is created. Only supported for hash indexed columns without
+	/// `uniform`, `ttl`, `merge` or `secondary_indexes`.
+	pub store_keys: bool,
+	/// How value table files are read.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	pub read_mode: ReadMode,
+	/// Access pattern hint passed to the kernel for the column's index and value table files.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	pub access_hint: AccessHint,
+	/// Size in bytes of a cache of recently read values, consulted before the index. Values
+	/// are cached decompressed and invalidated by commits. 0 disables the cache.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	/// Not supported for `btree_index` columns.
+	pub value_cache_bytes: usize,
+	/// Size in bytes of a cache of recently read index chunks, used by lookups and commits.
+	/// Helps when the index does not fit in memory. 0 disables the cache.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	pub index_cache_bytes: usize,
+	/// Size in bytes of a Bloom filter of the column keys, which lets lookups of most missing
+	/// keys skip the index. About 10 bits per key give 1% false positives. The filter is built
+	/// by scanning the index when the database is opened. 0 disables the filter.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	/// Not supported for `btree_index` columns. Ignored by read-only databases and followers.
+	pub bloom_filter_bytes: usize,
+	/// Reject values larger than this many bytes with `Error::ValueTooLarge`, including values
+	/// streamed with `Db::begin_value`. Values committed at once are limited to
+	/// `MAX_VALUE_SIZE` bytes regardless.
+	///
+	/// Not persisted in the metadata, may be changed each time the database is opened.
+	pub max_value_size: Option<usize>,
+	/// Encrypt values, including btree nodes, in the value tables and the log. Index chunks are
+	/// not encrypted, they only hold salted key hashes and value addresses. Can't be combined
+	/// with `uniform` or `KeyHash::Identity`, which would store key bytes in the index. Whether
+	/// the column is encrypted is stored in the metadata, the key provider must be set each
+	/// time the database is opened.
+	#[cfg(feature = "encryption")]
+	pub encryption: Option<crate::encryption::Encryption>,
+}
+
+/// Largest value that can be committed at once, the most LZ4 compresses in a single block.
+pub const MAX_VALUE_SIZE: usize = 0x7E00_0000;
+
+/// Access pattern hint for table files, applied with `madvise` and `posix_fadvise` where
+/// supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessHint {
+	/// Disable read-ahead. Suits random point lookups.
+	#[default]
+	Random,
+	/// Enable aggressive read-ahead. Suits bulk iteration and sync.
+	Sequential,
+	/// Same as `Random`, and release cached pages of the column's files each time a reindex
+	/// completes. Suits large, rarely read columns.
+	DontNeed,
+}
+
+/// Hash function applied to keys of hash indexed columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyHash {
+	/// Blake2b-256 keyed with the salt.
+	#[default]
+	Blake2b = 0,
+	/// SipHash-2-4 keyed with the salt. Faster than `Blake2b` for short keys, but only
+	/// suitable when keys are not chosen by an adversary who can observe timings.
+	SipHash24 = 1,
+	/// The first 32 bytes of the key, unsalted. For keys that already are uniformly distributed
+	/// hashes. Shorter keys are padded with zeros, so keys that only differ in trailing zeros
+	/// are the same key.
+	Identity = 2,
+}
+
+impl KeyHash {
+	pub(crate) fn from_u8(id: u8) -> Option<KeyHash> {
+		match id {
+			0 => Some(KeyHash::Blake2b),
+			1 => Some(KeyHash::SipHash24),
+			2 => Some(KeyHash::Identity),
+			_ => None,
+		}
+	}
+}
+
+/// Read strategy for value table files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadMode {
+	/// Read with `pread`. Only the entries read are cached by the kernel.
+	#[default]
+	Pread,
+	/// Read through a memory map of the whole file. Avoids a system call per read, but maps
+	/// all of the column's value tables into the address space. Takes precedence over
+	/// `Options::direct_io` for reads.
+	Mmap,
+}
+
+/// Growth strategy for value table files, see `Options::grow_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrowPolicy {
+	/// Extend files by this many bytes at a time.
+	Fixed(u64),
+	/// Extend files by their current size, but at least `min` and at most `max` bytes at a
+	/// time.
+	Exponential { min: u64, max: u64 },
+	/// Extend files by a single entry at a time, as values are written.
+	Append,
+}
+
+impl Default for GrowPolicy {
+	fn default() -> Self {
+		GrowPolicy::Fixed(256 * 1024)
+	}
+}
+
+impl GrowPolicy {
+	/// Entries to add to a file of `capacity` entries of `entry_size` bytes.
+	pub(crate) fn grow_entries(&self, capacity: u64, entry_size: u16) -> u64 {
+		let entry_size = entry_size as u64;
+		let bytes = match *self {
+			GrowPolicy::Fixed(bytes) => bytes,
+			GrowPolicy::Exponential { min, max } => (capacity * entry_size).clamp(min, max),
+			GrowPolicy::Append => entry_size,
+		};
+		(bytes / entry_size).max(1)
+	}
+
+	/// Smallest file capacity in entries that holds `entries` entries and is a multiple of the
+	/// minimal growth step.
+	pub(crate) fn round_capacity(&self, entries: u64, entry_size: u16) -> u64 {
+		let step = match *self {
+			GrowPolicy::Fixed(bytes) | GrowPolicy::Exponential { min: bytes, .. } =>
+				(bytes / entry_size as u64).max(1),
+			GrowPolicy::Append => 1,
+		};
+		entries.div_ceil(step).max(1) * step
+	}
+
+	fn is_valid(&self) -> bool {
+		match *self {
+			GrowPolicy::Fixed(bytes) => bytes > 0,
+			GrowPolicy::Exponential { min, max } => min > 0 && min <= max,
+			GrowPolicy::Append => true,
+		}
+	}
+}
+
+/// Combines the existing value, if any, with a merge operand into the new value.
+pub type MergeFn = dyn Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// Merge function registered for a column.
+#[derive(Clone)]
+pub struct MergeOperator(pub Arc<MergeFn>);
+
+impl MergeOperator {
+	pub fn new(f: impl Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static) -> Self {
+		MergeOperator(Arc::new(f))
+	}
+
+	pub fn merge(&self, existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+		(self.0)(existing, operand)
+	}
+}
+
+impl std::fmt::Debug for MergeOperator {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("MergeOperator")
+	}
+}
+
+// Merge functions are not part of the column metadata and are not compared.
+impl PartialEq for MergeOperator {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for MergeOperator {}
+
+/// Extracts secondary index keys from a value.
+pub type ExtractFn = dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync;
+
+/// Secondary index of a hash indexed column. Entries are stored in `column`, which must be a
+/// btree indexed column that is not written to otherwise.
+#[derive(Clone)]
+pub struct SecondaryIndex {
+	pub column: ColId,
+	pub extract: Arc<ExtractFn>,
+}
+
+impl SecondaryIndex {
+	pub fn new(
+		column: ColId,
+		extract: impl Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+	) -> Self {
+		SecondaryIndex { column, extract: Arc::new(extract) }
+	}
+
+	/// Index keys of `value`, without duplicates.
+	pub(crate) fn keys(&self, value: &[u8]) -> Vec<Vec<u8>> {
+		let mut keys = (self.extract)(value);
+		keys.sort_unstable();
+		keys.dedup();
+		keys
+	}
+}
+
+impl std::fmt::Debug for SecondaryIndex {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SecondaryIndex").field("column", &self.column).finish()
+	}
+}
+
+// Extract functions are not part of the column metadata and are not compared.
+impl PartialEq for SecondaryIndex {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for SecondaryIndex {}
+
+/// Receives the column and a problem found by the background scrubber.
+pub type ScrubFn = dyn Fn(ColId, &crate::CheckIssue) + Send + Sync;
+
+/// Options of a single commit, see `Db::commit_with`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitOptions {
+	/// Sync the log file with this commit, overriding `Options::sync_wal`. Commits are written
+	/// to the log file in batches; a batch is synced if any of its commits needs it.
+	pub sync: Option<bool>,
+	/// Block until the commit is written and flushed to the log file.
+	pub wait: bool,
+}
+
+/// Handling of damaged log records when the database is opened, see `Options::recovery_mode`.
+/// Records are damaged when the process or the system stops while they are written, or by
+/// disk errors. What was discarded is reported by `Db::recovery_report`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryMode {
+	/// Refuse to open the database if a record can't be replayed. The log files are kept.
+	Strict,
+	/// Discard a damaged record and the records after it if it is in the last log file, as
+	/// left by a torn write. Refuse to open the database otherwise.
+	TolerateTail,
+	/// Discard a damaged record and all records after it, in any log file.
+	#[default]
+	Salvage,
+}
+
+/// Background scrubber settings. The scrubber walks index chunks and the values they point to,
+/// and btree nodes, performing the same checks as `Db::check`.
+#[derive(Clone)]
+pub struct ScrubOptions {
+	/// Maximum bytes of index chunks and values read per second.
+	pub bytes_per_second: u64,
+	/// Pause between full passes over all columns.
+	pub interval: Duration,
+	/// Called for each problem found. Called from the scrubber thread.
+	pub on_issue: Arc<ScrubFn>,
+}
+
+impl ScrubOptions {
+	/// Scrub at most 1 MiB per second, with an hour between passes.
+	pub fn new(on_issue: impl Fn(ColId, &crate::CheckIssue) + Send + Sync + 'static) -> Self {
+		ScrubOptions {
+			bytes_per_second: 1024 * 1024,
+			interval: Duration::from_secs(3600),
+			on_issue: Arc::new(on_issue),
+		}
+	}
+}
+
+impl std::fmt::Debug for ScrubOptions {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("ScrubOptions")
+			.field("bytes_per_second", &self.bytes_per_second)
+			.field("interval", &self.interval)
+			.finish()
+	}
+}
+
+/// Rate limit of background table writes, see `Options::background_io_limit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoLimit {
+	/// Maximum bytes written per second. 0 for no limit.
+	pub bytes_per_second: u64,
+	/// Maximum index and value table writes per second. 0 for no limit.
+	pub ops_per_second: u64,
+}
+
+/// Limits on memory held by pending writes. Once a limit is exceeded, `Db::commit` blocks
+/// until background threads have written enough data to the log and tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+	/// Max size of keys and values of commits queued for writing to the log.
+	pub commit_queue_bytes: usize,
+	/// Max size of the log overlay, data written to the log but not to the tables yet.
+	pub log_overlay_bytes: usize,
+	/// Bound on the combined size of the commit queue, the log overlay and the
+	/// `value_cache_bytes`, `index_cache_bytes` and `bloom_filter_bytes` of all columns. When set,
+	/// the two limits above are scaled down to fit in what is left after the caches.
+	pub total_bytes: Option<usize>,
+}
+
+impl Default for MemoryBudget {
+	fn default() -> Self {
+		MemoryBudget {
+			commit_queue_bytes: 16 * 1024 * 1024,
+			log_overlay_bytes: 128 * 1024 * 1024,
+			total_bytes: None,
+		}
+	}
+}
+
+impl MemoryBudget {
+	fn cache_bytes(columns: &[ColumnOptions]) -> usize {
+		columns
+			.iter()
+			.map(|c| c.value_cache_bytes + c.index_cache_bytes + c.bloom_filter_bytes)
+			.sum()
+	}
+
+	/// Effective limits of the commit queue and the log overlay.
+	pub(crate) fn queue_limits(&self, columns: &[ColumnOptions]) -> (usize, usize) {
+		let (commit, log) = (self.commit_queue_bytes, self.log_overlay_bytes);
+		let available = match self.total_bytes {
+			Some(total) => total.saturating_sub(Self::cache_bytes(columns)),
+			None => return (commit, log),
+		};
+		if commit.saturating_add(log) <= available {
+			return (commit, log)
+		}
+		let commit = (available as u128 * commit as u128 / (commit as u128 + log as u128)) as usize;
+		(commit, available - commit)
+	}
 }
 
 /// Database metadata.
@@ -75,22 +543,181 @@ pub struct Metadata {
 	pub version: u32,
 	/// Column metadata.
 	pub columns: Vec<ColumnOptions>,
+	/// Columns removed with `Db::drop_column`. Their ids may be reused on the next open.
+	pub dropped: BTreeSet<ColId>,
 }
 
 impl ColumnOptions {
-	fn as_string(&self) -> String {
-		format!(
-			"preimage: {}, uniform: {}, refc: {}, compression: {}, ordered: {}",
-			self.preimage, self.uniform, self.ref_counted, self.compression as u8, self.btree_index,
-		)
+	// Check a value size against `max_value_size`, or `MAX_VALUE_SIZE` for values committed at
+	// once.
+	pub(crate) fn check_value_size(&self, actual: usize, streamed: bool) -> Result<()> {
+		let limit = match (self.max_value_size, streamed) {
+			(Some(limit), _) => limit,
+			(None, false) => MAX_VALUE_SIZE,
+			(None, true) => return Ok(()),
+		};
+		if actual > limit {
+			return Err(Error::ValueTooLarge { limit, actual })
+		}
+		Ok(())
+	}
+
+	pub(crate) fn as_string(&self) -> String {
+		let mut s = format!(
+			"preimage: {}, uniform: {}, refc: {}, compression: {}, ordered: {}, ttl: {}, checksums: {}, hash: {}",
+			self.preimage,
+			self.uniform,
+			self.ref_counted,
+			self.compression.id(),
+			self.btree_index,
+			self.ttl,
+			self.value_checksums,
+			self.key_hash as u8,
+		);
+		// Only written when set, so that metadata of existing columns is unchanged.
+		if let Some(level) = self.compression.level() {
+			s.push_str(&format!(", level: {}", level));
+		}
+		if let Some(dict) = &self.compression_dictionary {
+			s.push_str(&format!(", dict: {}", hex::encode(dict)));
+		}
+		if let Some(size) = self.max_file_size {
+			s.push_str(&format!(", segment: {}", size));
+		}
+		if let Some(threshold) = self.blob_threshold {
+			s.push_str(&format!(", blob: {}", threshold));
+		}
+		if self.count_keys {
+			s.push_str(", count: true");
+		}
+		if self.wide_ref_counts {
+			s.push_str(", refc64: true");
+		}
+		if self.prefix_compression {
+			s.push_str(&format!(", btree: {}", BTREE_FORMAT_PREFIX));
+		}
+		if self.store_keys {
+			s.push_str(", keys: true");
+		}
+		#[cfg(feature = "encryption")]
+		if self.encryption.is_some() {
+			s.push_str(", encrypted: true");
+		}
+		s
+	}
+
+	pub(crate) fn default_compression_threshold(&self) -> u32 {
+		if self.compression_dictionary.is_some() {
+			DEFAULT_DICTIONARY_COMPRESSION_THRESHOLD
+		} else {
+			DEFAULT_COMPRESSION_THRESHOLD
+		}
 	}
 
 	pub fn is_valid(&self) -> bool {
+		match self.check() {
+			Ok(()) => true,
+			Err(reason) => {
+				log::error!(target: "parity-db", "{}", reason);
+				false
+			},
+		}
+	}
+
+	/// Check that the options are supported together, see `Options::validate`.
+	pub fn validate(&self) -> Result<()> {
+		self.check().map_err(Error::InvalidConfiguration)
+	}
+
+	fn check(&self) -> std::result::Result<(), String> {
 		if self.ref_counted && !self.preimage {
-			log::error!(target: "parity-db", "Using `ref_counted` option without `preimage` enabled is not supported");
-			return false
+			return Err(
+				"Using `ref_counted` option without `preimage` enabled is not supported".into()
+			)
 		}
-		true
+		if self.merge.is_some() && (self.ref_counted || self.btree_index) {
+			return Err(
+				"Using `merge` option with `ref_counted` or `btree_index` is not supported".into()
+			)
+		}
+		if self.ttl && (self.ref_counted || self.btree_index || self.merge.is_some()) {
+			return Err(
+				"Using `ttl` option with `ref_counted`, `btree_index` or `merge` is not supported"
+					.into(),
+			)
+		}
+		if self.max_value_size.is_some_and(|size| size > MAX_VALUE_SIZE) {
+			return Err(format!("`max_value_size` must be at most {MAX_VALUE_SIZE} bytes"))
+		}
+		if self.value_cache_bytes > 0 && self.btree_index {
+			return Err("Using `value_cache_bytes` option with `btree_index` is not supported".into())
+		}
+		if !self.secondary_indexes.is_empty() && (self.ref_counted || self.btree_index || self.ttl)
+		{
+			return Err("Using `secondary_indexes` option with `ref_counted`, `btree_index` or `ttl` is not supported".into())
+		}
+		if self.key_hash != KeyHash::Blake2b && (self.uniform || self.btree_index) {
+			return Err(
+				"Using `key_hash` option with `uniform` or `btree_index` is not supported".into()
+			)
+		}
+		#[cfg(feature = "encryption")]
+		if self.encryption.is_some() && (self.uniform || self.key_hash == KeyHash::Identity) {
+			return Err(
+				"Using `encryption` option with `uniform` or `KeyHash::Identity` is not supported"
+					.into(),
+			)
+		}
+		if self.max_file_size.is_some_and(|size| size < 32 * 1024) {
+			return Err("`max_file_size` must be at least 32 KiB".into())
+		}
+		if let Some(threshold) = self.blob_threshold {
+			#[cfg(feature = "encryption")]
+			let encrypted = self.encryption.is_some();
+			#[cfg(not(feature = "encryption"))]
+			let encrypted = false;
+			if threshold < 32 * 1024 || self.btree_index || encrypted {
+				return Err("`blob_threshold` must be at least 32 KiB and is not supported with `btree_index` or encryption".into())
+			}
+		}
+		if self.wide_ref_counts && !self.ref_counted {
+			return Err(
+				"Using `wide_ref_counts` option without `ref_counted` is not supported".into()
+			)
+		}
+		if self.prefix_compression && !self.btree_index {
+			return Err(
+				"Using `prefix_compression` option without `btree_index` is not supported".into()
+			)
+		}
+		if self.store_keys &&
+			(self.uniform ||
+				self.btree_index ||
+				self.ttl || self.merge.is_some() ||
+				!self.secondary_indexes.is_empty())
+		{
+			return Err("Using `store_keys` option with `uniform`, `btree_index`, `ttl`, `merge` or `secondary_indexes` is not supported".into())
+		}
+		if self.bloom_filter_bytes > 0 && self.btree_index {
+			return Err(
+				"Using `bloom_filter_bytes` option with `btree_index` is not supported".into()
+			)
+		}
+		if !self.compression.is_valid() {
+			return Err(format!("Invalid compression level {:?}", self.compression))
+		}
+		if let Some(dict) = &self.compression_dictionary {
+			let supported = match self.compression {
+				CompressionType::Lz4 | CompressionType::Lz4Hc { .. } => true,
+				#[cfg(feature = "zstd")]
+				CompressionType::Zstd { .. } => true,
+				_ => false,
+			};
+			if !supported || dict.len() > MAX_DICTIONARY_SIZE {
+				return Err(format!("`compression_dictionary` requires `Lz4`, `Lz4Hc` or `Zstd` compression and at most {} bytes", MAX_DICTIONARY_SIZE))
+			}
+		}
+		Ok(())
 	}
 
 	fn from_string(s: &str) -> Option<Self> {
@@ -109,14 +736,79 @@ impl ColumnOptions {
 		let uniform = vals.get("uniform")?.parse().ok()?;
 		let ref_counted = vals.get("refc")?.parse().ok()?;
 		let compression: u8 = vals.get("compression").and_then(|c| c.parse().ok()).unwrap_or(0);
+		let level = match vals.get("level") {
+			Some(l) => Some(l.parse().ok()?),
+			None => None,
+		};
+		#[cfg(not(feature = "zstd"))]
+		if compression == 4 {
+			log::error!(target: "parity-db", "Column uses zstd compression, the `zstd` feature is required");
+			return None
+		}
+		let compression = CompressionType::from_id(compression, level)?;
 		let btree_index = vals.get("ordered").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let ttl = vals.get("ttl").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let value_checksums = vals.get("checksums").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let key_hash = match vals.get("hash") {
+			Some(h) => KeyHash::from_u8(h.parse().ok()?)?,
+			None => KeyHash::Blake2b,
+		};
+		let compression_dictionary = match vals.get("dict") {
+			Some(d) => Some(hex::decode(d).ok()?.into()),
+			None => None,
+		};
+		let max_file_size = match vals.get("segment") {
+			Some(size) => Some(size.parse().ok()?),
+			None => None,
+		};
+		let blob_threshold = match vals.get("blob") {
+			Some(threshold) => Some(threshold.parse().ok()?),
+			None => None,
+		};
+		let count_keys = vals.get("count").is_some_and(|c| *c == "true");
+		let wide_ref_counts = vals.get("refc64").is_some_and(|c| *c == "true");
+		let store_keys = vals.get("keys").is_some_and(|c| *c == "true");
+		let prefix_compression = match vals.get("btree").map(|v| v.parse::<u32>()) {
+			None => false,
+			Some(Ok(BTREE_FORMAT_PREFIX)) => true,
+			Some(version) => {
+				log::error!(target: "parity-db", "Unsupported btree node format {:?}", version);
+				return None
+			},
+		};
+		let encrypted = vals.get("encrypted").is_some_and(|e| *e == "true");
+		#[cfg(not(feature = "encryption"))]
+		if encrypted {
+			log::error!(target: "parity-db", "Column is encrypted, the `encryption` feature is required");
+			return None
+		}
 
 		Some(ColumnOptions {
 			preimage,
 			uniform,
 			ref_counted,
-			compression: compression.into(),
+			wide_ref_counts,
+			compression,
+			compression_dictionary,
 			btree_index,
+			merge: None,
+			secondary_indexes: Vec::new(),
+			ttl,
+			value_checksums,
+			key_hash,
+			max_file_size,
+			blob_threshold,
+			count_keys,
+			prefix_compression,
+			store_keys,
+			read_mode: ReadMode::Pread,
+			access_hint: AccessHint::Random,
+			value_cache_bytes: 0,
+			index_cache_bytes: 0,
+			bloom_filter_bytes: 0,
+			max_value_size: None,
+			#[cfg(feature = "encryption")]
+			encryption: encrypted.then(crate::encryption::Encryption::missing_keys),
 		})
 	}
 }
@@ -127,8 +819,28 @@ impl Default for ColumnOptions {
 			preimage: false,
 			uniform: false,
 			ref_counted: false,
+			wide_ref_counts: false,
 			compression: CompressionType::NoCompression,
+			compression_dictionary: None,
 			btree_index: false,
+			merge: None,
+			secondary_indexes: Vec::new(),
+			ttl: false,
+			value_checksums: false,
+			key_hash: KeyHash::Blake2b,
+			max_file_size: None,
+			blob_threshold: None,
+			count_keys: false,
+			prefix_compression: false,
+			store_keys: false,
+			read_mode: ReadMode::Pread,
+			access_hint: AccessHint::Random,
+			value_cache_bytes: 0,
+			index_cache_bytes: 0,
+			bloom_filter_bytes: 0,
+			max_value_size: None,
+			#[cfg(feature = "encryption")]
+			encryption: None,
 		}
 	}
 }
@@ -143,13 +855,48 @@ impl Options {
 			salt: None,
 			columns: (0..num_columns).map(|_| Default::default()).collect(),
 			compression_threshold: HashMap::new(),
-			#[cfg(any(test, feature = "instrumentation"))]
+			log_archive: None,
+			slow_io_warn: None,
+			direct_io: false,
+			io_uring: false,
+			scrub: None,
+			compaction_rate: 32 * 1024 * 1024,
+			background_io_limit: None,
+			grow_policy: Default::default(),
+			preallocate: false,
+			memory_budget: Default::default(),
+			max_commit_bytes: None,
+			compression_threads: 4,
+			reindex_threads: 4,
+			replay_threads: 4,
+			recovery_mode: Default::default(),
+			in_memory: false,
+			storage: None,
+			expiry_interval: Duration::from_secs(60),
+			#[cfg(feature = "metrics")]
+			metrics: None,
 			with_background_thread: true,
 			#[cfg(any(test, feature = "instrumentation"))]
 			always_flush: false,
 		}
 	}
 
+	/// Options of an in-memory database, for tests that don't need the data to outlive the
+	/// database. Nothing is written to disk and the log is never synced.
+	pub fn memory(num_columns: u8) -> Options {
+		Options {
+			in_memory: true,
+			sync_wal: false,
+			sync_data: false,
+			..Options::with_columns(Path::new(":memory:"), num_columns)
+		}
+	}
+
+	/// Access to the database files.
+	pub(crate) fn files(&self) -> Files {
+		Files::new(self.storage.clone())
+	}
+
 	// TODO on next major version remove in favor of write_metadata_with_version
 	pub fn write_metadata(&self, path: &Path, salt: &Salt) -> Result<()> {
 		self.write_metadata_with_version(path, salt, None)
@@ -176,20 +923,47 @@ impl Options {
 		path: &Path,
 		salt: &Salt,
 		version: Option<u32>,
+	) -> Result<()> {
+		self.write_metadata_file_with_dropped(path, salt, version, &BTreeSet::new())
+	}
+
+	/// Write metadata, marking `dropped` columns with a tombstone.
+	pub(crate) fn write_metadata_with_dropped(
+		&self,
+		path: &Path,
+		salt: &Salt,
+		version: Option<u32>,
+		dropped: &BTreeSet<ColId>,
+	) -> Result<()> {
+		let mut path = path.to_path_buf();
+		path.push("metadata");
+		self.write_metadata_file_with_dropped(&path, salt, version, dropped)
+	}
+
+	fn write_metadata_file_with_dropped(
+		&self,
+		path: &Path,
+		salt: &Salt,
+		version: Option<u32>,
+		dropped: &BTreeSet<ColId>,
 	) -> Result<()> {
 		let mut metadata = vec![
 			format!("version={}", version.unwrap_or(CURRENT_VERSION)),
 			format!("salt={}", hex::encode(salt)),
 		];
 		for i in 0..self.columns.len() {
-			metadata.push(format!("col{}={}", i, self.columns[i].as_string()));
+			if dropped.contains(&(i as ColId)) {
+				metadata.push(format!("col{}={}", i, DROPPED_COLUMN));
+			} else {
+				metadata.push(format!("col{}={}", i, self.columns[i].as_string()));
+			}
 		}
-		try_io!(std::fs::write(path, metadata.join("\n")));
+		try_io!(self.files().write(path, metadata.join("\n").as_bytes()));
 		Ok(())
 	}
 
 	pub fn load_and_validate_metadata(&self, create: bool) -> Result<Metadata> {
-		let meta = Self::load_metadata(&self.path)?;
+		let meta = Self::read_metadata_file(&self.files(), &self.path.join("metadata"))?;
 
 		if let Some(meta) = meta {
 			if meta.columns.len() != self.columns.len() {
@@ -200,7 +974,26 @@ impl Options {
 				)))
 			}
 
+			let mut meta = meta;
 			for c in 0..meta.columns.len() {
+				if meta.dropped.contains(&(c as ColId)) {
+					// The column id is free, it is reused with the given options.
+					meta.columns[c] = self.columns[c].clone();
+					continue
+				}
+				meta.columns[c].read_mode = self.columns[c].read_mode;
+				meta.columns[c].access_hint = self.columns[c].access_hint;
+				meta.columns[c].value_cache_bytes = self.columns[c].value_cache_bytes;
+				meta.columns[c].index_cache_bytes = self.columns[c].index_cache_bytes;
+				meta.columns[c].bloom_filter_bytes = self.columns[c].bloom_filter_bytes;
+				meta.columns[c].max_value_size = self.columns[c].max_value_size;
+				meta.columns[c].secondary_indexes = self.columns[c].secondary_indexes.clone();
+				#[cfg(feature = "encryption")]
+				if let (Some(keys), Some(_)) =
+					(&self.columns[c].encryption, &meta.columns[c].encryption)
+				{
+					meta.columns[c].encryption = Some(keys.clone());
+				}
 				if meta.columns[c] != self.columns[c] {
 					return Err(Error::IncompatibleColumnConfig {
 						id: c as ColId,
@@ -216,7 +1009,12 @@ impl Options {
 		} else if create {
 			let s: Salt = self.salt.unwrap_or_else(|| rand::thread_rng().gen());
 			self.write_metadata(&self.path, &s)?;
-			Ok(Metadata { version: CURRENT_VERSION, columns: self.columns.clone(), salt: s })
+			Ok(Metadata {
+				version: CURRENT_VERSION,
+				columns: self.columns.clone(),
+				salt: s,
+				dropped: BTreeSet::new(),
+			})
 		} else {
 			Err(Error::DatabaseNotFound)
 		}
@@ -229,16 +1027,21 @@ impl Options {
 	}
 
 	pub fn load_metadata_file(path: &Path) -> Result<Option<Metadata>> {
+		Self::read_metadata_file(&Files::default(), path)
+	}
+
+	fn read_metadata_file(files: &Files, path: &Path) -> Result<Option<Metadata>> {
 		use std::{io::BufRead, str::FromStr};
 
-		if !path.exists() {
-			return Ok(None)
-		}
-		let file = std::io::BufReader::new(try_io!(std::fs::File::open(path)));
+		let file = match try_io!(files.read(path)) {
+			Some(data) => data,
+			None => return Ok(None),
+		};
 		let mut salt = None;
 		let mut columns = Vec::new();
+		let mut dropped = BTreeSet::new();
 		let mut version = 0;
-		for l in file.lines() {
+		for l in file.as_slice().lines() {
 			let l = try_io!(l);
 			let mut vals = l.split('=');
 			let k = vals.next().ok_or_else(|| Error::Corruption("Bad metadata".into()))?;
@@ -253,6 +1056,11 @@ impl Options {
 				s.copy_from_slice(&salt_slice);
 				salt = Some(s);
 			} else if k.starts_with("col") {
+				if v == DROPPED_COLUMN {
+					dropped.insert(columns.len() as ColId);
+					columns.push(ColumnOptions::default());
+					continue
+				}
 				let col = ColumnOptions::from_string(v)
 					.ok_or_else(|| Error::Corruption("Bad column metadata".into()))?;
 				columns.push(col);
@@ -265,17 +1073,249 @@ impl Options {
 			)))
 		}
 		let salt = salt.ok_or_else(|| Error::InvalidConfiguration("Missing salt value".into()))?;
-		Ok(Some(Metadata { version, columns, salt }))
+		Ok(Some(Metadata { version, columns, salt, dropped }))
 	}
 
 	pub fn is_valid(&self) -> bool {
-		for option in self.columns.iter() {
-			if !option.is_valid() {
-				return false
+		match self.validate() {
+			Ok(()) => true,
+			Err(e) => {
+				log::error!(target: "parity-db", "{}", e);
+				false
+			},
+		}
+	}
+
+	/// Check that the options are supported together. Returns an `Error::InvalidConfiguration`
+	/// or `Error::IncompatibleColumnConfig` describing the first problem found.
+	pub fn validate(&self) -> Result<()> {
+		for (id, option) in self.columns.iter().enumerate() {
+			option
+				.check()
+				.map_err(|reason| Error::IncompatibleColumnConfig { id: id as ColId, reason })?;
+		}
+		check_secondary_indexes(&self.columns).map_err(Error::InvalidConfiguration)?;
+		if !self.grow_policy.is_valid() {
+			return Err(Error::InvalidConfiguration(format!(
+				"Invalid `grow_policy` {:?}",
+				self.grow_policy
+			)))
+		}
+		if self
+			.memory_budget
+			.total_bytes
+			.is_some_and(|total| MemoryBudget::cache_bytes(&self.columns) >= total)
+		{
+			return Err(Error::InvalidConfiguration(
+				"Column caches exceed `memory_budget.total_bytes`".into(),
+			))
+		}
+		Ok(())
+	}
+}
+
+// Setters of builder fields, each documented with the option it sets.
+macro_rules! setters {
+	($target:ident { $($field:ident: $ty:ty),* $(,)? } some { $($opt:ident: $opt_ty:ty),* $(,)? }) => {
+		$(
+			#[doc = concat!("Set `", stringify!($target), "::", stringify!($field), "`.")]
+			pub fn $field(mut self, value: $ty) -> Self {
+				self.0.$field = value;
+				self
+			}
+		)*
+		$(
+			#[doc = concat!("Set `", stringify!($target), "::", stringify!($opt), "`.")]
+			pub fn $opt(mut self, value: $opt_ty) -> Self {
+				self.0.$opt = Some(value);
+				self
+			}
+		)*
+	};
+}
+
+/// Builds `ColumnOptions`, rejecting unsupported and meaningless combinations of options.
+#[derive(Clone, Debug, Default)]
+pub struct ColumnOptionsBuilder(ColumnOptions);
+
+impl ColumnOptions {
+	pub fn builder() -> ColumnOptionsBuilder {
+		Default::default()
+	}
+}
+
+impl ColumnOptionsBuilder {
+	setters! {
+		ColumnOptions {
+			preimage: bool,
+			uniform: bool,
+			ref_counted: bool,
+			wide_ref_counts: bool,
+			compression: CompressionType,
+			btree_index: bool,
+			ttl: bool,
+			value_checksums: bool,
+			key_hash: KeyHash,
+			count_keys: bool,
+			prefix_compression: bool,
+			store_keys: bool,
+			read_mode: ReadMode,
+			access_hint: AccessHint,
+			value_cache_bytes: usize,
+			index_cache_bytes: usize,
+			bloom_filter_bytes: usize,
+		}
+		some {
+			compression_dictionary: Arc<[u8]>,
+			merge: MergeOperator,
+			max_file_size: u64,
+			blob_threshold: u32,
+			max_value_size: usize,
+		}
+	}
+
+	/// Add to `ColumnOptions::secondary_indexes`.
+	pub fn secondary_index(mut self, index: SecondaryIndex) -> Self {
+		self.0.secondary_indexes.push(index);
+		self
+	}
+
+	/// Set `ColumnOptions::encryption`.
+	#[cfg(feature = "encryption")]
+	pub fn encryption(mut self, encryption: crate::encryption::Encryption) -> Self {
+		self.0.encryption = Some(encryption);
+		self
+	}
+
+	/// Check the options, see `ColumnOptions::validate`. Options that would be ignored are
+	/// rejected too.
+	pub fn build(self) -> Result<ColumnOptions> {
+		let options = self.0;
+		options.validate()?;
+		if options.btree_index && options.uniform {
+			return Err(Error::InvalidConfiguration(
+				"`uniform` has no effect on btree indexed columns, keys are not hashed".into(),
+			))
+		}
+		Ok(options)
+	}
+}
+
+/// Builds `Options`, rejecting unsupported and meaningless combinations of options.
+#[derive(Clone, Debug)]
+pub struct OptionsBuilder(Options);
+
+impl Options {
+	/// Options of a database at `path`, with no columns and default settings.
+	pub fn builder(path: &Path) -> OptionsBuilder {
+		OptionsBuilder(Options::with_columns(path, 0))
+	}
+}
+
+impl OptionsBuilder {
+	setters! {
+		Options {
+			sync_wal: bool,
+			sync_data: bool,
+			stats: bool,
+			direct_io: bool,
+			io_uring: bool,
+			compaction_rate: u64,
+			grow_policy: GrowPolicy,
+			preallocate: bool,
+			memory_budget: MemoryBudget,
+			compression_threads: usize,
+			reindex_threads: usize,
+			replay_threads: usize,
+			recovery_mode: RecoveryMode,
+			in_memory: bool,
+			expiry_interval: Duration,
+		}
+		some {
+			salt: Salt,
+			log_archive: std::path::PathBuf,
+			slow_io_warn: Duration,
+			scrub: ScrubOptions,
+			background_io_limit: IoLimit,
+			max_commit_bytes: usize,
+			storage: Arc<dyn Storage>,
+		}
+	}
+
+	/// Add a column, built with `ColumnOptions::builder`.
+	pub fn column(mut self, column: ColumnOptions) -> Self {
+		self.0.columns.push(column);
+		self
+	}
+
+	/// Set `Options::compression_threshold` of column `col`.
+	pub fn compression_threshold(mut self, col: ColId, threshold: u32) -> Self {
+		self.0.compression_threshold.insert(col, threshold);
+		self
+	}
+
+	/// Set `Options::metrics`.
+	#[cfg(feature = "metrics")]
+	pub fn metrics(mut self, metrics: crate::metrics::Metrics) -> Self {
+		self.0.metrics = Some(metrics);
+		self
+	}
+
+	/// Check the options, see `Options::validate`. Options that would be ignored are rejected
+	/// too.
+	pub fn build(self) -> Result<Options> {
+		let options = self.0;
+		if options.columns.len() > ColId::MAX as usize + 1 {
+			return Err(Error::InvalidConfiguration(format!(
+				"At most {} columns are supported",
+				ColId::MAX as usize + 1
+			)))
+		}
+		options.validate()?;
+		for &col in options.compression_threshold.keys() {
+			match options.columns.get(col as usize) {
+				None =>
+					return Err(Error::InvalidConfiguration(format!(
+						"`compression_threshold` is set for missing column {}",
+						col
+					))),
+				Some(column) if column.compression == CompressionType::NoCompression =>
+					return Err(Error::IncompatibleColumnConfig {
+						id: col,
+						reason: "`compression_threshold` is set without `compression`".into(),
+					}),
+				Some(_) => (),
+			}
+		}
+		Ok(options)
+	}
+}
+
+/// Check that each secondary index is stored in a distinct btree indexed column.
+pub(crate) fn secondary_indexes_valid(columns: &[ColumnOptions]) -> bool {
+	match check_secondary_indexes(columns) {
+		Ok(()) => true,
+		Err(reason) => {
+			log::error!(target: "parity-db", "{}", reason);
+			false
+		},
+	}
+}
+
+fn check_secondary_indexes(columns: &[ColumnOptions]) -> std::result::Result<(), String> {
+	let mut used = BTreeSet::new();
+	for (c, options) in columns.iter().enumerate() {
+		for index in &options.secondary_indexes {
+			let target = columns.get(index.column as usize);
+			if index.column as usize == c ||
+				!target.is_some_and(|t| t.btree_index && !t.ref_counted) ||
+				!used.insert(index.column)
+			{
+				return Err(format!("Secondary indexes of column {} must be stored in distinct non reference counted btree columns", c))
 			}
 		}
-		true
 	}
+	Ok(())
 }
 
 impl Metadata {
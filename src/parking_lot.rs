@@ -66,6 +66,15 @@ mod with_loom {
 		pub fn wait<T>(&self, mutex_guard: &mut MutexGuard<'_, T>) {
 			mutex_guard.0 = Some(self.0.wait(mutex_guard.0.take().unwrap()).unwrap())
 		}
+
+		pub fn wait_for<T>(
+			&self,
+			mutex_guard: &mut MutexGuard<'_, T>,
+			timeout: std::time::Duration,
+		) {
+			mutex_guard.0 =
+				Some(self.0.wait_timeout(mutex_guard.0.take().unwrap(), timeout).unwrap().0)
+		}
 	}
 
 	#[derive(Debug, Default)]
@@ -87,6 +96,10 @@ mod with_loom {
 		pub fn write(&self) -> RwLockWriteGuard<'_, T> {
 			RwLockWriteGuard(self.0.write().unwrap())
 		}
+
+		pub fn into_inner(self) -> T {
+			self.0.into_inner().unwrap()
+		}
 	}
 
 	#[derive(Debug)]
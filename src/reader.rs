@@ -0,0 +1,44 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+use crate::{compress::Compress, error::Result, file::DbFile};
+use std::io::Read;
+
+/// Reads a value returned by [`crate::Db::get_reader`].
+///
+/// Values stored in their own file, see `ColumnOptions::blob_threshold`, are streamed from the
+/// file, and decompressed as they are read if the column uses `CompressionType::Snappy`. Other
+/// values are read at once.
+pub struct ValueReader(Box<dyn Read + Send>);
+
+impl ValueReader {
+	pub(crate) fn memory(value: Vec<u8>) -> ValueReader {
+		ValueReader(Box::new(std::io::Cursor::new(value)))
+	}
+
+	/// Value of `len` bytes at the start of `file`.
+	pub(crate) fn file(
+		file: DbFile,
+		len: u64,
+		compressed: bool,
+		compression: &Compress,
+	) -> Result<ValueReader> {
+		let reader = file.take(len);
+		if compressed {
+			return Ok(ValueReader(compression.decompress_reader(reader)?))
+		}
+		Ok(ValueReader(Box::new(reader)))
+	}
+}
+
+impl Read for ValueReader {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl std::fmt::Debug for ValueReader {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ValueReader").finish_non_exhaustive()
+	}
+}
@@ -0,0 +1,70 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Secondary indexes of hash indexed columns.
+//!
+//! Each index is kept in a btree indexed column, with an empty value stored under
+//! `index_key ++ primary_key ++ len(index_key)` for each index key extracted from the value of
+//! a primary key. The length suffix tells apart entries of index keys that are prefixes of
+//! other index keys. Index entries are written in the same commit as the values they are
+//! extracted from.
+
+use crate::{btree::BTreeIterator, error::Result};
+
+const LEN_SIZE: usize = 4;
+
+/// Btree key of the entry for `index_key` and `primary_key`.
+pub fn entry_key(index_key: &[u8], primary_key: &[u8]) -> Vec<u8> {
+	let mut key = Vec::with_capacity(index_key.len() + primary_key.len() + LEN_SIZE);
+	key.extend_from_slice(index_key);
+	key.extend_from_slice(primary_key);
+	key.extend_from_slice(&(index_key.len() as u32).to_be_bytes());
+	key
+}
+
+// Primary key of an entry for an index key of `index_key_len` bytes.
+fn primary_key(entry: &[u8], index_key_len: usize) -> Option<&[u8]> {
+	let len_at = entry.len().checked_sub(LEN_SIZE)?;
+	let len = u32::from_be_bytes(entry[len_at..].try_into().expect("4 bytes"));
+	(len as usize == index_key_len && index_key_len <= len_at)
+		.then(|| &entry[index_key_len..len_at])
+}
+
+/// Iterator over the primary keys with a given secondary index key, see `Db::iter_secondary`.
+pub struct SecondaryIterator<'a> {
+	iter: BTreeIterator<'a>,
+	key_len: usize,
+}
+
+impl<'a> SecondaryIterator<'a> {
+	pub(crate) fn new(iter: BTreeIterator<'a>, key_len: usize) -> Self {
+		SecondaryIterator { iter, key_len }
+	}
+
+	/// Next primary key, in key order.
+	#[allow(clippy::should_implement_trait)]
+	pub fn next(&mut self) -> Result<Option<Vec<u8>>> {
+		while let Some((entry, _)) = self.iter.next()? {
+			if let Some(key) = primary_key(&entry, self.key_len) {
+				return Ok(Some(key.to_vec()))
+			}
+		}
+		Ok(None)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{entry_key, primary_key};
+
+	#[test]
+	fn entry_keys() {
+		let entry = entry_key(b"ab", b"key");
+		assert_eq!(primary_key(&entry, 2), Some(&b"key"[..]));
+		// Entries of longer index keys share the prefix.
+		assert_eq!(primary_key(&entry, 1), None);
+		assert_eq!(primary_key(&entry_key(b"abk", b"ey"), 2), None);
+		assert_eq!(primary_key(&entry_key(b"", b""), 0), Some(&b""[..]));
+		assert_eq!(primary_key(b"abc", 0), None);
+	}
+}
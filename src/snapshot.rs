@@ -0,0 +1,252 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Point-in-time database views.
+//!
+//! A snapshot does not copy any data when created. Instead, while a snapshot is alive,
+//! every commit records the values it is about to replace into the snapshot overlay.
+//! A snapshot read first queries the current state and then checks the overlay:
+//! if the key was changed after the snapshot was taken, the recorded value is returned.
+
+use crate::{
+	btree::{BTreeIterator, IterDirection, LastKey},
+	column::ColId,
+	db::Snapshot,
+	error::Result,
+	parking_lot::RwLock,
+	Key, Value,
+};
+use std::{
+	collections::{BTreeMap, HashMap},
+	ops::Bound,
+	sync::Arc,
+};
+
+#[derive(Debug, Default)]
+struct SnapshotOverlay {
+	indexed: HashMap<Key, Option<Value>>,
+	btree_indexed: BTreeMap<Vec<u8>, Option<Value>>,
+}
+
+/// Values replaced since the snapshot was taken.
+#[derive(Debug)]
+pub(crate) struct SnapshotState {
+	overlay: RwLock<Vec<SnapshotOverlay>>,
+}
+
+impl SnapshotState {
+	pub fn new(num_columns: usize) -> Self {
+		SnapshotState {
+			overlay: RwLock::new((0..num_columns).map(|_| Default::default()).collect()),
+		}
+	}
+
+	/// Record the value of a hash column key before it is changed. Only the first change
+	/// after the snapshot is taken is recorded.
+	pub fn record(&self, col: ColId, key: Key, value: Option<Value>) {
+		let mut overlay = self.overlay.write();
+		Self::column_overlay(&mut overlay, col).indexed.entry(key).or_insert(value);
+	}
+
+	/// Record the value of a btree column key before it is changed.
+	pub fn record_btree(&self, col: ColId, key: Vec<u8>, value: Option<Value>) {
+		let mut overlay = self.overlay.write();
+		Self::column_overlay(&mut overlay, col)
+			.btree_indexed
+			.entry(key)
+			.or_insert(value);
+	}
+
+	// Columns may be added after the snapshot was taken.
+	fn column_overlay(overlay: &mut Vec<SnapshotOverlay>, col: ColId) -> &mut SnapshotOverlay {
+		if overlay.len() <= col as usize {
+			overlay.resize_with(col as usize + 1, Default::default);
+		}
+		&mut overlay[col as usize]
+	}
+
+	/// Snapshot value for a key, given the current value. The current value must be
+	/// queried before calling this.
+	pub fn get(&self, col: ColId, key: &Key, current: Option<Value>) -> Option<Value> {
+		match self.overlay.read().get(col as usize).and_then(|o| o.indexed.get(key)) {
+			Some(value) => value.clone(),
+			None => current,
+		}
+	}
+
+	pub fn btree_get(&self, col: ColId, key: &[u8], current: Option<Value>) -> Option<Value> {
+		match self.overlay.read().get(col as usize).and_then(|o| o.btree_indexed.get(key)) {
+			Some(value) => value.clone(),
+			None => current,
+		}
+	}
+
+	fn btree_next(
+		&self,
+		col: ColId,
+		last_key: &LastKey,
+		direction: IterDirection,
+	) -> Option<(Vec<u8>, Option<Value>)> {
+		let overlay = self.overlay.read();
+		let btree = &overlay.get(col as usize)?.btree_indexed;
+		let (lower, upper) = match (direction, last_key) {
+			(IterDirection::Forward, LastKey::End) | (IterDirection::Backward, LastKey::Start) =>
+				return None,
+			(_, LastKey::Start) | (_, LastKey::End) => (Bound::Unbounded, Bound::Unbounded),
+			(IterDirection::Forward, LastKey::At(key)) =>
+				(Bound::Excluded(key.clone()), Bound::Unbounded),
+			(IterDirection::Forward, LastKey::Seeked(key)) =>
+				(Bound::Included(key.clone()), Bound::Unbounded),
+			(IterDirection::Backward, LastKey::At(key)) =>
+				(Bound::Unbounded, Bound::Excluded(key.clone())),
+			(IterDirection::Backward, LastKey::Seeked(key)) =>
+				(Bound::Unbounded, Bound::Included(key.clone())),
+		};
+		let mut range = btree.range::<Vec<u8>, _>((lower, upper));
+		let next = match direction {
+			IterDirection::Forward => range.next(),
+			IterDirection::Backward => range.next_back(),
+		};
+		next.map(|(k, v)| (k.clone(), v.clone()))
+	}
+}
+
+type IterResult = Result<Option<(Vec<u8>, Value)>>;
+
+/// Iterator over a btree indexed column as of the time the snapshot was taken.
+pub struct SnapshotIterator<'a> {
+	iter: BTreeIterator<'a>,
+	state: Arc<SnapshotState>,
+	col: ColId,
+	last_key: LastKey,
+	// Direction the inner iterator is positioned for, `None` if it needs to be repositioned.
+	direction: Option<IterDirection>,
+	// Item read from the inner iterator but not returned yet.
+	pending: Option<Option<(Vec<u8>, Value)>>,
+	// Snapshot owned by the iterator, kept alive for the iterator lifetime.
+	_snapshot: Option<Snapshot<'a>>,
+}
+
+impl<'a> SnapshotIterator<'a> {
+	pub(crate) fn new(iter: BTreeIterator<'a>, state: Arc<SnapshotState>, col: ColId) -> Self {
+		SnapshotIterator {
+			iter,
+			state,
+			col,
+			last_key: LastKey::Start,
+			direction: None,
+			pending: None,
+			_snapshot: None,
+		}
+	}
+
+	pub(crate) fn with_snapshot(mut self, snapshot: Snapshot<'a>) -> Self {
+		self._snapshot = Some(snapshot);
+		self
+	}
+
+	pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+		self.reset(LastKey::Seeked(key.to_vec()));
+		Ok(())
+	}
+
+	pub fn seek_to_first(&mut self) -> Result<()> {
+		self.reset(LastKey::Start);
+		Ok(())
+	}
+
+	pub fn seek_to_last(&mut self) -> Result<()> {
+		self.reset(LastKey::End);
+		Ok(())
+	}
+
+	#[allow(clippy::should_implement_trait)]
+	pub fn next(&mut self) -> IterResult {
+		self.iter_inner(IterDirection::Forward)
+	}
+
+	pub fn prev(&mut self) -> IterResult {
+		self.iter_inner(IterDirection::Backward)
+	}
+
+	fn reset(&mut self, last_key: LastKey) {
+		self.last_key = last_key;
+		self.direction = None;
+		self.pending = None;
+	}
+
+	fn position(&mut self, direction: IterDirection) -> Result<()> {
+		if self.direction == Some(direction) {
+			return Ok(())
+		}
+		self.pending = None;
+		match &self.last_key {
+			LastKey::Start => self.iter.seek_to_first()?,
+			LastKey::End => self.iter.seek_to_last()?,
+			LastKey::Seeked(key) => self.iter.seek(key)?,
+			LastKey::At(key) => {
+				let key = key.clone();
+				self.iter.seek(&key)?;
+				// Skip the last returned key.
+				let next = self.inner_next(direction)?;
+				if next.as_ref().is_none_or(|(k, _)| *k != key) {
+					self.pending = Some(next);
+				}
+			},
+		}
+		self.direction = Some(direction);
+		Ok(())
+	}
+
+	fn inner_next(&mut self, direction: IterDirection) -> IterResult {
+		match direction {
+			IterDirection::Forward => self.iter.next(),
+			IterDirection::Backward => self.iter.prev(),
+		}
+	}
+
+	fn iter_inner(&mut self, direction: IterDirection) -> IterResult {
+		self.position(direction)?;
+		loop {
+			// The current state must be read before the snapshot overlay.
+			let current = match self.pending.take() {
+				Some(pending) => pending,
+				None => self.inner_next(direction)?,
+			};
+			let recorded = self.state.btree_next(self.col, &self.last_key, direction);
+			let (key, value) = match (current, recorded) {
+				(Some((key, value)), Some((recorded_key, recorded_value))) => {
+					let ordering = match direction {
+						IterDirection::Forward => recorded_key.cmp(&key),
+						IterDirection::Backward => key.cmp(&recorded_key),
+					};
+					match ordering {
+						std::cmp::Ordering::Less => {
+							self.pending = Some(Some((key, value)));
+							(recorded_key, recorded_value)
+						},
+						std::cmp::Ordering::Equal => (recorded_key, recorded_value),
+						std::cmp::Ordering::Greater => (key, Some(value)),
+					}
+				},
+				(Some((key, value)), None) => (key, Some(value)),
+				(None, Some((recorded_key, recorded_value))) => {
+					self.pending = Some(None);
+					(recorded_key, recorded_value)
+				},
+				(None, None) => {
+					self.pending = Some(None);
+					self.last_key = match direction {
+						IterDirection::Forward => LastKey::End,
+						IterDirection::Backward => LastKey::Start,
+					};
+					return Ok(None)
+				},
+			};
+			self.last_key = LastKey::At(key.clone());
+			if let Some(value) = value {
+				return Ok(Some((key, value)))
+			}
+		}
+	}
+}
@@ -15,11 +15,11 @@ const HISTOGRAM_BUCKETS: usize = 1024;
 const HISTOGRAM_BUCKET_BITS: u8 = 5;
 
 pub const TOTAL_SIZE: usize =
-	4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 13;
+	4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 15;
 
 // TODO: get rid of the struct and use index meta directly.
 #[derive(Debug)]
-pub struct ColumnStats {
+pub struct ColumnStatCounters {
 	value_histogram: Vec<AtomicU32>,
 	query_histogram: Vec<AtomicU64>, // Per size tier
 	oversized: AtomicU64,
@@ -36,6 +36,8 @@ pub struct ColumnStats {
 	queries_miss: AtomicU64,
 	uncompressed_bytes: AtomicU64,
 	compression_delta: Vec<AtomicI64>,
+	compressed_values: AtomicU64,
+	below_threshold_values: AtomicU64,
 }
 
 /// Database statistics summary.
@@ -57,6 +59,208 @@ pub struct ColumnStatSummary {
 	pub uncompressed_bytes: u64,
 }
 
+/// Column statistics. Value and query counters are only collected for hash indexed columns when
+/// `Options::stats` is enabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnStats {
+	/// Current number of values in the column.
+	pub total_values: u64,
+	/// Total size of (compressed) values in the column.
+	pub total_bytes: u64,
+	/// Total size of values in the column before compression.
+	pub uncompressed_bytes: u64,
+	/// Number of values by uncompressed size, as `(first size of the bucket, count)` for each
+	/// non-empty bucket. Buckets are 32 bytes wide.
+	pub value_histogram: Vec<(u32, u64)>,
+	/// Bytes saved by compression by uncompressed size, in the same buckets as
+	/// `value_histogram`.
+	pub compression_savings: Vec<(u32, i64)>,
+	/// Number of values stored compressed.
+	pub compressed_values: u64,
+	/// Number of values stored uncompressed because they are no larger than the column's
+	/// compression threshold. The remaining values did not get smaller when compressed.
+	pub below_threshold_values: u64,
+	/// Number of values too large for the histogram.
+	pub oversized_values: u64,
+	/// Total compressed size of the oversized values.
+	pub oversized_bytes: u64,
+	/// Number of commits that changed the column.
+	pub commits: u64,
+	/// Insertions of new values.
+	pub inserted_new: u64,
+	/// Insertions that replaced an existing value.
+	pub inserted_overwrite: u64,
+	/// Reference increases of existing values.
+	pub reference_increase_hit: u64,
+	/// Reference increases of missing values.
+	pub reference_increase_miss: u64,
+	/// Removals of existing values.
+	pub removed_hit: u64,
+	/// Removals of missing values.
+	pub removed_miss: u64,
+	/// Queries that found a value, by value table size tier.
+	pub query_hits: Vec<u64>,
+	/// Queries that found no value.
+	pub query_misses: u64,
+	/// Occupancy of the value tables.
+	pub tables: Vec<TableOccupancy>,
+	/// Progress of the last `Db::reencrypt_column` of the column since the database was opened.
+	#[cfg(feature = "encryption")]
+	pub reencryption: Option<ReencryptionProgress>,
+	/// Progress of the last `Db::compact_column` of the column since the database was opened.
+	pub compaction: Option<CompactionProgress>,
+	/// Progress of the ongoing `Db::migrate_column` of the column.
+	pub migration: Option<MigrationProgress>,
+}
+
+/// Progress of copying the values of a column to its new options, see `Db::migrate_column`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+	/// Keys copied so far.
+	pub copied_keys: u64,
+	/// Index chunks copied so far, for hash indexed columns.
+	pub scanned_chunks: u64,
+	/// Index chunks to copy, for hash indexed columns. 0 until the copy starts.
+	pub total_chunks: u64,
+	/// All values have been copied and the migration waits to switch to the new column.
+	pub copy_done: bool,
+}
+
+/// Progress of moving the values of a column to the start of its value tables.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionProgress {
+	/// Index chunks checked so far.
+	pub scanned_chunks: u64,
+	/// Index chunks to check.
+	pub total_chunks: u64,
+	/// Values moved to lower table entries.
+	pub moved_values: u64,
+	/// Bytes released from the value table files.
+	pub released_bytes: u64,
+	/// All index chunks have been checked and the files truncated.
+	pub done: bool,
+}
+
+/// Progress of rewriting the values of a column with the current encryption key.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReencryptionProgress {
+	/// Value table entries checked so far.
+	pub scanned_entries: u64,
+	/// Entries rewritten with the current key.
+	pub reencrypted_entries: u64,
+	/// Entries to check, as allocated when the re-encryption started.
+	pub total_entries: u64,
+	/// All entries have been checked.
+	pub done: bool,
+}
+
+/// Space used by the files of a column, see `Db::space_usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceUsage {
+	/// Bytes of value table entries holding values.
+	pub live_bytes: u64,
+	/// Size of the value table files in bytes.
+	pub allocated_bytes: u64,
+	/// Size of the index file in bytes. 0 for btree indexed columns.
+	pub index_bytes: u64,
+	/// Deleted value table entries waiting for reuse.
+	pub free_entries: u64,
+	/// Bytes of value table files that `Db::compact_column` is expected to release.
+	pub reclaimable_bytes: u64,
+}
+
+/// Bytes on disk used by a column, see `Db::column_disk_usage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnDiskUsage {
+	/// Size of the index files, including tables being reindexed. 0 for btree indexed columns.
+	pub index_bytes: u64,
+	/// Size of the value table file of each size tier.
+	pub value_bytes: Vec<u64>,
+	/// Size of the log records of changes to the column that are not yet enacted. The log files
+	/// are shared by all columns.
+	pub log_bytes: u64,
+}
+
+/// State of the write pipeline, see `Db::queue_stats`. Limits are set with
+/// `Options::memory_budget`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+	/// Keys and values of commits waiting to be written to the log, in bytes.
+	pub commit_queue_bytes: u64,
+	/// Limit of `commit_queue_bytes` past which `Db::commit` blocks.
+	pub commit_queue_limit: u64,
+	/// Commits waiting to be written to the log.
+	pub queued_commits: u64,
+	/// Commits queued and not yet enacted into the column tables.
+	pub commits_in_flight: u64,
+	/// Log records written and not yet enacted, in bytes.
+	pub log_queue_bytes: u64,
+	/// Limit of `log_queue_bytes` past which writing commits to the log is paused.
+	pub log_queue_limit: u64,
+	/// Threads blocked in `Db::commit` because the commit queue is full.
+	pub throttled_writers: u64,
+	/// Writing commits to the log is paused because the log queue is full.
+	pub log_throttled: bool,
+}
+
+/// Approximate size of a key range of a btree indexed column, see `Db::estimate_range_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeSize {
+	/// Number of keys.
+	pub keys: u64,
+	/// Size of the keys and values in bytes.
+	pub bytes: u64,
+}
+
+/// Shape of a btree indexed column, see `Db::tree_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeStats {
+	/// Number of levels below the root.
+	pub depth: u32,
+	/// Number of nodes at each level, root first. Empty if the tree is empty.
+	pub nodes: Vec<u64>,
+	/// Number of nodes by the number of keys they hold: `occupancy[n]` nodes hold `n` keys.
+	pub occupancy: Vec<u64>,
+}
+
+/// A btree node, see `Db::tree_nodes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeNode {
+	/// Level of the node, 0 for the root.
+	pub level: u32,
+	/// Address of the node in the value tables.
+	pub address: u64,
+	/// Keys held by the node, in order.
+	pub keys: Vec<Vec<u8>>,
+}
+
+/// How a value of a hash indexed column is stored, see `Db::get_with_meta`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValueMeta {
+	/// Reference count of the value. Always 1 unless the column is `ref_counted`.
+	pub rc: u64,
+	/// Size tier of the value table holding the value.
+	pub size_tier: u8,
+	/// The value is stored compressed.
+	pub compressed: bool,
+	/// Index of the first entry of the value in its value table.
+	pub offset: u64,
+}
+
+/// Occupancy of a value table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableOccupancy {
+	/// Size tier of the table.
+	pub tier: u8,
+	/// Size of a table entry in bytes.
+	pub entry_size: u16,
+	/// Entries in use, including removed entries waiting for reuse.
+	pub filled: u64,
+	/// Entries allocated in the file.
+	pub capacity: u64,
+}
+
 fn read_u32(cursor: &mut Cursor<&[u8]>) -> AtomicU32 {
 	let mut buf = [0u8; 4];
 	cursor.read_exact(&mut buf).expect("Incorrect stats buffer");
@@ -102,8 +306,8 @@ fn value_histogram_index(size: u32) -> Option<usize> {
 	}
 }
 
-impl ColumnStats {
-	pub fn from_slice(data: &[u8]) -> ColumnStats {
+impl ColumnStatCounters {
+	pub fn from_slice(data: &[u8]) -> ColumnStatCounters {
 		let mut cursor = Cursor::new(data);
 		let cursor = &mut cursor;
 
@@ -125,8 +329,10 @@ impl ColumnStats {
 			iter::repeat_with(|| read_i64(cursor)).take(HISTOGRAM_BUCKETS).collect();
 		let reference_increase_hit = read_u64(cursor);
 		let reference_increase_miss = read_u64(cursor);
+		let compressed_values = read_u64(cursor);
+		let below_threshold_values = read_u64(cursor);
 
-		ColumnStats {
+		ColumnStatCounters {
 			value_histogram,
 			query_histogram,
 			oversized,
@@ -143,11 +349,13 @@ impl ColumnStats {
 			queries_miss,
 			uncompressed_bytes,
 			compression_delta,
+			compressed_values,
+			below_threshold_values,
 		}
 	}
 
-	pub fn empty() -> ColumnStats {
-		ColumnStats {
+	pub fn empty() -> ColumnStatCounters {
+		ColumnStatCounters {
 			value_histogram: iter::repeat_with(Default::default).take(HISTOGRAM_BUCKETS).collect(),
 			query_histogram: iter::repeat_with(Default::default).take(SIZE_TIERS).collect(),
 			oversized: Default::default(),
@@ -166,6 +374,8 @@ impl ColumnStats {
 			compression_delta: iter::repeat_with(Default::default)
 				.take(HISTOGRAM_BUCKETS)
 				.collect(),
+			compressed_values: Default::default(),
+			below_threshold_values: Default::default(),
 		}
 	}
 
@@ -193,6 +403,8 @@ impl ColumnStats {
 		for v in &self.compression_delta {
 			v.store(0, Ordering::Relaxed)
 		}
+		self.compressed_values.store(0, Ordering::Relaxed);
+		self.below_threshold_values.store(0, Ordering::Relaxed);
 	}
 
 	pub fn summary(&self) -> ColumnStatSummary {
@@ -203,6 +415,47 @@ impl ColumnStats {
 		}
 	}
 
+	pub fn details(&self, tables: Vec<TableOccupancy>) -> ColumnStats {
+		let buckets = |i: usize| (i << HISTOGRAM_BUCKET_BITS) as u32;
+		ColumnStats {
+			total_values: self.total_values.load(Ordering::Relaxed),
+			total_bytes: self.total_bytes.load(Ordering::Relaxed),
+			uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+			value_histogram: self
+				.value_histogram
+				.iter()
+				.enumerate()
+				.map(|(i, v)| (buckets(i), v.load(Ordering::Relaxed) as u64))
+				.filter(|(_, count)| *count != 0)
+				.collect(),
+			compression_savings: self
+				.compression_delta
+				.iter()
+				.enumerate()
+				.map(|(i, v)| (buckets(i), v.load(Ordering::Relaxed)))
+				.filter(|(_, delta)| *delta != 0)
+				.collect(),
+			compressed_values: self.compressed_values.load(Ordering::Relaxed),
+			below_threshold_values: self.below_threshold_values.load(Ordering::Relaxed),
+			oversized_values: self.oversized.load(Ordering::Relaxed),
+			oversized_bytes: self.oversized_bytes.load(Ordering::Relaxed),
+			commits: self.commits.load(Ordering::Relaxed),
+			inserted_new: self.inserted_new.load(Ordering::Relaxed),
+			inserted_overwrite: self.inserted_overwrite.load(Ordering::Relaxed),
+			reference_increase_hit: self.reference_increase_hit.load(Ordering::Relaxed),
+			reference_increase_miss: self.reference_increase_miss.load(Ordering::Relaxed),
+			removed_hit: self.removed_hit.load(Ordering::Relaxed),
+			removed_miss: self.removed_miss.load(Ordering::Relaxed),
+			query_hits: self.query_histogram.iter().map(|v| v.load(Ordering::Relaxed)).collect(),
+			query_misses: self.queries_miss.load(Ordering::Relaxed),
+			tables,
+			#[cfg(feature = "encryption")]
+			reencryption: None,
+			compaction: None,
+			migration: None,
+		}
+	}
+
 	pub fn to_slice(&self, data: &mut [u8]) {
 		let mut cursor = Cursor::new(data);
 		for item in &self.value_histogram {
@@ -227,6 +480,8 @@ impl ColumnStats {
 		}
 		write_u64(&mut cursor, &self.reference_increase_hit);
 		write_u64(&mut cursor, &self.reference_increase_miss);
+		write_u64(&mut cursor, &self.compressed_values);
+		write_u64(&mut cursor, &self.below_threshold_values);
 	}
 
 	pub fn write_stats_text(&self, writer: &mut impl Write, col: ColId) -> std::io::Result<()> {
@@ -263,6 +518,12 @@ impl ColumnStats {
 			"Uncompressed bytes: {}",
 			self.uncompressed_bytes.load(Ordering::Relaxed)
 		)?;
+		writeln!(writer, "Compressed values: {}", self.compressed_values.load(Ordering::Relaxed))?;
+		writeln!(
+			writer,
+			"Values below compression threshold: {}",
+			self.below_threshold_values.load(Ordering::Relaxed)
+		)?;
 		writeln!(writer, "Compression deltas:")?;
 		for i in 0..HISTOGRAM_BUCKETS {
 			let count = self.value_histogram[i].load(Ordering::Relaxed);
@@ -311,7 +572,26 @@ impl ColumnStats {
 		self.queries_miss.fetch_add(1, Ordering::Relaxed);
 	}
 
-	pub fn insert(&self, size: u32, compressed: u32) {
+	// Counter of values stored like a value of `size` bytes stored in `compressed` bytes, if any.
+	fn compression_counter(
+		&self,
+		size: u32,
+		compressed: u32,
+		threshold: u32,
+	) -> Option<&AtomicU64> {
+		if compressed < size {
+			Some(&self.compressed_values)
+		} else if size <= threshold {
+			Some(&self.below_threshold_values)
+		} else {
+			None
+		}
+	}
+
+	pub fn insert(&self, size: u32, compressed: u32, threshold: u32) {
+		if let Some(counter) = self.compression_counter(size, compressed, threshold) {
+			counter.fetch_add(1, Ordering::Relaxed);
+		}
 		if let Some(index) = value_histogram_index(size) {
 			self.value_histogram[index].fetch_add(1, Ordering::Relaxed);
 			self.compression_delta[index]
@@ -325,7 +605,12 @@ impl ColumnStats {
 		self.uncompressed_bytes.fetch_add(size as u64, Ordering::Relaxed);
 	}
 
-	pub fn remove(&self, size: u32, compressed: u32) {
+	pub fn remove(&self, size: u32, compressed: u32, threshold: u32) {
+		if let Some(counter) = self.compression_counter(size, compressed, threshold) {
+			// Saturate, in case the threshold changed since the value was inserted.
+			let _ = counter
+				.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(1)));
+		}
 		if let Some(index) = value_histogram_index(size) {
 			self.value_histogram[index].fetch_sub(1, Ordering::Relaxed);
 			self.compression_delta[index]
@@ -339,14 +624,14 @@ impl ColumnStats {
 		self.uncompressed_bytes.fetch_sub(size as u64, Ordering::Relaxed);
 	}
 
-	pub fn insert_val(&self, size: u32, compressed: u32) {
+	pub fn insert_val(&self, size: u32, compressed: u32, threshold: u32) {
 		self.inserted_new.fetch_add(1, Ordering::Relaxed);
-		self.insert(size, compressed);
+		self.insert(size, compressed, threshold);
 	}
 
-	pub fn remove_val(&self, size: u32, compressed: u32) {
+	pub fn remove_val(&self, size: u32, compressed: u32, threshold: u32) {
 		self.removed_hit.fetch_add(1, Ordering::Relaxed);
-		self.remove(size, compressed);
+		self.remove(size, compressed, threshold);
 	}
 
 	pub fn reference_increase(&self) {
@@ -361,10 +646,17 @@ impl ColumnStats {
 		self.removed_miss.fetch_add(1, Ordering::Relaxed);
 	}
 
-	pub fn replace_val(&self, old: u32, old_compressed: u32, new: u32, new_compressed: u32) {
+	pub fn replace_val(
+		&self,
+		old: u32,
+		old_compressed: u32,
+		new: u32,
+		new_compressed: u32,
+		threshold: u32,
+	) {
 		self.inserted_overwrite.fetch_add(1, Ordering::Relaxed);
-		self.remove(old, old_compressed);
-		self.insert(new, new_compressed);
+		self.remove(old, old_compressed, threshold);
+		self.insert(new, new_compressed, threshold);
 	}
 
 	pub fn commit(&self) {
@@ -0,0 +1,300 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Pluggable storage of database files.
+//!
+//! By default database files are kept in `Options::path` on the local file system. A `Storage`
+//! set with `Options::storage` replaces the file system, e.g. with `MemoryStorage` or an object
+//! store. Files are identified by their name in the database directory. Index files are kept in
+//! memory in full and written back on flush.
+//!
+//! The crate does not build for wasm32 yet: `lz4`, `memmap2` and the random number generator
+//! still require a native target, so a browser backend can't be plugged in.
+
+use crate::{
+	file::DbFile,
+	parking_lot::{Mutex, RwLock},
+};
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+/// A file of a `Storage`.
+pub trait StorageFile: Send + Sync {
+	/// Read exactly `buf.len()` bytes at `offset`. Fails with `ErrorKind::UnexpectedEof` if the
+	/// file ends before that.
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+	/// Write `buf` at `offset`, extending the file if needed.
+	fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+	/// File size in bytes.
+	fn size(&self) -> io::Result<u64>;
+	/// Truncate the file, or extend it with zeroes.
+	fn set_size(&self, size: u64) -> io::Result<()>;
+	/// Persist all previous writes.
+	fn sync(&self) -> io::Result<()>;
+}
+
+/// Storage of database files in place of the file system, see `Options::storage`.
+///
+/// The database does not take a lock on the storage. Implementations must make sure that it is
+/// not opened by two databases at once.
+pub trait Storage: Send + Sync + std::fmt::Debug {
+	/// Open file `name`. If it does not exist, an empty file is created if `create` is set and
+	/// `None` is returned otherwise.
+	fn open(&self, name: &str, create: bool) -> io::Result<Option<Arc<dyn StorageFile>>>;
+	/// Remove file `name`. Removing a file that does not exist is not an error.
+	fn remove(&self, name: &str) -> io::Result<()>;
+	/// Rename file `from` to `to`, replacing `to` if it exists. Must be atomic.
+	fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+	/// Names of all files.
+	fn list(&self) -> io::Result<Vec<String>>;
+}
+
+/// Storage keeping all files in memory. Used by `Options::in_memory`.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+	files: Mutex<HashMap<String, Arc<MemoryFile>>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile(RwLock<Vec<u8>>);
+
+impl StorageFile for MemoryFile {
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		let data = self.0.read();
+		match data.get(offset as usize..offset as usize + buf.len()) {
+			Some(range) => {
+				buf.copy_from_slice(range);
+				Ok(())
+			},
+			None =>
+				Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+		}
+	}
+
+	fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+		let mut data = self.0.write();
+		let end = offset as usize + buf.len();
+		if data.len() < end {
+			data.resize(end, 0);
+		}
+		data[offset as usize..end].copy_from_slice(buf);
+		Ok(())
+	}
+
+	fn size(&self) -> io::Result<u64> {
+		Ok(self.0.read().len() as u64)
+	}
+
+	fn set_size(&self, size: u64) -> io::Result<()> {
+		self.0.write().resize(size as usize, 0);
+		Ok(())
+	}
+
+	fn sync(&self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Storage for MemoryStorage {
+	fn open(&self, name: &str, create: bool) -> io::Result<Option<Arc<dyn StorageFile>>> {
+		let mut files = self.files.lock();
+		let file = match files.get(name) {
+			Some(file) => file.clone(),
+			None if create => files.entry(name.into()).or_default().clone(),
+			None => return Ok(None),
+		};
+		Ok(Some(file))
+	}
+
+	fn remove(&self, name: &str) -> io::Result<()> {
+		self.files.lock().remove(name);
+		Ok(())
+	}
+
+	fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+		let mut files = self.files.lock();
+		let file = files.remove(from).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+		files.insert(to.into(), file);
+		Ok(())
+	}
+
+	fn list(&self) -> io::Result<Vec<String>> {
+		Ok(self.files.lock().keys().cloned().collect())
+	}
+}
+
+/// Access to database files, on the file system or in `Options::storage`.
+#[derive(Debug, Clone, Default)]
+pub struct Files(Option<Arc<dyn Storage>>);
+
+impl Files {
+	pub fn new(storage: Option<Arc<dyn Storage>>) -> Self {
+		Files(storage)
+	}
+
+	pub fn is_disk(&self) -> bool {
+		self.0.is_none()
+	}
+
+	pub fn storage(&self) -> Option<&Arc<dyn Storage>> {
+		self.0.as_ref()
+	}
+
+	/// Name of the file at `path` in the storage.
+	pub fn name(path: &Path) -> io::Result<&str> {
+		path.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+			io::Error::new(io::ErrorKind::InvalidInput, format!("Bad file name {}", path.display()))
+		})
+	}
+
+	/// Open a file for reading and writing. Returns `None` if it does not exist and `create` is
+	/// not set.
+	pub fn open(&self, path: &Path, create: bool) -> io::Result<Option<DbFile>> {
+		match &self.0 {
+			None => match std::fs::OpenOptions::new()
+				.create(create)
+				.truncate(false)
+				.read(true)
+				.write(true)
+				.open(path)
+			{
+				Ok(file) => Ok(Some(DbFile::Disk(file))),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+				Err(e) => Err(e),
+			},
+			Some(storage) => Ok(storage.open(Self::name(path)?, create)?.map(DbFile::storage)),
+		}
+	}
+
+	pub fn exists(&self, path: &Path) -> io::Result<bool> {
+		match &self.0 {
+			None => Ok(path.exists()),
+			Some(storage) => Ok(storage.open(Self::name(path)?, false)?.is_some()),
+		}
+	}
+
+	pub fn remove(&self, path: &Path) -> io::Result<()> {
+		match &self.0 {
+			None => std::fs::remove_file(path),
+			Some(storage) => storage.remove(Self::name(path)?),
+		}
+	}
+
+	pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		match &self.0 {
+			None => std::fs::rename(from, to),
+			Some(storage) => storage.rename(Self::name(from)?, Self::name(to)?),
+		}
+	}
+
+	/// Names of the files in directory `dir`.
+	pub fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+		match &self.0 {
+			None => {
+				let mut names = Vec::new();
+				for entry in std::fs::read_dir(dir)? {
+					let entry = entry?;
+					let is_file = match entry.metadata() {
+						Ok(metadata) => metadata.is_file(),
+						// Removed since listed.
+						Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+						Err(e) => return Err(e),
+					};
+					if let (true, Some(name)) = (is_file, entry.file_name().to_str()) {
+						names.push(name.into());
+					}
+				}
+				Ok(names)
+			},
+			Some(storage) => storage.list(),
+		}
+	}
+
+	/// Read the whole file. Returns `None` if it does not exist.
+	pub fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+		match &self.0 {
+			None => match std::fs::read(path) {
+				Ok(data) => Ok(Some(data)),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+				Err(e) => Err(e),
+			},
+			Some(storage) => match storage.open(Self::name(path)?, false)? {
+				Some(file) => {
+					let mut data = vec![0; file.size()? as usize];
+					file.read_at(&mut data, 0)?;
+					Ok(Some(data))
+				},
+				None => Ok(None),
+			},
+		}
+	}
+
+	/// Make file creations, renames and removals in directory `dir` durable.
+	pub fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+		if self.is_disk() {
+			std::fs::File::open(dir)?.sync_all()?;
+		}
+		Ok(())
+	}
+
+	/// Replace the contents of the file atomically and durably, by writing and syncing a
+	/// temporary file first, then renaming it and syncing the directory.
+	pub fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+		let mut tmp_path = path.as_os_str().to_owned();
+		tmp_path.push(".tmp");
+		let tmp_path = std::path::PathBuf::from(tmp_path);
+		match &self.0 {
+			None => {
+				use std::io::Write;
+				let mut file = std::fs::File::create(&tmp_path)?;
+				file.write_all(data)?;
+				file.sync_all()?;
+			},
+			Some(storage) => {
+				let file = storage
+					.open(Self::name(&tmp_path)?, true)?
+					.expect("Files are created when requested");
+				file.set_size(0)?;
+				file.write_at(data, 0)?;
+				file.sync()?;
+			},
+		}
+		self.rename(&tmp_path, path)?;
+		match path.parent() {
+			Some(dir) if !dir.as_os_str().is_empty() => self.sync_dir(dir),
+			_ => self.sync_dir(Path::new(".")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn disk_write() {
+		let tmp = tempfile::tempdir().unwrap();
+		let files = Files::default();
+		let path = tmp.path().join("metadata");
+		files.write(&path, b"first").unwrap();
+		files.write(&path, b"second").unwrap();
+		assert_eq!(files.read(&path).unwrap(), Some(b"second".to_vec()));
+		assert!(!tmp.path().join("metadata.tmp").exists());
+	}
+
+	#[test]
+	fn memory_storage() {
+		let storage = MemoryStorage::default();
+		assert!(storage.open("a", false).unwrap().is_none());
+		let file = storage.open("a", true).unwrap().unwrap();
+		file.write_at(b"hello", 2).unwrap();
+		assert_eq!(file.size().unwrap(), 7);
+		let mut buf = [0; 3];
+		storage.open("a", false).unwrap().unwrap().read_at(&mut buf, 4).unwrap();
+		assert_eq!(&buf, b"llo");
+		assert!(file.read_at(&mut buf, 5).is_err());
+		storage.rename("a", "b").unwrap();
+		assert_eq!(storage.list().unwrap(), vec!["b".to_string()]);
+		storage.remove("b").unwrap();
+		assert!(storage.list().unwrap().is_empty());
+	}
+}
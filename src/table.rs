@@ -5,9 +5,11 @@
 // All numerical values are little endian.
 //
 // Entry 0 (metadata)
-// [LAST_REMOVED: 8][FILLED: 8]
+// [LAST_REMOVED: 8][FILLED: 8][COUNT: 8]
 // LAST_REMOVED - 64-bit index of removed entries linked list head
 // FILLED - highest index filled with live data
+// COUNT - 64-bit number of keys in the column. Only in the first table of columns with
+// `count_keys`.
 //
 // Complete entry:
 // [SIZE: 2][REFS: 4][KEY: 26][VALUE]
@@ -43,19 +45,35 @@
 // [TOMBSTONE: 2][NEXT: 8]
 // TOMBSTONE - Deleted entry marker. 0xffff
 // NEXT - 64-bit index of the next deleted entry.
+//
+// Run of deleted entries
+// [FREE_RUN: 2][NEXT: 8][LEN: 8]
+// FREE_RUN - Marker of LEN consecutive deleted entries starting with this one. 0xfffc
+// NEXT - 64-bit index of the next deleted entry.
+// The other entries of the run hold no data. Their space is released with `punch_holes`.
+//
+// Blob entry (multipart table only, see `ColumnOptions::blob_threshold`)
+// [BLOB: 2][ID: 8][REFS: 4][KEY: 26][LEN: 8]
+// BLOB - Blob marker. 0xfffb, or 0x7ffb if the value is compressed.
+// ID - 64-bit id of the file holding the value, see `BlobStore`.
+// REF: 32-bit reference counter (optional).
+// KEY: lower 26 bytes of the key.
+// LEN: 64-bit size of the value in the file.
 
 use crate::{
+	blob::{BlobStore, BlobWriter},
 	column::ColId,
 	display::hex,
-	error::{try_io, Result},
+	error::Result,
+	file::TableIo,
 	log::{LogQuery, LogReader, LogWriter},
 	options::ColumnOptions as Options,
 	parking_lot::RwLock,
+	stats::{SpaceUsage, TableOccupancy},
 	table::key::{TableKey, TableKeyQuery, PARTIAL_SIZE},
 };
 use std::{
 	convert::TryInto,
-	io::Read,
 	mem::MaybeUninit,
 	sync::{
 		atomic::{AtomicBool, AtomicU64, Ordering},
@@ -63,24 +81,41 @@ use std::{
 	},
 };
 
+/// Entries in a value table file segment, see `ColumnOptions::max_file_size`.
+pub fn segment_entries(max_file_size: Option<u64>, entry_size: u16) -> u64 {
+	max_file_size.map_or(u64::MAX, |max| (max / entry_size as u64).max(1))
+}
+
 pub const SIZE_TIERS: usize = 1usize << SIZE_TIERS_BITS;
 pub const SIZE_TIERS_BITS: u8 = 8;
 pub const COMPRESSED_MASK: u16 = 0x80_00;
 pub const MAX_ENTRY_SIZE: usize = 0x7ff8; // Actual max size in V4 was 0x7dfe
 pub const MIN_ENTRY_SIZE: usize = 32;
 const REFS_SIZE: usize = 4;
+const WIDE_REFS_SIZE: usize = 8;
+const CHECKSUM_SIZE: usize = 4;
 const SIZE_SIZE: usize = 2;
 const INDEX_SIZE: usize = 8;
 const MAX_ENTRY_BUF_SIZE: usize = 0x8000;
+// Entry size of the table with multipart values.
+pub const MULTIPART_ENTRY_SIZE: u16 = 4096;
+// Alignment of released file ranges.
+const HOLE_ALIGN: u64 = 4096;
+// Size of the blocks read when scanning a table for deleted entries.
+const COMPACT_SCAN_BYTES: u64 = 1024 * 1024;
 
 const TOMBSTONE: &[u8] = &[0xff, 0xff];
+const FREE_RUN: &[u8] = &[0xfc, 0xff];
 const MULTIPART_V4: &[u8] = &[0xff, 0xfe];
 const MULTIHEAD_V4: &[u8] = &[0xff, 0xfd];
 const MULTIPART: &[u8] = &[0xfe, 0xff];
 const MULTIHEAD: &[u8] = &[0xfd, 0xff];
 const MULTIHEAD_COMPRESSED: &[u8] = &[0xfd, 0x7f];
+const BLOB: &[u8] = &[0xfb, 0xff];
+const BLOB_COMPRESSED: &[u8] = &[0xfb, 0x7f];
 // When a rc reach locked ref, it is locked in db.
-const LOCKED_REF: u32 = u32::MAX;
+const LOCKED_REF: u64 = u32::MAX as u64;
+const WIDE_LOCKED_REF: u64 = u64::MAX;
 
 pub type Value = Vec<u8>;
 
@@ -108,6 +143,15 @@ impl TableId {
 		format!("table_{:02}_{}", self.col(), hex(&[self.size_tier()]))
 	}
 
+	/// Name of segment `segment` of the table file, see `ColumnOptions::max_file_size`. The
+	/// first segment is named after the table.
+	pub fn segment_file_name(&self, segment: u64) -> String {
+		match segment {
+			0 => self.file_name(),
+			segment => format!("{}.{}", self.file_name(), segment),
+		}
+	}
+
 	pub fn is_file_name(col: ColId, name: &str) -> bool {
 		name.starts_with(&format!("table_{:02}_", col))
 	}
@@ -131,13 +175,27 @@ pub struct ValueTable {
 	filled: AtomicU64,
 	last_removed: AtomicU64,
 	dirty_header: AtomicBool,
+	// Number of keys in the column, first table of columns with `count_keys` only.
+	count: Option<AtomicU64>,
 	multipart: bool,
+	// Values above `ColumnOptions::blob_threshold`, multipart table only.
+	blobs: Option<BlobStore>,
 	ref_counted: bool,
+	// Reference counts are stored in 64 bits, see `ColumnOptions::wide_ref_counts`.
+	wide_rc: bool,
+	checksums: bool,
+	#[cfg(feature = "encryption")]
+	encryption: Option<crate::encryption::Encryption>,
+	#[cfg(feature = "encryption")]
+	btree_index: bool,
 	db_version: u32,
 }
 
 #[derive(Default, Clone, Copy)]
-struct Header([u8; 16]);
+struct Header([u8; 24]);
+
+const HEADER_SIZE: usize = 16;
+const COUNTED_HEADER_SIZE: usize = 24;
 
 impl Header {
 	fn last_removed(&self) -> u64 {
@@ -152,6 +210,20 @@ impl Header {
 	fn set_filled(&mut self, filled: u64) {
 		self.0[INDEX_SIZE..INDEX_SIZE * 2].copy_from_slice(&filled.to_le_bytes());
 	}
+	fn count(&self) -> u64 {
+		u64::from_le_bytes(self.0[INDEX_SIZE * 2..INDEX_SIZE * 3].try_into().unwrap())
+	}
+	fn set_count(&mut self, count: u64) {
+		self.0[INDEX_SIZE * 2..INDEX_SIZE * 3].copy_from_slice(&count.to_le_bytes());
+	}
+}
+
+fn header_size(counted: bool) -> usize {
+	if counted {
+		COUNTED_HEADER_SIZE
+	} else {
+		HEADER_SIZE
+	}
 }
 
 pub struct Entry<B: AsRef<[u8]> + AsMut<[u8]>>(usize, B);
@@ -160,7 +232,9 @@ pub type FullEntry = Entry<Vec<u8>>;
 #[cfg(not(feature = "loom"))]
 pub type FullEntry = Entry<[u8; MAX_ENTRY_BUF_SIZE]>;
 type PartialEntry = Entry<[u8; 10]>;
+type FreeEntry = Entry<[u8; 18]>;
 type PartialKeyEntry = Entry<[u8; 40]>; // 2 + 4 + 26 + 8
+type BlobEntry = Entry<[u8; 48]>; // 2 + 8 + 4 + 26 + 8
 
 impl<const C: usize> Entry<[u8; C]> {
 	#[inline(always)]
@@ -218,6 +292,25 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 		self.write_slice(TOMBSTONE);
 	}
 
+	fn is_free_run(&self) -> bool {
+		&self.1.as_ref()[0..SIZE_SIZE] == FREE_RUN
+	}
+
+	fn write_free_run(&mut self) {
+		self.write_slice(FREE_RUN);
+	}
+
+	// Size of a deleted entry, `None` if the entry is not deleted.
+	fn free_entry_size(&self) -> Option<usize> {
+		if self.is_tombstone() {
+			Some(SIZE_SIZE + INDEX_SIZE)
+		} else if self.is_free_run() {
+			Some(SIZE_SIZE + INDEX_SIZE * 2)
+		} else {
+			None
+		}
+	}
+
 	fn is_multipart(&self) -> bool {
 		&self.1.as_ref()[0..SIZE_SIZE] == MULTIPART
 	}
@@ -250,6 +343,22 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 		self.write_slice(MULTIHEAD_COMPRESSED);
 	}
 
+	fn is_blob_compressed(&self) -> bool {
+		&self.1.as_ref()[0..SIZE_SIZE] == BLOB_COMPRESSED
+	}
+
+	fn is_blob(&self) -> bool {
+		self.is_blob_compressed() || &self.1.as_ref()[0..SIZE_SIZE] == BLOB
+	}
+
+	fn write_blob(&mut self) {
+		self.write_slice(BLOB);
+	}
+
+	fn write_blob_compressed(&mut self) {
+		self.write_slice(BLOB_COMPRESSED);
+	}
+
 	fn is_multi(&self, db_version: u32) -> bool {
 		self.is_multipart() ||
 			self.is_multihead() ||
@@ -305,12 +414,20 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 		self.write_slice(&next_index.to_le_bytes());
 	}
 
-	fn read_rc(&mut self) -> u32 {
-		self.read_u32()
+	fn read_rc(&mut self, wide: bool) -> u64 {
+		if wide {
+			self.read_u64()
+		} else {
+			self.read_u32() as u64
+		}
 	}
 
-	fn write_rc(&mut self, rc: u32) {
-		self.write_slice(&rc.to_le_bytes());
+	fn write_rc(&mut self, rc: u64, wide: bool) {
+		if wide {
+			self.write_u64(rc);
+		} else {
+			self.write_u32(rc as u32);
+		}
 	}
 
 	fn read_partial(&mut self) -> &[u8] {
@@ -353,32 +470,48 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> std::ops::IndexMut<std::ops::Range<usize>> fo
 }
 
 impl ValueTable {
+	/// Close the table file and return the paths of its segments and blobs.
+	pub fn close(self) -> Result<Vec<std::path::PathBuf>> {
+		let blobs = match &self.blobs {
+			Some(blobs) => blobs.paths()?,
+			None => Vec::new(),
+		};
+		Ok(self.file.close().into_iter().chain(blobs).collect())
+	}
+
 	pub fn open(
 		path: Arc<std::path::PathBuf>,
 		id: TableId,
 		entry_size: Option<u16>,
 		options: &Options,
 		db_version: u32,
+		io: TableIo,
 	) -> Result<ValueTable> {
 		let (multipart, entry_size) = match entry_size {
 			Some(s) => (false, s),
-			None => (true, 4096),
+			None => (true, MULTIPART_ENTRY_SIZE),
 		};
 		assert!(entry_size >= MIN_ENTRY_SIZE as u16);
 		if db_version >= 4 {
 			assert!(entry_size <= MAX_ENTRY_SIZE as u16);
 		}
 
-		let mut filepath: std::path::PathBuf = std::path::PathBuf::clone(&*path);
-		filepath.push(id.file_name());
-		let file = crate::file::TableFile::open(filepath, entry_size, id)?;
+		let blobs = match options.blob_threshold {
+			Some(threshold) if multipart =>
+				Some(BlobStore::new(path.to_path_buf(), id, threshold, io.files.clone())),
+			_ => None,
+		};
+		let file = crate::file::TableFile::open(&path, entry_size, id, io)?;
+		let counted = options.count_keys && id.size_tier() == 0;
 		let mut filled = 1;
 		let mut last_removed = 0;
-		if let Some(file) = &mut *file.file.write() {
+		let mut count = 0;
+		if file.is_init() {
 			let mut header = Header::default();
-			try_io!(file.read_exact(&mut header.0));
+			file.read_at(&mut header.0[..header_size(counted)], 0)?;
 			last_removed = header.last_removed();
 			filled = header.filled();
+			count = header.count();
 			if filled == 0 {
 				filled = 1;
 			}
@@ -392,20 +525,27 @@ impl ValueTable {
 			filled: AtomicU64::new(filled),
 			last_removed: AtomicU64::new(last_removed),
 			dirty_header: AtomicBool::new(false),
+			count: counted.then(|| AtomicU64::new(count)),
 			multipart,
+			blobs,
 			ref_counted: options.ref_counted,
+			wide_rc: options.ref_counted && options.wide_ref_counts,
+			checksums: options.value_checksums,
+			#[cfg(feature = "encryption")]
+			encryption: options.encryption.clone(),
+			#[cfg(feature = "encryption")]
+			btree_index: options.btree_index,
 			db_version,
 		})
 	}
 
 	pub fn value_size(&self, key: &TableKey) -> Option<u16> {
-		let base = self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16;
-		let k_encoded = key.encoded_size() as u16;
-		if base < k_encoded {
-			None
-		} else {
-			Some(base - k_encoded)
-		}
+		let overhead = SIZE_SIZE +
+			self.ref_size() +
+			self.checksum_size() +
+			self.encryption_size() +
+			key.encoded_size();
+		(self.entry_size as usize).checked_sub(overhead).map(|size| size as u16)
 	}
 
 	// Return ref counter, partial key and if the value is compressed.
@@ -414,17 +554,22 @@ impl ValueTable {
 		&self,
 		key: &mut TableKeyQuery,
 		mut index: u64,
+		mut head: Option<&[u8]>,
 		log: &impl LogQuery,
 		mut f: impl FnMut(&[u8]) -> bool,
-	) -> Result<(u32, bool)> {
+	) -> Result<(u64, bool)> {
 		let mut buf = FullEntry::new_uninit_full_entry();
 		let mut part = 0;
 		let mut compressed = false;
 		let mut rc = 1;
 		let entry_size = self.entry_size as usize;
 		loop {
+			let prefetched = head.take();
 			let buf = if log.value(self.id, index, buf.as_mut()) {
 				&mut buf
+			} else if let Some(prefetched) = prefetched {
+				buf[0..entry_size].copy_from_slice(prefetched);
+				&mut buf
 			} else {
 				log::trace!(
 					target: "parity-db",
@@ -438,11 +583,17 @@ impl ValueTable {
 
 			buf.set_offset(0);
 
-			if buf.is_tombstone() {
+			if buf.free_entry_size().is_some() {
 				return Ok((0, false))
 			}
 
-			let (entry_end, next) = if self.multipart && buf.is_multi(self.db_version) {
+			let mut blob = None;
+			let (entry_end, next) = if self.is_blob(buf) {
+				compressed = buf.is_blob_compressed();
+				buf.skip_size();
+				blob = Some(buf.read_u64());
+				(self.blob_entry_size(), 0)
+			} else if self.multipart && buf.is_multi(self.db_version) {
 				if part == 0 && self.db_version > 6 && buf.is_multihead_compressed() {
 					compressed = true;
 				}
@@ -459,7 +610,7 @@ impl ValueTable {
 
 			if part == 0 {
 				if self.ref_counted {
-					rc = buf.read_rc();
+					rc = buf.read_rc(self.wide_rc);
 				}
 				match key {
 					TableKeyQuery::Fetch(Some(to_fetch)) => {
@@ -485,10 +636,25 @@ impl ValueTable {
 			}
 
 			if buf.offset() > entry_end {
-				return Err(crate::error::Error::Corruption(format!(
-					"Unexpected entry size. Expected at least {} bytes",
-					buf.offset() - 2
-				)))
+				return Err(crate::error::Error::ValueTableCorruption {
+					col: self.id.col(),
+					tier: self.id.size_tier(),
+					reason: format!(
+						"Unexpected entry size. Expected at least {} bytes",
+						buf.offset() - 2
+					),
+				})
+			}
+
+			if let (Some(id), Some(blobs)) = (blob, &self.blobs) {
+				let len = buf.read_u64();
+				match blobs.read(id, len)? {
+					Some(value) => {
+						f(&value);
+						break
+					},
+					None => return Ok((0, false)),
+				}
 			}
 
 			if !f(buf.remaining_to(entry_end)) {
@@ -524,14 +690,25 @@ impl ValueTable {
 		key: &mut TableKeyQuery,
 		index: u64,
 		log: &impl LogQuery,
-	) -> Result<Option<(Value, bool, u32)>> {
+	) -> Result<Option<(Value, bool, u64)>> {
+		self.query_with_head(key, index, None, log)
+	}
+
+	/// Same as `query`, with the first entry already read by `read_heads`.
+	pub fn query_with_head(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		head: Option<&[u8]>,
+		log: &impl LogQuery,
+	) -> Result<Option<(Value, bool, u64)>> {
 		let mut result = Vec::new();
-		let (rc, compressed) = self.for_parts(key, index, log, |buf| {
+		let (rc, compressed) = self.for_parts(key, index, head, log, |buf| {
 			result.extend_from_slice(buf);
 			true
 		})?;
 		if rc > 0 {
-			return Ok(Some((result, compressed, rc)))
+			return Ok(Some((self.decode_value(index, result)?, compressed, rc)))
 		}
 		Ok(None)
 	}
@@ -541,7 +718,7 @@ impl ValueTable {
 		&self,
 		index: u64,
 		log: &impl LogQuery,
-	) -> Result<Option<(Value, u32, [u8; PARTIAL_SIZE], bool)>> {
+	) -> Result<Option<(Value, u64, [u8; PARTIAL_SIZE], bool)>> {
 		let mut query_key = Default::default();
 		if let Some((value, compressed, rc)) =
 			self.query(&mut TableKeyQuery::Fetch(Some(&mut query_key)), index, log)?
@@ -551,6 +728,66 @@ impl ValueTable {
 		Ok(None)
 	}
 
+	/// Open the file of the blob value at `index` for reading, see `BlobStore`. Returns the
+	/// file, the size of the value in it and whether the value is compressed. `None` if there is
+	/// no blob with `key` at `index`, or if values are stored with a checksum, which can only be
+	/// checked by reading the whole value.
+	pub fn open_blob(
+		&self,
+		key: &TableKey,
+		index: u64,
+		log: &impl LogQuery,
+	) -> Result<Option<(crate::file::DbFile, u64, bool)>> {
+		let blobs = match &self.blobs {
+			Some(blobs) if !self.checksums => blobs,
+			_ => return Ok(None),
+		};
+		let mut buf = BlobEntry::new_uninit();
+		if !log.value(self.id, index, buf.as_mut()) {
+			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+		}
+		if !buf.is_blob() {
+			return Ok(None)
+		}
+		let compressed = buf.is_blob_compressed();
+		buf.skip_size();
+		let id = buf.read_u64();
+		if self.ref_counted && buf.read_rc(self.wide_rc) == 0 {
+			return Ok(None)
+		}
+		match key {
+			TableKey::Partial(k) if buf.read_partial() == key::partial_key(k) => (),
+			_ => return Ok(None),
+		}
+		let len = buf.read_u64();
+		Ok(blobs.open(id)?.map(|file| (file, len, compressed)))
+	}
+
+	/// Release cached pages of the table file.
+	pub fn drop_cache(&self) -> Result<()> {
+		self.file.drop_cache()
+	}
+
+	/// Read the first entry at each of `indexes` in one batch. Entries that are in the log
+	/// overlay are not read and are returned empty.
+	pub fn read_heads(&self, indexes: &[u64], log: &impl LogQuery) -> Result<Vec<Vec<u8>>> {
+		let entry_size = self.entry_size as usize;
+		let mut heads = vec![Vec::new(); indexes.len()];
+		if !self.file.is_init() {
+			return Ok(heads)
+		}
+		let mut buf = PartialEntry::new_uninit();
+		let mut reqs = Vec::with_capacity(indexes.len());
+		for (head, index) in heads.iter_mut().zip(indexes) {
+			if !log.value(self.id, *index, buf.as_mut()) {
+				head.resize(entry_size, 0);
+				reqs.push((*index * entry_size as u64, head.as_mut_slice()));
+			}
+		}
+		self.file.read_batch(&mut reqs)?;
+		Ok(heads)
+	}
+
 	pub fn size(
 		&self,
 		key: &TableKey,
@@ -559,17 +796,18 @@ impl ValueTable {
 	) -> Result<Option<(u32, bool)>> {
 		let mut result = 0;
 		let (rc, compressed) =
-			self.for_parts(&mut TableKeyQuery::Check(key), index, log, |buf| {
+			self.for_parts(&mut TableKeyQuery::Check(key), index, None, log, |buf| {
 				result += buf.len() as u32;
 				true
 			})?;
 		if rc > 0 {
-			return Ok(Some((result, compressed)))
+			let overhead = self.checksum_size() + self.encryption_size();
+			return Ok(Some((result.saturating_sub(overhead as u32), compressed)))
 		}
 		Ok(None)
 	}
 
-	pub fn has_key_at(&self, index: u64, key: &TableKey, log: &LogWriter) -> Result<bool> {
+	pub fn has_key_at(&self, index: u64, key: &TableKey, log: &impl LogQuery) -> Result<bool> {
 		match key {
 			TableKey::Partial(k) => Ok(match self.partial_key_at(index, log)? {
 				Some(existing_key) => &existing_key[..] == key::partial_key(k),
@@ -584,12 +822,25 @@ impl ValueTable {
 		index: u64,
 		log: &impl LogQuery,
 	) -> Result<Option<[u8; PARTIAL_SIZE]>> {
-		let mut query_key = Default::default();
-		let (rc, _compressed) =
-			self.for_parts(&mut TableKeyQuery::Fetch(Some(&mut query_key)), index, log, |_buf| {
-				false
-			})?;
-		Ok(if rc == 0 { None } else { Some(query_key) })
+		// Only the entry header is read: size, next part, ref counter and partial key.
+		let mut buf = PartialKeyEntry::new_uninit();
+		let len = buf.as_mut().len().min(self.entry_size as usize);
+		if !log.value(self.id, index, &mut buf.as_mut()[..len]) {
+			self.file.read_at(&mut buf.as_mut()[..len], index * self.entry_size as u64)?;
+		}
+		if buf.free_entry_size().is_some() {
+			return Ok(None)
+		}
+		buf.skip_size();
+		if self.multipart && buf.is_multi(self.db_version) || self.is_blob(&buf) {
+			buf.skip_next();
+		}
+		if self.ref_counted && buf.read_rc(self.wide_rc) == 0 {
+			return Ok(None)
+		}
+		let mut query_key = [0u8; PARTIAL_SIZE];
+		query_key.copy_from_slice(buf.read_partial());
+		Ok(Some(query_key))
 	}
 
 	pub fn is_tombstone(&self, index: u64, log: &impl LogQuery) -> Result<bool> {
@@ -600,16 +851,20 @@ impl ValueTable {
 			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
 			&mut buf
 		};
-		Ok(buf.is_tombstone())
+		Ok(buf.free_entry_size().is_some())
 	}
 
-	pub fn read_next_free(&self, index: u64, log: &LogWriter) -> Result<u64> {
-		let mut buf = PartialEntry::new_uninit();
+	// Read a deleted entry. Returns the next deleted entry and the number of consecutive
+	// deleted entries starting at `index`.
+	fn read_free_entry(&self, index: u64, log: &impl LogQuery) -> Result<(u64, u64)> {
+		let mut buf = FreeEntry::new_uninit();
 		if !log.value(self.id, index, buf.as_mut()) {
 			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
 		}
+		let run = buf.is_free_run();
 		buf.skip_size();
-		Ok(buf.read_next())
+		let next = buf.read_next();
+		Ok((next, if run { buf.read_u64() } else { 1 }))
 	}
 
 	pub fn read_next_part(&self, index: u64, log: &LogWriter) -> Result<Option<u64>> {
@@ -629,7 +884,14 @@ impl ValueTable {
 		let filled = self.filled.load(Ordering::Relaxed);
 		let last_removed = self.last_removed.load(Ordering::Relaxed);
 		let index = if last_removed != 0 {
-			let next_removed = self.read_next_free(last_removed, log)?;
+			let (mut next_removed, run) = self.read_free_entry(last_removed, log)?;
+			if run > 1 {
+				// The rest of the run is the next deleted entry.
+				let mut buf = FreeEntry::new_uninit();
+				Self::write_free_entry(&mut buf, next_removed, run - 1);
+				next_removed = last_removed + 1;
+				log.insert_value(self.id, next_removed, buf[0..buf.offset()].to_vec());
+			}
 			log::trace!(
 				target: "parity-db",
 				"{}: Inserting into removed slot {}",
@@ -652,6 +914,194 @@ impl ValueTable {
 		Ok(index)
 	}
 
+	fn write_free_entry(buf: &mut FreeEntry, next: u64, run: u64) {
+		if run > 1 {
+			buf.write_free_run();
+			buf.write_next(next);
+			buf.write_u64(run);
+		} else {
+			buf.write_tombstone();
+			buf.write_next(next);
+		}
+	}
+
+	/// Rebuild the list of deleted entries, joining consecutive deleted entries that span whole
+	/// pages into runs. Returns the file ranges of the runs, as `(offset, len)`, that may be
+	/// released with `punch_holes` once the plan is enacted. Nothing is written if there are no
+	/// such runs.
+	pub fn write_free_runs_plan(&self, log: &mut LogWriter) -> Result<Vec<(u64, u64)>> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let mut free = Vec::new();
+		let mut index = self.last_removed.load(Ordering::Relaxed);
+		while index != 0 {
+			if free.len() as u64 >= filled {
+				return Err(crate::error::Error::ValueTableCorruption {
+					col: self.id.col(),
+					tier: self.id.size_tier(),
+					reason: "Loop in the list of deleted entries".into(),
+				})
+			}
+			let (next, run) = self.read_free_entry(index, log)?;
+			free.extend(index..index + run);
+			index = next;
+		}
+		free.sort_unstable();
+
+		let entry_size = self.entry_size as u64;
+		let mut entries = Vec::new();
+		let mut runs = Vec::new();
+		let mut holes = Vec::new();
+		for run in free.chunk_by(|a, b| a + 1 == *b) {
+			let (start, end) = (run[0], run[run.len() - 1] + 1);
+			let from = (start * entry_size + (SIZE_SIZE + INDEX_SIZE * 2) as u64)
+				.next_multiple_of(HOLE_ALIGN);
+			let to = end * entry_size / HOLE_ALIGN * HOLE_ALIGN;
+			if to > from {
+				runs.push((start, end - start));
+				holes.push((from, to - from));
+			} else {
+				entries.extend(run.iter().map(|index| (*index, 1)));
+			}
+		}
+		if runs.is_empty() {
+			return Ok(holes)
+		}
+		// Single entries are reused first, so that released space stays released longer.
+		entries.extend(runs);
+		self.write_free_list(&entries, log);
+		log::debug!(
+			target: "parity-db",
+			"{}: Joined deleted entries into {} runs",
+			self.id,
+			holes.len(),
+		);
+		Ok(holes)
+	}
+
+	// Replace the list of deleted entries with `entries`, as `(index, run length)`, in order.
+	fn write_free_list(&self, entries: &[(u64, u64)], log: &mut LogWriter) {
+		for (i, (index, run)) in entries.iter().enumerate() {
+			let next = entries.get(i + 1).map_or(0, |(next, _)| *next);
+			let mut buf = FreeEntry::new_uninit();
+			Self::write_free_entry(&mut buf, next, *run);
+			log.insert_value(self.id, *index, buf[0..buf.offset()].to_vec());
+		}
+		self.last_removed
+			.store(entries.first().map_or(0, |(index, _)| *index), Ordering::Relaxed);
+		self.dirty_header.store(true, Ordering::Relaxed);
+	}
+
+	/// Rebuild the list of deleted entries from a scan of the table, in ascending order, so
+	/// that new values are written to the lowest deleted entries. Deleted entries at the end of
+	/// the table are dropped. Returns the end of the table if it held the same values without
+	/// deleted entries.
+	pub fn write_compact_plan(&self, log: &mut LogWriter) -> Result<u64> {
+		if !self.is_init() {
+			return Ok(1)
+		}
+		let mut filled = self.filled.load(Ordering::Relaxed);
+		let entry_size = self.entry_size as u64;
+		let block_entries = COMPACT_SCAN_BYTES / entry_size;
+		let mut block = vec![0; (block_entries * entry_size) as usize];
+		let (mut block_start, mut block_end) = (0, 0);
+		let mut free: Vec<(u64, u64)> = Vec::new();
+		let mut index = 1;
+		while index < filled {
+			if index >= block_end {
+				block_start = index;
+				block_end = filled.min(index + block_entries);
+				let len = ((block_end - index) * entry_size) as usize;
+				self.file.read_at(&mut block[..len], index * entry_size)?;
+			}
+			let mut buf = FreeEntry::new_uninit();
+			if !log.value(self.id, index, buf.as_mut()) {
+				let at = ((index - block_start) * entry_size) as usize;
+				let len = buf.as_mut().len();
+				buf.as_mut().copy_from_slice(&block[at..at + len]);
+			}
+			let run = match buf.free_entry_size() {
+				Some(_) if buf.is_free_run() => {
+					buf.set_offset(SIZE_SIZE + INDEX_SIZE);
+					buf.read_u64()
+				},
+				Some(_) => 1,
+				None => {
+					index += 1;
+					continue
+				},
+			};
+			match free.last_mut() {
+				Some((start, len)) if *start + *len == index => *len += run,
+				_ => free.push((index, run)),
+			}
+			index += run;
+		}
+		if let Some((start, len)) = free.last() {
+			if start + len >= filled {
+				filled = *start;
+				free.pop();
+			}
+		}
+		let deleted: u64 = free.iter().map(|(_, len)| len).sum();
+		log::debug!(
+			target: "parity-db",
+			"{}: Compacting {} entries, {} deleted",
+			self.id,
+			filled - 1,
+			deleted,
+		);
+		self.write_free_list(&free, log);
+		self.filled.store(filled, Ordering::Relaxed);
+		Ok(filled - deleted)
+	}
+
+	/// Move the value at `index` to the first deleted entry, if that is lower. The old entries
+	/// are deleted without adding them to the list of deleted entries, so that the list stays
+	/// in the order set by `write_compact_plan`, which adds them back. Returns the new index.
+	pub fn write_relocate_plan(
+		&self,
+		index: u64,
+		key: &TableKey,
+		log: &mut LogWriter,
+	) -> Result<Option<u64>> {
+		let first_free = self.last_removed.load(Ordering::Relaxed);
+		if first_free == 0 || first_free >= index {
+			return Ok(None)
+		}
+		let (value, compressed, rc) =
+			match self.query(&mut TableKeyQuery::Check(key), index, log)? {
+				Some(value) => value,
+				None => return Ok(None),
+			};
+		let new_index = self.overwrite_chain(key, &value, log, None, compressed, rc)?;
+		log::trace!(target: "parity-db", "{}: Moved slot {} to {}", self.id, index, new_index);
+		let mut index = index;
+		loop {
+			let next = self.read_next_part(index, log)?;
+			let mut buf = PartialEntry::new_uninit();
+			buf.write_tombstone();
+			buf.write_next(0);
+			log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
+			match next {
+				Some(next) => index = next,
+				None => return Ok(Some(new_index)),
+			}
+		}
+	}
+
+	/// Truncate the file after the last entry in use. Returns the number of bytes released.
+	pub fn shrink_file(&self) -> Result<u64> {
+		self.file.shrink(self.filled.load(Ordering::Relaxed), self.entry_size)
+	}
+
+	/// Release file ranges returned by `write_free_runs_plan` to the file system.
+	pub fn punch_holes(&self, holes: &[(u64, u64)]) -> Result<()> {
+		for (offset, len) in holes {
+			self.file.punch_hole(*offset, *len)?;
+		}
+		Ok(())
+	}
+
 	fn overwrite_chain(
 		&self,
 		key: &TableKey,
@@ -659,15 +1109,43 @@ impl ValueTable {
 		log: &mut LogWriter,
 		at: Option<u64>,
 		compressed: bool,
+		rc: u64,
 	) -> Result<u64> {
-		let mut remainder = value.len() + self.ref_size() + key.encoded_size();
-		let mut offset = 0;
-		let mut start = 0;
-		assert!(self.multipart || value.len() <= self.value_size(key).unwrap() as usize);
 		let (mut index, mut follow) = match at {
 			Some(index) => (index, true),
 			None => (self.next_free(log)?, false),
 		};
+		#[cfg(feature = "encryption")]
+		let encrypted;
+		#[cfg(feature = "encryption")]
+		let value = match self.encryption_at(index) {
+			Some(encryption) => {
+				encrypted = encryption.encrypt(value, self.id.col(), self.id.size_tier(), index)?;
+				encrypted.as_slice()
+			},
+			None => value,
+		};
+		let with_checksum;
+		let value = if self.checksums {
+			with_checksum = [value, &crc32fast::hash(value).to_le_bytes()].concat();
+			with_checksum.as_slice()
+		} else {
+			value
+		};
+		if let Some(blobs) = self.blobs.as_ref().filter(|b| value.len() > b.threshold) {
+			if follow {
+				if let Some(next) = self.read_next_part(index, log)? {
+					self.clear_chain(next, log)?;
+				}
+			}
+			let id = blobs.write(value)?;
+			self.write_blob_entry(index, key, id, value.len() as u64, compressed, rc, log);
+			return Ok(index)
+		}
+		let mut remainder = value.len() + self.ref_size() + key.encoded_size();
+		assert!(self.multipart || remainder + SIZE_SIZE <= self.entry_size as usize);
+		let mut offset = 0;
+		let mut start = 0;
 		loop {
 			let mut next_index = 0;
 			if follow {
@@ -713,7 +1191,7 @@ impl ValueTable {
 			if offset == 0 {
 				if self.ref_counted {
 					// first rc.
-					buf.write_rc(1u32);
+					buf.write_rc(rc, self.wide_rc);
 				}
 				key.write(&mut buf);
 			}
@@ -738,6 +1216,73 @@ impl ValueTable {
 		Ok(start)
 	}
 
+	#[allow(clippy::too_many_arguments)]
+	fn write_blob_entry(
+		&self,
+		index: u64,
+		key: &TableKey,
+		id: u64,
+		len: u64,
+		compressed: bool,
+		rc: u64,
+		log: &mut LogWriter,
+	) {
+		log::trace!(target: "parity-db", "{}: Writing blob {:016x} to slot {}: {}", self.id, id, index, key);
+		let mut buf = FullEntry::new_uninit_full_entry();
+		if compressed {
+			buf.write_blob_compressed();
+		} else {
+			buf.write_blob();
+		}
+		buf.write_u64(id);
+		if self.ref_counted {
+			buf.write_rc(rc, self.wide_rc);
+		}
+		key.write(&mut buf);
+		buf.write_u64(len);
+		log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
+	}
+
+	/// Create a blob to be written in pieces and then referenced with `write_blob_plan`.
+	/// Returns `None` if the table does not store blobs.
+	pub fn create_blob_writer(&self) -> Result<Option<BlobWriter>> {
+		match &self.blobs {
+			Some(blobs) => Ok(Some(blobs.create_writer(self.checksums)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Remove a blob created with `create_blob_writer` that will not be referenced.
+	pub fn discard_blob(&self, id: u64) -> Result<()> {
+		match &self.blobs {
+			Some(blobs) => blobs.discard(id),
+			None => Ok(()),
+		}
+	}
+
+	/// Write an entry referencing blob `id` of `len` bytes, created with `create_blob_writer`.
+	/// Replaces the entry at `at`, or inserts a new one. Returns the entry index.
+	pub fn write_blob_plan(
+		&self,
+		at: Option<u64>,
+		key: &TableKey,
+		id: u64,
+		len: u64,
+		log: &mut LogWriter,
+	) -> Result<u64> {
+		let index = match at {
+			Some(index) => {
+				if let Some(next) = self.read_next_part(index, log)? {
+					self.clear_chain(next, log)?;
+				}
+				index
+			},
+			None => self.next_free(log)?,
+		};
+		self.write_blob_entry(index, key, id, len, false, 1, log);
+		Ok(index)
+	}
+
 	fn clear_chain(&self, mut index: u64, log: &mut LogWriter) -> Result<()> {
 		loop {
 			match self.read_next_part(index, log)? {
@@ -779,7 +1324,7 @@ impl ValueTable {
 		log: &mut LogWriter,
 		compressed: bool,
 	) -> Result<u64> {
-		self.overwrite_chain(key, value, log, None, compressed)
+		self.overwrite_chain(key, value, log, None, compressed, 1)
 	}
 
 	pub fn write_replace_plan(
@@ -790,10 +1335,72 @@ impl ValueTable {
 		log: &mut LogWriter,
 		compressed: bool,
 	) -> Result<()> {
-		self.overwrite_chain(key, value, log, Some(index), compressed)?;
+		self.overwrite_chain(key, value, log, Some(index), compressed, 1)?;
 		Ok(())
 	}
 
+	/// Check if the value at `index` is encrypted with a known key other than the current one.
+	/// The last parts of multipart values can't be told apart from values with certainty. Their
+	/// key id is random and they are skipped unless the id is known.
+	#[cfg(feature = "encryption")]
+	pub fn has_stale_key(&self, index: u64, log: &impl LogQuery) -> Result<bool> {
+		let encryption = match self.encryption_at(index) {
+			Some(encryption) => encryption,
+			None => return Ok(false),
+		};
+		if index == 0 || index >= self.filled.load(Ordering::Relaxed) {
+			return Ok(false)
+		}
+		let mut buf = PartialEntry::new_uninit();
+		if !log.value(self.id, index, buf.as_mut()) {
+			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+		}
+		// Other parts of multipart values are checked with the first part.
+		if buf.is_multipart() {
+			return Ok(false)
+		}
+		let mut data = Vec::new();
+		let mut partial = Default::default();
+		let query = &mut self.fetch_query(&mut partial);
+		match self.for_parts(query, index, None, log, |buf| {
+			data.extend_from_slice(buf);
+			data.len() < crate::encryption::OVERHEAD
+		}) {
+			Ok((rc, _)) => Ok(rc > 0 && encryption.is_stale(&data)),
+			Err(crate::error::Error::ValueTableCorruption { .. }) |
+			Err(crate::error::Error::InvalidValueData) => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Rewrite the value at `index` encrypted with the current key, keeping its reference count.
+	/// Returns `false` if there is no value that can be decrypted at `index`.
+	#[cfg(feature = "encryption")]
+	pub fn write_reencrypt_plan(&self, index: u64, log: &mut LogWriter) -> Result<bool> {
+		let mut partial = Default::default();
+		let (value, compressed, rc) =
+			match self.query(&mut self.fetch_query(&mut partial), index, log) {
+				Ok(Some(value)) => value,
+				Ok(None) | Err(crate::error::Error::Decryption { .. }) => return Ok(false),
+				Err(e) => return Err(e),
+			};
+		let key =
+			if self.btree_index { TableKey::NoHash } else { TableKey::from_partial(&partial) };
+		log::trace!(target: "parity-db", "{}: Re-encrypting slot {}", self.id, index);
+		self.overwrite_chain(&key, &value, log, Some(index), compressed, rc)?;
+		Ok(true)
+	}
+
+	// Query reading the stored partial key into `partial`, if there is one.
+	#[cfg(feature = "encryption")]
+	fn fetch_query<'a>(&self, partial: &'a mut [u8; PARTIAL_SIZE]) -> TableKeyQuery<'a> {
+		if self.btree_index {
+			TableKeyQuery::Fetch(None)
+		} else {
+			TableKeyQuery::Fetch(Some(partial))
+		}
+	}
+
 	pub fn write_remove_plan(&self, index: u64, log: &mut LogWriter) -> Result<()> {
 		if self.multipart {
 			self.clear_chain(index, log)?;
@@ -808,15 +1415,17 @@ impl ValueTable {
 		Ok(())
 	}
 
-	pub fn write_dec_ref(&self, index: u64, log: &mut LogWriter) -> Result<bool> {
-		if self.change_ref(index, -1, log)? {
+	/// Change the reference count of the value at `index` by `delta`, removing the value once
+	/// the count reaches zero. Returns false if the value was removed.
+	pub fn write_change_ref(&self, index: u64, delta: i64, log: &mut LogWriter) -> Result<bool> {
+		if self.change_ref(index, delta, log)? {
 			return Ok(true)
 		}
 		self.write_remove_plan(index, log)?;
 		Ok(false)
 	}
 
-	pub fn change_ref(&self, index: u64, delta: i32, log: &mut LogWriter) -> Result<bool> {
+	pub fn change_ref(&self, index: u64, delta: i64, log: &mut LogWriter) -> Result<bool> {
 		let mut buf = FullEntry::new_uninit_full_entry();
 		let buf = if log.value(self.id, index, buf.as_mut()) {
 			&mut buf
@@ -826,11 +1435,15 @@ impl ValueTable {
 			&mut buf
 		};
 
-		if buf.is_tombstone() {
+		if buf.free_entry_size().is_some() {
 			return Ok(false)
 		}
 
-		let size = if self.multipart && buf.is_multi(self.db_version) {
+		let size = if self.is_blob(buf) {
+			buf.skip_size();
+			buf.skip_u64();
+			self.blob_entry_size()
+		} else if self.multipart && buf.is_multi(self.db_version) {
 			buf.skip_size();
 			buf.skip_next();
 			self.entry_size as usize
@@ -840,53 +1453,63 @@ impl ValueTable {
 		};
 
 		let rc_offset = buf.offset();
-		let mut counter = buf.read_rc();
-		if delta > 0 {
-			if counter >= LOCKED_REF - delta as u32 {
-				counter = LOCKED_REF
-			} else {
-				counter += delta as u32;
-			}
-		} else if counter != LOCKED_REF {
-			counter = counter.saturating_sub(-delta as u32);
-			if counter == 0 {
+		let locked = if self.wide_rc { WIDE_LOCKED_REF } else { LOCKED_REF };
+		let mut counter = buf.read_rc(self.wide_rc);
+		if counter != locked {
+			if delta >= 0 {
+				counter = counter.saturating_add(delta as u64).min(locked);
+				if counter == locked {
+					log::warn!(target: "parity-db", "{}: Reference count of slot {} reached {} and is locked", self.id, index, locked);
+				}
+			} else if counter <= delta.unsigned_abs() {
 				return Ok(false)
+			} else {
+				counter -= delta.unsigned_abs();
 			}
 		}
 
 		buf.set_offset(rc_offset);
-		buf.write_rc(counter);
+		buf.write_rc(counter, self.wide_rc);
 		// TODO: optimize actual buf size
 		log.insert_value(self.id, index, buf[0..size].to_vec());
 		Ok(true)
 	}
 
 	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
-		while index >= self.file.capacity.load(Ordering::Relaxed) {
+		while index >= self.file.capacity() {
 			self.file.grow(self.entry_size)?;
 		}
 		if index == 0 {
 			let mut header = Header::default();
-			log.read(&mut header.0)?;
-			self.file.write_at(&header.0, 0)?;
+			let header = &mut header.0[..self.header_size()];
+			log.read(header)?;
+			self.file.write_at(header, 0)?;
 			return Ok(())
 		}
 
 		let mut buf = FullEntry::new_uninit_full_entry();
 		log.read(&mut buf[0..SIZE_SIZE])?;
-		if buf.is_tombstone() {
-			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + INDEX_SIZE])?;
-			self.file
-				.write_at(&buf[0..SIZE_SIZE + INDEX_SIZE], index * (self.entry_size as u64))?;
+		if let Some(size) = buf.free_entry_size() {
+			log.read(&mut buf[SIZE_SIZE..size])?;
+			self.enact_blob(index, &mut buf, log)?;
+			self.file.write_at(&buf[0..size], index * (self.entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted tombstone in slot {}", self.id, index);
+		} else if self.is_blob(&buf) {
+			let size = self.blob_entry_size();
+			log.read(&mut buf[SIZE_SIZE..size])?;
+			self.enact_blob(index, &mut buf, log)?;
+			self.file.write_at(&buf[0..size], index * (self.entry_size as u64))?;
+			log::trace!(target: "parity-db", "{}: Enacted blob in slot {}", self.id, index);
 		} else if self.multipart && buf.is_multi(self.db_version) {
 			let entry_size = self.entry_size as usize;
 			log.read(&mut buf[SIZE_SIZE..entry_size])?;
+			self.enact_blob(index, &mut buf, log)?;
 			self.file.write_at(&buf[0..entry_size], index * (entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted multipart in slot {}", self.id, index);
 		} else {
 			let (len, _compressed) = buf.read_size();
 			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + len as usize])?;
+			self.enact_blob(index, &mut buf, log)?;
 			self.file
 				.write_at(&buf[0..(SIZE_SIZE + len as usize)], index * (self.entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted {}: {}, {} bytes", self.id, index, hex(&buf.1[6..32]), len);
@@ -897,15 +1520,18 @@ impl ValueTable {
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
 		if index == 0 {
 			let mut header = Header::default();
-			log.read(&mut header.0)?;
+			log.read(&mut header.0[..self.header_size()])?;
 			// TODO: sanity check last_removed and filled
 			return Ok(())
 		}
 		let mut buf = FullEntry::new_uninit_full_entry();
 		log.read(&mut buf[0..SIZE_SIZE])?;
-		if buf.is_tombstone() {
-			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + INDEX_SIZE])?;
+		if let Some(size) = buf.free_entry_size() {
+			log.read(&mut buf[SIZE_SIZE..size])?;
 			log::trace!(target: "parity-db", "{}: Validated tombstone in slot {}", self.id, index);
+		} else if self.is_blob(&buf) {
+			log.read(&mut buf[SIZE_SIZE..self.blob_entry_size()])?;
+			log::trace!(target: "parity-db", "{}: Validated blob in slot {}", self.id, index);
 		} else if self.multipart && buf.is_multi(self.db_version) {
 			let entry_size = self.entry_size as usize;
 			log.read(&mut buf[SIZE_SIZE..entry_size])?;
@@ -919,12 +1545,49 @@ impl ValueTable {
 		Ok(())
 	}
 
+	/// Read a logged entry into `writer`.
+	pub fn follow_plan(
+		&self,
+		index: u64,
+		log: &mut LogReader,
+		writer: &mut LogWriter,
+	) -> Result<()> {
+		if index == 0 {
+			let mut header = Header::default();
+			let header = &mut header.0[..self.header_size()];
+			log.read(header)?;
+			writer.insert_value(self.id, 0, header.to_vec());
+			return Ok(())
+		}
+		let mut buf = FullEntry::new_uninit_full_entry();
+		log.read(&mut buf[0..SIZE_SIZE])?;
+		let len = if let Some(size) = buf.free_entry_size() {
+			size
+		} else if self.is_blob(&buf) {
+			self.blob_entry_size()
+		} else if self.multipart && buf.is_multi(self.db_version) {
+			self.entry_size as usize
+		} else {
+			let (len, _compressed) = buf.read_size();
+			SIZE_SIZE + len as usize
+		};
+		log.read(&mut buf[SIZE_SIZE..len])?;
+		writer.insert_value(self.id, index, buf[0..len].to_vec());
+		Ok(())
+	}
+
+	/// Open the table file if it was created by another process, and reload the header.
+	pub fn refresh(&self) -> Result<()> {
+		self.file.refresh(self.entry_size)?;
+		self.refresh_metadata()
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
-		if self.file.file.read().is_none() {
+		if !self.file.is_init() {
 			return Ok(())
 		}
 		let mut header = Header::default();
-		self.file.read_at(&mut header.0, 0)?;
+		self.file.read_at(&mut header.0[..self.header_size()], 0)?;
 		let last_removed = header.last_removed();
 		let mut filled = header.filled();
 		if filled == 0 {
@@ -932,6 +1595,9 @@ impl ValueTable {
 		}
 		self.last_removed.store(last_removed, Ordering::Relaxed);
 		self.filled.store(filled, Ordering::Relaxed);
+		if let Some(count) = &self.count {
+			count.store(header.count(), Ordering::Relaxed);
+		}
 		Ok(())
 	}
 
@@ -940,50 +1606,186 @@ impl ValueTable {
 			self.dirty_header
 				.compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
 		{
-			// last_removed or filled pointers were modified. Add them to the log
+			// last_removed, filled or count were modified. Add them to the log
 			let mut buf = Header::default();
 			let last_removed = self.last_removed.load(Ordering::Relaxed);
 			let filled = self.filled.load(Ordering::Relaxed);
 			buf.set_last_removed(last_removed);
 			buf.set_filled(filled);
-			log.insert_value(self.id, 0, buf.0.to_vec());
+			if let Some(count) = &self.count {
+				buf.set_count(count.load(Ordering::Relaxed));
+			}
+			log.insert_value(self.id, 0, buf.0[..self.header_size()].to_vec());
 		}
 		Ok(())
 	}
 
 	pub fn flush(&self) -> Result<()> {
-		self.file.flush()
+		self.file.flush()?;
+		if let Some(blobs) = &self.blobs {
+			blobs.flush()?;
+		}
+		Ok(())
+	}
+
+	/// Remove blobs that are not referenced by the table. Called once the log is replayed.
+	pub fn collect_blobs(&self) -> Result<()> {
+		let blobs = match &self.blobs {
+			Some(blobs) => blobs,
+			None => return Ok(()),
+		};
+		if !blobs.take_unreferenced() || !self.is_init() {
+			return blobs.retain(|_| true)
+		}
+		let filled = self.filled.load(Ordering::Relaxed);
+		let entry_size = self.entry_size as u64;
+		let block_entries = COMPACT_SCAN_BYTES / entry_size;
+		let mut block = vec![0; (block_entries * entry_size) as usize];
+		let mut referenced = std::collections::HashSet::new();
+		let mut index = 1;
+		while index < filled {
+			let end = filled.min(index + block_entries);
+			let len = ((end - index) * entry_size) as usize;
+			self.file.read_at(&mut block[..len], index * entry_size)?;
+			for entry in block[..len].chunks(entry_size as usize) {
+				let mut buf = PartialEntry::new(
+					entry[..SIZE_SIZE + INDEX_SIZE].try_into().expect("10 bytes"),
+				);
+				if buf.is_blob() {
+					buf.skip_size();
+					referenced.insert(buf.read_u64());
+				}
+			}
+			index = end;
+		}
+		blobs.retain(|id| referenced.contains(&id))
+	}
+
+	fn is_blob<B: AsRef<[u8]> + AsMut<[u8]>>(&self, buf: &Entry<B>) -> bool {
+		self.blobs.is_some() && buf.is_blob()
+	}
+
+	fn blob_entry_size(&self) -> usize {
+		SIZE_SIZE + INDEX_SIZE + self.ref_size() + PARTIAL_SIZE + INDEX_SIZE
+	}
+
+	// Called before the entry at `index` is replaced with `entry`. Commits the blob of the new
+	// entry and removes the blob of the replaced entry.
+	fn enact_blob(&self, index: u64, entry: &mut FullEntry, log: &LogReader) -> Result<()> {
+		let blobs = match &self.blobs {
+			Some(blobs) => blobs,
+			None => return Ok(()),
+		};
+		let new = entry.is_blob().then(|| {
+			entry.set_offset(SIZE_SIZE);
+			entry.read_u64()
+		});
+		let mut old = PartialEntry::new_uninit();
+		self.file.read_at(old.as_mut(), index * self.entry_size as u64)?;
+		let old = old.is_blob().then(|| {
+			old.skip_size();
+			old.read_u64()
+		});
+		blobs.replace(old, new, log.is_replay())
 	}
 
 	fn ref_size(&self) -> usize {
-		if self.ref_counted {
+		if self.wide_rc {
+			WIDE_REFS_SIZE
+		} else if self.ref_counted {
 			REFS_SIZE
 		} else {
 			0
 		}
 	}
 
+	fn checksum_size(&self) -> usize {
+		if self.checksums {
+			CHECKSUM_SIZE
+		} else {
+			0
+		}
+	}
+
+	// The btree header is the first entry of the smallest table. It has no room for encryption
+	// overhead and is stored as is.
+	#[cfg(feature = "encryption")]
+	fn encryption_at(&self, index: u64) -> Option<&crate::encryption::Encryption> {
+		if self.btree_index && self.id.size_tier() == 0 && index == 1 {
+			return None
+		}
+		self.encryption.as_ref()
+	}
+
+	fn encryption_size(&self) -> usize {
+		#[cfg(feature = "encryption")]
+		if self.encryption.is_some() {
+			return crate::encryption::OVERHEAD
+		}
+		0
+	}
+
+	// Value stored at `index`, checked and decrypted.
+	fn decode_value(&self, index: u64, value: Value) -> Result<Value> {
+		let value = self.verify_checksum(index, value)?;
+		#[cfg(feature = "encryption")]
+		if let Some(encryption) = self.encryption_at(index) {
+			let (col, tier) = (self.id.col(), self.id.size_tier());
+			return encryption
+				.decrypt(&value, col, tier, index)?
+				.ok_or(crate::error::Error::Decryption { col, tier, index })
+		}
+		Ok(value)
+	}
+
+	// Check and remove the checksum stored after the value at `index`.
+	fn verify_checksum(&self, index: u64, mut value: Value) -> Result<Value> {
+		if !self.checksums {
+			return Ok(value)
+		}
+		let mismatch = || crate::error::Error::ChecksumMismatch {
+			col: self.id.col(),
+			tier: self.id.size_tier(),
+			index,
+		};
+		let len = value.len().checked_sub(CHECKSUM_SIZE).ok_or_else(mismatch)?;
+		let checksum = u32::from_le_bytes(value[len..].try_into().expect("4 bytes"));
+		value.truncate(len);
+		if crc32fast::hash(&value) != checksum {
+			return Err(mismatch())
+		}
+		Ok(value)
+	}
+
 	pub fn iter_while(
 		&self,
 		log: &impl LogQuery,
-		mut f: impl FnMut(u64, u32, Vec<u8>, bool) -> bool,
+		mut f: impl FnMut(u64, u64, [u8; PARTIAL_SIZE], Vec<u8>, bool) -> bool,
 	) -> Result<()> {
 		let filled = self.filled.load(Ordering::Relaxed);
-		for index in 1..filled {
+		let mut index = 0;
+		while index + 1 < filled {
+			index += 1;
 			let mut result = Vec::new();
 			// expect only indexed key.
-			let mut _fetch_key = Default::default();
+			let mut fetch_key = Default::default();
 			match self.for_parts(
-				&mut TableKeyQuery::Fetch(Some(&mut _fetch_key)),
+				&mut TableKeyQuery::Fetch(Some(&mut fetch_key)),
 				index,
+				None,
 				log,
 				|buf| {
 					result.extend_from_slice(buf);
 					true
 				},
 			) {
+				Ok((0, _)) => {
+					// Entries in a run of deleted entries hold no data.
+					let (_next, run) = self.read_free_entry(index, log)?;
+					index += run - 1;
+				},
 				Ok((rc, compressed)) =>
-					if rc > 0 && !f(index, rc, result, compressed) {
+					if !f(index, rc, fetch_key, self.decode_value(index, result)?, compressed) {
 						break
 					},
 				Err(crate::error::Error::InvalidValueData) => (), // ignore, can be external index.
@@ -994,7 +1796,89 @@ impl ValueTable {
 	}
 
 	pub fn is_init(&self) -> bool {
-		self.file.file.read().is_some()
+		self.file.is_init()
+	}
+
+	fn header_size(&self) -> usize {
+		header_size(self.count.is_some())
+	}
+
+	/// Number of keys in the column, if counted in this table.
+	pub fn count(&self) -> Option<u64> {
+		self.count.as_ref().map(|count| count.load(Ordering::Relaxed))
+	}
+
+	/// Add `delta` to the number of keys counted in this table, if any. Written to the log
+	/// with the header by `complete_plan`.
+	pub fn add_count(&self, delta: i64) {
+		if let Some(count) = &self.count {
+			if delta != 0 {
+				count.fetch_add(delta as u64, Ordering::Relaxed);
+				self.dirty_header.store(true, Ordering::Relaxed);
+			}
+		}
+	}
+
+	pub fn is_counted(&self) -> bool {
+		self.count.is_some()
+	}
+
+	pub fn occupancy(&self) -> TableOccupancy {
+		let mut occupancy = TableOccupancy {
+			tier: self.id.size_tier(),
+			entry_size: self.entry_size,
+			..Default::default()
+		};
+		if self.is_init() {
+			// The first entry holds the table header.
+			occupancy.filled = self.filled.load(Ordering::Relaxed) - 1;
+			occupancy.capacity = self.file.capacity().saturating_sub(1);
+		}
+		occupancy
+	}
+
+	/// Size of the table file in bytes, or 0 if it is not created yet.
+	pub fn file_size(&self) -> u64 {
+		if self.is_init() {
+			self.file.capacity() * self.entry_size as u64
+		} else {
+			0
+		}
+	}
+
+	/// Space used by the table file. Deleted entries are counted by following their list, which
+	/// may be cut short when commits are in progress.
+	pub fn space_usage(&self, log: &impl LogQuery) -> Result<SpaceUsage> {
+		let mut usage = SpaceUsage::default();
+		if !self.is_init() {
+			return Ok(usage)
+		}
+		let filled = self.filled.load(Ordering::Relaxed);
+		let mut index = self.last_removed.load(Ordering::Relaxed);
+		while index != 0 && index < filled && usage.free_entries < filled {
+			let mut buf = FreeEntry::new_uninit();
+			if !log.value(self.id, index, buf.as_mut()) {
+				self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+			}
+			if buf.free_entry_size().is_none() {
+				// Reused since the list head was read.
+				break
+			}
+			let run = buf.is_free_run();
+			buf.skip_size();
+			index = buf.read_next();
+			usage.free_entries += if run { buf.read_u64() } else { 1 };
+		}
+		// The first entry holds the table header.
+		let live = (filled - 1).saturating_sub(usage.free_entries);
+		let capacity = self.file.capacity();
+		let entry_size = self.entry_size as u64;
+		usage.live_bytes = live * entry_size;
+		usage.allocated_bytes = capacity * entry_size;
+		usage.reclaimable_bytes = capacity
+			.saturating_sub(self.file.shrunk_capacity(live + 1, self.entry_size)) *
+			entry_size;
+		Ok(usage)
 	}
 
 	pub fn init_with_entry(&self, entry: &[u8]) -> Result<()> {
@@ -1011,7 +1895,7 @@ impl ValueTable {
 
 		let empty_overlays = RwLock::new(Default::default());
 		let mut log = LogWriter::new(&empty_overlays, 0);
-		let at = self.overwrite_chain(&TableKey::NoHash, entry, &mut log, None, false)?;
+		let at = self.overwrite_chain(&TableKey::NoHash, entry, &mut log, None, false, 1)?;
 		self.complete_plan(&mut log)?;
 		assert_eq!(at, 1);
 		let log = log.drain();
@@ -1046,6 +1930,15 @@ pub mod key {
 			}
 		}
 
+		/// Key that writes the given stored partial key.
+		#[cfg(feature = "encryption")]
+		pub fn from_partial(partial: &[u8; PARTIAL_SIZE]) -> TableKey {
+			let mut key = Key::default();
+			let at = key.len() - PARTIAL_SIZE;
+			key[at..].copy_from_slice(partial);
+			TableKey::Partial(key)
+		}
+
 		pub fn index_from_partial(partial: &[u8]) -> u64 {
 			u64::from_be_bytes((partial[0..8]).try_into().unwrap())
 		}
@@ -1104,7 +1997,7 @@ pub mod key {
 mod test {
 	const ENTRY_SIZE: u16 = 64;
 
-	use super::{TableId, Value, ValueTable};
+	use super::{TableId, Value, ValueTable, REFS_SIZE, SIZE_SIZE};
 	use crate::{
 		log::{Log, LogAction, LogWriter},
 		options::{ColumnOptions, Options, CURRENT_VERSION},
@@ -1116,8 +2009,8 @@ mod test {
 
 	fn new_table(dir: &TempDir, size: Option<u16>, options: &ColumnOptions) -> ValueTable {
 		let id = TableId::new(0, 0);
-		ValueTable::open(Arc::new(dir.path().to_path_buf()), id, size, options, CURRENT_VERSION)
-			.unwrap()
+		let path = Arc::new(dir.path().to_path_buf());
+		ValueTable::open(path, id, size, options, CURRENT_VERSION, Default::default()).unwrap()
 	}
 
 	fn new_log(dir: &TempDir) -> Log {
@@ -1178,10 +2071,15 @@ mod test {
 		ColumnOptions { ref_counted: true, ..Default::default() }
 	}
 
+	fn checksum_options() -> ColumnOptions {
+		ColumnOptions { value_checksums: true, ..rc_options() }
+	}
+
 	#[test]
 	fn insert_simple() {
 		insert_simple_inner(&Default::default());
 		insert_simple_inner(&rc_options());
+		insert_simple_inner(&checksum_options());
 	}
 	fn insert_simple_inner(options: &ColumnOptions) {
 		let dir = tempdir().unwrap();
@@ -1214,6 +2112,7 @@ mod test {
 	fn remove_simple() {
 		remove_simple_inner(&Default::default());
 		remove_simple_inner(&rc_options());
+		remove_simple_inner(&checksum_options());
 	}
 	fn remove_simple_inner(options: &ColumnOptions) {
 		let dir = tempdir().unwrap();
@@ -1281,10 +2180,108 @@ mod test {
 		assert_eq!(table.last_removed.load(Ordering::Relaxed), 0);
 	}
 
+	#[test]
+	fn free_runs() {
+		let dir = tempdir().unwrap();
+		let table = new_table(&dir, Some(1024), &Default::default());
+		let log = new_log(&dir);
+		let keys: Vec<_> = (0..20).map(|k| TableKey::Partial(key(k))).collect();
+		let values: Vec<_> = (0..20).map(|_| value(900)).collect();
+
+		write_ops(&table, &log, |writer| {
+			for (key, value) in keys.iter().zip(&values) {
+				table.write_insert_plan(key, value, writer, false).unwrap();
+			}
+		});
+		write_ops(&table, &log, |writer| {
+			for index in [20, 5, 2, 17, 3, 4].into_iter().chain(6..17).chain([18]) {
+				table.write_remove_plan(index, writer).unwrap();
+			}
+		});
+		let mut holes = Vec::new();
+		write_ops(&table, &log, |writer| {
+			holes = table.write_free_runs_plan(writer).unwrap();
+			table.complete_plan(writer).unwrap();
+		});
+		// Entries 2..=18 are in a run, 20 is a single deleted entry.
+		assert_eq!(holes, vec![(4096, 19 * 1024 / 4096 * 4096 - 4096)]);
+		assert_eq!(table.last_removed.load(Ordering::Relaxed), 20);
+		table.punch_holes(&holes).unwrap();
+
+		let mut found = Vec::new();
+		table
+			.iter_while(log.overlays(), |index, _, _, value, _| {
+				found.push((index, value));
+				true
+			})
+			.unwrap();
+		assert_eq!(found, vec![(1, values[0].clone()), (19, values[18].clone())]);
+
+		write_ops(&table, &log, |writer| {
+			for (i, index) in [20, 2, 3, 4].into_iter().enumerate() {
+				let key = &keys[i];
+				assert_eq!(table.write_insert_plan(key, &values[i], writer, false).unwrap(), index);
+			}
+		});
+		assert_eq!(table.last_removed.load(Ordering::Relaxed), 5);
+		assert_eq!(table.filled.load(Ordering::Relaxed), 21);
+		write_ops(&table, &log, |writer| {
+			assert_eq!(table.read_free_entry(5, writer).unwrap(), (0, 14));
+			assert_eq!(table.get(&keys[3], 4, writer).unwrap(), Some((values[3].clone(), false)));
+		});
+	}
+
+	#[test]
+	fn compact_and_relocate() {
+		let dir = tempdir().unwrap();
+		let table = new_table(&dir, Some(ENTRY_SIZE), &rc_options());
+		let log = new_log(&dir);
+		let keys: Vec<_> = (0..10).map(|k| TableKey::Partial(key(k))).collect();
+		let values: Vec<_> = (0..10).map(|_| value(20)).collect();
+
+		write_ops(&table, &log, |writer| {
+			for (key, value) in keys.iter().zip(&values) {
+				table.write_insert_plan(key, value, writer, false).unwrap();
+			}
+			table.write_inc_ref(10, writer).unwrap();
+		});
+		write_ops(&table, &log, |writer| {
+			for index in [4, 2, 5, 3] {
+				table.write_remove_plan(index, writer).unwrap();
+			}
+		});
+		write_ops(&table, &log, |writer| {
+			assert_eq!(table.write_compact_plan(writer).unwrap(), 7);
+			table.complete_plan(writer).unwrap();
+		});
+		assert_eq!(table.last_removed.load(Ordering::Relaxed), 2);
+
+		write_ops(&table, &log, |writer| {
+			assert_eq!(table.write_relocate_plan(3, &keys[2], writer).unwrap(), None);
+			assert_eq!(table.write_relocate_plan(10, &keys[0], writer).unwrap(), None);
+			assert_eq!(table.write_relocate_plan(10, &keys[9], writer).unwrap(), Some(2));
+			table.complete_plan(writer).unwrap();
+		});
+		assert_eq!(table.last_removed.load(Ordering::Relaxed), 3);
+		let (value, rc, _, _) = table.get_with_meta(2, log.overlays()).unwrap().unwrap();
+		assert_eq!((value, rc), (values[9].clone(), 2));
+
+		write_ops(&table, &log, |writer| {
+			assert_eq!(table.write_compact_plan(writer).unwrap(), 7);
+			table.complete_plan(writer).unwrap();
+		});
+		assert_eq!(table.filled.load(Ordering::Relaxed), 10);
+		assert_eq!(table.last_removed.load(Ordering::Relaxed), 3);
+		write_ops(&table, &log, |writer| {
+			assert_eq!(table.read_free_entry(3, writer).unwrap(), (0, 3));
+		});
+	}
+
 	#[test]
 	fn replace_multipart_shorter() {
 		replace_multipart_shorter_inner(&Default::default());
 		replace_multipart_shorter_inner(&rc_options());
+		replace_multipart_shorter_inner(&checksum_options());
 	}
 	fn replace_multipart_shorter_inner(options: &ColumnOptions) {
 		let dir = tempdir().unwrap();
@@ -1315,9 +2312,9 @@ mod test {
 		assert_eq!(table.get(key1, 1, log.overlays()).unwrap(), Some((val1s, compressed)));
 		assert_eq!(table.last_removed.load(Ordering::Relaxed), 5);
 		write_ops(&table, &log, |writer| {
-			assert_eq!(table.read_next_free(5, writer).unwrap(), 4);
-			assert_eq!(table.read_next_free(4, writer).unwrap(), 3);
-			assert_eq!(table.read_next_free(3, writer).unwrap(), 0);
+			assert_eq!(table.read_free_entry(5, writer).unwrap(), (4, 1));
+			assert_eq!(table.read_free_entry(4, writer).unwrap(), (3, 1));
+			assert_eq!(table.read_free_entry(3, writer).unwrap(), (0, 1));
 		});
 	}
 
@@ -1325,6 +2322,7 @@ mod test {
 	fn replace_multipart_longer() {
 		replace_multipart_longer_inner(&Default::default());
 		replace_multipart_longer_inner(&rc_options());
+		replace_multipart_longer_inner(&checksum_options());
 	}
 	fn replace_multipart_longer_inner(options: &ColumnOptions) {
 		let dir = tempdir().unwrap();
@@ -1374,11 +2372,11 @@ mod test {
 			});
 			assert_eq!(table.get(key, 1, log.overlays()).unwrap(), Some((val.clone(), compressed)));
 			write_ops(&table, &log, |writer| {
-				table.write_dec_ref(1, writer).unwrap();
+				table.write_change_ref(1, -1, writer).unwrap();
 			});
 			assert_eq!(table.get(key, 1, log.overlays()).unwrap(), Some((val, compressed)));
 			write_ops(&table, &log, |writer| {
-				table.write_dec_ref(1, writer).unwrap();
+				table.write_change_ref(1, -1, writer).unwrap();
 			});
 			assert_eq!(table.get(key, 1, log.overlays()).unwrap(), None);
 		}
@@ -1401,9 +2399,9 @@ mod test {
 		});
 		assert_eq!(table.get(key, 1, log.overlays()).unwrap(), Some((val, compressed)));
 		write_ops(&table, &log, |writer| {
-			table.write_dec_ref(1, writer).unwrap();
-			table.write_dec_ref(1, writer).unwrap();
-			table.write_dec_ref(1, writer).unwrap();
+			table.write_change_ref(1, -1, writer).unwrap();
+			table.write_change_ref(1, -1, writer).unwrap();
+			table.write_change_ref(1, -1, writer).unwrap();
 		});
 		assert_eq!(table.get(key, 1, log.overlays()).unwrap(), None);
 	}
@@ -1434,7 +2432,7 @@ mod test {
 		});
 		assert_eq!(table.get(key, 1, log.overlays()).unwrap(), Some((val, compressed)));
 		write_ops(&table, &log, |writer| {
-			table.write_dec_ref(1, writer).unwrap();
+			table.write_change_ref(1, -1, writer).unwrap();
 		});
 		assert_eq!(table.last_removed.load(Ordering::Relaxed), 1);
 
@@ -1467,7 +2465,30 @@ mod test {
 		let log = new_log(&dir);
 		assert!(matches!(
 			table.get(key, 1, log.overlays()),
-			Err(crate::error::Error::Corruption(_))
+			Err(crate::error::Error::ValueTableCorruption { .. })
+		));
+	}
+
+	#[test]
+	fn bad_checksum() {
+		let dir = tempdir().unwrap();
+		let table = new_table(&dir, Some(ENTRY_SIZE), &checksum_options());
+		let log = new_log(&dir);
+
+		let key = &TableKey::Partial(key(1));
+		let val = value(19);
+
+		write_ops(&table, &log, |writer| {
+			table.write_insert_plan(key, &val, writer, false).unwrap();
+		});
+		assert_eq!(table.size(key, 1, log.overlays()).unwrap(), Some((19, false)));
+		// Corrupt the first value byte of entry 1
+		let offset = table.entry_size as usize + SIZE_SIZE + REFS_SIZE + key.encoded_size();
+		table.file.write_at(&[!val[0]], offset as u64).unwrap();
+		let log = new_log(&dir);
+		assert!(matches!(
+			table.get(key, 1, log.overlays()),
+			Err(crate::error::Error::ChecksumMismatch { col: 0, tier: 0, index: 1 })
 		));
 	}
 }
@@ -0,0 +1,117 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Columns with typed keys and values, see `Db::typed_column`.
+
+use crate::{
+	column::ColId,
+	db::Db,
+	error::{Error, Result},
+	Value,
+};
+use std::marker::PhantomData;
+
+/// Conversion of values to and from the bytes stored in a column. Implement it for application
+/// types, with the serialization format of choice, to use them with `TypedColumn`.
+pub trait Codec: Sized {
+	fn encode(&self) -> Vec<u8>;
+	fn decode(data: Vec<u8>) -> Result<Self>;
+}
+
+impl Codec for Vec<u8> {
+	fn encode(&self) -> Vec<u8> {
+		self.clone()
+	}
+
+	fn decode(data: Vec<u8>) -> Result<Self> {
+		Ok(data)
+	}
+}
+
+impl Codec for String {
+	fn encode(&self) -> Vec<u8> {
+		self.as_bytes().to_vec()
+	}
+
+	fn decode(data: Vec<u8>) -> Result<Self> {
+		String::from_utf8(data).map_err(|_| Error::InvalidValueData)
+	}
+}
+
+// Integers are big endian, so that they sort numerically as btree keys.
+macro_rules! int_codec {
+	($($t:ty),*) => {
+		$(
+			impl Codec for $t {
+				fn encode(&self) -> Vec<u8> {
+					self.to_be_bytes().to_vec()
+				}
+
+				fn decode(data: Vec<u8>) -> Result<Self> {
+					let bytes = data.try_into().map_err(|_| Error::InvalidValueData)?;
+					Ok(<$t>::from_be_bytes(bytes))
+				}
+			}
+		)*
+	};
+}
+
+int_codec!(u16, u32, u64, u128, i64);
+
+/// A column of the database with keys of type `K` and values of type `V`, returned by
+/// `Db::typed_column`.
+pub struct TypedColumn<'a, K, V> {
+	db: &'a Db,
+	col: ColId,
+	_types: PhantomData<fn(K) -> V>,
+}
+
+impl<'a, K: AsRef<[u8]>, V: Codec> TypedColumn<'a, K, V> {
+	pub(crate) fn new(db: &'a Db, col: ColId) -> Result<Self> {
+		if db.column_options(col).is_none() {
+			return Err(Error::InvalidInput(format!("Invalid column {}", col)))
+		}
+		Ok(TypedColumn { db, col, _types: PhantomData })
+	}
+
+	/// Column id.
+	pub fn col(&self) -> ColId {
+		self.col
+	}
+
+	pub fn get(&self, key: &K) -> Result<Option<V>> {
+		self.db.get(self.col, key.as_ref())?.map(V::decode).transpose()
+	}
+
+	/// Check if a value exists for the key without reading the value.
+	pub fn contains_key(&self, key: &K) -> Result<bool> {
+		self.db.contains_key(self.col, key.as_ref())
+	}
+
+	/// Commit `value` for `key`. Returns the commit id.
+	pub fn insert(&self, key: K, value: &V) -> Result<u64> {
+		self.db.commit([self.change(key, Some(value))])
+	}
+
+	/// Commit the removal of `key`. Returns the commit id.
+	pub fn remove(&self, key: K) -> Result<u64> {
+		self.db.commit([self.change(key, None)])
+	}
+
+	/// Change of this column to pass to `Db::commit`, to commit with changes of other columns.
+	pub fn change(&self, key: K, value: Option<&V>) -> (ColId, K, Option<Value>) {
+		(self.col, key, value.map(V::encode))
+	}
+}
+
+impl<'a, K, V> Clone for TypedColumn<'a, K, V> {
+	fn clone(&self) -> Self {
+		TypedColumn { db: self.db, col: self.col, _types: PhantomData }
+	}
+}
+
+impl<'a, K, V> std::fmt::Debug for TypedColumn<'a, K, V> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TypedColumn").field("col", &self.col).finish()
+	}
+}
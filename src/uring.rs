@@ -0,0 +1,427 @@
+// Copyright 2021-2022 Parity Technologies (UK) Ltd.
+// This file is dual-licensed as Apache-2.0 or MIT.
+
+//! Minimal io_uring support for batched value table reads.
+//!
+//! Each thread lazily sets up its own ring. If the kernel does not support io_uring, or an
+//! operation fails, callers fall back to regular reads. Only reads are submitted: index chunks
+//! are memory mapped and writes go through the regular file API.
+//!
+//! The kernel writes into the caller's buffers until a read completes, so `Ring::read` never
+//! returns while a submitted read is in flight, and a ring that returned an error is dropped
+//! rather than reused.
+
+use std::{
+	cell::RefCell,
+	os::unix::io::RawFd,
+	sync::atomic::{AtomicU32, Ordering},
+};
+
+const RING_ENTRIES: u32 = 64;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+	head: u32,
+	tail: u32,
+	ring_mask: u32,
+	ring_entries: u32,
+	flags: u32,
+	dropped: u32,
+	array: u32,
+	resv1: u32,
+	user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+	head: u32,
+	tail: u32,
+	ring_mask: u32,
+	ring_entries: u32,
+	overflow: u32,
+	cqes: u32,
+	flags: u32,
+	resv1: u32,
+	user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+	sq_entries: u32,
+	cq_entries: u32,
+	flags: u32,
+	sq_thread_cpu: u32,
+	sq_thread_idle: u32,
+	features: u32,
+	wq_fd: u32,
+	resv: [u32; 3],
+	sq_off: SqringOffsets,
+	cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+	opcode: u8,
+	flags: u8,
+	ioprio: u16,
+	fd: i32,
+	off: u64,
+	addr: u64,
+	len: u32,
+	rw_flags: u32,
+	user_data: u64,
+	buf_index: u16,
+	personality: u16,
+	splice_fd_in: i32,
+	addr3: u64,
+	pad: u64,
+}
+
+// Layout of `struct io_uring_params` and `struct io_uring_sqe` in `linux/io_uring.h`.
+const _: () = {
+	use std::mem::{offset_of, size_of};
+	assert!(size_of::<SqringOffsets>() == 40 && size_of::<CqringOffsets>() == 40);
+	assert!(offset_of!(SqringOffsets, array) == 24 && offset_of!(CqringOffsets, cqes) == 20);
+	assert!(size_of::<Params>() == 120);
+	assert!(offset_of!(Params, sq_off) == 40 && offset_of!(Params, cq_off) == 80);
+	assert!(size_of::<Sqe>() == 64);
+	assert!(offset_of!(Sqe, fd) == 4 && offset_of!(Sqe, off) == 8 && offset_of!(Sqe, addr) == 16);
+	assert!(offset_of!(Sqe, len) == 24 && offset_of!(Sqe, user_data) == 32);
+	assert!(size_of::<Cqe>() == 16 && offset_of!(Cqe, res) == 8);
+};
+
+#[repr(C)]
+struct Cqe {
+	user_data: u64,
+	res: i32,
+	flags: u32,
+}
+
+struct Mapping {
+	ptr: *mut libc::c_void,
+	len: usize,
+}
+
+impl Mapping {
+	fn new(fd: RawFd, len: usize, offset: libc::off_t) -> std::io::Result<Mapping> {
+		// SAFETY: maps a new region chosen by the kernel, no existing memory is affected.
+		let ptr = unsafe {
+			libc::mmap(
+				std::ptr::null_mut(),
+				len,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED | libc::MAP_POPULATE,
+				fd,
+				offset,
+			)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(std::io::Error::last_os_error())
+		}
+		Ok(Mapping { ptr, len })
+	}
+
+	fn at<T>(&self, offset: u32) -> *mut T {
+		debug_assert!((offset as usize) < self.len);
+		// SAFETY: offsets come from the kernel's ring description and are within the mapping.
+		unsafe { (self.ptr as *mut u8).add(offset as usize) as *mut T }
+	}
+}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		// SAFETY: the region was mapped by `Mapping::new` and no references into it outlive
+		// the mapping, as they are only taken while the owning `Ring` is borrowed.
+		unsafe { libc::munmap(self.ptr, self.len) };
+	}
+}
+
+struct Ring {
+	fd: RawFd,
+	sq: Mapping,
+	cq: Mapping,
+	sqes: Mapping,
+	params: Params,
+}
+
+impl Ring {
+	fn new(entries: u32) -> std::io::Result<Ring> {
+		let mut params = Params::default();
+		// SAFETY: `params` is a valid `io_uring_params` the kernel fills in.
+		let fd =
+			unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+		if fd < 0 {
+			return Err(std::io::Error::last_os_error())
+		}
+		let fd = fd as RawFd;
+		let map = || -> std::io::Result<(Mapping, Mapping, Mapping)> {
+			let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+			let cq_len = params.cq_off.cqes as usize +
+				params.cq_entries as usize * std::mem::size_of::<Cqe>();
+			let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+			Ok((
+				Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+				Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+				Mapping::new(fd, sqes_len, IORING_OFF_SQES)?,
+			))
+		};
+		match map() {
+			Ok((sq, cq, sqes)) => Ok(Ring { fd, sq, cq, sqes, params }),
+			Err(e) => {
+				// SAFETY: `fd` was returned by `io_uring_setup` above and is not used afterwards.
+				unsafe { libc::close(fd) };
+				Err(e)
+			},
+		}
+	}
+
+	fn atomic(map: &Mapping, offset: u32) -> &AtomicU32 {
+		// SAFETY: ring heads and tails are aligned `u32`s shared with the kernel, which only
+		// accesses them atomically.
+		unsafe { &*map.at::<AtomicU32>(offset) }
+	}
+
+	// Read all requests. Returns the number of bytes read or the error for each request. Returns
+	// only once all submitted reads completed, so the buffers are no longer written to. After an
+	// error unsubmitted entries may be left in the submission queue and the ring must be dropped.
+	fn read(&mut self, fd: RawFd, reqs: &mut [(u64, &mut [u8])]) -> std::io::Result<Vec<i32>> {
+		let mut results = vec![0; reqs.len()];
+		for (batch, chunk) in reqs.chunks_mut(self.params.sq_entries as usize).enumerate() {
+			let base = batch * self.params.sq_entries as usize;
+			let sq_off = &self.params.sq_off;
+			// SAFETY: the mask is written by the kernel at setup and never changes.
+			let mask = unsafe { *self.sq.at::<u32>(sq_off.ring_mask) };
+			let array = self.sq.at::<u32>(sq_off.array);
+			let sqes = self.sqes.at::<Sqe>(0);
+			let tail = Self::atomic(&self.sq, sq_off.tail);
+			let mut t = tail.load(Ordering::Acquire);
+			for (i, (offset, buf)) in chunk.iter_mut().enumerate() {
+				let index = t & mask;
+				// SAFETY: `index` is within the `sq_entries` entries of both arrays, and the slot
+				// is free as the previous batch completed. The buffer outlives the read, which
+				// completes before `submit_and_wait` returns.
+				unsafe {
+					sqes.add(index as usize).write(Sqe {
+						opcode: IORING_OP_READ,
+						flags: 0,
+						ioprio: 0,
+						fd,
+						off: *offset,
+						addr: buf.as_mut_ptr() as u64,
+						len: buf.len() as u32,
+						rw_flags: 0,
+						user_data: (base + i) as u64,
+						buf_index: 0,
+						personality: 0,
+						splice_fd_in: 0,
+						addr3: 0,
+						pad: 0,
+					});
+					*array.add(index as usize) = index;
+				}
+				t = t.wrapping_add(1);
+			}
+			tail.store(t, Ordering::Release);
+			self.submit_and_wait(chunk.len(), &mut results)?;
+		}
+		Ok(results)
+	}
+
+	// Submit `count` queued entries and wait for their completions. If submitting fails, the
+	// reads already submitted are still waited for before the error is returned.
+	fn submit_and_wait(&mut self, count: usize, results: &mut [i32]) -> std::io::Result<()> {
+		let cq_off = &self.params.cq_off;
+		// SAFETY: the mask is written by the kernel at setup and never changes.
+		let mask = unsafe { *self.cq.at::<u32>(cq_off.ring_mask) };
+		let cqes = self.cq.at::<Cqe>(cq_off.cqes);
+		let mut submitted = 0;
+		let mut completed = 0;
+		let mut error = None;
+		while completed < submitted || (error.is_none() && submitted < count) {
+			let to_submit = if error.is_none() { count - submitted } else { 0 };
+			// SAFETY: submits entries initialized by `read`, without a signal mask.
+			let ret = unsafe {
+				libc::syscall(
+					libc::SYS_io_uring_enter,
+					self.fd,
+					to_submit as u32,
+					1u32,
+					IORING_ENTER_GETEVENTS,
+					std::ptr::null::<libc::sigset_t>(),
+					0usize,
+				)
+			};
+			if ret < 0 {
+				let e = std::io::Error::last_os_error();
+				if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)) {
+					continue
+				}
+				if submitted == completed {
+					return Err(e)
+				}
+				if to_submit == 0 {
+					// Reads in flight may still write to buffers the caller is about to free.
+					log::error!(target: "parity-db", "Can't wait for io_uring reads: {}", e);
+					std::process::abort();
+				}
+				// Stop submitting and wait for the reads in flight.
+				error = Some(e);
+				continue
+			}
+			submitted += ret as usize;
+			let head = Self::atomic(&self.cq, cq_off.head);
+			let tail = Self::atomic(&self.cq, cq_off.tail);
+			let mut h = head.load(Ordering::Relaxed);
+			let t = tail.load(Ordering::Acquire);
+			if h == t && submitted == completed {
+				// Nothing in flight, the entries left are never submitted.
+				return Err(std::io::Error::other("io_uring submission stalled"))
+			}
+			while h != t {
+				// SAFETY: entries between head and tail are completions written by the kernel.
+				let cqe = unsafe { &*cqes.add((h & mask) as usize) };
+				results[cqe.user_data as usize] = cqe.res;
+				h = h.wrapping_add(1);
+				completed += 1;
+			}
+			head.store(h, Ordering::Release);
+		}
+		match error {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Drop for Ring {
+	fn drop(&mut self) {
+		// SAFETY: the ring owns `fd`, and no read is in flight once `read` returned.
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+thread_local! {
+	// `None` until first used, `Some(None)` if setting up the ring failed.
+	static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+}
+
+/// Read `(offset, buffer)` pairs from `fd` with a single submission per batch. Returns which
+/// buffers were filled completely, or `None` if io_uring is not available. The buffers are not
+/// written to after this returns.
+pub fn read(fd: RawFd, reqs: &mut [(u64, &mut [u8])]) -> Option<Vec<bool>> {
+	RING.with(|ring| {
+		let mut ring = ring.borrow_mut();
+		let slot = ring.get_or_insert_with(|| match Ring::new(RING_ENTRIES) {
+			Ok(ring) => Some(ring),
+			Err(e) => {
+				log::debug!(target: "parity-db", "io_uring is not available: {}", e);
+				None
+			},
+		});
+		let results = match slot.as_mut()?.read(fd, reqs) {
+			Ok(results) => results,
+			Err(e) => {
+				log::warn!(target: "parity-db", "io_uring read failed: {}", e);
+				// No read is in flight, but unsubmitted entries may be left in the ring.
+				*slot = None;
+				return None
+			},
+		};
+		Some(
+			reqs.iter()
+				.zip(results)
+				.map(|((_, buf), res)| res == buf.len() as i32)
+				.collect(),
+		)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Ring, RING, RING_ENTRIES};
+	use std::{fs::File, io::Write, os::unix::io::AsRawFd};
+
+	// Some kernels and sandboxes disable io_uring.
+	fn ring() -> Option<Ring> {
+		match Ring::new(RING_ENTRIES) {
+			Ok(ring) => Some(ring),
+			Err(e) => {
+				eprintln!("Skipping, io_uring is not available: {}", e);
+				None
+			},
+		}
+	}
+
+	fn data_file(dir: &tempfile::TempDir) -> File {
+		let path = dir.path().join("data");
+		File::create(&path)
+			.unwrap()
+			.write_all(&(0..1000u32).map(|i| i as u8).collect::<Vec<_>>())
+			.unwrap();
+		File::open(path).unwrap()
+	}
+
+	#[test]
+	fn short_reads() {
+		let Some(mut ring) = ring() else { return };
+		let dir = tempfile::tempdir().unwrap();
+		let file = data_file(&dir);
+		// More requests than ring entries, the last two past the end of the file.
+		let mut offsets: Vec<u64> = (0..100).map(|i| i * 10).collect();
+		offsets.extend([996, 2000]);
+		let mut bufs = vec![[0xffu8; 8]; offsets.len()];
+		let mut reqs: Vec<_> =
+			offsets.iter().copied().zip(bufs.iter_mut().map(|b| &mut b[..])).collect();
+		let results = ring.read(file.as_raw_fd(), &mut reqs).unwrap();
+		assert!(results[..100].iter().all(|&r| r == 8));
+		assert_eq!(&results[100..], &[4, 0]);
+		assert_eq!(
+			super::read(file.as_raw_fd(), &mut reqs).unwrap()[98..],
+			[true, true, false, false]
+		);
+		drop(reqs);
+		for (offset, buf) in offsets.iter().zip(&bufs).take(100) {
+			assert!(buf.iter().enumerate().all(|(i, &b)| b == (*offset as usize + i) as u8));
+		}
+		assert_eq!(bufs[100], [228, 229, 230, 231, 0xff, 0xff, 0xff, 0xff]);
+		assert_eq!(bufs[101], [0xff; 8]);
+	}
+
+	#[test]
+	fn error_completions() {
+		let Some(mut ring) = ring() else { return };
+		let dir = tempfile::tempdir().unwrap();
+		let write_only = File::create(dir.path().join("write_only")).unwrap();
+		let mut bufs = [[0u8; 8]; 2];
+		let mut reqs: Vec<_> = bufs.iter_mut().map(|b| (0, &mut b[..])).collect();
+		let results = ring.read(write_only.as_raw_fd(), &mut reqs).unwrap();
+		assert_eq!(results, [-libc::EBADF, -libc::EBADF]);
+		assert_eq!(super::read(write_only.as_raw_fd(), &mut reqs), Some(vec![false, false]));
+		// Failed completions leave the ring usable.
+		assert!(RING.with(|ring| matches!(*ring.borrow(), Some(Some(_)))));
+		let file = data_file(&dir);
+		assert_eq!(ring.read(file.as_raw_fd(), &mut reqs).unwrap(), [8, 8]);
+	}
+
+	#[test]
+	fn setup_failure() {
+		// Rejected with `EINVAL`, or `ENOSYS` and `EPERM` where io_uring is disabled.
+		assert!(Ring::new(0).is_err());
+		// Once setup failed, reads fall back to the caller without touching the buffers.
+		let dir = tempfile::tempdir().unwrap();
+		let file = data_file(&dir);
+		RING.with(|ring| *ring.borrow_mut() = Some(None));
+		let mut buf = [0xffu8; 8];
+		assert_eq!(super::read(file.as_raw_fd(), &mut [(0, &mut buf[..])]), None);
+		assert_eq!(buf, [0xff; 8]);
+	}
+}